@@ -2,6 +2,7 @@
 
 use crate::config::Config;
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use console::style;
 use serde::{Deserialize, Serialize};
 
@@ -20,48 +21,111 @@ struct PortalResponse {
     portal_url: String,
 }
 
-/// Create a clickable hyperlink for terminals that support OSC 8
-fn hyperlink(url: &str, text: &str) -> String {
-    format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", url, text)
+/// `GET /api/usage`
+#[derive(Debug, Deserialize)]
+struct UsageResponse {
+    plan: String,
+    bandwidth_bytes: u64,
+    /// Absent from servers that don't meter the directions apart
+    #[serde(default)]
+    ingress_bytes: u64,
+    #[serde(default)]
+    egress_bytes: u64,
+    bandwidth_limit: u64,
+    plan_expires_at: Option<DateTime<Utc>>,
 }
 
+/// `dvaar usage --json`
+#[derive(Debug, Serialize)]
+struct UsageEntry<'a> {
+    plan: &'a str,
+    bandwidth_bytes: u64,
+    bandwidth_limit: u64,
+    /// Of the limit, to two decimals; over 100 once it's exceeded
+    percent_used: f64,
+    plan_expires_at: Option<DateTime<Utc>>,
+    egress_bytes: u64,
+    ingress_bytes: u64,
+}
 
-/// Show usage statistics
-pub async fn usage() -> Result<()> {
-    use cliclack::{intro, outro, log};
+impl<'a> UsageEntry<'a> {
+    fn new(usage: &'a UsageResponse) -> Self {
+        Self {
+            plan: &usage.plan,
+            bandwidth_bytes: usage.bandwidth_bytes,
+            bandwidth_limit: usage.bandwidth_limit,
+            percent_used: percent_used(usage.bandwidth_bytes, usage.bandwidth_limit),
+            plan_expires_at: usage.plan_expires_at,
+            egress_bytes: usage.egress_bytes,
+            ingress_bytes: usage.ingress_bytes,
+        }
+    }
+}
 
-    let config = Config::load()?;
-    let token = config.require_auth()?;
+fn percent_used(used: u64, limit: u64) -> f64 {
+    if limit == 0 {
+        return 0.0;
+    }
+    (used as f64 * 10_000.0 / limit as f64).round() / 100.0
+}
 
-    intro("dvaar usage")?;
+/// Create a clickable hyperlink for terminals that support OSC 8
+fn hyperlink(url: &str, text: &str) -> String {
+    format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", url, text)
+}
 
-    let spinner = cliclack::spinner();
-    spinner.start("Fetching usage data...");
 
-    let client = reqwest::Client::new();
-    let response = client
-        .get(format!("{}/api/usage", config.server_url))
+/// Fetch the caller's usage
+async fn fetch_usage(config: &Config, token: &str) -> Result<UsageResponse> {
+    let response = reqwest::Client::new()
+        .get(format!("{}/api/usage", config.api_url()?))
         .header("Authorization", format!("Bearer {}", token))
         .send()
         .await?;
-
     if !response.status().is_success() {
         let status = response.status();
         let text = response.text().await.unwrap_or_default();
-        spinner.error(format!("Failed to fetch usage: {} - {}", status, text));
+        anyhow::bail!("Failed to fetch usage: {} - {}", status, text);
+    }
+    Ok(response.json().await?)
+}
+
+/// Show usage statistics, or with `json` print them as one JSON object
+pub async fn usage(json: bool) -> Result<()> {
+    use cliclack::{intro, outro, log};
+
+    let config = Config::load()?;
+    let token = config.require_auth()?;
+
+    if json {
+        let usage = fetch_usage(&config, token).await?;
+        println!("{}", serde_json::to_string_pretty(&UsageEntry::new(&usage))?);
         return Ok(());
     }
 
-    let data: serde_json::Value = response.json().await?;
-    spinner.stop("Usage data retrieved");
+    intro("dvaar usage")?;
 
-    let plan = data["plan"].as_str().unwrap_or("free");
-    let bandwidth = data["bandwidth_bytes"].as_u64().unwrap_or(0);
-    let limit = data["bandwidth_limit"].as_str().unwrap_or("unlimited");
+    let spinner = cliclack::spinner();
+    spinner.start("Fetching usage data...");
 
-    log::info(format!("Plan: {}", capitalize(plan)))?;
-    log::info(format!("Bandwidth Used: {}", format_bytes(bandwidth)))?;
-    log::info(format!("Bandwidth Limit: {}", limit))?;
+    let usage = match fetch_usage(&config, token).await {
+        Ok(usage) => usage,
+        Err(e) => {
+            spinner.error(e.to_string());
+            return Ok(());
+        }
+    };
+    spinner.stop("Usage data retrieved");
+
+    log::info(format!("Plan: {}", capitalize(&usage.plan)))?;
+    log::info(format!(
+        "Bandwidth Used: {} ({}%)",
+        format_bytes(usage.bandwidth_bytes),
+        percent_used(usage.bandwidth_bytes, usage.bandwidth_limit)
+    ))?;
+    log::info(format!("  Ingress (requests): {}", format_bytes(usage.ingress_bytes)))?;
+    log::info(format!("  Egress (responses): {}", format_bytes(usage.egress_bytes)))?;
+    log::info(format!("Bandwidth Limit: {}", format_bytes(usage.bandwidth_limit)))?;
 
     outro("Done")?;
 
@@ -96,7 +160,7 @@ pub async fn upgrade(plan: Option<String>) -> Result<()> {
 
     let client = reqwest::Client::new();
     let response = client
-        .post(format!("{}/api/billing/checkout", config.server_url))
+        .post(format!("{}/api/billing/checkout", config.api_url()?))
         .header("Authorization", format!("Bearer {}", token))
         .json(&CheckoutRequest { plan: plan.clone() })
         .send()
@@ -202,7 +266,7 @@ pub async fn portal() -> Result<()> {
 
     let client = reqwest::Client::new();
     let response = client
-        .get(format!("{}/api/billing/portal", config.server_url))
+        .get(format!("{}/api/billing/portal", config.api_url()?))
         .header("Authorization", format!("Bearer {}", token))
         .send()
         .await?;
@@ -251,3 +315,50 @@ pub async fn portal() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_json_schema() {
+        let usage: UsageResponse = serde_json::from_str(
+            r#"{"plan":"hobby","bandwidth_bytes":13421772800,"ingress_bytes":1073741824,"egress_bytes":12348030976,
+                "bandwidth_limit":53687091200,"plan_expires_at":"2026-11-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+        let entry = serde_json::to_value(UsageEntry::new(&usage)).unwrap();
+
+        let mut keys: Vec<&str> = entry.as_object().unwrap().keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            [
+                "bandwidth_bytes",
+                "bandwidth_limit",
+                "egress_bytes",
+                "ingress_bytes",
+                "percent_used",
+                "plan",
+                "plan_expires_at"
+            ]
+        );
+        assert_eq!(entry["plan"], "hobby");
+        assert_eq!(entry["bandwidth_bytes"], 13_421_772_800u64);
+        assert_eq!(entry["bandwidth_limit"], 53_687_091_200u64);
+        assert_eq!(entry["percent_used"], 25.0);
+        assert_eq!(entry["plan_expires_at"], "2026-11-01T00:00:00Z");
+        assert_eq!(entry["ingress_bytes"], 1_073_741_824u64);
+        assert_eq!(entry["egress_bytes"], 12_348_030_976u64);
+
+        // A free plan never expires, and an older server doesn't split usage
+        let free: UsageResponse = serde_json::from_str(
+            r#"{"plan":"free","bandwidth_bytes":1,"bandwidth_limit":3,"plan_expires_at":null}"#,
+        )
+        .unwrap();
+        let entry = serde_json::to_value(UsageEntry::new(&free)).unwrap();
+        assert!(entry["plan_expires_at"].is_null());
+        assert_eq!(entry["ingress_bytes"], 0);
+        assert_eq!(entry["percent_used"], 33.33);
+    }
+}
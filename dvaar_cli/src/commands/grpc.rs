@@ -0,0 +1,118 @@
+//! gRPC tunnel command
+//!
+//! `dvaar grpc` is `dvaar http` with the settings a gRPC server needs: HTTP/2
+//! from the first byte (gRPC servers rarely speak HTTP/1.1), no response
+//! timeout so server-streaming calls can stay quiet between messages, and no
+//! retries, since a call may not be safe to send twice. Bodies are relayed as
+//! raw bytes and `grpc-status`/`grpc-message` arrive as HTTP/2 trailers, which
+//! the tunnel forwards like any other response.
+
+use super::http::{self, HttpOptions};
+use anyhow::Result;
+use dvaar_common::constants;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+
+/// gRPC tunnel options
+#[derive(Debug, Clone)]
+pub struct GrpcOptions {
+    pub target: String,
+    pub subdomain: Option<String>,
+    pub detach: bool,
+    pub use_tls: bool,
+    pub insecure_upstream: bool,
+    pub upstream_ca: Option<PathBuf>,
+    pub upstream_sni: Option<String>,
+    pub connect_timeout: u64,
+    pub inspect_port: Option<u16>,
+    pub tui_mode: bool,
+}
+
+impl GrpcOptions {
+    /// The equivalent `dvaar http` options
+    fn http_options(self) -> HttpOptions {
+        HttpOptions {
+            target: self.target,
+            subdomain: self.subdomain,
+            auth: None,
+            host_header: None,
+            detach: self.detach,
+            use_tls: self.use_tls,
+            insecure_upstream: self.insecure_upstream,
+            upstream_ca: self.upstream_ca,
+            upstream_sni: self.upstream_sni,
+            upstream_client_cert: None,
+            upstream_client_key: None,
+            upstream_http2: true,
+            offline_page: None,
+            connect_timeout: self.connect_timeout,
+            // Zero never gives up on a silent stream
+            response_timeout: 0,
+            retry_upstream: 0,
+            pool_max_idle: constants::UPSTREAM_POOL_MAX_IDLE_PER_HOST,
+            log_json: false,
+            wildcard: false,
+            check_upstream: false,
+            region: None,
+            allowed_methods: Vec::new(),
+            denied_paths: Vec::new(),
+            mocks: Vec::new(),
+            mock_statuses: Vec::new(),
+            mock_delays: Vec::new(),
+            cors: false,
+            cors_origins: Vec::new(),
+            replacements: Vec::new(),
+            inspect_port: self.inspect_port,
+            inspect_port_fixed: false,
+            inspect_bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            inspect_memory_mb: crate::inspector::DEFAULT_INSPECT_MEMORY_MB,
+            tui_mode: self.tui_mode,
+            qr: true,
+            open: false,
+            no_forwarded_headers: false,
+            forward_only: None,
+            // Identical unary calls must each reach the server
+            coalesce: false,
+            ping_interval: constants::WS_PING_INTERVAL_SECONDS,
+            pong_timeout: constants::WS_PONG_TIMEOUT_SECONDS,
+            ws_idle_timeout: 0,
+            session_id: None,
+        }
+    }
+}
+
+/// Handle gRPC tunnel command
+pub async fn run(opts: GrpcOptions) -> Result<()> {
+    http::run(opts.http_options()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grpc_preset() {
+        let opts = GrpcOptions {
+            target: "50051".to_string(),
+            subdomain: Some("api".to_string()),
+            detach: false,
+            use_tls: true,
+            insecure_upstream: true,
+            upstream_ca: None,
+            upstream_sni: None,
+            connect_timeout: 5,
+            inspect_port: Some(38227),
+            tui_mode: true,
+        }
+        .http_options();
+
+        assert_eq!(opts.target, "50051");
+        assert_eq!(opts.subdomain.as_deref(), Some("api"));
+        assert!(opts.use_tls && opts.insecure_upstream);
+        assert!(opts.upstream_http2);
+        assert_eq!(opts.connect_timeout, 5);
+        assert_eq!(opts.response_timeout, 0);
+        assert_eq!(opts.retry_upstream, 0);
+        assert!(!opts.coalesce);
+    }
+}
@@ -1,15 +1,28 @@
 //! HTTP tunnel command
 
 use crate::config::{generate_session_id, logs_dir, Config, Session, Sessions};
-use crate::inspector::{find_inspector_port, InspectorClient, InspectorMode, RegisteredTunnel, RequestStore, TunnelStatus};
+use crate::inspector::port::reachable_ip;
+use crate::inspector::{
+    find_inspector_port, fixed_inspector_port, InspectorClient, InspectorMode, RegisteredTunnel, RequestStore,
+    TunnelStatus,
+};
 use crate::tunnel::client::TunnelClient;
+use crate::tunnel::request_log::RequestLogFormat;
+use crate::tunnel::upstream_tls::UpstreamCerts;
 use anyhow::{Context, Result};
 use chrono::Utc;
 use console::style;
-use std::net::SocketAddr;
+use dvaar_client::cors::CorsPolicy;
+use dvaar_client::mock::{MockRule, Mocks};
+use dvaar_client::rewrite::{BodyRewriter, Replacement};
+use dvaar_common::constants;
+use std::io::IsTerminal;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
 /// HTTP tunnel options
@@ -21,10 +34,70 @@ pub struct HttpOptions {
     pub host_header: Option<String>,
     pub detach: bool,
     pub use_tls: bool,
+    pub insecure_upstream: bool,
+    pub upstream_ca: Option<PathBuf>,
+    /// TLS server name for the upstream, when it isn't the target's host
+    pub upstream_sni: Option<String>,
+    /// Client certificate and key for upstreams that require mTLS
+    pub upstream_client_cert: Option<PathBuf>,
+    pub upstream_client_key: Option<PathBuf>,
+    pub upstream_http2: bool,
+    pub offline_page: Option<PathBuf>,
+    pub connect_timeout: u64,
+    pub response_timeout: u64,
+    pub retry_upstream: u32,
+    pub pool_max_idle: usize,
+    pub log_json: bool,
+    pub wildcard: bool,
+    /// Warn at connect if the upstream isn't listening
+    pub check_upstream: bool,
+    /// Region the server should serve the tunnel from
+    pub region: Option<String>,
+    /// Methods the server lets through; empty allows all
+    pub allowed_methods: Vec<String>,
+    /// Path globs the server refuses
+    pub denied_paths: Vec<String>,
+    /// `--mock` rules, checked before `--mock-status` and `--mock-delay`
+    pub mocks: Vec<String>,
+    pub mock_statuses: Vec<String>,
+    pub mock_delays: Vec<String>,
+    pub cors: bool,
+    /// Origins `--cors` allows; empty allows any
+    pub cors_origins: Vec<String>,
+    /// `--replace` rules for text response bodies
+    pub replacements: Vec<String>,
     pub inspect_port: Option<u16>,
+    /// Use `inspect_port` itself rather than the first free port from it
+    pub inspect_port_fixed: bool,
+    /// Address the inspector listens on, when this tunnel hosts it
+    pub inspect_bind: IpAddr,
+    /// Memory budget for the inspector's captures, when this tunnel hosts it
+    pub inspect_memory_mb: usize,
     pub tui_mode: bool,
+    pub qr: bool,
+    /// Open the public URL in a browser on the first connect
+    pub open: bool,
+    pub no_forwarded_headers: bool,
+    /// Request headers the upstream may see, when restricted
+    pub forward_only: Option<Vec<String>>,
+    pub coalesce: bool,
+    pub ping_interval: u64,
+    pub pong_timeout: u64,
+    pub ws_idle_timeout: u64,
+    /// Set in the detached child; it records itself under this session ID
+    pub session_id: Option<String>,
 }
 
+/// Session URL until the tunnel has connected
+pub(crate) const CONNECTING: &str = "Connecting...";
+
+/// How long `--detach` waits for the background tunnel to report its URL
+const DETACH_STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Environment variable the detached child reads `--auth` from, keeping the
+/// credentials out of its command line
+const DETACHED_AUTH_ENV: &str = "DVAAR_DETACHED_AUTH";
+
 /// Handle HTTP tunnel command
 pub async fn run(opts: HttpOptions) -> Result<()> {
     let config = Config::load()?;
@@ -33,9 +106,42 @@ pub async fn run(opts: HttpOptions) -> Result<()> {
     // Parse target
     let (target_addr, static_dir) = parse_target(&opts.target)?;
 
+    // Read the offline page up front so a bad path fails before we connect
+    let offline_page = opts.offline_page.as_deref().map(read_offline_page).transpose()?;
+    let upstream_certs = upstream_certs(&opts)?;
+    let mocks = mock_rules(&opts)?;
+    let rewriter = body_rewriter(&opts)?;
+
+    // Resolve `@file` and `env:VAR` values now so a missing source fails
+    // before we connect. The detached child gets the --host-header source
+    // and the --auth value in its environment, never a credential in argv.
+    let auth = opts.auth.as_deref().map(|value| resolve_value("--auth", value)).transpose()?;
+    let host_header = opts
+        .host_header
+        .as_deref()
+        .map(|value| resolve_value("--host-header", value))
+        .transpose()?;
+    if opts.session_id.is_none() && opts.auth.as_deref().is_some_and(is_literal) {
+        eprintln!(
+            "{} {}",
+            style("WARNING:").red().bold(),
+            style("--auth on the command line is visible in shell history and `ps`. Use --auth @file or --auth env:VAR instead.")
+                .yellow()
+        );
+    }
+
+    // Likewise options the plan doesn't cover, and a subdomain we can't have,
+    // without a round trip through the handshake
+    if let Some(features) = fetch_features(&config.api_url()?, token).await {
+        check_plan(&features, opts.subdomain.is_some(), opts.wildcard)?;
+    }
+    if let Some(subdomain) = &opts.subdomain {
+        check_subdomain(&config.api_url()?, token, subdomain).await?;
+    }
+
     // If detaching, spawn background process
     if opts.detach {
-        return spawn_background(opts).await;
+        return spawn_background(opts, auth).await;
     }
 
     // Start static file server if needed
@@ -59,11 +165,27 @@ pub async fn run(opts: HttpOptions) -> Result<()> {
     // Determine inspector mode if inspector is enabled
     let (inspector_store, inspector_client, actual_inspect_port, _inspector_handle) =
         if let Some(port) = opts.inspect_port {
-            match find_inspector_port(port).await? {
+            let mode = if opts.inspect_port_fixed {
+                fixed_inspector_port(opts.inspect_bind, port).await?
+            } else {
+                find_inspector_port(opts.inspect_bind, port).await?
+            };
+            match mode {
                 InspectorMode::Server(actual_port) => {
                     // We're the first tunnel - start the inspector server
-                    let store = Arc::new(RequestStore::new());
-                    let handle = crate::inspector::start_server(actual_port, store.clone()).await?;
+                    let store = Arc::new(RequestStore::with_memory_budget(opts.inspect_memory_mb * 1024 * 1024));
+                    let handle = crate::inspector::start_server(opts.inspect_bind, actual_port, store.clone()).await?;
+                    if !opts.inspect_bind.is_loopback() && opts.session_id.is_none() {
+                        eprintln!(
+                            "{} {}",
+                            style("WARNING:").red().bold(),
+                            style(format!(
+                                "The inspector is listening on {}; anyone who can reach it sees and can replay captured requests, headers and bodies included.",
+                                SocketAddr::new(opts.inspect_bind, actual_port)
+                            ))
+                            .yellow()
+                        );
+                    }
 
                     // Register ourselves as the primary tunnel
                     // (public_url will be set after connection)
@@ -81,7 +203,7 @@ pub async fn run(opts: HttpOptions) -> Result<()> {
                 }
                 InspectorMode::Client(actual_port) => {
                     // Inspector already running - connect as client
-                    let client = InspectorClient::new(actual_port, tunnel_id.clone());
+                    let client = InspectorClient::new(opts.inspect_bind, actual_port, tunnel_id.clone());
                     (None, Some(client), Some(actual_port), None)
                 }
             }
@@ -90,7 +212,7 @@ pub async fn run(opts: HttpOptions) -> Result<()> {
         };
 
     let mut client = TunnelClient::new(
-        &config.websocket_url(),
+        &config.websocket_url()?,
         token,
         opts.subdomain.clone(),
         actual_target.clone(),
@@ -98,19 +220,64 @@ pub async fn run(opts: HttpOptions) -> Result<()> {
 
     // Set user info from config
     client.set_user_info(config.user_email.clone(), config.user_plan.clone());
+    if let Some(url) = std::env::var("DVAAR_ADS_URL").ok().or_else(|| config.ads_url.clone()) {
+        client.set_ads_url(url);
+    }
 
     // Handle basic auth if provided
-    if let Some(auth) = &opts.auth {
+    if let Some(auth) = &auth {
         client.set_basic_auth(auth);
     }
 
     // Handle host header override
-    if let Some(host) = &opts.host_header {
+    if let Some(host) = &host_header {
         client.set_host_header(host);
     }
 
     // Set TLS mode
     client.set_upstream_tls(opts.use_tls);
+    client.set_upstream_certs(upstream_certs);
+    if let Some(name) = &opts.upstream_sni {
+        client.set_upstream_sni(name);
+    }
+    client.set_upstream_http2(opts.upstream_http2);
+
+    if let Some(html) = offline_page {
+        client.set_offline_page(html);
+    }
+    client.set_wildcard(opts.wildcard);
+    client.set_check_upstream(opts.check_upstream);
+    if let Some(region) = &opts.region {
+        client.set_region(region.clone());
+    }
+    client.set_access_rules(opts.allowed_methods.clone(), opts.denied_paths.clone());
+    client.set_mocks(mocks);
+    if opts.cors {
+        client.set_cors(CorsPolicy::new(opts.cors_origins.clone()));
+    }
+    client.set_rewriter(rewriter);
+    if opts.log_json {
+        client.set_request_log_format(RequestLogFormat::Json);
+    }
+    client.set_upstream_timeouts(
+        Duration::from_secs(opts.connect_timeout),
+        Duration::from_secs(opts.response_timeout),
+    );
+    client.set_upstream_pool_max_idle(opts.pool_max_idle);
+
+    client.set_keepalive(
+        Duration::from_secs(opts.ping_interval),
+        Duration::from_secs(opts.pong_timeout),
+    );
+    client.set_show_qr(opts.qr);
+    client.set_wire_format(dvaar_common::WireFormat::from_env());
+    client.set_forwarded_headers(!opts.no_forwarded_headers);
+    if let Some(ref names) = opts.forward_only {
+        client.set_forward_only(names.clone());
+    }
+    client.set_coalesce(opts.coalesce);
+    client.set_retry_upstream(opts.retry_upstream);
+    client.set_ws_idle_timeout(Duration::from_secs(opts.ws_idle_timeout));
 
     // Set inspector store or client
     if let Some(store) = inspector_store {
@@ -119,12 +286,64 @@ pub async fn run(opts: HttpOptions) -> Result<()> {
     if let Some(inspector_client) = inspector_client {
         client.set_inspector_client(inspector_client);
     }
+    if let Some(port) = actual_inspect_port {
+        client.set_inspector_addr(SocketAddr::new(reachable_ip(opts.inspect_bind), port).to_string());
+    }
+
+    // A wildcard bind is still reachable on localhost; a specific address isn't
+    match opts.inspect_bind {
+        ip if ip.is_loopback() || ip.is_unspecified() => {}
+        IpAddr::V4(ip) => client.set_inspector_host(ip.to_string()),
+        IpAddr::V6(ip) => client.set_inspector_host(format!("[{}]", ip)),
+    }
 
     // Set tunnel ID for registration
     client.set_tunnel_id(tunnel_id);
 
+    // A detached tunnel keeps its own session file, filling in the URL once
+    // connected and removing the file when it exits
+    let session = opts.session_id.clone().map(|id| Session {
+        id,
+        pid: std::process::id(),
+        command: format!("http {}", opts.target),
+        url: CONNECTING.to_string(),
+        target: opts.target.clone(),
+        started_at: Utc::now(),
+        subdomain: opts.subdomain.clone(),
+        inspector_port: actual_inspect_port,
+    });
+    if let Some(session) = &session {
+        Sessions::load()?.add(session.clone())?;
+        let session = session.clone();
+        client.set_on_connected(move |url| {
+            let session = Session {
+                url: url.to_string(),
+                ..session.clone()
+            };
+            if let Err(e) = Sessions::load().and_then(|mut sessions| sessions.add(session)) {
+                tracing::warn!("Failed to update session file: {:#}", e);
+            }
+        });
+    } else if opts.open && std::io::stdout().is_terminal() {
+        // Only a session is detached; with no terminal there's likely no one
+        // at a browser either
+        client.set_on_connected(open_once(|url| open::that(url)));
+    }
+
     // Run the tunnel
-    let result = client.run(actual_inspect_port, opts.tui_mode).await;
+    let result = match &session {
+        Some(session) => {
+            let result = tokio::select! {
+                result = client.run(actual_inspect_port, opts.tui_mode) => result,
+                _ = shutdown_signal() => Ok(()),
+            };
+            if let Err(e) = Sessions::load().and_then(|mut sessions| sessions.remove(&session.id)) {
+                tracing::warn!("Failed to remove session file: {:#}", e);
+            }
+            result
+        }
+        None => client.run(actual_inspect_port, opts.tui_mode).await,
+    };
 
     if let Err(e) = result {
         if opts.tui_mode {
@@ -138,6 +357,37 @@ pub async fn run(opts: HttpOptions) -> Result<()> {
     Ok(())
 }
 
+/// `--open`'s connect hook: hands the public URL to `open` the first time the
+/// tunnel comes up, and not again when it reconnects
+fn open_once(open: impl Fn(&str) -> std::io::Result<()> + Send + Sync + 'static) -> impl Fn(&str) + Send + Sync + 'static {
+    let opened = AtomicBool::new(false);
+    move |url| {
+        if !opened.swap(true, Ordering::Relaxed) {
+            if let Err(e) = open(url) {
+                tracing::warn!("Failed to open {} in a browser: {}", url, e);
+            }
+        }
+    }
+}
+
+/// Resolves once the process is asked to stop: SIGTERM from `dvaar stop`, or Ctrl+C
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            return;
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+}
+
 /// Parse the target argument
 fn parse_target(target: &str) -> Result<(String, Option<PathBuf>)> {
     // Check if it's a path (static file serving)
@@ -163,6 +413,95 @@ fn parse_target(target: &str) -> Result<(String, Option<PathBuf>)> {
     Ok((format!("{}:80", target), None))
 }
 
+/// What the caller's plan allows, as `GET /api/features` reports it
+#[derive(Debug, serde::Deserialize)]
+struct PlanFeatures {
+    plan: String,
+    custom_subdomains: bool,
+    reserved_subdomains: bool,
+}
+
+/// Ask the server what the caller's plan allows. `None` if it can't say (or
+/// predates the endpoint), leaving the handshake to refuse what it must.
+async fn fetch_features(api_url: &str, token: &str) -> Option<PlanFeatures> {
+    let response = reqwest::Client::new()
+        .get(format!("{}/api/features", api_url))
+        .header("Authorization", format!("Bearer {}", token))
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await;
+    match response {
+        Ok(response) if response.status().is_success() => response.json().await.ok(),
+        Ok(response) => {
+            tracing::debug!("Features check returned {}", response.status());
+            None
+        }
+        Err(e) => {
+            tracing::debug!("Features check failed: {}", e);
+            None
+        }
+    }
+}
+
+/// Refuse options the plan doesn't include, naming the way out
+fn check_plan(features: &PlanFeatures, subdomain: bool, wildcard: bool) -> Result<()> {
+    if subdomain && !features.custom_subdomains {
+        anyhow::bail!(
+            "Choosing a subdomain isn't included in the {} plan. Upgrade to Hobby ($5/mo) with: dvaar upgrade",
+            features.plan
+        );
+    }
+    if wildcard && !features.reserved_subdomains {
+        anyhow::bail!(
+            "--wildcard needs a reserved subdomain, which the {} plan doesn't include. Upgrade with: dvaar upgrade",
+            features.plan
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SubdomainCheckResponse {
+    available: bool,
+    reason: Option<String>,
+}
+
+/// Ask the server whether `subdomain` can be had. Only a definite no is an
+/// error; if the server can't answer (or predates the endpoint) the handshake
+/// still has the final word.
+async fn check_subdomain(api_url: &str, token: &str, subdomain: &str) -> Result<()> {
+    let response = reqwest::Client::new()
+        .get(format!("{}/api/subdomains/check", api_url))
+        .query(&[("name", subdomain)])
+        .header("Authorization", format!("Bearer {}", token))
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await;
+    let check: SubdomainCheckResponse = match response {
+        Ok(response) if response.status().is_success() => match response.json().await {
+            Ok(check) => check,
+            Err(_) => return Ok(()),
+        },
+        Ok(response) => {
+            tracing::debug!("Subdomain check returned {}", response.status());
+            return Ok(());
+        }
+        Err(e) => {
+            tracing::debug!("Subdomain check failed: {}", e);
+            return Ok(());
+        }
+    };
+
+    if !check.available {
+        anyhow::bail!(
+            "Subdomain '{}' is not available: {}",
+            subdomain,
+            check.reason.unwrap_or_else(|| "unknown reason".to_string())
+        );
+    }
+    Ok(())
+}
+
 /// Start a static file server for directory serving
 async fn start_static_server(dir: PathBuf) -> Result<StaticServer> {
     use axum::Router;
@@ -191,8 +530,116 @@ struct StaticServer {
     _handle: tokio::task::JoinHandle<()>,
 }
 
-/// Spawn as background process
-async fn spawn_background(opts: HttpOptions) -> Result<()> {
+/// The value of an option that can come from elsewhere: `@path` reads a
+/// file (trailing newline dropped), `env:VAR` an environment variable, and
+/// anything else is taken as is
+fn resolve_value(option: &str, value: &str) -> Result<String> {
+    let resolved = if let Some(path) = value.strip_prefix('@') {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {} from {}", option, path))?
+            .trim_end_matches(['\r', '\n'])
+            .to_string()
+    } else if let Some(var) = value.strip_prefix("env:") {
+        std::env::var(var).with_context(|| format!("{} refers to env:{}, which isn't set", option, var))?
+    } else {
+        return Ok(value.to_string());
+    };
+    if resolved.is_empty() {
+        anyhow::bail!("{} from {} is empty", option, value);
+    }
+    Ok(resolved)
+}
+
+fn is_literal(value: &str) -> bool {
+    !value.starts_with('@') && !value.starts_with("env:")
+}
+
+/// How to pass an option's value on to the detached child, which may not
+/// share our working directory
+fn value_source_arg(value: &str) -> String {
+    match value.strip_prefix('@').map(std::fs::canonicalize) {
+        Some(Ok(path)) => format!("@{}", path.display()),
+        _ => value.to_string(),
+    }
+}
+
+/// Certificate checks for the upstream from `--insecure-upstream` and
+/// `--upstream-ca`, and the client certificate to present to it
+fn upstream_certs(opts: &HttpOptions) -> Result<UpstreamCerts> {
+    let mut certs = if opts.insecure_upstream {
+        eprintln!(
+            "{} {}",
+            style("WARNING:").red().bold(),
+            style("--insecure-upstream accepts any certificate from the upstream. Use it only for local development.")
+                .yellow()
+        );
+        UpstreamCerts::insecure()
+    } else {
+        UpstreamCerts::default()
+    };
+    if let Some(path) = &opts.upstream_ca {
+        let pem = std::fs::read(path).with_context(|| format!("Failed to read CA file {}", path.display()))?;
+        certs = certs
+            .with_ca_pem(&pem)
+            .with_context(|| format!("Invalid CA file {}", path.display()))?;
+    }
+    if let (Some(cert_path), Some(key_path)) = (&opts.upstream_client_cert, &opts.upstream_client_key) {
+        let cert = std::fs::read(cert_path)
+            .with_context(|| format!("Failed to read client certificate {}", cert_path.display()))?;
+        let key =
+            std::fs::read(key_path).with_context(|| format!("Failed to read client key {}", key_path.display()))?;
+        certs = certs
+            .with_client_identity(&cert, &key)
+            .with_context(|| format!("Invalid client certificate {}", cert_path.display()))?;
+    }
+    Ok(certs)
+}
+
+type MockParser = fn(&str) -> Result<MockRule, String>;
+
+/// The `--mock`, `--mock-status` and `--mock-delay` rules, in that order
+fn mock_rules(opts: &HttpOptions) -> Result<Mocks> {
+    let parsers: [(&str, &[String], MockParser); 3] = [
+        ("--mock", &opts.mocks, MockRule::parse_mock),
+        ("--mock-status", &opts.mock_statuses, MockRule::parse_status),
+        ("--mock-delay", &opts.mock_delays, MockRule::parse_delay),
+    ];
+    let mut rules = Vec::new();
+    for (flag, values, parse) in parsers {
+        for value in values {
+            rules.push(parse(value).map_err(|e| anyhow::anyhow!("Invalid {} rule '{}': {}", flag, value, e))?);
+        }
+    }
+    Ok(Mocks::new(rules))
+}
+
+/// Parse the `--replace` rules
+fn body_rewriter(opts: &HttpOptions) -> Result<BodyRewriter> {
+    let rules = opts
+        .replacements
+        .iter()
+        .map(|rule| Replacement::parse(rule).map_err(|e| anyhow::anyhow!("Invalid --replace rule '{}': {}", rule, e)))
+        .collect::<Result<_>>()?;
+    Ok(BodyRewriter::new(rules))
+}
+
+/// Load the HTML shown by the server while this tunnel is offline
+fn read_offline_page(path: &std::path::Path) -> Result<String> {
+    let html = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read offline page {}", path.display()))?;
+    if html.len() > constants::MAX_OFFLINE_PAGE_BYTES {
+        anyhow::bail!(
+            "Offline page {} is too large ({} bytes, max {})",
+            path.display(),
+            html.len(),
+            constants::MAX_OFFLINE_PAGE_BYTES
+        );
+    }
+    Ok(html)
+}
+
+/// Spawn as background process. `auth` is the resolved `--auth` value.
+async fn spawn_background(opts: HttpOptions, auth: Option<String>) -> Result<()> {
     use cliclack::{intro, outro, note};
 
     intro(style(" dvaar ").on_cyan().black().to_string())?;
@@ -203,32 +650,143 @@ async fn spawn_background(opts: HttpOptions) -> Result<()> {
     let session_id = generate_session_id();
     let log_file = logs_dir().join(format!("{}.log", session_id));
 
-    // Build command args (without -d flag, with --no-tui for background mode)
-    let mut args = vec!["http".to_string(), opts.target.clone(), "--no-tui".to_string()];
+    // Build command args (without -d flag, with --no-tui for background mode).
+    // Requests are logged as JSON so `dvaar logs` can render them
+    let mut args = vec![
+        "http".to_string(),
+        opts.target.clone(),
+        "--no-tui".to_string(),
+        "--log-json".to_string(),
+        format!("--session-id={}", session_id),
+    ];
 
     if let Some(subdomain) = &opts.subdomain {
         args.push("--subdomain".to_string());
         args.push(subdomain.clone());
     }
 
-    if let Some(auth) = &opts.auth {
+    if auth.is_some() {
         args.push("--auth".to_string());
-        args.push(auth.clone());
+        args.push(format!("env:{}", DETACHED_AUTH_ENV));
     }
 
     if let Some(host) = &opts.host_header {
         args.push("--host-header".to_string());
-        args.push(host.clone());
+        args.push(value_source_arg(host));
     }
 
     if opts.use_tls {
         args.push("--use-tls".to_string());
     }
 
-    if let Some(port) = opts.inspect_port {
-        args.push(format!("--inspect={}", port));
+    if opts.insecure_upstream {
+        args.push("--insecure-upstream".to_string());
     }
 
+    if let Some(path) = &opts.upstream_ca {
+        let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+        args.push("--upstream-ca".to_string());
+        args.push(path.display().to_string());
+    }
+
+    if let Some(name) = &opts.upstream_sni {
+        args.push(format!("--upstream-sni={}", name));
+    }
+
+    for (flag, path) in [
+        ("--upstream-client-cert", &opts.upstream_client_cert),
+        ("--upstream-client-key", &opts.upstream_client_key),
+    ] {
+        if let Some(path) = path {
+            let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+            args.push(flag.to_string());
+            args.push(path.display().to_string());
+        }
+    }
+
+    if opts.upstream_http2 {
+        args.push("--upstream-http2".to_string());
+    }
+
+    if let Some(path) = &opts.offline_page {
+        // The child may not share our working directory
+        let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+        args.push("--offline-page".to_string());
+        args.push(path.display().to_string());
+    }
+
+    args.push(format!("--connect-timeout={}", opts.connect_timeout));
+    args.push(format!("--response-timeout={}", opts.response_timeout));
+    args.push(format!("--retry-upstream={}", opts.retry_upstream));
+    args.push(format!("--pool-max-idle={}", opts.pool_max_idle));
+
+    if opts.wildcard {
+        args.push("--wildcard".to_string());
+    }
+    if opts.check_upstream {
+        args.push("--check-upstream".to_string());
+    }
+
+    if let Some(region) = &opts.region {
+        args.push(format!("--region={}", region));
+    }
+
+    if !opts.allowed_methods.is_empty() {
+        args.push(format!("--allow-method={}", opts.allowed_methods.join(",")));
+    }
+    for glob in &opts.denied_paths {
+        args.push(format!("--deny-path={}", glob));
+    }
+    for rule in &opts.mocks {
+        args.push(format!("--mock={}", rule));
+    }
+    for rule in &opts.mock_statuses {
+        args.push(format!("--mock-status={}", rule));
+    }
+    for rule in &opts.mock_delays {
+        args.push(format!("--mock-delay={}", rule));
+    }
+
+    if opts.cors {
+        args.push("--cors".to_string());
+    }
+    if !opts.cors_origins.is_empty() {
+        args.push(format!("--cors-origin={}", opts.cors_origins.join(",")));
+    }
+    for rule in &opts.replacements {
+        args.push(format!("--replace={}", rule));
+    }
+
+    match opts.inspect_port {
+        Some(port) if opts.inspect_port_fixed => args.push(format!("--inspect-port={}", port)),
+        Some(port) => args.push(format!("--inspect={}", port)),
+        None => args.push("--no-inspect".to_string()),
+    }
+    if opts.inspect_port.is_some() {
+        args.push(format!("--inspect-bind={}", opts.inspect_bind));
+    }
+    args.push(format!("--inspect-memory-mb={}", opts.inspect_memory_mb));
+
+    if !opts.qr {
+        args.push("--qr=false".to_string());
+    }
+
+    if opts.no_forwarded_headers {
+        args.push("--no-forwarded-headers".to_string());
+    }
+
+    if let Some(ref names) = opts.forward_only {
+        args.push(format!("--forward-only={}", names.join(",")));
+    }
+
+    if opts.coalesce {
+        args.push("--coalesce".to_string());
+    }
+
+    args.push(format!("--ping-interval={}", opts.ping_interval));
+    args.push(format!("--pong-timeout={}", opts.pong_timeout));
+    args.push(format!("--ws-idle-timeout={}", opts.ws_idle_timeout));
+
     // Get current executable
     let exe = std::env::current_exe().context("Failed to get current executable")?;
 
@@ -240,34 +798,51 @@ async fn spawn_background(opts: HttpOptions) -> Result<()> {
     let log_err = log.try_clone()?;
 
     // Spawn child process
-    let child = Command::new(&exe)
+    let mut command = Command::new(&exe);
+    command
         .args(&args)
         .stdout(Stdio::from(log))
         .stderr(Stdio::from(log_err))
-        .stdin(Stdio::null())
-        .spawn()
-        .context("Failed to spawn background process")?;
-
-    let pid = child.id();
-
-    // Wait a moment and check the log for the URL
-    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-
-    let url = read_url_from_log(&log_file).unwrap_or_else(|| "Connecting...".to_string());
-
-    // Save session
-    let session = Session {
-        id: session_id.clone(),
-        pid,
-        command: format!("http {}", opts.target),
-        url: url.clone(),
-        target: opts.target.clone(),
-        started_at: Utc::now(),
+        .stdin(Stdio::null());
+    if let Some(auth) = &auth {
+        command.env(DETACHED_AUTH_ENV, auth);
+    }
+    // Out of the terminal's process group, so Ctrl+C or closing the shell
+    // doesn't take the tunnel down with it
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const DETACHED_PROCESS: u32 = 0x0000_0008;
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+        command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+    }
+    let mut child = command.spawn().context("Failed to spawn background process")?;
+
+    // The child records itself in its session file; wait for it to connect
+    let deadline = tokio::time::Instant::now() + DETACH_STARTUP_TIMEOUT;
+    let url = loop {
+        if let Some(status) = child.try_wait()? {
+            spinner.error("Background tunnel exited");
+            anyhow::bail!("Tunnel process exited ({}), see {}", status, log_file.display());
+        }
+        let url = Sessions::load()?
+            .find(&session_id)
+            .map(|session| session.url.clone())
+            .filter(|url| url != CONNECTING);
+        if let Some(url) = url {
+            break url;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            break CONNECTING.to_string();
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
     };
 
-    let mut sessions = Sessions::load()?;
-    sessions.add(session)?;
-
     spinner.stop("Background tunnel started");
 
     // Display session info
@@ -290,18 +865,72 @@ async fn spawn_background(opts: HttpOptions) -> Result<()> {
     Ok(())
 }
 
-fn read_url_from_log(path: &PathBuf) -> Option<String> {
-    let content = std::fs::read_to_string(path).ok()?;
-    for line in content.lines() {
-        if line.contains("https://") && line.contains(".dvaar.") {
-            // Extract URL
-            let start = line.find("https://")?;
-            let end = line[start..]
-                .find(|c: char| c.is_whitespace())
-                .map(|i| start + i)
-                .unwrap_or(line.len());
-            return Some(line[start..end].to_string());
-        }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_open_once() {
+        let opened = Arc::new(Mutex::new(Vec::new()));
+        let hook = open_once({
+            let opened = opened.clone();
+            move |url| {
+                opened.lock().unwrap().push(url.to_string());
+                Ok(())
+            }
+        });
+
+        hook("https://myapp.dvaar.app");
+        // A reconnect doesn't open another tab
+        hook("https://myapp.dvaar.app");
+        assert_eq!(*opened.lock().unwrap(), ["https://myapp.dvaar.app"]);
+    }
+
+    #[test]
+    fn test_plan_checks_follow_the_features() {
+        let free: PlanFeatures = serde_json::from_str(
+            r#"{"plan":"free","custom_subdomains":false,"custom_domains":false,"reserved_subdomains":false,"max_concurrent":5,"bandwidth":1073741824}"#,
+        )
+        .unwrap();
+        let hobby: PlanFeatures = serde_json::from_str(
+            r#"{"plan":"hobby","custom_subdomains":true,"custom_domains":true,"reserved_subdomains":true,"max_concurrent":10,"bandwidth":53687091200}"#,
+        )
+        .unwrap();
+
+        assert!(check_plan(&free, false, false).is_ok());
+        let error = check_plan(&free, true, false).unwrap_err().to_string();
+        assert!(error.contains("free plan") && error.contains("dvaar upgrade"), "{}", error);
+        assert!(check_plan(&free, true, true).is_err());
+        assert!(check_plan(&free, false, true).unwrap_err().to_string().contains("--wildcard"));
+
+        assert!(check_plan(&hobby, true, true).is_ok());
+    }
+
+    #[test]
+    fn test_resolve_value_sources() {
+        assert_eq!(resolve_value("--auth", "user:pass").unwrap(), "user:pass");
+        assert!(is_literal("user:pass"));
+
+        let path = std::env::temp_dir().join(format!("dvaar-auth-{}", Uuid::new_v4()));
+        std::fs::write(&path, "user:from-file\n").unwrap();
+        let source = format!("@{}", path.display());
+        assert_eq!(resolve_value("--auth", &source).unwrap(), "user:from-file");
+        assert!(!is_literal(&source));
+        std::fs::remove_file(&path).unwrap();
+        assert!(resolve_value("--auth", &source).is_err());
+
+        std::env::set_var("DVAAR_TEST_HOST_HEADER", "app.internal");
+        assert_eq!(
+            resolve_value("--host-header", "env:DVAAR_TEST_HOST_HEADER").unwrap(),
+            "app.internal"
+        );
+        assert!(!is_literal("env:DVAAR_TEST_HOST_HEADER"));
+        let unset = resolve_value("--auth", "env:DVAAR_TEST_UNSET_VAR").unwrap_err();
+        assert!(unset.to_string().contains("isn't set"));
+
+        std::env::set_var("DVAAR_TEST_EMPTY", "");
+        assert!(resolve_value("--auth", "env:DVAAR_TEST_EMPTY").is_err());
     }
-    None
 }
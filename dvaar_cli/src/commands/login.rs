@@ -293,7 +293,7 @@ async fn exchange_for_dvaar_token(
     config: &Config,
     github_token: &str,
 ) -> Result<DvaarTokenResponse> {
-    let url = format!("{}/api/auth/token", config.server_url);
+    let url = format!("{}/api/auth/token", config.api_url()?);
 
     let response = client
         .post(&url)
@@ -319,7 +319,7 @@ async fn exchange_for_dvaar_token(
 
 /// Get GitHub client ID from server
 async fn get_github_client_id(client: &reqwest::Client, config: &Config) -> Result<String> {
-    let url = format!("{}/api/auth/config", config.server_url);
+    let url = format!("{}/api/auth/config", config.api_url()?);
 
     let response = client
         .get(&url)
@@ -1,8 +1,11 @@
 //! CLI command handlers
 
 pub mod billing;
+pub mod grpc;
 pub mod http;
 pub mod login;
 pub mod session;
+pub mod share;
+pub mod status;
 pub mod uninstall;
 pub mod update;
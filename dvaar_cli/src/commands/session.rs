@@ -1,14 +1,54 @@
 //! Session management commands (ls, stop, logs)
 
-use crate::config::{logs_dir, Sessions};
+use crate::config::{Session, Sessions};
+use crate::tunnel::request_log::RequestLogLine;
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
 use std::io::{BufRead, BufReader};
 
-/// List all active sessions
-pub async fn list() -> Result<()> {
+/// One session in `dvaar ls --json`
+#[derive(Debug, Serialize)]
+struct SessionEntry<'a> {
+    id: &'a str,
+    subdomain: Option<&'a str>,
+    /// `None` until the tunnel has connected
+    public_url: Option<&'a str>,
+    target: &'a str,
+    pid: u32,
+    /// `running` or `stopped`
+    status: &'static str,
+    started_at: DateTime<Utc>,
+}
+
+impl<'a> SessionEntry<'a> {
+    fn new(session: &'a Session, running: bool) -> Self {
+        Self {
+            id: &session.id,
+            subdomain: session.subdomain.as_deref(),
+            public_url: Some(session.url.as_str()).filter(|url| *url != super::http::CONNECTING),
+            target: &session.target,
+            pid: session.pid,
+            status: if running { "running" } else { "stopped" },
+            started_at: session.started_at,
+        }
+    }
+}
+
+/// List all active sessions, as a table or with `json` as a JSON array
+pub async fn list(json: bool) -> Result<()> {
     let sessions = Sessions::load()?;
     let sessions = sessions.all();
 
+    if json {
+        let entries: Vec<SessionEntry> = sessions
+            .iter()
+            .map(|session| SessionEntry::new(session, is_process_running(session.pid)))
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+
     if sessions.is_empty() {
         println!("No active sessions.");
         println!();
@@ -18,10 +58,10 @@ pub async fn list() -> Result<()> {
 
     // Print header
     println!(
-        "{:<10} {:<30} {:<40} {:<10}",
-        "ID", "COMMAND", "URL", "STARTED"
+        "{:<10} {:<30} {:<40} {:<10} {:<16}",
+        "ID", "COMMAND", "URL", "INSPECTOR", "STARTED"
     );
-    println!("{}", "-".repeat(90));
+    println!("{}", "-".repeat(110));
 
     for session in sessions {
         // Check if process is still running
@@ -36,11 +76,17 @@ pub async fn list() -> Result<()> {
             .format("%Y-%m-%d %H:%M")
             .to_string();
 
+        let inspector = session
+            .inspector_port
+            .map(|port| format!(":{}", port))
+            .unwrap_or_else(|| "-".to_string());
+
         println!(
-            "{:<10} {:<30} {:<40} {:<10}",
+            "{:<10} {:<30} {:<40} {:<10} {:<16}",
             session.id,
             truncate(&session.command, 28),
             truncate(&session.url, 38),
+            inspector,
             started
         );
 
@@ -56,24 +102,15 @@ pub async fn list() -> Result<()> {
 pub async fn stop(id: &str) -> Result<()> {
     let mut sessions = Sessions::load()?;
 
-    let session = sessions
-        .find(id)
-        .ok_or_else(|| anyhow::anyhow!("Session not found: {}", id))?
-        .clone();
-
-    // Kill the process
-    if is_process_running(session.pid) {
-        kill_process(session.pid)?;
+    let (session, was_running) = stop_session(&mut sessions, id)?;
+    if was_running {
         println!("Stopped tunnel: {}", session.url);
     } else {
         println!("Process was already stopped.");
     }
 
-    // Remove from sessions
-    sessions.remove(&session.id)?;
-
     // Optionally clean up log file
-    let log_file = logs_dir().join(format!("{}.log", session.id));
+    let log_file = session.log_file();
     if log_file.exists() {
         println!("Log file: {:?}", log_file);
         println!("(You can delete it manually if no longer needed)");
@@ -82,15 +119,33 @@ pub async fn stop(id: &str) -> Result<()> {
     Ok(())
 }
 
+/// Signal a session's process and drop its session file. The process removes
+/// the file itself on the way out; removing it here as well covers one that
+/// had already died without cleaning up.
+fn stop_session(sessions: &mut Sessions, id: &str) -> Result<(Session, bool)> {
+    let session = sessions
+        .find(id)
+        .ok_or_else(|| anyhow::anyhow!("Session not found: {}", id))?
+        .clone();
+
+    let was_running = is_process_running(session.pid);
+    if was_running {
+        kill_process(session.pid)?;
+    }
+    sessions.remove(&session.id)?;
+
+    Ok((session, was_running))
+}
+
 /// Tail logs for a session
-pub async fn logs(id: &str, follow: bool) -> Result<()> {
+pub async fn logs(id: &str, follow: bool, json: bool) -> Result<()> {
     let sessions = Sessions::load()?;
 
     let session = sessions
         .find(id)
         .ok_or_else(|| anyhow::anyhow!("Session not found: {}", id))?;
 
-    let log_file = logs_dir().join(format!("{}.log", session.id));
+    let log_file = session.log_file();
 
     if !log_file.exists() {
         println!("No log file found for session: {}", id);
@@ -98,12 +153,16 @@ pub async fn logs(id: &str, follow: bool) -> Result<()> {
     }
 
     if follow {
-        // Follow mode - tail the file
-        tail_follow(&log_file).await?;
+        // Follow mode - tail the file until the tunnel exits
+        tail_follow(&log_file, session.pid, json).await?;
     } else {
         // Just read the whole file
         let content = std::fs::read_to_string(&log_file)?;
-        print!("{}", content);
+        for line in content.lines() {
+            if let Some(rendered) = render_log_line(line, json) {
+                println!("{}", rendered);
+            }
+        }
     }
 
     Ok(())
@@ -160,24 +219,42 @@ fn kill_process(pid: u32) -> Result<()> {
     Ok(())
 }
 
-/// Tail a file and follow new content
-async fn tail_follow(path: &std::path::Path) -> Result<()> {
+/// Render one session log line. Request entries are pretty-printed, or passed
+/// through as JSON with `json`; other output (banners, warnings) is kept as is,
+/// except in JSON mode where it would break a line-oriented consumer.
+fn render_log_line(line: &str, json: bool) -> Option<String> {
+    match (RequestLogLine::parse(line), json) {
+        (Some(entry), false) => Some(entry.pretty()),
+        (Some(_), true) => Some(line.to_string()),
+        (None, false) => Some(line.to_string()),
+        (None, true) => None,
+    }
+}
+
+/// Tail a file and follow new content for as long as process `pid` runs
+async fn tail_follow(path: &std::path::Path, pid: u32, json: bool) -> Result<()> {
     let file = std::fs::File::open(path)?;
     let reader = BufReader::new(file);
 
     // First, print existing content
     for line in reader.lines() {
-        println!("{}", line?);
+        if let Some(rendered) = render_log_line(&line?, json) {
+            println!("{}", rendered);
+        }
     }
 
     // Then follow new content
-    println!("--- Following log (Ctrl+C to stop) ---");
+    if !json {
+        println!("--- Following log (Ctrl+C to stop) ---");
+    }
 
     let mut last_pos = std::fs::metadata(path)?.len();
 
     loop {
         tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
 
+        // Checked before reading so the process's last lines are still printed
+        let running = is_process_running(pid);
         let current_size = std::fs::metadata(path)?.len();
 
         if current_size > last_pos {
@@ -189,11 +266,20 @@ async fn tail_follow(path: &std::path::Path) -> Result<()> {
             reader.seek(SeekFrom::Start(last_pos))?;
 
             for line in reader.lines() {
-                println!("{}", line?);
+                if let Some(rendered) = render_log_line(&line?, json) {
+                    println!("{}", rendered);
+                }
             }
 
             last_pos = current_size;
         }
+
+        if !running {
+            if !json {
+                println!("--- Tunnel stopped ---");
+            }
+            return Ok(());
+        }
     }
 }
 
@@ -204,3 +290,136 @@ fn truncate(s: &str, max_len: usize) -> String {
         s.to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn temp_sessions_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("dvaar-sessions-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn session(id: &str, pid: u32) -> Session {
+        Session {
+            id: id.to_string(),
+            pid,
+            command: "http 3000".to_string(),
+            url: "Connecting...".to_string(),
+            target: "3000".to_string(),
+            started_at: Utc::now(),
+            subdomain: Some("demo".to_string()),
+            inspector_port: Some(38227),
+        }
+    }
+
+    #[test]
+    fn test_session_file_created_updated_and_removed() {
+        let dir = temp_sessions_dir();
+        let mut sessions = Sessions::load_from(dir.clone()).unwrap();
+        assert!(sessions.all().is_empty());
+
+        // Written on start, then updated in place once connected
+        sessions.add(session("ab12cd34", 4242)).unwrap();
+        let connected = Session {
+            url: "https://demo.dvaar.app".to_string(),
+            ..session("ab12cd34", 4242)
+        };
+        sessions.add(connected).unwrap();
+        assert!(dir.join("ab12cd34.json").exists());
+
+        let reloaded = Sessions::load_from(dir.clone()).unwrap();
+        assert_eq!(reloaded.all().len(), 1);
+        let found = reloaded.find("ab12").unwrap();
+        assert_eq!(found.pid, 4242);
+        assert_eq!(found.url, "https://demo.dvaar.app");
+        assert_eq!(found.subdomain.as_deref(), Some("demo"));
+        assert_eq!(found.inspector_port, Some(38227));
+
+        // A stray file in the directory doesn't break listing
+        std::fs::write(dir.join("garbage.json"), "not json").unwrap();
+        assert_eq!(Sessions::load_from(dir.clone()).unwrap().all().len(), 1);
+
+        sessions.remove("ab12cd34").unwrap();
+        assert!(!dir.join("ab12cd34.json").exists());
+        assert!(Sessions::load_from(dir.clone()).unwrap().all().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stop_signals_process_and_removes_session() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let dir = temp_sessions_dir();
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        let mut sessions = Sessions::load_from(dir.clone()).unwrap();
+        sessions.add(session("ef56ab78", child.id())).unwrap();
+
+        let (stopped, was_running) = stop_session(&mut sessions, "ef56").unwrap();
+        assert_eq!(stopped.id, "ef56ab78");
+        assert!(was_running);
+        assert_eq!(child.wait().unwrap().signal(), Some(nix::libc::SIGTERM));
+        assert!(Sessions::load_from(dir.clone()).unwrap().all().is_empty());
+
+        // Stopping a session whose process is already gone just cleans up
+        sessions.add(session("ef56ab78", child.id())).unwrap();
+        let (_, was_running) = stop_session(&mut sessions, "ef56ab78").unwrap();
+        assert!(!was_running);
+        assert!(!dir.join("ef56ab78.json").exists());
+        assert!(stop_session(&mut sessions, "ef56ab78").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ls_json_schema() {
+        let connected = Session {
+            url: "https://demo.dvaar.app".to_string(),
+            ..session("ab12cd34", 4242)
+        };
+        let starting = Session {
+            subdomain: None,
+            ..session("ef56ab78", 4343)
+        };
+        let entries = vec![SessionEntry::new(&connected, true), SessionEntry::new(&starting, false)];
+        let value = serde_json::to_value(&entries).unwrap();
+
+        assert_eq!(
+            value[0],
+            serde_json::json!({
+                "id": "ab12cd34",
+                "subdomain": "demo",
+                "public_url": "https://demo.dvaar.app",
+                "target": "3000",
+                "pid": 4242,
+                "status": "running",
+                "started_at": connected.started_at,
+            })
+        );
+        assert_eq!(value[1]["subdomain"], serde_json::Value::Null);
+        assert_eq!(value[1]["public_url"], serde_json::Value::Null);
+        assert_eq!(value[1]["status"], "stopped");
+        // Timestamps are RFC 3339, for other tools to parse
+        let started = value[1]["started_at"].as_str().unwrap();
+        assert!(DateTime::parse_from_rfc3339(started).is_ok());
+    }
+
+    #[test]
+    fn test_render_log_line() {
+        let entry = RequestLogLine::new("GET", "/api/users", 200, Duration::from_millis(12), 340);
+        let json_line = entry.to_json();
+        let banner = "  Tunnel Active: https://demo.dvaar.app";
+
+        let pretty = render_log_line(&json_line, false).unwrap();
+        let pretty = console::strip_ansi_codes(&pretty);
+        assert!(pretty.contains("GET /api/users 200"));
+        assert!(!pretty.contains('{'));
+        assert_eq!(render_log_line(banner, false).as_deref(), Some(banner));
+
+        assert_eq!(render_log_line(&json_line, true), Some(json_line));
+        assert_eq!(render_log_line(banner, true), None);
+    }
+}
@@ -0,0 +1,47 @@
+//! Share command - a link to a read-only view of a tunnel's inspector
+//!
+//! The server signs the link for the tunnel's public URL and lets it expire
+//! after the requested time. Anyone holding it can watch the traffic the
+//! inspector captures until then, but can't replay or clear anything.
+
+use crate::config::Config;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+struct ShareResponse {
+    url: String,
+    expires_at: i64,
+}
+
+/// Ask the server for a share link to `subdomain`'s inspector, valid for `ttl_minutes`
+pub async fn share(subdomain: &str, ttl_minutes: u64) -> Result<()> {
+    let config = Config::load()?;
+    let token = config.require_auth()?;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/api/tunnels/{}/share", config.api_url()?, subdomain))
+        .header("Authorization", format!("Bearer {}", token))
+        .json(&serde_json::json!({ "ttl_secs": ttl_minutes * 60 }))
+        .send()
+        .await
+        .context("Failed to reach server")?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        anyhow::bail!("Failed to create share link: {} - {}", status, text);
+    }
+
+    let link: ShareResponse = response.json().await?;
+    let expires = DateTime::from_timestamp(link.expires_at, 0)
+        .map(|at| at.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| link.expires_at.to_string());
+
+    println!("{}", link.url);
+    println!("Read-only inspector for '{}', valid until {}.", subdomain, expires);
+    println!("Anyone with the link can see the requests it captures.");
+    Ok(())
+}
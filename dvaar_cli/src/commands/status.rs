@@ -0,0 +1,86 @@
+//! Status command - server-side view of the user's live tunnels
+
+use crate::config::Config;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+struct TunnelsResponse {
+    tunnels: Vec<TunnelStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TunnelStatus {
+    subdomain: String,
+    url: String,
+    node_ip: String,
+    region: Option<String>,
+    uptime_secs: Option<u64>,
+}
+
+/// Show tunnels the server currently has registered for this account
+pub async fn status(subdomain: Option<String>) -> Result<()> {
+    let config = Config::load()?;
+    let token = config.require_auth()?;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!("{}/api/tunnels", config.api_url()?))
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .await
+        .context("Failed to reach server")?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        anyhow::bail!("Failed to fetch tunnel status: {} - {}", status, text);
+    }
+
+    let data: TunnelsResponse = response.json().await?;
+    let tunnels: Vec<TunnelStatus> = data
+        .tunnels
+        .into_iter()
+        .filter(|t| subdomain.as_ref().is_none_or(|s| &t.subdomain == s))
+        .collect();
+
+    if tunnels.is_empty() {
+        match subdomain {
+            Some(s) => println!("Tunnel '{}' is not registered on any node.", s),
+            None => println!("No tunnels are currently registered."),
+        }
+        return Ok(());
+    }
+
+    println!(
+        "{:<40} {:<16} {:<10} {:<10}",
+        "URL", "NODE", "REGION", "UPTIME"
+    );
+    println!("{}", "-".repeat(78));
+
+    for tunnel in tunnels {
+        println!(
+            "{:<40} {:<16} {:<10} {:<10}",
+            tunnel.url,
+            tunnel.node_ip,
+            tunnel.region.as_deref().unwrap_or("-"),
+            tunnel.uptime_secs.map(format_uptime).unwrap_or_else(|| "-".to_string()),
+        );
+    }
+
+    Ok(())
+}
+
+/// Format seconds as a short uptime, e.g. "2h 5m"
+fn format_uptime(secs: u64) -> String {
+    let (days, hours, mins) = (secs / 86_400, (secs % 86_400) / 3_600, (secs % 3_600) / 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, mins)
+    } else if mins > 0 {
+        format!("{}m", mins)
+    } else {
+        format!("{}s", secs)
+    }
+}
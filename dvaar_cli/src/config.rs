@@ -28,9 +28,9 @@ pub fn config_file() -> PathBuf {
     config_dir().join("config.yml")
 }
 
-/// Get the sessions file path
-pub fn sessions_file() -> PathBuf {
-    config_dir().join("sessions.json")
+/// Get the directory holding one file per detached session
+pub fn sessions_dir() -> PathBuf {
+    config_dir().join("sessions")
 }
 
 /// Get the logs directory
@@ -66,6 +66,11 @@ pub struct Config {
     /// Server URL (default: https://api.dvaar.io)
     #[serde(default = "default_server_url")]
     pub server_url: String,
+
+    /// Where the TUI fetches sponsor ads (empty to turn them off); derived
+    /// from `server_url` when unset. `DVAAR_ADS_URL` takes precedence.
+    #[serde(default)]
+    pub ads_url: Option<String>,
 }
 
 fn default_server_url() -> String {
@@ -79,6 +84,7 @@ impl Default for Config {
             user_email: None,
             user_plan: None,
             server_url: default_server_url(),
+            ads_url: None,
         }
     }
 }
@@ -130,18 +136,55 @@ impl Config {
         self.user_plan = plan;
     }
 
-    /// Get WebSocket URL from server URL
-    pub fn websocket_url(&self) -> String {
-        let ws_scheme = if self.server_url.starts_with("https://") {
-            "wss"
-        } else {
-            "ws"
+    /// Base URL of the server's REST API
+    pub fn api_url(&self) -> Result<String> {
+        Ok(ServerUrls::parse(&self.server_url)?.api)
+    }
+
+    /// Base URL the tunnel WebSocket connects to
+    pub fn websocket_url(&self) -> Result<String> {
+        Ok(ServerUrls::parse(&self.server_url)?.websocket)
+    }
+}
+
+/// The two forms of the configured server URL
+///
+/// `server_url` may be given with any of `http`, `https`, `ws` or `wss`, or
+/// no scheme at all (HTTPS), with or without a port, a path prefix or a
+/// trailing slash. The secure schemes map to each other, as do the plain
+/// ones, so `ws://localhost:8080/` has its API at `http://localhost:8080`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerUrls {
+    /// e.g. `https://api.dvaar.io`, for `/api/...` requests
+    pub api: String,
+    /// e.g. `wss://api.dvaar.io`, which the client appends `/_dvaar/tunnel` to
+    pub websocket: String,
+}
+
+impl ServerUrls {
+    pub fn parse(server_url: &str) -> Result<Self> {
+        let server_url = server_url.trim();
+        let with_scheme = match server_url.contains("://") {
+            true => server_url.to_string(),
+            false => format!("https://{}", server_url),
         };
-        let host = self
-            .server_url
-            .trim_start_matches("https://")
-            .trim_start_matches("http://");
-        format!("{}://{}", ws_scheme, host)
+        let url = reqwest::Url::parse(&with_scheme)
+            .with_context(|| format!("Invalid server URL '{}'", server_url))?;
+        let (api_scheme, ws_scheme) = match url.scheme() {
+            "https" | "wss" => ("https", "wss"),
+            "http" | "ws" => ("http", "ws"),
+            other => anyhow::bail!("Invalid server URL '{}': unsupported scheme '{}'", server_url, other),
+        };
+        let host = url
+            .host_str()
+            .with_context(|| format!("Invalid server URL '{}': no host", server_url))?;
+        // The scheme's default port is dropped by the parser, and is the same for its pair
+        let port = url.port().map(|port| format!(":{}", port)).unwrap_or_default();
+        let base = format!("{}{}{}", host, port, url.path().trim_end_matches('/'));
+        Ok(Self {
+            api: format!("{}://{}", api_scheme, base),
+            websocket: format!("{}://{}", ws_scheme, base),
+        })
     }
 }
 
@@ -165,51 +208,91 @@ pub struct Session {
 
     /// When the session was started
     pub started_at: DateTime<Utc>,
+
+    /// Requested subdomain, if any
+    #[serde(default)]
+    pub subdomain: Option<String>,
+
+    /// Port of the web inspector the tunnel reports to
+    #[serde(default)]
+    pub inspector_port: Option<u16>,
 }
 
-/// Sessions registry
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+impl Session {
+    /// File the session's output is written to
+    pub fn log_file(&self) -> PathBuf {
+        logs_dir().join(format!("{}.log", self.id))
+    }
+}
+
+/// Sessions registry. Each session is a `<id>.json` file written by the
+/// detached process itself, so starting and stopping tunnels never race on a
+/// shared file.
+#[derive(Debug, Clone)]
 pub struct Sessions {
-    pub sessions: Vec<Session>,
+    dir: PathBuf,
+    sessions: Vec<Session>,
 }
 
 impl Sessions {
-    /// Load sessions from file
+    /// Load sessions from the config directory
     pub fn load() -> Result<Self> {
-        let path = sessions_file();
+        Self::load_from(sessions_dir())
+    }
 
-        if !path.exists() {
-            return Ok(Self::default());
-        }
+    /// Load sessions from `dir`, skipping files that can't be parsed
+    pub fn load_from(dir: PathBuf) -> Result<Self> {
+        let mut sessions = Vec::new();
 
-        let content = fs::read_to_string(&path).context("Failed to read sessions file")?;
-        let sessions: Sessions =
-            serde_json::from_str(&content).context("Failed to parse sessions file")?;
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self { dir, sessions }),
+            Err(e) => return Err(e).context("Failed to read sessions directory"),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match fs::read_to_string(&path).map(|content| serde_json::from_str::<Session>(&content)) {
+                Ok(Ok(session)) => sessions.push(session),
+                _ => tracing::debug!("Skipping unreadable session file {}", path.display()),
+            }
+        }
+        sessions.sort_by_key(|s| s.started_at);
 
-        Ok(sessions)
+        Ok(Self { dir, sessions })
     }
 
-    /// Save sessions to file
-    pub fn save(&self) -> Result<()> {
-        ensure_dirs()?;
-        let path = sessions_file();
-        let content = serde_json::to_string_pretty(self).context("Failed to serialize sessions")?;
-        fs::write(&path, content).context("Failed to write sessions file")?;
-        Ok(())
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
     }
 
-    /// Add a session
+    /// Add a session, or update it if one with the same ID exists
     pub fn add(&mut self, session: Session) -> Result<()> {
-        self.sessions.push(session);
-        self.save()
+        fs::create_dir_all(&self.dir).context("Failed to create sessions directory")?;
+        let content = serde_json::to_string_pretty(&session).context("Failed to serialize session")?;
+        // Write then rename, so `ls` never reads half a file
+        let tmp = self.dir.join(format!(".{}.json.tmp", session.id));
+        fs::write(&tmp, content).context("Failed to write session file")?;
+        fs::rename(&tmp, self.path(&session.id)).context("Failed to write session file")?;
+
+        match self.sessions.iter_mut().find(|s| s.id == session.id) {
+            Some(existing) => *existing = session,
+            None => self.sessions.push(session),
+        }
+        Ok(())
     }
 
     /// Remove a session by ID
     pub fn remove(&mut self, id: &str) -> Result<Option<Session>> {
+        match fs::remove_file(self.path(id)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("Failed to remove session file"),
+        }
         let idx = self.sessions.iter().position(|s| s.id == id);
-        let removed = idx.map(|i| self.sessions.remove(i));
-        self.save()?;
-        Ok(removed)
+        Ok(idx.map(|i| self.sessions.remove(i)))
     }
 
     /// Find a session by ID
@@ -237,3 +320,42 @@ mod hex {
         bytes.iter().map(|b| format!("{:02x}", b)).collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn urls(server_url: &str) -> (String, String) {
+        let urls = ServerUrls::parse(server_url).unwrap();
+        (urls.api, urls.websocket)
+    }
+
+    #[test]
+    fn test_server_urls() {
+        let hosted = ("https://api.dvaar.io".to_string(), "wss://api.dvaar.io".to_string());
+        assert_eq!(urls("https://api.dvaar.io"), hosted);
+        assert_eq!(urls("https://api.dvaar.io/"), hosted);
+        assert_eq!(urls("wss://api.dvaar.io"), hosted);
+        assert_eq!(urls("https://api.dvaar.io:443"), hosted);
+        assert_eq!(urls("api.dvaar.io"), hosted);
+
+        let local = ("http://localhost:8080".to_string(), "ws://localhost:8080".to_string());
+        assert_eq!(urls("http://localhost:8080"), local);
+        assert_eq!(urls("ws://localhost:8080/"), local);
+
+        assert_eq!(
+            urls("wss://tunnels.example.com:8443/dvaar/"),
+            (
+                "https://tunnels.example.com:8443/dvaar".to_string(),
+                "wss://tunnels.example.com:8443/dvaar".to_string()
+            )
+        );
+        assert_eq!(
+            urls("http://[::1]:8080"),
+            ("http://[::1]:8080".to_string(), "ws://[::1]:8080".to_string())
+        );
+
+        assert!(ServerUrls::parse("ftp://api.dvaar.io").is_err());
+        assert!(ServerUrls::parse("https://").is_err());
+    }
+}
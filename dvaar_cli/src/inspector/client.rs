@@ -3,10 +3,13 @@
 //! When a tunnel connects to an existing inspector (instead of starting its own),
 //! it uses this client to register itself and submit requests.
 
-use super::store::CapturedRequest;
+use super::port::reachable_ip;
+use super::store::{CapturedFrame, CapturedRequest};
 use anyhow::{Context, Result};
+use dvaar_common::heartbeat;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::RwLock;
@@ -29,6 +32,13 @@ struct RegisterTunnelRequest {
     local_addr: String,
 }
 
+/// A WebSocket connection to capture frames of
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpenWebSocketRequest {
+    pub stream_id: String,
+    pub path: String,
+}
+
 /// Response from tunnel registration
 #[derive(Debug, Deserialize)]
 struct RegisterTunnelResponse {
@@ -38,10 +48,10 @@ struct RegisterTunnelResponse {
 }
 
 impl InspectorClient {
-    /// Create a new inspector client
-    pub fn new(port: u16, tunnel_id: String) -> Self {
+    /// Create a client for the inspector listening on `bind`:`port`
+    pub fn new(bind: IpAddr, port: u16, tunnel_id: String) -> Self {
         Self {
-            base_url: format!("http://127.0.0.1:{}", port),
+            base_url: format!("http://{}", SocketAddr::new(reachable_ip(bind), port)),
             tunnel_id,
             client: Client::builder()
                 .timeout(Duration::from_secs(5))
@@ -153,6 +163,37 @@ impl InspectorClient {
         Ok(())
     }
 
+    /// Tell the inspector a WebSocket connection was opened
+    pub async fn submit_ws_open(&self, stream_id: &str, path: &str) -> Result<()> {
+        let url = format!("{}/api/tunnels/{}/ws", self.base_url, self.tunnel_id);
+
+        self.client
+            .post(&url)
+            .json(&OpenWebSocketRequest {
+                stream_id: stream_id.to_string(),
+                path: path.to_string(),
+            })
+            .send()
+            .await
+            .context("Failed to submit WebSocket to inspector")?;
+
+        Ok(())
+    }
+
+    /// Send a WebSocket frame to the inspector
+    pub async fn submit_ws_frame(&self, frame: CapturedFrame) -> Result<()> {
+        let url = format!("{}/api/tunnels/{}/ws/frame", self.base_url, self.tunnel_id);
+
+        self.client
+            .post(&url)
+            .json(&frame)
+            .send()
+            .await
+            .context("Failed to submit WebSocket frame to inspector")?;
+
+        Ok(())
+    }
+
     /// Send heartbeat to keep tunnel alive
     pub async fn heartbeat(&self) -> Result<()> {
         let url = format!(
@@ -177,13 +218,12 @@ impl InspectorClient {
 
     /// Start a background heartbeat task
     pub fn start_heartbeat_task(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(30));
-            loop {
-                interval.tick().await;
-                if self.heartbeat().await.is_err() {
+        heartbeat::spawn_heartbeat(move || {
+            let client = self.clone();
+            async move {
+                if client.heartbeat().await.is_err() {
                     tracing::warn!("Heartbeat to inspector failed");
-                    // Don't break - the inspector might come back
+                    // Keep going - the inspector might come back
                 }
             }
         })
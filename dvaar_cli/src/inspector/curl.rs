@@ -0,0 +1,132 @@
+//! Render a captured request as a `curl` command
+
+use super::store::CapturedRequest;
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+/// Headers curl works out for itself from the URL and body
+const SKIPPED_HEADERS: &[&str] = &["host", "content-length"];
+
+/// Build a command that reproduces `request` against `base_url`
+/// (scheme and host, no trailing slash).
+///
+/// Text bodies are passed inline with `--data-raw`. Binary bodies can't be
+/// quoted safely, so they're decoded from base64 and piped in on stdin.
+pub fn to_curl(request: &CapturedRequest, base_url: &str) -> String {
+    let mut args = vec!["curl".to_string()];
+
+    match request.method.as_str() {
+        "GET" => {}
+        "HEAD" => args.push("--head".to_string()),
+        method => args.push(format!("-X {}", shell_quote(method))),
+    }
+    args.push(shell_quote(&format!("{}{}", base_url, request.path)));
+
+    for (key, value) in &request.request_headers {
+        if SKIPPED_HEADERS.iter().any(|h| key.eq_ignore_ascii_case(h)) {
+            continue;
+        }
+        args.push(format!("-H {}", shell_quote(&format!("{}: {}", key, value))));
+    }
+
+    let body = &request.request_body;
+    let text_body = std::str::from_utf8(body)
+        .ok()
+        .filter(|text| !text.chars().any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t')));
+
+    if body.is_empty() {
+        args.join(" \\\n  ")
+    } else if let Some(text) = text_body {
+        args.push(format!("--data-raw {}", shell_quote(text)));
+        args.join(" \\\n  ")
+    } else {
+        args.push("--data-binary @-".to_string());
+        format!(
+            "# Binary request body ({} bytes), decoded from base64 and sent on stdin\n\
+             echo {} | base64 --decode | {}",
+            body.len(),
+            shell_quote(&STANDARD.encode(body)),
+            args.join(" \\\n  ")
+        )
+    }
+}
+
+/// Quote for POSIX shells: wrap in single quotes, which keep everything
+/// literal (newlines included) except `'` itself.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn captured(method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> CapturedRequest {
+        CapturedRequest {
+            id: "req-1".to_string(),
+            tunnel_id: String::new(),
+            timestamp: Utc::now(),
+            method: method.to_string(),
+            path: path.to_string(),
+            request_headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            request_body: body.to_vec(),
+            response_status: 200,
+            response_headers: Vec::new(),
+            response_body: Vec::new(),
+            duration_ms: 1,
+            ttfb_ms: None,
+            upstream_connect_ms: None,
+            size_bytes: 0,
+            request_size_bytes: 0,
+            trace_id: None,
+            body_evicted: false,
+        }
+    }
+
+    #[test]
+    fn test_escapes_quotes_and_newlines_in_headers() {
+        let request = captured(
+            "GET",
+            "/search?q=it's",
+            &[("Host", "demo.dvaar.app"), ("X-Note", "it's \"quoted\"\nsecond line")],
+            b"",
+        );
+        let curl = to_curl(&request, "https://demo.dvaar.app");
+
+        assert_eq!(
+            curl,
+            "curl \\\n  'https://demo.dvaar.app/search?q=it'\\''s' \\\n  \
+             -H 'X-Note: it'\\''s \"quoted\"\nsecond line'"
+        );
+    }
+
+    #[test]
+    fn test_post_with_body() {
+        let request = captured(
+            "POST",
+            "/api/items",
+            &[("Content-Type", "application/json"), ("Content-Length", "16")],
+            br#"{"name":"o'neil"}"#,
+        );
+        let curl = to_curl(&request, "http://localhost:3000");
+
+        assert!(curl.starts_with("curl \\\n  -X 'POST' \\\n  'http://localhost:3000/api/items'"));
+        assert!(curl.contains("-H 'Content-Type: application/json'"));
+        assert!(!curl.contains("Content-Length"));
+        assert!(curl.ends_with(r#"--data-raw '{"name":"o'\''neil"}'"#));
+    }
+
+    #[test]
+    fn test_binary_body_is_piped_on_stdin() {
+        let request = captured("PUT", "/upload", &[], &[0x89, b'P', b'N', b'G', 0x00, 0xff]);
+        let curl = to_curl(&request, "http://localhost:3000");
+
+        let mut lines = curl.lines();
+        assert!(lines.next().unwrap().starts_with("# Binary request body (6 bytes)"));
+        assert!(lines.next().unwrap().starts_with("echo 'iVBORwD/' | base64 --decode | curl"));
+        assert!(curl.ends_with("--data-binary @-"));
+    }
+}
@@ -0,0 +1,289 @@
+//! Compare two captured requests
+//!
+//! Headers are compared by name (case-insensitively, repeated headers
+//! joined), bodies line by line when both sides are text.
+
+use super::store::CapturedRequest;
+use serde::Serialize;
+use similar::{ChangeTag, TextDiff};
+use std::collections::BTreeMap;
+
+/// Unchanged lines kept around each change in a body diff
+const CONTEXT_LINES: usize = 3;
+
+/// What differs between two captured requests. Every part is empty when
+/// the requests match.
+#[derive(Debug, Serialize)]
+pub struct RequestDiff {
+    pub a: String,
+    pub b: String,
+    /// Method, path and status
+    pub fields: Vec<FieldChange>,
+    pub request_headers: Vec<HeaderChange>,
+    pub response_headers: Vec<HeaderChange>,
+    pub request_body: Option<BodyDiff>,
+    pub response_body: Option<BodyDiff>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub a: String,
+    pub b: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum HeaderChange {
+    Added { name: String, value: String },
+    Removed { name: String, value: String },
+    Changed { name: String, a: String, b: String },
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum BodyDiff {
+    /// Changed lines grouped into hunks with a few lines of context each
+    Text { hunks: Vec<Vec<DiffLine>> },
+    /// At least one side isn't text, so only the sizes are shown
+    Binary { a_bytes: usize, b_bytes: usize },
+    /// At least one body was dropped to save memory
+    Evicted,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DiffLine {
+    pub change: LineChange,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineChange {
+    Equal,
+    Added,
+    Removed,
+}
+
+/// Diff `b` against `a`
+pub fn diff_requests(a: &CapturedRequest, b: &CapturedRequest) -> RequestDiff {
+    let mut fields = Vec::new();
+    let status_a = a.response_status.to_string();
+    let status_b = b.response_status.to_string();
+    for (field, value_a, value_b) in [
+        ("method", &a.method, &b.method),
+        ("path", &a.path, &b.path),
+        ("status", &status_a, &status_b),
+    ] {
+        if value_a != value_b {
+            fields.push(FieldChange {
+                field,
+                a: value_a.clone(),
+                b: value_b.clone(),
+            });
+        }
+    }
+
+    RequestDiff {
+        a: a.id.clone(),
+        b: b.id.clone(),
+        fields,
+        request_headers: diff_headers(&a.request_headers, &b.request_headers),
+        response_headers: diff_headers(&a.response_headers, &b.response_headers),
+        request_body: diff_bodies(&a.request_body, &b.request_body, a.body_evicted || b.body_evicted),
+        response_body: diff_bodies(&a.response_body, &b.response_body, a.body_evicted || b.body_evicted),
+    }
+}
+
+fn diff_headers(a: &[(String, String)], b: &[(String, String)]) -> Vec<HeaderChange> {
+    let a = header_map(a);
+    let mut b = header_map(b);
+    let mut changes = Vec::new();
+    for (name, value_a) in a {
+        match b.remove(&name) {
+            None => changes.push(HeaderChange::Removed { name, value: value_a }),
+            Some(value_b) if value_b != value_a => changes.push(HeaderChange::Changed {
+                name,
+                a: value_a,
+                b: value_b,
+            }),
+            Some(_) => {}
+        }
+    }
+    changes.extend(b.into_iter().map(|(name, value)| HeaderChange::Added { name, value }));
+    // Keep the output in name order rather than removals first
+    changes.sort_by(|x, y| header_name(x).cmp(header_name(y)));
+    changes
+}
+
+fn header_name(change: &HeaderChange) -> &str {
+    match change {
+        HeaderChange::Added { name, .. }
+        | HeaderChange::Removed { name, .. }
+        | HeaderChange::Changed { name, .. } => name,
+    }
+}
+
+/// Lowercased names to values, repeats joined with `, `
+fn header_map(headers: &[(String, String)]) -> BTreeMap<String, String> {
+    let mut map: BTreeMap<String, String> = BTreeMap::new();
+    for (name, value) in headers {
+        map.entry(name.to_ascii_lowercase())
+            .and_modify(|joined| {
+                joined.push_str(", ");
+                joined.push_str(value);
+            })
+            .or_insert_with(|| value.clone());
+    }
+    map
+}
+
+fn diff_bodies(a: &[u8], b: &[u8], evicted: bool) -> Option<BodyDiff> {
+    if evicted {
+        return Some(BodyDiff::Evicted);
+    }
+    if a == b {
+        return None;
+    }
+    let (Ok(text_a), Ok(text_b)) = (std::str::from_utf8(a), std::str::from_utf8(b)) else {
+        return Some(BodyDiff::Binary {
+            a_bytes: a.len(),
+            b_bytes: b.len(),
+        });
+    };
+
+    let diff = TextDiff::from_lines(text_a, text_b);
+    let hunks = diff
+        .grouped_ops(CONTEXT_LINES)
+        .iter()
+        .map(|group| {
+            group
+                .iter()
+                .flat_map(|op| diff.iter_changes(op))
+                .map(|change| DiffLine {
+                    change: match change.tag() {
+                        ChangeTag::Equal => LineChange::Equal,
+                        ChangeTag::Insert => LineChange::Added,
+                        ChangeTag::Delete => LineChange::Removed,
+                    },
+                    text: change.value().trim_end_matches(['\r', '\n']).to_string(),
+                })
+                .collect()
+        })
+        .collect();
+    Some(BodyDiff::Text { hunks })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn captured(id: &str, headers: &[(&str, &str)], body: &str) -> CapturedRequest {
+        CapturedRequest {
+            id: id.to_string(),
+            tunnel_id: String::new(),
+            timestamp: Utc::now(),
+            method: "GET".to_string(),
+            path: "/api/items".to_string(),
+            request_headers: Vec::new(),
+            request_body: Vec::new(),
+            response_status: 200,
+            response_headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            response_body: body.as_bytes().to_vec(),
+            duration_ms: 1,
+            ttfb_ms: None,
+            upstream_connect_ms: None,
+            size_bytes: 0,
+            request_size_bytes: 0,
+            trace_id: None,
+            body_evicted: false,
+        }
+    }
+
+    #[test]
+    fn test_identical_requests_have_empty_diff() {
+        let headers = [("Content-Type", "application/json"), ("Set-Cookie", "a=1"), ("Set-Cookie", "b=2")];
+        let a = captured("a", &headers, "{\n  \"id\": 1\n}");
+        let b = captured("b", &headers, "{\n  \"id\": 1\n}");
+
+        let diff = diff_requests(&a, &b);
+        assert_eq!((diff.a.as_str(), diff.b.as_str()), ("a", "b"));
+        assert!(diff.fields.is_empty());
+        assert!(diff.request_headers.is_empty());
+        assert!(diff.response_headers.is_empty());
+        assert_eq!(diff.request_body, None);
+        assert_eq!(diff.response_body, None);
+    }
+
+    #[test]
+    fn test_header_only_changes() {
+        let a = captured("a", &[("Content-Type", "text/plain"), ("ETag", "\"v1\""), ("X-Old", "1")], "same");
+        let mut b = captured("b", &[("content-type", "text/plain"), ("ETag", "\"v2\""), ("X-New", "2")], "same");
+        b.response_status = 304;
+
+        let diff = diff_requests(&a, &b);
+        assert_eq!(
+            diff.fields,
+            vec![FieldChange {
+                field: "status",
+                a: "200".to_string(),
+                b: "304".to_string()
+            }]
+        );
+        // Names compare case-insensitively, so content-type is unchanged
+        assert_eq!(
+            diff.response_headers,
+            vec![
+                HeaderChange::Changed {
+                    name: "etag".to_string(),
+                    a: "\"v1\"".to_string(),
+                    b: "\"v2\"".to_string()
+                },
+                HeaderChange::Added {
+                    name: "x-new".to_string(),
+                    value: "2".to_string()
+                },
+                HeaderChange::Removed {
+                    name: "x-old".to_string(),
+                    value: "1".to_string()
+                },
+            ]
+        );
+        assert_eq!(diff.response_body, None);
+    }
+
+    #[test]
+    fn test_body_changes() {
+        let a = captured("a", &[], "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\n");
+        let b = captured("b", &[], "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nNINE\n");
+
+        let diff = diff_requests(&a, &b);
+        assert!(diff.response_headers.is_empty());
+        let Some(BodyDiff::Text { hunks }) = diff.response_body else {
+            panic!("expected a text diff, got {:?}", diff.response_body);
+        };
+        assert_eq!(hunks.len(), 1);
+        let lines: Vec<(LineChange, &str)> = hunks[0].iter().map(|l| (l.change, l.text.as_str())).collect();
+        assert_eq!(
+            lines,
+            vec![
+                (LineChange::Equal, "six"),
+                (LineChange::Equal, "seven"),
+                (LineChange::Equal, "eight"),
+                (LineChange::Removed, "nine"),
+                (LineChange::Added, "NINE"),
+            ]
+        );
+
+        let mut binary = captured("c", &[], "");
+        binary.response_body = vec![0xff, 0x00];
+        assert_eq!(
+            diff_requests(&a, &binary).response_body,
+            Some(BodyDiff::Binary { a_bytes: 45, b_bytes: 2 })
+        );
+    }
+}
@@ -122,6 +122,30 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
             color: #8b949e;
         }
 
+        /* Per-tunnel totals; a chip filters the list to its tunnel */
+        .tunnel-summary {
+            display: flex;
+            flex-wrap: wrap;
+            gap: 0.4rem;
+            padding: 0.5rem 1rem;
+            background: #0d1117;
+            border-bottom: 1px solid #30363d;
+        }
+        .tunnel-summary:empty { display: none; }
+        .summary-chip {
+            background: #161b22;
+            border: 1px solid #30363d;
+            border-radius: 4px;
+            padding: 0.3rem 0.6rem;
+            font-size: 0.75rem;
+            color: #8b949e;
+            cursor: pointer;
+        }
+        .summary-chip:hover { border-color: #484f58; }
+        .summary-chip.selected { border-color: #58a6ff; }
+        .summary-chip .name { color: #e6edf3; font-weight: 500; margin-right: 0.4rem; }
+        .summary-chip .errors { color: #f85149; }
+
         .filter-container {
             padding: 0.5rem 1rem;
             background: #0d1117;
@@ -197,6 +221,32 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
         .request-status.s3xx { color: #39c5cf; }
         .request-status.s4xx { color: #d29922; }
         .request-status.s5xx { color: #f85149; }
+        .request-delete {
+            background: none;
+            border: none;
+            color: #6e7681;
+            cursor: pointer;
+            font-size: 0.9rem;
+            padding: 0 0.25rem;
+            visibility: hidden;
+        }
+        .request-item:hover .request-delete { visibility: visible; }
+        .request-delete:hover { color: #f85149; }
+
+        /* WebSocket frames */
+        .frame-list { font-family: monospace; font-size: 0.8rem; }
+        .frame-item {
+            padding: 0.4rem 1.5rem;
+            border-bottom: 1px solid #21262d;
+            display: flex;
+            gap: 0.75rem;
+            align-items: baseline;
+        }
+        .frame-direction { font-weight: 600; min-width: 1.5rem; }
+        .frame-direction.inbound { color: #58a6ff; }
+        .frame-direction.outbound { color: #3fb950; }
+        .frame-meta { color: #8b949e; white-space: nowrap; }
+        .frame-data { flex: 1; white-space: pre-wrap; word-break: break-all; color: #e6edf3; }
 
         /* Right panel - request details */
         .detail-panel {
@@ -260,6 +310,9 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
         button.primary:hover { background: #2ea043; }
         button.danger { background: #21262d; color: #f85149; border-color: #da3633; }
         button.danger:hover { background: #da36331a; }
+        button.paused { color: #d29922; border-color: #9e6a03; }
+        /* Opened through a share link: a read-only view */
+        body.shared .mutating { display: none; }
 
         /* Request/Response sections */
         .section {
@@ -360,6 +413,12 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
             color: #e6edf3;
         }
 
+        /* Request diff */
+        .diff-line { white-space: pre-wrap; word-break: break-all; padding: 0 0.25rem; }
+        .diff-line.added { background: rgba(63, 185, 80, 0.15); color: #3fb950; }
+        .diff-line.removed { background: rgba(248, 81, 73, 0.15); color: #f85149; }
+        .diff-hunk + .diff-hunk { border-top: 1px dashed #30363d; margin-top: 0.25rem; padding-top: 0.25rem; }
+
         /* JSON highlighting */
         .json-key { color: #ff7b72; }
         .json-string { color: #a5d6ff; }
@@ -411,6 +470,7 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
         .info-value { color: #e6edf3; font-family: 'Monaco', monospace; font-size: 0.85rem; }
         .info-value a { color: #58a6ff; text-decoration: none; }
         .info-value a:hover { text-decoration: underline; }
+        .tunnel-qr { display: block; width: 160px; height: 160px; margin-top: 1rem; background: #fff; border-radius: 6px; }
 
         .metrics-grid {
             display: grid;
@@ -478,6 +538,7 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
 
     <nav class="nav-tabs">
         <button class="nav-tab active" data-tab="inspect" onclick="switchTab('inspect')">Inspect</button>
+        <button class="nav-tab" data-tab="websockets" onclick="switchTab('websockets')">WebSockets</button>
         <button class="nav-tab" data-tab="status" onclick="switchTab('status')">Status</button>
     </nav>
 
@@ -488,8 +549,12 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
             <div class="request-list-panel">
                 <div class="list-header">
                     <h2 id="request-count">All Requests</h2>
-                    <button onclick="clearRequests()" class="danger">Clear</button>
+                    <div>
+                        <button onclick="toggleCapture()" id="capture-toggle" class="mutating" title="Stop adding new requests to the list; traffic keeps flowing">Pause</button>
+                        <button onclick="clearRequests()" class="danger mutating">Clear</button>
+                    </div>
                 </div>
+                <div class="tunnel-summary" id="tunnel-summary"></div>
                 <div class="filter-container">
                     <input type="text" class="filter-input" placeholder="Filter by path, method, or status..." id="filter-input" oninput="filterRequests()">
                 </div>
@@ -506,6 +571,22 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
         </div>
     </div>
 
+    <!-- WebSockets Tab -->
+    <div class="tab-content" id="tab-websockets">
+        <div class="inspect-container">
+            <div class="request-list-panel">
+                <div class="list-header">
+                    <h2 id="ws-count">0 connections</h2>
+                </div>
+                <div class="request-list" id="ws-list"></div>
+            </div>
+            <div class="detail-panel">
+                <div class="detail-empty" id="frames-empty">Select a connection to view its frames</div>
+                <div class="frame-list" id="frame-list" style="display: none;"></div>
+            </div>
+        </div>
+    </div>
+
     <!-- Status Tab -->
     <div class="tab-content" id="tab-status">
         <div class="status-container">
@@ -524,6 +605,7 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
                         <span class="info-label">Inspector</span>
                         <span class="info-value" id="inspector-addr">-</span>
                     </div>
+                    <img class="tunnel-qr" id="tunnel-qr" alt="QR code for the public URL" style="display: none;">
                 </div>
 
                 <div class="status-card">
@@ -537,6 +619,14 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
                             <span class="metric-value" id="open-connections">0</span>
                             <span class="metric-label">Open Connections</span>
                         </div>
+                        <div class="metric">
+                            <span class="metric-value" id="ingress-bytes">0 B</span>
+                            <span class="metric-label">Ingress (requests)</span>
+                        </div>
+                        <div class="metric">
+                            <span class="metric-value" id="egress-bytes">0 B</span>
+                            <span class="metric-label">Egress (responses)</span>
+                        </div>
                     </div>
                     <h4 style="font-size: 0.85rem; color: #666; margin: 1rem 0 0.5rem;">Request Rate (req/min)</h4>
                     <table class="metrics-table">
@@ -554,21 +644,38 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
     </div>
 
     <script>
+        // A share link serves this page under a prefix on the tunnel's own host
+        const BASE = location.pathname.startsWith('/_dvaar/inspect') ? '/_dvaar/inspect' : '';
+        if (BASE) document.body.classList.add('shared');
+        // There it only reaches its own tunnel's endpoints, whichever ID the path names
+        const SHARED_API = BASE ? `${BASE}/api/tunnels/shared` : null;
+
         let requests = [];
         let tunnels = {};
         let selectedTunnelId = null;
         let selectedRequestId = null;
+        let diffBaseId = null;
         let ws = null;
         let currentTab = 'inspect';
         let metricsInterval = null;
+        let metricsSource = null;
         let filterText = '';
+        let websockets = [];
+        let selectedStreamId = null;
+        let frames = [];
+        let summaries = [];
+        let summaryTimer = null;
 
         function selectTunnel(tunnelId) {
             selectedTunnelId = tunnelId || null;
+            document.getElementById('tunnel-selector').value = selectedTunnelId || '';
             renderRequests();
+            renderSummary();
             if (currentTab === 'status') {
                 fetchTunnelInfo();
-                fetchMetrics();
+                startMetrics();
+            } else if (currentTab === 'websockets') {
+                fetchWebSockets();
             }
         }
 
@@ -587,6 +694,45 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
             selector.value = currentValue;
         }
 
+        // Refetched at most twice a second however fast requests arrive
+        function scheduleSummary() {
+            if (summaryTimer) return;
+            summaryTimer = setTimeout(async () => {
+                summaryTimer = null;
+                try {
+                    const res = await fetch(BASE + '/api/tunnels/summary');
+                    summaries = await res.json();
+                    renderSummary();
+                } catch (e) {
+                    console.error('Failed to fetch tunnel summary:', e);
+                }
+            }, 500);
+        }
+
+        function renderSummary() {
+            const container = document.getElementById('tunnel-summary');
+            // One tunnel's totals are just the list itself
+            if (summaries.length < 2) {
+                container.innerHTML = '';
+                return;
+            }
+            container.innerHTML = summaries.map(s => {
+                const name = escapeHtml(s.subdomain || s.tunnel_id.slice(0, 8));
+                const status = s.status === 'active' ? '●' : '○';
+                const errors = s.errors > 0
+                    ? `<span class="errors">${(s.error_rate * 100).toFixed(1)}% errors</span>`
+                    : '0% errors';
+                const selected = s.tunnel_id === selectedTunnelId ? 'selected' : '';
+                const next = s.tunnel_id === selectedTunnelId ? '' : s.tunnel_id;
+                return `
+                    <div class="summary-chip ${selected}" onclick="selectTunnel('${next}')" title="Totals over the requests captured for this tunnel">
+                        <span class="name">${status} ${name}</span>
+                        ${s.requests} req · ${errors} · ↑${formatSize(s.bytes_in)} ↓${formatSize(s.bytes_out)}
+                    </div>
+                `;
+            }).join('');
+        }
+
         function getFilteredRequests() {
             // Always create a copy to avoid mutation issues
             let filtered = [...requests];
@@ -620,11 +766,93 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
 
             if (tab === 'status') {
                 fetchTunnelInfo();
-                fetchMetrics();
-                if (!metricsInterval) metricsInterval = setInterval(fetchMetrics, 2000);
-            } else if (metricsInterval) {
-                clearInterval(metricsInterval);
-                metricsInterval = null;
+                startMetrics();
+            } else {
+                stopMetrics();
+            }
+            if (tab === 'websockets') fetchWebSockets();
+        }
+
+        async function fetchWebSockets() {
+            try {
+                const query = selectedTunnelId ? `?tunnel=${encodeURIComponent(selectedTunnelId)}` : '';
+                const res = await fetch(`${BASE}/api/ws${query}`);
+                websockets = await res.json();
+                renderWebSockets();
+            } catch (e) { console.error('Failed to fetch WebSockets:', e); }
+        }
+
+        async function selectWebSocket(streamId) {
+            selectedStreamId = streamId;
+            renderWebSockets();
+            try {
+                const res = await fetch(`${BASE}/api/ws/${encodeURIComponent(streamId)}`);
+                frames = res.ok ? await res.json() : [];
+            } catch (e) { frames = []; }
+            renderFrames();
+        }
+
+        function renderWebSockets() {
+            const shown = websockets.filter(w => !selectedTunnelId || w.tunnel_id === selectedTunnelId);
+            document.getElementById('ws-count').textContent =
+                `${shown.length} connection${shown.length !== 1 ? 's' : ''}`;
+            const container = document.getElementById('ws-list');
+            if (shown.length === 0) {
+                container.innerHTML = `
+                    <div class="empty-state">
+                        <p>No WebSocket connections yet</p>
+                        <p style="font-size: 0.8rem; margin-top: 0.5rem;">Frames sent through your tunnel will appear here</p>
+                    </div>
+                `;
+                return;
+            }
+            container.innerHTML = shown
+                .slice()
+                .reverse()
+                .map(w => `
+                    <div class="request-item ${selectedStreamId === w.stream_id ? 'selected' : ''}" onclick="selectWebSocket('${w.stream_id}')">
+                        <span class="method GET">WS</span>
+                        <span class="request-path" title="${escapeHtml(w.path)}">${escapeHtml(w.path)}</span>
+                        <div class="request-meta">
+                            <span>${w.frame_count} frame${w.frame_count !== 1 ? 's' : ''}</span>
+                            <span>${formatTimeAgo(w.last_frame_at || w.opened_at)}</span>
+                        </div>
+                    </div>
+                `)
+                .join('');
+        }
+
+        function renderFrames() {
+            const empty = document.getElementById('frames-empty');
+            const list = document.getElementById('frame-list');
+            if (!selectedStreamId) {
+                empty.style.display = 'flex';
+                list.style.display = 'none';
+                return;
+            }
+            empty.style.display = 'none';
+            list.style.display = 'block';
+            if (frames.length === 0) {
+                list.innerHTML = '<div class="empty-state"><p>No frames yet</p></div>';
+                return;
+            }
+            list.innerHTML = frames.map(f => `
+                <div class="frame-item">
+                    <span class="frame-direction ${f.direction}" title="${f.direction === 'inbound' ? 'Visitor to local app' : 'Local app to visitor'}">${f.direction === 'inbound' ? '↓' : '↑'}</span>
+                    <span class="frame-meta">${formatTime(f.timestamp)}</span>
+                    <span class="frame-meta">${f.is_binary ? 'binary' : 'text'} ${formatSize(f.size)}</span>
+                    <span class="frame-data">${f.is_binary ? '[Binary data]' : escapeHtml(decodeBody(f.data))}</span>
+                </div>
+            `).join('');
+        }
+
+        function showQr(publicUrl, src) {
+            const qrEl = document.getElementById('tunnel-qr');
+            if (publicUrl) {
+                qrEl.src = src;
+                qrEl.style.display = 'block';
+            } else {
+                qrEl.style.display = 'none';
             }
         }
 
@@ -637,39 +865,91 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
                         ? `<a href="${tunnel.public_url}" target="_blank">${tunnel.public_url}</a>`
                         : '-';
                     document.getElementById('local-addr').textContent = tunnel.local_addr || '-';
+                    showQr(tunnel.public_url, `${BASE}/api/qr?tunnel=${encodeURIComponent(selectedTunnelId)}`);
                     return;
                 }
-                const res = await fetch('/api/info');
+                const res = await fetch(BASE + '/api/info');
                 const info = await res.json();
                 const urlEl = document.getElementById('tunnel-url');
                 urlEl.innerHTML = info.public_url
                     ? `<a href="${info.public_url}" target="_blank">${info.public_url}</a>`
                     : '-';
                 document.getElementById('local-addr').textContent = info.local_addr || '-';
+                showQr(info.public_url, BASE + '/api/qr');
             } catch (e) { console.error('Failed to fetch info:', e); }
         }
 
+        // Metrics are pushed over SSE; polling is the fallback for browsers
+        // without EventSource or when the stream fails
+        function startMetrics() {
+            stopMetrics();
+            if (!window.EventSource || SHARED_API) {
+                startMetricsPolling();
+                return;
+            }
+            const query = selectedTunnelId ? `?tunnel=${encodeURIComponent(selectedTunnelId)}` : '';
+            metricsSource = new EventSource(`${BASE}/api/metrics/stream${query}`);
+            metricsSource.onmessage = (e) => renderMetrics(JSON.parse(e.data));
+            metricsSource.onerror = () => {
+                stopMetrics();
+                startMetricsPolling();
+            };
+        }
+
+        function startMetricsPolling() {
+            fetchMetrics();
+            metricsInterval = setInterval(fetchMetrics, 2000);
+        }
+
+        function stopMetrics() {
+            if (metricsSource) {
+                metricsSource.close();
+                metricsSource = null;
+            }
+            if (metricsInterval) {
+                clearInterval(metricsInterval);
+                metricsInterval = null;
+            }
+        }
+
         async function fetchMetrics() {
             try {
-                const url = selectedTunnelId ? `/api/tunnels/${selectedTunnelId}/metrics` : '/api/metrics';
+                const url = SHARED_API ? `${SHARED_API}/metrics`
+                    : selectedTunnelId ? `${BASE}/api/tunnels/${selectedTunnelId}/metrics` : BASE + '/api/metrics';
                 const res = await fetch(url);
                 if (!res.ok) throw new Error(`HTTP ${res.status}`);
-                const m = await res.json();
-                document.getElementById('total-requests').textContent = m.total_requests;
-                document.getElementById('open-connections').textContent = m.open_connections;
-                document.getElementById('rate-1m').textContent = m.requests_per_minute_1m.toFixed(2);
-                document.getElementById('rate-5m').textContent = m.requests_per_minute_5m.toFixed(2);
-                document.getElementById('rate-15m').textContent = m.requests_per_minute_15m.toFixed(2);
-                document.getElementById('p50').textContent = m.p50_duration_ms;
-                document.getElementById('p90').textContent = m.p90_duration_ms;
-                document.getElementById('p95').textContent = m.p95_duration_ms;
-                document.getElementById('p99').textContent = m.p99_duration_ms;
+                renderMetrics(await res.json());
             } catch (e) { console.error('Failed to fetch metrics:', e); }
         }
 
+        function renderMetrics(m) {
+            document.getElementById('total-requests').textContent = m.total_requests;
+            document.getElementById('open-connections').textContent = m.open_connections;
+            document.getElementById('ingress-bytes').textContent = formatSize(m.ingress_bytes);
+            document.getElementById('egress-bytes').textContent = formatSize(m.egress_bytes);
+            document.getElementById('rate-1m').textContent = m.requests_per_minute_1m.toFixed(2);
+            document.getElementById('rate-5m').textContent = m.requests_per_minute_5m.toFixed(2);
+            document.getElementById('rate-15m').textContent = m.requests_per_minute_15m.toFixed(2);
+            document.getElementById('p50').textContent = m.p50_duration_ms;
+            document.getElementById('p90').textContent = m.p90_duration_ms;
+            document.getElementById('p95').textContent = m.p95_duration_ms;
+            document.getElementById('p99').textContent = m.p99_duration_ms;
+        }
+
+        // A shared view has no live feed, so it polls its tunnel's requests
+        async function pollSharedRequests() {
+            try {
+                const res = await fetch(`${SHARED_API}/requests`);
+                if (!res.ok) throw new Error(`HTTP ${res.status}`);
+                requests = await res.json();
+                renderRequests();
+            } catch (e) { console.error('Failed to fetch requests:', e); }
+            setTimeout(pollSharedRequests, 2000);
+        }
+
         function connect() {
             const protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
-            ws = new WebSocket(`${protocol}//${window.location.host}/ws`);
+            ws = new WebSocket(`${protocol}//${window.location.host}${BASE}/ws`);
 
             ws.onopen = () => {
                 document.getElementById('ws-dot').classList.add('connected');
@@ -677,6 +957,7 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
                 document.getElementById('status-badge').textContent = 'online';
                 document.getElementById('status-badge').className = 'status-badge online';
                 document.getElementById('inspector-addr').textContent = window.location.host;
+                fetchCapture();
             };
 
             ws.onclose = () => {
@@ -696,36 +977,75 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
                     requests = msg.data || [];
                     console.log('Loaded', requests.length, 'initial requests');
                     renderRequests();
+                    scheduleSummary();
                 } else if (msg.type === 'request') {
                     if (msg.data && msg.data.id) {
                         requests.push(msg.data);
                         if (requests.length > 200) requests.shift();
                         console.log('New request:', msg.data.method, msg.data.path);
                         renderRequests();
+                        scheduleSummary();
                     }
+                } else if (msg.type === 'clear' && msg.data?.request_id) {
+                    const id = msg.data.request_id;
+                    requests = requests.filter(r => r.id !== id);
+                    if (selectedRequestId === id) selectedRequestId = null;
+                    if (diffBaseId === id) diffBaseId = null;
+                    renderRequests();
+                    renderDetails();
+                    scheduleSummary();
                 } else if (msg.type === 'clear') {
                     if (!msg.data?.tunnel_id) requests = [];
                     else requests = requests.filter(r => r.tunnel_id !== msg.data.tunnel_id);
                     selectedRequestId = null;
+                    diffBaseId = null;
                     renderRequests();
                     renderDetails();
+                    scheduleSummary();
+                    if (!msg.data?.tunnel_id) websockets = [];
+                    else websockets = websockets.filter(w => w.tunnel_id !== msg.data.tunnel_id);
+                    selectedStreamId = null;
+                    frames = [];
+                    renderWebSockets();
+                    renderFrames();
                 } else if (msg.type === 'tunnels') {
                     tunnels = {};
                     msg.data.forEach(t => tunnels[t.tunnel_id] = t);
                     updateTunnelSelector();
+                    scheduleSummary();
                 } else if (msg.type === 'tunnel_registered') {
                     tunnels[msg.data.tunnel_id] = msg.data;
                     updateTunnelSelector();
+                    scheduleSummary();
                 } else if (msg.type === 'tunnel_unregistered') {
                     if (tunnels[msg.data.tunnel_id]) tunnels[msg.data.tunnel_id].status = 'disconnected';
                     updateTunnelSelector();
+                    scheduleSummary();
                 } else if (msg.type === 'tunnel_status') {
                     if (tunnels[msg.data.tunnel_id]) tunnels[msg.data.tunnel_id].status = msg.data.status;
                     updateTunnelSelector();
+                    scheduleSummary();
                 } else if (msg.type === 'tunnel_updated') {
                     tunnels[msg.data.tunnel_id] = msg.data;
                     updateTunnelSelector();
+                    scheduleSummary();
                     if (currentTab === 'status' && selectedTunnelId === msg.data.tunnel_id) fetchTunnelInfo();
+                } else if (msg.type === 'capture') {
+                    renderCapture(msg.data.paused);
+                } else if (msg.type === 'ws_opened') {
+                    websockets.push(msg.data);
+                    if (currentTab === 'websockets') renderWebSockets();
+                } else if (msg.type === 'ws_frame') {
+                    const connection = websockets.find(w => w.stream_id === msg.data.stream_id);
+                    if (connection) {
+                        connection.frame_count += 1;
+                        connection.last_frame_at = msg.data.timestamp;
+                    }
+                    if (msg.data.stream_id === selectedStreamId) {
+                        frames.push(msg.data);
+                        renderFrames();
+                    }
+                    if (currentTab === 'websockets') renderWebSockets();
                 }
             };
         }
@@ -793,7 +1113,7 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
             btn.disabled = true;
             btn.textContent = '...';
             try {
-                const res = await fetch(`/api/replay/${id}`, { method: 'POST' });
+                const res = await fetch(`${BASE}/api/replay/${id}`, { method: 'POST' });
                 const data = await res.json();
                 btn.textContent = data.success ? data.status : 'Error';
                 setTimeout(() => { btn.textContent = 'Replay'; btn.disabled = false; }, 2000);
@@ -803,8 +1123,109 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
             }
         }
 
+        async function copyAsCurl(id, event) {
+            event.stopPropagation();
+            const btn = event.target;
+            try {
+                const res = await fetch(`${BASE}/api/curl/${id}`);
+                if (!res.ok) throw new Error(res.statusText);
+                await navigator.clipboard.writeText(await res.text());
+                btn.textContent = 'Copied';
+            } catch (e) {
+                btn.textContent = 'Error';
+            }
+            setTimeout(() => { btn.textContent = 'Copy as cURL'; }, 2000);
+        }
+
+        function markForDiff(id, event) {
+            event.stopPropagation();
+            diffBaseId = diffBaseId === id ? null : id;
+            renderDetails();
+        }
+
+        function renderHeaderChanges(changes) {
+            if (changes.length === 0) return '<div class="body-info">(no changes)</div>';
+            const rows = changes.map(c => {
+                if (c.change === 'added') return `<div class="diff-line added">+ ${escapeHtml(c.name)}: ${escapeHtml(c.value)}</div>`;
+                if (c.change === 'removed') return `<div class="diff-line removed">- ${escapeHtml(c.name)}: ${escapeHtml(c.value)}</div>`;
+                return `<div class="diff-line removed">- ${escapeHtml(c.name)}: ${escapeHtml(c.a)}</div>` +
+                    `<div class="diff-line added">+ ${escapeHtml(c.name)}: ${escapeHtml(c.b)}</div>`;
+            });
+            return `<div class="body-content"><pre>${rows.join('')}</pre></div>`;
+        }
+
+        function renderBodyDiff(body) {
+            if (!body) return '<div class="body-info">(no changes)</div>';
+            if (body.kind === 'evicted') return '<div class="body-info">(dropped to save memory)</div>';
+            if (body.kind === 'binary') return `<div class="body-info">Binary: ${formatSize(body.a_bytes)} vs ${formatSize(body.b_bytes)}</div>`;
+            const prefix = { equal: '  ', added: '+ ', removed: '- ' };
+            const hunks = body.hunks.map(hunk => `<div class="diff-hunk">${hunk
+                .map(l => `<div class="diff-line ${l.change}">${prefix[l.change]}${escapeHtml(l.text)}</div>`)
+                .join('')}</div>`);
+            return `<div class="body-content"><pre>${hunks.join('')}</pre></div>`;
+        }
+
+        async function showDiff(a, b, event) {
+            event.stopPropagation();
+            const res = await fetch(`${BASE}/api/diff?a=${encodeURIComponent(a)}&b=${encodeURIComponent(b)}`);
+            if (!res.ok) return;
+            const diff = await res.json();
+            const fields = diff.fields.map(f => `<span>${f.field}: ${escapeHtml(f.a)} &rarr; ${escapeHtml(f.b)}</span>`).join('');
+
+            document.getElementById('detail-content').innerHTML = `
+                <div class="detail-header">
+                    <div class="detail-title">
+                        <h3>Diff</h3>
+                        <div class="meta">${fields || '<span>Same method, path and status</span>'}</div>
+                    </div>
+                    <div class="detail-actions">
+                        <button onclick="renderDetails()">Back</button>
+                    </div>
+                </div>
+                <div class="section">
+                    <div class="section-header request-section"><span>Request headers</span></div>
+                    <div class="section-content">${renderHeaderChanges(diff.request_headers)}</div>
+                    <div class="section-header request-section"><span>Request body</span></div>
+                    <div class="section-content">${renderBodyDiff(diff.request_body)}</div>
+                </div>
+                <div class="section">
+                    <div class="section-header response-section"><span>Response headers</span></div>
+                    <div class="section-content">${renderHeaderChanges(diff.response_headers)}</div>
+                    <div class="section-header response-section"><span>Response body</span></div>
+                    <div class="section-content">${renderBodyDiff(diff.response_body)}</div>
+                </div>
+            `;
+        }
+
         async function clearRequests() {
-            await fetch('/api/clear', { method: 'POST' });
+            await fetch(BASE + '/api/clear', { method: 'POST' });
+        }
+
+        async function deleteRequest(id, event) {
+            event.stopPropagation();
+            // The list updates from the clear event, here and in other open dashboards
+            await fetch(`${BASE}/api/requests/${id}`, { method: 'DELETE' });
+        }
+
+        let capturePaused = false;
+
+        function renderCapture(paused) {
+            capturePaused = paused;
+            const button = document.getElementById('capture-toggle');
+            button.textContent = paused ? 'Resume' : 'Pause';
+            button.classList.toggle('paused', paused);
+        }
+
+        async function fetchCapture() {
+            try {
+                const res = await fetch(BASE + '/api/capture');
+                if (res.ok) renderCapture((await res.json()).paused);
+            } catch (e) { console.error('Failed to fetch capture state:', e); }
+        }
+
+        async function toggleCapture() {
+            const res = await fetch(capturePaused ? BASE + '/api/capture/resume' : BASE + '/api/capture/pause', { method: 'POST' });
+            if (res.ok) renderCapture((await res.json()).paused);
         }
 
         function renderRequests() {
@@ -837,6 +1258,7 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
                         <div class="request-meta">
                             <span class="request-status ${getStatusClass(req.response_status)}">${req.response_status}</span>
                             <span>${formatDuration(req.duration_ms)}</span>
+                            <button class="request-delete mutating" title="Delete this request" onclick="deleteRequest('${req.id}', event)">&times;</button>
                         </div>
                     </div>
                 `)
@@ -881,11 +1303,17 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
                         <div class="meta">
                             <span>${formatTimeAgo(req.timestamp)}</span>
                             <span>Duration ${formatDuration(req.duration_ms)}</span>
+                            ${req.upstream_connect_ms != null ? `<span title="Time until upstream returned headers">Upstream ${formatDuration(req.upstream_connect_ms)}</span>` : ''}
+                            ${req.ttfb_ms != null ? `<span title="Time to first response byte">TTFB ${formatDuration(req.ttfb_ms)}</span>` : ''}
                             <span>${formatSize(req.size_bytes)}</span>
+                            ${req.trace_id ? `<span title="traceparent trace id">Trace ${escapeHtml(req.trace_id)}</span>` : ''}
                         </div>
                     </div>
                     <div class="detail-actions">
-                        <button class="primary" onclick="replayRequest('${req.id}', event)">Replay</button>
+                        <button onclick="markForDiff('${req.id}', event)">${diffBaseId === req.id ? 'Marked' : 'Compare'}</button>
+                        ${diffBaseId && diffBaseId !== req.id ? `<button onclick="showDiff('${diffBaseId}', '${req.id}', event)">Diff with marked</button>` : ''}
+                        <button onclick="copyAsCurl('${req.id}', event)">Copy as cURL</button>
+                        <button class="primary mutating" onclick="replayRequest('${req.id}', event)">Replay</button>
                     </div>
                 </div>
 
@@ -905,7 +1333,7 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
                         </table>
                     </div>
                     <div class="section-content" id="req-body" style="display: none;">
-                        <div class="body-info">${reqBody ? formatSize(reqBody.length) : '(empty)'}</div>
+                        <div class="body-info">${reqBody ? formatSize(reqBody.length) : req.body_evicted ? '(dropped to save memory)' : '(empty)'}</div>
                         <div class="body-content"><pre>${escapeHtml(formatJson(reqBody)) || '(empty)'}</pre></div>
                     </div>
                 </div>
@@ -926,7 +1354,7 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
                         </table>
                     </div>
                     <div class="section-content" id="res-body" style="display: none;">
-                        <div class="body-info">${contentType ? contentType + ' - ' : ''}${resBody ? formatSize(resBody.length) : '(empty)'}</div>
+                        <div class="body-info">${contentType ? contentType + ' - ' : ''}${resBody ? formatSize(resBody.length) : req.body_evicted ? '(dropped to save memory)' : '(empty)'}</div>
                         <div class="body-content"><pre>${escapeHtml(formatJson(resBody)) || '(empty)'}</pre></div>
                     </div>
                 </div>
@@ -957,7 +1385,8 @@ pub const INSPECTOR_HTML: &str = r#"<!DOCTYPE html>
             return text.replace(/&/g, '&amp;').replace(/</g, '&lt;').replace(/>/g, '&gt;').replace(/"/g, '&quot;');
         }
 
-        connect();
+        if (SHARED_API) pollSharedRequests();
+        else connect();
     </script>
 </body>
 </html>"#;
@@ -1,12 +1,17 @@
 //! Local web inspector for debugging HTTP requests through the tunnel
 
 pub mod client;
+mod curl;
+mod diff;
 mod html;
 pub mod port;
 mod server;
 mod store;
 
 pub use client::InspectorClient;
-pub use port::{find_inspector_port, InspectorMode};
+pub use port::{find_inspector_port, fixed_inspector_port, InspectorMode};
 pub use server::start_server;
-pub use store::{CapturedRequest, RegisteredTunnel, RequestStore, TunnelStatus};
+pub use store::{
+    CapturedFrame, CapturedRequest, FrameDirection, RegisteredTunnel, RequestStore, TunnelStatus,
+    DEFAULT_INSPECT_MEMORY_MB,
+};
@@ -3,6 +3,7 @@
 use anyhow::Result;
 use reqwest::Client;
 use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::time::Duration;
 use tokio::net::TcpListener;
 
@@ -34,8 +35,18 @@ enum PortCheckResult {
     UsedByOther,
 }
 
+/// Address to reach an inspector listening on `bind` at: a wildcard bind is
+/// reachable over loopback
+pub fn reachable_ip(bind: IpAddr) -> IpAddr {
+    match bind {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    }
+}
+
 /// Check if a port has a dvaar inspector running
-async fn check_port_for_dvaar(port: u16) -> PortCheckResult {
+async fn check_port_for_dvaar(bind: IpAddr, port: u16) -> PortCheckResult {
     let client = match Client::builder()
         .timeout(Duration::from_millis(500))
         .build()
@@ -44,7 +55,7 @@ async fn check_port_for_dvaar(port: u16) -> PortCheckResult {
         Err(_) => return PortCheckResult::UsedByOther,
     };
 
-    let health_url = format!("http://127.0.0.1:{}/api/health", port);
+    let health_url = format!("http://{}/api/health", SocketAddr::new(reachable_ip(bind), port));
 
     match client.get(&health_url).send().await {
         Ok(response) if response.status().is_success() => {
@@ -69,8 +80,8 @@ async fn check_port_for_dvaar(port: u16) -> PortCheckResult {
 }
 
 /// Try to bind to a port to check if it's truly available
-async fn can_bind_port(port: u16) -> bool {
-    TcpListener::bind(format!("127.0.0.1:{}", port)).await.is_ok()
+async fn can_bind_port(bind: IpAddr, port: u16) -> bool {
+    TcpListener::bind(SocketAddr::new(bind, port)).await.is_ok()
 }
 
 /// Find an available port for the inspector
@@ -82,19 +93,19 @@ async fn can_bind_port(port: u16) -> bool {
 /// Continues trying ports until finding one that's:
 /// - Available for binding (Server mode)
 /// - Has a dvaar inspector (Client mode)
-pub async fn find_inspector_port(preferred_port: u16) -> Result<InspectorMode> {
+pub async fn find_inspector_port(bind: IpAddr, preferred_port: u16) -> Result<InspectorMode> {
     for offset in 0..MAX_PORT_ATTEMPTS {
         let port = preferred_port + offset;
 
         // First, check if there's already a dvaar inspector on this port
-        match check_port_for_dvaar(port).await {
+        match check_port_for_dvaar(bind, port).await {
             PortCheckResult::DvaarInspector => {
                 tracing::debug!("Found existing dvaar inspector on port {}", port);
                 return Ok(InspectorMode::Client(port));
             }
             PortCheckResult::Available => {
                 // Double-check by actually trying to bind
-                if can_bind_port(port).await {
+                if can_bind_port(bind, port).await {
                     tracing::debug!("Port {} is available for inspector", port);
                     return Ok(InspectorMode::Server(port));
                 }
@@ -114,6 +125,23 @@ pub async fn find_inspector_port(preferred_port: u16) -> Result<InspectorMode> {
     )
 }
 
+/// Use exactly `port` for the inspector: join the dvaar inspector already
+/// there, or start one. Anything else on the port is an error rather than a
+/// reason to move, since the user asked for this port.
+pub async fn fixed_inspector_port(bind: IpAddr, port: u16) -> Result<InspectorMode> {
+    match check_port_for_dvaar(bind, port).await {
+        PortCheckResult::DvaarInspector => return Ok(InspectorMode::Client(port)),
+        PortCheckResult::Available if can_bind_port(bind, port).await => return Ok(InspectorMode::Server(port)),
+        PortCheckResult::Available | PortCheckResult::UsedByOther => {}
+    }
+
+    anyhow::bail!(
+        "Inspector port {} is already in use on {}. Free it, pick another --inspect-port, or use --inspect to start from a port and take the next free one",
+        port,
+        bind
+    )
+}
+
 /// Get the actual port from an InspectorMode
 impl InspectorMode {
     pub fn port(&self) -> u16 {
@@ -135,13 +163,45 @@ impl InspectorMode {
 mod tests {
     use super::*;
 
+    const LOOPBACK: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
     #[tokio::test]
     async fn test_find_available_port() {
         // Should find an available port starting from a high number
-        let result = find_inspector_port(49000).await;
+        let result = find_inspector_port(LOOPBACK, 49000).await;
         assert!(result.is_ok());
         let mode = result.unwrap();
         assert!(mode.is_server());
         assert!(mode.port() >= 49000);
     }
+
+    #[tokio::test]
+    async fn test_fixed_port_is_honored() {
+        let port = TcpListener::bind((LOOPBACK, 0)).await.unwrap().local_addr().unwrap().port();
+
+        let mode = fixed_inspector_port(LOOPBACK, port).await.unwrap();
+        assert!(mode.is_server());
+        assert_eq!(mode.port(), port);
+    }
+
+    #[tokio::test]
+    async fn test_fixed_port_taken_is_an_error() {
+        // Something that isn't an inspector holds the port
+        let squatter = TcpListener::bind((LOOPBACK, 0)).await.unwrap();
+        let port = squatter.local_addr().unwrap().port();
+
+        let err = fixed_inspector_port(LOOPBACK, port).await.unwrap_err();
+        assert!(err.to_string().contains(&format!("Inspector port {} is already in use", port)));
+
+        // Auto-picking from the same port moves past it instead
+        let mode = find_inspector_port(LOOPBACK, port).await.unwrap();
+        assert_ne!(mode.port(), port);
+    }
+
+    #[test]
+    fn test_wildcard_bind_is_reached_over_loopback() {
+        assert_eq!(reachable_ip("0.0.0.0".parse().unwrap()), LOOPBACK);
+        assert_eq!(reachable_ip("::".parse().unwrap()), IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!(reachable_ip("192.168.1.20".parse().unwrap()), "192.168.1.20".parse::<IpAddr>().unwrap());
+    }
 }
@@ -1,23 +1,34 @@
 //! Inspector HTTP server with WebSocket support
 
 use super::html::INSPECTOR_HTML;
-use super::store::{CapturedRequest, RegisteredTunnel, RequestStore, TunnelStatus};
+use super::client::OpenWebSocketRequest;
+use super::store::{
+    CapturedFrame, CapturedRequest, RegisteredTunnel, RequestStore, TunnelStatus, TunnelSummary,
+    WebSocketCapture,
+};
 use anyhow::{Context, Result};
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        Path, State,
+        Path, Query, State,
+    },
+    http::{header, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
     },
-    http::StatusCode,
-    response::{Html, IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 use chrono::Utc;
-use futures_util::{SinkExt, StreamExt};
+use dvaar_common::{constants, heartbeat};
+use futures_util::{SinkExt, Stream, StreamExt};
 use tokio::sync::broadcast;
 use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::task::JoinHandle;
 
 /// App state for the inspector server
@@ -28,13 +39,14 @@ struct AppState {
     upstream_tls: bool,
 }
 
-/// Start the inspector server on the given port
-pub async fn start_server(port: u16, store: Arc<RequestStore>) -> Result<JoinHandle<()>> {
-    start_server_with_upstream(port, store, String::new(), false).await
+/// Start the inspector server on the given address and port
+pub async fn start_server(bind: IpAddr, port: u16, store: Arc<RequestStore>) -> Result<JoinHandle<()>> {
+    start_server_with_upstream(bind, port, store, String::new(), false).await
 }
 
 /// Start the inspector server with upstream info for replay
 pub async fn start_server_with_upstream(
+    bind: IpAddr,
     port: u16,
     store: Arc<RequestStore>,
     upstream_addr: String,
@@ -53,36 +65,47 @@ pub async fn start_server_with_upstream(
         .route("/api/health", get(health_check))
         // Legacy endpoints
         .route("/api/requests", get(get_requests))
-        .route("/api/requests/{id}", get(get_request))
+        .route("/api/requests/{id}", get(get_request).delete(delete_request))
         .route("/api/replay/{id}", post(replay_request))
+        .route("/api/curl/{id}", get(get_curl))
+        .route("/api/diff", get(get_diff))
+        .route("/api/qr", get(get_qr))
         .route("/api/clear", post(clear_requests))
+        .route("/api/capture", get(get_capture))
+        .route("/api/capture/pause", post(pause_capture))
+        .route("/api/capture/resume", post(resume_capture))
         .route("/api/metrics", get(get_metrics))
+        .route("/api/metrics/stream", get(metrics_stream))
         .route("/api/info", get(get_info))
         // Multi-tunnel endpoints
         .route("/api/tunnels", get(get_tunnels))
+        .route("/api/tunnels/summary", get(get_tunnel_summaries))
         .route("/api/tunnels/register", post(register_tunnel))
         .route("/api/tunnels/{tunnel_id}/unregister", post(unregister_tunnel))
         .route("/api/tunnels/{tunnel_id}/heartbeat", post(heartbeat))
         .route("/api/tunnels/{tunnel_id}/request", post(submit_request))
         .route("/api/tunnels/{tunnel_id}/requests", get(get_tunnel_requests))
         .route("/api/tunnels/{tunnel_id}/metrics", get(get_tunnel_metrics))
+        .route("/api/tunnels/{tunnel_id}/ws", post(open_websocket))
+        .route("/api/tunnels/{tunnel_id}/ws/frame", post(submit_ws_frame))
+        // Tunnelled WebSocket connections
+        .route("/api/ws", get(get_websockets))
+        .route("/api/ws/{stream_id}", get(get_ws_frames))
         // WebSocket
         .route("/ws", get(ws_handler))
         .with_state(state);
 
-    let addr = format!("127.0.0.1:{}", port);
+    let addr = SocketAddr::new(bind, port);
     let listener = tokio::net::TcpListener::bind(&addr)
         .await
         .context(format!("Failed to bind inspector to {}", addr))?;
 
     // Start cleanup task for stale tunnels
     let cleanup_store = store.clone();
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
-        loop {
-            interval.tick().await;
-            cleanup_store.cleanup_stale_tunnels(120).await; // 2 minute threshold
-        }
+    heartbeat::spawn_interval_task(Duration::from_secs(60), move || {
+        let store = cleanup_store.clone();
+        // Four missed heartbeats
+        async move { store.cleanup_stale_tunnels(4 * constants::HEARTBEAT_INTERVAL_SECONDS as i64).await }
     });
 
     let handle = tokio::spawn(async move {
@@ -190,6 +213,11 @@ async fn get_tunnels(State(state): State<AppState>) -> Json<Vec<RegisteredTunnel
     Json(state.store.get_tunnels().await)
 }
 
+/// Requests, error rate and bytes for each tunnel
+async fn get_tunnel_summaries(State(state): State<AppState>) -> Json<Vec<TunnelSummary>> {
+    Json(state.store.tunnel_summaries().await)
+}
+
 /// Get requests for a specific tunnel
 async fn get_tunnel_requests(
     State(state): State<AppState>,
@@ -209,6 +237,56 @@ async fn get_tunnel_metrics(
     }
 }
 
+/// Start capturing a remote tunnel's WebSocket connection
+async fn open_websocket(
+    State(state): State<AppState>,
+    Path(tunnel_id): Path<String>,
+    Json(request): Json<OpenWebSocketRequest>,
+) -> StatusCode {
+    state
+        .store
+        .open_websocket(&tunnel_id, &request.stream_id, &request.path)
+        .await;
+    StatusCode::OK
+}
+
+/// Submit a WebSocket frame from a remote tunnel
+async fn submit_ws_frame(
+    State(state): State<AppState>,
+    Json(frame): Json<CapturedFrame>,
+) -> StatusCode {
+    state.store.add_ws_frame(frame).await;
+    StatusCode::OK
+}
+
+// ============================================================================
+// WebSocket Frames
+// ============================================================================
+
+#[derive(Deserialize)]
+struct WebSocketsQuery {
+    tunnel: Option<String>,
+}
+
+/// List captured WebSocket connections (all tunnels, or one with `?tunnel=`)
+async fn get_websockets(
+    State(state): State<AppState>,
+    Query(query): Query<WebSocketsQuery>,
+) -> Json<Vec<WebSocketCapture>> {
+    Json(state.store.get_websockets(query.tunnel.as_deref()).await)
+}
+
+/// Frames captured on one WebSocket connection
+async fn get_ws_frames(
+    State(state): State<AppState>,
+    Path(stream_id): Path<String>,
+) -> Response {
+    match state.store.get_ws_frames(&stream_id).await {
+        Some(frames) => Json(frames).into_response(),
+        None => (StatusCode::NOT_FOUND, "WebSocket not found").into_response(),
+    }
+}
+
 // ============================================================================
 // Legacy Endpoints
 // ============================================================================
@@ -234,11 +312,63 @@ async fn get_request(
     }
 }
 
+/// Remove one captured request; open dashboards drop it too
+async fn delete_request(State(state): State<AppState>, Path(id): Path<String>) -> StatusCode {
+    if state.store.remove_request(&id).await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
 /// Get metrics snapshot
 async fn get_metrics(State(state): State<AppState>) -> Json<crate::metrics::MetricsSnapshot> {
     Json(state.store.get_metrics().await)
 }
 
+/// How often the metrics stream rechecks when nothing has happened; the
+/// request rates decay with time even without new requests
+const METRICS_STREAM_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+struct MetricsStreamQuery {
+    tunnel: Option<String>,
+}
+
+/// Server-Sent Events stream of metrics snapshots (all tunnels, or one with
+/// `?tunnel=`), pushed whenever the snapshot changes
+async fn metrics_stream(
+    State(state): State<AppState>,
+    Query(query): Query<MetricsStreamQuery>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let mut events = state.store.subscribe();
+    let stream = async_stream::stream! {
+        let mut ticker = tokio::time::interval(METRICS_STREAM_INTERVAL);
+        let mut last_sent = None;
+        loop {
+            let snapshot = match &query.tunnel {
+                Some(id) => state.store.get_tunnel_metrics(id).await.unwrap_or_default(),
+                None => state.store.get_metrics().await,
+            };
+            let json = serde_json::to_string(&snapshot).unwrap_or_default();
+            if last_sent.as_ref() != Some(&json) {
+                yield Ok(Event::default().data(json.as_str()));
+                last_sent = Some(json);
+            }
+
+            tokio::select! {
+                _ = ticker.tick() => {}
+                event = events.recv() => {
+                    if let Err(broadcast::error::RecvError::Closed) = event {
+                        break;
+                    }
+                }
+            }
+        }
+    };
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
 /// Get tunnel info for status page
 async fn get_info(State(state): State<AppState>) -> Json<super::store::TunnelInfoData> {
     Json(state.store.get_tunnel_info().await)
@@ -324,12 +454,104 @@ async fn replay_request(
     }
 }
 
+/// Render a captured request as a curl command against the tunnel's public
+/// URL, falling back to the upstream address
+async fn get_curl(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    let request = match state.store.get_request(&id).await {
+        Some(req) => req,
+        None => return (StatusCode::NOT_FOUND, "Request not found").into_response(),
+    };
+
+    let public_url = match state.store.get_tunnel(&request.tunnel_id).await {
+        Some(tunnel) => tunnel.public_url,
+        None => state.store.get_tunnel_info().await.public_url,
+    };
+    let base_url = if !public_url.is_empty() {
+        public_url.trim_end_matches('/').to_string()
+    } else if !state.upstream_addr.is_empty() {
+        let scheme = if state.upstream_tls { "https" } else { "http" };
+        format!("{}://{}", scheme, state.upstream_addr)
+    } else {
+        "http://localhost".to_string()
+    };
+
+    super::curl::to_curl(&request, &base_url).into_response()
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    a: String,
+    b: String,
+}
+
+/// Header and body differences between two captured requests
+async fn get_diff(State(state): State<AppState>, Query(query): Query<DiffQuery>) -> Response {
+    let (Some(a), Some(b)) = (
+        state.store.get_request(&query.a).await,
+        state.store.get_request(&query.b).await,
+    ) else {
+        return (StatusCode::NOT_FOUND, "Request not found").into_response();
+    };
+    Json(super::diff::diff_requests(&a, &b)).into_response()
+}
+
+#[derive(Deserialize)]
+struct QrQuery {
+    tunnel: Option<String>,
+}
+
+/// QR code (SVG) for a tunnel's public URL, so it can be scanned from the
+/// dashboard; without `?tunnel=` it is for the legacy single tunnel
+async fn get_qr(State(state): State<AppState>, Query(query): Query<QrQuery>) -> Response {
+    let public_url = match query.tunnel {
+        Some(id) => state.store.get_tunnel(&id).await.map(|t| t.public_url),
+        None => Some(state.store.get_tunnel_info().await.public_url),
+    };
+    let Some(public_url) = public_url.filter(|url| !url.is_empty()) else {
+        return (StatusCode::NOT_FOUND, "No public URL yet").into_response();
+    };
+
+    match qrcode::QrCode::new(&public_url) {
+        Ok(code) => {
+            let svg = code
+                .render::<qrcode::render::svg::Color>()
+                .min_dimensions(200, 200)
+                .build();
+            ([(header::CONTENT_TYPE, "image/svg+xml")], svg).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
 /// Clear all captured requests
 async fn clear_requests(State(state): State<AppState>) -> StatusCode {
     state.store.clear().await;
     StatusCode::OK
 }
 
+#[derive(Serialize)]
+struct CaptureState {
+    paused: bool,
+}
+
+/// Whether new requests are being kept
+async fn get_capture(State(state): State<AppState>) -> Json<CaptureState> {
+    Json(CaptureState {
+        paused: state.store.is_capture_paused(),
+    })
+}
+
+/// Freeze the captured requests; traffic keeps flowing
+async fn pause_capture(State(state): State<AppState>) -> Json<CaptureState> {
+    state.store.set_capture_paused(true);
+    get_capture(State(state)).await
+}
+
+async fn resume_capture(State(state): State<AppState>) -> Json<CaptureState> {
+    state.store.set_capture_paused(false);
+    get_capture(State(state)).await
+}
+
 // ============================================================================
 // WebSocket
 // ============================================================================
@@ -406,3 +628,100 @@ async fn handle_websocket(socket: WebSocket, state: AppState) {
 
     send_task.abort();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state(store: Arc<RequestStore>) -> AppState {
+        AppState {
+            store,
+            upstream_addr: Arc::new(String::new()),
+            upstream_tls: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_qr_endpoint_returns_svg() {
+        let store = Arc::new(RequestStore::new());
+        let state = test_state(store.clone());
+        let no_tunnel = || Query(QrQuery { tunnel: None });
+
+        let response = get_qr(State(state.clone()), no_tunnel()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        store
+            .set_tunnel_info("https://demo.dvaar.app".to_string(), "localhost:3000".to_string())
+            .await;
+        let response = get_qr(State(state), no_tunnel()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/svg+xml");
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let svg = std::str::from_utf8(&body).unwrap();
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.contains("<svg") && svg.trim_end().ends_with("</svg>"));
+        assert!(svg.contains("<path"));
+    }
+
+    #[tokio::test]
+    async fn test_delete_request_notifies_dashboards() {
+        let store = Arc::new(RequestStore::new());
+        for id in ["keep", "drop"] {
+            store
+                .add_request(CapturedRequest {
+                    id: id.to_string(),
+                    tunnel_id: String::new(),
+                    timestamp: Utc::now(),
+                    method: "GET".to_string(),
+                    path: "/".to_string(),
+                    request_headers: Vec::new(),
+                    request_body: Vec::new(),
+                    response_status: 200,
+                    response_headers: Vec::new(),
+                    response_body: Vec::new(),
+                    duration_ms: 1,
+                    ttfb_ms: None,
+                    upstream_connect_ms: None,
+                    size_bytes: 0,
+                    request_size_bytes: 0,
+                    trace_id: None,
+                    body_evicted: false,
+                })
+                .await;
+        }
+        let state = test_state(store.clone());
+        let mut dashboard = store.subscribe();
+
+        let status = delete_request(State(state.clone()), Path("drop".to_string())).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let event = serde_json::to_value(dashboard.try_recv().unwrap()).unwrap();
+        assert_eq!(event["type"], "clear");
+        assert_eq!(event["data"]["request_id"], "drop");
+
+        let left: Vec<_> = store.get_requests().await.into_iter().map(|r| r.id).collect();
+        assert_eq!(left, ["keep"]);
+        let status = delete_request(State(state), Path("drop".to_string())).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_stream_sends_snapshot() {
+        let state = test_state(Arc::new(RequestStore::new()));
+        let response = metrics_stream(State(state), Query(MetricsStreamQuery { tunnel: None }))
+            .await
+            .into_response();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+
+        let mut body = response.into_body().into_data_stream();
+        let frame = tokio::time::timeout(Duration::from_secs(5), body.next())
+            .await
+            .expect("no event within 5s")
+            .unwrap()
+            .unwrap();
+        let frame = std::str::from_utf8(&frame).unwrap();
+        let data = frame.strip_prefix("data: ").unwrap().trim_end();
+        let snapshot: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(snapshot["total_requests"], 0);
+    }
+}
@@ -4,12 +4,22 @@ use crate::metrics::{Metrics, MetricsSnapshot};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::sync::{broadcast, RwLock};
 
 /// Maximum number of requests to store per tunnel
 const MAX_REQUESTS_PER_TUNNEL: usize = 50;
 
+/// Default for `--inspect-memory-mb`
+pub const DEFAULT_INSPECT_MEMORY_MB: usize = 64;
+
+/// Payload bytes kept per WebSocket connection; older frames go first
+const MAX_FRAME_BYTES_PER_CONNECTION: usize = 256 * 1024;
+
+/// WebSocket connections kept; the one quiet the longest goes first
+const MAX_WEBSOCKETS: usize = 50;
+
 /// Tunnel status
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -30,6 +40,23 @@ pub struct RegisteredTunnel {
     pub last_seen: DateTime<Utc>,
 }
 
+/// Traffic totals for one tunnel, over the requests the store holds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TunnelSummary {
+    pub tunnel_id: String,
+    pub subdomain: String,
+    pub status: TunnelStatus,
+    pub requests: usize,
+    /// Responses with a 5xx status
+    pub errors: usize,
+    /// `errors / requests`, 0 with no requests
+    pub error_rate: f64,
+    /// Request body bytes up the tunnel
+    pub bytes_in: u64,
+    /// Response body bytes back down it
+    pub bytes_out: u64,
+}
+
 /// Tunnel info for the status page (legacy, kept for compatibility)
 #[derive(Debug, Clone, Default, Serialize)]
 pub struct TunnelInfoData {
@@ -54,7 +81,123 @@ pub struct CapturedRequest {
     #[serde(with = "base64_serde")]
     pub response_body: Vec<u8>,
     pub duration_ms: u64,
+    /// Time from receiving the request until the first response byte from upstream
+    #[serde(default)]
+    pub ttfb_ms: Option<u64>,
+    /// Time spent in the upstream send, up to receiving response headers
+    #[serde(default)]
+    pub upstream_connect_ms: Option<u64>,
+    /// Response body bytes sent back down the tunnel
     pub size_bytes: usize,
+    /// Request body bytes that came up the tunnel, captured or not
+    #[serde(default)]
+    pub request_size_bytes: usize,
+    /// Trace id from the request's `traceparent` header
+    #[serde(default)]
+    pub trace_id: Option<String>,
+    /// Bodies were dropped to keep the store within its memory budget
+    #[serde(default)]
+    pub body_evicted: bool,
+}
+
+impl CapturedRequest {
+    /// Approximate memory held by this capture
+    fn captured_bytes(&self) -> usize {
+        let headers: usize = self
+            .request_headers
+            .iter()
+            .chain(&self.response_headers)
+            .map(|(k, v)| k.len() + v.len())
+            .sum();
+        self.request_body.len() + self.response_body.len() + headers + self.path.len()
+    }
+
+    fn has_body(&self) -> bool {
+        !self.request_body.is_empty() || !self.response_body.is_empty()
+    }
+
+    /// Drop the bodies, returning how many bytes that freed
+    fn evict_body(&mut self) -> usize {
+        let freed = self.request_body.len() + self.response_body.len();
+        self.request_body = Vec::new();
+        self.response_body = Vec::new();
+        self.body_evicted = true;
+        freed
+    }
+
+    /// Extract the trace id from a W3C `traceparent` header (`00-<trace-id>-<parent-id>-<flags>`)
+    pub fn trace_id_from_headers(headers: &[(String, String)]) -> Option<String> {
+        let value = headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("traceparent"))
+            .map(|(_, v)| v.trim())?;
+
+        let mut parts = value.split('-');
+        let _version = parts.next()?;
+        let trace_id = parts.next()?;
+        if trace_id.len() != 32 || !trace_id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(trace_id.to_ascii_lowercase())
+    }
+}
+
+/// Which way a WebSocket frame crossed the tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameDirection {
+    /// From the visitor to the local app
+    Inbound,
+    /// From the local app to the visitor
+    Outbound,
+}
+
+/// A WebSocket frame seen on a tunnelled connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedFrame {
+    pub stream_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub direction: FrameDirection,
+    pub is_binary: bool,
+    /// Size on the wire, even when `data` was cut short
+    pub size: usize,
+    #[serde(with = "base64_serde")]
+    pub data: Vec<u8>,
+}
+
+/// A tunnelled WebSocket connection, without its frames
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebSocketCapture {
+    pub stream_id: String,
+    #[serde(default)]
+    pub tunnel_id: String,
+    pub path: String,
+    pub opened_at: DateTime<Utc>,
+    pub last_frame_at: Option<DateTime<Utc>>,
+    /// Frames seen, including ones no longer kept
+    pub frame_count: usize,
+}
+
+struct FrameLog {
+    connection: WebSocketCapture,
+    frames: VecDeque<CapturedFrame>,
+    bytes: usize,
+}
+
+impl FrameLog {
+    fn push(&mut self, mut frame: CapturedFrame) {
+        frame.data.truncate(MAX_FRAME_BYTES_PER_CONNECTION);
+        self.connection.frame_count += 1;
+        self.connection.last_frame_at = Some(frame.timestamp);
+        self.bytes += frame.data.len();
+        self.frames.push_back(frame);
+        while self.bytes > MAX_FRAME_BYTES_PER_CONNECTION {
+            let Some(dropped) = self.frames.pop_front() else {
+                break;
+            };
+            self.bytes -= dropped.data.len();
+        }
+    }
 }
 
 /// Events broadcast to WebSocket subscribers
@@ -63,8 +206,13 @@ pub struct CapturedRequest {
 pub enum InspectorEvent {
     #[serde(rename = "request")]
     NewRequest(CapturedRequest),
+    /// Requests removed: a tunnel's (or all), or with `request_id` just that one
     #[serde(rename = "clear")]
-    Clear { tunnel_id: Option<String> },
+    Clear {
+        tunnel_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
     #[serde(rename = "tunnel_registered")]
     TunnelRegistered(RegisteredTunnel),
     #[serde(rename = "tunnel_unregistered")]
@@ -73,6 +221,12 @@ pub enum InspectorEvent {
     TunnelStatusUpdate { tunnel_id: String, status: TunnelStatus },
     #[serde(rename = "tunnel_updated")]
     TunnelUpdated(RegisteredTunnel),
+    #[serde(rename = "ws_opened")]
+    WebSocketOpened(WebSocketCapture),
+    #[serde(rename = "ws_frame")]
+    WebSocketFrame(CapturedFrame),
+    #[serde(rename = "capture")]
+    Capture { paused: bool },
 }
 
 /// Store for captured requests with broadcast capability
@@ -87,10 +241,25 @@ pub struct RequestStore {
     broadcast_tx: broadcast::Sender<InspectorEvent>,
     /// Legacy tunnel info (for single-tunnel compatibility)
     tunnel_info: RwLock<TunnelInfoData>,
+    /// Bytes held by all stored requests; only changed under the `requests` write lock
+    captured_bytes: AtomicUsize,
+    /// Most bytes to hold before evicting the oldest captures
+    memory_budget: usize,
+    /// Frames of tunnelled WebSocket connections: stream_id -> frames
+    websockets: RwLock<HashMap<String, FrameLog>>,
+    /// While set, requests and frames are counted in the metrics but not kept
+    capture_paused: AtomicBool,
 }
 
 impl RequestStore {
     pub fn new() -> Self {
+        Self::with_memory_budget(DEFAULT_INSPECT_MEMORY_MB * 1024 * 1024)
+    }
+
+    /// Store that holds at most `memory_budget` bytes of captures across all
+    /// tunnels. Past that, the oldest bodies are dropped first, keeping their
+    /// metadata, and only then whole requests.
+    pub fn with_memory_budget(memory_budget: usize) -> Self {
         let (broadcast_tx, _) = broadcast::channel(100);
         Self {
             requests: RwLock::new(HashMap::new()),
@@ -98,9 +267,84 @@ impl RequestStore {
             metrics: RwLock::new(HashMap::new()),
             broadcast_tx,
             tunnel_info: RwLock::new(TunnelInfoData::default()),
+            captured_bytes: AtomicUsize::new(0),
+            memory_budget,
+            websockets: RwLock::new(HashMap::new()),
+            capture_paused: AtomicBool::new(false),
         }
     }
 
+    /// Stop or restart keeping new requests and frames. Traffic through the
+    /// tunnels is unaffected.
+    pub fn set_capture_paused(&self, paused: bool) {
+        if self.capture_paused.swap(paused, Ordering::Relaxed) != paused {
+            let _ = self.broadcast_tx.send(InspectorEvent::Capture { paused });
+        }
+    }
+
+    pub fn is_capture_paused(&self) -> bool {
+        self.capture_paused.load(Ordering::Relaxed)
+    }
+
+    /// Bytes currently held by stored requests
+    pub fn captured_bytes(&self) -> usize {
+        self.captured_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Append to a tunnel's requests, making room by count and by memory
+    fn push_request(
+        &self,
+        requests: &mut HashMap<String, VecDeque<CapturedRequest>>,
+        tunnel_id: &str,
+        request: CapturedRequest,
+    ) {
+        let Some(tunnel_requests) = requests.get_mut(tunnel_id) else {
+            return;
+        };
+        if tunnel_requests.len() >= MAX_REQUESTS_PER_TUNNEL {
+            if let Some(evicted) = tunnel_requests.pop_front() {
+                self.captured_bytes.fetch_sub(evicted.captured_bytes(), Ordering::Relaxed);
+            }
+        }
+        self.captured_bytes.fetch_add(request.captured_bytes(), Ordering::Relaxed);
+        tunnel_requests.push_back(request);
+        self.enforce_memory_budget(requests);
+    }
+
+    fn enforce_memory_budget(&self, requests: &mut HashMap<String, VecDeque<CapturedRequest>>) {
+        while self.captured_bytes() > self.memory_budget {
+            // Oldest request, across all tunnels, that still has a body
+            let oldest_body = requests
+                .iter()
+                .filter_map(|(tunnel_id, queue)| {
+                    let index = queue.iter().position(CapturedRequest::has_body)?;
+                    Some((queue[index].timestamp, tunnel_id.clone(), index))
+                })
+                .min();
+            if let Some((_, tunnel_id, index)) = oldest_body {
+                let freed = requests.get_mut(&tunnel_id).unwrap()[index].evict_body();
+                self.captured_bytes.fetch_sub(freed, Ordering::Relaxed);
+                continue;
+            }
+
+            let oldest = requests
+                .iter()
+                .filter_map(|(tunnel_id, queue)| Some((queue.front()?.timestamp, tunnel_id.clone())))
+                .min();
+            let Some((_, tunnel_id)) = oldest else {
+                break;
+            };
+            if let Some(evicted) = requests.get_mut(&tunnel_id).unwrap().pop_front() {
+                self.captured_bytes.fetch_sub(evicted.captured_bytes(), Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn forget_requests<'a>(&self, requests: impl IntoIterator<Item = &'a CapturedRequest>) {
+        let freed: usize = requests.into_iter().map(CapturedRequest::captured_bytes).sum();
+        self.captured_bytes.fetch_sub(freed, Ordering::Relaxed);
+    }
+
     /// Register a new tunnel
     pub async fn register_tunnel(&self, tunnel: RegisteredTunnel) -> String {
         let tunnel_id = tunnel.tunnel_id.clone();
@@ -189,6 +433,41 @@ impl RequestStore {
         self.tunnels.read().await.values().cloned().collect()
     }
 
+    /// Per-tunnel totals for the dashboard's summary bar, oldest tunnel first
+    pub async fn tunnel_summaries(&self) -> Vec<TunnelSummary> {
+        let tunnels = self.tunnels.read().await;
+        let requests = self.requests.read().await;
+        let mut summaries: Vec<(DateTime<Utc>, TunnelSummary)> = tunnels
+            .values()
+            .map(|tunnel| {
+                let captured = requests.get(&tunnel.tunnel_id);
+                let captured = captured.iter().flat_map(|queue| queue.iter());
+                let mut summary = TunnelSummary {
+                    tunnel_id: tunnel.tunnel_id.clone(),
+                    subdomain: tunnel.subdomain.clone(),
+                    status: tunnel.status,
+                    requests: 0,
+                    errors: 0,
+                    error_rate: 0.0,
+                    bytes_in: 0,
+                    bytes_out: 0,
+                };
+                for request in captured {
+                    summary.requests += 1;
+                    summary.errors += usize::from(request.response_status >= 500);
+                    summary.bytes_in += request.request_size_bytes as u64;
+                    summary.bytes_out += request.size_bytes as u64;
+                }
+                if summary.requests > 0 {
+                    summary.error_rate = summary.errors as f64 / summary.requests as f64;
+                }
+                (tunnel.registered_at, summary)
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.tunnel_id.cmp(&b.1.tunnel_id)));
+        summaries.into_iter().map(|(_, summary)| summary).collect()
+    }
+
     /// Get a specific tunnel
     pub async fn get_tunnel(&self, tunnel_id: &str) -> Option<RegisteredTunnel> {
         self.tunnels.read().await.get(tunnel_id).cloned()
@@ -251,18 +530,17 @@ impl RequestStore {
 
         // Record metrics for this tunnel
         if let Some(metrics) = self.metrics.read().await.get(tunnel_id) {
-            metrics.record_request(request.duration_ms).await;
+            metrics
+                .record_request(request.duration_ms, request.request_size_bytes, request.size_bytes)
+                .await;
         }
 
-        let mut requests = self.requests.write().await;
-        if let Some(tunnel_requests) = requests.get_mut(tunnel_id) {
-            // Evict oldest if at capacity
-            if tunnel_requests.len() >= MAX_REQUESTS_PER_TUNNEL {
-                tunnel_requests.pop_front();
-            }
-            tunnel_requests.push_back(request.clone());
+        if self.is_capture_paused() {
+            return;
         }
 
+        self.push_request(&mut *self.requests.write().await, tunnel_id, request.clone());
+
         // Broadcast to subscribers
         let _ = self.broadcast_tx.send(InspectorEvent::NewRequest(request));
     }
@@ -276,16 +554,15 @@ impl RequestStore {
         };
 
         if tunnel_id.is_empty() {
+            if self.is_capture_paused() {
+                return;
+            }
             // No tunnel registered, store in default bucket
             let mut requests = self.requests.write().await;
-            let default_requests = requests.entry(String::new()).or_insert_with(|| {
+            requests.entry(String::new()).or_insert_with(|| {
                 VecDeque::with_capacity(MAX_REQUESTS_PER_TUNNEL)
             });
-
-            if default_requests.len() >= MAX_REQUESTS_PER_TUNNEL {
-                default_requests.pop_front();
-            }
-            default_requests.push_back(request.clone());
+            self.push_request(&mut requests, "", request.clone());
             let _ = self.broadcast_tx.send(InspectorEvent::NewRequest(request));
         } else {
             self.add_request_for_tunnel(&tunnel_id, request).await;
@@ -325,23 +602,121 @@ impl RequestStore {
         None
     }
 
+    /// Start capturing frames of a WebSocket connection
+    pub async fn open_websocket(&self, tunnel_id: &str, stream_id: &str, path: &str) {
+        let connection = WebSocketCapture {
+            stream_id: stream_id.to_string(),
+            tunnel_id: tunnel_id.to_string(),
+            path: path.to_string(),
+            opened_at: Utc::now(),
+            last_frame_at: None,
+            frame_count: 0,
+        };
+
+        let mut websockets = self.websockets.write().await;
+        if websockets.len() >= MAX_WEBSOCKETS && !websockets.contains_key(stream_id) {
+            let quietest = websockets
+                .values()
+                .min_by_key(|log| log.connection.last_frame_at.unwrap_or(log.connection.opened_at))
+                .map(|log| log.connection.stream_id.clone());
+            if let Some(stream_id) = quietest {
+                websockets.remove(&stream_id);
+            }
+        }
+        websockets.insert(
+            stream_id.to_string(),
+            FrameLog {
+                connection: connection.clone(),
+                frames: VecDeque::new(),
+                bytes: 0,
+            },
+        );
+        drop(websockets);
+
+        let _ = self.broadcast_tx.send(InspectorEvent::WebSocketOpened(connection));
+    }
+
+    /// Record a frame of a connection passed to `open_websocket`
+    pub async fn add_ws_frame(&self, frame: CapturedFrame) {
+        if self.is_capture_paused() {
+            return;
+        }
+        let mut websockets = self.websockets.write().await;
+        let Some(log) = websockets.get_mut(&frame.stream_id) else {
+            return;
+        };
+        log.push(frame.clone());
+        drop(websockets);
+
+        let _ = self.broadcast_tx.send(InspectorEvent::WebSocketFrame(frame));
+    }
+
+    /// WebSocket connections for a specific tunnel (or all if None), oldest first
+    pub async fn get_websockets(&self, tunnel_id: Option<&str>) -> Vec<WebSocketCapture> {
+        let mut connections: Vec<_> = self
+            .websockets
+            .read()
+            .await
+            .values()
+            .filter(|log| tunnel_id.is_none_or(|id| log.connection.tunnel_id == id))
+            .map(|log| log.connection.clone())
+            .collect();
+        connections.sort_by_key(|connection| connection.opened_at);
+        connections
+    }
+
+    /// The frames still kept for a WebSocket connection
+    pub async fn get_ws_frames(&self, stream_id: &str) -> Option<Vec<CapturedFrame>> {
+        let websockets = self.websockets.read().await;
+        websockets
+            .get(stream_id)
+            .map(|log| log.frames.iter().cloned().collect())
+    }
+
     /// Clear requests for a specific tunnel (or all if None)
     pub async fn clear_tunnel(&self, tunnel_id: Option<&str>) {
         match tunnel_id {
             Some(id) => {
                 if let Some(requests) = self.requests.write().await.get_mut(id) {
+                    self.forget_requests(requests.iter());
                     requests.clear();
                 }
             }
             None => {
                 for requests in self.requests.write().await.values_mut() {
+                    self.forget_requests(requests.iter());
                     requests.clear();
                 }
             }
         }
+        self.websockets
+            .write()
+            .await
+            .retain(|_, log| tunnel_id.is_some_and(|id| log.connection.tunnel_id != id));
         let _ = self.broadcast_tx.send(InspectorEvent::Clear {
             tunnel_id: tunnel_id.map(String::from),
+            request_id: None,
+        });
+    }
+
+    /// Remove one captured request. Returns false if there was none by that id.
+    pub async fn remove_request(&self, id: &str) -> bool {
+        let mut requests = self.requests.write().await;
+        let removed = requests.iter_mut().find_map(|(tunnel_id, tunnel_requests)| {
+            let index = tunnel_requests.iter().position(|r| r.id == id)?;
+            Some((tunnel_id.clone(), tunnel_requests.remove(index)?))
+        });
+        let Some((tunnel_id, request)) = removed else {
+            return false;
+        };
+        self.forget_requests([&request]);
+        drop(requests);
+
+        let _ = self.broadcast_tx.send(InspectorEvent::Clear {
+            tunnel_id: Some(tunnel_id),
+            request_id: Some(request.id),
         });
+        true
     }
 
     /// Clear all requests (legacy method)
@@ -406,3 +781,261 @@ mod base64_serde {
         STANDARD.decode(&s).map_err(serde::de::Error::custom)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_id_from_traceparent() {
+        let headers = vec![
+            ("Accept".to_string(), "*/*".to_string()),
+            (
+                "Traceparent".to_string(),
+                "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01".to_string(),
+            ),
+        ];
+        assert_eq!(
+            CapturedRequest::trace_id_from_headers(&headers).as_deref(),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+    }
+
+    fn request(id: usize, timestamp: DateTime<Utc>, body_len: usize) -> CapturedRequest {
+        CapturedRequest {
+            id: id.to_string(),
+            tunnel_id: String::new(),
+            timestamp,
+            method: "POST".to_string(),
+            path: "/".to_string(),
+            request_headers: Vec::new(),
+            request_body: vec![b'q'; body_len],
+            response_status: 200,
+            response_headers: Vec::new(),
+            response_body: vec![b's'; body_len],
+            duration_ms: 1,
+            ttfb_ms: None,
+            upstream_connect_ms: None,
+            size_bytes: body_len,
+            request_size_bytes: body_len,
+            trace_id: None,
+            body_evicted: false,
+        }
+    }
+
+    fn tunnel(tunnel_id: &str) -> RegisteredTunnel {
+        RegisteredTunnel {
+            tunnel_id: tunnel_id.to_string(),
+            subdomain: tunnel_id.to_string(),
+            public_url: String::new(),
+            local_addr: "localhost:3000".to_string(),
+            status: TunnelStatus::Active,
+            registered_at: Utc::now(),
+            last_seen: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_budget_evicts_oldest_bodies_first() {
+        const BODY: usize = 1000;
+        // Room for five requests' bodies plus all eight one-byte paths
+        let budget = 5 * 2 * BODY + 8;
+        let store = RequestStore::with_memory_budget(budget);
+        store.register_tunnel(tunnel("a")).await;
+        store.register_tunnel(tunnel("b")).await;
+
+        let start = Utc::now();
+        for i in 0..8 {
+            let tunnel_id = if i % 2 == 0 { "a" } else { "b" };
+            let at = start + chrono::Duration::milliseconds(i as i64);
+            store.add_request_for_tunnel(tunnel_id, request(i, at, BODY)).await;
+        }
+
+        let requests = store.get_requests().await;
+        assert_eq!(requests.len(), 8, "metadata is kept");
+        for (i, request) in requests.iter().enumerate() {
+            assert_eq!(request.id, i.to_string());
+            let evicted = i < 3;
+            assert_eq!(request.body_evicted, evicted, "request {}", i);
+            assert_eq!(request.response_body.len(), if evicted { 0 } else { BODY });
+        }
+        assert_eq!(store.captured_bytes(), budget);
+
+        // With every body gone, whole requests go, oldest first
+        let tiny = RequestStore::with_memory_budget(3);
+        tiny.register_tunnel(tunnel("a")).await;
+        for i in 0..5 {
+            let at = start + chrono::Duration::milliseconds(i as i64);
+            tiny.add_request_for_tunnel("a", request(i, at, BODY)).await;
+        }
+        let ids: Vec<_> = tiny.get_requests().await.into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, ["2", "3", "4"]);
+        assert!(tiny.get_requests().await.iter().all(|r| r.body_evicted));
+
+        tiny.clear().await;
+        assert_eq!(tiny.captured_bytes(), 0);
+    }
+
+    fn frame(stream_id: &str, direction: FrameDirection, data: &[u8]) -> CapturedFrame {
+        CapturedFrame {
+            stream_id: stream_id.to_string(),
+            timestamp: Utc::now(),
+            direction,
+            is_binary: false,
+            size: data.len(),
+            data: data.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_paused_capture_keeps_the_store_still() {
+        let store = RequestStore::new();
+        store.register_tunnel(tunnel("a")).await;
+        let mut events = store.subscribe();
+        let at = |i: usize| Utc::now() + chrono::Duration::milliseconds(i as i64);
+
+        store.add_request_for_tunnel("a", request(0, at(0), 100)).await;
+        let held = store.captured_bytes();
+
+        store.set_capture_paused(true);
+        assert!(matches!(events.try_recv(), Ok(InspectorEvent::NewRequest(_))));
+        assert!(matches!(events.try_recv(), Ok(InspectorEvent::Capture { paused: true })));
+        for i in 1..=5 {
+            store.add_request_for_tunnel("a", request(i, at(i), 100)).await;
+        }
+        store.open_websocket("a", "ws-1", "/chat").await;
+        store.add_ws_frame(frame("ws-1", FrameDirection::Inbound, b"hello")).await;
+
+        assert_eq!(store.get_requests().await.len(), 1);
+        assert_eq!(store.captured_bytes(), held);
+        assert!(store.get_ws_frames("ws-1").await.unwrap().is_empty());
+        // Only the opened connection was announced; no requests or frames
+        assert!(matches!(events.try_recv(), Ok(InspectorEvent::WebSocketOpened(_))));
+        assert!(events.try_recv().is_err());
+        // The traffic still shows in the metrics
+        assert_eq!(store.get_tunnel_metrics("a").await.unwrap().total_requests, 6);
+
+        store.set_capture_paused(false);
+        store.add_request_for_tunnel("a", request(6, at(6), 100)).await;
+        let ids: Vec<_> = store.get_requests().await.into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, ["0", "6"]);
+    }
+
+    #[tokio::test]
+    async fn test_remove_one_request() {
+        let store = RequestStore::new();
+        store.register_tunnel(tunnel("a")).await;
+        store.register_tunnel(tunnel("b")).await;
+        let at = |i: usize| Utc::now() + chrono::Duration::milliseconds(i as i64);
+        store.add_request_for_tunnel("a", request(0, at(0), 100)).await;
+        store.add_request_for_tunnel("a", request(1, at(1), 300)).await;
+        store.add_request_for_tunnel("b", request(2, at(2), 100)).await;
+        let held = store.captured_bytes();
+        let mut events = store.subscribe();
+
+        assert!(store.remove_request("1").await);
+        let ids: Vec<_> = store.get_requests().await.into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, ["0", "2"]);
+        assert_eq!(store.captured_bytes(), held - request(1, at(1), 300).captured_bytes());
+        match events.try_recv() {
+            Ok(InspectorEvent::Clear { tunnel_id, request_id }) => {
+                assert_eq!(tunnel_id.as_deref(), Some("a"));
+                assert_eq!(request_id.as_deref(), Some("1"));
+            }
+            other => panic!("expected Clear, got {:?}", other),
+        }
+
+        assert!(!store.remove_request("1").await);
+        assert!(!store.remove_request("missing").await);
+        assert!(events.try_recv().is_err());
+        assert_eq!(store.get_requests().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_ws_frames_recorded_per_connection() {
+        let store = RequestStore::new();
+        store.open_websocket("a", "ws-1", "/chat").await;
+        store.add_ws_frame(frame("ws-1", FrameDirection::Inbound, b"hello")).await;
+        store.add_ws_frame(frame("ws-1", FrameDirection::Outbound, b"hi there")).await;
+        // Frames of connections that were never opened aren't kept
+        store.add_ws_frame(frame("ws-2", FrameDirection::Inbound, b"stray")).await;
+
+        let frames = store.get_ws_frames("ws-1").await.unwrap();
+        let seen: Vec<_> = frames.iter().map(|f| (f.direction, f.data.as_slice())).collect();
+        assert_eq!(
+            seen,
+            [(FrameDirection::Inbound, &b"hello"[..]), (FrameDirection::Outbound, &b"hi there"[..])]
+        );
+        assert!(store.get_ws_frames("ws-2").await.is_none());
+
+        let connections = store.get_websockets(Some("a")).await;
+        assert_eq!(connections.len(), 1);
+        assert_eq!((connections[0].path.as_str(), connections[0].frame_count), ("/chat", 2));
+        assert!(store.get_websockets(Some("b")).await.is_empty());
+
+        // Past the per-connection cap the oldest frames go; a huge one is cut short
+        let big = vec![b'x'; MAX_FRAME_BYTES_PER_CONNECTION / 2 + 1];
+        store.add_ws_frame(frame("ws-1", FrameDirection::Outbound, &big)).await;
+        store.add_ws_frame(frame("ws-1", FrameDirection::Outbound, &big)).await;
+        let frames = store.get_ws_frames("ws-1").await.unwrap();
+        assert_eq!(frames.len(), 1);
+        let huge = vec![b'x'; MAX_FRAME_BYTES_PER_CONNECTION * 2];
+        store.add_ws_frame(frame("ws-1", FrameDirection::Inbound, &huge)).await;
+        let frames = store.get_ws_frames("ws-1").await.unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].size, huge.len());
+        assert_eq!(frames[0].data.len(), MAX_FRAME_BYTES_PER_CONNECTION);
+        assert_eq!(store.get_websockets(None).await[0].frame_count, 5);
+
+        store.clear().await;
+        assert!(store.get_websockets(None).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_summaries() {
+        let store = RequestStore::new();
+        store.register_tunnel(tunnel("web")).await;
+        store.register_tunnel(tunnel("api")).await;
+        store.register_tunnel(tunnel("idle")).await;
+
+        let at = |i: usize| Utc::now() + chrono::Duration::milliseconds(i as i64);
+        for i in 0..3 {
+            store.add_request_for_tunnel("web", request(i, at(i), 100)).await;
+        }
+        for (i, status) in [200, 502, 503, 404].into_iter().enumerate() {
+            let mut failed = request(10 + i, at(10 + i), 10);
+            failed.response_status = status;
+            failed.request_size_bytes = 0;
+            store.add_request_for_tunnel("api", failed).await;
+        }
+        store.unregister_tunnel("idle").await;
+
+        let summaries = store.tunnel_summaries().await;
+        let by_id = |id: &str| summaries.iter().find(|s| s.tunnel_id == id).unwrap().clone();
+        assert_eq!(summaries.len(), 3);
+
+        let web = by_id("web");
+        assert_eq!((web.requests, web.errors, web.error_rate), (3, 0, 0.0));
+        assert_eq!((web.bytes_in, web.bytes_out), (300, 300));
+
+        // A 404 is the app answering, not a failure
+        let api = by_id("api");
+        assert_eq!((api.requests, api.errors, api.error_rate), (4, 2, 0.5));
+        assert_eq!((api.bytes_in, api.bytes_out), (0, 40));
+
+        let idle = by_id("idle");
+        assert_eq!((idle.requests, idle.error_rate), (0, 0.0));
+        assert_eq!(idle.status, TunnelStatus::Disconnected);
+
+        store.clear_tunnel(Some("api")).await;
+        assert_eq!(store.tunnel_summaries().await.iter().map(|s| s.requests).sum::<usize>(), 3);
+    }
+
+    #[test]
+    fn test_trace_id_missing_or_malformed() {
+        assert_eq!(CapturedRequest::trace_id_from_headers(&[]), None);
+
+        let headers = vec![("traceparent".to_string(), "00-not-a-trace-01".to_string())];
+        assert_eq!(CapturedRequest::trace_id_from_headers(&headers), None);
+    }
+}
@@ -3,10 +3,13 @@
 //! Usage:
 //!   dvaar login [TOKEN]         Authenticate with Dvaar
 //!   dvaar http <TARGET>         Create an HTTP tunnel
-//!   dvaar ls                    List active tunnels
+//!   dvaar grpc <TARGET>         Create a tunnel to a gRPC server
+//!   dvaar ls [--json]           List active tunnels
+//!   dvaar status [SUBDOMAIN]    Check tunnels registered on the server
+//!   dvaar share <SUBDOMAIN>     Share a read-only view of a tunnel's inspector
 //!   dvaar stop <ID>             Stop a tunnel
 //!   dvaar logs <ID>             View tunnel logs
-//!   dvaar usage                 View bandwidth usage
+//!   dvaar usage [--json]        View bandwidth usage
 //!   dvaar upgrade               Upgrade your plan
 
 mod commands;
@@ -35,6 +38,8 @@ struct Cli {
     verbose: bool,
 }
 
+// Parsed once at startup; boxing the `http` options would buy nothing
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Authenticate with Dvaar
@@ -52,11 +57,11 @@ enum Commands {
         #[arg(short = 's', long = "subdomain")]
         subdomain: Option<String>,
 
-        /// Enable basic authentication (format: user:password)
+        /// Enable basic authentication (format: user:password, or @file / env:VAR to read it from there)
         #[arg(long)]
         auth: Option<String>,
 
-        /// Override the Host header sent to upstream
+        /// Override the Host header sent to upstream (also accepts @file / env:VAR)
         #[arg(long)]
         host_header: Option<String>,
 
@@ -68,6 +73,192 @@ enum Commands {
         #[arg(long)]
         use_tls: bool,
 
+        /// Accept any certificate from the upstream (self-signed dev servers)
+        #[arg(long, requires = "use_tls")]
+        insecure_upstream: bool,
+
+        /// Also trust the CA certificates in this PEM file for the upstream
+        #[arg(long, value_name = "FILE", requires = "use_tls")]
+        upstream_ca: Option<std::path::PathBuf>,
+
+        /// Server name to send as SNI and verify the upstream's certificate against
+        #[arg(long, value_name = "NAME", requires = "use_tls")]
+        upstream_sni: Option<String>,
+
+        /// Client certificate (PEM) to present to upstreams that require one
+        #[arg(long, value_name = "FILE", requires_all = ["use_tls", "upstream_client_key"])]
+        upstream_client_cert: Option<std::path::PathBuf>,
+
+        /// Private key (PEM) for --upstream-client-cert
+        #[arg(long, value_name = "FILE", requires = "upstream_client_cert")]
+        upstream_client_key: Option<std::path::PathBuf>,
+
+        /// Speak HTTP/2 to the upstream (prior knowledge, or ALPN with --use-tls)
+        #[arg(long)]
+        upstream_http2: bool,
+
+        /// HTML page shown to visitors while the tunnel is offline
+        #[arg(long, value_name = "FILE")]
+        offline_page: Option<std::path::PathBuf>,
+
+        /// Seconds to wait for the upstream to accept a connection
+        #[arg(long, value_name = "SECS", default_value_t = dvaar_common::constants::UPSTREAM_CONNECT_TIMEOUT_SECONDS)]
+        connect_timeout: u64,
+
+        /// Seconds an upstream response may stay silent before it is abandoned (0 = never)
+        #[arg(long, value_name = "SECS", default_value_t = dvaar_common::constants::UPSTREAM_RESPONSE_TIMEOUT_SECONDS)]
+        response_timeout: u64,
+
+        /// Retry GET/HEAD/OPTIONS up to N times while the upstream is down or answers 502/503
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        retry_upstream: u32,
+
+        /// Idle upstream connections kept open for reuse
+        #[arg(long, value_name = "N", default_value_t = dvaar_common::constants::UPSTREAM_POOL_MAX_IDLE_PER_HOST)]
+        pool_max_idle: usize,
+
+        /// Print one JSON line per request (used for detached sessions)
+        #[arg(long, hide = true)]
+        log_json: bool,
+
+        /// Also route every *.<subdomain> host to this tunnel (reserved subdomains only)
+        #[arg(long, requires = "subdomain")]
+        wildcard: bool,
+
+        /// Warn at connect if nothing is listening on the upstream yet
+        #[arg(long)]
+        check_upstream: bool,
+
+        /// Serve the tunnel from a node in this region, a country code (e.g. DE, US); fails if there is none
+        #[arg(long, value_name = "CODE")]
+        region: Option<String>,
+
+        /// Only let these methods through the public URL; others get a 405 (comma-separated or repeated)
+        #[arg(long, value_name = "METHOD", value_delimiter = ',')]
+        allow_method: Vec<String>,
+
+        /// Refuse paths matching this glob with a 403; `*` stays within a segment, `**` doesn't (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        deny_path: Vec<String>,
+
+        /// Answer matching requests without the upstream: "[METHOD ]PATH=STATUS[@MS][:BODY]" (repeatable)
+        #[arg(long, value_name = "RULE")]
+        mock: Vec<String>,
+
+        /// Answer matching requests with this status: "[METHOD ]PATH=STATUS" (repeatable)
+        #[arg(long, value_name = "RULE")]
+        mock_status: Vec<String>,
+
+        /// Hold matching requests this long before they go upstream: "[METHOD ]PATH=MS" (repeatable)
+        #[arg(long, value_name = "RULE")]
+        mock_delay: Vec<String>,
+
+        /// Answer CORS preflights locally and add Access-Control-Allow-* to responses
+        #[arg(long)]
+        cors: bool,
+
+        /// Origins --cors allows, echoed back with credentials allowed (comma-separated; default: any)
+        #[arg(long, value_name = "ORIGIN", value_delimiter = ',', requires = "cors")]
+        cors_origin: Vec<String>,
+
+        /// Replace text in HTML, JS, CSS and JSON responses: "FROM=TO" (repeatable)
+        #[arg(long, value_name = "RULE")]
+        replace: Vec<String>,
+
+        /// Set custom port for local web inspector (default: 38227)
+        #[arg(long, value_name = "PORT")]
+        inspect: Option<u16>,
+
+        /// Run the inspector on exactly this port, failing if something else has it
+        #[arg(long, value_name = "PORT", conflicts_with_all = ["inspect", "no_inspect"])]
+        inspect_port: Option<u16>,
+
+        /// Address the inspector listens on; anything but loopback exposes captured traffic to the network
+        #[arg(long, value_name = "ADDR", default_value_t = std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), conflicts_with = "no_inspect")]
+        inspect_bind: std::net::IpAddr,
+
+        /// Disable local web inspector
+        #[arg(long)]
+        no_inspect: bool,
+
+        /// Memory the inspector may use for captured requests, in MB; the oldest bodies are dropped first
+        #[arg(long, value_name = "MB", default_value_t = inspector::DEFAULT_INSPECT_MEMORY_MB)]
+        inspect_memory_mb: usize,
+
+        /// Disable TUI mode (use simple text output)
+        #[arg(long)]
+        no_tui: bool,
+
+        /// Print a QR code for the public URL in simple mode (skipped when not a terminal)
+        #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+        qr: bool,
+
+        /// Open the public URL in the default browser once the tunnel is up
+        #[arg(long)]
+        open: bool,
+
+        /// Don't pass X-Forwarded-For/Proto/Host on to the upstream
+        #[arg(long)]
+        no_forwarded_headers: bool,
+
+        /// Forward only these request headers to the upstream (comma-separated)
+        #[arg(long, value_name = "HEADERS", value_delimiter = ',')]
+        forward_only: Option<Vec<String>>,
+
+        /// Send identical GETs that arrive together upstream once and share the response
+        #[arg(long)]
+        coalesce: bool,
+
+        /// Seconds between keepalive pings to the server
+        #[arg(long, value_name = "SECS", default_value_t = dvaar_common::constants::WS_PING_INTERVAL_SECONDS)]
+        ping_interval: u64,
+
+        /// Seconds without a pong before the connection is considered lost
+        #[arg(long, value_name = "SECS", default_value_t = dvaar_common::constants::WS_PONG_TIMEOUT_SECONDS)]
+        pong_timeout: u64,
+
+        /// Seconds a proxied WebSocket may carry no frames before it is closed (0 = never)
+        #[arg(long, value_name = "SECS", default_value_t = dvaar_common::constants::WS_IDLE_TIMEOUT_SECONDS)]
+        ws_idle_timeout: u64,
+
+        /// Session this detached tunnel records itself under (set by --detach)
+        #[arg(long, hide = true)]
+        session_id: Option<String>,
+    },
+
+    /// Create a tunnel to a gRPC server (HTTP/2, trailers, no response timeout)
+    Grpc {
+        /// Target to tunnel to (port, host:port or URL)
+        target: String,
+
+        /// Request a specific subdomain (e.g., -s myapp → myapp.dvaar.app)
+        #[arg(short = 's', long = "subdomain")]
+        subdomain: Option<String>,
+
+        /// Run in background (daemon mode)
+        #[arg(short = 'd', long)]
+        detach: bool,
+
+        /// Use TLS for the upstream connection (h2 via ALPN)
+        #[arg(long)]
+        use_tls: bool,
+
+        /// Accept any certificate from the upstream (self-signed dev servers)
+        #[arg(long, requires = "use_tls")]
+        insecure_upstream: bool,
+
+        /// Also trust the CA certificates in this PEM file for the upstream
+        #[arg(long, value_name = "FILE", requires = "use_tls")]
+        upstream_ca: Option<std::path::PathBuf>,
+
+        /// Server name to send as SNI and verify the upstream's certificate against
+        #[arg(long, value_name = "NAME", requires = "use_tls")]
+        upstream_sni: Option<String>,
+
+        /// Seconds to wait for the upstream to accept a connection
+        #[arg(long, value_name = "SECS", default_value_t = dvaar_common::constants::UPSTREAM_CONNECT_TIMEOUT_SECONDS)]
+        connect_timeout: u64,
+
         /// Set custom port for local web inspector (default: 38227)
         #[arg(long, value_name = "PORT")]
         inspect: Option<u16>,
@@ -82,7 +273,27 @@ enum Commands {
     },
 
     /// List active tunnels
-    Ls,
+    Ls {
+        /// Print the sessions as a JSON array
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Show tunnels the server has registered for your account
+    Status {
+        /// Only show this subdomain
+        subdomain: Option<String>,
+    },
+
+    /// Share a read-only view of a tunnel's inspector through an expiring link
+    Share {
+        /// Subdomain of the running tunnel
+        subdomain: String,
+
+        /// Minutes until the link stops working (at most a day)
+        #[arg(long, default_value = "60", value_parser = clap::value_parser!(u64).range(1..=1440))]
+        ttl: u64,
+    },
 
     /// Stop a tunnel
     Stop {
@@ -98,10 +309,18 @@ enum Commands {
         /// Follow log output
         #[arg(short, long)]
         follow: bool,
+
+        /// Print request entries as raw JSON lines
+        #[arg(long)]
+        json: bool,
     },
 
     /// View bandwidth usage
-    Usage,
+    Usage {
+        /// Print the usage as a JSON object
+        #[arg(long)]
+        json: bool,
+    },
 
     /// Upgrade your plan
     Upgrade {
@@ -157,15 +376,51 @@ async fn main() -> Result<()> {
             host_header,
             detach,
             use_tls,
+            insecure_upstream,
+            upstream_ca,
+            upstream_sni,
+            upstream_client_cert,
+            upstream_client_key,
+            upstream_http2,
+            offline_page,
+            connect_timeout,
+            response_timeout,
+            retry_upstream,
+            pool_max_idle,
+            log_json,
+            wildcard,
+            check_upstream,
+            region,
+            allow_method,
+            deny_path,
+            mock,
+            mock_status,
+            mock_delay,
+            cors,
+            cors_origin,
+            replace,
             inspect,
+            inspect_port,
+            inspect_bind,
             no_inspect,
+            inspect_memory_mb,
             no_tui,
+            qr,
+            open,
+            no_forwarded_headers,
+            forward_only,
+            coalesce,
+            ping_interval,
+            pong_timeout,
+            ws_idle_timeout,
+            session_id,
         } => {
             // Inspector is enabled by default on port 38227, unless --no-inspect is set
+            let inspect_port_fixed = inspect_port.is_some();
             let inspect_port = if no_inspect {
                 None
             } else {
-                Some(inspect.unwrap_or(38227))
+                Some(inspect_port.or(inspect).unwrap_or(38227))
             };
 
             // TUI is enabled by default unless --no-tui or --detach is set
@@ -178,26 +433,97 @@ async fn main() -> Result<()> {
                 host_header,
                 detach,
                 use_tls,
+                insecure_upstream,
+                upstream_ca,
+                upstream_sni,
+                upstream_client_cert,
+                upstream_client_key,
+                upstream_http2,
+                offline_page,
+                connect_timeout,
+                response_timeout,
+                retry_upstream,
+                pool_max_idle,
+                log_json,
+                wildcard,
+                check_upstream,
+                region,
+                allowed_methods: allow_method,
+                denied_paths: deny_path,
+                mocks: mock,
+                mock_statuses: mock_status,
+                mock_delays: mock_delay,
+                cors,
+                cors_origins: cors_origin,
+                replacements: replace,
                 inspect_port,
+                inspect_port_fixed,
+                inspect_bind,
+                inspect_memory_mb,
                 tui_mode,
+                qr,
+                open,
+                no_forwarded_headers,
+                forward_only,
+                coalesce,
+                ping_interval,
+                pong_timeout,
+                ws_idle_timeout,
+                session_id,
             };
             commands::http::run(opts).await?;
         }
 
-        Commands::Ls => {
-            commands::session::list().await?;
+        Commands::Grpc {
+            target,
+            subdomain,
+            detach,
+            use_tls,
+            insecure_upstream,
+            upstream_ca,
+            upstream_sni,
+            connect_timeout,
+            inspect,
+            no_inspect,
+            no_tui,
+        } => {
+            let opts = commands::grpc::GrpcOptions {
+                target,
+                subdomain,
+                detach,
+                use_tls,
+                insecure_upstream,
+                upstream_ca,
+                upstream_sni,
+                connect_timeout,
+                inspect_port: if no_inspect { None } else { Some(inspect.unwrap_or(38227)) },
+                tui_mode: !no_tui && !detach,
+            };
+            commands::grpc::run(opts).await?;
+        }
+
+        Commands::Ls { json } => {
+            commands::session::list(json).await?;
+        }
+
+        Commands::Status { subdomain } => {
+            commands::status::status(subdomain).await?;
+        }
+
+        Commands::Share { subdomain, ttl } => {
+            commands::share::share(&subdomain, ttl).await?;
         }
 
         Commands::Stop { id } => {
             commands::session::stop(&id).await?;
         }
 
-        Commands::Logs { id, follow } => {
-            commands::session::logs(&id, follow).await?;
+        Commands::Logs { id, follow, json } => {
+            commands::session::logs(&id, follow, json).await?;
         }
 
-        Commands::Usage => {
-            commands::billing::usage().await?;
+        Commands::Usage { json } => {
+            commands::billing::usage(json).await?;
         }
 
         Commands::Upgrade { plan } => {
@@ -1,6 +1,7 @@
 //! Metrics tracking for tunnel requests
 //!
-//! Tracks request counts, rates (sliding windows), and duration percentiles.
+//! Tracks request counts, rates (sliding windows), duration percentiles, and
+//! body bytes in each direction.
 
 use serde::Serialize;
 use std::collections::VecDeque;
@@ -16,6 +17,11 @@ struct MetricsInner {
     total_requests: u64,
     open_connections: u32,
 
+    /// Request body bytes from visitors to the upstream
+    ingress_bytes: u64,
+    /// Response body bytes from the upstream back to visitors
+    egress_bytes: u64,
+
     /// Request timestamps for rate calculation (keep last 15 minutes)
     request_times: VecDeque<Instant>,
 
@@ -28,6 +34,8 @@ struct MetricsInner {
 pub struct MetricsSnapshot {
     pub total_requests: u64,
     pub open_connections: u32,
+    pub ingress_bytes: u64,
+    pub egress_bytes: u64,
     pub requests_per_minute_1m: f64,
     pub requests_per_minute_5m: f64,
     pub requests_per_minute_15m: f64,
@@ -43,16 +51,20 @@ impl Metrics {
             inner: RwLock::new(MetricsInner {
                 total_requests: 0,
                 open_connections: 0,
+                ingress_bytes: 0,
+                egress_bytes: 0,
                 request_times: VecDeque::with_capacity(10000),
                 durations: VecDeque::with_capacity(1000),
             }),
         }
     }
 
-    /// Record a completed request with its duration
-    pub async fn record_request(&self, duration_ms: u64) {
+    /// Record a completed request with its duration and body sizes
+    pub async fn record_request(&self, duration_ms: u64, ingress_bytes: usize, egress_bytes: usize) {
         let mut inner = self.inner.write().await;
         inner.total_requests += 1;
+        inner.ingress_bytes += ingress_bytes as u64;
+        inner.egress_bytes += egress_bytes as u64;
         inner.request_times.push_back(Instant::now());
 
         // Keep only last 15 minutes of request times
@@ -114,6 +126,8 @@ impl Metrics {
         MetricsSnapshot {
             total_requests: inner.total_requests,
             open_connections: inner.open_connections,
+            ingress_bytes: inner.ingress_bytes,
+            egress_bytes: inner.egress_bytes,
             requests_per_minute_1m: rate_1m,
             requests_per_minute_5m: rate_5m,
             requests_per_minute_15m: rate_15m,
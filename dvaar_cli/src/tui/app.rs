@@ -5,6 +5,7 @@ use crate::metrics::MetricsSnapshot;
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 
 /// Advertisement/sponsor to display in TUI
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -64,7 +65,26 @@ pub struct TunnelInfo {
     pub user_email: Option<String>,
     pub user_plan: Option<String>,
     pub version: String,
+    /// How long the WebSocket connect took, then the latest keepalive round trip
     pub latency_ms: Option<u64>,
+    /// How long the server took to answer Init, token check included
+    pub handshake_ms: Option<u64>,
+    /// Why the server closed the tunnel, shown next to the status
+    pub close_reason: Option<String>,
+    /// When the tunnel last came online; `None` while it isn't
+    pub connected_at: Option<Instant>,
+    /// Why the upstream looked unreachable at connect (`--check-upstream`),
+    /// until a request gets through
+    pub upstream_warning: Option<String>,
+    /// Upstream host the last request couldn't resolve, until one gets through
+    pub unresolved_host: Option<String>,
+}
+
+impl TunnelInfo {
+    /// How long the tunnel has been online
+    pub fn uptime(&self) -> Option<Duration> {
+        self.connected_at.map(|at| at.elapsed())
+    }
 }
 
 impl Default for TunnelInfo {
@@ -78,6 +98,11 @@ impl Default for TunnelInfo {
             user_plan: None,
             version: env!("CARGO_PKG_VERSION").to_string(),
             latency_ms: None,
+            handshake_ms: None,
+            close_reason: None,
+            connected_at: None,
+            upstream_warning: None,
+            unresolved_host: None,
         }
     }
 }
@@ -105,6 +130,10 @@ pub enum TuiEvent {
     ConnectionOpened,
     /// Connection closed (for tracking open connections in client mode)
     ConnectionClosed,
+    /// A request failed because the upstream host didn't resolve
+    UpstreamUnresolved(String),
+    /// The server answered a ping after this long
+    RoundTrip(Duration),
 }
 
 /// TUI application state
@@ -152,6 +181,8 @@ impl TuiApp {
             metrics: MetricsSnapshot {
                 total_requests: 0,
                 open_connections: 0,
+                ingress_bytes: 0,
+                egress_bytes: 0,
                 requests_per_minute_1m: 0.0,
                 requests_per_minute_5m: 0.0,
                 requests_per_minute_15m: 0.0,
@@ -184,12 +215,10 @@ impl TuiApp {
         self.ads.get(self.current_ad_index)
     }
 
-    /// Update ads list
+    /// Update ads list; an empty list hides the sponsor line
     pub fn set_ads(&mut self, ads: Vec<Ad>) {
-        if !ads.is_empty() {
-            self.ads = ads;
-            self.current_ad_index = 0;
-        }
+        self.ads = ads;
+        self.current_ad_index = 0;
     }
 
     /// Add a new request to the display
@@ -260,9 +289,23 @@ impl TuiApp {
     /// Handle TUI event
     pub fn handle_event(&mut self, event: TuiEvent) {
         match event {
-            TuiEvent::NewRequest(req) => self.add_request(req),
+            TuiEvent::NewRequest(req) => {
+                if req.response_status != 502 {
+                    self.tunnel_info.upstream_warning = None;
+                    self.tunnel_info.unresolved_host = None;
+                }
+                self.add_request(req)
+            }
             TuiEvent::MetricsUpdate(metrics) => self.update_metrics(metrics),
-            TuiEvent::StatusChange(status) => self.tunnel_info.status = status,
+            TuiEvent::StatusChange(status) => {
+                // Uptime restarts whenever the tunnel comes back
+                if status != TunnelStatus::Online {
+                    self.tunnel_info.connected_at = None;
+                } else if self.tunnel_info.connected_at.is_none() {
+                    self.tunnel_info.connected_at = Some(Instant::now());
+                }
+                self.tunnel_info.status = status;
+            }
             TuiEvent::TunnelInfoUpdate(info) => self.update_tunnel_info(info),
             TuiEvent::Key(key) => self.handle_key(key),
             TuiEvent::Tick => {} // Just triggers a redraw
@@ -282,6 +325,8 @@ impl TuiApp {
                     self.metrics.open_connections = self.local_open_connections;
                 }
             }
+            TuiEvent::UpstreamUnresolved(host) => self.tunnel_info.unresolved_host = Some(host),
+            TuiEvent::RoundTrip(rtt) => self.tunnel_info.latency_ms = Some(rtt.as_millis() as u64),
         }
     }
 }
@@ -1,6 +1,6 @@
 //! TUI rendering functions
 
-use super::app::{TuiApp, TunnelStatus, View};
+use super::app::{TuiApp, TunnelInfo, TunnelStatus, View};
 use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
@@ -60,11 +60,7 @@ fn draw_unified_header(frame: &mut Frame, app: &TuiApp, area: Rect) {
         TunnelStatus::Offline => Color::Red,
     };
 
-    let latency_str = app
-        .tunnel_info
-        .latency_ms
-        .map(|ms| format!("{}ms", ms))
-        .unwrap_or_else(|| "-".to_string());
+    let latency_str = format_latency(app.tunnel_info.latency_ms, app.tunnel_info.handshake_ms);
 
     let user_str = match (&app.tunnel_info.user_email, &app.tunnel_info.user_plan) {
         (Some(email), Some(plan)) => format!("{} ({})", email, plan),
@@ -73,6 +69,12 @@ fn draw_unified_header(frame: &mut Frame, app: &TuiApp, area: Rect) {
         (None, None) => "Anonymous".to_string(),
     };
 
+    let uptime_str = app
+        .tunnel_info
+        .uptime()
+        .map(format_uptime)
+        .unwrap_or_else(|| "-".to_string());
+
     let inspector_str = app
         .tunnel_info
         .inspector_url
@@ -148,12 +150,28 @@ fn draw_unified_header(frame: &mut Frame, app: &TuiApp, area: Rect) {
         ]),
         // Empty line after sponsor
         Line::from(""),
-        // Status line
+        // Status line, with the server's reason if it closed the tunnel
+        Line::from(
+            [
+                Span::styled("Status      ", Style::default().fg(Color::DarkGray)),
+                Span::styled(
+                    app.tunnel_info.status.as_str(),
+                    Style::default().fg(status_color).add_modifier(Modifier::BOLD),
+                ),
+            ]
+            .into_iter()
+            .chain(app.tunnel_info.close_reason.as_deref().map(|reason| {
+                Span::styled(format!(" - {}", reason), Style::default().fg(status_color))
+            }))
+            .collect::<Vec<_>>(),
+        ),
+        // Uptime line, with requests served so far
         Line::from(vec![
-            Span::styled("Status      ", Style::default().fg(Color::DarkGray)),
+            Span::styled("Uptime      ", Style::default().fg(Color::DarkGray)),
+            Span::styled(&uptime_str, Style::default().fg(Color::White)),
             Span::styled(
-                app.tunnel_info.status.as_str(),
-                Style::default().fg(status_color).add_modifier(Modifier::BOLD),
+                format!("  {} requests", app.metrics.total_requests),
+                Style::default().fg(Color::DarkGray),
             ),
         ]),
         // Latency line
@@ -166,13 +184,19 @@ fn draw_unified_header(frame: &mut Frame, app: &TuiApp, area: Rect) {
             Span::styled("Account     ", Style::default().fg(Color::DarkGray)),
             Span::styled(&user_str, Style::default().fg(Color::White)),
         ]),
-        // Forwarding line with underlined links
-        Line::from(vec![
-            Span::styled("Forwarding  ", Style::default().fg(Color::DarkGray)),
-            Span::styled(&public_url, Style::default().fg(Color::Green).add_modifier(Modifier::UNDERLINED)),
-            Span::styled(" → ", Style::default().fg(Color::DarkGray)),
-            Span::styled(&local_addr, Style::default().fg(Color::Cyan).add_modifier(Modifier::UNDERLINED)),
-        ]),
+        // Forwarding line with underlined links, flagged if the upstream wasn't
+        // reachable or its host didn't resolve
+        Line::from(
+            [
+                Span::styled("Forwarding  ", Style::default().fg(Color::DarkGray)),
+                Span::styled(&public_url, Style::default().fg(Color::Green).add_modifier(Modifier::UNDERLINED)),
+                Span::styled(" → ", Style::default().fg(Color::DarkGray)),
+                Span::styled(&local_addr, Style::default().fg(Color::Cyan).add_modifier(Modifier::UNDERLINED)),
+            ]
+            .into_iter()
+            .chain(upstream_flag(&app.tunnel_info))
+            .collect::<Vec<_>>(),
+        ),
         // Inspector line with underlined link
         Line::from(vec![
             Span::styled("Inspector   ", Style::default().fg(Color::DarkGray)),
@@ -244,6 +268,17 @@ fn draw_unified_header(frame: &mut Frame, app: &TuiApp, area: Rect) {
     }
 }
 
+/// What's wrong with the upstream, shown after the forwarding address
+fn upstream_flag(info: &TunnelInfo) -> Option<Span<'static>> {
+    if let Some(host) = &info.unresolved_host {
+        Some(Span::styled(format!("  could not resolve {}", host), Style::default().fg(Color::Red)))
+    } else {
+        info.upstream_warning
+            .as_ref()
+            .map(|_| Span::styled("  not reachable", Style::default().fg(Color::Yellow)))
+    }
+}
+
 /// Draw recent requests (last 10) - responsive layout
 fn draw_recent_requests(frame: &mut Frame, app: &TuiApp, area: Rect) {
     let width = area.width as usize;
@@ -336,11 +371,11 @@ fn draw_recent_requests(frame: &mut Frame, app: &TuiApp, area: Rect) {
 /// Draw all requests with scrolling and scrollbar
 fn draw_all_requests(frame: &mut Frame, app: &TuiApp, area: Rect) {
     // Calculate available width for path column
-    // Order: Status + Time + Duration + Method + Path + Size
-    let fixed_width = 6 + 9 + 10 + 7 + 8 + 6; // status + time + duration + method + size + padding
+    // Order: Status + Time + Duration + TTFB + Method + Path + Size
+    let fixed_width = 6 + 9 + 10 + 8 + 7 + 8 + 7; // status + time + duration + ttfb + method + size + padding
     let path_width = (area.width as usize).saturating_sub(fixed_width).max(10);
 
-    let header = Row::new(vec!["Status", "Time", "Duration", "Method", "Path", "Size"])
+    let header = Row::new(vec!["Status", "Time", "Duration", "TTFB", "Method", "Path", "Size"])
         .style(Style::default().fg(Color::DarkGray).add_modifier(Modifier::BOLD))
         .bottom_margin(0);
 
@@ -361,6 +396,8 @@ fn draw_all_requests(frame: &mut Frame, app: &TuiApp, area: Rect) {
                 Cell::from(format!("{:>3}", req.response_status)).style(status_style),
                 Cell::from(format_datetime(&req.timestamp)),
                 Cell::from(format_duration_short(req.duration_ms)),
+                Cell::from(req.ttfb_ms.map(format_duration_short).unwrap_or_else(|| "-".to_string()))
+                    .style(Style::default().fg(Color::DarkGray)),
                 Cell::from(format!("{:>6}", truncate_str(&req.method, 6))).style(method_style),
                 Cell::from(truncate_path(&req.path, path_width)),
                 Cell::from(format_size_short(req.size_bytes)),
@@ -381,6 +418,7 @@ fn draw_all_requests(frame: &mut Frame, app: &TuiApp, area: Rect) {
             Constraint::Length(6),
             Constraint::Length(9),
             Constraint::Length(10),
+            Constraint::Length(8),
             Constraint::Length(7),
             Constraint::Min(10),
             Constraint::Length(8),
@@ -526,6 +564,27 @@ fn format_duration_short(ms: u64) -> String {
     }
 }
 
+/// Format how long the tunnel has been up: "42s", "5m 03s", "2h 07m"
+fn format_uptime(uptime: std::time::Duration) -> String {
+    let secs = uptime.as_secs();
+    if secs >= 3600 {
+        format!("{}h {:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Format the connect time, then the handshake after it: "23ms  handshake 41ms"
+fn format_latency(connect_ms: Option<u64>, handshake_ms: Option<u64>) -> String {
+    match (connect_ms, handshake_ms) {
+        (Some(connect), Some(handshake)) => format!("{}ms  handshake {}ms", connect, handshake),
+        (Some(connect), None) => format!("{}ms", connect),
+        _ => "-".to_string(),
+    }
+}
+
 /// Truncate any string to max length
 fn truncate_str(s: &str, max_len: usize) -> String {
     if s.len() > max_len && max_len > 3 {
@@ -536,3 +595,25 @@ fn truncate_str(s: &str, max_len: usize) -> String {
         s.to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(Duration::from_millis(900)), "0s");
+        assert_eq!(format_uptime(Duration::from_secs(42)), "42s");
+        assert_eq!(format_uptime(Duration::from_secs(5 * 60 + 3)), "5m 03s");
+        assert_eq!(format_uptime(Duration::from_secs(2 * 3600 + 7 * 60 + 59)), "2h 07m");
+        assert_eq!(format_uptime(Duration::from_secs(30 * 3600)), "30h 00m");
+    }
+
+    #[test]
+    fn test_format_latency() {
+        assert_eq!(format_latency(Some(23), Some(41)), "23ms  handshake 41ms");
+        assert_eq!(format_latency(Some(23), None), "23ms");
+        assert_eq!(format_latency(None, None), "-");
+    }
+}
@@ -1,3 +1,7 @@
 //! Tunnel module
 
 pub mod client;
+pub mod presenter;
+pub mod request_log;
+pub mod upstream_tls;
+pub mod ws_capture;
@@ -0,0 +1,213 @@
+//! Showing what a tunnel serves
+//!
+//! The requests themselves are answered by `dvaar_client`'s `Proxy`, which
+//! reports each one as an `Event`. The presenter turns those into request log
+//! lines, TUI updates and inspector entries, in the order they happened.
+
+use super::request_log::{RequestLogFormat, RequestLogLine};
+use super::ws_capture::FrameCapture;
+use crate::inspector::{CapturedRequest, InspectorClient, RequestStore};
+use crate::tui::TuiEvent;
+use chrono::Utc;
+use console::style;
+use dvaar_client::{Event, Exchange};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+pub struct Presenter {
+    log_format: RequestLogFormat,
+    inspector: Option<Arc<RequestStore>>,
+    inspector_client: Option<Arc<InspectorClient>>,
+    tunnel_id: String,
+    tui_tx: Option<mpsc::Sender<TuiEvent>>,
+    frame_capture: Option<FrameCapture>,
+}
+
+impl Presenter {
+    /// Record into `inspector`, or `inspector_client` when this tunnel joined
+    /// another process's inspector, and update the TUI through `tui_tx`
+    pub fn new(
+        log_format: RequestLogFormat,
+        inspector: Option<Arc<RequestStore>>,
+        inspector_client: Option<Arc<InspectorClient>>,
+        tunnel_id: String,
+        tui_tx: Option<mpsc::Sender<TuiEvent>>,
+    ) -> Self {
+        let frame_capture = FrameCapture::spawn(inspector.clone(), inspector_client.clone(), tunnel_id.clone());
+        Self {
+            log_format,
+            inspector,
+            inspector_client,
+            tunnel_id,
+            tui_tx,
+            frame_capture,
+        }
+    }
+
+    /// Whether there is an inspector to keep bodies and frames for
+    pub fn captures(&self) -> bool {
+        self.inspector.is_some() || self.inspector_client.is_some()
+    }
+
+    /// Show events as they arrive, until every sender is gone
+    pub fn spawn(self, mut events: mpsc::Receiver<Event>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                self.show(event).await;
+            }
+        })
+    }
+
+    async fn show(&self, event: Event) {
+        match event {
+            Event::RequestFinished {
+                method,
+                uri,
+                status,
+                elapsed,
+                size_bytes,
+            } => {
+                RequestLogLine::new(&method, &uri, status, elapsed, size_bytes).print(self.log_format);
+            }
+            Event::RequestCaptured(exchange) => {
+                let captured = self.captured_request(exchange);
+                if let Some(ref tx) = self.tui_tx {
+                    let _ = tx.send(TuiEvent::NewRequest(captured.clone())).await;
+                }
+                // Submit to inspector (client mode) or local store (server mode)
+                if let Some(ref client) = self.inspector_client {
+                    let _ = client.submit_request(captured).await;
+                } else if let Some(ref store) = self.inspector {
+                    store.add_request_for_tunnel(&self.tunnel_id, captured).await;
+                }
+            }
+            Event::ConnectionOpened => {
+                if let Some(ref store) = self.inspector {
+                    if let Some(metrics) = store.metrics_for_tunnel(&self.tunnel_id).await {
+                        metrics.increment_connections().await;
+                    }
+                }
+                if let Some(ref tx) = self.tui_tx {
+                    let _ = tx.send(TuiEvent::ConnectionOpened).await;
+                }
+            }
+            Event::ConnectionClosed => {
+                if let Some(ref store) = self.inspector {
+                    if let Some(metrics) = store.metrics_for_tunnel(&self.tunnel_id).await {
+                        metrics.decrement_connections().await;
+                    }
+                }
+                if let Some(ref tx) = self.tui_tx {
+                    let _ = tx.send(TuiEvent::ConnectionClosed).await;
+                }
+            }
+            Event::UpstreamUnresolved(host) => {
+                if let Some(ref tx) = self.tui_tx {
+                    let _ = tx.send(TuiEvent::UpstreamUnresolved(host)).await;
+                }
+            }
+            Event::RoundTrip(rtt) => match self.tui_tx {
+                // Keep the header's latency current
+                Some(ref tx) => {
+                    let _ = tx.send(TuiEvent::RoundTrip(rtt)).await;
+                }
+                None => tracing::trace!("Server round trip {:?}", rtt),
+            },
+            Event::WebSocketOpened { ref uri, .. } => {
+                println!(
+                    "  {} {} {} {}",
+                    style(chrono::Local::now().format("%H:%M:%S").to_string()).dim(),
+                    style("     WS").magenta(),
+                    style(uri).white(),
+                    style("101").green(),
+                );
+                if let Some(capture) = &self.frame_capture {
+                    capture.observe(&event);
+                }
+            }
+            Event::WebSocketFrame { .. } => {
+                if let Some(capture) = &self.frame_capture {
+                    capture.observe(&event);
+                }
+            }
+        }
+    }
+
+    /// The inspector's record of an exchange
+    fn captured_request(&self, exchange: Exchange) -> CapturedRequest {
+        CapturedRequest {
+            trace_id: CapturedRequest::trace_id_from_headers(&exchange.request_headers),
+            id: exchange.stream_id,
+            tunnel_id: self.tunnel_id.clone(),
+            timestamp: Utc::now(),
+            method: exchange.method,
+            path: exchange.uri,
+            request_headers: exchange.request_headers,
+            request_body: exchange.request_body,
+            response_status: exchange.status,
+            response_headers: exchange.response_headers,
+            response_body: exchange.response_body,
+            duration_ms: exchange.duration.as_millis() as u64,
+            ttfb_ms: exchange.ttfb.map(|ttfb| ttfb.as_millis() as u64),
+            upstream_connect_ms: Some(exchange.upstream_connect.as_millis() as u64),
+            size_bytes: exchange.size_bytes,
+            request_size_bytes: exchange.request_size_bytes,
+            body_evicted: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inspector::{RegisteredTunnel, TunnelStatus};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_large_response_counts_as_egress() {
+        const RESPONSE_BODY: usize = 256 * 1024;
+
+        let store = Arc::new(RequestStore::new());
+        store
+            .register_tunnel(RegisteredTunnel {
+                tunnel_id: "t1".to_string(),
+                subdomain: "app".to_string(),
+                public_url: String::new(),
+                local_addr: "localhost:3000".to_string(),
+                status: TunnelStatus::Active,
+                registered_at: Utc::now(),
+                last_seen: Utc::now(),
+            })
+            .await;
+
+        let presenter = Presenter::new(RequestLogFormat::Pretty, Some(store.clone()), None, "t1".to_string(), None);
+        assert!(presenter.captures());
+        let (events_tx, events_rx) = mpsc::channel(4);
+        let shown = presenter.spawn(events_rx);
+        events_tx
+            .send(Event::RequestCaptured(Exchange {
+                stream_id: dvaar_common::new_stream_id(),
+                method: "POST".to_string(),
+                uri: "/export".to_string(),
+                request_headers: vec![],
+                request_body: b"{\"q\":1}".to_vec(),
+                status: 200,
+                response_headers: vec![],
+                response_body: vec![b'x'; RESPONSE_BODY],
+                duration: Duration::from_millis(12),
+                ttfb: Some(Duration::from_millis(3)),
+                upstream_connect: Duration::from_millis(1),
+                size_bytes: RESPONSE_BODY,
+                request_size_bytes: 7,
+            }))
+            .await
+            .unwrap();
+        drop(events_tx);
+        shown.await.unwrap();
+
+        let metrics = store.get_tunnel_metrics("t1").await.unwrap();
+        assert_eq!(metrics.ingress_bytes, 7);
+        assert_eq!(metrics.egress_bytes, RESPONSE_BODY as u64);
+        assert_eq!(store.get_requests().await.len(), 1);
+    }
+}
@@ -0,0 +1,165 @@
+//! Per-request log lines printed by the tunnel client
+//!
+//! Foreground tunnels print a styled line per request. Detached tunnels write
+//! the same information as JSON, one object per line, so `dvaar logs` can pick
+//! request entries out of the session log and render them.
+
+use chrono::{DateTime, Local, Utc};
+use console::style;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How the client prints each proxied request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RequestLogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+/// One proxied request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestLogLine {
+    pub timestamp: DateTime<Utc>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub duration_ms: u64,
+    pub size_bytes: usize,
+}
+
+impl RequestLogLine {
+    pub fn new(method: &str, path: &str, status: u16, elapsed: Duration, size_bytes: usize) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            method: method.to_string(),
+            path: path.to_string(),
+            status,
+            duration_ms: elapsed.as_millis() as u64,
+            size_bytes,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// Parse a log file line; anything that isn't a request entry gives `None`
+    pub fn parse(line: &str) -> Option<Self> {
+        if !line.starts_with('{') {
+            return None;
+        }
+        serde_json::from_str(line).ok()
+    }
+
+    pub fn print(&self, format: RequestLogFormat) {
+        match format {
+            RequestLogFormat::Pretty => println!("{}", self.pretty()),
+            RequestLogFormat::Json => println!("{}", self.to_json()),
+        }
+    }
+
+    /// Styled, column-aligned rendering
+    pub fn pretty(&self) -> String {
+        let timestamp = style(
+            self.timestamp
+                .with_timezone(&Local)
+                .format("%H:%M:%S")
+                .to_string(),
+        )
+        .dim();
+
+        // Method styling
+        let method = self.method.as_str();
+        let method_styled = match method {
+            "GET" => style(format!("{:>7}", method)).green(),
+            "POST" => style(format!("{:>7}", method)).yellow(),
+            "PUT" => style(format!("{:>7}", method)).blue(),
+            "PATCH" => style(format!("{:>7}", method)).magenta(),
+            "DELETE" => style(format!("{:>7}", method)).red(),
+            "HEAD" => style(format!("{:>7}", method)).cyan(),
+            "OPTIONS" => style(format!("{:>7}", method)).white(),
+            _ => style(format!("{:>7}", method)).white(),
+        };
+
+        // Status code styling
+        let status = self.status;
+        let status_styled = if status >= 500 {
+            style(status.to_string()).red().bold()
+        } else if status >= 400 {
+            style(status.to_string()).yellow()
+        } else if status >= 300 {
+            style(status.to_string()).cyan()
+        } else if status >= 200 {
+            style(status.to_string()).green()
+        } else {
+            style(status.to_string()).white()
+        };
+
+        // Duration styling
+        let elapsed_ms = self.duration_ms;
+        let duration_styled = if elapsed_ms > 1000 {
+            style(format!("{:>6}ms", elapsed_ms)).red()
+        } else if elapsed_ms > 500 {
+            style(format!("{:>6}ms", elapsed_ms)).yellow()
+        } else if elapsed_ms > 100 {
+            style(format!("{:>6}ms", elapsed_ms)).white()
+        } else {
+            style(format!("{:>6}ms", elapsed_ms)).green()
+        };
+
+        // Size formatting
+        let body_size = self.size_bytes;
+        let size_str = if body_size >= 1_000_000 {
+            format!("{:.1}MB", body_size as f64 / 1_000_000.0)
+        } else if body_size >= 1_000 {
+            format!("{:.1}KB", body_size as f64 / 1_000.0)
+        } else {
+            format!("{}B", body_size)
+        };
+        let size_styled = style(format!("{:>8}", size_str)).dim();
+
+        // Truncate URI if too long
+        let max_uri_len = 50;
+        let uri_display = if self.path.len() > max_uri_len {
+            format!("{}...", &self.path[..max_uri_len - 3])
+        } else {
+            self.path.clone()
+        };
+
+        format!(
+            "  {} {} {} {} {} {}",
+            timestamp, method_styled, style(uri_display).white(), status_styled, duration_styled, size_styled,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_line_roundtrip() {
+        let line = RequestLogLine::new("POST", "/api/items", 201, Duration::from_millis(42), 1_536);
+        let json = line.to_json();
+
+        assert!(!json.contains('\n'));
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["method"], "POST");
+        assert_eq!(value["status"], 201);
+        assert_eq!(value["duration_ms"], 42);
+        assert_eq!(RequestLogLine::parse(&json), Some(line));
+
+        assert_eq!(RequestLogLine::parse("  Tunnel Active: https://demo.dvaar.app"), None);
+    }
+
+    #[test]
+    fn test_pretty_format() {
+        let line = RequestLogLine::new("GET", "/health", 503, Duration::from_millis(1_200), 2_500_000);
+        let pretty = console::strip_ansi_codes(&line.pretty()).to_string();
+
+        assert!(pretty.contains("    GET /health 503"));
+        assert!(pretty.contains("1200ms"));
+        assert!(pretty.contains("2.5MB"));
+    }
+}
@@ -0,0 +1,135 @@
+//! WebSocket frames for the inspector
+//!
+//! Frames are copied off the tunnel as they pass and handed to a task that
+//! records them, in order, in the local store or the inspector this tunnel
+//! joined. Capture is best effort: when that task falls behind, frames are
+//! dropped from the inspector rather than holding up the connection.
+
+use crate::inspector::{CapturedFrame, FrameDirection, InspectorClient, RequestStore};
+use chrono::Utc;
+use dvaar_client::{Direction, Event};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Captures waiting to be recorded before new ones are dropped
+const CAPTURE_QUEUE: usize = 256;
+
+enum Capture {
+    Open { stream_id: String, path: String },
+    Frame(CapturedFrame),
+}
+
+#[derive(Clone)]
+pub struct FrameCapture {
+    tx: mpsc::Sender<Capture>,
+}
+
+impl FrameCapture {
+    /// Start recording into `inspector`, or `inspector_client` when this
+    /// tunnel joined another process's inspector. `None` if neither is set.
+    pub fn spawn(
+        inspector: Option<Arc<RequestStore>>,
+        inspector_client: Option<Arc<InspectorClient>>,
+        tunnel_id: String,
+    ) -> Option<Self> {
+        if inspector.is_none() && inspector_client.is_none() {
+            return None;
+        }
+
+        let (tx, mut rx) = mpsc::channel(CAPTURE_QUEUE);
+        tokio::spawn(async move {
+            while let Some(capture) = rx.recv().await {
+                match (capture, &inspector_client, &inspector) {
+                    (Capture::Open { stream_id, path }, Some(client), _) => {
+                        let _ = client.submit_ws_open(&stream_id, &path).await;
+                    }
+                    (Capture::Open { stream_id, path }, None, Some(store)) => {
+                        store.open_websocket(&tunnel_id, &stream_id, &path).await;
+                    }
+                    (Capture::Frame(frame), Some(client), _) => {
+                        let _ = client.submit_ws_frame(frame).await;
+                    }
+                    (Capture::Frame(frame), None, Some(store)) => store.add_ws_frame(frame).await,
+                    (_, None, None) => unreachable!("checked before spawning"),
+                }
+            }
+        });
+        Some(Self { tx })
+    }
+
+    /// Note a WebSocket opening or a frame crossing one; other events are ignored
+    pub fn observe(&self, event: &Event) {
+        let capture = match event {
+            Event::WebSocketOpened { stream_id, uri } => Capture::Open {
+                stream_id: stream_id.clone(),
+                path: uri.clone(),
+            },
+            Event::WebSocketFrame {
+                stream_id,
+                data,
+                is_binary,
+                direction,
+            } => Capture::Frame(CapturedFrame {
+                stream_id: stream_id.clone(),
+                timestamp: Utc::now(),
+                direction: match direction {
+                    Direction::Inbound => FrameDirection::Inbound,
+                    Direction::Outbound => FrameDirection::Outbound,
+                },
+                is_binary: *is_binary,
+                size: data.len(),
+                data: data.clone(),
+            }),
+            _ => return,
+        };
+        let _ = self.tx.try_send(capture);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ws_frame(data: &[u8], is_binary: bool, direction: Direction) -> Event {
+        Event::WebSocketFrame {
+            stream_id: "ws-1".to_string(),
+            data: data.to_vec(),
+            is_binary,
+            direction,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frames_recorded_with_direction() {
+        let store = Arc::new(RequestStore::new());
+        let capture = FrameCapture::spawn(Some(store.clone()), None, "tunnel".to_string()).unwrap();
+
+        capture.observe(&Event::WebSocketOpened {
+            stream_id: "ws-1".to_string(),
+            uri: "/socket".to_string(),
+        });
+        capture.observe(&ws_frame(b"ping", false, Direction::Inbound));
+        capture.observe(&ws_frame(&[0, 1, 2], true, Direction::Outbound));
+        capture.observe(&Event::RoundTrip(Duration::from_millis(20)));
+
+        let frames = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match store.get_ws_frames("ws-1").await {
+                    Some(frames) if frames.len() == 2 => return frames,
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .expect("frames were not recorded");
+
+        assert_eq!(frames[0].direction, FrameDirection::Inbound);
+        assert_eq!((frames[0].data.as_slice(), frames[0].is_binary), (&b"ping"[..], false));
+        assert_eq!(frames[1].direction, FrameDirection::Outbound);
+        assert_eq!((frames[1].size, frames[1].is_binary), (3, true));
+
+        let connections = store.get_websockets(Some("tunnel")).await;
+        assert_eq!(connections[0].path, "/socket");
+    }
+}
@@ -2,6 +2,7 @@
 
 use anyhow::Result;
 use console::style;
+use dvaar_common::is_newer_version;
 use serde::Deserialize;
 use std::time::Duration;
 
@@ -31,31 +32,6 @@ async fn fetch_latest_version() -> Result<String> {
     Ok(version)
 }
 
-/// Compare versions (returns true if latest > current)
-fn is_newer_version(latest: &str, current: &str) -> bool {
-    let parse_version = |v: &str| -> Vec<u32> {
-        v.split('.')
-            .filter_map(|s| s.parse().ok())
-            .collect()
-    };
-
-    let latest_parts = parse_version(latest);
-    let current_parts = parse_version(current);
-
-    for i in 0..latest_parts.len().max(current_parts.len()) {
-        let l = latest_parts.get(i).unwrap_or(&0);
-        let c = current_parts.get(i).unwrap_or(&0);
-        if l > c {
-            return true;
-        }
-        if l < c {
-            return false;
-        }
-    }
-
-    false
-}
-
 /// Check for updates and prompt user if available
 /// Always checks GitHub releases API (fast, 5s timeout)
 pub async fn check_for_updates() {
@@ -132,18 +108,3 @@ async fn prompt_for_update(latest_version: &str) {
         _ => {}
     }
 }
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_version_comparison() {
-        assert!(is_newer_version("1.0.1", "1.0.0"));
-        assert!(is_newer_version("1.1.0", "1.0.0"));
-        assert!(is_newer_version("2.0.0", "1.9.9"));
-        assert!(!is_newer_version("1.0.0", "1.0.0"));
-        assert!(!is_newer_version("1.0.0", "1.0.1"));
-        assert!(!is_newer_version("0.9.0", "1.0.0"));
-    }
-}
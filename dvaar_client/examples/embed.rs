@@ -0,0 +1,28 @@
+//! Expose a local port through a Dvaar tunnel until Ctrl+C
+//!
+//! ```sh
+//! DVAAR_TOKEN=... cargo run -p dvaar_client --example embed -- 3000 my-app
+//! ```
+
+use dvaar_client::TunnelBuilder;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let target = args.next().unwrap_or_else(|| "3000".to_string());
+    let subdomain = args.next();
+
+    let server = std::env::var("DVAAR_SERVER_URL").unwrap_or_else(|_| "wss://api.dvaar.io".to_string());
+    let token = std::env::var("DVAAR_TOKEN")?;
+
+    let mut builder = TunnelBuilder::new(server, token).target(target);
+    if let Some(subdomain) = subdomain {
+        builder = builder.subdomain(subdomain);
+    }
+    let tunnel = builder.connect().await?;
+    println!("Forwarding {} -> localhost", tunnel.url());
+
+    tokio::signal::ctrl_c().await?;
+    tunnel.shutdown().await?;
+    Ok(())
+}
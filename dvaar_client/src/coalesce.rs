@@ -0,0 +1,174 @@
+//! Request coalescing
+//!
+//! A page that fires the same slow GET from several places at once makes the
+//! local server compute the same answer several times. With `--coalesce`,
+//! an identical GET that arrives while one is already in flight doesn't go
+//! upstream; it waits for the first one and gets a copy of its response.
+//!
+//! Requests only join while the first one is still waiting for its response
+//! headers. Once those arrive the key is released, so a request never gets a
+//! response that started before it was sent.
+
+use dvaar_common::{ControlPacket, HttpRequestPacket, StreamErrorCode};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Headers a response might vary on, so requests only join when these match
+const VARY_HEADERS: &[&str] = &[
+    "host",
+    "accept",
+    "accept-encoding",
+    "accept-language",
+    "authorization",
+    "cookie",
+    "range",
+    "if-none-match",
+    "if-modified-since",
+];
+
+/// Followers waiting on each in-flight request, by key
+type Waiting = Arc<Mutex<HashMap<String, Vec<String>>>>;
+
+#[derive(Clone, Default)]
+pub struct Coalescer {
+    in_flight: Waiting,
+}
+
+impl Coalescer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decide where `request`'s response goes. Returns the sender its
+    /// handler should write to, or `None` when it joined an identical request
+    /// already in flight and must not be sent upstream.
+    pub fn route(
+        &self,
+        request: &HttpRequestPacket,
+        packet_tx: &mpsc::Sender<ControlPacket>,
+    ) -> Option<mpsc::Sender<ControlPacket>> {
+        let Some(key) = coalesce_key(request) else {
+            return Some(packet_tx.clone());
+        };
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(followers) = in_flight.get_mut(&key) {
+            tracing::debug!("Coalescing {} into an identical request in flight", request.stream_id);
+            followers.push(request.stream_id.clone());
+            return None;
+        }
+        in_flight.insert(key.clone(), Vec::new());
+        drop(in_flight);
+
+        let (tap_tx, tap_rx) = mpsc::channel(32);
+        tokio::spawn(fan_out(
+            self.in_flight.clone(),
+            key,
+            tap_rx,
+            packet_tx.clone(),
+        ));
+        Some(tap_tx)
+    }
+
+    /// Drop a follower the server cancelled before its response started
+    pub fn cancel(&self, stream_id: &str) {
+        for followers in self.in_flight.lock().unwrap().values_mut() {
+            followers.retain(|id| id != stream_id);
+        }
+    }
+}
+
+/// Key identifying requests that may share a response, if this one may
+fn coalesce_key(request: &HttpRequestPacket) -> Option<String> {
+    if !matches!(request.method.as_str(), "GET" | "HEAD") || request.is_websocket_upgrade() {
+        return None;
+    }
+    let has_body = request.headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("transfer-encoding")
+            || (name.eq_ignore_ascii_case("content-length") && value.trim() != "0")
+    });
+    if has_body {
+        return None;
+    }
+
+    let mut key = format!("{} {}", request.method, request.uri);
+    for vary in VARY_HEADERS {
+        key.push('\n');
+        key.push_str(vary);
+        for (name, value) in &request.headers {
+            if name.eq_ignore_ascii_case(vary) {
+                key.push(':');
+                key.push_str(value);
+            }
+        }
+    }
+    Some(key)
+}
+
+/// Copy a packet of the leader's response to a follower's stream
+fn for_stream(packet: &ControlPacket, stream_id: &str) -> Option<ControlPacket> {
+    let stream_id = stream_id.to_string();
+    match packet {
+        ControlPacket::HttpResponse(response) => {
+            let mut response = response.clone();
+            response.stream_id = stream_id;
+            Some(ControlPacket::HttpResponse(response))
+        }
+        ControlPacket::Data { data, .. } => Some(ControlPacket::Data {
+            stream_id,
+            data: data.clone(),
+        }),
+        ControlPacket::Trailers { headers, .. } => Some(ControlPacket::Trailers {
+            stream_id,
+            headers: headers.clone(),
+        }),
+        ControlPacket::End { .. } => Some(ControlPacket::End { stream_id }),
+        ControlPacket::StreamError { error, code, .. } => Some(ControlPacket::StreamError {
+            stream_id,
+            error: error.clone(),
+            code: *code,
+        }),
+        _ => None,
+    }
+}
+
+/// Forward the leader's response, repeating it for every follower
+async fn fan_out(
+    in_flight: Waiting,
+    key: String,
+    mut rx: mpsc::Receiver<ControlPacket>,
+    packet_tx: mpsc::Sender<ControlPacket>,
+) {
+    let mut followers: Option<Vec<String>> = None;
+    let mut finished = false;
+
+    while let Some(packet) = rx.recv().await {
+        let followers = followers.get_or_insert_with(|| in_flight.lock().unwrap().remove(&key).unwrap_or_default());
+        finished |= matches!(packet, ControlPacket::End { .. } | ControlPacket::StreamError { .. });
+        for follower in followers.iter() {
+            if let Some(copy) = for_stream(&packet, follower) {
+                if packet_tx.send(copy).await.is_err() {
+                    return;
+                }
+            }
+        }
+        if packet_tx.send(packet).await.is_err() {
+            return;
+        }
+    }
+
+    // The leader went away without finishing, e.g. it was cancelled
+    let followers = followers.unwrap_or_else(|| in_flight.lock().unwrap().remove(&key).unwrap_or_default());
+    if !finished {
+        for follower in followers {
+            let _ = packet_tx
+                .send(ControlPacket::StreamError {
+                    stream_id: follower,
+                    error: "Coalesced request was cancelled".to_string(),
+                    code: StreamErrorCode::Cancelled,
+                })
+                .await;
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! CORS answered by the client
+//!
+//! Dev servers often don't handle `OPTIONS`, so a browser calling a tunneled
+//! API from another origin fails its preflight before the real request is
+//! sent. With `--cors`, preflights are answered here without reaching the
+//! upstream, and responses get `Access-Control-Allow-*` headers added.
+//!
+//! Any origin is allowed unless `--cors-origin` lists some; then only those
+//! get through, are echoed back, and may send credentials. Since the answer
+//! then depends on the origin, every response says `Vary: Origin`.
+
+use dvaar_common::HttpRequestPacket;
+
+/// How long a browser may cache a preflight answer, in seconds
+const PREFLIGHT_MAX_AGE: &str = "86400";
+
+/// Methods offered when a preflight doesn't name one
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS";
+
+/// Response headers replaced by the policy's own
+const CORS_RESPONSE_HEADERS: &[&str] = &[
+    "access-control-allow-origin",
+    "access-control-allow-credentials",
+    "access-control-expose-headers",
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct CorsPolicy {
+    /// Allowed origins; empty allows any
+    origins: Vec<String>,
+}
+
+impl CorsPolicy {
+    pub fn new(origins: Vec<String>) -> Self {
+        let origins = origins
+            .into_iter()
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        Self { origins }
+    }
+
+    /// Whether `request` is a CORS preflight rather than a plain `OPTIONS`
+    pub fn is_preflight(request: &HttpRequestPacket) -> bool {
+        request.method.eq_ignore_ascii_case("OPTIONS")
+            && header(&request.headers, "access-control-request-method").is_some()
+            && header(&request.headers, "origin").is_some()
+    }
+
+    /// Status and headers answering a preflight; a disallowed origin gets a
+    /// 403 without CORS headers, which the browser reports as blocked
+    pub fn preflight(&self, request_headers: &[(String, String)]) -> (u16, Vec<(String, String)>) {
+        let Some(mut headers) = self.origin_headers(request_headers) else {
+            let mut headers = Vec::new();
+            self.vary_on_origin(&mut headers);
+            return (403, headers);
+        };
+        let methods = header(request_headers, "access-control-request-method").unwrap_or(ALLOWED_METHODS);
+        headers.push(("Access-Control-Allow-Methods".to_string(), methods.to_string()));
+        if let Some(requested) = header(request_headers, "access-control-request-headers") {
+            headers.push(("Access-Control-Allow-Headers".to_string(), requested.to_string()));
+        }
+        headers.push(("Access-Control-Max-Age".to_string(), PREFLIGHT_MAX_AGE.to_string()));
+        self.vary_on_origin(&mut headers);
+        (204, headers)
+    }
+
+    /// Replace the upstream's CORS headers on an actual response. Requests
+    /// without an allowed `Origin` keep the upstream's, though a cache is still
+    /// told the answer depends on the origin.
+    pub fn apply(&self, request_headers: &[(String, String)], response_headers: &mut Vec<(String, String)>) {
+        self.vary_on_origin(response_headers);
+        let Some(mut headers) = self.origin_headers(request_headers) else {
+            return;
+        };
+        response_headers.retain(|(name, _)| !CORS_RESPONSE_HEADERS.contains(&name.to_ascii_lowercase().as_str()));
+        headers.push(("Access-Control-Expose-Headers".to_string(), "*".to_string()));
+        response_headers.extend(headers);
+    }
+
+    /// Add `Origin` to the response's `Vary` when origins are listed, or a
+    /// cache could hand one origin's answer to another
+    fn vary_on_origin(&self, response_headers: &mut Vec<(String, String)>) {
+        if self.origins.is_empty() {
+            return;
+        }
+        let vary = response_headers
+            .iter_mut()
+            .find(|(name, _)| name.eq_ignore_ascii_case("vary"));
+        match vary {
+            Some((_, value)) => {
+                let listed = value
+                    .split(',')
+                    .map(str::trim)
+                    .any(|field| field == "*" || field.eq_ignore_ascii_case("origin"));
+                if !listed {
+                    value.push_str(", Origin");
+                }
+            }
+            None => response_headers.push(("Vary".to_string(), "Origin".to_string())),
+        }
+    }
+
+    /// `Access-Control-Allow-Origin` and friends for the request's origin,
+    /// or `None` when it has none or it isn't allowed
+    fn origin_headers(&self, request_headers: &[(String, String)]) -> Option<Vec<(String, String)>> {
+        let origin = header(request_headers, "origin")?;
+        if self.origins.is_empty() {
+            return Some(vec![("Access-Control-Allow-Origin".to_string(), "*".to_string())]);
+        }
+        if !self.origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) {
+            return None;
+        }
+        Some(vec![
+            ("Access-Control-Allow-Origin".to_string(), origin.to_string()),
+            ("Access-Control-Allow-Credentials".to_string(), "true".to_string()),
+        ])
+    }
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        header(headers, name)
+    }
+
+    #[test]
+    fn test_listed_origins_only() {
+        let policy = CorsPolicy::new(vec!["https://app.example.com/".to_string()]);
+        let request = headers(&[
+            ("Origin", "https://app.example.com"),
+            ("Access-Control-Request-Method", "PUT"),
+            ("Access-Control-Request-Headers", "content-type, x-token"),
+        ]);
+        let (status, allowed) = policy.preflight(&request);
+        assert_eq!(status, 204);
+        assert_eq!(value(&allowed, "access-control-allow-origin"), Some("https://app.example.com"));
+        assert_eq!(value(&allowed, "access-control-allow-credentials"), Some("true"));
+        assert_eq!(value(&allowed, "access-control-allow-methods"), Some("PUT"));
+        assert_eq!(value(&allowed, "access-control-allow-headers"), Some("content-type, x-token"));
+        assert_eq!(value(&allowed, "vary"), Some("Origin"));
+
+        let (status, refused) = policy.preflight(&headers(&[("Origin", "https://evil.example")]));
+        assert_eq!(status, 403);
+        assert_eq!(refused, headers(&[("Vary", "Origin")]));
+
+        // The upstream's answer stands for origins we don't allow
+        let mut response = headers(&[("Access-Control-Allow-Origin", "https://upstream.example")]);
+        policy.apply(&headers(&[("Origin", "https://evil.example")]), &mut response);
+        assert_eq!(
+            response,
+            headers(&[("Access-Control-Allow-Origin", "https://upstream.example"), ("Vary", "Origin")])
+        );
+
+        // Requests without an Origin are cached apart from those with one
+        let mut response = headers(&[("Vary", "Accept-Encoding")]);
+        policy.apply(&[], &mut response);
+        assert_eq!(response, headers(&[("Vary", "Accept-Encoding, Origin")]));
+        let mut response = headers(&[("vary", "origin")]);
+        policy.apply(&[], &mut response);
+        assert_eq!(response, headers(&[("vary", "origin")]));
+
+        // Without a list the answer is the same for everyone
+        let mut response = Vec::new();
+        CorsPolicy::default().apply(&[], &mut response);
+        assert!(response.is_empty());
+    }
+}
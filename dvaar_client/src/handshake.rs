@@ -0,0 +1,238 @@
+//! Opening a tunnel connection
+//!
+//! The client connects to `/_dvaar/tunnel`, sends `Init` and gets an `InitAck`
+//! back. The ack either assigns a domain, carries an error, or redirects the
+//! client to a better placed node, in which case the whole exchange starts
+//! over there. Once a domain is assigned, current servers send `Ready` when
+//! the route is live.
+
+use crate::Error;
+use dvaar_common::{constants, ClientHello, ControlPacket, ServerHello, WireFormat};
+use futures_util::{SinkExt, StreamExt};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{self, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+/// How long the server has to answer `Init`, and then to send `Ready`
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Write half of the connection to the tunnel server
+pub type ServerSink = futures_util::stream::SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+
+/// Read half of the connection to the tunnel server
+pub type ServerStream = futures_util::stream::SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
+/// A connection whose `Init` has been answered
+pub struct Connection {
+    pub sink: ServerSink,
+    pub stream: ServerStream,
+    /// The server's answer; check `error` before using the domain
+    pub hello: ServerHello,
+    /// The format the rest of the tunnel uses
+    pub wire_format: WireFormat,
+    /// How long the WebSocket connect took, in ms
+    pub latency_ms: u64,
+    /// How long the server took to answer `Init`, in ms; this includes it
+    /// checking the token, so it is usually well above `latency_ms`
+    pub handshake_ms: u64,
+}
+
+/// Connect to `server_url` and send `hello`, following the server's redirect
+/// to a better placed node at most `MAX_REDIRECT_HOPS` times
+pub async fn connect(server_url: &str, mut hello: ClientHello, wire_format: WireFormat) -> Result<Connection, Error> {
+    let mut server_url = server_url.to_string();
+    let mut hops = 0;
+
+    loop {
+        let url = format!("{}/_dvaar/tunnel", server_url);
+
+        let start_time = Instant::now();
+        let (ws_stream, _) = connect_async(&url).await.map_err(|e| Error::Connect(Box::new(e)))?;
+        let latency_ms = start_time.elapsed().as_millis() as u64;
+
+        let (mut sink, mut stream) = ws_stream.split();
+
+        hello.redirect_hops = Some(hops);
+        let init_bytes = ControlPacket::Init(hello.clone()).encode(wire_format)?;
+        let init_sent = Instant::now();
+        sink.send(Message::Binary(init_bytes.into())).await?;
+
+        let ack_msg = tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.next())
+            .await
+            .map_err(|_| Error::Timeout("server response"))?
+            .ok_or(Error::Closed)??;
+        let handshake_ms = init_sent.elapsed().as_millis() as u64;
+
+        let ack_data = match ack_msg {
+            Message::Binary(data) => data,
+            _ => return Err(Error::UnexpectedPacket("a non-binary message".to_string())),
+        };
+
+        let (mut server_hello, settled) = decode_init_ack(&ack_data, wire_format)?;
+
+        match server_hello.redirect_to.take() {
+            None => {
+                return Ok(Connection {
+                    sink,
+                    stream,
+                    hello: server_hello,
+                    wire_format: settled,
+                    latency_ms,
+                    handshake_ms,
+                })
+            }
+            Some(host) if hops < constants::MAX_REDIRECT_HOPS => {
+                tracing::info!("Server redirected the tunnel to {}", host);
+                let _ = sink.close().await;
+                server_url = redirect_url(&server_url, &host);
+                hops += 1;
+            }
+            Some(host) => return Err(Error::TooManyRedirects(host)),
+        }
+    }
+}
+
+/// URL of the node a server redirected to. `host` may be a bare `host[:port]`,
+/// which keeps the scheme of the URL being redirected from.
+fn redirect_url(server_url: &str, host: &str) -> String {
+    if host.contains("://") {
+        return host.trim_end_matches('/').to_string();
+    }
+    let scheme = server_url.split_once("://").map_or("wss", |(scheme, _)| scheme);
+    format!("{}://{}", scheme, host.trim_end_matches('/'))
+}
+
+/// Decode the server's InitAck. It comes in the format the tunnel will use,
+/// which is MessagePack if the server doesn't allow the JSON asked for.
+fn decode_init_ack(data: &[u8], requested: WireFormat) -> Result<(ServerHello, WireFormat), Error> {
+    let wire_format = WireFormat::detect(data);
+    let hello = match ControlPacket::decode_limited(data, constants::MAX_FRAME_BYTES, wire_format)? {
+        ControlPacket::InitAck(hello) => hello,
+        other => return Err(Error::UnexpectedPacket(format!("{:?} instead of InitAck", other))),
+    };
+    if wire_format != requested {
+        tracing::warn!("Server doesn't allow {:?} on the wire, using {:?}", requested, wire_format);
+    }
+    Ok((hello, wire_format))
+}
+
+/// Wait for the server's `Ready` after a successful InitAck, so the tunnel
+/// is only reported up once traffic can flow. Servers older than the `Ready`
+/// packet never send one, so there's nothing to wait for.
+pub async fn wait_for_ready<S>(read: &mut S, server_version: &str, wire_format: WireFormat) -> Result<(), Error>
+where
+    S: futures_util::Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    if dvaar_common::is_newer_version(constants::READY_PROTOCOL_VERSION, server_version) {
+        return Ok(());
+    }
+
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        loop {
+            let msg = read.next().await.ok_or(Error::Closed)??;
+            if let Message::Binary(data) = msg {
+                match ControlPacket::decode_limited(&data, constants::MAX_FRAME_BYTES, wire_format)? {
+                    ControlPacket::Ready => return Ok(()),
+                    other => return Err(Error::UnexpectedPacket(format!("{:?} instead of Ready", other))),
+                }
+            }
+        }
+    })
+    .await
+    .map_err(|_| Error::Timeout("the tunnel to become ready"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redirect_url_keeps_scheme() {
+        assert_eq!(redirect_url("wss://api.dvaar.io", "eu-1.dvaar.io"), "wss://eu-1.dvaar.io");
+        assert_eq!(redirect_url("ws://localhost:8080", "127.0.0.1:9090"), "ws://127.0.0.1:9090");
+        assert_eq!(redirect_url("wss://api.dvaar.io", "ws://other:8080/"), "ws://other:8080");
+    }
+
+    #[test]
+    fn test_init_ack_settles_wire_format() {
+        let ack = ControlPacket::InitAck(ServerHello {
+            assigned_domain: "demo.dvaar.app".to_string(),
+            error: None,
+            server_version: constants::PROTOCOL_VERSION.to_string(),
+            redirect_to: None,
+        });
+
+        let json = ack.encode(WireFormat::Json).unwrap();
+        let (hello, wire) = decode_init_ack(&json, WireFormat::Json).unwrap();
+        assert_eq!(hello.assigned_domain, "demo.dvaar.app");
+        assert_eq!(wire, WireFormat::Json);
+
+        // Asked for JSON, but the server answered in MessagePack
+        let msgpack = ack.encode(WireFormat::MessagePack).unwrap();
+        let (hello, wire) = decode_init_ack(&msgpack, WireFormat::Json).unwrap();
+        assert_eq!(hello.assigned_domain, "demo.dvaar.app");
+        assert_eq!(wire, WireFormat::MessagePack);
+
+        let ready = ControlPacket::Ready.encode(WireFormat::Json).unwrap();
+        assert!(decode_init_ack(&ready, WireFormat::Json).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_and_handshake_timed_apart() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            ws.next().await.unwrap().unwrap();
+            // A server slow to check the token
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            let ack = ControlPacket::InitAck(ServerHello {
+                assigned_domain: "demo.dvaar.app".to_string(),
+                error: None,
+                server_version: constants::PROTOCOL_VERSION.to_string(),
+                redirect_to: None,
+            });
+            ws.send(Message::Binary(ack.to_bytes().unwrap().into())).await.unwrap();
+            let _ = ws.next().await;
+        });
+
+        let hello = ClientHello {
+            token: "token".to_string(),
+            requested_subdomain: None,
+            tunnel_type: dvaar_common::TunnelType::Http,
+            client_version: "0.0.0".to_string(),
+            offline_page: None,
+            wildcard: false,
+            redirect_hops: None,
+            allowed_methods: Vec::new(),
+            denied_paths: Vec::new(),
+            region: None,
+            flow_control: false,
+        };
+        let connection = connect(&format!("ws://{}", addr), hello, WireFormat::MessagePack).await.unwrap();
+        assert_eq!(connection.hello.assigned_domain, "demo.dvaar.app");
+        assert!(connection.handshake_ms >= 300, "{}", connection.handshake_ms);
+        assert!(connection.latency_ms < 300, "{}", connection.latency_ms);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_ready() {
+        let ready = Message::Binary(ControlPacket::Ready.to_bytes().unwrap().into());
+
+        let mut read = futures_util::stream::iter(vec![Ok(ready)]);
+        wait_for_ready(&mut read, constants::PROTOCOL_VERSION, WireFormat::MessagePack).await.unwrap();
+
+        // Older servers never send Ready, so we don't wait for one
+        let mut read = futures_util::stream::iter(Vec::<Result<Message, tungstenite::Error>>::new());
+        wait_for_ready(&mut read, "2.0.0", WireFormat::MessagePack).await.unwrap();
+
+        // A current server hanging up before Ready is an error
+        let mut read = futures_util::stream::iter(Vec::<Result<Message, tungstenite::Error>>::new());
+        assert!(wait_for_ready(&mut read, constants::PROTOCOL_VERSION, WireFormat::MessagePack).await.is_err());
+    }
+}
@@ -0,0 +1,349 @@
+//! Dvaar tunnels from Rust programs
+//!
+//! Opens a tunnel to a Dvaar server and serves it from a local target, the
+//! way `dvaar http` does, without the terminal UI or the inspector:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), dvaar_client::Error> {
+//! let tunnel = dvaar_client::TunnelBuilder::new("wss://api.dvaar.io", "my-token")
+//!     .subdomain("my-app")
+//!     .target("localhost:3000")
+//!     .connect()
+//!     .await?;
+//! println!("Serving on {}", tunnel.url());
+//! tunnel.shutdown().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The CLI runs its tunnels on the same pieces: [`handshake`] opens the
+//! connection and [`Proxy`] serves it, reporting the traffic as [`Event`]s
+//! for the terminal UI and inspector. Programs that need more control than
+//! the builder gives can do the same.
+
+mod coalesce;
+pub mod cors;
+pub mod handshake;
+pub mod mock;
+pub mod proxy;
+mod request;
+pub mod rewrite;
+pub mod stream_writer;
+mod upstream_metrics;
+mod upstream_retry;
+mod websocket;
+
+pub use proxy::{Direction, Event, Exchange, Proxy, UpstreamTls};
+pub use stream_writer::{StreamWindows, StreamWriter, Window};
+
+use dvaar_common::{constants, ClientHello, CloseCode, TunnelType, WireFormat};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to connect to tunnel server: {0}")]
+    Connect(#[source] Box<tungstenite::Error>),
+
+    #[error("WebSocket error: {0}")]
+    WebSocket(#[source] Box<tungstenite::Error>),
+
+    #[error("Protocol error: {0}")]
+    Protocol(#[from] dvaar_common::ProtocolError),
+
+    #[error("Timed out waiting for {0}")]
+    Timeout(&'static str),
+
+    #[error("Connection closed by the server")]
+    Closed,
+
+    #[error("Server closed the tunnel: {reason}")]
+    ClosedByServer { code: CloseCode, reason: String },
+
+    #[error("Unexpected packet from the server: {0}")]
+    UnexpectedPacket(String),
+
+    #[error("Server redirected the tunnel again (to {0}), giving up")]
+    TooManyRedirects(String),
+
+    #[error("Server refused the tunnel: {0}")]
+    Rejected(String),
+
+    #[error("No target set to forward requests to")]
+    MissingTarget,
+
+    #[error("Failed to build HTTP client: {0}")]
+    HttpClient(#[source] reqwest::Error),
+}
+
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Self {
+        Error::WebSocket(Box::new(e))
+    }
+}
+
+/// Configures and opens a tunnel
+#[derive(Debug, Clone)]
+pub struct TunnelBuilder {
+    server_url: String,
+    token: String,
+    subdomain: Option<String>,
+    target: Option<String>,
+    wire_format: WireFormat,
+    ping_interval: Duration,
+    pong_timeout: Duration,
+}
+
+impl TunnelBuilder {
+    /// `server_url` is the server's WebSocket base URL, e.g. `wss://api.dvaar.io`
+    pub fn new(server_url: impl Into<String>, token: impl Into<String>) -> Self {
+        Self {
+            server_url: server_url.into().trim_end_matches('/').to_string(),
+            token: token.into(),
+            subdomain: None,
+            target: None,
+            wire_format: WireFormat::default(),
+            ping_interval: Duration::from_secs(constants::WS_PING_INTERVAL_SECONDS),
+            pong_timeout: Duration::from_secs(constants::WS_PONG_TIMEOUT_SECONDS),
+        }
+    }
+
+    /// Ask for this subdomain instead of a random one
+    pub fn subdomain(mut self, subdomain: impl Into<String>) -> Self {
+        self.subdomain = Some(subdomain.into());
+        self
+    }
+
+    /// Where requests go: `host:port`, or a bare port on localhost
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        let target = target.into();
+        self.target = Some(if target.parse::<u16>().is_ok() {
+            format!("localhost:{}", target)
+        } else {
+            target
+        });
+        self
+    }
+
+    /// Set how often to ping the server and how long to wait for a pong
+    /// before the tunnel is treated as dead
+    pub fn keepalive(mut self, ping_interval: Duration, pong_timeout: Duration) -> Self {
+        self.ping_interval = ping_interval;
+        self.pong_timeout = pong_timeout;
+        self
+    }
+
+    /// Open the tunnel. Returns once the public URL is routable, with the
+    /// tunnel served in the background until [`TunnelHandle::shutdown`].
+    pub async fn connect(self) -> Result<TunnelHandle, Error> {
+        let target = self.target.ok_or(Error::MissingTarget)?;
+        let http_client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(constants::UPSTREAM_CONNECT_TIMEOUT_SECONDS))
+            .read_timeout(Duration::from_secs(constants::UPSTREAM_RESPONSE_TIMEOUT_SECONDS))
+            .build()
+            .map_err(Error::HttpClient)?;
+
+        let hello = ClientHello {
+            token: self.token,
+            requested_subdomain: self.subdomain,
+            tunnel_type: TunnelType::Http,
+            client_version: env!("CARGO_PKG_VERSION").to_string(),
+            offline_page: None,
+            wildcard: false,
+            redirect_hops: None,
+            allowed_methods: Vec::new(),
+            denied_paths: Vec::new(),
+            region: None,
+            flow_control: true,
+        };
+        let mut connection = handshake::connect(&self.server_url, hello, self.wire_format).await?;
+        if let Some(error) = connection.hello.error {
+            return Err(Error::Rejected(error));
+        }
+        handshake::wait_for_ready(&mut connection.stream, &connection.hello.server_version, connection.wire_format)
+            .await?;
+
+        let domain = connection.hello.assigned_domain.clone();
+        let proxy = Proxy::new(http_client, target).keepalive(self.ping_interval, self.pong_timeout);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let task = tokio::spawn(proxy.run(connection, shutdown_rx));
+
+        Ok(TunnelHandle {
+            domain,
+            shutdown_tx,
+            task,
+        })
+    }
+}
+
+/// A running tunnel. Dropping the handle closes the tunnel too, just
+/// without waiting for it to finish.
+pub struct TunnelHandle {
+    domain: String,
+    shutdown_tx: oneshot::Sender<()>,
+    task: JoinHandle<Result<(), Error>>,
+}
+
+impl TunnelHandle {
+    /// The domain the server assigned, e.g. `my-app.dvaar.app`
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// The public URL visitors use
+    pub fn url(&self) -> String {
+        format!("https://{}", self.domain)
+    }
+
+    /// Whether the tunnel has stopped: the server closed it or stopped
+    /// answering pings
+    pub fn is_closed(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Close the tunnel, returning the error that ended it if it had
+    /// already stopped on its own
+    pub async fn shutdown(self) -> Result<(), Error> {
+        let _ = self.shutdown_tx.send(());
+        self.task.await.unwrap_or(Err(Error::Closed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dvaar_common::{ControlPacket, HttpRequestPacket, ServerHello};
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    fn binary(packet: ControlPacket) -> Message {
+        Message::Binary(packet.to_bytes().unwrap().into())
+    }
+
+    /// A one-connection tunnel server that assigns `<subdomain>.test.dvaar.app`
+    /// or rejects the subdomain `taken`, then sends one request if `request`
+    /// is set. Returns what the client sent after Init, up to the response's
+    /// `End` or the connection closing.
+    async fn mock_server(request: Option<HttpRequestPacket>) -> (String, JoinHandle<Vec<ControlPacket>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+
+            let hello = match ws.next().await.unwrap().unwrap() {
+                Message::Binary(data) => match ControlPacket::from_bytes(&data).unwrap() {
+                    ControlPacket::Init(hello) => hello,
+                    other => panic!("expected Init, got {:?}", other),
+                },
+                other => panic!("expected a binary message, got {:?}", other),
+            };
+            let subdomain = hello.requested_subdomain.unwrap_or_else(|| "random".to_string());
+            let error = (subdomain == "taken").then(|| "Subdomain is already in use".to_string());
+            let ack = ServerHello {
+                assigned_domain: format!("{}.test.dvaar.app", subdomain),
+                error: error.clone(),
+                server_version: constants::PROTOCOL_VERSION.to_string(),
+                redirect_to: None,
+            };
+            ws.send(binary(ControlPacket::InitAck(ack))).await.unwrap();
+            if error.is_some() {
+                return Vec::new();
+            }
+            ws.send(binary(ControlPacket::Ready)).await.unwrap();
+            if let Some(request) = request {
+                let stream_id = request.stream_id.clone();
+                ws.send(binary(ControlPacket::HttpRequest(request))).await.unwrap();
+                ws.send(binary(ControlPacket::End { stream_id })).await.unwrap();
+            }
+
+            let mut received = Vec::new();
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Message::Binary(data) = msg {
+                    let packet = ControlPacket::from_bytes(&data).unwrap();
+                    let done = matches!(packet, ControlPacket::End { .. });
+                    received.push(packet);
+                    if done {
+                        let _ = ws.close(None).await;
+                        break;
+                    }
+                }
+            }
+            received
+        });
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn test_connect_reports_assigned_url() {
+        let (url, server) = mock_server(None).await;
+        let tunnel = TunnelBuilder::new(url, "token")
+            .subdomain("my-app")
+            .target("3000")
+            .connect()
+            .await
+            .unwrap();
+        assert_eq!(tunnel.domain(), "my-app.test.dvaar.app");
+        assert_eq!(tunnel.url(), "https://my-app.test.dvaar.app");
+        assert!(!tunnel.is_closed());
+
+        // Shutting down closes the connection, which ends the server's loop
+        tunnel.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+
+        let (url, _server) = mock_server(None).await;
+        let rejected = TunnelBuilder::new(url, "token").subdomain("taken").target("3000").connect().await;
+        assert!(matches!(rejected, Err(Error::Rejected(_))));
+
+        let no_target = TunnelBuilder::new("ws://127.0.0.1:1", "token").connect().await;
+        assert!(matches!(no_target, Err(Error::MissingTarget)));
+    }
+
+    #[tokio::test]
+    async fn test_requests_reach_target() {
+        // A target that answers every request with the same body
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = upstream.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = socket.read(&mut buf).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: 5\r\n\r\nhello")
+                .await
+                .unwrap();
+        });
+
+        let (url, server) = mock_server(Some(HttpRequestPacket {
+            stream_id: "s1".to_string(),
+            method: "GET".to_string(),
+            uri: "/".to_string(),
+            headers: vec![("Host".to_string(), "my-app.test.dvaar.app".to_string())],
+        }))
+        .await;
+        let tunnel = TunnelBuilder::new(url, "token").target(port.to_string()).connect().await.unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        tunnel.shutdown().await.unwrap();
+
+        let status = received.iter().find_map(|packet| match packet {
+            ControlPacket::HttpResponse(response) => Some(response.status),
+            _ => None,
+        });
+        assert_eq!(status, Some(200));
+        let body: Vec<u8> = received
+            .iter()
+            .filter_map(|packet| match packet {
+                ControlPacket::Data { data, .. } => Some(data.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(body, b"hello");
+        assert!(received.iter().any(|packet| matches!(packet, ControlPacket::End { .. })));
+    }
+}
@@ -0,0 +1,193 @@
+//! Canned responses and injected latency
+//!
+//! To see how a frontend copes with a failing or slow API, `--mock` answers
+//! matching requests in the client without touching the upstream:
+//! `--mock "GET /health=200:OK"`, or `--mock "/api/*=503@2000:Down"` to wait
+//! two seconds first. `--mock-status` and `--mock-delay` cover the two usual
+//! cases on their own: an error status for matching paths, or extra latency
+//! before a matching request goes upstream as normal.
+//!
+//! Rules are `[METHOD ]PATH=...`. Without a method any method matches. A path
+//! matches exactly, or by prefix when it ends in `*`, and the query string is
+//! ignored. The first matching rule wins.
+
+use std::time::Duration;
+
+/// A response sent in place of the upstream's
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockRule {
+    method: Option<String>,
+    path: String,
+    /// Held before responding, or before going upstream without a response
+    pub delay: Duration,
+    pub response: Option<MockResponse>,
+}
+
+impl MockRule {
+    /// `[METHOD ]PATH=STATUS[@MS][:BODY]`
+    pub fn parse_mock(rule: &str) -> Result<Self, String> {
+        let (method, path, value) = split_rule(rule)?;
+        let (head, body) = value.split_once(':').unwrap_or((value, ""));
+        let (status, delay) = match head.split_once('@') {
+            Some((status, delay)) => (status, parse_millis(delay)?),
+            None => (head, Duration::ZERO),
+        };
+        Ok(Self {
+            method,
+            path,
+            delay,
+            response: Some(MockResponse {
+                status: parse_status(status)?,
+                body: body.as_bytes().to_vec(),
+            }),
+        })
+    }
+
+    /// `[METHOD ]PATH=STATUS`, answered with the status's reason phrase
+    pub fn parse_status(rule: &str) -> Result<Self, String> {
+        let (method, path, value) = split_rule(rule)?;
+        let status = parse_status(value)?;
+        let reason = reqwest::StatusCode::from_u16(status)
+            .ok()
+            .and_then(|status| status.canonical_reason())
+            .unwrap_or_default();
+        Ok(Self {
+            method,
+            path,
+            delay: Duration::ZERO,
+            response: Some(MockResponse {
+                status,
+                body: reason.as_bytes().to_vec(),
+            }),
+        })
+    }
+
+    /// `[METHOD ]PATH=MS`, after which the request goes upstream
+    pub fn parse_delay(rule: &str) -> Result<Self, String> {
+        let (method, path, value) = split_rule(rule)?;
+        Ok(Self {
+            method,
+            path,
+            delay: parse_millis(value)?,
+            response: None,
+        })
+    }
+
+    fn matches(&self, method: &str, uri: &str) -> bool {
+        if self.method.as_deref().is_some_and(|m| m != method) {
+            return false;
+        }
+        let path = uri.split_once('?').map_or(uri, |(path, _)| path);
+        match self.path.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.path,
+        }
+    }
+}
+
+/// Every rule a tunnel was started with, in order
+#[derive(Debug, Clone, Default)]
+pub struct Mocks {
+    rules: Vec<MockRule>,
+}
+
+impl Mocks {
+    pub fn new(rules: Vec<MockRule>) -> Self {
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The first rule matching a request; `uri` may carry a query string
+    pub fn find(&self, method: &str, uri: &str) -> Option<&MockRule> {
+        self.rules.iter().find(|rule| rule.matches(method, uri))
+    }
+}
+
+/// Split `[METHOD ]PATH=VALUE` into its parts
+fn split_rule(rule: &str) -> Result<(Option<String>, String, &str), String> {
+    let (target, value) = rule.split_once('=').ok_or("expected PATH=VALUE")?;
+    let (method, path) = match target.trim().split_once(' ') {
+        Some((method, path)) => (Some(method.to_ascii_uppercase()), path.trim()),
+        None => (None, target.trim()),
+    };
+    if !path.starts_with('/') {
+        return Err(format!("path '{}' must start with /", path));
+    }
+    Ok((method, path.to_string(), value.trim_start()))
+}
+
+fn parse_status(value: &str) -> Result<u16, String> {
+    match value.trim().parse() {
+        Ok(status @ 100..=599) => Ok(status),
+        _ => Err(format!("'{}' is not an HTTP status", value.trim())),
+    }
+}
+
+fn parse_millis(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    value
+        .strip_suffix("ms")
+        .unwrap_or(value)
+        .parse()
+        .map(Duration::from_millis)
+        .map_err(|_| format!("'{}' is not a delay in milliseconds", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rules() {
+        let rule = MockRule::parse_mock("get /health=200:OK: all good").unwrap();
+        assert!(rule.matches("GET", "/health?verbose=1"));
+        assert!(!rule.matches("POST", "/health"));
+        assert!(!rule.matches("GET", "/healthz"));
+        assert_eq!(rule.delay, Duration::ZERO);
+        assert_eq!(
+            rule.response,
+            Some(MockResponse {
+                status: 200,
+                body: b"OK: all good".to_vec()
+            })
+        );
+
+        let rule = MockRule::parse_mock("/api/*=503@1500ms").unwrap();
+        assert!(rule.matches("POST", "/api/orders"));
+        assert_eq!(rule.delay, Duration::from_millis(1500));
+        assert_eq!(rule.response.unwrap().body, b"");
+
+        let rule = MockRule::parse_status("/api/*=502").unwrap();
+        assert_eq!(rule.response.unwrap().body, b"Bad Gateway");
+
+        let rule = MockRule::parse_delay("GET /slow=250").unwrap();
+        assert_eq!(rule.delay, Duration::from_millis(250));
+        assert!(rule.response.is_none());
+
+        assert!(MockRule::parse_mock("/health").is_err());
+        assert!(MockRule::parse_mock("health=200").is_err());
+        assert!(MockRule::parse_mock("/health=OK").is_err());
+        assert!(MockRule::parse_mock("/health=200@soon").is_err());
+        assert!(MockRule::parse_status("/health=1000").is_err());
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let mocks = Mocks::new(vec![
+            MockRule::parse_mock("/api/health=200:OK").unwrap(),
+            MockRule::parse_status("/api/*=500").unwrap(),
+        ]);
+        assert_eq!(mocks.find("GET", "/api/health").unwrap().response.as_ref().unwrap().status, 200);
+        assert_eq!(mocks.find("GET", "/api/users").unwrap().response.as_ref().unwrap().status, 500);
+        assert!(mocks.find("GET", "/").is_none());
+    }
+}
@@ -0,0 +1,1000 @@
+//! Serving requests on an open tunnel
+//!
+//! [`Proxy`] reads the server's packets and answers each `HttpRequest` from
+//! the target: plain requests through a pooled client, WebSocket upgrades
+//! bridged frame by frame. It keeps the tunnel alive with pings, paces
+//! responses to servers that do flow control, reports upstream health, and
+//! stops when the server closes the tunnel.
+//!
+//! Nothing is printed or stored here. Programs that show the traffic, like
+//! the CLI's terminal UI and inspector, get it as [`Event`]s.
+
+use crate::coalesce::Coalescer;
+use crate::cors::CorsPolicy;
+use crate::handshake::{Connection, ServerSink};
+use crate::mock::Mocks;
+use crate::request::{handle_request, serve_shared_inspector, RequestContext, SharedInspector};
+use crate::rewrite::BodyRewriter;
+use crate::stream_writer::StreamWindows;
+use crate::upstream_metrics::MetricsTracker;
+use crate::upstream_retry::UpstreamRetry;
+use crate::websocket::{self, WebSocketReaper};
+use crate::Error;
+use dvaar_common::{constants, is_newer_version, ControlPacket, HttpRequestPacket, Keepalive, StreamErrorCode, WireFormat};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, mpsc::error::SendTimeoutError, oneshot, Mutex};
+use tokio_tungstenite::{tungstenite::Message, Connector};
+
+/// Request body chunks buffered per stream before the read loop waits
+const BODY_CHANNEL_CAPACITY: usize = 32;
+
+/// Request bodies that see no chunk for this long are dropped
+const REQUEST_BODY_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// What a request to a TLS upstream needs besides its address
+#[derive(Clone)]
+pub struct UpstreamTls {
+    /// For WebSocket upgrades; plain requests get TLS from the pooled client
+    pub connector: Connector,
+    /// Name to send as SNI and verify, when it isn't the address's host.
+    /// For an upstream reached by address whose certificate names something
+    /// else (`127.0.0.1:8443` serving `app.local`); connections still go to
+    /// the address.
+    pub server_name: Option<String>,
+}
+
+impl UpstreamTls {
+    /// `host:port` to put in request URLs: the server name if there is one,
+    /// on the address's port
+    pub fn authority(&self, upstream_addr: &str) -> String {
+        match (&self.server_name, upstream_addr.rsplit_once(':')) {
+            (Some(name), Some((_, port))) => format!("{}:{}", name, port),
+            (Some(name), None) => name.clone(),
+            (None, _) => upstream_addr.to_string(),
+        }
+    }
+}
+
+/// Which way a WebSocket frame crossed the tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the visitor to the target
+    Inbound,
+    /// From the target to the visitor
+    Outbound,
+}
+
+/// Something that happened on the tunnel, for programs that show its traffic
+#[derive(Debug)]
+pub enum Event {
+    /// A request was answered
+    RequestFinished {
+        method: String,
+        uri: String,
+        status: u16,
+        elapsed: Duration,
+        /// Response body bytes sent
+        size_bytes: usize,
+    },
+    /// A request went to the target, with what was sent and received.
+    /// Only reported while capturing.
+    RequestCaptured(Exchange),
+    /// A request started using a connection to the target
+    ConnectionOpened,
+    /// That connection is no longer in use
+    ConnectionClosed,
+    /// A request failed because the target's host didn't resolve
+    UpstreamUnresolved(String),
+    /// A WebSocket upgrade went through
+    WebSocketOpened { stream_id: String, uri: String },
+    /// A frame crossed a bridged WebSocket. Only reported while capturing,
+    /// and dropped rather than holding up the socket when events back up.
+    WebSocketFrame {
+        stream_id: String,
+        data: Vec<u8>,
+        is_binary: bool,
+        direction: Direction,
+    },
+    /// How long the server took to answer a ping
+    RoundTrip(Duration),
+}
+
+/// One request and its response, bodies cut off at `MAX_CAPTURED_BODY` bytes
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    pub stream_id: String,
+    pub method: String,
+    pub uri: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: Vec<u8>,
+    pub status: u16,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: Vec<u8>,
+    pub duration: Duration,
+    /// Until the first response body byte, or the headers for an empty body;
+    /// `None` when the target never answered
+    pub ttfb: Option<Duration>,
+    /// Until the target's response headers arrived
+    pub upstream_connect: Duration,
+    pub size_bytes: usize,
+    pub request_size_bytes: usize,
+}
+
+/// Largest request or response body kept in an [`Exchange`]
+pub const MAX_CAPTURED_BODY: usize = 1024 * 1024;
+
+/// Answers a tunnel's requests from a target
+///
+/// Built with the target and a client for it, then configured before
+/// [`run`](Proxy::run) serves an open connection:
+///
+/// ```no_run
+/// # async fn serve(connection: dvaar_client::handshake::Connection) -> Result<(), dvaar_client::Error> {
+/// let (_shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+/// dvaar_client::Proxy::new(reqwest::Client::new(), "localhost:3000")
+///     .basic_auth("admin:secret")
+///     .run(connection, shutdown_rx)
+///     .await
+/// # }
+/// ```
+pub struct Proxy {
+    http_client: reqwest::Client,
+    /// `host:port` requests are sent to
+    target: String,
+    upstream_tls: Option<UpstreamTls>,
+    basic_auth: Option<String>,
+    host_header: Option<String>,
+    mocks: Option<Arc<Mocks>>,
+    cors: Option<Arc<CorsPolicy>>,
+    rewriter: Option<Arc<BodyRewriter>>,
+    upstream_retry: UpstreamRetry,
+    ping_interval: Duration,
+    pong_timeout: Duration,
+    ws_idle_timeout: Duration,
+    forwarded_headers: bool,
+    forward_only: Option<Vec<String>>,
+    coalesce: bool,
+    /// Where share-link requests for the inspector are sent
+    shared_inspector: Option<(String, String)>,
+    events: Option<mpsc::Sender<Event>>,
+    capture: bool,
+}
+
+impl Proxy {
+    /// Send requests to `target` (`host:port`) through `http_client`, with
+    /// none of the options below
+    pub fn new(http_client: reqwest::Client, target: impl Into<String>) -> Self {
+        Self {
+            http_client,
+            target: target.into(),
+            upstream_tls: None,
+            basic_auth: None,
+            host_header: None,
+            mocks: None,
+            cors: None,
+            rewriter: None,
+            upstream_retry: UpstreamRetry::default(),
+            ping_interval: Duration::from_secs(constants::WS_PING_INTERVAL_SECONDS),
+            pong_timeout: Duration::from_secs(constants::WS_PONG_TIMEOUT_SECONDS),
+            ws_idle_timeout: Duration::from_secs(constants::WS_IDLE_TIMEOUT_SECONDS),
+            forwarded_headers: true,
+            forward_only: None,
+            coalesce: false,
+            shared_inspector: None,
+            events: None,
+            capture: false,
+        }
+    }
+
+    /// Reach the target over TLS. `http_client` must trust it too.
+    pub fn upstream_tls(mut self, tls: UpstreamTls) -> Self {
+        self.upstream_tls = Some(tls);
+        self
+    }
+
+    /// Require `Authorization: Basic` with these `user:pass` credentials
+    pub fn basic_auth(mut self, credentials: impl Into<String>) -> Self {
+        self.basic_auth = Some(credentials.into());
+        self
+    }
+
+    /// Send this `Host` header to the target instead of the visitor's
+    pub fn host_header(mut self, host: impl Into<String>) -> Self {
+        self.host_header = Some(host.into());
+        self
+    }
+
+    /// Answer or delay requests matching these rules before they go upstream
+    pub fn mocks(mut self, mocks: Mocks) -> Self {
+        self.mocks = (!mocks.is_empty()).then(|| Arc::new(mocks));
+        self
+    }
+
+    /// Answer CORS preflights here and add CORS headers to responses
+    pub fn cors(mut self, policy: CorsPolicy) -> Self {
+        self.cors = Some(Arc::new(policy));
+        self
+    }
+
+    /// Search and replace in text response bodies
+    pub fn rewriter(mut self, rewriter: BodyRewriter) -> Self {
+        self.rewriter = (!rewriter.is_empty()).then(|| Arc::new(rewriter));
+        self
+    }
+
+    /// Retry safe requests up to `attempts` times while the target refuses
+    /// connections or answers 502/503, waiting `budget` at most in all
+    pub fn retry_upstream(mut self, attempts: u32, budget: Duration) -> Self {
+        self.upstream_retry = UpstreamRetry::new(attempts, budget);
+        self
+    }
+
+    /// Set how often to ping the server and how long to wait for a pong
+    /// before the tunnel is treated as dead
+    pub fn keepalive(mut self, ping_interval: Duration, pong_timeout: Duration) -> Self {
+        self.ping_interval = ping_interval;
+        self.pong_timeout = pong_timeout;
+        self
+    }
+
+    /// Close bridged WebSockets after this long without a frame (zero never does)
+    pub fn ws_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.ws_idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Pass the server's `X-Forwarded-*` headers on to the target (the default)
+    pub fn forwarded_headers(mut self, enabled: bool) -> Self {
+        self.forwarded_headers = enabled;
+        self
+    }
+
+    /// Forward only these request headers to the target (case-insensitive)
+    pub fn forward_only(mut self, names: Vec<String>) -> Self {
+        self.forward_only = Some(names);
+        self
+    }
+
+    /// Send identical in-flight GETs upstream once and share the response
+    pub fn coalesce(mut self, enabled: bool) -> Self {
+        self.coalesce = enabled;
+        self
+    }
+
+    /// Serve share-link visitors a read-only view of the inspector at `addr`,
+    /// limited to the tunnel it knows as `tunnel_id`
+    pub fn shared_inspector(mut self, addr: impl Into<String>, tunnel_id: impl Into<String>) -> Self {
+        self.shared_inspector = Some((addr.into(), tunnel_id.into()));
+        self
+    }
+
+    /// Report what happens on the tunnel to `events`. With `capture`, that
+    /// includes each request's bodies and every WebSocket frame.
+    pub fn events(mut self, events: mpsc::Sender<Event>, capture: bool) -> Self {
+        self.events = Some(events);
+        self.capture = capture;
+        self
+    }
+
+    /// What every request handler on one connection shares
+    fn context(&self, windows: Option<StreamWindows>) -> RequestContext {
+        RequestContext {
+            upstream_retry: self.upstream_retry,
+            upstream_tls: self.upstream_tls.clone(),
+            basic_auth: self.basic_auth.clone(),
+            host_header: self.host_header.clone(),
+            mocks: self.mocks.clone(),
+            cors: self.cors.clone(),
+            rewriter: self.rewriter.clone(),
+            windows,
+            events: self.events.clone(),
+            capture: self.capture,
+            ..RequestContext::new(self.target.clone(), self.http_client.clone())
+        }
+    }
+
+    /// Answer one request the way a running tunnel would, with its response
+    /// packets going to `packet_tx` instead of a server
+    pub async fn serve(
+        &self,
+        request: HttpRequestPacket,
+        body_rx: mpsc::Receiver<Vec<u8>>,
+        packet_tx: mpsc::Sender<ControlPacket>,
+    ) {
+        handle_request(&self.context(None), request, body_rx, packet_tx).await;
+    }
+
+    /// Serve the tunnel until the server closes it, it stops answering
+    /// pings, or `shutdown` fires (or its sender is dropped)
+    pub async fn run(self, connection: Connection, mut shutdown: oneshot::Receiver<()>) -> Result<(), Error> {
+        let Connection {
+            sink,
+            mut stream,
+            hello,
+            wire_format,
+            ..
+        } = connection;
+        let server_version = hello.server_version;
+
+        // Servers that acknowledge response bytes get each body paced to the visitor
+        let flow_control = !is_newer_version(constants::FLOW_CONTROL_PROTOCOL_VERSION, &server_version);
+        let ctx = Arc::new(self.context(flow_control.then(StreamWindows::default)));
+        // Share-link requests go to the inspector instead, as plain requests
+        let shared_inspector = self.shared_inspector.clone().map(|(addr, tunnel_id)| {
+            Arc::new(SharedInspector {
+                ctx: RequestContext {
+                    websockets: ctx.websockets.clone(),
+                    events: self.events.clone(),
+                    ..RequestContext::new(addr, self.http_client.clone())
+                },
+                tunnel_id,
+            })
+        });
+        let frame_events = self.events.clone().filter(|_| self.capture);
+
+        let upstream_metrics = Arc::new(MetricsTracker::new());
+        let (packet_tx, packet_rx) = mpsc::channel::<ControlPacket>(100);
+        let mut writer_task = tokio::spawn(write_packets(
+            sink,
+            packet_rx,
+            PacketEncoding {
+                wire_format,
+                server_version: server_version.clone(),
+            },
+            upstream_metrics.clone(),
+            frame_events.clone(),
+        ));
+        let ws_reaper = WebSocketReaper::spawn(ctx.websockets.clone(), packet_tx.clone(), self.ws_idle_timeout);
+
+        // Active request body channels (stream_id -> sender + last activity)
+        let bodies: Arc<Mutex<HashMap<String, RequestBodyState>>> = Arc::default();
+        let cleanup_task = spawn_body_cleanup(bodies.clone());
+
+        // Running request handlers, aborted when the server cancels their stream
+        let in_flight: InFlightRequests = Arc::default();
+        let coalescer = self.coalesce.then(Coalescer::new);
+
+        let mut ping_interval = tokio::time::interval(self.ping_interval);
+        let mut last_pong = Instant::now();
+        let keepalive = Keepalive::for_server(&server_version);
+        let mut upstream_metrics_interval = metrics_push_interval();
+
+        let result = loop {
+            let msg = tokio::select! {
+                msg = stream.next() => match msg {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => break Err(e.into()),
+                    None => break Ok(()),
+                },
+                _ = &mut shutdown => break Ok(()),
+                // Keepalive: ping the server, give up if it stopped answering
+                _ = ping_interval.tick() => {
+                    if last_pong.elapsed() > self.pong_timeout {
+                        break Err(Error::Timeout("a pong from the server"));
+                    }
+                    let _ = packet_tx.send(keepalive.ping()).await;
+                    continue;
+                }
+                // Report upstream health to the server
+                _ = upstream_metrics_interval.tick() => {
+                    let _ = packet_tx.send(ControlPacket::Metrics(upstream_metrics.snapshot())).await;
+                    continue;
+                }
+            };
+
+            let data = match msg {
+                Message::Binary(data) => data,
+                Message::Pong(_) => {
+                    last_pong = Instant::now();
+                    continue;
+                }
+                Message::Close(_) => break Ok(()),
+                _ => continue,
+            };
+            let packet = match ControlPacket::decode_limited(&data, constants::MAX_FRAME_BYTES, wire_format) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    tracing::warn!("Failed to parse packet: {}", e);
+                    continue;
+                }
+            };
+
+            match packet {
+                ControlPacket::HttpRequest(mut request) => {
+                    if take_share_marker(&mut request.headers) {
+                        let stream_id = request.stream_id.clone();
+                        let in_flight_for_task = in_flight.clone();
+                        let mut tasks = in_flight.lock().await;
+                        let task = tokio::spawn(serve_shared_inspector(
+                            shared_inspector.clone(),
+                            request,
+                            packet_tx.clone(),
+                            in_flight_for_task,
+                        ));
+                        tasks.insert(stream_id, task.abort_handle());
+                        continue;
+                    }
+                    if !self.forwarded_headers {
+                        strip_forwarded_headers(&mut request.headers);
+                    }
+                    if let Some(allowed) = &self.forward_only {
+                        keep_only_headers(&mut request.headers, allowed, self.basic_auth.is_some());
+                    }
+                    let stream_id = request.stream_id.clone();
+                    upstream_metrics.request_started(&stream_id);
+                    let packet_tx = match &coalescer {
+                        Some(coalescer) => match coalescer.route(&request, &packet_tx) {
+                            Some(tx) => tx,
+                            None => continue,
+                        },
+                        None => packet_tx.clone(),
+                    };
+                    let (body_tx, body_rx) = mpsc::channel::<Vec<u8>>(BODY_CHANNEL_CAPACITY);
+                    bodies.lock().await.insert(
+                        stream_id.clone(),
+                        RequestBodyState {
+                            sender: body_tx,
+                            last_activity: Instant::now(),
+                        },
+                    );
+
+                    let ctx = ctx.clone();
+                    let in_flight_for_task = in_flight.clone();
+                    let stream_id_for_task = stream_id.clone();
+
+                    // Hold the lock while spawning so the task can't finish
+                    // (and deregister) before it is registered
+                    let mut tasks = in_flight.lock().await;
+                    let task = tokio::spawn(async move {
+                        handle_request(&ctx, request, body_rx, packet_tx).await;
+                        in_flight_for_task.lock().await.remove(&stream_id_for_task);
+                    });
+                    tasks.insert(stream_id, task.abort_handle());
+                }
+                ControlPacket::StreamCancel { stream_id } => {
+                    if let Some(coalescer) = &coalescer {
+                        coalescer.cancel(&stream_id);
+                    }
+                    upstream_metrics.request_cancelled(&stream_id);
+                    cancel_request(&bodies, &in_flight, &stream_id).await;
+                }
+                ControlPacket::Data { stream_id, data } => {
+                    forward_body_chunk(
+                        &bodies,
+                        &in_flight,
+                        &packet_tx,
+                        stream_id,
+                        data,
+                        Duration::from_millis(constants::STREAM_SEND_TIMEOUT_MS),
+                    )
+                    .await;
+                }
+                ControlPacket::End { stream_id } => {
+                    bodies.lock().await.remove(&stream_id);
+                }
+                ControlPacket::WebSocketFrame {
+                    stream_id,
+                    data,
+                    is_binary,
+                } => {
+                    if let Some(events) = &frame_events {
+                        let _ = events.try_send(Event::WebSocketFrame {
+                            stream_id: stream_id.clone(),
+                            data: data.clone(),
+                            is_binary,
+                            direction: Direction::Inbound,
+                        });
+                    }
+                    websocket::forward_frame(&ctx.websockets, &packet_tx, stream_id, data, is_binary).await;
+                }
+                ControlPacket::WebSocketClose { stream_id, .. } => {
+                    websocket::close(&ctx.websockets, &stream_id).await;
+                }
+                ControlPacket::WindowUpdate { stream_id, bytes } => {
+                    if let Some(windows) = &ctx.windows {
+                        windows.grant(&stream_id, bytes);
+                    }
+                }
+                ControlPacket::Ping(nonce) => {
+                    let _ = packet_tx.send(ControlPacket::Pong(nonce)).await;
+                }
+                ControlPacket::Pong(nonce) => {
+                    last_pong = Instant::now();
+                    if let (Some(rtt), Some(events)) = (keepalive.round_trip(nonce), &self.events) {
+                        let _ = events.try_send(Event::RoundTrip(rtt));
+                    }
+                }
+                ControlPacket::Close { code, reason } => break Err(Error::ClosedByServer { code, reason }),
+                _ => {
+                    tracing::debug!("Unexpected packet type");
+                }
+            }
+        };
+
+        cleanup_task.abort();
+        drop(ws_reaper);
+        for (_, task) in in_flight.lock().await.drain() {
+            task.abort();
+        }
+        websocket::close_all(&ctx.websockets).await;
+        // Let the writer flush what's queued and close the connection
+        drop(packet_tx);
+        drop(coalescer);
+        if tokio::time::timeout(Duration::from_secs(5), &mut writer_task).await.is_err() {
+            writer_task.abort();
+        }
+        result
+    }
+}
+
+/// How packets are put on the wire for the server at the other end
+struct PacketEncoding {
+    wire_format: WireFormat,
+    /// What the server's version can decode decides how packets are sent
+    server_version: String,
+}
+
+/// The one task that writes to the server, closing the connection when
+/// every sender is gone. Each packet passes the upstream health tracker on
+/// its way, and outgoing WebSocket frames are reported to `frame_events`.
+async fn write_packets(
+    mut sink: ServerSink,
+    mut packet_rx: mpsc::Receiver<ControlPacket>,
+    encoding: PacketEncoding,
+    upstream_metrics: Arc<MetricsTracker>,
+    frame_events: Option<mpsc::Sender<Event>>,
+) {
+    while let Some(packet) = packet_rx.recv().await {
+        upstream_metrics.observe(&packet);
+        if let (Some(events), ControlPacket::WebSocketFrame { stream_id, data, is_binary }) = (&frame_events, &packet) {
+            let _ = events.try_send(Event::WebSocketFrame {
+                stream_id: stream_id.clone(),
+                data: data.clone(),
+                is_binary: *is_binary,
+                direction: Direction::Outbound,
+            });
+        }
+        let bytes = match packet.for_server(&encoding.server_version).encode(encoding.wire_format) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                tracing::error!("Failed to serialize packet: {}", e);
+                continue;
+            }
+        };
+        if sink.send(Message::Binary(bytes.into())).await.is_err() {
+            return;
+        }
+    }
+    let _ = sink.close().await;
+}
+
+pub(crate) struct RequestBodyState {
+    pub sender: mpsc::Sender<Vec<u8>>,
+    pub last_activity: Instant,
+}
+
+/// In-flight request handlers (stream_id -> task), so a cancelled stream can be aborted
+pub(crate) type InFlightRequests = Arc<Mutex<HashMap<String, tokio::task::AbortHandle>>>;
+
+/// Drop request bodies nothing has been sent on for `REQUEST_BODY_IDLE_TIMEOUT`
+fn spawn_body_cleanup(bodies: Arc<Mutex<HashMap<String, RequestBodyState>>>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let cutoff = Instant::now()
+                .checked_sub(REQUEST_BODY_IDLE_TIMEOUT)
+                .unwrap_or_else(Instant::now);
+            bodies.lock().await.retain(|stream_id, state| {
+                if state.last_activity < cutoff {
+                    tracing::warn!("Dropping stale request body stream {}", stream_id);
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+    })
+}
+
+/// Hand a request body chunk to its handler. If the upstream stops reading
+/// the body, that request is failed: waiting on it would block the read loop
+/// and with it every other stream on the tunnel.
+async fn forward_body_chunk(
+    bodies: &Mutex<HashMap<String, RequestBodyState>>,
+    in_flight: &InFlightRequests,
+    packet_tx: &mpsc::Sender<ControlPacket>,
+    stream_id: String,
+    data: Vec<u8>,
+    timeout: Duration,
+) {
+    let body_tx = {
+        let mut bodies = bodies.lock().await;
+        match bodies.get_mut(&stream_id) {
+            Some(state) => {
+                state.last_activity = Instant::now();
+                state.sender.clone()
+            }
+            None => return,
+        }
+    };
+
+    match body_tx.send_timeout(data, timeout).await {
+        Ok(()) => {}
+        Err(SendTimeoutError::Closed(_)) => {
+            bodies.lock().await.remove(&stream_id);
+        }
+        Err(SendTimeoutError::Timeout(_)) => {
+            tracing::warn!("Upstream stopped reading the body of {}, failing the request", stream_id);
+            bodies.lock().await.remove(&stream_id);
+            if let Some(task) = in_flight.lock().await.remove(&stream_id) {
+                task.abort();
+            }
+            let _ = packet_tx
+                .send(ControlPacket::StreamError {
+                    stream_id,
+                    error: "Upstream stopped reading the request body".to_string(),
+                    code: StreamErrorCode::Timeout,
+                })
+                .await;
+        }
+    }
+}
+
+/// Stop a request the server cancelled: its body is dropped and its handler
+/// aborted, which closes the upstream connection mid-request
+async fn cancel_request(
+    bodies: &Mutex<HashMap<String, RequestBodyState>>,
+    in_flight: &InFlightRequests,
+    stream_id: &str,
+) {
+    bodies.lock().await.remove(stream_id);
+    if let Some(task) = in_flight.lock().await.remove(stream_id) {
+        tracing::debug!("Aborting cancelled request {}", stream_id);
+        task.abort();
+    }
+}
+
+/// Ticks every `METRICS_INTERVAL_SECONDS`, the first one a full period in
+fn metrics_push_interval() -> tokio::time::Interval {
+    let period = Duration::from_secs(constants::METRICS_INTERVAL_SECONDS);
+    tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+}
+
+/// Remove the header the server sets on requests arriving through an
+/// inspector share link, returning whether it was there
+fn take_share_marker(headers: &mut Vec<(String, String)>) -> bool {
+    let before = headers.len();
+    headers.retain(|(key, _)| !key.eq_ignore_ascii_case(constants::INSPECT_SHARE_HEADER));
+    headers.len() != before
+}
+
+/// Drop `X-Forwarded-*` headers, for upstreams that reject requests carrying them
+fn strip_forwarded_headers(headers: &mut Vec<(String, String)>) {
+    let forwarded = [
+        constants::FORWARDED_FOR_HEADER,
+        constants::FORWARDED_PROTO_HEADER,
+        constants::FORWARDED_HOST_HEADER,
+    ];
+    headers.retain(|(key, _)| !forwarded.iter().any(|name| key.eq_ignore_ascii_case(name)));
+}
+
+/// Drop every header not in `allowed`, for `--forward-only`. Headers a
+/// WebSocket upgrade needs always stay, and so does `Authorization` while
+/// `--auth` has to check it.
+fn keep_only_headers(headers: &mut Vec<(String, String)>, allowed: &[String], keep_authorization: bool) {
+    headers.retain(|(key, _)| {
+        let key = key.to_ascii_lowercase();
+        allowed.iter().any(|name| name.eq_ignore_ascii_case(&key))
+            || matches!(key.as_str(), "connection" | "upgrade")
+            || key.starts_with("sec-websocket-")
+            || (keep_authorization && key == "authorization")
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dvaar_common::{CloseCode, ServerHello};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::connect_async;
+
+    #[test]
+    fn test_strip_forwarded_headers() {
+        let mut headers = vec![
+            ("x-forwarded-for".to_string(), "203.0.113.9".to_string()),
+            ("X-Forwarded-Proto".to_string(), "https".to_string()),
+            ("x-forwarded-host".to_string(), "myapp.dvaar.app".to_string()),
+            ("accept".to_string(), "*/*".to_string()),
+        ];
+        strip_forwarded_headers(&mut headers);
+        assert_eq!(headers, vec![("accept".to_string(), "*/*".to_string())]);
+    }
+
+    #[test]
+    fn test_keep_only_headers() {
+        let headers = vec![
+            ("Accept".to_string(), "*/*".to_string()),
+            ("cookie".to_string(), "session=abc".to_string()),
+            ("X-Real-IP".to_string(), "203.0.113.9".to_string()),
+            ("content-type".to_string(), "text/plain".to_string()),
+            ("Authorization".to_string(), "Basic dTpw".to_string()),
+            ("Upgrade".to_string(), "websocket".to_string()),
+            ("Sec-WebSocket-Key".to_string(), "dGhlIHNhbXBsZSBub25jZQ==".to_string()),
+        ];
+        let allowed = vec!["accept".to_string(), "Content-Type".to_string()];
+        let names = |keep_authorization| {
+            let mut kept = headers.clone();
+            keep_only_headers(&mut kept, &allowed, keep_authorization);
+            kept.into_iter().map(|(k, _)| k).collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(false), ["Accept", "content-type", "Upgrade", "Sec-WebSocket-Key"]);
+        assert_eq!(names(true), ["Accept", "content-type", "Authorization", "Upgrade", "Sec-WebSocket-Key"]);
+    }
+
+    #[tokio::test]
+    async fn test_forward_only_keeps_host_override() {
+        // Upstream answering with the request head it received
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0u8; 1024];
+            while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = socket.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", buf.len());
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(&buf).await.unwrap();
+        });
+
+        let mut headers = vec![
+            ("Host".to_string(), "myapp.dvaar.app".to_string()),
+            ("Accept-Language".to_string(), "en".to_string()),
+            ("Cookie".to_string(), "session=abc".to_string()),
+        ];
+        keep_only_headers(&mut headers, &["accept-language".to_string()], false);
+        let request = HttpRequestPacket {
+            stream_id: dvaar_common::new_stream_id(),
+            method: "GET".to_string(),
+            uri: "/".to_string(),
+            headers,
+        };
+        let (body_tx, body_rx) = mpsc::channel(1);
+        drop(body_tx);
+        let (packet_tx, mut packet_rx) = mpsc::channel(16);
+        Proxy::new(reqwest::Client::new(), addr.to_string())
+            .host_header("app.local")
+            .serve(request, body_rx, packet_tx)
+            .await;
+
+        let mut received = Vec::new();
+        while let Ok(packet) = packet_rx.try_recv() {
+            if let ControlPacket::Data { data, .. } = packet {
+                received.extend(data);
+            }
+        }
+        let received = String::from_utf8(received).unwrap().to_lowercase();
+        assert!(received.contains("\r\nhost: app.local\r\n"), "{}", received);
+        assert!(received.contains("\r\naccept-language: en\r\n"), "{}", received);
+        assert!(!received.contains("cookie"), "{}", received);
+    }
+
+    #[tokio::test]
+    async fn test_shared_inspector_is_read_only() {
+        // An inspector API with nothing captured yet
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let inspector = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await;
+                let _ = socket
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n[]")
+                    .await;
+            }
+        });
+
+        let mut headers = vec![
+            ("Accept".to_string(), "*/*".to_string()),
+            ("x-dvaar-inspect".to_string(), "1".to_string()),
+        ];
+        assert!(take_share_marker(&mut headers));
+        assert_eq!(headers.len(), 1);
+        assert!(!take_share_marker(&mut headers));
+
+        let serve = |method: &str, inspector_addr: Option<String>| {
+            let request = HttpRequestPacket {
+                stream_id: dvaar_common::new_stream_id(),
+                method: method.to_string(),
+                uri: "/api/tunnels/t1/requests".to_string(),
+                headers: headers.clone(),
+            };
+            async move {
+                let (packet_tx, mut packet_rx) = mpsc::channel(64);
+                let in_flight: InFlightRequests = Arc::default();
+                let inspector = inspector_addr.map(|addr| {
+                    Arc::new(SharedInspector {
+                        ctx: RequestContext::new(addr, reqwest::Client::new()),
+                        tunnel_id: "t1".to_string(),
+                    })
+                });
+                serve_shared_inspector(inspector, request, packet_tx, in_flight).await;
+                match packet_rx.recv().await {
+                    Some(ControlPacket::HttpResponse(response)) => response.status,
+                    other => panic!("expected HttpResponse, got {:?}", other),
+                }
+            }
+        };
+
+        assert_eq!(serve("GET", Some(inspector.clone())).await, 200);
+        assert_eq!(serve("POST", Some(inspector)).await, 405);
+        assert_eq!(serve("GET", None).await, 404);
+    }
+
+    #[tokio::test]
+    async fn test_unread_request_body_fails_only_its_stream() {
+        let bodies = Mutex::new(HashMap::new());
+        let in_flight: InFlightRequests = Arc::default();
+        let (packet_tx, mut packet_rx) = mpsc::channel(8);
+
+        // "stuck" has a full body channel nobody reads; "ok" drains its own
+        let (stuck_tx, _stuck_rx) = mpsc::channel::<Vec<u8>>(1);
+        stuck_tx.try_send(b"first".to_vec()).unwrap();
+        let (ok_tx, mut ok_rx) = mpsc::channel::<Vec<u8>>(1);
+        for (id, sender) in [("stuck", stuck_tx), ("ok", ok_tx)] {
+            bodies.lock().await.insert(
+                id.to_string(),
+                RequestBodyState {
+                    sender,
+                    last_activity: Instant::now(),
+                },
+            );
+        }
+        let handler = tokio::spawn(std::future::pending::<()>());
+        in_flight.lock().await.insert("stuck".to_string(), handler.abort_handle());
+
+        let timeout = Duration::from_millis(50);
+        forward_body_chunk(&bodies, &in_flight, &packet_tx, "stuck".to_string(), b"more".to_vec(), timeout).await;
+        forward_body_chunk(&bodies, &in_flight, &packet_tx, "ok".to_string(), b"chunk".to_vec(), timeout).await;
+
+        assert_eq!(ok_rx.recv().await, Some(b"chunk".to_vec()));
+        assert!(bodies.lock().await.contains_key("ok"));
+        assert!(!bodies.lock().await.contains_key("stuck"));
+        assert!(handler.await.unwrap_err().is_cancelled());
+        match packet_rx.try_recv() {
+            Ok(ControlPacket::StreamError { stream_id, .. }) => assert_eq!(stream_id, "stuck"),
+            other => panic!("expected StreamError, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_stream_closes_the_upstream_connection() {
+        // Upstream that sends part of a response, then reports when the connection closes
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (closed_tx, closed_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1000000\r\n\r\npartial")
+                .await
+                .unwrap();
+            while matches!(socket.read(&mut buf).await, Ok(n) if n > 0) {}
+            let _ = closed_tx.send(());
+        });
+
+        let bodies = Mutex::new(HashMap::new());
+        let in_flight: InFlightRequests = Arc::default();
+        let (packet_tx, mut packet_rx) = mpsc::channel(64);
+        // A GET carries no body, so the channel is closed from the start
+        let (_, body_rx) = mpsc::channel(1);
+        let stream_id = dvaar_common::new_stream_id();
+        let request = HttpRequestPacket {
+            stream_id: stream_id.clone(),
+            method: "GET".to_string(),
+            uri: "/slow".to_string(),
+            headers: vec![],
+        };
+        let handler = tokio::spawn(async move {
+            let ctx = RequestContext::new(addr.clone(), reqwest::Client::new());
+            handle_request(&ctx, request, body_rx, packet_tx).await;
+        });
+        in_flight.lock().await.insert(stream_id.clone(), handler.abort_handle());
+
+        // Cancel once the response has started
+        tokio::time::timeout(Duration::from_secs(5), packet_rx.recv())
+            .await
+            .expect("response never started")
+            .expect("handler exited before responding");
+        cancel_request(&bodies, &in_flight, &stream_id).await;
+
+        let aborted = tokio::time::timeout(Duration::from_secs(5), handler)
+            .await
+            .expect("request was not aborted");
+        assert!(aborted.unwrap_err().is_cancelled());
+        assert!(in_flight.lock().await.is_empty());
+        tokio::time::timeout(Duration::from_secs(5), closed_rx)
+            .await
+            .expect("upstream connection was left open")
+            .unwrap();
+    }
+
+    /// A tunnel to a server at `addr` that has already said hello
+    async fn connection(addr: std::net::SocketAddr) -> Connection {
+        let (ws_stream, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        let (sink, stream) = ws_stream.split();
+        Connection {
+            sink,
+            stream,
+            hello: ServerHello {
+                assigned_domain: "app.test.dvaar.app".to_string(),
+                error: None,
+                server_version: constants::PROTOCOL_VERSION.to_string(),
+                redirect_to: None,
+            },
+            wire_format: WireFormat::MessagePack,
+            latency_ms: 0,
+            handshake_ms: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unresponsive_server_closes_tunnel() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Accept the WebSocket but never answer our pings
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        });
+
+        let proxy = Proxy::new(reqwest::Client::new(), "localhost:1")
+            .keepalive(Duration::from_millis(50), Duration::from_millis(150));
+        let (_shutdown_tx, shutdown_rx) = oneshot::channel();
+        let result = tokio::time::timeout(Duration::from_secs(5), proxy.run(connection(addr).await, shutdown_rx))
+            .await
+            .expect("tunnel should give up on a silent server");
+        assert!(matches!(result, Err(Error::Timeout(_))), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn test_server_close_reason_ends_tunnel() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Say why, then drop the connection like the server does
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let close = ControlPacket::Close {
+                code: CloseCode::BandwidthExceeded,
+                reason: "Monthly bandwidth limit exceeded (1 GB)".to_string(),
+            };
+            ws.send(Message::Binary(close.to_bytes().unwrap().into())).await.unwrap();
+        });
+
+        let proxy = Proxy::new(reqwest::Client::new(), "localhost:1");
+        let (_shutdown_tx, shutdown_rx) = oneshot::channel();
+        let error = tokio::time::timeout(Duration::from_secs(5), proxy.run(connection(addr).await, shutdown_rx))
+            .await
+            .expect("tunnel should end once the server closes it")
+            .unwrap_err();
+        assert!(matches!(
+            &error,
+            Error::ClosedByServer { code: CloseCode::BandwidthExceeded, reason }
+                if reason == "Monthly bandwidth limit exceeded (1 GB)"
+        ));
+        assert!(error.to_string().contains("Monthly bandwidth limit exceeded"));
+    }
+}
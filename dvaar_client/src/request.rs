@@ -0,0 +1,1276 @@
+//! Answering one request from the target
+//!
+//! A handler gets the request, a channel its body arrives on, and the
+//! tunnel's packet channel, and owns the stream's response from there:
+//! CORS preflights, basic auth and mocks are answered here, everything else
+//! is sent on to the target (with retries, if set) and its response streamed
+//! back through a `StreamWriter`. WebSocket upgrades are handed to
+//! [`websocket`](crate::websocket).
+
+use crate::cors::CorsPolicy;
+use crate::mock::Mocks;
+use crate::proxy::{Event, Exchange, InFlightRequests, UpstreamTls, MAX_CAPTURED_BODY};
+use crate::rewrite::BodyRewriter;
+use crate::stream_writer::{StreamWindows, StreamWriter};
+use crate::upstream_retry::UpstreamRetry;
+use crate::websocket::{self, WebSockets};
+use dvaar_common::{
+    check_header_limits, constant_time_eq, strip_hop_by_hop_headers, ControlPacket, HttpRequestPacket,
+    HttpResponsePacket, StreamErrorCode,
+};
+use http_body_util::BodyExt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Per-tunnel settings and state that every request handler shares
+pub(crate) struct RequestContext {
+    pub http_client: reqwest::Client,
+    pub upstream_retry: UpstreamRetry,
+    pub upstream_addr: String,
+    pub upstream_tls: Option<UpstreamTls>,
+    pub basic_auth: Option<String>,
+    pub host_header: Option<String>,
+    pub mocks: Option<Arc<Mocks>>,
+    pub cors: Option<Arc<CorsPolicy>>,
+    pub rewriter: Option<Arc<BodyRewriter>>,
+    /// Set when the server acknowledges response bytes, so each body is
+    /// paced to the visitor
+    pub windows: Option<StreamWindows>,
+    pub websockets: WebSockets,
+    pub events: Option<mpsc::Sender<Event>>,
+    /// Report bodies and WebSocket frames along with the other events
+    pub capture: bool,
+}
+
+impl RequestContext {
+    /// Plain requests to `upstream_addr`, with none of the tunnel's options
+    pub fn new(upstream_addr: String, http_client: reqwest::Client) -> Self {
+        Self {
+            http_client,
+            upstream_retry: UpstreamRetry::default(),
+            upstream_addr,
+            upstream_tls: None,
+            basic_auth: None,
+            host_header: None,
+            mocks: None,
+            cors: None,
+            rewriter: None,
+            windows: None,
+            websockets: Arc::default(),
+            events: None,
+            capture: false,
+        }
+    }
+
+    /// Report `event`, waiting for room if events back up
+    pub async fn emit(&self, event: Event) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event).await;
+        }
+    }
+
+    /// Report a request that was answered
+    async fn finished(&self, method: &str, uri: &str, status: u16, elapsed: Duration, size_bytes: usize) {
+        self.emit(Event::RequestFinished {
+            method: method.to_string(),
+            uri: uri.to_string(),
+            status,
+            elapsed,
+            size_bytes,
+        })
+        .await;
+    }
+}
+
+/// Reports an open connection to the target, and its closing when dropped
+struct ConnectionGuard {
+    events: Option<mpsc::Sender<Event>>,
+}
+
+impl ConnectionGuard {
+    async fn open(events: Option<mpsc::Sender<Event>>) -> Self {
+        if let Some(ref tx) = events {
+            let _ = tx.send(Event::ConnectionOpened).await;
+        }
+        Self { events }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(tx) = self.events.take() {
+            tokio::spawn(async move {
+                let _ = tx.send(Event::ConnectionClosed).await;
+            });
+        }
+    }
+}
+
+/// The inspector share-link visitors see, and the one tunnel they may see in it
+pub(crate) struct SharedInspector {
+    pub ctx: RequestContext,
+    pub tunnel_id: String,
+}
+
+/// Answer a request that came through a share link from the inspector.
+/// The view is read-only, so only GET and HEAD get through, and only to
+/// this tunnel's own data. Nothing about these requests is captured.
+pub(crate) async fn serve_shared_inspector(
+    inspector: Option<Arc<SharedInspector>>,
+    mut request: HttpRequestPacket,
+    packet_tx: mpsc::Sender<ControlPacket>,
+    in_flight: InFlightRequests,
+) {
+    let stream_id = request.stream_id.clone();
+    match inspector {
+        _ if !matches!(request.method.as_str(), "GET" | "HEAD") => {
+            let headers = vec![("Allow".to_string(), "GET, HEAD".to_string())];
+            StreamWriter::new(stream_id.clone(), packet_tx)
+                .respond(405, headers, b"The shared inspector is read-only")
+                .await;
+        }
+        None => {
+            StreamWriter::new(stream_id.clone(), packet_tx)
+                .respond(404, Vec::new(), b"This tunnel has no inspector")
+                .await;
+        }
+        Some(inspector) => match shared_inspector_path(&request.uri, &inspector.tunnel_id) {
+            Some(path) => {
+                request.uri = path;
+                // GETs carry no body, so the channel is closed from the start
+                let (_, body_rx) = mpsc::channel(1);
+                handle_request(&inspector.ctx, request, body_rx, packet_tx).await;
+            }
+            None => {
+                StreamWriter::new(stream_id.clone(), packet_tx)
+                    .respond(404, Vec::new(), b"Not part of the shared inspector")
+                    .await;
+            }
+        },
+    }
+    in_flight.lock().await.remove(&stream_id);
+}
+
+/// Where a share-link read goes on the inspector: the dashboard page, or
+/// `tunnel_id`'s own requests and metrics whichever tunnel the path names.
+/// Anything else could list other tunnels' traffic, so it has no path.
+fn shared_inspector_path(uri: &str, tunnel_id: &str) -> Option<String> {
+    let path = uri.split_once('?').map_or(uri, |(path, _)| path);
+    if path == "/" {
+        return Some(path.to_string());
+    }
+    let (_, endpoint) = path.strip_prefix("/api/tunnels/")?.split_once('/')?;
+    matches!(endpoint, "requests" | "metrics").then(|| format!("/api/tunnels/{}/{}", tunnel_id, endpoint))
+}
+
+pub(crate) async fn handle_request(
+    ctx: &RequestContext,
+    request: HttpRequestPacket,
+    body_rx: mpsc::Receiver<Vec<u8>>,
+    packet_tx: mpsc::Sender<ControlPacket>,
+) {
+    let start_time = Instant::now();
+    let stream_id = request.stream_id.clone();
+    let method = request.method.clone();
+    let uri = request.uri.clone();
+    let upstream_addr = ctx.upstream_addr.as_str();
+
+    // Check if this is a WebSocket upgrade request
+    if request.is_websocket_upgrade() {
+        websocket::handle_upgrade(ctx, request, packet_tx).await;
+        return;
+    }
+
+    // The only writer for this stream's response from here on
+    let window = ctx.windows.as_ref().map(|windows| windows.open(&stream_id));
+    let mut writer = StreamWriter::new(stream_id.clone(), packet_tx).with_window(window);
+
+    // Track the open connection; the guard closes it however this handler exits,
+    // including when the task is aborted because the downstream went away
+    let _connection = ConnectionGuard::open(ctx.events.clone()).await;
+
+    let request_headers = request.headers.clone();
+
+    // Regular HTTP request
+    let (scheme, authority) = match &ctx.upstream_tls {
+        Some(tls) => ("https", tls.authority(upstream_addr)),
+        None => ("http", upstream_addr.to_string()),
+    };
+    let url = format!("{}://{}{}", scheme, authority, &uri);
+    // The URL names the SNI host; the upstream still sees its address as Host
+    // unless --host-header says otherwise
+    let host_header = ctx.host_header.as_deref().or((authority != upstream_addr).then_some(upstream_addr));
+
+    tracing::debug!("{} {}", method, url);
+
+    // Build request using reqwest for streaming support
+    let http_method = match method.as_str() {
+        "GET" => reqwest::Method::GET,
+        "POST" => reqwest::Method::POST,
+        "PUT" => reqwest::Method::PUT,
+        "DELETE" => reqwest::Method::DELETE,
+        "PATCH" => reqwest::Method::PATCH,
+        "HEAD" => reqwest::Method::HEAD,
+        "OPTIONS" => reqwest::Method::OPTIONS,
+        _ => reqwest::Method::GET,
+    };
+
+    let mut req_builder = ctx.http_client.request(http_method, &url);
+
+    // Add headers, minus the visitor's hop-by-hop ones: the pooled
+    // connection to the upstream is ours to manage
+    let mut upstream_headers = request.headers.clone();
+    strip_hop_by_hop_headers(&mut upstream_headers);
+    for (key, value) in &upstream_headers {
+        let key_lower = key.to_lowercase();
+        // The body is sent whole, so there's no `Expect` for the upstream to answer
+        // With --replace, ask for bodies uncompressed so they can be rewritten
+        if key_lower == "host"
+            || key_lower == "content-length"
+            || key_lower == "expect"
+            || (ctx.rewriter.is_some() && key_lower == "accept-encoding")
+        {
+            continue;
+        }
+        req_builder = req_builder.header(key.as_str(), value.as_str());
+    }
+
+    // Override host header if specified
+    if let Some(host) = host_header {
+        req_builder = req_builder.header("Host", host);
+    }
+
+    // Preflights carry no credentials, so they're answered before the auth check
+    if let Some(policy) = ctx.cors.as_deref().filter(|_| CorsPolicy::is_preflight(&request)) {
+        let (status, headers) = policy.preflight(&request.headers);
+        writer.respond(status, headers, b"").await;
+        ctx.finished(&method, &uri, status, start_time.elapsed(), 0).await;
+        return;
+    }
+
+    // Enforce basic auth before anything reaches the upstream
+    if let Some(expected) = &ctx.basic_auth {
+        if !check_basic_auth(&request.headers, expected) {
+            // Return 401
+            let headers = vec![(
+                "WWW-Authenticate".to_string(),
+                "Basic realm=\"dvaar\"".to_string(),
+            )];
+            writer.respond(401, headers, b"Unauthorized").await;
+            return;
+        }
+    }
+
+    // Mocked paths are answered here, or held before going upstream
+    if let Some(rule) = ctx.mocks.as_deref().and_then(|mocks| mocks.find(&method, &uri)) {
+        tokio::time::sleep(rule.delay).await;
+        if let Some(response) = &rule.response {
+            tracing::debug!("Mocked {} {} with {}", method, uri, response.status);
+            let headers = vec![("Content-Type".to_string(), "text/plain".to_string())];
+            writer.respond(response.status, headers, &response.body).await;
+            ctx.finished(&method, &uri, response.status, start_time.elapsed(), response.body.len())
+                .await;
+            return;
+        }
+    }
+
+    // Past auth, the visitor may go ahead with a body it's holding back
+    if request.expects_continue() && writer.continue_body().await.is_err() {
+        return;
+    }
+
+    // Collect request body chunks for capture (if enabled) and create stream
+    let mut captured_request_body = Vec::new();
+
+    // Collect all body chunks first
+    let mut body_chunks = Vec::new();
+    let mut body_rx = body_rx;
+    while let Some(chunk) = body_rx.recv().await {
+        if ctx.capture && captured_request_body.len() < MAX_CAPTURED_BODY {
+            captured_request_body.extend_from_slice(&chunk);
+        }
+        body_chunks.push(chunk);
+    }
+
+    let request_size_bytes = body_chunks.iter().map(Vec::len).sum();
+
+    // Send request and stream response
+    let send_start = Instant::now();
+    let send_result = ctx.upstream_retry.send(&method, req_builder, &body_chunks).await;
+    let upstream_connect = send_start.elapsed();
+
+    match send_result {
+        Ok(response) => {
+            let headers_at = start_time.elapsed();
+            let status = response.status().as_u16();
+            let mut response_headers: Vec<(String, String)> = response
+                .headers()
+                .iter()
+                .filter_map(|(k, v)| v.to_str().ok().map(|s| (k.to_string(), s.to_string())))
+                .collect();
+            strip_hop_by_hop_headers(&mut response_headers);
+            if let Some(policy) = &ctx.cors {
+                policy.apply(&request_headers, &mut response_headers);
+            }
+
+            // However many headers the upstream sends, only so many are
+            // copied into the packet; the visitor gets a 502 instead
+            if let Err(e) = check_header_limits(&response_headers) {
+                tracing::warn!("Refusing upstream response: {}", e);
+                writer
+                    .fail(StreamErrorCode::Other, format!("Upstream response headers too large: {}", e))
+                    .await;
+                ctx.finished(&method, &uri, 502, start_time.elapsed(), 0).await;
+                return;
+            }
+
+            let has_body = HttpResponsePacket {
+                stream_id: stream_id.clone(),
+                status,
+                headers: Vec::new(),
+            }
+            .has_body(&method);
+            // Bytes after a bodiless response would corrupt the framing downstream
+            let mut body = if has_body {
+                reqwest::Body::from(response)
+            } else {
+                reqwest::Body::from(Vec::new())
+            };
+
+            // Bodies to rewrite are read whole, then sent on at their new length
+            if let Some(rewriter) = ctx.rewriter.as_deref().filter(|r| has_body && r.applies_to(&response_headers)) {
+                match rewriter.rewrite_body(body).await {
+                    Ok((rewritten, length)) => {
+                        body = rewritten;
+                        if let Some(length) = length {
+                            response_headers.retain(|(name, _)| !name.eq_ignore_ascii_case("content-length"));
+                            response_headers.push(("Content-Length".to_string(), length.to_string()));
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!("Error reading response to rewrite: {}", e);
+                        writer.fail(StreamErrorCode::UpstreamDown, e.to_string()).await;
+                        return;
+                    }
+                }
+            }
+
+            // Send response headers
+            if writer.headers(status, response_headers.clone()).await.is_err() {
+                return;
+            }
+
+            // Stream response body and capture it
+            let mut total_bytes = 0usize;
+            let mut captured_response_body = Vec::new();
+            let mut ttfb = None;
+
+            // Read frame by frame rather than as a byte stream so trailers
+            // (gRPC's status among them) make it through
+            while let Some(frame_result) = body.frame().await {
+                let frame = match frame_result {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        tracing::error!("Error streaming response: {}", e);
+                        let code = if e.is_timeout() {
+                            StreamErrorCode::Timeout
+                        } else {
+                            StreamErrorCode::UpstreamDown
+                        };
+                        writer.fail(code, e.to_string()).await;
+                        return;
+                    }
+                };
+                let chunk = match frame.into_data() {
+                    Ok(chunk) => chunk,
+                    Err(frame) => {
+                        if let Ok(trailers) = frame.into_trailers() {
+                            let trailers = trailers
+                                .iter()
+                                .filter_map(|(k, v)| v.to_str().ok().map(|v| (k.to_string(), v.to_string())))
+                                .collect();
+                            if writer.trailers(trailers).await.is_err() {
+                                return;
+                            }
+                        }
+                        continue;
+                    }
+                };
+
+                if ttfb.is_none() {
+                    ttfb = Some(start_time.elapsed());
+                }
+                total_bytes += chunk.len();
+
+                if ctx.capture && captured_response_body.len() < MAX_CAPTURED_BODY {
+                    captured_response_body.extend_from_slice(&chunk);
+                }
+
+                if writer.data(&chunk).await.is_err() {
+                    return;
+                }
+            }
+
+            writer.end().await;
+
+            let elapsed = start_time.elapsed();
+            ctx.finished(&method, &uri, status, elapsed, total_bytes).await;
+
+            if ctx.capture {
+                ctx.emit(Event::RequestCaptured(Exchange {
+                    stream_id,
+                    method,
+                    uri,
+                    request_headers,
+                    request_body: captured_request_body,
+                    status,
+                    response_headers,
+                    response_body: captured_response_body,
+                    duration: elapsed,
+                    // Empty bodies have no first chunk; the headers were the first byte
+                    ttfb: Some(ttfb.unwrap_or(headers_at)),
+                    upstream_connect,
+                    size_bytes: total_bytes,
+                    request_size_bytes,
+                }))
+                .await;
+            }
+        }
+        Err(e) => {
+            let unresolved = unresolved_host(&e);
+            match &unresolved {
+                Some(host) => tracing::error!("Upstream request failed: could not resolve {}", host),
+                None => tracing::error!("Upstream request failed: {}", e),
+            }
+            if let Some(host) = unresolved {
+                ctx.emit(Event::UpstreamUnresolved(host)).await;
+            }
+
+            let error_body = upstream_error_message(&e, upstream_addr).into_bytes();
+            let response_headers = vec![("Content-Type".to_string(), "text/plain".to_string())];
+
+            writer.respond(502, response_headers.clone(), &error_body).await;
+
+            let elapsed = start_time.elapsed();
+            ctx.finished(&method, &uri, 502, elapsed, 0).await;
+
+            if ctx.capture {
+                ctx.emit(Event::RequestCaptured(Exchange {
+                    stream_id,
+                    method,
+                    uri,
+                    request_headers,
+                    request_body: captured_request_body,
+                    status: 502,
+                    response_headers,
+                    response_body: error_body,
+                    duration: elapsed,
+                    ttfb: None,
+                    upstream_connect,
+                    size_bytes: 0,
+                    request_size_bytes,
+                }))
+                .await;
+            }
+        }
+    }
+}
+
+/// Validate an `Authorization: Basic ...` header against the expected `user:pass`.
+/// Missing, malformed or non-Basic headers are all rejected.
+fn check_basic_auth(headers: &[(String, String)], expected: &str) -> bool {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let Some((_, value)) = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("authorization"))
+    else {
+        return false;
+    };
+
+    let Some((scheme, encoded)) = value.trim().split_once(' ') else {
+        return false;
+    };
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return false;
+    }
+
+    match STANDARD.decode(encoded.trim()) {
+        Ok(decoded) => constant_time_eq(&decoded, expected.as_bytes()),
+        Err(_) => false,
+    }
+}
+
+/// Body of the 502 sent back when the upstream request fails
+fn upstream_error_message(error: &reqwest::Error, upstream_addr: &str) -> String {
+    if let Some(host) = unresolved_host(error) {
+        format!("Bad Gateway: could not resolve {}", host)
+    } else if error.is_connect() {
+        if error.is_timeout() {
+            format!("Bad Gateway: timed out connecting to upstream {}", upstream_addr)
+        } else {
+            format!("Bad Gateway: could not connect to upstream {} ({})", upstream_addr, error)
+        }
+    } else if error.is_timeout() {
+        format!("Bad Gateway: upstream {} stopped responding", upstream_addr)
+    } else {
+        format!("Bad Gateway: {}", error)
+    }
+}
+
+/// The upstream host, if the request failed because it didn't resolve. The
+/// connector reports that as a "dns error" somewhere down the source chain.
+fn unresolved_host(error: &reqwest::Error) -> Option<String> {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(cause) = source {
+        if cause.to_string() == "dns error" {
+            return error.url().and_then(|url| url.host_str()).map(str::to_string);
+        }
+        source = cause.source();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coalesce::Coalescer;
+    use crate::mock::MockRule;
+    use crate::rewrite::Replacement;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use dvaar_common::constants;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn auth_header(value: &str) -> Vec<(String, String)> {
+        vec![("Authorization".to_string(), value.to_string())]
+    }
+
+    #[test]
+    fn test_basic_auth_accepts_matching_credentials() {
+        let headers = auth_header(&format!("Basic {}", STANDARD.encode("admin:secret")));
+        assert!(check_basic_auth(&headers, "admin:secret"));
+
+        // Scheme is case-insensitive
+        let headers = auth_header(&format!("basic {}", STANDARD.encode("admin:secret")));
+        assert!(check_basic_auth(&headers, "admin:secret"));
+    }
+
+    #[test]
+    fn test_basic_auth_rejects_bad_credentials() {
+        let wrong = auth_header(&format!("Basic {}", STANDARD.encode("admin:wrong")));
+        assert!(!check_basic_auth(&wrong, "admin:secret"));
+
+        assert!(!check_basic_auth(&[], "admin:secret"));
+        assert!(!check_basic_auth(&auth_header("Basic not-base64!!"), "admin:secret"));
+        assert!(!check_basic_auth(&auth_header("Basic"), "admin:secret"));
+        assert!(!check_basic_auth(&auth_header("Bearer admin:secret"), "admin:secret"));
+    }
+
+    /// Upstream that answers every request with `response`, after `delay`
+    async fn spawn_canned_upstream(response: &'static [u8], delay: Duration) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    let _ = socket.read(&mut buf).await;
+                    tokio::time::sleep(delay).await;
+                    let _ = socket.write_all(response).await;
+                });
+            }
+        });
+        addr
+    }
+
+    fn get(uri: &str) -> HttpRequestPacket {
+        HttpRequestPacket {
+            stream_id: dvaar_common::new_stream_id(),
+            method: "GET".to_string(),
+            uri: uri.to_string(),
+            headers: vec![],
+        }
+    }
+
+    /// Answer one bodiless request through `handle_request` and return every
+    /// packet sent back to the server
+    async fn serve_packets(ctx: &RequestContext, request: HttpRequestPacket) -> Vec<ControlPacket> {
+        let (body_tx, body_rx) = mpsc::channel(1);
+        drop(body_tx);
+        let (packet_tx, mut packet_rx) = mpsc::channel(64);
+        handle_request(ctx, request, body_rx, packet_tx).await;
+
+        let mut packets = Vec::new();
+        while let Some(packet) = packet_rx.recv().await {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    /// Proxy one request to `upstream_addr` and return every packet sent back to the server
+    async fn proxy_packets(upstream_retry: UpstreamRetry, upstream_addr: &str, method: &str) -> Vec<ControlPacket> {
+        let request = HttpRequestPacket {
+            method: method.to_string(),
+            headers: vec![("Connection".to_string(), "keep-alive".to_string())],
+            ..get("/")
+        };
+        let ctx = RequestContext {
+            upstream_retry,
+            ..RequestContext::new(upstream_addr.to_string(), reqwest::Client::new())
+        };
+        serve_packets(&ctx, request).await
+    }
+
+    fn response_status(packets: &[ControlPacket]) -> Option<u16> {
+        packets.iter().find_map(|packet| match packet {
+            ControlPacket::HttpResponse(response) => Some(response.status),
+            _ => None,
+        })
+    }
+
+    /// Proxy one GET to an upstream that sends headers immediately and the
+    /// body after `body_delay`, returning what was captured of it
+    async fn capture_with_body_delay(body_delay: Duration) -> Exchange {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0u8; 1024];
+            while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = socket.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n")
+                .await
+                .unwrap();
+            socket.flush().await.unwrap();
+            tokio::time::sleep(body_delay).await;
+            socket.write_all(b"hello").await.unwrap();
+        });
+
+        let (events_tx, mut events_rx) = mpsc::channel(16);
+        let ctx = RequestContext {
+            events: Some(events_tx),
+            capture: true,
+            ..RequestContext::new(addr.to_string(), reqwest::Client::new())
+        };
+        serve_packets(&ctx, get("/")).await;
+        drop(ctx);
+
+        while let Some(event) = events_rx.recv().await {
+            if let Event::RequestCaptured(exchange) = event {
+                return exchange;
+            }
+        }
+        panic!("request was not captured");
+    }
+
+    #[tokio::test]
+    async fn test_timing_breakdown() {
+        let fast = capture_with_body_delay(Duration::ZERO).await;
+        let slow = capture_with_body_delay(Duration::from_millis(200)).await;
+
+        for captured in [&fast, &slow] {
+            let ttfb = captured.ttfb.unwrap();
+            assert!(ttfb <= captured.duration);
+            assert!(captured.upstream_connect <= ttfb);
+        }
+
+        assert!(slow.ttfb.unwrap() >= Duration::from_millis(200));
+        assert!(slow.ttfb.unwrap() > fast.ttfb.unwrap());
+        assert_eq!((slow.response_body.as_slice(), slow.size_bytes), (&b"hello"[..], 5));
+    }
+
+    #[tokio::test]
+    async fn test_identical_gets_reach_upstream_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        const REQUESTS: usize = 8;
+
+        // Slow upstream counting the requests it answers
+        let hits = Arc::new(AtomicUsize::new(0));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let upstream_hits = hits.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let hits = upstream_hits.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 1024];
+                    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                        let n = socket.read(&mut chunk).await.unwrap();
+                        if n == 0 {
+                            return;
+                        }
+                        buf.extend_from_slice(&chunk[..n]);
+                    }
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    let _ = socket
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\ncomputed")
+                        .await;
+                });
+            }
+        });
+
+        let coalescer = Coalescer::new();
+        let (packet_tx, mut packet_rx) = mpsc::channel(64);
+        let mut stream_ids = Vec::new();
+        let mut handlers = Vec::new();
+        for _ in 0..REQUESTS {
+            let request = HttpRequestPacket {
+                headers: vec![("Accept".to_string(), "text/plain".to_string())],
+                ..get("/report")
+            };
+            stream_ids.push(request.stream_id.clone());
+            let Some(packet_tx) = coalescer.route(&request, &packet_tx) else {
+                continue;
+            };
+            let (body_tx, body_rx) = mpsc::channel(1);
+            drop(body_tx);
+            let addr = addr.to_string();
+            handlers.push(tokio::spawn(async move {
+                let ctx = RequestContext::new(addr.clone(), reqwest::Client::new());
+                handle_request(&ctx, request, body_rx, packet_tx).await;
+            }));
+        }
+        assert_eq!(handlers.len(), 1);
+
+        // A request that differs in a vary header goes upstream on its own
+        let other = HttpRequestPacket {
+            headers: vec![("Accept".to_string(), "application/json".to_string())],
+            ..get("/report")
+        };
+        assert!(coalescer.route(&other, &packet_tx).is_some());
+
+        let mut responses: HashMap<String, (u16, Vec<u8>)> = HashMap::new();
+        let mut ended = 0;
+        while ended < REQUESTS {
+            match tokio::time::timeout(Duration::from_secs(5), packet_rx.recv()).await.unwrap().unwrap() {
+                ControlPacket::HttpResponse(response) => {
+                    responses.insert(response.stream_id, (response.status, Vec::new()));
+                }
+                ControlPacket::Data { stream_id, data } => {
+                    responses.get_mut(&stream_id).unwrap().1.extend(data);
+                }
+                ControlPacket::End { .. } => ended += 1,
+                other => panic!("unexpected packet {:?}", other),
+            }
+        }
+
+        for stream_id in &stream_ids {
+            assert_eq!(responses[stream_id], (200, b"computed".to_vec()));
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unresolvable_upstream_host() {
+        // .invalid never resolves, with or without a network
+        let addr = "no-such-upstream.invalid:3000";
+        let (events_tx, mut events_rx) = mpsc::channel(16);
+        let ctx = RequestContext {
+            events: Some(events_tx),
+            ..RequestContext::new(addr.to_string(), reqwest::Client::new())
+        };
+
+        let mut status = None;
+        let mut body = Vec::new();
+        for packet in serve_packets(&ctx, get("/")).await {
+            match packet {
+                ControlPacket::HttpResponse(response) => status = Some(response.status),
+                ControlPacket::Data { data, .. } => body.extend(data),
+                _ => {}
+            }
+        }
+        assert_eq!(status, Some(502));
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "Bad Gateway: could not resolve no-such-upstream.invalid"
+        );
+
+        drop(ctx);
+        let mut unresolved = None;
+        while let Some(event) = events_rx.recv().await {
+            if let Event::UpstreamUnresolved(host) = event {
+                unresolved = Some(host);
+            }
+        }
+        assert_eq!(unresolved.as_deref(), Some("no-such-upstream.invalid"));
+    }
+
+    #[tokio::test]
+    async fn test_bodiless_responses_send_headers_then_end() {
+        // The upstream wrongly sends a body on both
+        let head = spawn_canned_upstream(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+            Duration::ZERO,
+        )
+        .await
+        .to_string();
+        let not_modified = spawn_canned_upstream(
+            b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+            Duration::ZERO,
+        )
+        .await
+        .to_string();
+
+        for (addr, method, status) in [(head, "HEAD", 200), (not_modified, "GET", 304)] {
+            let packets = proxy_packets(UpstreamRetry::default(), &addr, method).await;
+            assert_eq!(packets.len(), 2, "{} {}: {:?}", method, status, packets);
+            match &packets[0] {
+                ControlPacket::HttpResponse(response) => assert_eq!(response.status, status),
+                other => panic!("expected HttpResponse, got {:?}", other),
+            }
+            assert!(matches!(packets[1], ControlPacket::End { .. }));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mocked_paths_skip_the_upstream() {
+        let addr = spawn_canned_upstream(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\nConnection: close\r\n\r\nupstream",
+            Duration::ZERO,
+        )
+        .await
+        .to_string();
+        let ctx = RequestContext {
+            mocks: Some(Arc::new(Mocks::new(vec![
+                MockRule::parse_mock("GET /health=200@100:OK").unwrap(),
+                MockRule::parse_status("/api/*=503").unwrap(),
+            ]))),
+            ..RequestContext::new(addr, reqwest::Client::new())
+        };
+
+        for (uri, status, body) in [
+            ("/health", 200, &b"OK"[..]),
+            ("/api/orders?page=2", 503, b"Service Unavailable"),
+            ("/", 200, b"upstream"),
+        ] {
+            let started = Instant::now();
+            let packets = serve_packets(&ctx, get(uri)).await;
+            if uri == "/health" {
+                assert!(started.elapsed() >= Duration::from_millis(100));
+            }
+
+            assert_eq!(response_status(&packets), Some(status), "{}", uri);
+            assert!(
+                packets
+                    .iter()
+                    .any(|p| matches!(p, ControlPacket::Data { data, .. } if data == body)),
+                "{}: {:?}",
+                uri,
+                packets
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight_and_response_headers() {
+        let addr = spawn_canned_upstream(
+            b"HTTP/1.1 200 OK\r\nAccess-Control-Allow-Origin: https://other.example\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+            Duration::ZERO,
+        )
+        .await
+        .to_string();
+        let ctx = RequestContext {
+            cors: Some(Arc::new(CorsPolicy::default())),
+            ..RequestContext::new(addr, reqwest::Client::new())
+        };
+        let origin = ("Origin".to_string(), "https://app.example.com".to_string());
+
+        let mut responses = Vec::new();
+        for (method, extra) in [
+            ("OPTIONS", Some(("Access-Control-Request-Method".to_string(), "POST".to_string()))),
+            ("GET", None),
+        ] {
+            let request = HttpRequestPacket {
+                method: method.to_string(),
+                headers: [origin.clone()].into_iter().chain(extra).collect(),
+                ..get("/api/orders")
+            };
+            match serve_packets(&ctx, request).await.into_iter().next() {
+                Some(ControlPacket::HttpResponse(response)) => responses.push(response),
+                other => panic!("expected HttpResponse, got {:?}", other),
+            }
+        }
+        let header = |response: &HttpResponsePacket, name: &str| {
+            let values: Vec<_> = response
+                .headers
+                .iter()
+                .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.clone())
+                .collect();
+            values.join(", ")
+        };
+
+        // The upstream would have answered 200; the preflight never reached it
+        let preflight = &responses[0];
+        assert_eq!(preflight.status, 204);
+        assert_eq!(header(preflight, "access-control-allow-origin"), "*");
+        assert_eq!(header(preflight, "access-control-allow-methods"), "POST");
+
+        let get = &responses[1];
+        assert_eq!(get.status, 200);
+        assert_eq!(header(get, "access-control-allow-origin"), "*");
+        assert_eq!(header(get, "access-control-expose-headers"), "*");
+    }
+
+    #[tokio::test]
+    async fn test_replace_rewrites_text_bodies_only() {
+        let rewriter = Arc::new(BodyRewriter::new(vec![
+            Replacement::parse("http://localhost:3000=https://app.dvaar.app").unwrap(),
+        ]));
+        let html = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 41\r\nConnection: close\r\n\r\n\
+                     <a href=\"http://localhost:3000/\">home</a>";
+        let png = b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 21\r\nConnection: close\r\n\r\n\
+                    http://localhost:3000";
+
+        let mut results = Vec::new();
+        for canned in [&html[..], &png[..]] {
+            let addr = spawn_canned_upstream(canned, Duration::ZERO).await.to_string();
+            let ctx = RequestContext {
+                rewriter: Some(rewriter.clone()),
+                ..RequestContext::new(addr, reqwest::Client::new())
+            };
+
+            let mut length = None;
+            let mut body = Vec::new();
+            for packet in serve_packets(&ctx, get("/")).await {
+                match packet {
+                    ControlPacket::HttpResponse(response) => {
+                        length = response
+                            .headers
+                            .iter()
+                            .find(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+                            .map(|(_, value)| value.clone());
+                    }
+                    ControlPacket::Data { data, .. } => body.extend(data),
+                    _ => {}
+                }
+            }
+            results.push((length, String::from_utf8(body).unwrap()));
+        }
+
+        let rewritten = "<a href=\"https://app.dvaar.app/\">home</a>";
+        assert_eq!(results[0], (Some(rewritten.len().to_string()), rewritten.to_string()));
+        assert_eq!(results[1], (Some("21".to_string()), "http://localhost:3000".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_response_headers_fail_the_stream() {
+        // Besides the filler, hyper hands over `content-length: 0`
+        let fixed = "x-big".len() + "content-length".len() + "0".len();
+        let canned = |filler: usize| -> &'static [u8] {
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nX-Big: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                "a".repeat(filler)
+            );
+            Box::leak(response.into_bytes().into_boxed_slice())
+        };
+
+        let at_limit = canned(constants::MAX_HEADER_BYTES - fixed);
+        let addr = spawn_canned_upstream(at_limit, Duration::ZERO).await.to_string();
+        let packets = proxy_packets(UpstreamRetry::default(), &addr, "GET").await;
+        assert!(matches!(&packets[0], ControlPacket::HttpResponse(r) if r.status == 200), "{:?}", packets);
+
+        let over_limit = canned(constants::MAX_HEADER_BYTES - fixed + 1);
+        let addr = spawn_canned_upstream(over_limit, Duration::ZERO).await.to_string();
+        let packets = proxy_packets(UpstreamRetry::default(), &addr, "GET").await;
+        assert_eq!(packets.len(), 1);
+        match &packets[0] {
+            ControlPacket::StreamError { code, error, .. } => {
+                assert_eq!(*code, StreamErrorCode::Other);
+                assert!(error.contains("headers"), "{}", error);
+            }
+            other => panic!("expected StreamError, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_trailers_are_forwarded() {
+        let addr = spawn_canned_upstream(
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/grpc-web\r\nTransfer-Encoding: chunked\r\n\
+              Trailer: grpc-status\r\nConnection: close\r\n\r\n5\r\nhello\r\n0\r\ngrpc-status: 0\r\n\r\n",
+            Duration::ZERO,
+        )
+        .await
+        .to_string();
+
+        let packets = proxy_packets(UpstreamRetry::default(), &addr, "POST").await;
+        assert_eq!(packets.len(), 4, "{:?}", packets);
+        assert!(matches!(&packets[1], ControlPacket::Data { data, .. } if data == b"hello"));
+        match &packets[2] {
+            ControlPacket::Trailers { headers, .. } => {
+                assert_eq!(headers, &vec![("grpc-status".to_string(), "0".to_string())]);
+            }
+            other => panic!("expected Trailers, got {:?}", other),
+        }
+        assert!(matches!(packets[3], ControlPacket::End { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_grpc_unary_call_with_trailer_status() {
+        use hyper::body::Frame;
+        use hyper::service::service_fn;
+        use hyper_util::rt::{TokioExecutor, TokioIo};
+
+        // A length-prefixed gRPC message; the zero bytes must survive the trip
+        const MESSAGE: &[u8] = &[0, 0, 0, 0, 3, 0x08, 0x00, 0x2a];
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let service = service_fn(|_req: hyper::Request<hyper::body::Incoming>| async move {
+                let mut trailers = http::HeaderMap::new();
+                trailers.insert("grpc-status", "0".parse().unwrap());
+                let frames = futures_util::stream::iter([
+                    Ok::<_, std::convert::Infallible>(Frame::data(bytes::Bytes::from_static(MESSAGE))),
+                    Ok(Frame::trailers(trailers)),
+                ]);
+                let response = hyper::Response::builder()
+                    .header("content-type", "application/grpc")
+                    .body(http_body_util::StreamBody::new(frames))
+                    .unwrap();
+                Ok::<_, std::convert::Infallible>(response)
+            });
+            let _ = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(socket), service)
+                .await;
+        });
+
+        let h2 = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+        let ctx = RequestContext::new(addr, h2);
+        let request = HttpRequestPacket {
+            method: "POST".to_string(),
+            headers: vec![("Content-Type".to_string(), "application/grpc".to_string())],
+            ..get("/demo.Echo/Say")
+        };
+        let packets = serve_packets(&ctx, request).await;
+        assert_eq!(response_status(&packets), Some(200));
+        let body: Vec<u8> = packets
+            .iter()
+            .filter_map(|packet| match packet {
+                ControlPacket::Data { data, .. } => Some(data.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(body, MESSAGE);
+        assert!(packets.iter().any(|packet| matches!(
+            packet,
+            ControlPacket::Trailers { headers, .. } if headers == &vec![("grpc-status".to_string(), "0".to_string())]
+        )));
+        assert!(matches!(packets.last(), Some(ControlPacket::End { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_hop_by_hop_headers_stay_on_their_hop() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Keep-alive upstream that counts connections, records request heads
+        // and names a header of its own in `Connection`
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let heads = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (accepted, seen) = (connections.clone(), heads.clone());
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let seen = seen.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 1024];
+                    loop {
+                        while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                            match socket.read(&mut chunk).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                            }
+                        }
+                        seen.lock().unwrap().push(String::from_utf8_lossy(&buf).to_ascii_lowercase());
+                        buf.clear();
+                        let response = b"HTTP/1.1 200 OK\r\nConnection: X-Upstream-Hint\r\n\
+                            X-Upstream-Hint: internal\r\nContent-Length: 2\r\n\r\nok";
+                        if socket.write_all(response).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        let ctx = RequestContext::new(addr, reqwest::Client::new());
+        for _ in 0..2 {
+            let request = HttpRequestPacket {
+                headers: vec![
+                    ("Connection".to_string(), "close, X-Debug-Token".to_string()),
+                    ("X-Debug-Token".to_string(), "abc".to_string()),
+                    ("Keep-Alive".to_string(), "timeout=5".to_string()),
+                    ("Accept".to_string(), "text/plain".to_string()),
+                ],
+                ..get("/")
+            };
+            let packets = serve_packets(&ctx, request).await;
+            let response = packets
+                .iter()
+                .find_map(|packet| match packet {
+                    ControlPacket::HttpResponse(response) => Some(response),
+                    _ => None,
+                })
+                .unwrap();
+            assert_eq!(response.status, 200);
+            assert!(
+                !response.headers.iter().any(|(name, _)| {
+                    name.eq_ignore_ascii_case("connection") || name.eq_ignore_ascii_case("x-upstream-hint")
+                }),
+                "{:?}",
+                response.headers
+            );
+        }
+
+        // The visitor's `Connection: close` didn't cost the pooled connection
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        let heads = heads.lock().unwrap();
+        assert_eq!(heads.len(), 2);
+        for head in heads.iter() {
+            assert!(head.contains("accept: text/plain"), "{}", head);
+            for stripped in ["connection:", "x-debug-token", "keep-alive"] {
+                assert!(!head.contains(stripped), "{} in {}", stripped, head);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_share_link_sees_only_its_own_tunnel() {
+        // An inspector holding tunnels "a" and "b", answering with the path it was asked for
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let n = socket.read(&mut buf).await.unwrap_or(0);
+                let head = String::from_utf8_lossy(&buf[..n]).to_string();
+                let path = head.split(' ').nth(1).unwrap_or("").to_string();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    path.len(),
+                    path
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        let inspector = Arc::new(SharedInspector {
+            ctx: RequestContext::new(addr, reqwest::Client::new()),
+            tunnel_id: "a".to_string(),
+        });
+
+        let mut seen = Vec::new();
+        for uri in [
+            "/",
+            "/api/tunnels/a/requests",
+            "/api/tunnels/b/requests",
+            "/api/tunnels/b/metrics?window=1m",
+            "/api/tunnels",
+            "/api/tunnels/summary",
+            "/api/tunnels/b/ws",
+            "/api/requests",
+            "/api/requests/r1",
+            "/api/metrics",
+            "/api/ws",
+            "/api/ws/s1",
+            "/ws",
+        ] {
+            let (packet_tx, mut packet_rx) = mpsc::channel(16);
+            serve_shared_inspector(Some(inspector.clone()), get(uri), packet_tx, Arc::default()).await;
+            let mut status = None;
+            let mut body = Vec::new();
+            while let Some(packet) = packet_rx.recv().await {
+                match packet {
+                    ControlPacket::HttpResponse(response) => status = Some(response.status),
+                    ControlPacket::Data { data, .. } => body.extend(data),
+                    _ => {}
+                }
+            }
+            let body = String::from_utf8(body).unwrap();
+            seen.push((uri, status.unwrap(), if status == Some(200) { body } else { String::new() }));
+        }
+
+        // Tunnel b's requests are only ever answered with a's
+        let answered = |uri: &'static str, path: &str| (uri, 200, path.to_string());
+        assert_eq!(
+            &seen[..4],
+            [
+                answered("/", "/"),
+                answered("/api/tunnels/a/requests", "/api/tunnels/a/requests"),
+                answered("/api/tunnels/b/requests", "/api/tunnels/a/requests"),
+                answered("/api/tunnels/b/metrics?window=1m", "/api/tunnels/a/metrics"),
+            ]
+        );
+        for (uri, status, _) in &seen[4..] {
+            assert_eq!(*status, 404, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_upstream_waits_for_restart() {
+        // Reserve a port nothing listens on yet
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let retry = UpstreamRetry::new(5, Duration::from_secs(10));
+        let restarted = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                .await
+                .unwrap();
+        });
+
+        let packets = proxy_packets(retry, &addr.to_string(), "GET").await;
+        assert_eq!(response_status(&packets), Some(200));
+        restarted.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retry_upstream_never_retries_post() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                seen.fetch_add(1, Ordering::SeqCst);
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await;
+                let _ = socket
+                    .write_all(b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 0\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .await;
+            }
+        });
+
+        let retry = UpstreamRetry::new(3, Duration::from_secs(10));
+        let packets = proxy_packets(retry, &addr, "POST").await;
+        assert_eq!(response_status(&packets), Some(503));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // The same answer to a GET is retried until the attempts run out
+        let packets = proxy_packets(retry, &addr, "GET").await;
+        assert_eq!(response_status(&packets), Some(503));
+        assert_eq!(requests.load(Ordering::SeqCst), 1 + 4);
+    }
+}
@@ -0,0 +1,160 @@
+//! Search and replace in response bodies
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+use http_body_util::BodyExt;
+
+/// Larger bodies stream through unchanged rather than being held in memory
+pub const MAX_REWRITE_BYTES: usize = 16 * 1024 * 1024;
+
+/// Content types whose bodies are rewritten, matched on the media type alone
+const REWRITTEN_TYPES: &[&str] = &[
+    "text/html",
+    "text/css",
+    "text/javascript",
+    "application/javascript",
+    "application/x-javascript",
+    "application/json",
+];
+
+/// One `--replace` rule. Apps built to run on `http://localhost:3000` put
+/// that origin in their HTML and scripts, which breaks them behind the public
+/// URL; `http://localhost:3000=https://myapp.dvaar.app` swaps every occurrence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replacement {
+    from: Vec<u8>,
+    to: Vec<u8>,
+}
+
+impl Replacement {
+    /// `FROM=TO`, split at the first `=`
+    pub fn parse(rule: &str) -> Result<Self, String> {
+        let (from, to) = rule.split_once('=').ok_or("expected FROM=TO")?;
+        if from.is_empty() {
+            return Err("nothing to replace before '='".to_string());
+        }
+        Ok(Self {
+            from: from.as_bytes().to_vec(),
+            to: to.as_bytes().to_vec(),
+        })
+    }
+}
+
+/// Every `--replace` rule, applied in order
+///
+/// Only HTML, JavaScript, CSS and JSON responses are rewritten. Those are
+/// read whole, so a match can't be split across two chunks, and sent on with
+/// a `Content-Length` for the new size; others stream through as they are.
+/// Compressed bodies can't be searched, so while rules are set the upstream
+/// is asked for uncompressed responses; any it compresses anyway are left
+/// alone.
+#[derive(Debug, Clone, Default)]
+pub struct BodyRewriter {
+    rules: Vec<Replacement>,
+}
+
+impl BodyRewriter {
+    pub fn new(rules: Vec<Replacement>) -> Self {
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether a response with these headers has its body rewritten: a text
+    /// type listed above, not compressed, and not declared too large
+    pub fn applies_to(&self, headers: &[(String, String)]) -> bool {
+        let media_type = header(headers, "content-type")
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        let text = REWRITTEN_TYPES.contains(&media_type.as_str()) || media_type.ends_with("+json");
+        let encoded = header(headers, "content-encoding").is_some_and(|value| !value.eq_ignore_ascii_case("identity"));
+        let too_large = header(headers, "content-length")
+            .and_then(|value| value.trim().parse::<usize>().ok())
+            .is_some_and(|length| length > MAX_REWRITE_BYTES);
+        text && !encoded && !too_large
+    }
+
+    /// Read a response body and rewrite it, returning the body to send and
+    /// its new length. One that turns out larger than `MAX_REWRITE_BYTES` is
+    /// passed on unchanged, with no length.
+    pub async fn rewrite_body(&self, mut body: reqwest::Body) -> Result<(reqwest::Body, Option<usize>), reqwest::Error> {
+        let mut read = Vec::new();
+        while let Some(frame) = body.frame().await {
+            // Trailers on a text body are rare enough to drop
+            let Ok(chunk) = frame?.into_data() else {
+                continue;
+            };
+            read.extend_from_slice(&chunk);
+            if read.len() > MAX_REWRITE_BYTES {
+                let read = futures_util::stream::iter([Ok(Bytes::from(read))]);
+                return Ok((reqwest::Body::wrap_stream(read.chain(body.into_data_stream())), None));
+            }
+        }
+        let rewritten = self.rewrite(&read);
+        let length = rewritten.len();
+        Ok((reqwest::Body::from(rewritten), Some(length)))
+    }
+
+    /// `body` with every rule applied
+    pub fn rewrite(&self, body: &[u8]) -> Vec<u8> {
+        self.rules
+            .iter()
+            .fold(body.to_vec(), |body, rule| replace_all(&body, &rule.from, &rule.to))
+    }
+}
+
+fn replace_all(haystack: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(haystack.len());
+    let mut rest = haystack;
+    while let Some(at) = rest.windows(from.len()).position(|window| window == from) {
+        out.extend_from_slice(&rest[..at]);
+        out.extend_from_slice(to);
+        rest = &rest[at + from.len()..];
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(content_type: &str) -> Vec<(String, String)> {
+        vec![("Content-Type".to_string(), content_type.to_string())]
+    }
+
+    #[test]
+    fn test_rules_and_content_types() {
+        let rewriter = BodyRewriter::new(vec![
+            Replacement::parse("http://localhost:3000=https://app.dvaar.app").unwrap(),
+            Replacement::parse("localhost=app.dvaar.app").unwrap(),
+        ]);
+        assert_eq!(
+            rewriter.rewrite(b"<a href=\"http://localhost:3000/x\">localhost:3000</a>"),
+            b"<a href=\"https://app.dvaar.app/x\">app.dvaar.app:3000</a>"
+        );
+
+        assert!(rewriter.applies_to(&headers("text/html; charset=utf-8")));
+        assert!(rewriter.applies_to(&headers("application/manifest+json")));
+        assert!(!rewriter.applies_to(&headers("image/png")));
+        assert!(!rewriter.applies_to(&[]));
+
+        let mut gzipped = headers("text/html");
+        gzipped.push(("Content-Encoding".to_string(), "gzip".to_string()));
+        assert!(!rewriter.applies_to(&gzipped));
+
+        assert!(Replacement::parse("no-equals").is_err());
+        assert!(Replacement::parse("=to").is_err());
+        assert_eq!(Replacement::parse("a=b=c").unwrap().to, b"b=c");
+    }
+}
@@ -0,0 +1,283 @@
+//! Ordered output for one stream
+
+use dvaar_common::{constants, ControlPacket, HttpResponsePacket, StreamErrorCode};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Semaphore};
+
+/// Largest `Data` payload sent in one packet
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The tunnel's packet channel has closed
+#[derive(Debug)]
+pub struct TunnelClosed;
+
+/// Response bytes each open stream may still send, topped up by the
+/// server's `WindowUpdate`s
+#[derive(Debug, Clone, Default)]
+pub struct StreamWindows(Arc<Mutex<HashMap<String, Arc<Semaphore>>>>);
+
+impl StreamWindows {
+    /// Start a stream with a full window; it is forgotten once the returned
+    /// `Window` is dropped
+    pub fn open(&self, stream_id: &str) -> Window {
+        let credit = Arc::new(Semaphore::new(constants::STREAM_WINDOW_BYTES));
+        self.0.lock().unwrap().insert(stream_id.to_string(), credit.clone());
+        Window {
+            windows: self.clone(),
+            stream_id: stream_id.to_string(),
+            credit,
+        }
+    }
+
+    /// Let a stream send `bytes` more. Credit never grows past a full
+    /// window, whatever the server says.
+    pub fn grant(&self, stream_id: &str, bytes: u32) {
+        if let Some(credit) = self.0.lock().unwrap().get(stream_id) {
+            let room = constants::STREAM_WINDOW_BYTES.saturating_sub(credit.available_permits());
+            credit.add_permits((bytes as usize).min(room));
+        }
+    }
+}
+
+/// One stream's share of [`StreamWindows`]
+///
+/// Servers that do flow control acknowledge response bytes with
+/// `WindowUpdate` as their visitor reads them. A writer given a `Window`
+/// waits for those once `STREAM_WINDOW_BYTES` are outstanding, so a slow
+/// visitor holds back the upstream instead of filling buffers on the way.
+#[derive(Debug)]
+pub struct Window {
+    windows: StreamWindows,
+    stream_id: String,
+    credit: Arc<Semaphore>,
+}
+
+impl Window {
+    /// Wait until `bytes` may be sent, and use them up
+    async fn take(&self, bytes: usize) {
+        // The semaphore is never closed
+        if let Ok(permits) = self.credit.acquire_many(bytes as u32).await {
+            permits.forget();
+        }
+    }
+}
+
+impl Drop for Window {
+    fn drop(&mut self) {
+        let mut windows = self.windows.0.lock().unwrap();
+        // A stream ID reused since then has a window of its own
+        if windows.get(&self.stream_id).is_some_and(|credit| Arc::ptr_eq(credit, &self.credit)) {
+            windows.remove(&self.stream_id);
+        }
+    }
+}
+
+/// The one writer of a response's packets
+///
+/// Packets carry no sequence numbers. Every packet goes through one mpsc
+/// channel to the task that writes the WebSocket, and the server reads them
+/// back in a single loop, so a stream's packets arrive in the order they were
+/// queued as long as only one task queues them. This isn't `Clone`, and
+/// [`end`](Self::end) and [`fail`](Self::fail) consume it, so nothing can be
+/// queued for a stream after its `End`.
+pub struct StreamWriter {
+    stream_id: String,
+    packet_tx: mpsc::Sender<ControlPacket>,
+    window: Option<Window>,
+}
+
+impl StreamWriter {
+    pub fn new(stream_id: String, packet_tx: mpsc::Sender<ControlPacket>) -> Self {
+        Self {
+            stream_id,
+            packet_tx,
+            window: None,
+        }
+    }
+
+    /// Hold the body to `window`, for servers that send `WindowUpdate`
+    pub fn with_window(mut self, window: Option<Window>) -> Self {
+        self.window = window;
+        self
+    }
+
+    async fn send(&self, packet: ControlPacket) -> Result<(), TunnelClosed> {
+        self.packet_tx.send(packet).await.map_err(|_| TunnelClosed)
+    }
+
+    /// Let the visitor send the body of an `Expect: 100-continue` request;
+    /// only meaningful before `headers`
+    pub async fn continue_body(&mut self) -> Result<(), TunnelClosed> {
+        self.send(ControlPacket::Continue {
+            stream_id: self.stream_id.clone(),
+        })
+        .await
+    }
+
+    pub async fn headers(&mut self, status: u16, headers: Vec<(String, String)>) -> Result<(), TunnelClosed> {
+        self.send(ControlPacket::HttpResponse(HttpResponsePacket {
+            stream_id: self.stream_id.clone(),
+            status,
+            headers,
+        }))
+        .await
+    }
+
+    /// Queue body bytes, split into packets of at most `STREAM_CHUNK_SIZE`.
+    /// With a window, each packet first waits for the server to have room.
+    pub async fn data(&mut self, data: &[u8]) -> Result<(), TunnelClosed> {
+        for chunk in data.chunks(STREAM_CHUNK_SIZE) {
+            if let Some(window) = &self.window {
+                window.take(chunk.len()).await;
+            }
+            self.send(ControlPacket::Data {
+                stream_id: self.stream_id.clone(),
+                data: chunk.to_vec(),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Queue trailing headers; only valid after the last `data`
+    pub async fn trailers(&mut self, headers: Vec<(String, String)>) -> Result<(), TunnelClosed> {
+        self.send(ControlPacket::Trailers {
+            stream_id: self.stream_id.clone(),
+            headers,
+        })
+        .await
+    }
+
+    pub async fn end(self) {
+        let _ = self
+            .send(ControlPacket::End {
+                stream_id: self.stream_id.clone(),
+            })
+            .await;
+    }
+
+    /// Abort the response part way through
+    pub async fn fail(self, code: StreamErrorCode, error: String) {
+        let _ = self
+            .send(ControlPacket::StreamError {
+                stream_id: self.stream_id.clone(),
+                error,
+                code,
+            })
+            .await;
+    }
+
+    /// Send a complete response in one go
+    pub async fn respond(mut self, status: u16, headers: Vec<(String, String)>, body: &[u8]) {
+        if self.headers(status, headers).await.is_err() || self.data(body).await.is_err() {
+            return;
+        }
+        self.end().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Distinct, position-dependent bytes so any reordering shows up
+    fn body(stream: usize, len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 + stream * 7) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_interleaved_streams_reassemble_in_order() {
+        const STREAMS: usize = 8;
+        // Pieces of varying size, some over STREAM_CHUNK_SIZE so they get split
+        let piece_sizes = [1, 700, STREAM_CHUNK_SIZE + 5, 13, 3 * STREAM_CHUNK_SIZE, 64];
+
+        let (packet_tx, mut packet_rx) = mpsc::channel(4);
+        for stream in 0..STREAMS {
+            let mut writer = StreamWriter::new(format!("stream-{}", stream), packet_tx.clone());
+            tokio::spawn(async move {
+                let total: usize = piece_sizes.iter().sum();
+                let full = body(stream, total);
+                writer.headers(200, Vec::new()).await.unwrap();
+                let mut offset = 0;
+                for size in piece_sizes {
+                    writer.data(&full[offset..offset + size]).await.unwrap();
+                    offset += size;
+                    tokio::task::yield_now().await;
+                }
+                writer.end().await;
+            });
+        }
+        drop(packet_tx);
+
+        let mut bodies: HashMap<String, Vec<u8>> = HashMap::new();
+        let mut ended = Vec::new();
+        let mut switches = 0;
+        let mut last_stream = String::new();
+        while let Some(packet) = packet_rx.recv().await {
+            let stream_id = match packet {
+                ControlPacket::HttpResponse(response) => {
+                    assert!(!bodies.contains_key(&response.stream_id), "headers after data");
+                    bodies.insert(response.stream_id.clone(), Vec::new());
+                    response.stream_id
+                }
+                ControlPacket::Data { stream_id, data } => {
+                    assert!(!ended.contains(&stream_id), "data after end");
+                    bodies.get_mut(&stream_id).expect("data before headers").extend(data);
+                    stream_id
+                }
+                ControlPacket::End { stream_id } => {
+                    ended.push(stream_id.clone());
+                    stream_id
+                }
+                other => panic!("unexpected packet {:?}", other),
+            };
+            if stream_id != last_stream {
+                switches += 1;
+                last_stream = stream_id;
+            }
+        }
+
+        assert_eq!(ended.len(), STREAMS);
+        assert!(switches > STREAMS * 2, "streams weren't interleaved");
+        let total: usize = piece_sizes.iter().sum();
+        for stream in 0..STREAMS {
+            assert_eq!(bodies[&format!("stream-{}", stream)], body(stream, total));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_window_holds_back_the_body_until_granted() {
+        let (packet_tx, mut packet_rx) = mpsc::channel(64);
+        let windows = StreamWindows::default();
+        let mut writer = StreamWriter::new("s1".to_string(), packet_tx).with_window(Some(windows.open("s1")));
+
+        // A window and one more chunk: the last chunk waits for credit
+        let body = vec![7u8; constants::STREAM_WINDOW_BYTES + STREAM_CHUNK_SIZE];
+        let writing = tokio::spawn(async move {
+            writer.data(&body).await.unwrap();
+            writer.end().await;
+        });
+
+        let mut sent = 0;
+        while sent < constants::STREAM_WINDOW_BYTES {
+            match packet_rx.recv().await.unwrap() {
+                ControlPacket::Data { data, .. } => sent += data.len(),
+                other => panic!("unexpected packet {:?}", other),
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(packet_rx.try_recv().is_err(), "sent past the window");
+
+        // Credit for another stream doesn't free this one
+        windows.grant("other", u32::MAX);
+        windows.grant("s1", STREAM_CHUNK_SIZE as u32);
+        assert!(matches!(packet_rx.recv().await, Some(ControlPacket::Data { data, .. }) if data.len() == STREAM_CHUNK_SIZE));
+        assert!(matches!(packet_rx.recv().await, Some(ControlPacket::End { .. })));
+        writing.await.unwrap();
+
+        // The finished stream's window is gone
+        assert!(windows.0.lock().unwrap().is_empty());
+    }
+}
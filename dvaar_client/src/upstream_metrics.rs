@@ -0,0 +1,165 @@
+//! Upstream health reported to the server
+//!
+//! The server only sees that the tunnel is connected; whether the local
+//! server behind it answers is something only the client knows. Every packet
+//! going back to the server passes the tracker, which times each request from
+//! dispatch to its response headers and counts the ones that fail, and the
+//! totals go out as a `Metrics` packet every `METRICS_INTERVAL_SECONDS`.
+
+use dvaar_common::{ControlPacket, UpstreamMetrics};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[derive(Default)]
+pub struct MetricsTracker {
+    window: Mutex<Window>,
+}
+
+#[derive(Default)]
+struct Window {
+    pending: HashMap<String, Pending>,
+    requests: u64,
+    errors: u64,
+    latency_total_ms: u64,
+}
+
+struct Pending {
+    started_at: Instant,
+    latency_ms: Option<u64>,
+    server_error: bool,
+}
+
+impl Window {
+    fn finish(&mut self, stream_id: &str, failed: bool) {
+        let Some(pending) = self.pending.remove(stream_id) else {
+            return;
+        };
+        let latency_ms = pending
+            .latency_ms
+            .unwrap_or_else(|| pending.started_at.elapsed().as_millis() as u64);
+        self.requests += 1;
+        self.latency_total_ms += latency_ms;
+        if failed || pending.server_error {
+            self.errors += 1;
+        }
+    }
+}
+
+impl MetricsTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A request was handed to the upstream
+    pub fn request_started(&self, stream_id: &str) {
+        self.window.lock().unwrap().pending.insert(
+            stream_id.to_string(),
+            Pending {
+                started_at: Instant::now(),
+                latency_ms: None,
+                server_error: false,
+            },
+        );
+    }
+
+    /// The server cancelled a request; it counts neither way
+    pub fn request_cancelled(&self, stream_id: &str) {
+        self.window.lock().unwrap().pending.remove(stream_id);
+    }
+
+    /// Note a packet on its way to the server
+    pub fn observe(&self, packet: &ControlPacket) {
+        let mut window = self.window.lock().unwrap();
+        match packet {
+            ControlPacket::HttpResponse(response) => {
+                // An upgraded WebSocket has no End; it's done once it's up
+                if response.status == 101 {
+                    window.finish(&response.stream_id, false);
+                } else if let Some(pending) = window.pending.get_mut(&response.stream_id) {
+                    pending.latency_ms = Some(pending.started_at.elapsed().as_millis() as u64);
+                    pending.server_error = response.status >= 500;
+                }
+            }
+            ControlPacket::End { stream_id } => window.finish(stream_id, false),
+            ControlPacket::StreamError { stream_id, .. } => window.finish(stream_id, true),
+            _ => {}
+        }
+    }
+
+    /// Totals since the previous snapshot, starting a new period
+    pub fn snapshot(&self) -> UpstreamMetrics {
+        let mut window = self.window.lock().unwrap();
+        let metrics = UpstreamMetrics {
+            requests: window.requests,
+            upstream_errors: window.errors,
+            avg_latency_ms: window.latency_total_ms.checked_div(window.requests).unwrap_or(0),
+            active_streams: window.pending.len() as u32,
+        };
+        window.requests = 0;
+        window.errors = 0;
+        window.latency_total_ms = 0;
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dvaar_common::{HttpResponsePacket, StreamErrorCode};
+    use std::time::Duration;
+
+    fn response(stream_id: &str, status: u16) -> ControlPacket {
+        ControlPacket::HttpResponse(HttpResponsePacket {
+            stream_id: stream_id.to_string(),
+            status,
+            headers: vec![],
+        })
+    }
+
+    fn end(stream_id: &str) -> ControlPacket {
+        ControlPacket::End {
+            stream_id: stream_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_snapshot_counts_errors_and_latency() {
+        let tracker = MetricsTracker::new();
+        for id in ["ok", "app-error", "unreachable", "reset", "slow", "cancelled"] {
+            tracker.request_started(id);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+
+        tracker.observe(&response("ok", 200));
+        tracker.observe(&end("ok"));
+        tracker.observe(&response("app-error", 500));
+        tracker.observe(&end("app-error"));
+        tracker.observe(&response("unreachable", 502));
+        tracker.observe(&end("unreachable"));
+        tracker.observe(&response("reset", 200));
+        tracker.observe(&ControlPacket::StreamError {
+            stream_id: "reset".to_string(),
+            error: "connection reset".to_string(),
+            code: StreamErrorCode::UpstreamDown,
+        });
+        tracker.request_cancelled("cancelled");
+        // Packets for streams it never saw start are ignored
+        tracker.observe(&end("unknown"));
+
+        let metrics = tracker.snapshot();
+        assert_eq!(metrics.requests, 4);
+        assert_eq!(metrics.upstream_errors, 3);
+        assert_eq!(metrics.active_streams, 1);
+        assert!(metrics.avg_latency_ms >= 20, "{:?}", metrics);
+        assert!(metrics.is_failing());
+
+        // A new period starts empty, still counting what's in flight
+        tracker.observe(&response("slow", 200));
+        let metrics = tracker.snapshot();
+        assert_eq!((metrics.requests, metrics.upstream_errors, metrics.avg_latency_ms), (0, 0, 0));
+        assert_eq!(metrics.active_streams, 1);
+        tracker.observe(&end("slow"));
+        assert_eq!(tracker.snapshot().requests, 1);
+    }
+}
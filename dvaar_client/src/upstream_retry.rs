@@ -0,0 +1,83 @@
+//! Retrying safe requests while the upstream restarts
+//!
+//! A dev server that is restarting refuses connections or answers 502/503 for
+//! a moment. With `--retry-upstream N`, GET, HEAD and OPTIONS requests are
+//! sent again up to N times in that window instead of the visitor seeing the
+//! error. Waits double from `FIRST_DELAY`, or follow the upstream's
+//! `Retry-After`, and all of them together stay within the response timeout.
+
+use bytes::Bytes;
+use std::time::{Duration, Instant};
+
+const FIRST_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UpstreamRetry {
+    /// Extra attempts after the first
+    pub attempts: u32,
+    /// Total time retries may spend waiting
+    pub budget: Duration,
+}
+
+impl UpstreamRetry {
+    pub fn new(attempts: u32, budget: Duration) -> Self {
+        Self { attempts, budget }
+    }
+
+    /// Send the request built by `request` with `body`, retrying if allowed.
+    /// The last attempt's result is returned whatever it was.
+    pub async fn send(
+        &self,
+        method: &str,
+        request: reqwest::RequestBuilder,
+        body: &[Vec<u8>],
+    ) -> reqwest::Result<reqwest::Response> {
+        let retryable = self.attempts > 0 && matches!(method, "GET" | "HEAD" | "OPTIONS");
+        let started = Instant::now();
+        let mut attempt = 0;
+
+        loop {
+            // Bodiless builders always clone; if one somehow doesn't, send it once
+            let this_attempt = match request.try_clone() {
+                Some(builder) if retryable && attempt < self.attempts => builder,
+                _ => return request.body(stream_body(body)).send().await,
+            };
+            let result = this_attempt.body(stream_body(body)).send().await;
+
+            let delay = match &result {
+                Ok(response) if matches!(response.status().as_u16(), 502 | 503) => {
+                    retry_after(response).unwrap_or_else(|| backoff(attempt))
+                }
+                Err(e) if e.is_connect() => backoff(attempt),
+                _ => return result,
+            };
+            if started.elapsed() + delay > self.budget {
+                return result;
+            }
+
+            attempt += 1;
+            tracing::debug!("Upstream not ready, retrying in {:?} ({}/{})", delay, attempt, self.attempts);
+            drop(result);
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+fn stream_body(chunks: &[Vec<u8>]) -> reqwest::Body {
+    let chunks: Vec<_> = chunks
+        .iter()
+        .map(|chunk| Ok::<Bytes, std::io::Error>(Bytes::from(chunk.clone())))
+        .collect();
+    reqwest::Body::wrap_stream(futures_util::stream::iter(chunks))
+}
+
+fn backoff(attempt: u32) -> Duration {
+    FIRST_DELAY * 2u32.saturating_pow(attempt.min(10))
+}
+
+/// `Retry-After` in seconds; HTTP dates aren't worth honouring for a dev server
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response.headers().get(reqwest::header::RETRY_AFTER)?;
+    let seconds: u64 = value.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds))
+}
@@ -0,0 +1,483 @@
+//! WebSocket passthrough
+//!
+//! An upgrade request is replayed against the target on a connection of its
+//! own. Once the target answers 101, frames are relayed decoded in both
+//! directions as `WebSocketFrame` packets until either side closes, or the
+//! socket sits idle past the tunnel's idle timeout.
+
+use crate::proxy::Event;
+use crate::request::RequestContext;
+use crate::stream_writer::StreamWriter;
+use dvaar_common::{ControlPacket, HttpRequestPacket};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use tokio_tungstenite::{
+    client_async_tls_with_config,
+    tungstenite::{self, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+type LocalSink = futures_util::stream::SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+
+/// Bridged WebSockets by stream ID
+pub(crate) type WebSockets = Arc<Mutex<HashMap<String, LocalWebSocket>>>;
+
+/// Active WebSocket connection to the target
+pub(crate) struct LocalWebSocket {
+    pub write: Arc<Mutex<LocalSink>>,
+    /// When a frame last crossed this socket, in either direction
+    last_frame: Arc<std::sync::Mutex<Instant>>,
+    /// The task relaying the target's frames to the server
+    reader: tokio::task::AbortHandle,
+}
+
+impl LocalWebSocket {
+    fn touch(&self) {
+        *self.last_frame.lock().unwrap() = Instant::now();
+    }
+}
+
+/// Closes idle bridged WebSockets until dropped
+pub(crate) struct WebSocketReaper(tokio::task::JoinHandle<()>);
+
+impl Drop for WebSocketReaper {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl WebSocketReaper {
+    /// Close bridged WebSockets that have carried no frames for `idle_timeout`.
+    /// Normally the server's `WebSocketClose` ends them; this catches the ones
+    /// whose visitor vanished without it arriving. `None` if disabled.
+    pub fn spawn(
+        websockets: WebSockets,
+        packet_tx: mpsc::Sender<ControlPacket>,
+        idle_timeout: Duration,
+    ) -> Option<Self> {
+        if idle_timeout.is_zero() {
+            return None;
+        }
+        Some(Self(tokio::spawn(async move {
+            let mut interval = tokio::time::interval((idle_timeout / 2).min(Duration::from_secs(60)));
+            loop {
+                interval.tick().await;
+                let idle: Vec<_> = {
+                    let mut ws_map = websockets.lock().await;
+                    let stale: Vec<String> = ws_map
+                        .iter()
+                        .filter(|(_, ws)| ws.last_frame.lock().unwrap().elapsed() >= idle_timeout)
+                        .map(|(stream_id, _)| stream_id.clone())
+                        .collect();
+                    stale
+                        .into_iter()
+                        .filter_map(|stream_id| ws_map.remove(&stream_id).map(|ws| (stream_id, ws.write)))
+                        .collect()
+                };
+
+                for (stream_id, write) in idle {
+                    tracing::debug!("Closing WebSocket {} after {:?} without frames", stream_id, idle_timeout);
+                    let close = Message::Close(Some(tungstenite::protocol::CloseFrame {
+                        code: tungstenite::protocol::frame::coding::CloseCode::Away,
+                        reason: "Idle timeout".into(),
+                    }));
+                    let _ = tokio::time::timeout(Duration::from_secs(5), async {
+                        write.lock().await.send(close).await
+                    })
+                    .await;
+                    let _ = packet_tx
+                        .send(ControlPacket::WebSocketClose {
+                            stream_id,
+                            code: Some(1001),
+                            reason: Some("Idle timeout".to_string()),
+                        })
+                        .await;
+                }
+            }
+        })))
+    }
+}
+
+/// Pass a visitor's frame on to the target. If the target's side is gone,
+/// the server is told the socket closed.
+pub(crate) async fn forward_frame(
+    websockets: &WebSockets,
+    packet_tx: &mpsc::Sender<ControlPacket>,
+    stream_id: String,
+    data: Vec<u8>,
+    is_binary: bool,
+) {
+    let ws_sender = {
+        let ws_map = websockets.lock().await;
+        ws_map.get(&stream_id).map(|ws| {
+            ws.touch();
+            ws.write.clone()
+        })
+    };
+    let Some(ws_sender) = ws_sender else {
+        return;
+    };
+
+    let msg = if is_binary {
+        Message::Binary(data.into())
+    } else {
+        Message::Text(String::from_utf8_lossy(&data).to_string().into())
+    };
+    let mut ws_sender = ws_sender.lock().await;
+    if ws_sender.send(msg).await.is_err() {
+        websockets.lock().await.remove(&stream_id);
+        let _ = packet_tx
+            .send(ControlPacket::WebSocketClose {
+                stream_id,
+                code: Some(1006),
+                reason: Some("Local connection closed".to_string()),
+            })
+            .await;
+    }
+}
+
+/// Close the target's side of a socket the visitor closed
+pub(crate) async fn close(websockets: &WebSockets, stream_id: &str) {
+    let ws_sender = {
+        let ws_map = websockets.lock().await;
+        ws_map.get(stream_id).map(|ws| ws.write.clone())
+    };
+    if let Some(ws_sender) = ws_sender {
+        let mut ws_sender = ws_sender.lock().await;
+        let _ = ws_sender.send(Message::Close(None)).await;
+    }
+    websockets.lock().await.remove(stream_id);
+}
+
+/// Drop every bridged socket when the tunnel goes away
+pub(crate) async fn close_all(websockets: &WebSockets) {
+    for (_, ws) in websockets.lock().await.drain() {
+        ws.reader.abort();
+    }
+}
+
+/// Bridge a WebSocket to the upstream. Unlike plain requests these can't
+/// share the pooled client: each holds its own connection for as long as
+/// the socket stays open.
+pub(crate) async fn handle_upgrade(
+    ctx: &RequestContext,
+    request: HttpRequestPacket,
+    packet_tx: mpsc::Sender<ControlPacket>,
+) {
+    let stream_id = request.stream_id.clone();
+    let upstream_addr = ctx.upstream_addr.as_str();
+    let host_header = ctx.host_header.as_deref();
+    // Frames go through packet_tx once the upgrade response has ended
+    let mut writer = StreamWriter::new(stream_id.clone(), packet_tx.clone());
+    let (scheme, authority) = match &ctx.upstream_tls {
+        Some(tls) => ("wss", tls.authority(upstream_addr)),
+        None => ("ws", upstream_addr.to_string()),
+    };
+    let url = format!("{}://{}{}", scheme, authority, request.uri);
+
+    tracing::debug!("WebSocket upgrade: {}", url);
+
+    // Build WebSocket request with original headers
+    let mut ws_request = tungstenite::http::Request::builder()
+        .uri(&url)
+        .method("GET");
+
+    for (key, value) in &request.headers {
+        let key_lower = key.to_lowercase();
+        if key_lower == "host" && host_header.is_some() {
+            continue;
+        }
+        // Messages are relayed decoded, and nothing on this leg can
+        // inflate a compressed frame, so the visitor's extension offer
+        // (permessage-deflate) isn't passed on
+        if key_lower == "sec-websocket-extensions" {
+            continue;
+        }
+        ws_request = ws_request.header(key.as_str(), value.as_str());
+    }
+
+    if let Some(host) = host_header {
+        ws_request = ws_request.header("Host", host);
+    }
+
+    let ws_request = match ws_request.body(()) {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::error!("Failed to build WebSocket request: {}", e);
+            let body = format!("Failed to build WebSocket request: {}", e);
+            writer.respond(502, vec![], body.as_bytes()).await;
+            return;
+        }
+    };
+
+    // Connect to local WebSocket. The TCP connection is ours to make so that
+    // it goes to the address even when the URL names the SNI host.
+    let connected = match TcpStream::connect(upstream_addr).await {
+        Ok(stream) => {
+            let connector = ctx.upstream_tls.as_ref().map(|tls| tls.connector.clone());
+            client_async_tls_with_config(ws_request, stream, None, connector).await
+        }
+        Err(e) => Err(tungstenite::Error::Io(e)),
+    };
+    let (ws_stream, response) = match connected {
+        Ok(connected) => connected,
+        Err(e) => {
+            tracing::error!("Failed to connect to local WebSocket: {}", e);
+            let headers = vec![("Content-Type".to_string(), "text/plain".to_string())];
+            let body = format!("WebSocket connection failed: {}", e);
+            writer.respond(502, headers, body.as_bytes()).await;
+            return;
+        }
+    };
+
+    let status = response.status().as_u16();
+    let headers: Vec<(String, String)> = response
+        .headers()
+        .iter()
+        .filter(|(k, _)| *k != "sec-websocket-extensions")
+        .filter_map(|(k, v)| v.to_str().ok().map(|s| (k.to_string(), s.to_string())))
+        .collect();
+
+    // Send upgrade response to server, with no body
+    if writer.headers(status, headers).await.is_err() {
+        return;
+    }
+    writer.end().await;
+
+    if status != 101 {
+        return;
+    }
+    ctx.emit(Event::WebSocketOpened {
+        stream_id: stream_id.clone(),
+        uri: request.uri.clone(),
+    })
+    .await;
+
+    // Successfully upgraded, start forwarding frames
+    let (write, read) = ws_stream.split();
+    let write = Arc::new(Mutex::new(write));
+    let last_frame = Arc::new(std::sync::Mutex::new(Instant::now()));
+
+    // Register under the lock so the reader can't finish (and deregister)
+    // before it is registered
+    let mut ws_map = ctx.websockets.lock().await;
+    let reader = tokio::spawn(relay_from_target(
+        read,
+        write.clone(),
+        last_frame.clone(),
+        stream_id.clone(),
+        packet_tx,
+        ctx.websockets.clone(),
+    ));
+    ws_map.insert(
+        stream_id,
+        LocalWebSocket {
+            write,
+            last_frame,
+            reader: reader.abort_handle(),
+        },
+    );
+}
+
+/// Read from the target's socket and forward each frame to the server
+async fn relay_from_target(
+    mut read: futures_util::stream::SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    write: Arc<Mutex<LocalSink>>,
+    last_frame: Arc<std::sync::Mutex<Instant>>,
+    stream_id: String,
+    packet_tx: mpsc::Sender<ControlPacket>,
+    websockets: WebSockets,
+) {
+    while let Some(msg_result) = read.next().await {
+        match msg_result {
+            Ok(msg) => {
+                let packet = match msg {
+                    Message::Binary(data) => ControlPacket::WebSocketFrame {
+                        stream_id: stream_id.clone(),
+                        data: data.to_vec(),
+                        is_binary: true,
+                    },
+                    Message::Text(text) => ControlPacket::WebSocketFrame {
+                        stream_id: stream_id.clone(),
+                        data: text.as_bytes().to_vec(),
+                        is_binary: false,
+                    },
+                    Message::Ping(data) => {
+                        let mut ws_write = write.lock().await;
+                        let _ = ws_write.send(Message::Pong(data)).await;
+                        continue;
+                    }
+                    Message::Pong(_) => continue,
+                    Message::Close(frame) => {
+                        let (code, reason) = frame
+                            .map(|f| (Some(f.code.into()), Some(f.reason.to_string())))
+                            .unwrap_or((None, None));
+                        let _ = packet_tx
+                            .send(ControlPacket::WebSocketClose {
+                                stream_id: stream_id.clone(),
+                                code,
+                                reason,
+                            })
+                            .await;
+                        break;
+                    }
+                    Message::Frame(_) => continue,
+                };
+                *last_frame.lock().unwrap() = Instant::now();
+                if packet_tx.send(packet).await.is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                tracing::debug!("Local WebSocket error: {}", e);
+                let _ = packet_tx
+                    .send(ControlPacket::WebSocketClose {
+                        stream_id: stream_id.clone(),
+                        code: Some(1006),
+                        reason: Some(e.to_string()),
+                    })
+                    .await;
+                break;
+            }
+        }
+    }
+    websockets.lock().await.remove(&stream_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An upgrade request for `/socket` on `addr`, plus `extra` headers
+    fn upgrade_request(addr: std::net::SocketAddr, extra: &[(&str, &str)]) -> HttpRequestPacket {
+        let host = addr.to_string();
+        HttpRequestPacket {
+            stream_id: "ws-1".to_string(),
+            method: "GET".to_string(),
+            uri: "/socket".to_string(),
+            headers: [
+                ("Host", host.as_str()),
+                ("Connection", "Upgrade"),
+                ("Upgrade", "websocket"),
+                ("Sec-WebSocket-Version", "13"),
+                ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ]
+            .iter()
+            .chain(extra)
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_websocket_is_reaped() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The local app just waits; report the close frame it's sent
+        let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Message::Close(frame) = msg {
+                    let _ = closed_tx.send(frame.map(|f| u16::from(f.code)));
+                    break;
+                }
+            }
+        });
+
+        let (packet_tx, mut packet_rx) = mpsc::channel(8);
+        let ctx = RequestContext::new(addr.to_string(), reqwest::Client::new());
+        let websockets = ctx.websockets.clone();
+        handle_upgrade(&ctx, upgrade_request(addr, &[]), packet_tx.clone()).await;
+        assert!(websockets.lock().await.contains_key("ws-1"));
+
+        // The server's WebSocketClose never comes; the reaper closes both ends
+        let _reaper = WebSocketReaper::spawn(websockets.clone(), packet_tx, Duration::from_millis(100));
+        let close = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(ControlPacket::WebSocketClose { stream_id, code, .. }) = packet_rx.recv().await {
+                    return (stream_id, code);
+                }
+            }
+        })
+        .await
+        .expect("idle WebSocket was not reaped");
+        assert_eq!(close, ("ws-1".to_string(), Some(1001)));
+        assert!(websockets.lock().await.is_empty());
+
+        let local_close = tokio::time::timeout(Duration::from_secs(5), closed_rx).await.unwrap().unwrap();
+        assert_eq!(local_close, Some(1001));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_extensions_are_not_negotiated() {
+        use tungstenite::handshake::server::{Request as WsRequest, Response as WsResponse};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // A local app that takes up whatever extension it's offered
+        let (offered_tx, offered_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            // The error type is tungstenite's to choose
+            #[allow(clippy::result_large_err)]
+            let callback = |request: &WsRequest, mut response: WsResponse| {
+                let offered = request.headers().get("sec-websocket-extensions").cloned();
+                if let Some(offer) = &offered {
+                    response.headers_mut().insert("sec-websocket-extensions", offer.clone());
+                }
+                let _ = offered_tx.send(offered);
+                Ok(response)
+            };
+            let mut ws = tokio_tungstenite::accept_hdr_async(stream, callback).await.unwrap();
+            while let Some(Ok(msg)) = ws.next().await {
+                if msg.is_close() {
+                    break;
+                }
+                let _ = ws.send(msg).await;
+            }
+        });
+
+        let (packet_tx, mut packet_rx) = mpsc::channel(8);
+        let request = upgrade_request(
+            addr,
+            &[("Sec-WebSocket-Extensions", "permessage-deflate; client_max_window_bits")],
+        );
+        let ctx = RequestContext::new(addr.to_string(), reqwest::Client::new());
+        let websockets = ctx.websockets.clone();
+        handle_upgrade(&ctx, request, packet_tx).await;
+
+        assert_eq!(offered_rx.await.unwrap(), None);
+        match packet_rx.recv().await {
+            Some(ControlPacket::HttpResponse(response)) => {
+                assert_eq!(response.status, 101);
+                assert!(!response
+                    .headers
+                    .iter()
+                    .any(|(k, _)| k.eq_ignore_ascii_case("sec-websocket-extensions")));
+            }
+            other => panic!("expected HttpResponse, got {:?}", other),
+        }
+
+        // Frames go through as sent, uncompressed both ways
+        let write = websockets.lock().await["ws-1"].write.clone();
+        write.lock().await.send(Message::Text("hello hello hello".into())).await.unwrap();
+        let echoed = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(ControlPacket::WebSocketFrame { data, is_binary, .. }) = packet_rx.recv().await {
+                    return (data, is_binary);
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(echoed, (b"hello hello hello".to_vec(), false));
+    }
+}
@@ -0,0 +1,87 @@
+//! Periodic background tasks
+//!
+//! The server refreshes its node entry and each tunnel's route, and the CLI
+//! keeps its tunnels registered with the inspector, all on a timer. They share
+//! these helpers so the loop and its cadence live in one place.
+
+use crate::constants;
+use std::future::Future;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// Run `tick` every `period`, the first time one period from now, until the
+/// returned handle is aborted. A tick that overruns pushes the next one back
+/// rather than bunching them up.
+pub fn spawn_interval_task<F, Fut>(period: Duration, mut tick: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            tick().await;
+        }
+    })
+}
+
+/// Run `beat` every `HEARTBEAT_INTERVAL_SECONDS`, as `spawn_interval_task`
+pub fn spawn_heartbeat<F, Fut>(beat: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    spawn_interval_task(Duration::from_secs(constants::HEARTBEAT_INTERVAL_SECONDS), beat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_interval_task_cadence_and_abort() {
+        let ticks = Arc::new(AtomicU32::new(0));
+        let counter = ticks.clone();
+        let task = spawn_interval_task(Duration::from_secs(30), move || {
+            let counter = counter.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        // Nothing at the start, then one tick per period
+        tokio::time::sleep(Duration::from_secs(29)).await;
+        assert_eq!(ticks.load(Ordering::SeqCst), 0);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(ticks.load(Ordering::SeqCst), 1);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(ticks.load(Ordering::SeqCst), 3);
+
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+        tokio::time::sleep(Duration::from_secs(300)).await;
+        assert_eq!(ticks.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat_uses_the_shared_interval() {
+        let ticks = Arc::new(AtomicU32::new(0));
+        let counter = ticks.clone();
+        let task = spawn_heartbeat(move || {
+            let counter = counter.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        let period = Duration::from_secs(constants::HEARTBEAT_INTERVAL_SECONDS);
+        tokio::time::sleep(period * 2 + Duration::from_millis(1)).await;
+        assert_eq!(ticks.load(Ordering::SeqCst), 2);
+        task.abort();
+    }
+}
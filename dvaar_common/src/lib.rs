@@ -7,6 +7,8 @@ use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use uuid::Uuid;
 
+pub mod heartbeat;
+
 /// Protocol errors
 #[derive(Debug, Error)]
 pub enum ProtocolError {
@@ -16,8 +18,106 @@ pub enum ProtocolError {
     #[error("Failed to deserialize message: {0}")]
     Deserialize(#[from] rmp_serde::decode::Error),
 
-    #[error("Invalid message format")]
-    InvalidFormat,
+    #[error("Failed to encode or decode JSON message: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Frame of {size} bytes exceeds the {max} byte limit")]
+    FrameTooLarge { size: usize, max: usize },
+}
+
+/// Why a stream failed, so the server can answer with a fitting status
+/// instead of a blanket 502. Sent as a number; codes this side doesn't know
+/// read as `Other`, which isn't sent at all so the packet keeps the shape
+/// servers before codes decode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "u16", into = "u16")]
+pub enum StreamErrorCode {
+    #[default]
+    Other,
+    /// The upstream couldn't be reached
+    UpstreamDown,
+    /// The upstream took too long
+    Timeout,
+    /// The request or response body was over a size limit
+    BodyTooLarge,
+    /// The request was abandoned before it finished
+    Cancelled,
+}
+
+impl StreamErrorCode {
+    fn is_other(&self) -> bool {
+        *self == Self::Other
+    }
+}
+
+impl From<u16> for StreamErrorCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1 => Self::UpstreamDown,
+            2 => Self::Timeout,
+            3 => Self::BodyTooLarge,
+            4 => Self::Cancelled,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl From<StreamErrorCode> for u16 {
+    fn from(code: StreamErrorCode) -> Self {
+        match code {
+            StreamErrorCode::Other => 0,
+            StreamErrorCode::UpstreamDown => 1,
+            StreamErrorCode::Timeout => 2,
+            StreamErrorCode::BodyTooLarge => 3,
+            StreamErrorCode::Cancelled => 4,
+        }
+    }
+}
+
+/// Why the server closed a tunnel, so the client can tell the user and decide
+/// whether to reconnect. Sent as a number; codes this side doesn't know read
+/// as `Other`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "u16", into = "u16")]
+pub enum CloseCode {
+    #[default]
+    Other,
+    /// The client stopped answering keepalive pings
+    Idle,
+    /// The account ran out of monthly bandwidth mid-session
+    BandwidthExceeded,
+    /// An operator closed the tunnel
+    AdminClosed,
+}
+
+impl CloseCode {
+    /// Whether a client should connect again after being closed with this code.
+    /// Retrying after an operator or quota close would only be closed again.
+    pub fn should_reconnect(self) -> bool {
+        matches!(self, Self::Idle)
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1 => Self::Idle,
+            2 => Self::BandwidthExceeded,
+            3 => Self::AdminClosed,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> Self {
+        match code {
+            CloseCode::Other => 0,
+            CloseCode::Idle => 1,
+            CloseCode::BandwidthExceeded => 2,
+            CloseCode::AdminClosed => 3,
+        }
+    }
 }
 
 /// Control packet - the main message type for tunnel communication
@@ -66,13 +166,70 @@ pub enum ControlPacket {
     StreamError {
         stream_id: String,
         error: String,
+        #[serde(default, skip_serializing_if = "StreamErrorCode::is_other")]
+        code: StreamErrorCode,
     },
 
-    /// Keepalive ping
-    Ping,
+    /// Stream cancelled - the downstream client went away, abort the upstream request
+    StreamCancel {
+        stream_id: String,
+    },
 
-    /// Keepalive pong
-    Pong,
+    /// Keepalive ping, optionally carrying a nonce for the peer to echo
+    /// (see [`Keepalive`]). Sent as the bare `Ping` of older peers when `None`.
+    Ping(Option<u64>),
+
+    /// Keepalive pong, echoing the ping's nonce
+    Pong(Option<u64>),
+
+    /// Sent by the server once the tunnel is fully wired up; requests are only
+    /// routed to the client after this
+    Ready,
+
+    /// Response trailers (e.g. gRPC's `grpc-status`), sent after the last
+    /// Data packet and before End
+    Trailers {
+        stream_id: String,
+        headers: Vec<(String, String)>,
+    },
+
+    /// Client's view of its upstream, sent every `METRICS_INTERVAL_SECONDS`
+    Metrics(UpstreamMetrics),
+
+    /// Client is ready for the body of a request that sent
+    /// `Expect: 100-continue`; the server answers the visitor's `100 Continue`
+    Continue { stream_id: String },
+
+    /// Sent by the server just before it drops the tunnel, saying why
+    Close { code: CloseCode, reason: String },
+
+    /// The server has passed `bytes` more of a response body on to the
+    /// visitor, so the client may send that many more on the stream. Only
+    /// sent to clients that asked for flow control in their hello.
+    WindowUpdate { stream_id: String, bytes: u32 },
+}
+
+/// How the client's upstream has been doing since the previous report
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpstreamMetrics {
+    /// Requests that finished in the period
+    pub requests: u64,
+
+    /// Of those, the ones that failed or got a 5xx
+    pub upstream_errors: u64,
+
+    /// Mean time to response headers over the period's requests
+    pub avg_latency_ms: u64,
+
+    /// Requests in flight when the report was made
+    pub active_streams: u32,
+}
+
+impl UpstreamMetrics {
+    /// Whether at least half of the period's requests failed
+    pub fn is_failing(&self) -> bool {
+        self.requests > 0 && self.upstream_errors * 2 >= self.requests
+    }
 }
 
 /// Initial handshake from client
@@ -89,6 +246,38 @@ pub struct ClientHello {
 
     /// Client version for compatibility checking
     pub client_version: String,
+
+    /// HTML served in place of the tunnel while it is offline
+    #[serde(default)]
+    pub offline_page: Option<String>,
+
+    /// Also route `*.<subdomain>` to this tunnel (reserved subdomains only)
+    #[serde(default)]
+    pub wildcard: bool,
+
+    /// Redirects followed to reach this server. `None` from clients that
+    /// can't follow one, which are never redirected.
+    #[serde(default)]
+    pub redirect_hops: Option<u32>,
+
+    /// Methods the server lets through to the tunnel (e.g. `GET`); any
+    /// other method gets a 405. Empty allows every method.
+    #[serde(default)]
+    pub allowed_methods: Vec<String>,
+
+    /// Path globs the server answers with a 403 instead of forwarding
+    #[serde(default)]
+    pub denied_paths: Vec<String>,
+
+    /// Region the tunnel must be served from (e.g. `eu`). The server
+    /// redirects to a node there, or refuses if there is none.
+    #[serde(default)]
+    pub region: Option<String>,
+
+    /// The client keeps at most `STREAM_WINDOW_BYTES` of each response body
+    /// unacknowledged and wants `WindowUpdate`s as the server drains them
+    #[serde(default)]
+    pub flow_control: bool,
 }
 
 /// Server response to client handshake
@@ -102,6 +291,11 @@ pub struct ServerHello {
 
     /// Server version
     pub server_version: String,
+
+    /// Another node that would serve the client better; the client should
+    /// reconnect there instead of waiting for `Ready`
+    #[serde(default)]
+    pub redirect_to: Option<String>,
 }
 
 /// Type of tunnel
@@ -166,6 +360,13 @@ impl HttpRequestPacket {
         });
         has_upgrade_connection && has_websocket_upgrade
     }
+
+    /// Whether the visitor is waiting for `100 Continue` before sending the body
+    pub fn expects_continue(&self) -> bool {
+        self.headers
+            .iter()
+            .any(|(k, v)| k.eq_ignore_ascii_case("expect") && v.eq_ignore_ascii_case("100-continue"))
+    }
 }
 
 impl HttpResponsePacket {
@@ -173,9 +374,91 @@ impl HttpResponsePacket {
     pub fn is_websocket_upgrade(&self) -> bool {
         self.status == 101
     }
+
+    /// Whether this response may carry a body. Responses to HEAD, and 204 and
+    /// 304 responses, never do, whatever the upstream sends after the headers.
+    pub fn has_body(&self, request_method: &str) -> bool {
+        !request_method.eq_ignore_ascii_case("HEAD") && self.status != 204 && self.status != 304
+    }
+}
+
+/// A header list over `MAX_HEADER_COUNT` or `MAX_HEADER_BYTES`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum HeaderLimitError {
+    #[error("{0} headers exceeds the limit of {max}", max = constants::MAX_HEADER_COUNT)]
+    TooMany(usize),
+
+    #[error("{0} bytes of headers exceeds the limit of {max}", max = constants::MAX_HEADER_BYTES)]
+    TooLarge(usize),
+}
+
+/// Refuse header lists too long or too large to copy into a packet
+pub fn check_header_limits(headers: &[(String, String)]) -> Result<(), HeaderLimitError> {
+    if headers.len() > constants::MAX_HEADER_COUNT {
+        return Err(HeaderLimitError::TooMany(headers.len()));
+    }
+    let bytes: usize = headers.iter().map(|(name, value)| name.len() + value.len()).sum();
+    if bytes > constants::MAX_HEADER_BYTES {
+        return Err(HeaderLimitError::TooLarge(bytes));
+    }
+    Ok(())
+}
+
+/// Headers about one connection rather than the message (RFC 7230 §6.1),
+/// on top of any the `Connection` header names
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Drop hop-by-hop headers before a message goes on to its next hop, so a
+/// visitor's `Connection: close` can't close the pooled connection to the
+/// upstream, nor the upstream's reach the visitor. `TE: trailers` stays:
+/// gRPC needs it end to end.
+pub fn strip_hop_by_hop_headers(headers: &mut Vec<(String, String)>) {
+    let listed: Vec<String> = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect();
+    headers.retain(|(name, value)| {
+        let name = name.to_ascii_lowercase();
+        if listed.contains(&name) {
+            return false;
+        }
+        if name == "te" {
+            return value.trim().eq_ignore_ascii_case("trailers");
+        }
+        !HOP_BY_HOP_HEADERS.contains(&name.as_str())
+    });
 }
 
 impl ControlPacket {
+    /// The packet as a server on `server_version` can decode it: servers
+    /// before stream error codes fail on a `StreamError` that has one
+    pub fn for_server(self, server_version: &str) -> Self {
+        match self {
+            ControlPacket::StreamError { stream_id, error, .. }
+                if is_newer_version(constants::STREAM_ERROR_CODE_PROTOCOL_VERSION, server_version) =>
+            {
+                ControlPacket::StreamError {
+                    stream_id,
+                    error,
+                    code: StreamErrorCode::Other,
+                }
+            }
+            packet => packet,
+        }
+    }
+
     /// Serialize the packet to MessagePack bytes
     pub fn to_bytes(&self) -> Result<Vec<u8>, ProtocolError> {
         Ok(rmp_serde::to_vec(self)?)
@@ -183,8 +466,161 @@ impl ControlPacket {
 
     /// Deserialize from MessagePack bytes
     pub fn from_bytes(data: &[u8]) -> Result<Self, ProtocolError> {
-        Ok(rmp_serde::from_slice(data)?)
+        match rmp_serde::from_slice(data) {
+            Ok(packet) => Ok(packet),
+            Err(e) => rmp_serde::from_slice::<LegacyKeepalive>(data)
+                .map(Self::from)
+                .map_err(|_| e.into()),
+        }
+    }
+
+    /// Deserialize a frame received from a peer, rejecting it without decoding
+    /// if it is larger than `max_frame_bytes`
+    pub fn from_bytes_limited(data: &[u8], max_frame_bytes: usize) -> Result<Self, ProtocolError> {
+        Self::decode_limited(data, max_frame_bytes, WireFormat::MessagePack)
+    }
+
+    /// Serialize the packet in the given wire format
+    pub fn encode(&self, format: WireFormat) -> Result<Vec<u8>, ProtocolError> {
+        match format {
+            WireFormat::MessagePack => self.to_bytes(),
+            WireFormat::Json => Ok(serde_json::to_vec(self)?),
+        }
+    }
+
+    /// Deserialize a frame in the given wire format, rejecting it without
+    /// decoding if it is larger than `max_frame_bytes`
+    pub fn decode_limited(data: &[u8], max_frame_bytes: usize, format: WireFormat) -> Result<Self, ProtocolError> {
+        if data.len() > max_frame_bytes {
+            return Err(ProtocolError::FrameTooLarge {
+                size: data.len(),
+                max: max_frame_bytes,
+            });
+        }
+        match format {
+            WireFormat::MessagePack => Self::from_bytes(data),
+            WireFormat::Json => match serde_json::from_slice(data) {
+                Ok(packet) => Ok(packet),
+                Err(e) => serde_json::from_slice::<LegacyKeepalive>(data)
+                    .map(Self::from)
+                    .map_err(|_| e.into()),
+            },
+        }
+    }
+}
+
+/// `Ping` and `Pong` as peers before keepalive nonces send them, which
+/// don't decode as the variants with a payload
+#[derive(Deserialize)]
+enum LegacyKeepalive {
+    Ping,
+    Pong,
+}
+
+impl From<LegacyKeepalive> for ControlPacket {
+    fn from(packet: LegacyKeepalive) -> Self {
+        match packet {
+            LegacyKeepalive::Ping => ControlPacket::Ping(None),
+            LegacyKeepalive::Pong => ControlPacket::Pong(None),
+        }
+    }
+}
+
+/// Keepalive pings that measure the round trip
+///
+/// Each `Ping` carries the milliseconds since the `Keepalive` was created and
+/// the peer echoes them in its `Pong`, so the round trip is read off this
+/// side's clock alone and the two ends' clocks never have to agree. Peers
+/// that predate nonces only understand a bare `Ping`, so nonces are sent
+/// once the peer is known to echo them.
+#[derive(Debug, Clone)]
+pub struct Keepalive {
+    started: std::time::Instant,
+    nonces: bool,
+}
+
+impl Keepalive {
+    pub fn new(nonces: bool) -> Self {
+        Self {
+            started: std::time::Instant::now(),
+            nonces,
+        }
     }
+
+    /// A client's keepalive, sending nonces if the server's version has them
+    pub fn for_server(server_version: &str) -> Self {
+        Self::new(!is_newer_version(constants::KEEPALIVE_NONCE_PROTOCOL_VERSION, server_version))
+    }
+
+    /// Start sending nonces, e.g. once the peer has sent one itself
+    pub fn enable_nonces(&mut self) {
+        self.nonces = true;
+    }
+
+    /// The next ping to send
+    pub fn ping(&self) -> ControlPacket {
+        ControlPacket::Ping(self.nonces.then(|| self.elapsed_ms()))
+    }
+
+    /// Round trip for the nonce echoed in a `Pong`. `None` for a bare pong,
+    /// or a nonce from the future, which this side can't have sent.
+    pub fn round_trip(&self, nonce: Option<u64>) -> Option<std::time::Duration> {
+        self.elapsed_ms()
+            .checked_sub(nonce?)
+            .map(std::time::Duration::from_millis)
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+}
+
+/// Encoding of packets on the wire
+///
+/// MessagePack unless both ends opt into JSON with `DVAAR_WIRE=json`, which is
+/// far easier to read when debugging the protocol or writing a client. The
+/// client asks by encoding its `Init` in JSON; the server answers `InitAck`
+/// in the format the tunnel will use, MessagePack if it doesn't allow JSON.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    #[default]
+    MessagePack,
+    Json,
+}
+
+impl WireFormat {
+    /// The format asked for by `DVAAR_WIRE`; anything but `json` means MessagePack
+    pub fn from_env() -> Self {
+        match std::env::var(constants::WIRE_FORMAT_ENV) {
+            Ok(value) if value.trim().eq_ignore_ascii_case("json") => Self::Json,
+            _ => Self::MessagePack,
+        }
+    }
+
+    /// The format a frame is in. In JSON every packet is an object or a
+    /// string; in MessagePack those start with a type marker, never with the
+    /// bytes for `{` or `"`.
+    pub fn detect(data: &[u8]) -> Self {
+        match data.first() {
+            Some(b'{') | Some(b'"') => Self::Json,
+            _ => Self::MessagePack,
+        }
+    }
+
+    /// The format a tunnel settles on: JSON only if both ends want it
+    pub fn negotiate(client: Self, server: Self) -> Self {
+        if client == Self::Json && server == Self::Json {
+            Self::Json
+        } else {
+            Self::MessagePack
+        }
+    }
+}
+
+/// Compare two secrets without stopping at the first differing byte, so
+/// response times don't tell how much of a guess was right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
 }
 
 /// Generate a new stream ID
@@ -192,9 +628,44 @@ pub fn new_stream_id() -> String {
     Uuid::new_v4().to_string()
 }
 
+/// Compare versions (returns true if latest > current)
+pub fn is_newer_version(latest: &str, current: &str) -> bool {
+    let parse_version = |v: &str| -> Vec<u32> {
+        v.split('.')
+            .filter_map(|s| s.parse().ok())
+            .collect()
+    };
+
+    let latest_parts = parse_version(latest);
+    let current_parts = parse_version(current);
+
+    for i in 0..latest_parts.len().max(current_parts.len()) {
+        let l = latest_parts.get(i).unwrap_or(&0);
+        let c = current_parts.get(i).unwrap_or(&0);
+        if l > c {
+            return true;
+        }
+        if l < c {
+            return false;
+        }
+    }
+
+    false
+}
+
 /// Route information stored in Redis
+///
+/// Nodes of different versions share these during a rolling upgrade, so a
+/// new field must default when absent, and fields a node doesn't know are
+/// ignored. `version` says which fields the writer knew of.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RouteInfo {
+    /// `ROUTE_INFO_VERSION` of the node that wrote the route; 0 for nodes
+    /// from before it was recorded. Writing a route always stamps this
+    /// build's version, since fields it doesn't know of are dropped.
+    #[serde(default, serialize_with = "serialize_own_route_version")]
+    pub version: u8,
+
     /// IP address of the node hosting this tunnel
     pub node_ip: String,
 
@@ -203,14 +674,35 @@ pub struct RouteInfo {
 
     /// User ID for authorization checks
     pub user_id: String,
+
+    /// When the tunnel connected (unix seconds); absent on routes written by older nodes
+    #[serde(default)]
+    pub connected_at: Option<u64>,
+
+    /// Whether otherwise unrouted `*.<subdomain>` hosts fall back to this tunnel
+    #[serde(default)]
+    pub wildcard: bool,
+
+    /// Identifies the connection that wrote the route, so a connection only
+    /// removes a route it still holds; empty on routes written by older nodes
+    #[serde(default)]
+    pub connection_id: String,
 }
 
 impl RouteInfo {
     pub fn new(node_ip: String, internal_port: u16, user_id: String) -> Self {
+        let connected_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .ok();
         Self {
+            version: constants::ROUTE_INFO_VERSION,
             node_ip,
             internal_port,
             user_id,
+            connected_at,
+            wildcard: false,
+            connection_id: Uuid::new_v4().to_string(),
         }
     }
 
@@ -218,25 +710,45 @@ impl RouteInfo {
         serde_json::to_string(self)
     }
 
+    /// Parse a route written by a node of any version: fields it didn't know
+    /// of take their defaults, and fields from newer nodes are skipped
     pub fn from_json(s: &str) -> Result<Self, serde_json::Error> {
         serde_json::from_str(s)
     }
 }
 
+fn serialize_own_route_version<S: serde::Serializer>(_: &u8, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u8(constants::ROUTE_INFO_VERSION)
+}
+
 /// Constants for the protocol
 pub mod constants {
     /// Redis key prefix for routes
     pub const ROUTE_PREFIX: &str = "route:";
 
+    /// Version of the `RouteInfo` this build writes; bump it with each new field
+    pub const ROUTE_INFO_VERSION: u8 = 2;
+
     /// Redis key prefix for usage tracking
     pub const USAGE_PREFIX: &str = "usage:";
 
+    /// Redis key prefixes for the request (visitor to upstream) and response
+    /// (upstream to visitor) shares of the usage
+    pub const USAGE_INGRESS_PREFIX: &str = "usage_in:";
+    pub const USAGE_EGRESS_PREFIX: &str = "usage_out:";
+
     /// Redis key prefix for node registration
     pub const NODE_PREFIX: &str = "node";
 
     /// TTL for node registration (seconds) - nodes must heartbeat within this time
     pub const NODE_TTL_SECONDS: u64 = 60;
 
+    /// How often a node refreshes its registration; one missed beat is survivable
+    pub const NODE_HEARTBEAT_INTERVAL_SECONDS: u64 = 20;
+
+    /// Default number of tunnels a node advertises room for
+    pub const NODE_MAX_TUNNELS: u32 = 1000;
+
     /// TTL for route keys in seconds
     pub const ROUTE_TTL_SECONDS: u64 = 60;
 
@@ -252,22 +764,153 @@ pub mod constants {
     /// Header for subdomain override (local development)
     pub const SUBDOMAIN_HEADER: &str = "X-Subdomain";
 
+    /// Host a request was sent to when it reached the tunnel through a wildcard route
+    pub const WILDCARD_HOST_HEADER: &str = "X-Dvaar-Wildcard-Host";
+
+    /// Visitor IPs, appended to by each proxy on the way
+    pub const FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+
+    /// Scheme the visitor used to reach the tunnel
+    pub const FORWARDED_PROTO_HEADER: &str = "X-Forwarded-Proto";
+
+    /// Host the visitor asked for
+    pub const FORWARDED_HOST_HEADER: &str = "X-Forwarded-Host";
+
+    /// Set by ingress on requests opened through an inspector share link; the
+    /// client answers them from its inspector, never the upstream
+    pub const INSPECT_SHARE_HEADER: &str = "X-Dvaar-Inspect";
+
+    /// Times a request has passed through ingress; clients forward it upstream
+    pub const HOP_HEADER: &str = "X-Dvaar-Hop";
+
+    /// Passes through ingress after which a request is taken to be looping
+    pub const MAX_HOPS: u32 = 5;
+
+    /// Redis key prefix for per-subdomain offline pages
+    pub const OFFLINE_PAGE_PREFIX: &str = "offline_page:";
+
+    /// How long a reserved subdomain's offline page outlives its tunnel
+    /// (seconds). Any other page goes with the route, as the name may pass
+    /// to someone else.
+    pub const OFFLINE_PAGE_TTL_SECONDS: i64 = 7 * 24 * 60 * 60;
+
+    /// Redis key prefix for the page served while a subdomain is in maintenance
+    pub const MAINTENANCE_PREFIX: &str = "maintenance:";
+
+    /// Largest maintenance page accepted (bytes)
+    pub const MAX_MAINTENANCE_PAGE_BYTES: usize = 64 * 1024;
+
+    /// Redis key prefix for each tunnel's latest upstream metrics
+    pub const UPSTREAM_METRICS_PREFIX: &str = "upstream_metrics:";
+
+    /// How often the client reports upstream metrics
+    pub const METRICS_INTERVAL_SECONDS: u64 = 30;
+
+    /// Largest offline page the server will store
+    pub const MAX_OFFLINE_PAGE_BYTES: usize = 64 * 1024;
+
+    /// Most `--allow-method` plus `--deny-path` rules one tunnel may carry
+    pub const MAX_ACCESS_RULES: usize = 64;
+
+    /// Default time the client waits to connect to its upstream (seconds)
+    pub const UPSTREAM_CONNECT_TIMEOUT_SECONDS: u64 = 10;
+
+    /// Default time the client waits on a silent upstream response (seconds)
+    pub const UPSTREAM_RESPONSE_TIMEOUT_SECONDS: u64 = 300;
+
+    /// Default idle connections the client keeps open to its upstream
+    pub const UPSTREAM_POOL_MAX_IDLE_PER_HOST: usize = 10;
+
     /// WebSocket ping interval
     pub const WS_PING_INTERVAL_SECONDS: u64 = 15;
 
-    /// Protocol version - bumped for streaming support
-    pub const PROTOCOL_VERSION: &str = "2.0.0";
+    /// How long a peer may go without answering pings before the tunnel is considered dead
+    pub const WS_PONG_TIMEOUT_SECONDS: u64 = 45;
+
+    /// How long a bridged WebSocket may go without a frame before the client closes it
+    pub const WS_IDLE_TIMEOUT_SECONDS: u64 = 1800;
+
+    /// Default buffer of commands queued for one tunnel
+    pub const TUNNEL_CHANNEL_CAPACITY: usize = 32;
+
+    /// Default buffer of chunks queued for one stream
+    pub const STREAM_CHANNEL_CAPACITY: usize = 32;
+
+    /// Default limit on requests in flight through one tunnel at a time
+    pub const MAX_STREAMS_PER_TUNNEL: usize = 256;
+
+    /// How long a stream whose buffer is full may hold up the tunnel before it is failed (ms)
+    pub const STREAM_SEND_TIMEOUT_MS: u64 = 5_000;
+
+    /// Response body bytes a flow-controlled client may send on a stream
+    /// before the server acknowledges them with `WindowUpdate`
+    pub const STREAM_WINDOW_BYTES: usize = 1024 * 1024;
+
+    /// How long ingress holds an `Expect: 100-continue` body for the client's
+    /// `Continue` before reading it anyway; clients predating the packet never send one
+    pub const CONTINUE_TIMEOUT_MS: u64 = 1_000;
+
+    /// Default largest control frame a peer will decode
+    pub const MAX_FRAME_BYTES: usize = 16 * 1024 * 1024;
+
+    /// Most headers a request or response may carry through the tunnel
+    pub const MAX_HEADER_COUNT: usize = 100;
+
+    /// Most bytes of header names and values a request or response may carry
+    pub const MAX_HEADER_BYTES: usize = 64 * 1024;
+
+    /// How long a client waits before reconnecting after a server close that allows it
+    pub const RECONNECT_DELAY_SECONDS: u64 = 3;
+
+    /// How many times a client follows a redirect to another node at connect
+    pub const MAX_REDIRECT_HOPS: u32 = 1;
+
+    /// Environment variable selecting the wire format (`json` or `msgpack`)
+    pub const WIRE_FORMAT_ENV: &str = "DVAAR_WIRE";
+
+    /// Protocol version - bumped for streaming support, then for `Ready`,
+    /// then for keepalive nonces, then for `WindowUpdate` flow control, then
+    /// for `StreamError` codes
+    pub const PROTOCOL_VERSION: &str = "2.4.0";
+
+    /// First protocol version whose servers send `ControlPacket::Ready`
+    pub const READY_PROTOCOL_VERSION: &str = "2.1.0";
+
+    /// First protocol version whose servers echo the nonce in `Ping`
+    pub const KEEPALIVE_NONCE_PROTOCOL_VERSION: &str = "2.2.0";
+
+    /// First protocol version whose servers send `WindowUpdate` to clients
+    /// that ask for flow control
+    pub const FLOW_CONTROL_PROTOCOL_VERSION: &str = "2.3.0";
+
+    /// First protocol version whose servers decode a `StreamError`'s code
+    pub const STREAM_ERROR_CODE_PROTOCOL_VERSION: &str = "2.4.0";
 
     /// Bandwidth limits (bytes per month)
     pub const BANDWIDTH_FREE: u64 = 1 * 1024 * 1024 * 1024; // 1 GB
     pub const BANDWIDTH_HOBBY: u64 = 50 * 1024 * 1024 * 1024; // 50 GB
     pub const BANDWIDTH_PRO: u64 = 500 * 1024 * 1024 * 1024; // 500 GB
 
+    /// Default per-tunnel throughput (bytes per second)
+    pub const BANDWIDTH_RATE_FREE: u64 = 1024 * 1024; // 1 MiB/s
+    pub const BANDWIDTH_RATE_HOBBY: u64 = 10 * 1024 * 1024; // 10 MiB/s
+    pub const BANDWIDTH_RATE_PRO: u64 = 50 * 1024 * 1024; // 50 MiB/s
+
     /// Concurrent tunnel limits
     pub const CONCURRENT_TUNNELS_FREE: u32 = 5;
     pub const CONCURRENT_TUNNELS_HOBBY: u32 = 10;
     pub const CONCURRENT_TUNNELS_PRO: u32 = 50;
 
+    /// Tunnels a user may open per hour
+    pub const TUNNELS_PER_HOUR_FREE: u32 = 60;
+    pub const TUNNELS_PER_HOUR_HOBBY: u32 = 200;
+    pub const TUNNELS_PER_HOUR_PRO: u32 = 1000;
+
+    /// Requests per minute across all of a user's tunnels
+    pub const REQUESTS_PER_MINUTE_FREE: u32 = 300;
+    pub const REQUESTS_PER_MINUTE_HOBBY: u32 = 1000;
+    pub const REQUESTS_PER_MINUTE_PRO: u32 = 5000;
+
     /// Redis key prefix for user tunnel count
     pub const USER_TUNNELS_PREFIX: &str = "user_tunnels:";
 
@@ -287,6 +930,13 @@ mod tests {
             requested_subdomain: Some("my-app".to_string()),
             tunnel_type: TunnelType::Http,
             client_version: "0.1.0".to_string(),
+            offline_page: None,
+            wildcard: false,
+            redirect_hops: None,
+            allowed_methods: Vec::new(),
+            denied_paths: Vec::new(),
+            region: None,
+            flow_control: true,
         });
 
         let bytes = packet.to_bytes().unwrap();
@@ -296,11 +946,57 @@ mod tests {
             ControlPacket::Init(hello) => {
                 assert_eq!(hello.token, "test-token");
                 assert_eq!(hello.requested_subdomain, Some("my-app".to_string()));
+                assert!(hello.flow_control);
             }
             _ => panic!("Wrong packet type"),
         }
     }
 
+    #[test]
+    fn test_json_wire_roundtrip() {
+        let packets = vec![
+            ControlPacket::HttpRequest(HttpRequestPacket {
+                stream_id: "s-1".to_string(),
+                method: "GET".to_string(),
+                uri: "/".to_string(),
+                headers: vec![("Accept".to_string(), "*/*".to_string())],
+            }),
+            ControlPacket::Data {
+                stream_id: "s-1".to_string(),
+                data: vec![0, 123, 255],
+            },
+            ControlPacket::Trailers {
+                stream_id: "s-1".to_string(),
+                headers: vec![("grpc-status".to_string(), "0".to_string())],
+            },
+            ControlPacket::End {
+                stream_id: "s-1".to_string(),
+            },
+            ControlPacket::Ping(Some(42)),
+            ControlPacket::WindowUpdate {
+                stream_id: "s-1".to_string(),
+                bytes: 65536,
+            },
+        ];
+
+        for packet in packets {
+            let json = packet.encode(WireFormat::Json).unwrap();
+            assert!(serde_json::from_slice::<serde_json::Value>(&json).is_ok());
+            assert_eq!(WireFormat::detect(&json), WireFormat::Json);
+            let decoded = ControlPacket::decode_limited(&json, json.len(), WireFormat::Json).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", packet));
+
+            // The same packet in MessagePack is never mistaken for JSON
+            let msgpack = packet.encode(WireFormat::MessagePack).unwrap();
+            assert_eq!(WireFormat::detect(&msgpack), WireFormat::MessagePack);
+            assert!(ControlPacket::decode_limited(&msgpack, msgpack.len(), WireFormat::Json).is_err());
+        }
+
+        assert_eq!(WireFormat::negotiate(WireFormat::Json, WireFormat::Json), WireFormat::Json);
+        assert_eq!(WireFormat::negotiate(WireFormat::Json, WireFormat::MessagePack), WireFormat::MessagePack);
+        assert_eq!(WireFormat::negotiate(WireFormat::MessagePack, WireFormat::Json), WireFormat::MessagePack);
+    }
+
     #[test]
     fn test_route_info_json() {
         let route = RouteInfo::new("192.168.1.1".to_string(), 6000, "user-123".to_string());
@@ -311,6 +1007,33 @@ mod tests {
         assert_eq!(decoded.node_ip, "192.168.1.1");
         assert_eq!(decoded.internal_port, 6000);
         assert_eq!(decoded.user_id, "user-123");
+        assert_eq!(decoded.connected_at, route.connected_at);
+        assert_eq!(decoded.version, constants::ROUTE_INFO_VERSION);
+
+        // Routes written before connected_at existed still parse
+        let legacy = RouteInfo::from_json(r#"{"node_ip":"10.0.0.1","internal_port":6000,"user_id":"u"}"#).unwrap();
+        assert_eq!(legacy.version, 0);
+        assert_eq!(legacy.connected_at, None);
+        assert!(!legacy.wildcard);
+    }
+
+    #[test]
+    fn test_route_info_from_a_newer_node() {
+        let newer = RouteInfo::from_json(
+            r#"{"version":7,"node_ip":"10.0.0.2","internal_port":6001,"user_id":"u","connected_at":1700000000,
+                "wildcard":true,"region":"eu","weights":[1,2,3]}"#,
+        )
+        .unwrap();
+        assert_eq!(newer.version, 7);
+        assert_eq!(newer.node_ip, "10.0.0.2");
+        assert_eq!(newer.internal_port, 6001);
+        assert_eq!(newer.connected_at, Some(1_700_000_000));
+        assert!(newer.wildcard);
+
+        // Written back, it keeps what this node knows of, under this node's version
+        let rewritten = RouteInfo::from_json(&newer.to_json().unwrap()).unwrap();
+        assert_eq!(rewritten.version, constants::ROUTE_INFO_VERSION);
+        assert!(rewritten.wildcard);
     }
 
     #[test]
@@ -338,6 +1061,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_stream_cancel_roundtrip() {
+        let stream_id = new_stream_id();
+        let packet = ControlPacket::StreamCancel {
+            stream_id: stream_id.clone(),
+        };
+
+        let bytes = packet.to_bytes().unwrap();
+        let decoded = ControlPacket::from_bytes(&bytes).unwrap();
+
+        match decoded {
+            ControlPacket::StreamCancel { stream_id: id } => assert_eq!(id, stream_id),
+            _ => panic!("Wrong packet type"),
+        }
+    }
+
     #[test]
     fn test_websocket_upgrade_detection() {
         let upgrade_request = HttpRequestPacket {
@@ -362,4 +1101,255 @@ mod tests {
         };
         assert!(!normal_request.is_websocket_upgrade());
     }
+
+    #[test]
+    fn test_oversized_frames_are_rejected() {
+        let packet = ControlPacket::Data {
+            stream_id: new_stream_id(),
+            data: vec![7; 4096],
+        };
+        let bytes = packet.to_bytes().unwrap();
+        assert!(ControlPacket::from_bytes_limited(&bytes, bytes.len()).is_ok());
+        assert!(matches!(
+            ControlPacket::from_bytes_limited(&bytes, bytes.len() - 1),
+            Err(ProtocolError::FrameTooLarge { size, max }) if size == bytes.len() && max == bytes.len() - 1
+        ));
+
+        // A small frame whose length prefixes claim ~4 GiB fails on the
+        // missing bytes instead of allocating for them
+        let mut crafted = vec![0x81, 0xa4];
+        crafted.extend_from_slice(b"Data");
+        crafted.extend_from_slice(&[0x92, 0xa1, b's']);
+        for prefix in [0xc6, 0xdd] {
+            // bin32 and array32
+            let mut frame = crafted.clone();
+            frame.push(prefix);
+            frame.extend_from_slice(&u32::MAX.to_be_bytes());
+            frame.extend_from_slice(&[1, 2, 3]);
+            assert!(ControlPacket::from_bytes_limited(&frame, constants::MAX_FRAME_BYTES).is_err());
+        }
+    }
+
+    #[test]
+    fn test_stream_error_codes() {
+        let packet = ControlPacket::StreamError {
+            stream_id: "s".to_string(),
+            error: "timed out".to_string(),
+            code: StreamErrorCode::Timeout,
+        };
+        for format in [WireFormat::MessagePack, WireFormat::Json] {
+            let bytes = packet.encode(format).unwrap();
+            match ControlPacket::decode_limited(&bytes, constants::MAX_FRAME_BYTES, format).unwrap() {
+                ControlPacket::StreamError { error, code, .. } => {
+                    assert_eq!(error, "timed out");
+                    assert_eq!(code, StreamErrorCode::Timeout);
+                }
+                other => panic!("unexpected packet {:?}", other),
+            }
+        }
+
+        // Peers that predate codes, or send one this side doesn't know
+        #[derive(Serialize)]
+        enum Legacy {
+            StreamError { stream_id: String, error: String },
+        }
+        let legacy = rmp_serde::to_vec(&Legacy::StreamError {
+            stream_id: "s".to_string(),
+            error: "boom".to_string(),
+        })
+        .unwrap();
+        assert!(matches!(
+            ControlPacket::from_bytes(&legacy).unwrap(),
+            ControlPacket::StreamError { code: StreamErrorCode::Other, .. }
+        ));
+        assert_eq!(StreamErrorCode::from(999), StreamErrorCode::Other);
+    }
+
+    #[test]
+    fn test_stream_error_for_older_servers() {
+        // A 2.3 server's view of the packet
+        #[derive(Debug, Deserialize, PartialEq)]
+        enum Legacy {
+            StreamError { stream_id: String, error: String },
+        }
+        let packet = ControlPacket::StreamError {
+            stream_id: "s".to_string(),
+            error: "timed out".to_string(),
+            code: StreamErrorCode::Timeout,
+        };
+        let expected = Legacy::StreamError {
+            stream_id: "s".to_string(),
+            error: "timed out".to_string(),
+        };
+
+        // The code is what breaks it
+        assert!(rmp_serde::from_slice::<Legacy>(&packet.to_bytes().unwrap()).is_err());
+
+        let downgraded = packet.clone().for_server("2.3.0");
+        let bytes = downgraded.to_bytes().unwrap();
+        assert_eq!(rmp_serde::from_slice::<Legacy>(&bytes).unwrap(), expected);
+        let json = downgraded.encode(WireFormat::Json).unwrap();
+        assert_eq!(serde_json::from_slice::<Legacy>(&json).unwrap(), expected);
+
+        // Current servers still get the code
+        assert!(matches!(
+            packet.for_server(constants::PROTOCOL_VERSION),
+            ControlPacket::StreamError { code: StreamErrorCode::Timeout, .. }
+        ));
+    }
+
+    #[test]
+    fn test_keepalive_round_trip() {
+        let keepalive = Keepalive::new(true);
+        let ControlPacket::Ping(nonce) = keepalive.ping() else {
+            panic!("not a ping");
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let rtt = keepalive.round_trip(nonce).unwrap();
+        assert!(rtt >= std::time::Duration::from_millis(50), "{:?}", rtt);
+        assert!(rtt < std::time::Duration::from_secs(5), "{:?}", rtt);
+
+        assert_eq!(keepalive.round_trip(None), None);
+        assert_eq!(keepalive.round_trip(Some(u64::MAX)), None);
+
+        // Servers before nonces get bare pings
+        assert!(matches!(Keepalive::for_server("2.1.0").ping(), ControlPacket::Ping(None)));
+        assert!(matches!(Keepalive::for_server(constants::PROTOCOL_VERSION).ping(), ControlPacket::Ping(Some(_))));
+    }
+
+    #[test]
+    fn test_keepalive_with_older_peers() {
+        #[derive(Debug, Serialize, Deserialize, PartialEq)]
+        enum Legacy {
+            Ping,
+            Pong,
+        }
+
+        for format in [WireFormat::MessagePack, WireFormat::Json] {
+            let encode = |legacy: &Legacy| match format {
+                WireFormat::MessagePack => rmp_serde::to_vec(legacy).unwrap(),
+                WireFormat::Json => serde_json::to_vec(legacy).unwrap(),
+            };
+            let decode = |bytes: &[u8]| -> Legacy {
+                match format {
+                    WireFormat::MessagePack => rmp_serde::from_slice(bytes).unwrap(),
+                    WireFormat::Json => serde_json::from_slice(bytes).unwrap(),
+                }
+            };
+
+            // Their bare packets read as carrying no nonce
+            let ping = ControlPacket::decode_limited(&encode(&Legacy::Ping), 64, format).unwrap();
+            assert!(matches!(ping, ControlPacket::Ping(None)));
+            let pong = ControlPacket::decode_limited(&encode(&Legacy::Pong), 64, format).unwrap();
+            assert!(matches!(pong, ControlPacket::Pong(None)));
+
+            // And what we send them without a nonce reads as theirs
+            assert_eq!(decode(&ControlPacket::Ping(None).encode(format).unwrap()), Legacy::Ping);
+            assert_eq!(decode(&ControlPacket::Pong(None).encode(format).unwrap()), Legacy::Pong);
+        }
+    }
+
+    #[test]
+    fn test_close_codes() {
+        let packet = ControlPacket::Close {
+            code: CloseCode::BandwidthExceeded,
+            reason: "Monthly bandwidth limit exceeded".to_string(),
+        };
+        for format in [WireFormat::MessagePack, WireFormat::Json] {
+            let bytes = packet.encode(format).unwrap();
+            match ControlPacket::decode_limited(&bytes, constants::MAX_FRAME_BYTES, format).unwrap() {
+                ControlPacket::Close { code, reason } => {
+                    assert_eq!(code, CloseCode::BandwidthExceeded);
+                    assert_eq!(reason, "Monthly bandwidth limit exceeded");
+                }
+                other => panic!("unexpected packet {:?}", other),
+            }
+        }
+
+        assert!(CloseCode::Idle.should_reconnect());
+        assert!(!CloseCode::BandwidthExceeded.should_reconnect());
+        assert!(!CloseCode::AdminClosed.should_reconnect());
+        assert!(!CloseCode::Other.should_reconnect());
+        assert_eq!(CloseCode::from(999), CloseCode::Other);
+    }
+
+    #[test]
+    fn test_bodiless_responses() {
+        let response = |status| HttpResponsePacket {
+            stream_id: new_stream_id(),
+            status,
+            headers: vec![],
+        };
+        assert!(response(200).has_body("GET"));
+        assert!(!response(200).has_body("HEAD"));
+        assert!(!response(200).has_body("head"));
+        assert!(!response(204).has_body("POST"));
+        assert!(!response(304).has_body("GET"));
+    }
+
+    #[test]
+    fn test_header_limits() {
+        use constants::{MAX_HEADER_BYTES, MAX_HEADER_COUNT};
+
+        let many = |n: usize| -> Vec<(String, String)> {
+            (0..n).map(|i| (format!("x-{}", i), "1".to_string())).collect()
+        };
+        assert_eq!(check_header_limits(&many(MAX_HEADER_COUNT)), Ok(()));
+        assert_eq!(
+            check_header_limits(&many(MAX_HEADER_COUNT + 1)),
+            Err(HeaderLimitError::TooMany(MAX_HEADER_COUNT + 1))
+        );
+
+        // Names count as well as values
+        let large = |n: usize| vec![("x-big".to_string(), "a".repeat(n - "x-big".len()))];
+        assert_eq!(check_header_limits(&large(MAX_HEADER_BYTES)), Ok(()));
+        assert_eq!(
+            check_header_limits(&large(MAX_HEADER_BYTES + 1)),
+            Err(HeaderLimitError::TooLarge(MAX_HEADER_BYTES + 1))
+        );
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop_headers() {
+        let mut headers: Vec<(String, String)> = [
+            ("Connection", "close, X-Debug-Token"),
+            ("Keep-Alive", "timeout=5"),
+            ("x-debug-token", "abc"),
+            ("Transfer-Encoding", "chunked"),
+            ("Upgrade", "h2c"),
+            ("Proxy-Connection", "keep-alive"),
+            ("TE", "trailers"),
+            ("Content-Type", "text/plain"),
+            ("Accept", "*/*"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        strip_hop_by_hop_headers(&mut headers);
+        let names: Vec<&str> = headers.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["TE", "Content-Type", "Accept"]);
+
+        // Only `TE: trailers` gets through
+        let mut te = vec![("te".to_string(), "gzip, trailers".to_string())];
+        strip_hop_by_hop_headers(&mut te);
+        assert!(te.is_empty());
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cre", b"s3cret"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_version_comparison() {
+        assert!(is_newer_version("1.0.1", "1.0.0"));
+        assert!(is_newer_version("1.1.0", "1.0.0"));
+        assert!(is_newer_version("2.0.0", "1.9.9"));
+        assert!(!is_newer_version("1.0.0", "1.0.0"));
+        assert!(!is_newer_version("1.0.0", "1.0.1"));
+        assert!(!is_newer_version("0.9.0", "1.0.0"));
+    }
 }
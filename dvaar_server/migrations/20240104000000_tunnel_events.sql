@@ -0,0 +1,19 @@
+-- Audit log of tunnel sessions, for abuse investigation
+
+-- No foreign key on user_id: the record has to outlive the account
+CREATE TABLE tunnel_events (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL,
+    subdomain TEXT NOT NULL,
+    node_ip TEXT NOT NULL,
+    client_ip TEXT,
+    started_at TIMESTAMPTZ DEFAULT NOW() NOT NULL,
+    ended_at TIMESTAMPTZ
+);
+
+CREATE INDEX idx_tunnel_events_user_id ON tunnel_events(user_id, started_at DESC);
+CREATE INDEX idx_tunnel_events_subdomain ON tunnel_events(subdomain, started_at DESC);
+CREATE INDEX idx_tunnel_events_started_at ON tunnel_events(started_at DESC);
+
+COMMENT ON COLUMN tunnel_events.client_ip IS 'Address the tunnel client connected from';
+COMMENT ON COLUMN tunnel_events.ended_at IS 'NULL while the tunnel is up, or if the node died before recording the close';
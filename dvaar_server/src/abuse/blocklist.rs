@@ -2,6 +2,7 @@
 
 use std::collections::HashSet;
 use once_cell::sync::Lazy;
+use regex::Regex;
 
 /// Blocked subdomain patterns - these cannot be registered
 static BLOCKED_EXACT: Lazy<HashSet<&'static str>> = Lazy::new(|| {
@@ -113,6 +114,8 @@ pub enum BlockReason {
     LooksLikeIP,
     /// All numbers (suspicious)
     AllNumeric,
+    /// Matches an operator-configured wildcard or regex pattern
+    MatchedPattern(String),
 }
 
 impl BlockReason {
@@ -125,6 +128,7 @@ impl BlockReason {
             BlockReason::InvalidCharacters => "Subdomain can only contain lowercase letters, numbers, and hyphens".to_string(),
             BlockReason::LooksLikeIP => "Subdomain cannot look like an IP address".to_string(),
             BlockReason::AllNumeric => "Subdomain cannot be all numbers".to_string(),
+            BlockReason::MatchedPattern(p) => format!("Subdomain matches blocked pattern '{}'", p),
         }
     }
 }
@@ -171,6 +175,87 @@ pub fn check_subdomain(subdomain: &str) -> SubdomainCheck {
     SubdomainCheck::Allowed
 }
 
+/// Error compiling a configured blocklist pattern
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid blocklist pattern '{pattern}': {source}")]
+pub struct PatternError {
+    pattern: String,
+    #[source]
+    source: regex::Error,
+}
+
+/// Subdomain blocklist: the built-in lists plus patterns loaded from config.
+///
+/// Patterns are either wildcards (`paypal*`, `*-bank`, `g??gle`) matched against the
+/// whole subdomain, or regexes prefixed with `re:` (`re:^pay-?pal\d*$`). They are
+/// compiled once and reused for every check.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    patterns: Vec<(String, Regex)>,
+}
+
+impl Blocklist {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self, PatternError> {
+        let patterns = patterns
+            .iter()
+            .map(|p| {
+                let pattern = p.as_ref().trim();
+                let source = match pattern.strip_prefix("re:") {
+                    Some(re) => re.to_string(),
+                    None => wildcard_to_regex(pattern),
+                };
+                Regex::new(&source)
+                    .map(|re| (pattern.to_string(), re))
+                    .map_err(|source| PatternError {
+                        pattern: pattern.to_string(),
+                        source,
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { patterns })
+    }
+
+    /// Number of configured patterns
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Check a subdomain against the built-in rules, then the configured patterns
+    pub fn check(&self, subdomain: &str) -> SubdomainCheck {
+        if let SubdomainCheck::Blocked(reason) = check_subdomain(subdomain) {
+            return SubdomainCheck::Blocked(reason);
+        }
+
+        let subdomain = subdomain.to_lowercase();
+        for (pattern, re) in &self.patterns {
+            if re.is_match(&subdomain) {
+                return SubdomainCheck::Blocked(BlockReason::MatchedPattern(pattern.clone()));
+            }
+        }
+
+        SubdomainCheck::Allowed
+    }
+}
+
+/// Translate a `*`/`?` wildcard into an anchored regex
+fn wildcard_to_regex(pattern: &str) -> String {
+    let mut re = String::from("^");
+    for c in pattern.to_lowercase().chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
 /// Validate subdomain characters
 fn is_valid_subdomain_chars(s: &str) -> bool {
     if s.is_empty() {
@@ -243,4 +328,37 @@ mod tests {
         assert!(matches!(check_subdomain("123456"), SubdomainCheck::Blocked(BlockReason::AllNumeric)));
         assert!(matches!(check_subdomain("192-168-1-1"), SubdomainCheck::Blocked(BlockReason::LooksLikeIP)));
     }
+
+    #[test]
+    fn test_configured_patterns() {
+        let blocklist = Blocklist::new(&["acmecorp", "zorg*", "re:^bank\\d+$"]).unwrap();
+
+        // Exact pattern
+        assert!(matches!(
+            blocklist.check("acmecorp"),
+            SubdomainCheck::Blocked(BlockReason::MatchedPattern(p)) if p == "acmecorp"
+        ));
+        // Wildcard
+        assert!(matches!(
+            blocklist.check("zorg-wallet"),
+            SubdomainCheck::Blocked(BlockReason::MatchedPattern(p)) if p == "zorg*"
+        ));
+        // Regex
+        assert!(matches!(
+            blocklist.check("bank42"),
+            SubdomainCheck::Blocked(BlockReason::MatchedPattern(p)) if p == "re:^bank\\d+$"
+        ));
+        // Built-in rules still apply first
+        assert!(matches!(blocklist.check("paypal"), SubdomainCheck::Blocked(BlockReason::ExactMatch(_))));
+
+        // Benign subdomains pass, and wildcards are anchored
+        assert!(matches!(blocklist.check("my-cool-app"), SubdomainCheck::Allowed));
+        assert!(matches!(blocklist.check("acmecorp-fan"), SubdomainCheck::Allowed));
+        assert!(matches!(blocklist.check("my-zorg"), SubdomainCheck::Allowed));
+    }
+
+    #[test]
+    fn test_invalid_pattern_rejected() {
+        assert!(Blocklist::new(&["re:(unclosed"]).is_err());
+    }
 }
@@ -9,5 +9,5 @@
 pub mod blocklist;
 pub mod rate_limit;
 
-pub use blocklist::{check_subdomain, BlockReason, SubdomainCheck};
+pub use blocklist::{Blocklist, SubdomainCheck};
 pub use rate_limit::{RateLimitConfig, RateLimitResult, RateLimiter};
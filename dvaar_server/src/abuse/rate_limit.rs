@@ -2,11 +2,27 @@
 //!
 //! Uses Redis for distributed rate limiting across nodes.
 //! Implements sliding window algorithm for smooth rate limiting.
+//!
+//! Limits tied to a plan come from `PlanFeatures` and are keyed by user, so
+//! they hold however many tunnels or subdomains the user spreads work over.
+//!
+//! Redis calls go through the same circuit breaker as routing. While Redis
+//! can't be reached each node counts in memory, so limits hold per node
+//! rather than being dropped.
 
+use crate::redis::CircuitBreaker;
+use crate::routes::billing::PlanFeatures;
+use dvaar_common::heartbeat::spawn_interval_task;
 use fred::prelude::*;
 use std::sync::Arc;
 use std::time::Duration;
 
+/// How often fallback counts are swept
+const FALLBACK_CLEANUP_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Fallback counts older than the longest window are dropped
+const FALLBACK_MAX_AGE: Duration = Duration::from_secs(3600);
+
 /// Rate limiter configuration
 #[derive(Debug, Clone)]
 pub struct RateLimitConfig {
@@ -29,22 +45,14 @@ impl RateLimitConfig {
 pub mod limits {
     use super::*;
 
-    /// Tunnel creation: 60 per hour for free, 200 per hour for paid
-    pub fn tunnel_creation_free() -> RateLimitConfig {
-        RateLimitConfig::new(60, 3600)
-    }
-
-    pub fn tunnel_creation_paid() -> RateLimitConfig {
-        RateLimitConfig::new(200, 3600)
-    }
-
-    /// Request rate: 10000/min for free, 100000/min for paid
-    pub fn requests_free() -> RateLimitConfig {
-        RateLimitConfig::new(10000, 60)
+    /// Tunnel creation: the plan's tunnels per hour
+    pub fn tunnel_creation(features: &PlanFeatures) -> RateLimitConfig {
+        RateLimitConfig::new(features.tunnels_per_hour, 3600)
     }
 
-    pub fn requests_paid() -> RateLimitConfig {
-        RateLimitConfig::new(100000, 60)
+    /// Request rate: the plan's requests per minute, across all tunnels
+    pub fn user_requests(features: &PlanFeatures) -> RateLimitConfig {
+        RateLimitConfig::new(features.requests_per_minute, 60)
     }
 
     /// Auth attempts: 100 per hour (same for all)
@@ -92,16 +100,42 @@ impl RateLimitResult {
 #[derive(Clone)]
 pub struct RateLimiter {
     redis: Arc<Client>,
+    /// Shared with `RouteManager`, so both back off from a failing Redis
+    breaker: Arc<CircuitBreaker>,
+    /// Counts for this node alone, used while Redis is unavailable
+    fallback: Arc<local::LocalRateLimiter>,
 }
 
 impl RateLimiter {
-    pub fn new(redis: Arc<Client>) -> Self {
-        Self { redis }
+    pub fn new(redis: Arc<Client>, breaker: Arc<CircuitBreaker>) -> Self {
+        Self {
+            redis,
+            breaker,
+            fallback: Arc::new(local::LocalRateLimiter::new()),
+        }
+    }
+
+    /// Rate limiter whose circuit is open, so it only ever counts locally
+    #[cfg(test)]
+    pub(crate) async fn without_redis() -> Self {
+        let breaker = Arc::new(CircuitBreaker::new(1, Duration::from_secs(3600)));
+        let _ = breaker.call(async { Err::<(), _>(anyhow::anyhow!("connection refused")) }).await;
+        Self::new(Arc::new(Client::default()), breaker)
+    }
+
+    /// Periodically drop fallback counts whose window has long passed
+    pub fn spawn_fallback_cleanup(&self) -> tokio::task::JoinHandle<()> {
+        let fallback = self.fallback.clone();
+        spawn_interval_task(FALLBACK_CLEANUP_INTERVAL, move || {
+            fallback.cleanup(FALLBACK_MAX_AGE);
+            async {}
+        })
     }
 
     /// Check and increment rate limit
     ///
-    /// Uses Redis MULTI/EXEC for atomic sliding window rate limiting.
+    /// Uses Redis INCR + EXPIRE for fixed window rate limiting, falling back
+    /// to counting on this node while Redis is unavailable.
     ///
     /// # Arguments
     /// * `key_prefix` - Prefix for the rate limit key (e.g., "rl:tunnel")
@@ -112,24 +146,31 @@ impl RateLimiter {
     /// * `RateLimitResult` with current state and whether request is allowed
     pub async fn check(&self, key_prefix: &str, identifier: &str, config: &RateLimitConfig) -> anyhow::Result<RateLimitResult> {
         let key = format!("{}:{}", key_prefix, identifier);
+        match self.breaker.call(self.check_redis(&key, config)).await {
+            Ok(result) => Ok(result),
+            Err(e) => {
+                tracing::debug!("Rate limiting {} on this node only: {}", key, e);
+                Ok(self.fallback.check(&key, config))
+            }
+        }
+    }
+
+    async fn check_redis(&self, key: &str, config: &RateLimitConfig) -> anyhow::Result<RateLimitResult> {
         let window_secs = config.window.as_secs();
 
         // Use INCR + EXPIRE for simple sliding window
         // This is a simplified version - for production, consider using
         // a proper sliding window with sorted sets
 
-        let current: u32 = self.redis.incr(&key).await.unwrap_or(1);
+        let current: u32 = self.redis.incr(key).await?;
 
         // Set expiry on first request
         if current == 1 {
-            let _: () = self.redis
-                .expire(&key, window_secs as i64, None)
-                .await
-                .unwrap_or(());
+            let _: () = self.redis.expire(key, window_secs as i64, None).await?;
         }
 
         // Get TTL for reset time
-        let ttl: i64 = self.redis.ttl(&key).await.unwrap_or(window_secs as i64);
+        let ttl: i64 = self.redis.ttl(key).await?;
         let reset_in_secs = if ttl > 0 { ttl as u64 } else { window_secs };
 
         let allowed = current <= config.max_requests;
@@ -153,8 +194,14 @@ impl RateLimiter {
         let key = format!("{}:{}", key_prefix, identifier);
         let window_secs = config.window.as_secs();
 
-        let current: u32 = self.redis.get(&key).await.unwrap_or(0);
-        let ttl: i64 = self.redis.ttl(&key).await.unwrap_or(window_secs as i64);
+        let (current, ttl): (u32, i64) = self
+            .breaker
+            .call(async {
+                let current: Option<u32> = self.redis.get(&key).await?;
+                let ttl: i64 = self.redis.ttl(&key).await?;
+                Ok((current.unwrap_or(0), ttl))
+            })
+            .await?;
         let reset_in_secs = if ttl > 0 { ttl as u64 } else { window_secs };
 
         let allowed = current < config.max_requests;
@@ -176,28 +223,22 @@ impl RateLimiter {
     /// Reset rate limit for an identifier
     pub async fn reset(&self, key_prefix: &str, identifier: &str) -> anyhow::Result<()> {
         let key = format!("{}:{}", key_prefix, identifier);
-        let _: () = self.redis.del(&key).await?;
-        Ok(())
+        self.breaker
+            .call(async {
+                let _: () = self.redis.del(&key).await?;
+                Ok(())
+            })
+            .await
     }
 
     /// Check rate limit for tunnel creation
-    pub async fn check_tunnel_creation(&self, user_id: &str, is_paid: bool) -> anyhow::Result<RateLimitResult> {
-        let config = if is_paid {
-            limits::tunnel_creation_paid()
-        } else {
-            limits::tunnel_creation_free()
-        };
-        self.check("rl:tunnel", user_id, &config).await
+    pub async fn check_tunnel_creation(&self, user_id: &str, features: &PlanFeatures) -> anyhow::Result<RateLimitResult> {
+        self.check("rl:tunnel", user_id, &limits::tunnel_creation(features)).await
     }
 
-    /// Check rate limit for incoming requests to a tunnel
-    pub async fn check_requests(&self, subdomain: &str, is_paid: bool) -> anyhow::Result<RateLimitResult> {
-        let config = if is_paid {
-            limits::requests_paid()
-        } else {
-            limits::requests_free()
-        };
-        self.check("rl:req", subdomain, &config).await
+    /// Check rate limit for a request to any of the user's tunnels
+    pub async fn check_user_requests(&self, user_id: &str, features: &PlanFeatures) -> anyhow::Result<RateLimitResult> {
+        self.check("rl:req:user", user_id, &limits::user_requests(features)).await
     }
 
     /// Check rate limit for auth attempts (by IP)
@@ -303,4 +344,45 @@ mod tests {
         assert!(!result.allowed);
         assert_eq!(result.remaining, 0);
     }
+
+    #[tokio::test]
+    async fn test_user_request_limits_follow_the_plan() {
+        let limiter = RateLimiter::without_redis().await;
+        let free = PlanFeatures::for_plan("free");
+        let pro = PlanFeatures::for_plan("pro");
+
+        let first = limiter.check_user_requests("user-1", &free).await.unwrap();
+        assert_eq!(first.limit, free.requests_per_minute);
+        assert_eq!(first.remaining, free.requests_per_minute - 1);
+        assert_eq!(
+            limiter.check_user_requests("user-2", &pro).await.unwrap().limit,
+            pro.requests_per_minute
+        );
+
+        // Each user draws on their own budget
+        for _ in 1..free.requests_per_minute {
+            assert!(limiter.check_user_requests("user-1", &free).await.unwrap().allowed);
+        }
+        assert!(!limiter.check_user_requests("user-1", &free).await.unwrap().allowed);
+        assert!(limiter.check_user_requests("user-2", &pro).await.unwrap().allowed);
+    }
+
+    #[tokio::test]
+    async fn test_open_circuit_counts_locally() {
+        let limiter = RateLimiter::without_redis().await;
+        assert!(limiter.breaker.is_open());
+        let config = RateLimitConfig::new(2, 60);
+
+        // The client was never connected, so these can only have been counted locally
+        assert!(limiter.check("rl:test", "user-1", &config).await.unwrap().allowed);
+        assert!(limiter.check("rl:test", "user-1", &config).await.unwrap().allowed);
+        let limited = limiter.check("rl:test", "user-1", &config).await.unwrap();
+        assert!(!limited.allowed);
+        assert_eq!((limited.current, limited.remaining), (3, 0));
+
+        // Calls that have nothing to fall back on fail instead of reaching Redis
+        let err = limiter.peek("rl:test", "user-1", &config).await.unwrap_err();
+        assert!(err.is::<crate::redis::RedisUnavailable>());
+        assert!(limiter.reset("rl:test", "user-1").await.is_err());
+    }
 }
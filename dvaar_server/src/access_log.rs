@@ -0,0 +1,206 @@
+//! Per-request access log for ingress traffic
+//!
+//! Separate from `tracing`: one JSON line per request, written to a file that
+//! rotates by size, so operators get an auditable record regardless of how
+//! the log subscriber is configured.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+/// Entries queued for the writer before new ones are dropped
+const ACCESS_LOG_QUEUE: usize = 4096;
+
+/// One ingress request
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessLogEntry {
+    pub timestamp: DateTime<Utc>,
+    pub subdomain: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub bytes: u64,
+    pub duration_ms: u64,
+    pub client_ip: IpAddr,
+}
+
+/// Handle for recording entries; writing happens on a blocking thread
+#[derive(Clone)]
+pub struct AccessLog {
+    tx: mpsc::Sender<AccessLogEntry>,
+}
+
+impl AccessLog {
+    /// Start the writer for `file`
+    pub fn spawn(mut file: RotatingFile) -> Self {
+        let (tx, mut rx) = mpsc::channel::<AccessLogEntry>(ACCESS_LOG_QUEUE);
+        tokio::task::spawn_blocking(move || {
+            while let Some(entry) = rx.blocking_recv() {
+                if let Err(e) = write_entry(&mut file, &entry) {
+                    tracing::error!("Failed to write access log: {}", e);
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    /// Queue an entry. Never blocks the request: if the writer has fallen
+    /// behind, the entry is dropped.
+    pub fn record(&self, entry: AccessLogEntry) {
+        if self.tx.try_send(entry).is_err() {
+            tracing::warn!("Access log queue full, dropping entry");
+        }
+    }
+}
+
+fn write_entry(file: &mut RotatingFile, entry: &AccessLogEntry) -> io::Result<()> {
+    let line = serde_json::to_string(entry)?;
+    file.write_line(&line)
+}
+
+/// Append-only file that is rotated once it reaches `max_bytes`:
+/// `access.log` becomes `access.log.1`, `.1` becomes `.2`, and so on,
+/// keeping at most `max_files` old files.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    written: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            max_files,
+            file,
+            written,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        // Rotate before a line would cross the limit; an empty file always takes it
+        if self.written > 0 && self.written + len > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.written += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated_path(&self.path, self.max_files));
+            for n in (1..self.max_files).rev() {
+                let from = rotated_path(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_log_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dvaar-access-log-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    fn entry(n: usize) -> AccessLogEntry {
+        AccessLogEntry {
+            timestamp: Utc::now(),
+            subdomain: "myapp".to_string(),
+            method: "GET".to_string(),
+            path: format!("/items/{}", n),
+            status: 200,
+            bytes: 512,
+            duration_ms: 3,
+            client_ip: "203.0.113.7".parse().unwrap(),
+        }
+    }
+
+    fn line_count(path: &Path) -> usize {
+        fs::read_to_string(path).map(|s| s.lines().count()).unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn test_one_line_per_request() {
+        let path = temp_log_path("access.log");
+        let log = AccessLog::spawn(RotatingFile::open(&path, 1024 * 1024, 3).unwrap());
+
+        for n in 0..25 {
+            log.record(entry(n));
+        }
+
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(5);
+        while line_count(&path) < 25 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 25);
+        let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first["subdomain"], "myapp");
+        assert_eq!(first["path"], "/items/0");
+        assert_eq!(first["status"], 200);
+        assert_eq!(first["client_ip"], "203.0.113.7");
+    }
+
+    #[test]
+    fn test_rotates_at_max_size() {
+        let path = temp_log_path("access.log");
+        let line = "x".repeat(99);
+        // Ten 100-byte lines per file
+        let mut file = RotatingFile::open(&path, 1000, 2).unwrap();
+
+        for _ in 0..10 {
+            file.write_line(&line).unwrap();
+        }
+        assert!(!rotated_path(&path, 1).exists());
+
+        file.write_line(&line).unwrap();
+        assert_eq!(line_count(&rotated_path(&path, 1)), 10);
+        assert_eq!(line_count(&path), 1);
+
+        // Only max_files old files are kept
+        for _ in 0..30 {
+            file.write_line(&line).unwrap();
+        }
+        assert!(rotated_path(&path, 2).exists());
+        assert!(!rotated_path(&path, 3).exists());
+        assert!(fs::metadata(&path).unwrap().len() <= 1000);
+    }
+}
@@ -0,0 +1,219 @@
+//! Method and path rules for a tunnel's public URL
+
+use axum::http::Method;
+use dvaar_common::constants;
+
+/// Why a request was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denial {
+    /// 405: the method isn't in the allowlist
+    Method,
+    /// 403: the path matches a denied glob
+    Path,
+}
+
+/// Requests a client asked the server to refuse outright, e.g. anything but
+/// `GET` on a read-only demo, or `/admin/**`. Refused requests never reach
+/// the tunnel.
+///
+/// Path globs: `*` matches within one path segment, `**` across segments
+/// and `?` any one character other than `/`. They're matched against the
+/// path the upstream will end up serving, so `/%61dmin/x` or
+/// `/foo/../admin/x` can't get around `/admin/**`.
+#[derive(Debug, Clone, Default)]
+pub struct AccessRules {
+    allowed_methods: Vec<Method>,
+    denied_paths: Vec<String>,
+}
+
+impl AccessRules {
+    /// Rules from the client's `Init`, or a message for the client if they
+    /// aren't usable
+    pub fn new(allowed_methods: &[String], denied_paths: &[String]) -> Result<Self, String> {
+        if allowed_methods.len() + denied_paths.len() > constants::MAX_ACCESS_RULES {
+            return Err(format!(
+                "Too many access rules (at most {} methods and paths together)",
+                constants::MAX_ACCESS_RULES
+            ));
+        }
+        let allowed_methods = allowed_methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .map_err(|_| format!("Invalid method in access rules: {}", method))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(glob) = denied_paths.iter().find(|glob| !glob.starts_with('/')) {
+            return Err(format!("Denied path '{}' must start with /", glob));
+        }
+        Ok(Self {
+            allowed_methods,
+            denied_paths: denied_paths.to_vec(),
+        })
+    }
+
+    /// Methods to list in a 405's `Allow` header
+    pub fn allowed_methods(&self) -> &[Method] {
+        &self.allowed_methods
+    }
+
+    /// Check a request; `path` excludes the query string. A method outside
+    /// the allowlist gets a 405 even on a denied path, since no path would help.
+    pub fn check(&self, method: &Method, path: &str) -> Result<(), Denial> {
+        if !self.allowed_methods.is_empty() && !self.allowed_methods.contains(method) {
+            return Err(Denial::Method);
+        }
+        if self.denied_paths.is_empty() {
+            return Ok(());
+        }
+        let path = normalize_path(path);
+        if self.denied_paths.iter().any(|glob| glob_match(glob.as_bytes(), &path)) {
+            return Err(Denial::Path);
+        }
+        Ok(())
+    }
+}
+
+/// Percent-decode `path`, collapse repeated `/` and resolve `.`/`..`
+/// segments (never above the root). A trailing `/` is kept.
+fn normalize_path(path: &str) -> Vec<u8> {
+    let decoded = percent_decode(path.as_bytes());
+    let mut segments: Vec<&[u8]> = Vec::new();
+    for segment in decoded.split(|&b| b == b'/') {
+        match segment {
+            b"" | b"." => {}
+            b".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = Vec::with_capacity(decoded.len() + 1);
+    for segment in &segments {
+        normalized.push(b'/');
+        normalized.extend_from_slice(segment);
+    }
+    let trailing_slash = matches!(decoded.rsplit(|&b| b == b'/').next(), Some(b"" | b"." | b".."));
+    if normalized.is_empty() || trailing_slash {
+        normalized.push(b'/');
+    }
+    normalized
+}
+
+/// Decode `%XX` escapes; a `%` not followed by two hex digits stays as is
+fn percent_decode(input: &[u8]) -> Vec<u8> {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] == b'%' && i + 2 < input.len() {
+            if let (Some(hi), Some(lo)) = (hex(input[i + 1]), hex(input[i + 2])) {
+                decoded.push((hi << 4) | lo);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(input[i]);
+        i += 1;
+    }
+    decoded
+}
+
+/// Whether `path` matches `glob`, in O(glob × path) so a hostile glob
+/// can't make matching blow up
+fn glob_match(glob: &[u8], path: &[u8]) -> bool {
+    // matched[j]: the glob so far matches the first j bytes of the path
+    let mut matched = vec![false; path.len() + 1];
+    matched[0] = true;
+    let mut i = 0;
+    while i < glob.len() {
+        let mut next = vec![false; path.len() + 1];
+        match glob[i] {
+            b'*' if glob.get(i + 1) == Some(&b'*') => {
+                i += 1;
+                next[0] = matched[0];
+                for j in 1..=path.len() {
+                    next[j] = matched[j] || next[j - 1];
+                }
+            }
+            b'*' => {
+                next[0] = matched[0];
+                for j in 1..=path.len() {
+                    next[j] = matched[j] || (next[j - 1] && path[j - 1] != b'/');
+                }
+            }
+            b'?' => {
+                for j in 1..=path.len() {
+                    next[j] = matched[j - 1] && path[j - 1] != b'/';
+                }
+            }
+            c => {
+                for j in 1..=path.len() {
+                    next[j] = matched[j - 1] && path[j - 1] == c;
+                }
+            }
+        }
+        matched = next;
+        i += 1;
+    }
+    matched[path.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(methods: &[&str], paths: &[&str]) -> AccessRules {
+        let methods: Vec<String> = methods.iter().map(|m| m.to_string()).collect();
+        let paths: Vec<String> = paths.iter().map(|p| p.to_string()).collect();
+        AccessRules::new(&methods, &paths).unwrap()
+    }
+
+    #[test]
+    fn test_path_globs() {
+        let globs = rules(&[], &["/admin/**", "/*.env", "/v?/internal"]);
+        assert_eq!(globs.check(&Method::GET, "/admin/users/1"), Err(Denial::Path));
+        assert_eq!(globs.check(&Method::GET, "/.env"), Err(Denial::Path));
+        assert_eq!(globs.check(&Method::GET, "/v1/internal"), Err(Denial::Path));
+        // `*` and `?` stay within a segment
+        assert_eq!(globs.check(&Method::GET, "/config/.env"), Ok(()));
+        assert_eq!(globs.check(&Method::GET, "/v10/internal"), Ok(()));
+        assert_eq!(globs.check(&Method::GET, "/administrator"), Ok(()));
+
+        assert!(AccessRules::new(&[], &["admin/**".to_string()]).is_err());
+        assert!(AccessRules::new(&["GE T".to_string()], &[]).is_err());
+    }
+
+    #[test]
+    fn test_method_checked_before_path() {
+        let read_only = rules(&["get", "HEAD"], &["/admin/**"]);
+        assert_eq!(read_only.check(&Method::GET, "/"), Ok(()));
+        assert_eq!(read_only.check(&Method::HEAD, "/"), Ok(()));
+        assert_eq!(read_only.check(&Method::POST, "/"), Err(Denial::Method));
+        assert_eq!(read_only.check(&Method::POST, "/admin/delete"), Err(Denial::Method));
+        assert_eq!(read_only.check(&Method::GET, "/admin/delete"), Err(Denial::Path));
+        assert_eq!(AccessRules::default().check(&Method::DELETE, "/admin/x"), Ok(()));
+    }
+
+    #[test]
+    fn test_denied_paths_see_the_normalized_path() {
+        let globs = rules(&[], &["/admin/**"]);
+        // Percent-encoded, including an encoded separator
+        assert_eq!(globs.check(&Method::GET, "/%61dmin/x"), Err(Denial::Path));
+        assert_eq!(globs.check(&Method::GET, "/admin%2Fx"), Err(Denial::Path));
+        // Repeated slashes
+        assert_eq!(globs.check(&Method::GET, "//admin/x"), Err(Denial::Path));
+        assert_eq!(globs.check(&Method::GET, "/admin//x"), Err(Denial::Path));
+        // Dot segments, also encoded, and `..` past the root
+        assert_eq!(globs.check(&Method::GET, "/foo/../admin/x"), Err(Denial::Path));
+        assert_eq!(globs.check(&Method::GET, "/./admin/./x"), Err(Denial::Path));
+        assert_eq!(globs.check(&Method::GET, "/foo/%2e%2e/admin/x"), Err(Denial::Path));
+        assert_eq!(globs.check(&Method::GET, "/../../admin/x"), Err(Denial::Path));
+        // Still only what's under /admin
+        assert_eq!(globs.check(&Method::GET, "/admin/../public"), Ok(()));
+        assert_eq!(globs.check(&Method::GET, "/admin"), Ok(()));
+        assert_eq!(globs.check(&Method::GET, "/admin/"), Err(Denial::Path));
+        assert_eq!(globs.check(&Method::GET, "/100%"), Ok(()));
+    }
+}
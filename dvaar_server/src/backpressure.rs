@@ -0,0 +1,133 @@
+//! Backpressure between a tunnel and the streams multiplexed over it
+//!
+//! A tunnel's receive loop feeds every stream on it. If it waited forever on
+//! one stream whose downstream reader is slow, all other streams would stall
+//! behind it, so sends into a full stream get a deadline and the stream is
+//! failed once it passes.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc::{
+    self,
+    error::{SendTimeoutError, TrySendError},
+};
+
+/// Node-wide counters, reported by the admin metrics endpoint
+#[derive(Debug, Default)]
+pub struct ChannelStats {
+    /// Sends that found a stream's buffer full and had to wait
+    pub full_events: AtomicU64,
+    /// Streams failed because their buffer stayed full past the send timeout
+    pub stalled_streams: AtomicU64,
+}
+
+/// Outcome of [`send_or_stall`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Sent,
+    /// The consumer is gone
+    Closed,
+    /// The consumer didn't make room within the timeout; the caller should fail the stream
+    Stalled,
+}
+
+/// Send `item`, waiting at most `timeout` for room if the channel is full
+pub async fn send_or_stall<T>(
+    tx: &mpsc::Sender<T>,
+    item: T,
+    timeout: Duration,
+    stats: &ChannelStats,
+) -> Delivery {
+    let item = match tx.try_send(item) {
+        Ok(()) => return Delivery::Sent,
+        Err(TrySendError::Closed(_)) => return Delivery::Closed,
+        Err(TrySendError::Full(item)) => item,
+    };
+
+    stats.full_events.fetch_add(1, Ordering::Relaxed);
+    match tx.send_timeout(item, timeout).await {
+        Ok(()) => Delivery::Sent,
+        Err(SendTimeoutError::Closed(_)) => Delivery::Closed,
+        Err(SendTimeoutError::Timeout(_)) => {
+            stats.stalled_streams.fetch_add(1, Ordering::Relaxed);
+            Delivery::Stalled
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn test_full_channel_waits_then_stalls() {
+        let stats = ChannelStats::default();
+        let (tx, mut rx) = mpsc::channel::<u32>(1);
+        let timeout = Duration::from_millis(20);
+
+        assert_eq!(send_or_stall(&tx, 1, timeout, &stats).await, Delivery::Sent);
+        assert_eq!(send_or_stall(&tx, 2, timeout, &stats).await, Delivery::Stalled);
+        assert_eq!(stats.full_events.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.stalled_streams.load(Ordering::Relaxed), 1);
+
+        assert_eq!(rx.recv().await, Some(1));
+        drop(rx);
+        assert_eq!(send_or_stall(&tx, 3, timeout, &stats).await, Delivery::Closed);
+    }
+
+    /// One reader loop feeding many streams, as a tunnel's recv task does:
+    /// a consumer that never reads must cost the others at most one timeout.
+    #[tokio::test]
+    async fn test_slow_stream_does_not_starve_others() {
+        const STREAMS: usize = 64;
+        const CHUNKS: usize = 200;
+        const SLOW: usize = 7;
+
+        let stats = ChannelStats::default();
+        let timeout = Duration::from_millis(100);
+
+        let mut senders = Vec::new();
+        let mut consumers = Vec::new();
+        let mut slow_rx = None;
+        for stream in 0..STREAMS {
+            let (tx, mut rx) = mpsc::channel::<usize>(4);
+            senders.push(tx);
+            if stream == SLOW {
+                // Held open but never read
+                slow_rx = Some(rx);
+                continue;
+            }
+            consumers.push(tokio::spawn(async move {
+                let mut received = 0;
+                while rx.recv().await.is_some() {
+                    received += 1;
+                }
+                received
+            }));
+        }
+
+        let started = Instant::now();
+        let mut failed = HashSet::new();
+        for chunk in 0..CHUNKS {
+            for (stream, tx) in senders.iter().enumerate() {
+                if failed.contains(&stream) {
+                    continue;
+                }
+                if send_or_stall(tx, chunk, timeout, &stats).await == Delivery::Stalled {
+                    failed.insert(stream);
+                }
+            }
+        }
+        drop(senders);
+
+        for consumer in consumers {
+            assert_eq!(consumer.await.unwrap(), CHUNKS);
+        }
+        assert_eq!(failed, HashSet::from([SLOW]));
+        assert_eq!(stats.stalled_streams.load(Ordering::Relaxed), 1);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(slow_rx);
+    }
+}
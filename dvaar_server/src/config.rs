@@ -1,16 +1,25 @@
 //! Server configuration loaded from environment variables
 
+use dvaar_common::{constants, WireFormat};
 use std::env;
 use std::net::IpAddr;
 
 #[derive(Debug, Clone)]
+#[cfg_attr(test, derive(Default))]
 pub struct Config {
-    /// Host to bind to
+    /// Host to bind to, an IPv4 or IPv6 address
     pub host: String,
 
+    /// With an IPv6 host such as `::`, take IPv4 connections on the same
+    /// sockets too; off leaves them IPv6 only
+    pub dual_stack: bool,
+
     /// Public port for HTTP/WebSocket traffic
     pub port: u16,
 
+    /// Every port the public server listens on; `port` alone unless `PUBLIC_PORTS` is set
+    pub public_ports: Vec<u16>,
+
     /// Internal port for node-to-node communication
     pub internal_port: u16,
 
@@ -26,9 +35,23 @@ pub struct Config {
     /// This node's public IP address
     pub node_ip: String,
 
+    /// Region this node serves, compared against the client's `cf-ipcountry`
+    pub node_region: Option<String>,
+
+    /// Host clients can reach this node on directly; only nodes with one are
+    /// offered as redirect targets at connect
+    pub node_public_host: Option<String>,
+
+    /// Tunnels this node advertises room for; past it, connecting clients are
+    /// redirected to nodes with room
+    pub node_max_tunnels: u32,
+
     /// Secret for node-to-node authentication
     pub cluster_secret: String,
 
+    /// Key signing inspector share links; the same on every node
+    pub share_link_secret: String,
+
     /// Allow X-Subdomain header override (local development only)
     pub allow_subdomain_header: bool,
 
@@ -49,6 +72,95 @@ pub struct Config {
 
     /// Stripe webhook secret (optional for MVP)
     pub stripe_webhook_secret: Option<String>,
+
+    /// Interval between keepalive pings sent to tunnel clients
+    pub ws_ping_interval_secs: u64,
+
+    /// Close a tunnel when the client hasn't answered a ping for this long
+    pub ws_pong_timeout_secs: u64,
+
+    /// Give free users a subdomain derived from their user id instead of a fresh random one
+    pub stable_subdomains: bool,
+
+    /// Salt mixed into stable subdomains, so names can't be predicted from user ids
+    pub subdomain_salt: String,
+
+    /// Adjectives for random subdomains, one per line (built-in list when unset)
+    pub subdomain_adjectives_file: Option<String>,
+
+    /// Nouns for random subdomains, one per line (built-in list when unset)
+    pub subdomain_nouns_file: Option<String>,
+
+    /// Shape of random subdomains, from `{adjective}`, `{noun}`, `{number}` and `{suffix}`
+    pub subdomain_template: String,
+
+    /// Extra blocked subdomain patterns: wildcards (`paypal*`) or regexes (`re:^pay-?pal`)
+    pub blocked_subdomain_patterns: Vec<String>,
+
+    /// File to write one JSON line per ingress request to (disabled when unset)
+    pub access_log_path: Option<String>,
+
+    /// Rotate the access log once it reaches this many bytes
+    pub access_log_max_bytes: u64,
+
+    /// Number of rotated access log files to keep
+    pub access_log_max_files: usize,
+
+    /// Commands (requests and request body chunks) buffered per tunnel
+    pub tunnel_channel_capacity: usize,
+
+    /// Response chunks buffered per stream
+    pub stream_channel_capacity: usize,
+
+    /// Requests a tunnel may have in flight before ingress answers 503
+    pub max_streams_per_tunnel: usize,
+
+    /// How long a full stream may hold up its tunnel before the stream is failed
+    pub stream_send_timeout_ms: u64,
+
+    /// Largest control frame accepted from a tunnel client
+    pub max_frame_bytes: usize,
+
+    /// Add a tunnel's traffic to the user's usage once this many bytes are pending
+    pub usage_flush_bytes: u64,
+
+    /// Add whatever traffic is pending at least this often, however little
+    pub usage_flush_interval_secs: u64,
+
+    /// JSON lets clients that ask for it use JSON on the wire instead of MessagePack
+    pub wire_format: WireFormat,
+
+    /// Read a PROXY protocol v2 header on public connections (must match the load balancer)
+    pub proxy_protocol: bool,
+
+    /// Serve the public port on this Unix socket instead of `host:port`
+    pub unix_socket: Option<String>,
+
+    /// UDP port for HTTP/3 ingress; only used by builds with the `http3` feature
+    pub http3_port: Option<u16>,
+
+    /// PEM certificate chain for HTTP/3, which terminates TLS itself
+    #[cfg_attr(not(feature = "http3"), allow(dead_code))]
+    pub http3_cert_file: Option<String>,
+
+    /// PEM private key for `http3_cert_file`
+    #[cfg_attr(not(feature = "http3"), allow(dead_code))]
+    pub http3_key_file: Option<String>,
+
+    /// Number of GET responses to cache at the edge (0 disables the cache)
+    pub response_cache_entries: usize,
+
+    /// Largest response body the cache will hold
+    pub response_cache_max_body_bytes: usize,
+
+    /// Per-tunnel throughput for free tunnels, bytes/sec (0 = unthrottled)
+    pub bandwidth_rate_free: u64,
+
+    /// Per-tunnel throughput for hobby tunnels, bytes/sec (0 = unthrottled)
+    pub bandwidth_rate_hobby: u64,
+
+    /// Per-tunnel throughput for pro tunnels, bytes/sec (0 = unthrottled)
+    pub bandwidth_rate_pro: u64,
 }
 
 impl Config {
@@ -61,12 +173,40 @@ impl Config {
             return Err(ConfigError::InsecureClusterSecret);
         }
 
+        // PORT defaults to the first of PUBLIC_PORTS, and is all of them when that's unset
+        let public_ports = match env::var("PUBLIC_PORTS") {
+            Ok(list) if !list.trim().is_empty() => parse_ports(&list)?,
+            _ => Vec::new(),
+        };
+        let port = match env::var("PORT") {
+            Ok(port) => port.parse().map_err(|_| ConfigError::InvalidPort)?,
+            Err(_) => public_ports.first().copied().unwrap_or(8080),
+        };
+        let public_ports = if public_ports.is_empty() { vec![port] } else { public_ports };
+
+        // QUIC carries its own TLS, so HTTP/3 needs a certificate even behind a TLS proxy
+        let http3_port = match env::var("HTTP3_PORT") {
+            Ok(port) if !port.is_empty() => Some(port.parse().map_err(|_| ConfigError::InvalidPort)?),
+            _ => None,
+        };
+        let http3_cert_file = env::var("HTTP3_CERT_FILE").ok().filter(|p| !p.is_empty());
+        let http3_key_file = env::var("HTTP3_KEY_FILE").ok().filter(|p| !p.is_empty());
+        if http3_port.is_some() {
+            if http3_cert_file.is_none() {
+                return Err(ConfigError::MissingEnv("HTTP3_CERT_FILE"));
+            }
+            if http3_key_file.is_none() {
+                return Err(ConfigError::MissingEnv("HTTP3_KEY_FILE"));
+            }
+        }
+
         Ok(Self {
             host: env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
-            port: env::var("PORT")
-                .unwrap_or_else(|_| "8080".to_string())
-                .parse()
-                .map_err(|_| ConfigError::InvalidPort)?,
+            dual_stack: env::var("DUAL_STACK")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(true),
+            port,
+            public_ports,
             internal_port: env::var("INTERNAL_PORT")
                 .unwrap_or_else(|_| "6000".to_string())
                 .parse()
@@ -76,6 +216,15 @@ impl Config {
             public_url: env::var("PUBLIC_URL")
                 .unwrap_or_else(|_| "http://localhost:8080".to_string()),
             node_ip,
+            node_region: env::var("NODE_REGION").ok().filter(|v| !v.is_empty()),
+            node_public_host: env::var("NODE_PUBLIC_HOST").ok().filter(|v| !v.is_empty()),
+            node_max_tunnels: env_u64("NODE_MAX_TUNNELS", constants::NODE_MAX_TUNNELS as u64)?
+                .try_into()
+                .map_err(|_| ConfigError::InvalidValue("NODE_MAX_TUNNELS"))?,
+            share_link_secret: env::var("SHARE_LINK_SECRET")
+                .ok()
+                .filter(|s| !s.is_empty())
+                .unwrap_or_else(|| cluster_secret.clone()),
             cluster_secret,
             allow_subdomain_header: env::var("ALLOW_SUBDOMAIN_HEADER")
                 .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
@@ -89,9 +238,62 @@ impl Config {
                 .unwrap_or_else(|_| String::new()),
             stripe_secret_key: env::var("STRIPE_SECRET_KEY").ok(),
             stripe_webhook_secret: env::var("STRIPE_WEBHOOK_SECRET").ok(),
+            ws_ping_interval_secs: env_u64("WS_PING_INTERVAL_SECS", constants::WS_PING_INTERVAL_SECONDS)?,
+            ws_pong_timeout_secs: env_u64("WS_PONG_TIMEOUT_SECS", constants::WS_PONG_TIMEOUT_SECONDS)?,
+            stable_subdomains: env::var("STABLE_SUBDOMAINS")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            subdomain_salt: env::var("SUBDOMAIN_SALT").unwrap_or_default(),
+            subdomain_adjectives_file: env::var("SUBDOMAIN_ADJECTIVES_FILE").ok().filter(|p| !p.is_empty()),
+            subdomain_nouns_file: env::var("SUBDOMAIN_NOUNS_FILE").ok().filter(|p| !p.is_empty()),
+            subdomain_template: env::var("SUBDOMAIN_TEMPLATE")
+                .ok()
+                .filter(|t| !t.is_empty())
+                .unwrap_or_else(|| crate::subdomain_names::DEFAULT_TEMPLATE.to_string()),
+            // Whitespace-separated so regexes are free to use commas
+            blocked_subdomain_patterns: env::var("BLOCKED_SUBDOMAIN_PATTERNS")
+                .map(|v| v.split_whitespace().map(str::to_string).collect())
+                .unwrap_or_default(),
+            access_log_path: env::var("ACCESS_LOG_PATH").ok().filter(|p| !p.is_empty()),
+            access_log_max_bytes: env_u64("ACCESS_LOG_MAX_BYTES", 100 * 1024 * 1024)?,
+            access_log_max_files: env_u64("ACCESS_LOG_MAX_FILES", 5)? as usize,
+            tunnel_channel_capacity: env_capacity("TUNNEL_CHANNEL_CAPACITY", constants::TUNNEL_CHANNEL_CAPACITY)?,
+            stream_channel_capacity: env_capacity("STREAM_CHANNEL_CAPACITY", constants::STREAM_CHANNEL_CAPACITY)?,
+            max_streams_per_tunnel: env_capacity("MAX_STREAMS_PER_TUNNEL", constants::MAX_STREAMS_PER_TUNNEL)?,
+            stream_send_timeout_ms: env_u64("STREAM_SEND_TIMEOUT_MS", constants::STREAM_SEND_TIMEOUT_MS)?,
+            max_frame_bytes: env_u64("MAX_FRAME_BYTES", constants::MAX_FRAME_BYTES as u64)? as usize,
+            usage_flush_bytes: env_u64("USAGE_FLUSH_BYTES", crate::routes::tunnel::USAGE_FLUSH_BYTES)?,
+            // Zero would make the flush ticker panic
+            usage_flush_interval_secs: env_capacity(
+                "USAGE_FLUSH_INTERVAL_SECS",
+                crate::routes::tunnel::USAGE_FLUSH_INTERVAL_SECS as usize,
+            )? as u64,
+            wire_format: WireFormat::from_env(),
+            proxy_protocol: env::var("PROXY_PROTOCOL")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            unix_socket: env::var("UNIX_SOCKET").ok().filter(|p| !p.is_empty()),
+            http3_port,
+            http3_cert_file,
+            http3_key_file,
+            response_cache_entries: env_u64("RESPONSE_CACHE_ENTRIES", 0)? as usize,
+            response_cache_max_body_bytes: env_u64("RESPONSE_CACHE_MAX_BODY_BYTES", 1024 * 1024)? as usize,
+            bandwidth_rate_free: env_u64("BANDWIDTH_RATE_FREE", constants::BANDWIDTH_RATE_FREE)?,
+            bandwidth_rate_hobby: env_u64("BANDWIDTH_RATE_HOBBY", constants::BANDWIDTH_RATE_HOBBY)?,
+            bandwidth_rate_pro: env_u64("BANDWIDTH_RATE_PRO", constants::BANDWIDTH_RATE_PRO)?,
         })
     }
 
+    /// Throughput allowed to one tunnel on `plan`, or `None` if unthrottled
+    pub fn bandwidth_rate(&self, plan: &str) -> Option<u64> {
+        let rate = match plan {
+            "pro" => self.bandwidth_rate_pro,
+            "hobby" => self.bandwidth_rate_hobby,
+            _ => self.bandwidth_rate_free,
+        };
+        (rate > 0).then_some(rate)
+    }
+
     /// Get the full tunnel domain for a subdomain (e.g., "myapp.dvaar.app")
     pub fn full_domain(&self, subdomain: &str) -> String {
         format!("{}.{}", subdomain, self.tunnel_domain)
@@ -116,6 +318,9 @@ pub enum ConfigError {
     #[error("Invalid port number")]
     InvalidPort,
 
+    #[error("Invalid value for environment variable: {0}")]
+    InvalidValue(&'static str),
+
     #[error("CLUSTER_SECRET must be set to a secure value in non-local environments")]
     InsecureClusterSecret,
 }
@@ -129,3 +334,31 @@ fn is_local_node(ip: &str) -> bool {
         .map(|addr| addr.is_loopback())
         .unwrap_or(false)
 }
+
+/// Parse a comma-separated port list, dropping repeats
+fn parse_ports(list: &str) -> Result<Vec<u16>, ConfigError> {
+    let mut ports = Vec::new();
+    for port in list.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let port: u16 = port.parse().map_err(|_| ConfigError::InvalidPort)?;
+        if !ports.contains(&port) {
+            ports.push(port);
+        }
+    }
+    Ok(ports)
+}
+
+/// Read an optional numeric environment variable, falling back to `default` when unset
+fn env_u64(name: &'static str, default: u64) -> Result<u64, ConfigError> {
+    match env::var(name) {
+        Ok(value) => value.parse().map_err(|_| ConfigError::InvalidValue(name)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Like `env_u64`, but rejects zero: `mpsc::channel(0)` panics
+fn env_capacity(name: &'static str, default: usize) -> Result<usize, ConfigError> {
+    match env_u64(name, default as u64)? {
+        0 => Err(ConfigError::InvalidValue(name)),
+        n => Ok(n as usize),
+    }
+}
@@ -30,13 +30,6 @@ pub struct User {
     pub updated_at: DateTime<Utc>,
 }
 
-impl User {
-    /// Check if user has a paid plan
-    pub fn is_paid(&self) -> bool {
-        self.plan != "free"
-    }
-}
-
 /// API key model
 #[derive(Debug, Clone, sqlx::FromRow)]
 pub struct ApiKey {
@@ -76,6 +69,18 @@ pub struct UserPlan {
     pub custom_domains_allowed: bool,
 }
 
+/// One tunnel session, from the audit log
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+pub struct TunnelEvent {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub subdomain: String,
+    pub node_ip: String,
+    pub client_ip: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+}
+
 /// Database queries
 pub mod queries {
     use super::*;
@@ -342,4 +347,105 @@ pub mod queries {
         .fetch_one(pool)
         .await
     }
+
+    /// Record that a tunnel came up, returning the event to close later
+    pub async fn record_tunnel_event(
+        pool: &PgPool,
+        user_id: Uuid,
+        subdomain: &str,
+        node_ip: &str,
+        client_ip: Option<&str>,
+    ) -> Result<Uuid, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+            INSERT INTO tunnel_events (user_id, subdomain, node_ip, client_ip)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id
+            "#,
+        )
+        .bind(user_id)
+        .bind(subdomain)
+        .bind(node_ip)
+        .bind(client_ip)
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Record that a tunnel went down
+    pub async fn close_tunnel_event(pool: &PgPool, id: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE tunnel_events SET ended_at = NOW() WHERE id = $1 AND ended_at IS NULL")
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Most recent tunnel events, newest first, optionally for one user or subdomain
+    pub async fn recent_tunnel_events(
+        pool: &PgPool,
+        user_id: Option<Uuid>,
+        subdomain: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<TunnelEvent>, sqlx::Error> {
+        sqlx::query_as::<_, TunnelEvent>(
+            r#"
+            SELECT id, user_id, subdomain, node_ip, client_ip, started_at, ended_at
+            FROM tunnel_events
+            WHERE ($1::uuid IS NULL OR user_id = $1)
+              AND ($2::text IS NULL OR subdomain = $2)
+            ORDER BY started_at DESC
+            LIMIT $3
+            "#,
+        )
+        .bind(user_id)
+        .bind(subdomain)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pool on a migrated test database, or `None` to skip when
+    /// `TEST_DATABASE_URL` isn't set
+    async fn test_pool() -> Option<PgPool> {
+        let url = std::env::var("TEST_DATABASE_URL").ok()?;
+        let pool = init_pool(&url).await.expect("connect to TEST_DATABASE_URL");
+        run_migrations(&pool).await.expect("run migrations");
+        Some(pool)
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_events_record_and_query() {
+        let Some(pool) = test_pool().await else {
+            eprintln!("TEST_DATABASE_URL not set, skipping");
+            return;
+        };
+
+        let user = queries::create_user(&pool, &format!("{}@example.com", Uuid::new_v4())).await.unwrap();
+        let subdomain = format!("audit-{}", Uuid::new_v4().simple());
+
+        let first = queries::record_tunnel_event(&pool, user.id, &subdomain, "10.0.0.1", Some("203.0.113.9"))
+            .await
+            .unwrap();
+        queries::close_tunnel_event(&pool, first).await.unwrap();
+        let second = queries::record_tunnel_event(&pool, user.id, "other", "10.0.0.2", None).await.unwrap();
+
+        let events = queries::recent_tunnel_events(&pool, Some(user.id), None, 10).await.unwrap();
+        assert_eq!(events.iter().map(|e| e.id).collect::<Vec<_>>(), [second, first]);
+        assert!(events[0].ended_at.is_none());
+        assert!(events[1].ended_at.is_some());
+        assert_eq!(events[1].client_ip.as_deref(), Some("203.0.113.9"));
+        assert_eq!(events[1].node_ip, "10.0.0.1");
+
+        let by_subdomain = queries::recent_tunnel_events(&pool, None, Some(&subdomain), 10).await.unwrap();
+        assert_eq!(by_subdomain.len(), 1);
+        assert_eq!(by_subdomain[0].user_id, user.id);
+
+        let limited = queries::recent_tunnel_events(&pool, Some(user.id), None, 1).await.unwrap();
+        assert_eq!(limited.len(), 1);
+    }
 }
@@ -0,0 +1,301 @@
+//! HTTP/3 ingress (the `http3` feature)
+//!
+//! QUIC saves mobile visitors a round trip or two on every new connection.
+//! This listener accepts HTTP/3 on a UDP port, rebuilds each request as the
+//! `Request<Body>` a TCP connection would have produced and hands it to
+//! `handle_ingress`, so from the tunnel's side nothing changes. Ingress
+//! responses over TCP carry `Alt-Svc` pointing browsers here.
+
+use crate::routes::{ingress, AppState};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, HeaderValue, Request, Response, StatusCode, Uri},
+};
+use axum_extra::extract::Host;
+use bytes::{Buf, Bytes};
+use h3::error::{Code, StreamError};
+use http_body_util::BodyExt;
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// How long browsers may remember the `Alt-Svc` advertisement
+const ALT_SVC_MAX_AGE_SECS: u64 = 86400;
+
+/// Connection-specific headers, which HTTP/3 messages must not carry
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+type SendStream = h3::server::RequestStream<h3_quinn::SendStream<Bytes>, Bytes>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Http3Error {
+    #[error("Failed to read {path}: {source}")]
+    Pem {
+        path: String,
+        #[source]
+        source: rustls::pki_types::pem::Error,
+    },
+
+    #[error("Invalid HTTP/3 TLS config: {0}")]
+    Tls(#[from] rustls::Error),
+
+    #[error("TLS config has no cipher suite QUIC can use: {0}")]
+    Quic(#[from] quinn::crypto::rustls::NoInitialCipherSuite),
+
+    #[error("Failed to open the HTTP/3 socket: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Open the QUIC endpoint, failing on an unreadable certificate or key
+pub fn bind(addr: SocketAddr, cert_file: &str, key_file: &str) -> Result<quinn::Endpoint, Http3Error> {
+    let pem_error = |path: &str| {
+        let path = path.to_string();
+        move |source| Http3Error::Pem { path, source }
+    };
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(pem_error(cert_file))?;
+    let key = PrivateKeyDer::from_pem_file(key_file).map_err(pem_error(key_file))?;
+
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)?;
+    let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    Ok(quinn::Endpoint::server(config, addr)?)
+}
+
+/// Point the visitor at HTTP/3 on `port`. Whatever `Alt-Svc` the tunnel
+/// sent is about its own localhost, so it's replaced.
+pub fn advertise(mut response: Response<Body>, port: u16) -> Response<Body> {
+    response.headers_mut().insert(header::ALT_SVC, alt_svc(port));
+    response
+}
+
+fn alt_svc(port: u16) -> HeaderValue {
+    HeaderValue::from_str(&format!("h3=\":{}\"; ma={}", port, ALT_SVC_MAX_AGE_SECS))
+        .expect("Alt-Svc value is always valid")
+}
+
+/// Accept connections until the endpoint is closed
+pub async fn serve(endpoint: quinn::Endpoint, state: AppState) -> std::io::Result<()> {
+    while let Some(incoming) = endpoint.accept().await {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let remote = incoming.remote_address();
+            match incoming.await {
+                Ok(connection) => serve_connection(connection, state).await,
+                Err(e) => tracing::debug!("QUIC handshake with {} failed: {}", remote, e),
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn serve_connection(connection: quinn::Connection, state: AppState) {
+    let addr = connection.remote_address();
+    let mut connection = match h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection)).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            tracing::debug!("HTTP/3 setup with {} failed: {}", addr, e);
+            return;
+        }
+    };
+
+    loop {
+        let resolver = match connection.accept().await {
+            Ok(Some(resolver)) => resolver,
+            Ok(None) => return,
+            Err(e) => {
+                if !e.is_h3_no_error() {
+                    tracing::debug!("HTTP/3 connection from {} closed: {}", addr, e);
+                }
+                return;
+            }
+        };
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            let (request, stream) = match resolver.resolve_request().await {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    tracing::debug!("Bad HTTP/3 request from {}: {}", addr, e);
+                    return;
+                }
+            };
+            let (send, mut recv) = stream.split();
+
+            let body = async_stream::stream! {
+                loop {
+                    match recv.recv_data().await {
+                        Ok(Some(mut chunk)) => yield Ok(chunk.copy_to_bytes(chunk.remaining())),
+                        Ok(None) => break,
+                        Err(e) => {
+                            yield Err(e);
+                            break;
+                        }
+                    }
+                }
+            };
+
+            let response = match ingress_request(request, Body::from_stream(body)) {
+                Some((host, request)) => {
+                    ingress::handle_ingress(State(state), Host(host), ConnectInfo(addr), request).await
+                }
+                None => Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("Missing host"))
+                    .unwrap(),
+            };
+            if let Err(e) = send_response(send, response).await {
+                tracing::debug!("HTTP/3 response to {} failed: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// The request as `handle_ingress` gets it over TCP: `:authority` becomes
+/// the Host header and the URI is origin-form. `None` without a host.
+pub(crate) fn ingress_request(request: Request<()>, body: Body) -> Option<(String, Request<Body>)> {
+    let (mut parts, ()) = request.into_parts();
+    let host = match parts.uri.authority() {
+        Some(authority) => authority.to_string(),
+        None => parts.headers.get(header::HOST)?.to_str().ok()?.to_string(),
+    };
+    // Host leads, as it does on an HTTP/1.1 request line
+    let mut headers = HeaderMap::with_capacity(parts.headers.len() + 1);
+    headers.insert(header::HOST, HeaderValue::from_str(&host).ok()?);
+    for (name, value) in parts.headers.iter().filter(|(name, _)| **name != header::HOST) {
+        headers.append(name, value.clone());
+    }
+    parts.headers = headers;
+    parts.uri = parts
+        .uri
+        .path_and_query()
+        .map(|pq| Uri::from(pq.clone()))
+        .unwrap_or_else(|| Uri::from_static("/"));
+    Some((host, Request::from_parts(parts, body)))
+}
+
+async fn send_response(mut send: SendStream, response: Response<Body>) -> Result<(), StreamError> {
+    let (mut parts, mut body) = response.into_parts();
+    strip_connection_headers(&mut parts.headers);
+    send.send_response(Response::from_parts(parts, ())).await?;
+
+    while let Some(frame) = body.frame().await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                // Same as a TCP connection dropped mid-body: the visitor sees it cut short
+                tracing::debug!("Response body failed mid-stream: {}", e);
+                send.stop_stream(Code::H3_INTERNAL_ERROR);
+                return Ok(());
+            }
+        };
+        match frame.into_data() {
+            Ok(data) => send.send_data(data).await?,
+            Err(frame) => {
+                if let Ok(mut trailers) = frame.into_trailers() {
+                    strip_connection_headers(&mut trailers);
+                    return send.send_trailers(trailers).await;
+                }
+            }
+        }
+    }
+    send.finish().await
+}
+
+fn strip_connection_headers(headers: &mut HeaderMap) {
+    for name in CONNECTION_HEADERS {
+        headers.remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::{TunnelCommand, TunnelHandle};
+    use dvaar_common::HttpRequestPacket;
+    use std::sync::atomic::Ordering;
+    use tokio::sync::mpsc;
+
+    /// What a tunnel receives for `request`, without the random stream id
+    async fn tunnel_request(mut request: Request<Body>, host: &str) -> (HttpRequestPacket, Vec<u8>) {
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+        let handle = TunnelHandle::new(request_tx, "user-1".to_string());
+        handle.ready.store(true, Ordering::Release);
+
+        ingress::set_forwarded_headers(request.headers_mut(), "203.0.113.9".parse().unwrap(), host);
+        tokio::spawn(async move { ingress::forward_to_local_tunnel(&handle, request).await });
+
+        let mut packet = match request_rx.recv().await {
+            Some(TunnelCommand::Request(req)) => req.request,
+            other => panic!("expected Request, got {:?}", other),
+        };
+        packet.stream_id.clear();
+
+        let mut body = Vec::new();
+        loop {
+            match request_rx.recv().await {
+                Some(TunnelCommand::Data { data, .. }) => body.extend(data),
+                Some(TunnelCommand::End { .. }) => return (packet, body),
+                other => panic!("expected body, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_h3_request_matches_h1() {
+        let h1 = Request::builder()
+            .method("POST")
+            .uri("/search?q=dvaar")
+            .header("host", "myapp.dvaar.app")
+            .header("content-type", "application/json")
+            .header("accept", "application/json")
+            .body(Body::from("{\"page\":2}"))
+            .unwrap();
+
+        // HTTP/3 has no Host header and an absolute URI
+        let h3 = Request::builder()
+            .method("POST")
+            .uri("https://myapp.dvaar.app/search?q=dvaar")
+            .header("content-type", "application/json")
+            .header("accept", "application/json")
+            .body(())
+            .unwrap();
+        let (host, h3) = ingress_request(h3, Body::from("{\"page\":2}")).unwrap();
+        assert_eq!(host, "myapp.dvaar.app");
+
+        let from_h1 = tunnel_request(h1, "myapp.dvaar.app").await;
+        let from_h3 = tunnel_request(h3, &host).await;
+        assert_eq!(from_h3.0.uri, "/search?q=dvaar");
+        assert_eq!(from_h3.0.method, from_h1.0.method);
+        assert_eq!(from_h3.0.uri, from_h1.0.uri);
+        assert_eq!(from_h3.0.headers, from_h1.0.headers);
+        assert_eq!(from_h3.1, from_h1.1);
+
+        let no_host = Request::builder().uri("/").body(()).unwrap();
+        assert!(ingress_request(no_host, Body::empty()).is_none());
+    }
+
+    #[test]
+    fn test_alt_svc_and_connection_headers() {
+        let upstream = Response::builder()
+            .header("alt-svc", "h3=\":5173\"")
+            .body(Body::empty())
+            .unwrap();
+        let response = advertise(upstream, 443);
+        assert_eq!(response.headers()["alt-svc"], "h3=\":443\"; ma=86400");
+
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", HeaderValue::from_static("keep-alive"));
+        headers.insert("transfer-encoding", HeaderValue::from_static("chunked"));
+        headers.insert("content-type", HeaderValue::from_static("text/plain"));
+        strip_connection_headers(&mut headers);
+        assert_eq!(headers.len(), 1);
+    }
+}
@@ -8,11 +8,24 @@
 //! - Bandwidth metering
 
 mod abuse;
+mod access_log;
+mod access_rules;
+mod backpressure;
 mod config;
 mod db;
+#[cfg(feature = "http3")]
+mod http3;
+mod proxy_protocol;
 mod redis;
+mod response_cache;
+mod route_reconciler;
 mod routes;
 mod services;
+mod share_links;
+mod subdomain_names;
+mod throttle;
+#[cfg(unix)]
+mod unix_socket;
 
 use axum::{
     body::Body,
@@ -22,8 +35,9 @@ use axum::{
     routing::get,
     Router,
 };
+use axum::serve::ListenerExt;
 use axum_extra::extract::Host;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -58,47 +72,78 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("Connecting to Redis...");
     let redis_client = redis::init_client(&config.redis_url).await?;
 
-    // Create app state
-    let state = routes::AppState::new(config.clone(), db_pool, redis_client).await;
-
-    // Register this node in the cluster
-    let node_info = redis::NodeInfo {
-        node_id: config.node_ip.clone(),
-        ip: config.node_ip.clone(),
-        port: config.port,
-        region: std::env::var("NODE_REGION").ok(),
-        tunnel_count: 0,
-        max_tunnels: 1000, // TODO: Make configurable
+    // Compile operator-supplied blocklist patterns up front so a bad pattern fails startup
+    let blocklist = abuse::Blocklist::new(&config.blocked_subdomain_patterns)?;
+    if !blocklist.is_empty() {
+        tracing::info!("Loaded {} blocked subdomain patterns", blocklist.len());
+    }
+
+    // Likewise the wordlists and template for random subdomains
+    let subdomain_names = subdomain_names::SubdomainNames::load(
+        config.subdomain_adjectives_file.as_deref(),
+        config.subdomain_nouns_file.as_deref(),
+        &config.subdomain_template,
+    )?;
+    tracing::info!("Random subdomains: {} possible names", subdomain_names.combinations());
+
+    // Open the access log up front too, so an unwritable path fails startup
+    let access_log = match &config.access_log_path {
+        Some(path) => {
+            let file = access_log::RotatingFile::open(
+                path,
+                config.access_log_max_bytes,
+                config.access_log_max_files,
+            )?;
+            tracing::info!("Writing access log to {}", path);
+            Some(access_log::AccessLog::spawn(file))
+        }
+        None => None,
+    };
+
+    // The HTTP/3 certificate is checked at startup as well
+    #[cfg(feature = "http3")]
+    let http3_endpoint = match (config.http3_port, &config.http3_cert_file, &config.http3_key_file) {
+        (Some(port), Some(cert_file), Some(key_file)) => {
+            let addr = socket_addr(&config.host, port)?;
+            let endpoint = http3::bind(addr, cert_file, key_file)?;
+            tracing::info!("HTTP/3 listening on {} (udp)", addr);
+            Some(endpoint)
+        }
+        _ => None,
     };
-    if let Err(e) = state.route_manager.register_node(&config.node_ip, &node_info).await {
+    #[cfg(not(feature = "http3"))]
+    if config.http3_port.is_some() {
+        tracing::warn!("HTTP3_PORT is ignored: this build doesn't include the http3 feature");
+    }
+
+    // Create app state
+    let state = routes::AppState::new(
+        config.clone(),
+        db_pool,
+        redis_client,
+        blocklist,
+        subdomain_names,
+        access_log,
+    )
+    .await;
+
+    // Register this node in the cluster, then keep the registration alive
+    let node_info = redis::NodeInfo::for_this_node(&config, 0);
+    if let Err(e) = state.route_manager.register_node(&node_info.node_id, &node_info).await {
         tracing::warn!("Failed to register node: {}", e);
     } else {
-        tracing::info!("Node registered in cluster: {}", config.node_ip);
+        tracing::info!("Node registered in cluster: {}", node_info.node_id);
     }
-
-    // Spawn node heartbeat task
-    let state_clone = state.clone();
-    let node_ip = config.node_ip.clone();
-    tokio::spawn(async move {
-        let interval = std::time::Duration::from_secs(30);
-        loop {
-            tokio::time::sleep(interval).await;
-            let tunnel_count = state_clone.tunnels.len() as u32;
-            if let Err(e) = state_clone.route_manager.update_node_tunnels(&node_ip, tunnel_count).await {
-                tracing::warn!("Failed to update node tunnels: {}", e);
-            }
-            // Re-register to refresh TTL
-            let info = redis::NodeInfo {
-                node_id: node_ip.clone(),
-                ip: node_ip.clone(),
-                port: state_clone.config.port,
-                region: std::env::var("NODE_REGION").ok(),
-                tunnel_count,
-                max_tunnels: 1000,
-            };
-            let _ = state_clone.route_manager.register_node(&node_ip, &info).await;
-        }
-    });
+    let tunnels = state.tunnels.clone();
+    redis::spawn_node_heartbeat(
+        (*state.route_manager).clone(),
+        state.config.clone(),
+        move || tunnels.len() as u32,
+    );
+    // Put back any of our routes Redis loses, e.g. to a restart or flush
+    route_reconciler::spawn_route_reconciler(state.route_manager.clone(), state.tunnels.clone());
+    // Rate limits are counted in memory while Redis is down; sweep what's left
+    state.rate_limiter.spawn_fallback_cleanup();
 
     // Build main router (public port)
     let app = Router::new()
@@ -113,26 +158,72 @@ async fn main() -> anyhow::Result<()> {
         .layer(CorsLayer::permissive())
         .with_state(state.clone());
 
-    // Build internal router (for node-to-node communication)
+    // Build internal router (for node-to-node communication), closed to
+    // anything that doesn't carry the cluster secret
     let internal_app = Router::new()
         .merge(routes::proxy::router())
+        .layer(axum::middleware::from_fn_with_state(
+            std::sync::Arc::<str>::from(config.cluster_secret.as_str()),
+            routes::proxy::require_cluster_secret,
+        ))
         .with_state(state.clone());
 
     // Start servers
-    let public_addr: SocketAddr = format!("{}:{}", config.host, config.port).parse()?;
-    let internal_addr: SocketAddr = format!("{}:{}", config.host, config.internal_port).parse()?;
+    let public_addrs = public_addrs(&config.host, &config.public_ports)?;
+    let internal_addr = socket_addr(&config.host, config.internal_port)?;
 
-    tracing::info!("Public server listening on {}", public_addr);
+    match &config.unix_socket {
+        Some(path) => tracing::info!("Public server listening on {}", path),
+        None => {
+            for addr in &public_addrs {
+                tracing::info!("Public server listening on {}", addr);
+            }
+        }
+    }
     tracing::info!("Internal server listening on {}", internal_addr);
 
     // Run both servers
     let public_server = async {
-        let listener = tokio::net::TcpListener::bind(public_addr).await?;
-        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await
+        if let Some(path) = &config.unix_socket {
+            if config.proxy_protocol {
+                tracing::warn!("PROXY_PROTOCOL is ignored on a Unix socket");
+            }
+            #[cfg(unix)]
+            {
+                let app = app.into_make_service_with_connect_info::<SocketAddr>();
+                let listener = unix_socket::UnixSocketListener::bind(path)?;
+                return axum::serve(listener.tap_io(|_| {}), app).await;
+            }
+            #[cfg(not(unix))]
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("cannot listen on {}: Unix sockets are not supported here", path),
+            ));
+        }
+
+        let mut listeners = Vec::with_capacity(public_addrs.len());
+        for addr in &public_addrs {
+            listeners.push(bind_tcp(*addr, config.dual_stack)?);
+        }
+        if config.proxy_protocol {
+            tracing::info!("Expecting PROXY protocol v2 headers on the public ports");
+        }
+        serve_public(listeners, app, config.proxy_protocol).await
+    };
+
+    #[cfg(feature = "http3")]
+    let public_server = async {
+        match http3_endpoint {
+            Some(endpoint) => tokio::select! {
+                result = public_server => result,
+                result = http3::serve(endpoint, state.clone()) => result,
+            },
+            None => public_server.await,
+        }
     };
 
     let internal_server = async {
-        let listener = tokio::net::TcpListener::bind(internal_addr).await?;
+        let listener = bind_tcp(internal_addr, config.dual_stack)?;
         axum::serve(listener, internal_app.into_make_service_with_connect_info::<SocketAddr>()).await
     };
 
@@ -152,6 +243,61 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Addresses for the public listeners, one per configured port
+fn public_addrs(host: &str, ports: &[u16]) -> anyhow::Result<Vec<SocketAddr>> {
+    ports.iter().map(|port| socket_addr(host, *port)).collect()
+}
+
+/// `HOST` and a port as an address. IPv6 hosts may be bracketed or not.
+fn socket_addr(host: &str, port: u16) -> anyhow::Result<SocketAddr> {
+    let ip: IpAddr = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+        .parse()
+        .map_err(|_| anyhow::anyhow!("HOST must be an IP address, got {}", host))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Listen on `addr`. An IPv6 socket takes IPv4 connections as well when
+/// `dual_stack` is set, rather than leaving that to the OS default.
+fn bind_tcp(addr: SocketAddr, dual_stack: bool) -> std::io::Result<tokio::net::TcpListener> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    // As tokio's own bind does, so a restart doesn't wait out TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// Serve `app` on every listener. They share the router, and with it the
+/// state; if any of them fails the server stops.
+async fn serve_public(
+    listeners: Vec<tokio::net::TcpListener>,
+    app: Router,
+    proxy_protocol: bool,
+) -> std::io::Result<()> {
+    let servers = listeners.into_iter().map(|listener| {
+        let app = app.clone().into_make_service_with_connect_info::<SocketAddr>();
+        Box::pin(async move {
+            if proxy_protocol {
+                // axum derives ConnectInfo for custom listeners through `tap_io`
+                let listener = proxy_protocol::ProxyProtocolListener::new(listener)?.tap_io(|_| {});
+                axum::serve(listener, app).await
+            } else {
+                axum::serve(listener, app).await
+            }
+        })
+    });
+    let (result, _, _) = futures_util::future::select_all(servers).await;
+    result
+}
+
 /// Caddy on-demand TLS check - validates subdomain before cert provisioning
 async fn caddy_check(
     State(state): State<routes::AppState>,
@@ -195,6 +341,12 @@ async fn health_check(State(state): State<routes::AppState>) -> impl IntoRespons
         .unwrap_or("error");
 
     let tunnels = state.tunnels.len();
+    // Reported for visibility; a failing local app isn't the node's fault
+    let failing_upstreams = state
+        .tunnels
+        .iter()
+        .filter(|tunnel| tunnel.upstream.is_failing())
+        .count();
 
     let status = if db_status == "ok" && redis_status == "ok" {
         "healthy"
@@ -206,7 +358,9 @@ async fn health_check(State(state): State<routes::AppState>) -> impl IntoRespons
         "status": status,
         "db": db_status,
         "redis": redis_status,
-        "tunnels": tunnels
+        "redis_circuit_open": state.route_manager.redis_circuit_open(),
+        "tunnels": tunnels,
+        "failing_upstreams": failing_upstreams
     }))
 }
 
@@ -231,3 +385,71 @@ async fn handle_fallback(
     // Everything else (*.dvaar.app or custom domains) goes to ingress
     routes::ingress::handle_ingress(State(state), Host(host), ConnectInfo(addr), request).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[tokio::test]
+    async fn test_public_ports_share_one_router() {
+        let addrs = public_addrs("127.0.0.1", &[0, 0]).unwrap();
+        assert_eq!(addrs.len(), 2);
+        let mut listeners = Vec::new();
+        for addr in &addrs {
+            listeners.push(tokio::net::TcpListener::bind(addr).await.unwrap());
+        }
+        let ports: Vec<u16> = listeners.iter().map(|l| l.local_addr().unwrap().port()).collect();
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/hits",
+                get(|State(hits): State<Arc<AtomicUsize>>| async move {
+                    (hits.fetch_add(1, Ordering::SeqCst) + 1).to_string()
+                }),
+            )
+            .with_state(hits.clone());
+        tokio::spawn(serve_public(listeners, app, false));
+
+        // Each port sees the count left by the other
+        let client = reqwest::Client::new();
+        let mut counts = Vec::new();
+        for port in ports.iter().chain(&ports) {
+            let body = client
+                .get(format!("http://127.0.0.1:{}/hits", port))
+                .send()
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap();
+            counts.push(body);
+        }
+        assert_eq!(counts, ["1", "2", "3", "4"]);
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_host_binds_dual_stack() {
+        assert_eq!(socket_addr("::", 80).unwrap(), "[::]:80".parse().unwrap());
+        assert_eq!(socket_addr("[::1]", 80).unwrap(), "[::1]:80".parse().unwrap());
+        assert_eq!(socket_addr("0.0.0.0", 80).unwrap(), "0.0.0.0:80".parse().unwrap());
+        assert!(socket_addr("localhost", 80).is_err());
+
+        let connects = |port: u16| async move {
+            let v4 = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.is_ok();
+            let v6 = tokio::net::TcpStream::connect(("::1", port)).await.is_ok();
+            (v4, v6)
+        };
+
+        let dual = bind_tcp(socket_addr("::", 0).unwrap(), true).unwrap();
+        assert_eq!(connects(dual.local_addr().unwrap().port()).await, (true, true));
+
+        let v6_only = bind_tcp(socket_addr("::", 0).unwrap(), false).unwrap();
+        assert_eq!(connects(v6_only.local_addr().unwrap().port()).await, (false, true));
+    }
+}
@@ -0,0 +1,258 @@
+//! PROXY protocol v2 on the public listener
+//!
+//! Behind an L4 load balancer every connection comes from the balancer's IP.
+//! When the balancer is set to send a PROXY v2 header, this listener strips it
+//! and reports the client address it carries instead, so `ConnectInfo` (and
+//! everything keyed on it: loopback checks, rate limits, access logs) sees the
+//! real client. Connections without a header keep their peer address.
+
+use axum::serve::Listener;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// First 12 bytes of every v2 header
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Signature, version/command, family and address block length
+const FIXED_LEN: usize = 16;
+
+/// How long a new connection has to deliver its header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connections whose header has been read, waiting for `accept`
+const ACCEPT_QUEUE: usize = 128;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyHeaderError {
+    #[error("bad PROXY protocol signature")]
+    Signature,
+
+    #[error("unsupported PROXY protocol version {0}")]
+    Version(u8),
+
+    #[error("unknown PROXY protocol command {0}")]
+    Command(u8),
+
+    #[error("PROXY address block too short for its family")]
+    Truncated,
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// TCP listener that reads a PROXY v2 header off each connection.
+///
+/// Headers are read on a task per connection, so a client that connects and
+/// stalls can't hold up accepting everyone else.
+pub struct ProxyProtocolListener {
+    local_addr: SocketAddr,
+    rx: mpsc::Receiver<(TcpStream, SocketAddr)>,
+}
+
+impl ProxyProtocolListener {
+    pub fn new(listener: TcpListener) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, rx) = mpsc::channel(ACCEPT_QUEUE);
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, peer) = tokio::select! {
+                    _ = tx.closed() => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            tracing::warn!("Failed to accept connection: {}", e);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    },
+                };
+
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HEADER_TIMEOUT, read_proxy_header(&mut stream)).await {
+                        Ok(Ok(source)) => {
+                            let _ = tx.send((stream, source.unwrap_or(peer))).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!("Dropping connection from {}: {}", peer, e),
+                        Err(_) => tracing::debug!("Dropping connection from {}: no PROXY header in time", peer),
+                    }
+                });
+            }
+        });
+
+        Ok(Self { local_addr, rx })
+    }
+}
+
+impl Listener for ProxyProtocolListener {
+    type Io = TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.rx.recv().await {
+            Some(accepted) => accepted,
+            // The accept task only exits once we're dropped
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Read a v2 header off the front of `stream` if there is one. `Ok(None)`
+/// means the peer address should be used: no header, a LOCAL header (the
+/// balancer's own health checks), or a non-IP address family.
+async fn read_proxy_header(stream: &mut TcpStream) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    // HTTP and TLS never start with CR, so one byte tells whether a header follows
+    let mut first = [0u8; 1];
+    if stream.peek(&mut first).await? == 0 || first[0] != SIGNATURE[0] {
+        return Ok(None);
+    }
+
+    let mut fixed = [0u8; FIXED_LEN];
+    stream.read_exact(&mut fixed).await?;
+    let block_len = parse_fixed(&fixed)?;
+
+    let mut block = vec![0u8; block_len];
+    stream.read_exact(&mut block).await?;
+    parse_source(&fixed, &block)
+}
+
+/// Validate the fixed part of a header and return the address block length
+fn parse_fixed(fixed: &[u8; FIXED_LEN]) -> Result<usize, ProxyHeaderError> {
+    if fixed[..12] != SIGNATURE {
+        return Err(ProxyHeaderError::Signature);
+    }
+    let version = fixed[12] >> 4;
+    if version != 2 {
+        return Err(ProxyHeaderError::Version(version));
+    }
+    let command = fixed[12] & 0x0f;
+    if command > 1 {
+        return Err(ProxyHeaderError::Command(command));
+    }
+    Ok(u16::from_be_bytes([fixed[14], fixed[15]]) as usize)
+}
+
+/// Source address from the address block. Anything after the addresses
+/// (TLVs) is ignored.
+fn parse_source(fixed: &[u8; FIXED_LEN], block: &[u8]) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    // LOCAL
+    if fixed[12] & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    match fixed[13] >> 4 {
+        // AF_INET: src addr, dst addr, src port, dst port
+        0x1 => {
+            if block.len() < 12 {
+                return Err(ProxyHeaderError::Truncated);
+            }
+            let ip = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            let port = u16::from_be_bytes([block[8], block[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6
+        0x2 => {
+            if block.len() < 36 {
+                return Err(ProxyHeaderError::Truncated);
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&block[..16]);
+            let port = u16::from_be_bytes([block[32], block[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    /// PROXY TCP4 192.0.2.10:51234 -> 198.51.100.1:443
+    const TCP4_HEADER: [u8; 28] = [
+        0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a, // signature
+        0x21, 0x11, 0x00, 0x0c, // v2 PROXY, TCP over IPv4, 12 bytes
+        0xc0, 0x00, 0x02, 0x0a, 0xc6, 0x33, 0x64, 0x01, // addresses
+        0xc8, 0x22, 0x01, 0xbb, // ports
+    ];
+
+    fn split(header: &[u8]) -> ([u8; FIXED_LEN], &[u8]) {
+        let mut fixed = [0u8; FIXED_LEN];
+        fixed.copy_from_slice(&header[..FIXED_LEN]);
+        (fixed, &header[FIXED_LEN..])
+    }
+
+    #[test]
+    fn test_parses_known_header() {
+        let (fixed, block) = split(&TCP4_HEADER);
+        assert_eq!(parse_fixed(&fixed).unwrap(), 12);
+        assert_eq!(
+            parse_source(&fixed, block).unwrap(),
+            Some("192.0.2.10:51234".parse().unwrap())
+        );
+
+        // TCP over IPv6, with a trailing TLV
+        let mut header = SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x21, 0x00, 0x28]);
+        header.extend_from_slice(&"2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+        header.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        header.extend_from_slice(&[0x1f, 0x90, 0x01, 0xbb]);
+        header.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let (fixed, block) = split(&header);
+        assert_eq!(parse_fixed(&fixed).unwrap(), 40);
+        assert_eq!(
+            parse_source(&fixed, block).unwrap(),
+            Some("[2001:db8::7]:8080".parse().unwrap())
+        );
+
+        // LOCAL: keep the peer address
+        let mut fixed = fixed;
+        fixed[12] = 0x20;
+        assert_eq!(parse_source(&fixed, block).unwrap(), None);
+
+        // Version 1 binary header is rejected
+        fixed[12] = 0x11;
+        assert!(matches!(parse_fixed(&fixed), Err(ProxyHeaderError::Version(1))));
+    }
+
+    async fn accept_one(prefix: &[u8]) -> (SocketAddr, SocketAddr, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut listener = ProxyProtocolListener::new(listener).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        let mut sent = prefix.to_vec();
+        sent.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        client.write_all(&sent).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let (mut stream, addr) = listener.accept().await;
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        (addr, client_addr, received)
+    }
+
+    #[tokio::test]
+    async fn test_listener_reports_header_source() {
+        let (addr, _, received) = accept_one(&TCP4_HEADER).await;
+        assert_eq!(addr, "192.0.2.10:51234".parse().unwrap());
+        assert_eq!(received, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_listener_falls_back_without_header() {
+        let (addr, client_addr, received) = accept_one(b"").await;
+        assert_eq!(addr, client_addr);
+        assert_eq!(received, b"GET / HTTP/1.1\r\n\r\n");
+    }
+}
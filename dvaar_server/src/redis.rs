@@ -1,10 +1,12 @@
 //! Redis connection and operations for routing
 
+use crate::config::Config;
 use dashmap::DashMap;
-use dvaar_common::{constants, RouteInfo};
+use dvaar_common::heartbeat::{spawn_heartbeat, spawn_interval_task};
+use dvaar_common::{constants, RouteInfo, UpstreamMetrics};
 use fred::clients::Client;
 use fred::interfaces::*;
-use fred::types::{config::Config as RedisConfig, Expiration};
+use fred::types::{config::Config as RedisConfig, ExpireOptions, Expiration};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -25,11 +27,144 @@ struct CacheEntry {
 /// Cache TTL - routes are cached locally for 5 seconds
 const ROUTE_CACHE_TTL: Duration = Duration::from_secs(5);
 
-/// Redis operations for route management
+/// Consecutive Redis failures before calls are short-circuited
+const BREAKER_FAILURE_THRESHOLD: u32 = 5;
+
+/// How long the circuit stays open before one call is let through to probe Redis
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// Sets the route at KEYS[1] to ARGV[1] with a TTL of ARGV[3] seconds unless
+/// it's held by a user other than ARGV[2]; returns 1 if it was set
+const CLAIM_ROUTE_SCRIPT: &str = r#"
+local held = redis.call('GET', KEYS[1])
+if held then
+  local ok, route = pcall(cjson.decode, held)
+  if not ok or route.user_id ~= ARGV[2] then return 0 end
+end
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[3])
+return 1
+"#;
+
+/// Deletes the route at KEYS[1], and the maintenance flag at KEYS[2] that
+/// goes with it, only if connection ARGV[1] still holds the route
+const RELEASE_ROUTE_SCRIPT: &str = r#"
+local held = redis.call('GET', KEYS[1])
+if held then
+  local ok, route = pcall(cjson.decode, held)
+  if ok and route.connection_id == ARGV[1] then
+    redis.call('DEL', KEYS[2])
+    return redis.call('DEL', KEYS[1])
+  end
+end
+return 0
+"#;
+
+/// Returned instead of calling Redis while the circuit is open
+#[derive(Debug, thiserror::Error)]
+#[error("Redis is unavailable (circuit open)")]
+pub struct RedisUnavailable;
+
+/// Stops hammering a Redis that keeps failing. After
+/// `BREAKER_FAILURE_THRESHOLD` failures in a row calls fail straight away
+/// with `RedisUnavailable`; once per cooldown one call goes through, and
+/// its success closes the circuit again.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: std::sync::Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    /// While open, when the next probe may go through
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: std::sync::Mutex::new(BreakerState::default()),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state.lock().unwrap().open_until.is_some()
+    }
+
+    /// Run a Redis call unless the circuit is open, recording how it went
+    pub async fn call<T, F>(&self, call: F) -> anyhow::Result<T>
+    where
+        F: std::future::Future<Output = anyhow::Result<T>>,
+    {
+        if !self.allow() {
+            return Err(RedisUnavailable.into());
+        }
+        let result = call.await;
+        self.record(result.is_ok());
+        result
+    }
+
+    fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            None => true,
+            Some(until) if Instant::now() >= until => {
+                // Let this one probe; the rest wait out another cooldown
+                state.open_until = Some(Instant::now() + self.cooldown);
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    fn record(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        if success {
+            if state.open_until.is_some() {
+                tracing::info!("Redis is reachable again, closing the circuit");
+            }
+            *state = BreakerState::default();
+            return;
+        }
+        state.failures += 1;
+        if state.failures >= self.threshold && state.open_until.is_none() {
+            tracing::warn!(
+                "Redis failed {} times in a row, pausing calls for {:?}",
+                state.failures,
+                self.cooldown
+            );
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+/// Tunnel traffic by direction: ingress carries requests from visitors to the
+/// upstream, egress carries responses back to them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct ByteUsage {
+    pub ingress: u64,
+    pub egress: u64,
+}
+
+impl ByteUsage {
+    /// Both directions together, as counted against the plan's bandwidth
+    pub fn total(&self) -> u64 {
+        self.ingress + self.egress
+    }
+}
+
+/// Redis operations for route management. Clones share the route cache and
+/// circuit breaker.
+#[derive(Clone)]
 pub struct RouteManager {
     client: Client,
     /// Local cache for route lookups to reduce Redis hits on hot path
     route_cache: Arc<DashMap<String, CacheEntry>>,
+    breaker: Arc<CircuitBreaker>,
 }
 
 impl RouteManager {
@@ -37,9 +172,20 @@ impl RouteManager {
         Self {
             client,
             route_cache: Arc::new(DashMap::new()),
+            breaker: Arc::new(CircuitBreaker::new(BREAKER_FAILURE_THRESHOLD, BREAKER_COOLDOWN)),
         }
     }
 
+    /// The breaker guarding this client, for other users of the same Redis
+    pub fn breaker(&self) -> Arc<CircuitBreaker> {
+        self.breaker.clone()
+    }
+
+    /// Whether Redis calls are currently being short-circuited
+    pub fn redis_circuit_open(&self) -> bool {
+        self.breaker.is_open()
+    }
+
     /// Ping Redis to check connection
     pub async fn ping(&self) -> anyhow::Result<()> {
         self.client.ping::<()>(None).await?;
@@ -52,20 +198,59 @@ impl RouteManager {
         subdomain: &str,
         route_info: &RouteInfo,
     ) -> anyhow::Result<()> {
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::ROUTE_PREFIX, subdomain);
+                let value = route_info.to_json()?;
+
+                self.client
+                    .set::<(), _, _>(
+                        &key,
+                        value,
+                        Some(Expiration::EX(constants::ROUTE_TTL_SECONDS as i64)),
+                        None,
+                        false,
+                    )
+                    .await?;
+
+                // Update local cache
+                self.route_cache.insert(
+                    subdomain.to_string(),
+                    CacheEntry {
+                        route: route_info.clone(),
+                        cached_at: Instant::now(),
+                    },
+                );
+
+                Ok(())
+            })
+            .await
+    }
+
+    /// Register a route for a subdomain nobody else holds, returning whether
+    /// it was registered. The check and the write run as one script, so of
+    /// two users racing for a free name exactly one gets it. A route of the
+    /// same user's, left by a connection that hasn't been cleaned up yet, is
+    /// taken over.
+    pub async fn claim_route(&self, subdomain: &str, route_info: &RouteInfo) -> anyhow::Result<bool> {
         let key = format!("{}{}", constants::ROUTE_PREFIX, subdomain);
         let value = route_info.to_json()?;
-
-        self.client
-            .set::<(), _, _>(
-                &key,
-                value,
-                Some(Expiration::EX(constants::ROUTE_TTL_SECONDS as i64)),
-                None,
-                false,
-            )
+        let claimed: i64 = self
+            .breaker
+            .call(async {
+                Ok(self
+                    .client
+                    .eval(
+                        CLAIM_ROUTE_SCRIPT,
+                        vec![key],
+                        vec![value, route_info.user_id.clone(), constants::ROUTE_TTL_SECONDS.to_string()],
+                    )
+                    .await?)
+            })
             .await?;
-
-        // Update local cache
+        if claimed == 0 {
+            return Ok(false);
+        }
         self.route_cache.insert(
             subdomain.to_string(),
             CacheEntry {
@@ -73,8 +258,7 @@ impl RouteManager {
                 cached_at: Instant::now(),
             },
         );
-
-        Ok(())
+        Ok(true)
     }
 
     /// Get route info for a subdomain (with local caching)
@@ -91,7 +275,7 @@ impl RouteManager {
 
         // Fetch from Redis
         let key = format!("{}{}", constants::ROUTE_PREFIX, subdomain);
-        let value: Option<String> = self.client.get(&key).await?;
+        let value: Option<String> = self.breaker.call(async { Ok(self.client.get(&key).await?) }).await?;
 
         match value {
             Some(json) => {
@@ -110,53 +294,150 @@ impl RouteManager {
         }
     }
 
-    /// Remove a route (on disconnect)
-    pub async fn remove_route(&self, subdomain: &str) -> anyhow::Result<()> {
-        let key = format!("{}{}", constants::ROUTE_PREFIX, subdomain);
-        self.client.del::<i64, _>(&key).await?;
-        // Invalidate local cache
+    /// Which of `subdomains` have no route, checked in one round trip
+    pub async fn missing_routes(&self, subdomains: &[String]) -> anyhow::Result<Vec<String>> {
+        if subdomains.is_empty() {
+            return Ok(Vec::new());
+        }
+        let keys: Vec<String> = subdomains
+            .iter()
+            .map(|subdomain| format!("{}{}", constants::ROUTE_PREFIX, subdomain))
+            .collect();
+        let values: Vec<Option<String>> = self.breaker.call(async { Ok(self.client.mget(keys).await?) }).await?;
+        Ok(subdomains
+            .iter()
+            .zip(values)
+            .filter(|(_, value)| value.is_none())
+            .map(|(subdomain, _)| subdomain.clone())
+            .collect())
+    }
+
+    /// Remove a route on disconnect, along with its maintenance flag, unless
+    /// another connection has taken it over since `connection_id` registered it
+    pub async fn release_route(&self, subdomain: &str, connection_id: &str) -> anyhow::Result<()> {
+        // Invalidate local cache, even if Redis can't be reached
         self.route_cache.remove(subdomain);
-        Ok(())
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::ROUTE_PREFIX, subdomain);
+                let maintenance_key = format!("{}{}", constants::MAINTENANCE_PREFIX, subdomain);
+                self.client
+                    .eval::<i64, _, _, _>(
+                        RELEASE_ROUTE_SCRIPT,
+                        vec![key, maintenance_key],
+                        vec![connection_id.to_string()],
+                    )
+                    .await?;
+                Ok(())
+            })
+            .await
     }
 
-    /// Refresh route TTL (heartbeat)
+    /// Refresh route TTL (heartbeat), and that of its maintenance flag and
+    /// offline page. A reserved subdomain's longer-lived page isn't cut short.
     pub async fn refresh_route(&self, subdomain: &str) -> anyhow::Result<bool> {
-        let key = format!("{}{}", constants::ROUTE_PREFIX, subdomain);
-        let result: bool = self
-            .client
-            .expire(&key, constants::ROUTE_TTL_SECONDS as i64, None)
-            .await?;
-        Ok(result)
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::ROUTE_PREFIX, subdomain);
+                let result: bool = self
+                    .client
+                    .expire(&key, constants::ROUTE_TTL_SECONDS as i64, None)
+                    .await?;
+                let maintenance_key = format!("{}{}", constants::MAINTENANCE_PREFIX, subdomain);
+                self.client
+                    .expire::<bool, _>(&maintenance_key, constants::ROUTE_TTL_SECONDS as i64, None)
+                    .await?;
+                let offline_page_key = format!("{}{}", constants::OFFLINE_PAGE_PREFIX, subdomain);
+                self.client
+                    .expire::<bool, _>(
+                        &offline_page_key,
+                        constants::ROUTE_TTL_SECONDS as i64,
+                        Some(ExpireOptions::GT),
+                    )
+                    .await?;
+                Ok(result)
+            })
+            .await
     }
 
-    /// Increment bandwidth usage for a user with a desired TTL (seconds)
-    pub async fn increment_usage(&self, user_id: &str, bytes: u64, ttl_secs: i64) -> anyhow::Result<u64> {
-        let key = format!("{}{}", constants::USAGE_PREFIX, user_id);
-        let result: i64 = self.client.incr_by(&key, bytes as i64).await?;
+    /// Add tunnel traffic to a user's usage with a desired TTL (seconds),
+    /// returning the new total
+    pub async fn increment_usage(&self, user_id: &str, usage: ByteUsage, ttl_secs: i64) -> anyhow::Result<u64> {
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::USAGE_PREFIX, user_id);
+                let result: i64 = self.client.incr_by(&key, usage.total() as i64).await?;
+                self.refresh_usage_ttl(&key, ttl_secs).await?;
+
+                for (prefix, bytes) in [
+                    (constants::USAGE_INGRESS_PREFIX, usage.ingress),
+                    (constants::USAGE_EGRESS_PREFIX, usage.egress),
+                ] {
+                    let key = format!("{}{}", prefix, user_id);
+                    self.client.incr_by::<i64, _>(&key, bytes as i64).await?;
+                    self.refresh_usage_ttl(&key, ttl_secs).await?;
+                }
 
-        if ttl_secs > 0 {
-            let ttl: i64 = self.client.ttl(&key).await.unwrap_or(-2);
-            let drift_allowance_secs: i64 = 60;
+                Ok(result as u64)
+            })
+            .await
+    }
 
-            if ttl < 0 || ttl > ttl_secs + drift_allowance_secs || ttl_secs - ttl > drift_allowance_secs {
-                self.client.expire::<(), _>(&key, ttl_secs, None).await?;
-            }
+    /// Keep a usage key expiring with the billing period, within a minute's drift
+    async fn refresh_usage_ttl(&self, key: &str, ttl_secs: i64) -> anyhow::Result<()> {
+        if ttl_secs <= 0 {
+            return Ok(());
         }
+        let ttl: i64 = self.client.ttl(key).await.unwrap_or(-2);
+        let drift_allowance_secs: i64 = 60;
 
-        Ok(result as u64)
+        if ttl < 0 || ttl > ttl_secs + drift_allowance_secs || ttl_secs - ttl > drift_allowance_secs {
+            self.client.expire::<(), _>(key, ttl_secs, None).await?;
+        }
+        Ok(())
     }
 
     /// Get bandwidth usage for a user
     pub async fn get_usage(&self, user_id: &str) -> anyhow::Result<u64> {
-        let key = format!("{}{}", constants::USAGE_PREFIX, user_id);
-        let value: Option<i64> = self.client.get(&key).await?;
-        Ok(value.unwrap_or(0) as u64)
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::USAGE_PREFIX, user_id);
+                let value: Option<i64> = self.client.get(&key).await?;
+                Ok(value.unwrap_or(0) as u64)
+            })
+            .await
+    }
+
+    /// Get a user's usage split into request and response bytes
+    pub async fn get_usage_by_direction(&self, user_id: &str) -> anyhow::Result<ByteUsage> {
+        self.breaker
+            .call(async {
+                let ingress: Option<i64> = self
+                    .client
+                    .get(format!("{}{}", constants::USAGE_INGRESS_PREFIX, user_id))
+                    .await?;
+                let egress: Option<i64> = self
+                    .client
+                    .get(format!("{}{}", constants::USAGE_EGRESS_PREFIX, user_id))
+                    .await?;
+                Ok(ByteUsage {
+                    ingress: ingress.unwrap_or(0) as u64,
+                    egress: egress.unwrap_or(0) as u64,
+                })
+            })
+            .await
     }
 
     /// Reset bandwidth usage (e.g., monthly reset)
     pub async fn reset_usage(&self, user_id: &str) -> anyhow::Result<()> {
-        let key = format!("{}{}", constants::USAGE_PREFIX, user_id);
-        self.client.del::<i64, _>(&key).await?;
+        for prefix in [
+            constants::USAGE_PREFIX,
+            constants::USAGE_INGRESS_PREFIX,
+            constants::USAGE_EGRESS_PREFIX,
+        ] {
+            let key = format!("{}{}", prefix, user_id);
+            self.client.del::<i64, _>(&key).await?;
+        }
         Ok(())
     }
 
@@ -200,6 +481,126 @@ impl RouteManager {
         Ok(value)
     }
 
+    /// Store the offline page for a subdomain, kept for `ttl_secs` (refreshed
+    /// each time its tunnel connects)
+    pub async fn set_offline_page(&self, subdomain: &str, html: &str, ttl_secs: i64) -> anyhow::Result<()> {
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::OFFLINE_PAGE_PREFIX, subdomain);
+                self.client
+                    .set::<(), _, _>(
+                        &key,
+                        html,
+                        Some(Expiration::EX(ttl_secs)),
+                        None,
+                        false,
+                    )
+                    .await?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Remove a subdomain's offline page
+    pub async fn clear_offline_page(&self, subdomain: &str) -> anyhow::Result<()> {
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::OFFLINE_PAGE_PREFIX, subdomain);
+                self.client.del::<(), _>(&key).await?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Get a subdomain's offline page, if one was registered
+    pub async fn get_offline_page(&self, subdomain: &str) -> anyhow::Result<Option<String>> {
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::OFFLINE_PAGE_PREFIX, subdomain);
+                let value: Option<String> = self.client.get(&key).await?;
+                Ok(value)
+            })
+            .await
+    }
+
+    /// Put a subdomain into maintenance, answering with `page` until cleared.
+    /// The flag lives as long as the route: it has the route's TTL, is
+    /// refreshed with it, and is removed when the route is released.
+    pub async fn set_maintenance(&self, subdomain: &str, page: &str) -> anyhow::Result<()> {
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::MAINTENANCE_PREFIX, subdomain);
+                self.client
+                    .set::<(), _, _>(
+                        &key,
+                        page,
+                        Some(Expiration::EX(constants::ROUTE_TTL_SECONDS as i64)),
+                        None,
+                        false,
+                    )
+                    .await?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Take a subdomain out of maintenance
+    pub async fn clear_maintenance(&self, subdomain: &str) -> anyhow::Result<()> {
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::MAINTENANCE_PREFIX, subdomain);
+                self.client.del::<(), _>(&key).await?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// The page a subdomain in maintenance answers with, if it is in maintenance
+    pub async fn get_maintenance(&self, subdomain: &str) -> anyhow::Result<Option<String>> {
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::MAINTENANCE_PREFIX, subdomain);
+                let value: Option<String> = self.client.get(&key).await?;
+                Ok(value)
+            })
+            .await
+    }
+
+    /// Store the latest upstream metrics for a subdomain, kept for a few report intervals
+    pub async fn set_upstream_metrics(
+        &self,
+        subdomain: &str,
+        metrics: &UpstreamMetrics,
+    ) -> anyhow::Result<()> {
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::UPSTREAM_METRICS_PREFIX, subdomain);
+                let value = serde_json::to_string(metrics)?;
+                self.client
+                    .set::<(), _, _>(
+                        &key,
+                        value,
+                        Some(Expiration::EX(3 * constants::METRICS_INTERVAL_SECONDS as i64)),
+                        None,
+                        false,
+                    )
+                    .await?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Get the latest upstream metrics for a subdomain, if any are recent
+    pub async fn get_upstream_metrics(&self, subdomain: &str) -> anyhow::Result<Option<UpstreamMetrics>> {
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::UPSTREAM_METRICS_PREFIX, subdomain);
+                let value: Option<String> = self.client.get(&key).await?;
+                Ok(value.and_then(|v| serde_json::from_str(&v).ok()))
+            })
+            .await
+    }
+
     /// Register this node in the cluster (uses individual keys with TTL per node)
     pub async fn register_node(&self, node_id: &str, node_info: &NodeInfo) -> anyhow::Result<()> {
         let key = format!("{}:{}", constants::NODE_PREFIX, node_id);
@@ -224,25 +625,29 @@ impl RouteManager {
 
     /// Update node's tunnel count
     pub async fn update_node_tunnels(&self, node_id: &str, tunnel_count: u32) -> anyhow::Result<()> {
-        let key = format!("{}:{}", constants::NODE_PREFIX, node_id);
-
-        if let Some(json) = self.client.get::<Option<String>, _>(&key).await? {
-            if let Ok(mut info) = serde_json::from_str::<NodeInfo>(&json) {
-                info.tunnel_count = tunnel_count;
-                let value = serde_json::to_string(&info)?;
-                // Refresh TTL on update
-                self.client
-                    .set::<(), _, _>(
-                        &key,
-                        value,
-                        Some(Expiration::EX(constants::NODE_TTL_SECONDS as i64)),
-                        None,
-                        false,
-                    )
-                    .await?;
-            }
-        }
-        Ok(())
+        self.breaker
+            .call(async {
+                let key = format!("{}:{}", constants::NODE_PREFIX, node_id);
+
+                if let Some(json) = self.client.get::<Option<String>, _>(&key).await? {
+                    if let Ok(mut info) = serde_json::from_str::<NodeInfo>(&json) {
+                        info.tunnel_count = tunnel_count;
+                        let value = serde_json::to_string(&info)?;
+                        // Refresh TTL on update
+                        self.client
+                            .set::<(), _, _>(
+                                &key,
+                                value,
+                                Some(Expiration::EX(constants::NODE_TTL_SECONDS as i64)),
+                                None,
+                                false,
+                            )
+                            .await?;
+                    }
+                }
+                Ok(())
+            })
+            .await
     }
 
     /// Get all registered nodes
@@ -250,20 +655,13 @@ impl RouteManager {
         // Get all node IDs from the set
         let node_ids: Vec<String> = self.client.smembers("nodes:active").await?;
 
-        let mut nodes = Vec::new();
-        let mut stale_nodes = Vec::new();
-
+        let mut entries = Vec::with_capacity(node_ids.len());
         for node_id in node_ids {
             let key = format!("{}:{}", constants::NODE_PREFIX, node_id);
-            if let Some(json) = self.client.get::<Option<String>, _>(&key).await? {
-                if let Ok(info) = serde_json::from_str::<NodeInfo>(&json) {
-                    nodes.push(info);
-                }
-            } else {
-                // Node key expired but still in set - mark for cleanup
-                stale_nodes.push(node_id);
-            }
+            let json = self.client.get::<Option<String>, _>(&key).await?;
+            entries.push((node_id, json));
         }
+        let (nodes, stale_nodes) = live_nodes(entries);
 
         // Clean up stale nodes from the set
         for stale_id in stale_nodes {
@@ -285,55 +683,86 @@ impl RouteManager {
     /// Each tunnel is tracked individually - stale tunnels auto-expire.
     /// Returns (current_count, was_allowed).
     pub async fn register_user_tunnel(&self, user_id: &str, subdomain: &str, limit: u32) -> anyhow::Result<(u32, bool)> {
-        let key = format!("{}{}", constants::USER_TUNNELS_PREFIX, user_id);
-        let now = chrono::Utc::now().timestamp();
-        let cutoff = now - constants::USER_TUNNELS_TTL_SECONDS;
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::USER_TUNNELS_PREFIX, user_id);
+                let now = chrono::Utc::now().timestamp();
+                let cutoff = now - constants::USER_TUNNELS_TTL_SECONDS;
 
-        // Remove stale tunnels (older than TTL)
-        self.client.zremrangebyscore::<(), _, _, _>(&key, f64::NEG_INFINITY, cutoff as f64).await?;
+                // Remove stale tunnels (older than TTL)
+                self.client.zremrangebyscore::<(), _, _, _>(&key, f64::NEG_INFINITY, cutoff as f64).await?;
 
-        // Check current count before adding
-        let current_count: i64 = self.client.zcard(&key).await?;
+                // Check current count before adding
+                let current_count: i64 = self.client.zcard(&key).await?;
 
-        if current_count >= limit as i64 {
-            return Ok((current_count as u32, false));
-        }
+                if current_count >= limit as i64 {
+                    return Ok((current_count as u32, false));
+                }
 
-        // Add this tunnel with current timestamp as score
-        self.client.zadd::<(), _, _>(&key, None, None, false, false, (now as f64, subdomain)).await?;
+                // Add this tunnel with current timestamp as score
+                self.client.zadd::<(), _, _>(&key, None, None, false, false, (now as f64, subdomain)).await?;
 
-        Ok((current_count as u32 + 1, true))
+                Ok((current_count as u32 + 1, true))
+            })
+            .await
     }
 
     /// Unregister a tunnel for a user
     pub async fn unregister_user_tunnel(&self, user_id: &str, subdomain: &str) -> anyhow::Result<()> {
-        let key = format!("{}{}", constants::USER_TUNNELS_PREFIX, user_id);
-        self.client.zrem::<(), _, _>(&key, subdomain).await?;
-        Ok(())
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::USER_TUNNELS_PREFIX, user_id);
+                self.client.zrem::<(), _, _>(&key, subdomain).await?;
+                Ok(())
+            })
+            .await
     }
 
     /// Refresh a tunnel's timestamp (called during heartbeat)
     pub async fn refresh_user_tunnel(&self, user_id: &str, subdomain: &str) -> anyhow::Result<()> {
-        let key = format!("{}{}", constants::USER_TUNNELS_PREFIX, user_id);
-        let now = chrono::Utc::now().timestamp();
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::USER_TUNNELS_PREFIX, user_id);
+                let now = chrono::Utc::now().timestamp();
+
+                // Update this tunnel's timestamp
+                self.client.zadd::<(), _, _>(&key, None, None, false, false, (now as f64, subdomain)).await?;
+                Ok(())
+            })
+            .await
+    }
 
-        // Update this tunnel's timestamp
-        self.client.zadd::<(), _, _>(&key, None, None, false, false, (now as f64, subdomain)).await?;
-        Ok(())
+    /// List the subdomains of a user's live tunnels (heartbeat within the TTL)
+    pub async fn list_user_tunnels(&self, user_id: &str) -> anyhow::Result<Vec<String>> {
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::USER_TUNNELS_PREFIX, user_id);
+                let cutoff = chrono::Utc::now().timestamp() - constants::USER_TUNNELS_TTL_SECONDS;
+                let subdomains: Vec<String> = self
+                    .client
+                    .zrangebyscore(&key, cutoff, "+inf", false, None)
+                    .await?;
+                Ok(subdomains)
+            })
+            .await
     }
 
     /// Get current tunnel count for a user (cleaning stale entries)
     pub async fn count_user_tunnels(&self, user_id: &str) -> anyhow::Result<u32> {
-        let key = format!("{}{}", constants::USER_TUNNELS_PREFIX, user_id);
-        let now = chrono::Utc::now().timestamp();
-        let cutoff = now - constants::USER_TUNNELS_TTL_SECONDS;
-
-        // Remove stale tunnels
-        self.client.zremrangebyscore::<(), _, _, _>(&key, f64::NEG_INFINITY, cutoff as f64).await?;
-
-        // Get count
-        let count: i64 = self.client.zcard(&key).await?;
-        Ok(count as u32)
+        self.breaker
+            .call(async {
+                let key = format!("{}{}", constants::USER_TUNNELS_PREFIX, user_id);
+                let now = chrono::Utc::now().timestamp();
+                let cutoff = now - constants::USER_TUNNELS_TTL_SECONDS;
+
+                // Remove stale tunnels
+                self.client.zremrangebyscore::<(), _, _, _>(&key, f64::NEG_INFINITY, cutoff as f64).await?;
+
+                // Get count
+                let count: i64 = self.client.zcard(&key).await?;
+                Ok(count as u32)
+            })
+            .await
     }
 }
 
@@ -346,40 +775,158 @@ pub struct NodeInfo {
     pub region: Option<String>,
     pub tunnel_count: u32,
     pub max_tunnels: u32,
+    /// Host tunnel clients can be redirected to
+    #[serde(default)]
+    pub public_host: Option<String>,
+}
+
+impl NodeInfo {
+    /// This node's registry entry, carrying `tunnel_count` tunnels
+    pub fn for_this_node(config: &Config, tunnel_count: u32) -> Self {
+        Self {
+            node_id: config.node_ip.clone(),
+            ip: config.node_ip.clone(),
+            port: config.port,
+            region: config.node_region.clone(),
+            tunnel_count,
+            max_tunnels: config.node_max_tunnels,
+            public_host: config.node_public_host.clone(),
+        }
+    }
 }
 
-/// Start a heartbeat task that refreshes a route and user tunnel timestamp periodically
-pub fn spawn_heartbeat(
+/// Split `(node_id, entry)` pairs read from the registry into the nodes that
+/// are still alive and the ids whose entry has expired (or never parsed)
+fn live_nodes(entries: Vec<(String, Option<String>)>) -> (Vec<NodeInfo>, Vec<String>) {
+    let mut nodes = Vec::new();
+    let mut stale = Vec::new();
+    for (node_id, json) in entries {
+        match json.as_deref().map(serde_json::from_str::<NodeInfo>) {
+            Some(Ok(info)) => nodes.push(info),
+            Some(Err(e)) => {
+                tracing::warn!("Ignoring unreadable registration for node {}: {}", node_id, e);
+            }
+            // Key expired but still in the set, so the node stopped heartbeating
+            None => stale.push(node_id),
+        }
+    }
+    (nodes, stale)
+}
+
+// A node must survive one missed heartbeat before its entry expires
+const _: () = assert!(constants::NODE_HEARTBEAT_INTERVAL_SECONDS * 2 < constants::NODE_TTL_SECONDS);
+
+/// Keep this node registered: refresh its entry (and TTL) with the current
+/// tunnel count every `NODE_HEARTBEAT_INTERVAL_SECONDS`. If the node dies the
+/// entry expires after `NODE_TTL_SECONDS` and drops out of `get_all_nodes`.
+pub fn spawn_node_heartbeat(
+    route_manager: RouteManager,
+    config: Arc<Config>,
+    tunnel_count: impl Fn() -> u32 + Send + 'static,
+) -> tokio::task::JoinHandle<()> {
+    let interval = Duration::from_secs(constants::NODE_HEARTBEAT_INTERVAL_SECONDS);
+    spawn_interval_task(interval, move || {
+        let route_manager = route_manager.clone();
+        let info = NodeInfo::for_this_node(&config, tunnel_count());
+        async move {
+            if let Err(e) = route_manager.register_node(&info.node_id, &info).await {
+                tracing::warn!("Failed to refresh node registration: {}", e);
+            }
+        }
+    })
+}
+
+/// Start a heartbeat task that refreshes a route and user tunnel timestamp
+/// every `HEARTBEAT_INTERVAL_SECONDS`, registering the route again if Redis
+/// lost it (or never had it, when Redis was down as the tunnel connected).
+/// It runs until the returned handle is aborted.
+pub fn spawn_route_heartbeat(
     route_manager: RouteManager,
     subdomain: String,
+    route_info: RouteInfo,
     user_id: String,
-    mut shutdown_rx: tokio::sync::watch::Receiver<bool>,
 ) -> tokio::task::JoinHandle<()> {
-    tokio::spawn(async move {
-        let interval = Duration::from_secs(constants::HEARTBEAT_INTERVAL_SECONDS);
-
-        loop {
-            tokio::select! {
-                _ = tokio::time::sleep(interval) => {
-                    // Refresh route TTL
-                    if let Err(e) = route_manager.refresh_route(&subdomain).await {
-                        tracing::error!("Failed to refresh route for {}: {}", subdomain, e);
-                    } else {
-                        tracing::debug!("Refreshed route for {}", subdomain);
-                    }
-
-                    // Refresh this tunnel's timestamp in the sorted set
-                    if let Err(e) = route_manager.refresh_user_tunnel(&user_id, &subdomain).await {
-                        tracing::error!("Failed to refresh tunnel timestamp for {}: {}", subdomain, e);
-                    }
-                }
-                _ = shutdown_rx.changed() => {
-                    if *shutdown_rx.borrow() {
-                        tracing::info!("Heartbeat task for {} shutting down", subdomain);
-                        break;
+    let (subdomain, route_info, user_id) = (Arc::new(subdomain), Arc::new(route_info), Arc::new(user_id));
+    spawn_heartbeat(move || {
+        let (route_manager, subdomain, route_info, user_id) =
+            (route_manager.clone(), subdomain.clone(), route_info.clone(), user_id.clone());
+        async move {
+            // Refresh route TTL
+            match route_manager.refresh_route(&subdomain).await {
+                Ok(true) => tracing::debug!("Refreshed route for {}", subdomain),
+                Ok(false) => {
+                    tracing::info!("Route for {} missing from Redis, registering it again", subdomain);
+                    if let Err(e) = route_manager.register_route(&subdomain, &route_info).await {
+                        tracing::error!("Failed to register route for {}: {}", subdomain, e);
                     }
                 }
+                Err(e) => tracing::error!("Failed to refresh route for {}: {}", subdomain, e),
+            }
+
+            // Refresh this tunnel's timestamp in the sorted set
+            if let Err(e) = route_manager.refresh_user_tunnel(&user_id, &subdomain).await {
+                tracing::error!("Failed to refresh tunnel timestamp for {}: {}", subdomain, e);
             }
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_breaker_opens_and_recovers() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(50));
+        let calls = std::sync::atomic::AtomicU32::new(0);
+        let failing = || async {
+            calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err::<(), _>(anyhow::anyhow!("connection refused"))
+        };
+
+        assert!(breaker.call(failing()).await.is_err());
+        assert!(!breaker.is_open());
+        assert!(breaker.call(failing()).await.is_err());
+        assert!(breaker.is_open());
+
+        // Open: fails without calling Redis
+        let err = breaker.call(failing()).await.unwrap_err();
+        assert!(err.is::<RedisUnavailable>());
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        // After the cooldown one probe goes through and closes it on success
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(breaker.call(async { Ok(7) }).await.unwrap(), 7);
+        assert!(!breaker.is_open());
+    }
+
+    fn node(node_id: &str, tunnel_count: u32) -> NodeInfo {
+        NodeInfo {
+            node_id: node_id.to_string(),
+            ip: node_id.to_string(),
+            port: 6000,
+            region: Some("in".to_string()),
+            tunnel_count,
+            max_tunnels: 500,
+            public_host: None,
+        }
+    }
+
+    #[test]
+    fn test_registered_nodes_are_listed_until_they_expire() {
+        let registered = serde_json::to_string(&node("10.0.0.1", 3)).unwrap();
+        let (nodes, stale) = live_nodes(vec![
+            ("10.0.0.1".to_string(), Some(registered)),
+            // Stopped heartbeating: its key hit the TTL but the id is still in the set
+            ("10.0.0.2".to_string(), None),
+            ("10.0.0.3".to_string(), Some("not json".to_string())),
+        ]);
+
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].node_id, "10.0.0.1");
+        assert_eq!(nodes[0].region.as_deref(), Some("in"));
+        assert_eq!(nodes[0].tunnel_count, 3);
+        assert_eq!(nodes[0].max_tunnels, 500);
+        assert_eq!(stale, ["10.0.0.2"]);
+    }
+}
@@ -0,0 +1,371 @@
+//! Opt-in cache for GET responses on the ingress path
+//!
+//! Static assets behind a tunnel get fetched over and over; each fetch crosses
+//! the tunnel to the user's machine and back. Responses the upstream marks as
+//! cacheable with an explicit `max-age` are kept in a small LRU and served from
+//! here until they expire. Anything that might be personalised (cookies,
+//! credentials, `Vary` beyond `Accept-Encoding`) is never cached.
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode},
+};
+use dvaar_common::constants;
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Response headers that describe the connection rather than the resource
+const HOP_BY_HOP: &[header::HeaderName] = &[header::CONNECTION, header::TRANSFER_ENCODING];
+
+struct CachedResponse {
+    headers: HeaderMap,
+    body: Bytes,
+    stored_at: Instant,
+    max_age: Duration,
+    last_used: u64,
+}
+
+impl CachedResponse {
+    fn age(&self) -> Duration {
+        self.stored_at.elapsed()
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.age() < self.max_age
+    }
+}
+
+struct Entries {
+    map: HashMap<String, CachedResponse>,
+    /// Bumped on every use; the entry with the smallest `last_used` is evicted
+    clock: u64,
+}
+
+pub struct ResponseCache {
+    entries: Mutex<Entries>,
+    capacity: usize,
+    max_body_bytes: usize,
+}
+
+impl std::fmt::Debug for ResponseCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponseCache")
+            .field("entries", &self.entries.lock().unwrap().map.len())
+            .field("capacity", &self.capacity)
+            .field("max_body_bytes", &self.max_body_bytes)
+            .finish()
+    }
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize, max_body_bytes: usize) -> Self {
+        Self {
+            entries: Mutex::new(Entries {
+                map: HashMap::new(),
+                clock: 0,
+            }),
+            capacity,
+            max_body_bytes,
+        }
+    }
+
+    /// Cache key for `request` to the tunnel connection `owner`, or `None`
+    /// if it must go to the upstream. The key includes the wildcard host, so
+    /// each host under a wildcard tunnel gets its own entries, and
+    /// `Accept-Encoding`, so a compressed body is only served to clients that
+    /// asked for it.
+    pub fn key(owner: &str, request: &Request<Body>) -> Option<String> {
+        if request.method() != Method::GET {
+            return None;
+        }
+        let headers = request.headers();
+        if headers.contains_key(header::AUTHORIZATION) || headers.contains_key(header::COOKIE) {
+            return None;
+        }
+        let bypass = header_directives(headers, header::CACHE_CONTROL)
+            .any(|d| d == "no-cache" || d == "no-store");
+        if bypass {
+            return None;
+        }
+
+        let header_str = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or("");
+        Some(format!(
+            "{} {} GET {} {}",
+            owner,
+            header_str(constants::WILDCARD_HOST_HEADER),
+            request.uri(),
+            header_str(header::ACCEPT_ENCODING.as_str())
+        ))
+    }
+
+    /// A fresh cached response for `key`, or a 304 if the client already has it
+    pub fn get(&self, key: &str, request_headers: &HeaderMap) -> Option<Response<Body>> {
+        let mut entries = self.entries.lock().unwrap();
+        let fresh = entries.map.get(key).map(CachedResponse::is_fresh)?;
+        if !fresh {
+            entries.map.remove(key);
+            return None;
+        }
+
+        entries.clock += 1;
+        let clock = entries.clock;
+        let cached = entries.map.get_mut(key)?;
+        cached.last_used = clock;
+
+        let not_modified = match (
+            cached.headers.get(header::ETAG),
+            request_headers.get(header::IF_NONE_MATCH),
+        ) {
+            (Some(etag), Some(if_none_match)) => if_none_match
+                .to_str()
+                .map(|v| v.split(',').any(|tag| tag.trim() == "*" || tag.trim().as_bytes() == etag.as_bytes()))
+                .unwrap_or(false),
+            _ => false,
+        };
+
+        let (status, body) = if not_modified {
+            (StatusCode::NOT_MODIFIED, Body::empty())
+        } else {
+            (StatusCode::OK, Body::from(cached.body.clone()))
+        };
+        let mut response = Response::new(body);
+        *response.status_mut() = status;
+        *response.headers_mut() = cached.headers.clone();
+        if not_modified {
+            response.headers_mut().remove(header::CONTENT_LENGTH);
+        }
+        response
+            .headers_mut()
+            .insert(header::AGE, HeaderValue::from(cached.age().as_secs()));
+        response
+            .headers_mut()
+            .insert("x-cache", HeaderValue::from_static("HIT"));
+        Some(response)
+    }
+
+    /// Pass `response` through, keeping a copy of its body for `key` if the
+    /// upstream allows it. The copy is stored only once the whole body has
+    /// streamed through without error.
+    pub fn store(self: &Arc<Self>, key: String, response: Response<Body>) -> Response<Body> {
+        if response.status() != StatusCode::OK {
+            return response;
+        }
+        let Some(max_age) = cacheable_max_age(response.headers()) else {
+            return response;
+        };
+
+        let (parts, body) = response.into_parts();
+        let mut headers = parts.headers.clone();
+        for name in HOP_BY_HOP {
+            headers.remove(name);
+        }
+
+        let cache = self.clone();
+        let mut upstream = body.into_data_stream();
+        let body = async_stream::stream! {
+            let mut copy = Some(Vec::new());
+            while let Some(chunk) = upstream.next().await {
+                match &chunk {
+                    Ok(bytes) => {
+                        if let Some(buf) = copy.as_mut() {
+                            if buf.len() + bytes.len() > cache.max_body_bytes {
+                                copy = None;
+                            } else {
+                                buf.extend_from_slice(bytes);
+                            }
+                        }
+                    }
+                    Err(_) => copy = None,
+                }
+                yield chunk;
+            }
+            if let Some(buf) = copy {
+                cache.insert(key, headers, buf.into(), max_age);
+            }
+        };
+
+        Response::from_parts(parts, Body::from_stream(body))
+    }
+
+    fn insert(&self, key: String, headers: HeaderMap, body: Bytes, max_age: Duration) {
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.map.contains_key(&key) && entries.map.len() >= self.capacity {
+            let oldest = entries
+                .map
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.map.remove(&oldest);
+            }
+        }
+
+        entries.clock += 1;
+        let last_used = entries.clock;
+        entries.map.insert(
+            key,
+            CachedResponse {
+                headers,
+                body,
+                stored_at: Instant::now(),
+                max_age,
+                last_used,
+            },
+        );
+    }
+}
+
+/// How long a response may be cached: only with an explicit positive
+/// `max-age` (or `s-maxage`), and never when it sets cookies, is private, or
+/// varies on anything but `Accept-Encoding`.
+fn cacheable_max_age(headers: &HeaderMap) -> Option<Duration> {
+    if headers.contains_key(header::SET_COOKIE) {
+        return None;
+    }
+    let varies = header_directives(headers, header::VARY).any(|v| v != "accept-encoding");
+    if varies {
+        return None;
+    }
+
+    let mut max_age = None;
+    let mut shared_max_age = None;
+    for directive in header_directives(headers, header::CACHE_CONTROL) {
+        match directive.split_once('=') {
+            Some(("max-age", secs)) => max_age = secs.trim_matches('"').parse().ok(),
+            Some(("s-maxage", secs)) => shared_max_age = secs.trim_matches('"').parse().ok(),
+            None if matches!(directive.as_str(), "no-store" | "no-cache" | "private") => return None,
+            _ => {}
+        }
+    }
+
+    shared_max_age
+        .or(max_age)
+        .filter(|secs: &u64| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// Comma-separated values of every `name` header, trimmed and lowercased
+fn header_directives(headers: &HeaderMap, name: header::HeaderName) -> impl Iterator<Item = String> + '_ {
+    headers
+        .get_all(name)
+        .into_iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim().to_ascii_lowercase())
+        .filter(|d| !d.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::builder()
+            .uri(uri)
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn upstream(cache_control: &str, body: &'static str) -> Response<Body> {
+        Response::builder()
+            .header(header::CACHE_CONTROL, cache_control)
+            .header(header::ETAG, "\"v1\"")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    /// Send `response` to the client in full, as hyper would
+    async fn drain(response: Response<Body>) -> Bytes {
+        axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit() {
+        let cache = Arc::new(ResponseCache::new(8, 1024));
+        let key = ResponseCache::key("myapp", &get("/app.js")).unwrap();
+
+        assert!(cache.get(&key, &HeaderMap::new()).is_none());
+        let body = drain(cache.store(key.clone(), upstream("public, max-age=60", "console.log(1)"))).await;
+        assert_eq!(body, "console.log(1)");
+
+        let hit = cache.get(&key, &HeaderMap::new()).expect("cached");
+        assert_eq!(hit.status(), StatusCode::OK);
+        assert_eq!(hit.headers()["x-cache"], "HIT");
+        assert_eq!(hit.headers()[header::AGE], "0");
+        assert_eq!(drain(hit).await, "console.log(1)");
+
+        // Revalidation with the stored ETag gets a 304
+        let mut conditional = HeaderMap::new();
+        conditional.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"v1\""));
+        let revalidated = cache.get(&key, &conditional).unwrap();
+        assert_eq!(revalidated.status(), StatusCode::NOT_MODIFIED);
+        assert!(drain(revalidated).await.is_empty());
+
+        // Other connections, wildcard hosts, encodings and credentialed
+        // requests don't share it
+        assert_ne!(ResponseCache::key("other", &get("/app.js")).unwrap(), key);
+        let mut wildcard = get("/app.js");
+        wildcard
+            .headers_mut()
+            .insert(constants::WILDCARD_HOST_HEADER, HeaderValue::from_static("a.myapp.dvaar.app"));
+        assert_ne!(ResponseCache::key("myapp", &wildcard).unwrap(), key);
+        let mut identity = get("/app.js");
+        identity.headers_mut().remove(header::ACCEPT_ENCODING);
+        assert_ne!(ResponseCache::key("myapp", &identity).unwrap(), key);
+        let mut with_cookie = get("/app.js");
+        with_cookie.headers_mut().insert(header::COOKIE, HeaderValue::from_static("session=1"));
+        assert!(ResponseCache::key("myapp", &with_cookie).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_no_store_bypasses_cache() {
+        let cache = Arc::new(ResponseCache::new(8, 1024));
+        let key = ResponseCache::key("myapp", &get("/data")).unwrap();
+
+        for cache_control in ["no-store, max-age=60", "private, max-age=60", "public"] {
+            drain(cache.store(key.clone(), upstream(cache_control, "fresh"))).await;
+            assert!(cache.get(&key, &HeaderMap::new()).is_none(), "{}", cache_control);
+        }
+
+        let mut varies = upstream("max-age=60", "fresh");
+        varies.headers_mut().insert(header::VARY, HeaderValue::from_static("Cookie"));
+        drain(cache.store(key.clone(), varies)).await;
+        assert!(cache.get(&key, &HeaderMap::new()).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_entries_expire() {
+        let cache = Arc::new(ResponseCache::new(8, 1024));
+        let key = ResponseCache::key("myapp", &get("/logo.svg")).unwrap();
+        drain(cache.store(key.clone(), upstream("max-age=30", "<svg/>"))).await;
+
+        tokio::time::advance(Duration::from_secs(29)).await;
+        let hit = cache.get(&key, &HeaderMap::new()).unwrap();
+        assert_eq!(hit.headers()[header::AGE], "29");
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(cache.get(&key, &HeaderMap::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used() {
+        let cache = Arc::new(ResponseCache::new(2, 1024));
+        for path in ["/a", "/b"] {
+            let key = ResponseCache::key("myapp", &get(path)).unwrap();
+            drain(cache.store(key, upstream("max-age=60", "x"))).await;
+        }
+        let a = ResponseCache::key("myapp", &get("/a")).unwrap();
+        let b = ResponseCache::key("myapp", &get("/b")).unwrap();
+        assert!(cache.get(&a, &HeaderMap::new()).is_some());
+
+        let c = ResponseCache::key("myapp", &get("/c")).unwrap();
+        drain(cache.store(c.clone(), upstream("max-age=60", "x"))).await;
+        assert!(cache.get(&a, &HeaderMap::new()).is_some());
+        assert!(cache.get(&b, &HeaderMap::new()).is_none());
+        assert!(cache.get(&c, &HeaderMap::new()).is_some());
+    }
+}
@@ -0,0 +1,152 @@
+//! Putting this node's routes back after Redis loses them
+//!
+//! Each tunnel's heartbeat registers its route again when it finds it gone,
+//! but only on its own next beat. When Redis restarts empty or is flushed,
+//! every tunnel on the node is unreachable from other nodes at once, and the
+//! clients have no reason to reconnect. The reconciler runs at startup and
+//! then every `RECONCILE_INTERVAL`, asks Redis which of this node's routes
+//! are missing in one call, and registers those again.
+
+use crate::redis::RouteManager;
+use crate::routes::TunnelHandle;
+use dashmap::DashMap;
+use dvaar_common::RouteInfo;
+use futures_util::future::BoxFuture;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often routes are checked; well inside the route TTL, so a flush is
+/// repaired before clients notice much
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The parts of the route registry the reconciler uses
+pub trait RouteRegistry: Send + Sync {
+    /// Which of `subdomains` have no route
+    fn missing_routes<'a>(&'a self, subdomains: &'a [String]) -> BoxFuture<'a, anyhow::Result<Vec<String>>>;
+
+    /// Register a tunnel's route, and its place in the owner's tunnel count
+    fn restore_route<'a>(&'a self, subdomain: &'a str, route: &'a RouteInfo) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+impl RouteRegistry for RouteManager {
+    fn missing_routes<'a>(&'a self, subdomains: &'a [String]) -> BoxFuture<'a, anyhow::Result<Vec<String>>> {
+        Box::pin(self.missing_routes(subdomains))
+    }
+
+    fn restore_route<'a>(&'a self, subdomain: &'a str, route: &'a RouteInfo) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.register_route(subdomain, route).await?;
+            self.refresh_user_tunnel(&route.user_id, subdomain).await
+        })
+    }
+}
+
+/// Register again the routes Redis has lost for tunnels connected here.
+/// Tunnels still starting up are left alone; they register themselves.
+/// Returns how many routes were restored.
+pub async fn reconcile_routes(
+    registry: &dyn RouteRegistry,
+    tunnels: &DashMap<String, TunnelHandle>,
+) -> anyhow::Result<usize> {
+    let live: Vec<(String, RouteInfo)> = tunnels
+        .iter()
+        .filter(|entry| entry.is_ready())
+        .filter_map(|entry| entry.route.clone().map(|route| (entry.key().clone(), route)))
+        .collect();
+    if live.is_empty() {
+        return Ok(0);
+    }
+
+    let subdomains: Vec<String> = live.iter().map(|(subdomain, _)| subdomain.clone()).collect();
+    let missing = registry.missing_routes(&subdomains).await?;
+
+    let mut restored = 0;
+    for (subdomain, route) in live.iter().filter(|(subdomain, _)| missing.contains(subdomain)) {
+        // The tunnel may have closed since the snapshot; don't resurrect its route
+        if !tunnels.contains_key(subdomain) {
+            continue;
+        }
+        match registry.restore_route(subdomain, route).await {
+            Ok(()) => restored += 1,
+            Err(e) => tracing::warn!("Failed to restore route for {}: {}", subdomain, e),
+        }
+    }
+    Ok(restored)
+}
+
+/// Reconcile now, then every `RECONCILE_INTERVAL`
+pub fn spawn_route_reconciler(
+    route_manager: Arc<RouteManager>,
+    tunnels: Arc<DashMap<String, TunnelHandle>>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RECONCILE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match reconcile_routes(route_manager.as_ref(), &tunnels).await {
+                Ok(0) => {}
+                Ok(restored) => tracing::info!("Restored {} routes missing from Redis", restored),
+                Err(e) => tracing::debug!("Route reconciliation skipped: {}", e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+    use tokio::sync::mpsc;
+
+    /// Redis as a map of subdomain to route
+    #[derive(Default)]
+    struct FakeRegistry(Mutex<HashMap<String, RouteInfo>>);
+
+    impl RouteRegistry for FakeRegistry {
+        fn missing_routes<'a>(&'a self, subdomains: &'a [String]) -> BoxFuture<'a, anyhow::Result<Vec<String>>> {
+            let routes = self.0.lock().unwrap();
+            let missing = subdomains.iter().filter(|s| !routes.contains_key(*s)).cloned().collect();
+            Box::pin(async move { Ok(missing) })
+        }
+
+        fn restore_route<'a>(&'a self, subdomain: &'a str, route: &'a RouteInfo) -> BoxFuture<'a, anyhow::Result<()>> {
+            self.0.lock().unwrap().insert(subdomain.to_string(), route.clone());
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn tunnel(tunnels: &DashMap<String, TunnelHandle>, subdomain: &str, ready: bool) -> RouteInfo {
+        let (request_tx, _request_rx) = mpsc::channel(1);
+        let mut handle = TunnelHandle::new(request_tx, format!("user-{}", subdomain));
+        let route = RouteInfo::new("10.0.0.1".to_string(), 6000, handle.user_id.clone());
+        handle.route = Some(route.clone());
+        handle.ready.store(ready, Ordering::Release);
+        tunnels.insert(subdomain.to_string(), handle);
+        route
+    }
+
+    #[tokio::test]
+    async fn test_routes_restored_after_flush() {
+        let registry = FakeRegistry::default();
+        let tunnels = DashMap::new();
+        let app = tunnel(&tunnels, "app", true);
+        let api = tunnel(&tunnels, "api", true);
+        tunnel(&tunnels, "starting", false);
+        for (subdomain, route) in [("app", &app), ("api", &api)] {
+            registry.restore_route(subdomain, route).await.unwrap();
+        }
+
+        // Nothing lost, nothing to do
+        assert_eq!(reconcile_routes(&registry, &tunnels).await.unwrap(), 0);
+
+        registry.0.lock().unwrap().clear();
+        assert_eq!(reconcile_routes(&registry, &tunnels).await.unwrap(), 2);
+        let routes = registry.0.lock().unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes["app"].user_id, "user-app");
+        assert_eq!(routes["app"].connected_at, app.connected_at);
+        assert_eq!(routes["api"].user_id, "user-api");
+    }
+}
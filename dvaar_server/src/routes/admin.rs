@@ -1,15 +1,17 @@
 //! Admin routes for metrics and observability (admin.dvaar.io)
 
-use crate::routes::AppState;
+use crate::db::queries;
+use crate::routes::{AppState, TunnelCommand};
 use axum::{
     body::Body,
-    extract::{Request, State},
+    extract::{Path, Query, Request, State},
     http::{HeaderMap, StatusCode},
     response::{Html, IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Build the admin router
@@ -19,6 +21,8 @@ pub fn router() -> Router<AppState> {
         .route("/api/metrics", get(get_metrics))
         .route("/api/health", get(health_check))
         .route("/api/nodes", get(get_nodes))
+        .route("/api/tunnel-events", get(get_tunnel_events))
+        .route("/api/tunnels/{subdomain}/close", post(close_tunnel))
         .route("/api/ads", get(get_ads).post(set_ads))
 }
 
@@ -73,15 +77,36 @@ struct Metrics {
     total_bandwidth_bytes: u64,
     node_ip: String,
     uptime_seconds: u64,
+    /// Times a stream's response buffer was full when the tunnel had data for it
+    channel_full_events: u64,
+    /// Streams failed because a slow reader kept their buffer full
+    stalled_streams: u64,
 }
 
 #[derive(Serialize)]
 struct NodeInfo {
+    node_id: String,
     ip: String,
-    active_tunnels: usize,
+    region: Option<String>,
+    active_tunnels: u32,
+    max_tunnels: u32,
     status: String,
 }
 
+impl From<crate::redis::NodeInfo> for NodeInfo {
+    fn from(node: crate::redis::NodeInfo) -> Self {
+        Self {
+            node_id: node.node_id,
+            ip: node.ip,
+            region: node.region,
+            active_tunnels: node.tunnel_count,
+            max_tunnels: node.max_tunnels,
+            // Only nodes that heartbeated within the TTL are listed
+            status: "healthy".to_string(),
+        }
+    }
+}
+
 static START_TIME: std::sync::OnceLock<SystemTime> = std::sync::OnceLock::new();
 
 fn get_start_time() -> &'static SystemTime {
@@ -139,6 +164,8 @@ async fn get_metrics(State(state): State<AppState>, headers: HeaderMap) -> Respo
         total_bandwidth_bytes: total_bandwidth,
         node_ip,
         uptime_seconds: uptime,
+        channel_full_events: state.channel_stats.full_events.load(Ordering::Relaxed),
+        stalled_streams: state.channel_stats.stalled_streams.load(Ordering::Relaxed),
     };
 
     Json(metrics).into_response()
@@ -182,17 +209,89 @@ async fn get_nodes(State(state): State<AppState>, headers: HeaderMap) -> Respons
         return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
     }
 
-    // In single-node setup, just return this node
-    // In multi-node, would scan Redis for all registered nodes
-    let nodes = vec![NodeInfo {
-        ip: state.config.node_ip.clone(),
-        active_tunnels: state.tunnels.len(),
-        status: "healthy".to_string(),
-    }];
+    // Every node that has heartbeated within the TTL; without Redis only
+    // this node is known
+    let nodes: Vec<NodeInfo> = match state.route_manager.get_all_nodes().await {
+        Ok(nodes) => nodes.into_iter().map(NodeInfo::from).collect(),
+        Err(e) => {
+            tracing::warn!("Failed to list nodes: {}", e);
+            let this_node = crate::redis::NodeInfo::for_this_node(&state.config, state.tunnels.len() as u32);
+            vec![NodeInfo {
+                status: "unregistered".to_string(),
+                ..NodeInfo::from(this_node)
+            }]
+        }
+    };
 
     Json(nodes).into_response()
 }
 
+/// Filters for the tunnel audit log
+#[derive(Debug, Deserialize)]
+struct TunnelEventsQuery {
+    user_id: Option<uuid::Uuid>,
+    subdomain: Option<String>,
+    limit: Option<i64>,
+}
+
+/// Most tunnel events returned at once
+const MAX_TUNNEL_EVENTS: i64 = 1000;
+
+/// Recent tunnel creations, newest first, for abuse investigation
+async fn get_tunnel_events(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<TunnelEventsQuery>,
+) -> Response {
+    if !validate_admin(&state, &headers) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_TUNNEL_EVENTS);
+    match queries::recent_tunnel_events(&state.db, query.user_id, query.subdomain.as_deref(), limit).await {
+        Ok(events) => Json(events).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to query tunnel events: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CloseTunnelRequest {
+    reason: Option<String>,
+}
+
+/// Close a tunnel held by this node, telling its client why
+async fn close_tunnel(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(subdomain): Path<String>,
+    body: Option<Json<CloseTunnelRequest>>,
+) -> Response {
+    if !validate_admin(&state, &headers) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    let request_tx = match state.tunnels.get(&subdomain) {
+        Some(handle) => handle.request_tx.clone(),
+        None => return (StatusCode::NOT_FOUND, "Tunnel not found on this node").into_response(),
+    };
+    let reason = body
+        .and_then(|Json(body)| body.reason)
+        .unwrap_or_else(|| "Closed by an administrator".to_string());
+    let command = TunnelCommand::Close {
+        code: dvaar_common::CloseCode::AdminClosed,
+        reason,
+    };
+    if request_tx.send(command).await.is_err() {
+        return (StatusCode::GONE, "Tunnel already closing").into_response();
+    }
+
+    tracing::info!("Admin closed tunnel {}", subdomain);
+    Json(serde_json::json!({ "status": "ok" })).into_response()
+}
+
 /// Get ads list (public endpoint - no auth required)
 async fn get_ads(State(state): State<AppState>) -> Response {
     // Try to get ads from Redis
@@ -314,6 +413,7 @@ const ADMIN_DASHBOARD_HTML: &str = r#"<!DOCTYPE html>
         .status { display: inline-block; width: 8px; height: 8px; border-radius: 50%; margin-right: 0.5rem; }
         .status.healthy { background: #22c55e; }
         .status.unhealthy { background: #ef4444; }
+        .status.unregistered { background: #eab308; }
         table { width: 100%; border-collapse: collapse; background: #111; border-radius: 8px; overflow: hidden; }
         th, td { padding: 1rem; text-align: left; border-bottom: 1px solid #222; }
         th { background: #1a1a1a; font-weight: 600; }
@@ -358,7 +458,7 @@ const ADMIN_DASHBOARD_HTML: &str = r#"<!DOCTYPE html>
 
         <h2 style="margin-bottom: 1rem;">Nodes</h2>
         <table>
-            <thead><tr><th>IP</th><th>Tunnels</th><th>Status</th></tr></thead>
+            <thead><tr><th>IP</th><th>Region</th><th>Tunnels</th><th>Status</th></tr></thead>
             <tbody id="nodes"></tbody>
         </table>
     </div>
@@ -405,7 +505,8 @@ const ADMIN_DASHBOARD_HTML: &str = r#"<!DOCTYPE html>
                 document.getElementById('nodes').innerHTML = nodes.map(n => `
                     <tr>
                         <td>${n.ip}</td>
-                        <td>${n.active_tunnels}</td>
+                        <td>${n.region ?? '-'}</td>
+                        <td>${n.active_tunnels} / ${n.max_tunnels}</td>
                         <td><span class="status ${n.status}"></span>${n.status}</td>
                     </tr>
                 `).join('');
@@ -435,6 +536,10 @@ pub async fn handle_admin_request(
         ("GET", "/api/metrics") => get_metrics(State(state), headers).await,
         ("GET", "/api/health") => health_check(State(state)).await,
         ("GET", "/api/nodes") => get_nodes(State(state), headers).await,
+        ("GET", "/api/tunnel-events") => match Query::try_from_uri(request.uri()) {
+            Ok(query) => get_tunnel_events(State(state), headers, query).await,
+            Err(e) => e.into_response(),
+        },
         ("GET", "/api/ads") => get_ads(State(state)).await,
         ("POST", "/api/ads") => {
             // Extract body for POST
@@ -448,6 +553,26 @@ pub async fn handle_admin_request(
             };
             set_ads(State(state), headers, Json(ads)).await
         }
+        ("POST", path) if path.starts_with("/api/tunnels/") && path.ends_with("/close") => {
+            let subdomain = path["/api/tunnels/".len()..path.len() - "/close".len()].to_string();
+            if subdomain.is_empty() || subdomain.contains('/') {
+                return (StatusCode::NOT_FOUND, "Not found").into_response();
+            }
+            // The body is optional; without one the client gets a generic reason
+            let body_bytes = match axum::body::to_bytes(request.into_body(), 1024 * 64).await {
+                Ok(bytes) => bytes,
+                Err(_) => return (StatusCode::BAD_REQUEST, "Failed to read body").into_response(),
+            };
+            let body = if body_bytes.is_empty() {
+                None
+            } else {
+                match serde_json::from_slice(&body_bytes) {
+                    Ok(body) => Some(Json(body)),
+                    Err(_) => return (StatusCode::BAD_REQUEST, "Invalid JSON").into_response(),
+                }
+            };
+            close_tunnel(State(state), headers, Path(subdomain), body).await
+        }
         _ => (StatusCode::NOT_FOUND, "Not found").into_response(),
     }
 }
@@ -1,18 +1,21 @@
 //! Authentication routes (GitHub OAuth)
 
 use crate::db::queries;
+use crate::redis::NodeInfo;
+use crate::routes::billing::PlanFeatures;
 use crate::routes::AppState;
+use crate::share_links;
 use axum::{
-    extract::{Query, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     response::{IntoResponse, Redirect, Response},
     routing::{get, post},
     Json, Router,
 };
 use chrono::Utc;
-use dvaar_common::constants;
+use dvaar_common::{constants, RouteInfo, UpstreamMetrics};
 use http::Uri;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -37,6 +40,9 @@ pub fn router() -> Router<AppState> {
         .route("/api/auth/token", post(exchange_token))
         .route("/api/user", get(get_user))
         .route("/api/usage", get(get_usage))
+        .route("/api/tunnels", get(list_tunnels))
+        .route("/api/tunnels/{subdomain}/share", post(share_tunnel))
+        .route("/api/tunnels/{subdomain}/maintenance", post(set_maintenance))
         .route("/api/nodes", get(get_nodes))
 }
 
@@ -336,6 +342,11 @@ async fn get_usage(
         .get_usage(&user.id.to_string())
         .await
         .unwrap_or(0);
+    let by_direction = state
+        .route_manager
+        .get_usage_by_direction(&user.id.to_string())
+        .await
+        .unwrap_or_default();
 
     // Check if plan has expired
     let effective_plan = if let Some(expires_at) = user.plan_expires_at {
@@ -349,21 +360,269 @@ async fn get_usage(
     };
 
     // Get bandwidth limit based on effective plan
-    let bandwidth_limit = match effective_plan {
-        "pro" => constants::BANDWIDTH_PRO,
-        "hobby" => constants::BANDWIDTH_HOBBY,
-        _ => constants::BANDWIDTH_FREE,
-    };
+    let bandwidth_limit = PlanFeatures::for_plan(effective_plan).bandwidth;
 
     Json(serde_json::json!({
         "plan": effective_plan,
         "bandwidth_bytes": usage,
+        "ingress_bytes": by_direction.ingress,
+        "egress_bytes": by_direction.egress,
         "bandwidth_limit": bandwidth_limit,
         "plan_expires_at": user.plan_expires_at
     }))
     .into_response()
 }
 
+/// A live tunnel as reported by `GET /api/tunnels`
+#[derive(Debug, Serialize)]
+struct TunnelStatusEntry {
+    subdomain: String,
+    url: String,
+    node_ip: String,
+    region: Option<String>,
+    connected_at: Option<u64>,
+    uptime_secs: Option<u64>,
+    /// The client's latest report on its upstream, if one is recent
+    upstream: Option<UpstreamMetrics>,
+}
+
+/// List the caller's active tunnels across all nodes
+async fn list_tunnels(
+    State(state): State<AppState>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let token = match extract_bearer_token(&headers) {
+        Some(t) => t,
+        None => return (StatusCode::UNAUTHORIZED, "Missing authorization header").into_response(),
+    };
+
+    let user = match queries::find_user_by_token(&state.db, token).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return (StatusCode::UNAUTHORIZED, "Invalid token").into_response(),
+        Err(e) => {
+            tracing::error!("Database error: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response();
+        }
+    };
+    let user_id = user.id.to_string();
+
+    let subdomains = match state.route_manager.list_user_tunnels(&user_id).await {
+        Ok(subdomains) => subdomains,
+        Err(e) => {
+            tracing::error!("Failed to list tunnels for {}: {}", user_id, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to list tunnels").into_response();
+        }
+    };
+
+    let mut routes = Vec::with_capacity(subdomains.len());
+    for subdomain in subdomains {
+        if let Ok(Some(route)) = state.route_manager.get_route(&subdomain).await {
+            routes.push((subdomain, route));
+        }
+    }
+    let nodes = state.route_manager.get_all_nodes().await.unwrap_or_default();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let mut tunnels = tunnel_statuses(&user_id, routes, &nodes, now, |s| state.config.full_url(s));
+    for tunnel in &mut tunnels {
+        tunnel.upstream = state
+            .route_manager
+            .get_upstream_metrics(&tunnel.subdomain)
+            .await
+            .unwrap_or_default();
+    }
+    Json(serde_json::json!({ "tunnels": tunnels })).into_response()
+}
+
+/// Keep only routes owned by `user_id` and annotate them with region and uptime
+fn tunnel_statuses(
+    user_id: &str,
+    routes: Vec<(String, RouteInfo)>,
+    nodes: &[NodeInfo],
+    now: u64,
+    full_url: impl Fn(&str) -> String,
+) -> Vec<TunnelStatusEntry> {
+    let mut tunnels: Vec<TunnelStatusEntry> = routes
+        .into_iter()
+        // The user's tunnel set only names subdomains; the route is the authority on ownership
+        .filter(|(_, route)| route.user_id == user_id)
+        .map(|(subdomain, route)| TunnelStatusEntry {
+            url: full_url(&subdomain),
+            region: nodes
+                .iter()
+                .find(|node| node.ip == route.node_ip)
+                .and_then(|node| node.region.clone()),
+            uptime_secs: route.connected_at.map(|at| now.saturating_sub(at)),
+            connected_at: route.connected_at,
+            node_ip: route.node_ip,
+            subdomain,
+            upstream: None,
+        })
+        .collect();
+    tunnels.sort_by(|a, b| a.subdomain.cmp(&b.subdomain));
+    tunnels
+}
+
+/// Route of `subdomain` if the bearer of `headers` owns it and it is
+/// connected; otherwise the response to send instead
+async fn owned_route(
+    state: &AppState,
+    headers: &axum::http::HeaderMap,
+    subdomain: &str,
+) -> Result<RouteInfo, Response> {
+    let token = match extract_bearer_token(headers) {
+        Some(t) => t,
+        None => return Err((StatusCode::UNAUTHORIZED, "Missing authorization header").into_response()),
+    };
+
+    let user = match queries::find_user_by_token(&state.db, token).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Err((StatusCode::UNAUTHORIZED, "Invalid token").into_response()),
+        Err(e) => {
+            tracing::error!("Database error: {}", e);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error").into_response());
+        }
+    };
+
+    // Someone else's tunnel looks the same as no tunnel at all
+    match state.route_manager.get_route(subdomain).await {
+        Ok(Some(route)) if route.user_id == user.id.to_string() => Ok(route),
+        Ok(_) => Err((StatusCode::NOT_FOUND, "No active tunnel with that subdomain").into_response()),
+        Err(e) => {
+            tracing::error!("Failed to look up route for {}: {}", subdomain, e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to look up tunnel").into_response())
+        }
+    }
+}
+
+/// Request body for `POST /api/tunnels/{subdomain}/share`
+#[derive(Debug, Deserialize)]
+struct ShareRequest {
+    ttl_secs: Option<u64>,
+}
+
+/// Mint a link to a read-only view of one of the caller's tunnel inspectors
+async fn share_tunnel(
+    State(state): State<AppState>,
+    Path(subdomain): Path<String>,
+    headers: axum::http::HeaderMap,
+    Json(body): Json<ShareRequest>,
+) -> Response {
+    let route = match owned_route(&state, &headers, &subdomain).await {
+        Ok(route) => route,
+        Err(response) => return response,
+    };
+
+    let ttl_secs = body.ttl_secs.unwrap_or(share_links::DEFAULT_SHARE_TTL_SECS);
+    if ttl_secs == 0 || ttl_secs > share_links::MAX_SHARE_TTL_SECS {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("ttl_secs must be between 1 and {}", share_links::MAX_SHARE_TTL_SECS),
+        )
+            .into_response();
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let expires_at = now + ttl_secs;
+    let token = share_links::mint(&state.config.share_link_secret, &subdomain, &route.user_id, expires_at);
+    Json(serde_json::json!({
+        "url": format!("{}{}?token={}", state.config.full_url(&subdomain), share_links::SHARE_PATH, token),
+        "expires_at": expires_at,
+    }))
+    .into_response()
+}
+
+/// Served while in maintenance when the owner didn't give a page
+const DEFAULT_MAINTENANCE_PAGE: &str = "This site is down for maintenance. Please check back soon.";
+
+/// Request body for `POST /api/tunnels/{subdomain}/maintenance`
+#[derive(Debug, Deserialize)]
+struct MaintenanceRequest {
+    enabled: bool,
+    /// Page to answer with, `DEFAULT_MAINTENANCE_PAGE` if absent
+    body: Option<String>,
+}
+
+/// Put one of the caller's tunnels into maintenance, answering every request
+/// with a 503 and the given page while the tunnel stays connected, or take
+/// it back out
+async fn set_maintenance(
+    State(state): State<AppState>,
+    Path(subdomain): Path<String>,
+    headers: axum::http::HeaderMap,
+    Json(body): Json<MaintenanceRequest>,
+) -> Response {
+    let route = match owned_route(&state, &headers, &subdomain).await {
+        Ok(route) => route,
+        Err(response) => return response,
+    };
+
+    let page = body
+        .enabled
+        .then(|| body.body.unwrap_or_else(|| DEFAULT_MAINTENANCE_PAGE.to_string()));
+    if page.as_ref().is_some_and(|page| page.len() > constants::MAX_MAINTENANCE_PAGE_BYTES) {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("body must be at most {} bytes", constants::MAX_MAINTENANCE_PAGE_BYTES),
+        )
+            .into_response();
+    }
+
+    // Redis keeps it for the life of the route; the tunnel's node is told directly
+    let stored = match &page {
+        Some(page) => state.route_manager.set_maintenance(&subdomain, page).await,
+        None => state.route_manager.clear_maintenance(&subdomain).await,
+    };
+    if let Err(e) = stored {
+        tracing::error!("Failed to store maintenance state for {}: {}", subdomain, e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to update maintenance").into_response();
+    }
+    if let Err(e) = apply_maintenance(&state, &subdomain, &route, page.clone()).await {
+        tracing::warn!("Failed to reach the node serving {}: {}", subdomain, e);
+        return (
+            StatusCode::BAD_GATEWAY,
+            "Saved, but the tunnel's node couldn't be reached; it applies when the tunnel reconnects",
+        )
+            .into_response();
+    }
+
+    Json(serde_json::json!({ "subdomain": subdomain, "maintenance": page.is_some() })).into_response()
+}
+
+/// Switch maintenance on the node serving `subdomain`: here, or over the
+/// internal port
+async fn apply_maintenance(
+    state: &AppState,
+    subdomain: &str,
+    route: &RouteInfo,
+    page: Option<String>,
+) -> anyhow::Result<()> {
+    if let Some(handle) = state.tunnels.get(subdomain) {
+        handle.maintenance.set(page);
+        return Ok(());
+    }
+    let url = format!(
+        "http://{}:{}/_internal/maintenance/{}",
+        route.node_ip, route.internal_port, subdomain
+    );
+    state
+        .node_client
+        .post(url)
+        .header(constants::CLUSTER_SECRET_HEADER, &state.config.cluster_secret)
+        .json(&serde_json::json!({ "page": page }))
+        .timeout(std::time::Duration::from_secs(5))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
 /// Get auth config (public endpoint for CLI)
 async fn auth_config(State(state): State<AppState>) -> Response {
     Json(serde_json::json!({
@@ -618,3 +877,50 @@ fn is_local_redirect_uri(uri: &str) -> bool {
         _ => false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(node_ip: &str, user_id: &str, connected_at: u64) -> RouteInfo {
+        RouteInfo {
+            version: constants::ROUTE_INFO_VERSION,
+            node_ip: node_ip.to_string(),
+            internal_port: 6000,
+            user_id: user_id.to_string(),
+            connected_at: Some(connected_at),
+            wildcard: false,
+            connection_id: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_tunnel_statuses_only_include_callers_tunnels() {
+        let routes = vec![
+            ("mine".to_string(), route("10.0.0.1", "alice", 1_000)),
+            ("theirs".to_string(), route("10.0.0.1", "bob", 1_000)),
+            ("also-mine".to_string(), route("10.0.0.2", "alice", 1_900)),
+        ];
+        let nodes = vec![NodeInfo {
+            node_id: "10.0.0.1".to_string(),
+            ip: "10.0.0.1".to_string(),
+            port: 6000,
+            region: Some("eu".to_string()),
+            tunnel_count: 2,
+            max_tunnels: 100,
+            public_host: None,
+        }];
+
+        let tunnels = tunnel_statuses("alice", routes, &nodes, 2_000, |s| format!("https://{}.test", s));
+
+        let names: Vec<&str> = tunnels.iter().map(|t| t.subdomain.as_str()).collect();
+        assert_eq!(names, ["also-mine", "mine"]);
+
+        assert_eq!(tunnels[1].url, "https://mine.test");
+        assert_eq!(tunnels[1].region.as_deref(), Some("eu"));
+        assert_eq!(tunnels[1].uptime_secs, Some(1_000));
+        // Node without a registration has no known region
+        assert_eq!(tunnels[0].region, None);
+        assert_eq!(tunnels[0].uptime_secs, Some(100));
+    }
+}
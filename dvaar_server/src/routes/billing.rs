@@ -1,17 +1,20 @@
 //! Billing routes (Stripe integration)
 
 use crate::db::queries;
+use crate::routes::tunnel::authenticate_client;
 use crate::routes::AppState;
+use crate::services::AuthedUser;
 use axum::{
     body::Bytes,
     extract::{State, Request},
-    http::{HeaderMap, StatusCode},
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc, Duration};
+use dvaar_common::constants;
 
 /// Stripe API base URL
 const STRIPE_API_URL: &str = "https://api.stripe.com/v1";
@@ -23,6 +26,87 @@ pub fn router() -> Router<AppState> {
         .route("/api/billing/portal", get(customer_portal))
         .route("/api/billing/webhook", post(stripe_webhook))
         .route("/api/billing/plans", get(list_plans))
+        .route("/api/features", get(get_features))
+}
+
+/// What a plan lets its users do. Tunnel setup enforces these limits, and
+/// `GET /api/features` reports them so the CLI can explain a refusal before
+/// it connects.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlanFeatures {
+    pub plan: String,
+    /// Pick a subdomain rather than get a random one
+    pub custom_subdomains: bool,
+    pub custom_domains: bool,
+    pub reserved_subdomains: bool,
+    pub max_concurrent: u32,
+    /// Bytes a month, both directions together
+    pub bandwidth: u64,
+    pub tunnels_per_hour: u32,
+    /// Shared by all of the user's tunnels
+    pub requests_per_minute: u32,
+}
+
+impl PlanFeatures {
+    /// Features of `plan`; anything unknown gets the free plan's
+    pub fn for_plan(plan: &str) -> Self {
+        let (plan, paid, max_concurrent, bandwidth, tunnels_per_hour, requests_per_minute) = match plan {
+            "pro" => (
+                "pro",
+                true,
+                constants::CONCURRENT_TUNNELS_PRO,
+                constants::BANDWIDTH_PRO,
+                constants::TUNNELS_PER_HOUR_PRO,
+                constants::REQUESTS_PER_MINUTE_PRO,
+            ),
+            "hobby" => (
+                "hobby",
+                true,
+                constants::CONCURRENT_TUNNELS_HOBBY,
+                constants::BANDWIDTH_HOBBY,
+                constants::TUNNELS_PER_HOUR_HOBBY,
+                constants::REQUESTS_PER_MINUTE_HOBBY,
+            ),
+            _ => (
+                "free",
+                false,
+                constants::CONCURRENT_TUNNELS_FREE,
+                constants::BANDWIDTH_FREE,
+                constants::TUNNELS_PER_HOUR_FREE,
+                constants::REQUESTS_PER_MINUTE_FREE,
+            ),
+        };
+        Self {
+            plan: plan.to_string(),
+            custom_subdomains: paid,
+            custom_domains: paid,
+            reserved_subdomains: paid,
+            max_concurrent,
+            bandwidth,
+            tunnels_per_hour,
+            requests_per_minute,
+        }
+    }
+
+    /// Features of the plan in force for `user` now
+    pub fn for_user(user: &AuthedUser) -> Self {
+        Self::for_plan(user.effective_plan())
+    }
+}
+
+/// The caller's effective plan features
+async fn get_features(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "));
+    let Some(token) = token else {
+        return (StatusCode::UNAUTHORIZED, "Missing authorization header").into_response();
+    };
+    match authenticate_client(state.authenticator.as_ref(), token).await {
+        Ok(user) => Json(PlanFeatures::for_user(&user)).into_response(),
+        Err(message) => (StatusCode::UNAUTHORIZED, message).into_response(),
+    }
 }
 
 /// Request body for creating checkout session
@@ -174,7 +258,7 @@ async fn stripe_webhook(
         Err(_) => return (StatusCode::BAD_REQUEST, "Invalid payload").into_response(),
     };
 
-    // Verify signature (simplified - in production use stripe crate)
+    // Verify signature
     if !verify_webhook_signature(payload_str, signature, &webhook_secret) {
         return (StatusCode::BAD_REQUEST, "Invalid signature").into_response();
     }
@@ -210,48 +294,29 @@ async fn stripe_webhook(
 
 /// List available plans
 async fn list_plans() -> Response {
+    let plan = |id: &str, name: &str, price: u32| {
+        let features = PlanFeatures::for_plan(id);
+        serde_json::json!({
+            "id": id,
+            "name": name,
+            "price": price,
+            "features": {
+                "concurrent_tunnels": features.max_concurrent,
+                "tunnels_per_hour": features.tunnels_per_hour,
+                "requests_per_min": features.requests_per_minute,
+                "bandwidth_gb": features.bandwidth / (1024 * 1024 * 1024),
+                "custom_domains": features.custom_domains,
+                "reserved_subdomains": features.reserved_subdomains
+            }
+        })
+    };
+    let mut pro = plan("pro", "Pro", 15);
+    pro["features"]["team_members"] = 5.into();
     Json(serde_json::json!({
         "plans": [
-            {
-                "id": "free",
-                "name": "Free",
-                "price": 0,
-                "features": {
-                    "concurrent_tunnels": 5,
-                    "tunnels_per_hour": 60,
-                    "requests_per_min": 300,
-                    "bandwidth_gb": 1,
-                    "custom_domains": false,
-                    "reserved_subdomains": false
-                }
-            },
-            {
-                "id": "hobby",
-                "name": "Hobby",
-                "price": 5,
-                "features": {
-                    "concurrent_tunnels": 10,
-                    "tunnels_per_hour": 200,
-                    "requests_per_min": 1000,
-                    "bandwidth_gb": 50,
-                    "custom_domains": true,
-                    "reserved_subdomains": true
-                }
-            },
-            {
-                "id": "pro",
-                "name": "Pro",
-                "price": 15,
-                "features": {
-                    "concurrent_tunnels": 50,
-                    "tunnels_per_hour": 1000,
-                    "requests_per_min": 5000,
-                    "bandwidth_gb": 500,
-                    "custom_domains": true,
-                    "reserved_subdomains": true,
-                    "team_members": 5
-                }
-            }
+            plan("free", "Free", 0),
+            plan("hobby", "Hobby", 5),
+            pro
         ]
     }))
     .into_response()
@@ -471,25 +536,34 @@ async fn determine_plan_from_subscription(http_client: &reqwest::Client, subscri
     }
 }
 
-/// Maximum age of webhook timestamp to accept (5 minutes)
+/// How far a webhook's timestamp may be from now, either way (5 minutes)
 const WEBHOOK_TIMESTAMP_TOLERANCE_SECS: i64 = 300;
 
 fn verify_webhook_signature(payload: &str, signature: &str, secret: &str) -> bool {
-    // Parse signature header
-    let parts: std::collections::HashMap<&str, &str> = signature
-        .split(',')
-        .filter_map(|part| {
-            let mut iter = part.splitn(2, '=');
-            Some((iter.next()?, iter.next()?))
-        })
-        .collect();
+    verify_webhook_signature_at(payload, signature, secret, Utc::now().timestamp())
+}
 
-    let timestamp = match parts.get("t") {
-        Some(t) => *t,
-        None => return false,
-    };
+/// Check a `Stripe-Signature` header (`t=<unix time>,v1=<hex hmac>,...`)
+/// against the payload as of `now`. The HMAC-SHA256 covers `"{t}.{payload}"`,
+/// so an old event can't be replayed with a fresh timestamp. Stripe sends one
+/// `v1` per active secret while a secret is being rolled; any may match.
+fn verify_webhook_signature_at(payload: &str, signature: &str, secret: &str, now: i64) -> bool {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    let mut timestamp = None;
+    let mut candidates = Vec::new();
+    for part in signature.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", t)) => timestamp = Some(t),
+            Some(("v1", sig)) => candidates.push(sig),
+            _ => {}
+        }
+    }
 
-    // Verify timestamp is within tolerance (prevents replay attacks)
+    let Some(timestamp) = timestamp else {
+        return false;
+    };
     let timestamp_secs: i64 = match timestamp.parse() {
         Ok(t) => t,
         Err(_) => {
@@ -497,55 +571,112 @@ fn verify_webhook_signature(payload: &str, signature: &str, secret: &str) -> boo
             return false;
         }
     };
-
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs() as i64;
-
-    let age = now - timestamp_secs;
-    if age < 0 || age > WEBHOOK_TIMESTAMP_TOLERANCE_SECS {
-        tracing::warn!("Webhook timestamp outside tolerance: {} seconds old", age);
+    let skew = now.saturating_sub(timestamp_secs);
+    if skew.abs() > WEBHOOK_TIMESTAMP_TOLERANCE_SECS {
+        tracing::warn!("Webhook timestamp outside tolerance: {} seconds off", skew);
         return false;
     }
 
-    let provided_sig = match parts.get("v1") {
-        Some(s) => *s,
-        None => return false,
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+        return false;
     };
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+
+    // verify_slice compares in constant time
+    candidates
+        .into_iter()
+        .filter_map(|sig| hex::decode(sig).ok())
+        .any(|sig| mac.clone().verify_slice(&sig).is_ok())
+}
 
-    // Compute expected signature
-    let signed_payload = format!("{}.{}", timestamp, payload);
+fn extract_bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+}
 
-    use hmac::{Hmac, Mac};
-    use sha2::Sha256;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    type HmacSha256 = Hmac<Sha256>;
+    fn user(plan: &str, plan_expires_at: Option<DateTime<Utc>>) -> AuthedUser {
+        AuthedUser {
+            id: uuid::Uuid::new_v4(),
+            email: "dev@example.com".to_string(),
+            plan: plan.to_string(),
+            plan_expires_at,
+        }
+    }
 
-    let mut mac = match HmacSha256::new_from_slice(secret.as_bytes()) {
-        Ok(m) => m,
-        Err(_) => return false,
-    };
+    #[test]
+    fn test_features_follow_the_effective_plan() {
+        let free = PlanFeatures::for_user(&user("free", None));
+        let hobby = PlanFeatures::for_user(&user("hobby", Some(Utc::now() + Duration::days(30))));
+        assert_ne!(free, hobby);
+
+        assert_eq!(free.plan, "free");
+        assert!(!free.custom_subdomains && !free.custom_domains && !free.reserved_subdomains);
+        assert_eq!(free.max_concurrent, constants::CONCURRENT_TUNNELS_FREE);
+        assert_eq!(free.bandwidth, constants::BANDWIDTH_FREE);
+
+        assert_eq!(hobby.plan, "hobby");
+        assert!(hobby.custom_subdomains && hobby.custom_domains && hobby.reserved_subdomains);
+        assert_eq!(hobby.max_concurrent, constants::CONCURRENT_TUNNELS_HOBBY);
+        assert_eq!(hobby.bandwidth, constants::BANDWIDTH_HOBBY);
+
+        // A lapsed plan is back to free, and so is one nobody knows
+        let lapsed = PlanFeatures::for_user(&user("hobby", Some(Utc::now() - Duration::days(1))));
+        assert_eq!(lapsed, free);
+        assert_eq!(PlanFeatures::for_plan("enterprise"), free);
+    }
 
-    mac.update(signed_payload.as_bytes());
-    let expected = hex::encode(mac.finalize().into_bytes());
+    const SECRET: &str = "whsec_test_secret";
+    const PAYLOAD: &str = r#"{"id":"evt_1","type":"checkout.session.completed"}"#;
+    const SIGNED_AT: i64 = 1_700_000_000;
+    /// HMAC-SHA256 of "1700000000.{PAYLOAD}" under SECRET
+    const SIGNATURE: &str = "a13d4b1f9003bc2fb6c06224e878b6056185eaa50d40e1e7dc05517f8af41d7b";
 
-    // Constant-time comparison using subtle crate pattern
-    // Compare byte-by-byte to avoid timing attacks
-    if expected.len() != provided_sig.len() {
-        return false;
+    fn header(sigs: &[&str]) -> String {
+        let mut header = format!("t={}", SIGNED_AT);
+        for sig in sigs {
+            header.push_str(&format!(",v1={}", sig));
+        }
+        header
     }
 
-    let mut result = 0u8;
-    for (a, b) in expected.bytes().zip(provided_sig.bytes()) {
-        result |= a ^ b;
+    #[test]
+    fn test_webhook_signature_vector() {
+        assert!(verify_webhook_signature_at(PAYLOAD, &header(&[SIGNATURE]), SECRET, SIGNED_AT));
+        assert!(verify_webhook_signature_at(PAYLOAD, &header(&[SIGNATURE]), SECRET, SIGNED_AT + 60));
+
+        // During a secret roll only one of the signatures is ours
+        let other = "0".repeat(64);
+        assert!(verify_webhook_signature_at(PAYLOAD, &header(&[&other, SIGNATURE]), SECRET, SIGNED_AT));
+
+        assert!(!verify_webhook_signature_at(PAYLOAD, &header(&[SIGNATURE]), "whsec_other", SIGNED_AT));
+        assert!(!verify_webhook_signature_at(PAYLOAD, &header(&[]), SECRET, SIGNED_AT));
+        assert!(!verify_webhook_signature_at(PAYLOAD, &format!("v1={}", SIGNATURE), SECRET, SIGNED_AT));
     }
-    result == 0
-}
 
-fn extract_bearer_token(headers: &HeaderMap) -> Option<&str> {
-    headers
-        .get("authorization")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "))
+    #[test]
+    fn test_webhook_tampered_payload_rejected() {
+        let tampered = PAYLOAD.replace("evt_1", "evt_2");
+        assert!(!verify_webhook_signature_at(&tampered, &header(&[SIGNATURE]), SECRET, SIGNED_AT));
+
+        // Same signature moved to a different timestamp
+        let moved = format!("t={},v1={}", SIGNED_AT + 1, SIGNATURE);
+        assert!(!verify_webhook_signature_at(PAYLOAD, &moved, SECRET, SIGNED_AT));
+    }
+
+    #[test]
+    fn test_webhook_stale_timestamp_rejected() {
+        let stale = SIGNED_AT + WEBHOOK_TIMESTAMP_TOLERANCE_SECS + 1;
+        assert!(!verify_webhook_signature_at(PAYLOAD, &header(&[SIGNATURE]), SECRET, stale));
+
+        let early = SIGNED_AT - WEBHOOK_TIMESTAMP_TOLERANCE_SECS - 1;
+        assert!(!verify_webhook_signature_at(PAYLOAD, &header(&[SIGNATURE]), SECRET, early));
+    }
 }
@@ -1,36 +1,80 @@
 //! Public ingress handler - handles incoming HTTP requests to tunneled services
 
+use crate::abuse::{RateLimitResult, RateLimiter};
+use crate::access_log::{AccessLog, AccessLogEntry};
+use crate::access_rules::{AccessRules, Denial};
 use crate::db::queries;
-use crate::routes::{AppState, StreamChunk, TunnelCommand, TunnelRequest};
+use crate::response_cache::ResponseCache;
+use crate::routes::{AppState, StreamChunk, TunnelCommand, TunnelHandle, TunnelRequest};
+use crate::share_links;
 use axum::{
     body::Body,
     extract::ws::{Message, WebSocket, WebSocketUpgrade},
     extract::ConnectInfo,
     extract::State,
-    http::{Request, Response, StatusCode},
+    http::{HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode},
     response::IntoResponse,
 };
 use axum_extra::extract::Host;
-use dvaar_common::{constants, HttpRequestPacket, new_stream_id};
+use dashmap::DashMap;
+use dvaar_common::{check_header_limits, constants, HttpRequestPacket, RouteInfo, StreamErrorCode, new_stream_id};
 use futures_util::{SinkExt, StreamExt};
-use std::net::SocketAddr;
-use tokio::sync::mpsc;
+use http_body::Frame;
+use http_body_util::StreamBody;
+use rand::Rng;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot};
 use tokio::net::TcpStream;
 use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
 
+/// W3C trace context header
+const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Read bytes gathered into one `WindowUpdate`; a quarter of the window keeps
+/// the client sending without an update per chunk
+const WINDOW_UPDATE_BYTES: usize = constants::STREAM_WINDOW_BYTES / 4;
+
+/// Served when a tunnel is down and its owner hasn't registered an offline page
+const DEFAULT_OFFLINE_PAGE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{subdomain} is offline</title>
+<style>
+  body { margin: 0; min-height: 100vh; display: flex; align-items: center; justify-content: center;
+         font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", sans-serif; background: #0b0b12; color: #e6e6ef; }
+  main { text-align: center; padding: 2rem; }
+  h1 { font-size: 1.5rem; margin: 0 0 .5rem; }
+  p { color: #9a9ab0; margin: 0 0 1.5rem; }
+  a { color: #7c6cff; text-decoration: none; font-size: .875rem; }
+</style>
+</head>
+<body>
+<main>
+  <h1>{subdomain} is offline</h1>
+  <p>This tunnel isn't connected right now. It may be back in a moment &mdash; try refreshing.</p>
+  <a href="https://dvaar.io">Powered by dvaar</a>
+</main>
+</body>
+</html>
+"#;
+
 /// Rate limit error response
-#[allow(dead_code)]
-fn rate_limit_response(reset_in_secs: u64) -> Response<Body> {
+fn rate_limit_response(limit: &RateLimitResult) -> Response<Body> {
     let body = format!(
         "Rate limit exceeded. Try again in {} seconds.",
-        reset_in_secs
+        limit.reset_in_secs
     );
-    Response::builder()
+    let mut response = Response::builder()
         .status(StatusCode::TOO_MANY_REQUESTS)
-        .header("Retry-After", reset_in_secs.to_string())
-        .header("X-RateLimit-Reset", reset_in_secs.to_string())
-        .body(Body::from(body))
-        .unwrap()
+        .header("Retry-After", limit.reset_in_secs.to_string());
+    for (name, value) in limit.headers() {
+        response = response.header(name, value);
+    }
+    response.body(Body::from(body)).unwrap()
 }
 
 /// Handle an incoming public HTTP request
@@ -40,6 +84,105 @@ pub async fn handle_ingress(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     request: Request<Body>,
 ) -> Response<Body> {
+    let addr = client_addr(addr);
+    #[cfg(feature = "http3")]
+    if let Some(port) = state.config.http3_port {
+        let response = logged_ingress(state, host, addr, request).await;
+        return crate::http3::advertise(response, port);
+    }
+    logged_ingress(state, host, addr, request).await
+}
+
+/// A visitor's address as the loopback check, forwarded headers and logs
+/// should see it. IPv4 clients of a dual-stack listener arrive v4-mapped
+/// (`::ffff:127.0.0.1`), which would otherwise not count as loopback.
+pub(crate) fn client_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+async fn logged_ingress(
+    state: AppState,
+    host: String,
+    addr: SocketAddr,
+    request: Request<Body>,
+) -> Response<Body> {
+    let Some(access_log) = state.access_log.clone() else {
+        return route_ingress(state, host, addr, request).await;
+    };
+
+    let started = std::time::Instant::now();
+    let entry = AccessLogEntry {
+        timestamp: chrono::Utc::now(),
+        // Custom domains are logged by host
+        subdomain: extract_subdomain(&host, &state.config.tunnel_domain)
+            .unwrap_or_else(|| host.split(':').next().unwrap_or(&host).to_string()),
+        method: request.method().to_string(),
+        path: request.uri().path().to_string(),
+        status: 0,
+        bytes: 0,
+        duration_ms: 0,
+        client_ip: addr.ip(),
+    };
+
+    let response = route_ingress(state, host, addr, request).await;
+    record_access(access_log, entry, started, response)
+}
+
+/// Log the request once its response body has been sent (or abandoned),
+/// so the entry carries the real byte count and duration
+fn record_access(
+    access_log: AccessLog,
+    mut entry: AccessLogEntry,
+    started: std::time::Instant,
+    response: Response<Body>,
+) -> Response<Body> {
+    entry.status = response.status().as_u16();
+    let (parts, body) = response.into_parts();
+    let mut recorder = AccessRecorder {
+        access_log,
+        entry,
+        started,
+    };
+    let body = body.into_data_stream().map(move |chunk| {
+        if let Ok(bytes) = &chunk {
+            recorder.add_bytes(bytes.len());
+        }
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+/// Records its entry when dropped along with the response body
+struct AccessRecorder {
+    access_log: AccessLog,
+    entry: AccessLogEntry,
+    started: std::time::Instant,
+}
+
+impl AccessRecorder {
+    fn add_bytes(&mut self, len: usize) {
+        self.entry.bytes += len as u64;
+    }
+}
+
+impl Drop for AccessRecorder {
+    fn drop(&mut self) {
+        self.entry.duration_ms = self.started.elapsed().as_millis() as u64;
+        self.access_log.record(self.entry.clone());
+    }
+}
+
+async fn route_ingress(
+    state: AppState,
+    host: String,
+    addr: SocketAddr,
+    mut request: Request<Body>,
+) -> Response<Body> {
+    if !count_hop(request.headers_mut()) {
+        return loop_detected_response();
+    }
+    set_forwarded_headers(request.headers_mut(), addr.ip(), &host);
+
     // Extract subdomain from host header (tunnel domain: *.dvaar.app)
     let subdomain = match extract_subdomain(&host, &state.config.tunnel_domain) {
         Some(s) => s,
@@ -86,30 +229,197 @@ pub async fn handle_ingress(
 
     tracing::debug!("Ingress request for subdomain: {}", subdomain);
 
+    forward_ingress(&state, subdomain, host, request).await
+}
+
+/// Send the request to the subdomain's tunnel, wherever it is connected
+async fn forward_ingress(
+    state: &AppState,
+    subdomain: String,
+    host: String,
+    mut request: Request<Body>,
+) -> Response<Body> {
+    // Start a trace here unless the caller is already part of one
+    ensure_traceparent(request.headers_mut());
+
+    // Only we get to say a request arrived through a wildcard
+    set_wildcard_host(request.headers_mut(), None);
+
+    // Share links lead to the client's inspector, never the app, and only
+    // while the subdomain is held by whoever minted them
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let owner = if share_links::is_share_path(request.uri().path()) {
+        current_owner(state, &subdomain).await
+    } else {
+        None
+    };
+    if let Some(response) = share_links::handle_share_path(
+        &state.config.share_link_secret,
+        &subdomain,
+        owner.as_deref(),
+        &mut request,
+        now,
+    ) {
+        return response;
+    }
+
     // Check 1: Local tunnel
     if let Some(handle) = state.tunnels.get(&subdomain) {
-        return forward_to_local_tunnel(&handle, request).await;
+        return forward_within_rate_limit(&state.rate_limiter, &handle, request).await;
     }
 
-    // Check 2: Remote node via Redis
-    match state.route_manager.get_route(&subdomain).await {
-        Ok(Some(route_info)) => {
+    // Check 2: Redis route, exact or via a wildcard parent
+    let lookup = |name: String| {
+        let route_manager = state.route_manager.clone();
+        async move { route_manager.get_route(&name).await }
+    };
+    match resolve_route(&subdomain, lookup).await {
+        Ok(Some((owner, route_info))) => {
+            if owner != subdomain {
+                tracing::debug!("Wildcard match: {} -> {}", subdomain, owner);
+                set_wildcard_host(request.headers_mut(), Some(&host));
+                if let Some(handle) = state.tunnels.get(&owner) {
+                    return forward_within_rate_limit(&state.rate_limiter, &handle, request).await;
+                }
+            }
             // Proxy to remote node
-            forward_to_remote_node(&state, &subdomain, &route_info, request).await
+            forward_to_remote_node(state, &owner, &route_info, request).await
+        }
+        Ok(None) => offline_response(state, &subdomain, StatusCode::NOT_FOUND).await,
+        Err(e) => {
+            tracing::error!("Redis error: {}", e);
+            // Redis being down doesn't stop this node serving its own tunnels
+            if let Some(owner) = local_wildcard_owner(&state.tunnels, &subdomain) {
+                set_wildcard_host(request.headers_mut(), Some(&host));
+                if let Some(handle) = state.tunnels.get(&owner) {
+                    return forward_within_rate_limit(&state.rate_limiter, &handle, request).await;
+                }
+            }
+            Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header("Retry-After", "5")
+                .body(Body::from("Routing temporarily unavailable"))
+                .unwrap()
         }
-        Ok(None) => (StatusCode::NOT_FOUND, "Tunnel not found").into_response(),
+    }
+}
+
+/// The user whose tunnel holds `subdomain` right now, if any
+async fn current_owner(state: &AppState, subdomain: &str) -> Option<String> {
+    if let Some(handle) = state.tunnels.get(subdomain) {
+        return Some(handle.user_id.clone());
+    }
+    match state.route_manager.get_route(subdomain).await {
+        Ok(route) => route.map(|route| route.user_id),
         Err(e) => {
             tracing::error!("Redis error: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, "Internal error").into_response()
+            None
+        }
+    }
+}
+
+/// The nearest parent of `subdomain` connected to this node as a wildcard
+/// tunnel, for when Redis can't be asked
+fn local_wildcard_owner(tunnels: &DashMap<String, TunnelHandle>, subdomain: &str) -> Option<String> {
+    let mut rest = subdomain;
+    while let Some((_, parent)) = rest.split_once('.') {
+        if tunnels.get(parent).is_some_and(|handle| handle.wildcard) {
+            return Some(parent.to_string());
         }
+        rest = parent;
     }
+    None
+}
+
+/// 405 (listing what is allowed) or 403 for a request the tunnel's access
+/// rules refuse
+fn access_denied_response(access: &AccessRules, denial: Denial) -> Response<Body> {
+    match denial {
+        Denial::Method => {
+            let allow = access.allowed_methods().iter().map(|m| m.as_str()).collect::<Vec<_>>().join(", ");
+            Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(axum::http::header::ALLOW, allow)
+                .body(Body::from("Method not allowed"))
+                .unwrap()
+        }
+        Denial::Path => (StatusCode::FORBIDDEN, "Forbidden").into_response(),
+    }
+}
+
+/// Forward a request to a local tunnel unless its owner is over their plan's
+/// request rate. All of a user's tunnels draw on one budget, wherever they
+/// are connected, so spreading traffic over more subdomains gains nothing.
+pub(crate) async fn forward_within_rate_limit(
+    rate_limiter: &RateLimiter,
+    handle: &TunnelHandle,
+    request: Request<Body>,
+) -> Response<Body> {
+    match rate_limiter.check_user_requests(&handle.user_id, &handle.plan).await {
+        Ok(limit) if !limit.allowed => {
+            tracing::debug!("Request rate limit exceeded for user {}: {}/{}", handle.user_id, limit.current, limit.limit);
+            return rate_limit_response(&limit);
+        }
+        Err(e) => tracing::error!("Request rate limit check failed: {}", e),
+        Ok(_) => {}
+    }
+    forward_to_local_tunnel(handle, request).await
 }
 
 /// Forward request to a local tunnel with streaming support
-async fn forward_to_local_tunnel(
+pub(crate) async fn forward_to_local_tunnel(
     handle: &crate::routes::TunnelHandle,
     request: Request<Body>,
 ) -> Response<Body> {
+    // The owner has taken the app down on purpose; nothing reaches it
+    if let Some(page) = handle.maintenance.page() {
+        return maintenance_response(page);
+    }
+
+    // Route is up but the tunnel isn't wired yet; ask the caller to come back
+    if !handle.is_ready() {
+        return Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Retry-After", "1")
+            .body(Body::from("tunnel starting"))
+            .unwrap();
+    }
+
+    if let Err(denial) = handle.access.check(request.method(), request.uri().path()) {
+        return access_denied_response(&handle.access, denial);
+    }
+
+    // Repeat GETs for cacheable responses are answered without crossing the
+    // tunnel. Entries belong to this connection, so a subdomain that changes
+    // hands never serves what the previous tunnel returned.
+    let cached = handle
+        .response_cache
+        .as_ref()
+        .zip(handle.route.as_ref())
+        .and_then(|(cache, route)| Some((cache, ResponseCache::key(&route.connection_id, &request)?)));
+    if let Some((cache, key)) = cached {
+        if let Some(response) = cache.get(&key, request.headers()) {
+            return response;
+        }
+        return cache.store(key, send_to_tunnel(handle, request).await);
+    }
+
+    send_to_tunnel(handle, request).await
+}
+
+/// Send a request that passed the tunnel's checks through it, streaming
+/// the response back
+async fn send_to_tunnel(handle: &TunnelHandle, request: Request<Body>) -> Response<Body> {
+    // Past its limit a tunnel gets no more streams until some finish; the
+    // slot is held until the response body (or WebSocket) is done
+    let Ok(stream_slot) = handle.stream_slots.clone().try_acquire_owned() else {
+        return Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Retry-After", "1")
+            .body(Body::from("Too many requests in flight for this tunnel"))
+            .unwrap();
+    };
+
     let stream_id = new_stream_id();
     let (mut parts, body) = request.into_parts();
 
@@ -135,6 +445,9 @@ async fn forward_to_local_tunnel(
         .iter()
         .filter_map(|(k, v)| v.to_str().ok().map(|s| (k.to_string(), s.to_string())))
         .collect();
+    if let Err(e) = check_header_limits(&headers) {
+        return (StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE, e.to_string()).into_response();
+    }
 
     let http_request = HttpRequestPacket {
         stream_id: stream_id.clone(),
@@ -147,7 +460,16 @@ async fn forward_to_local_tunnel(
         headers,
     };
 
-    let (response_tx, mut response_rx) = mpsc::channel::<StreamChunk>(32);
+    // An `Expect: 100-continue` body stays unread, and so the visitor
+    // unanswered, until the client says to go ahead
+    let (mut continue_tx, continue_rx) = if http_request.expects_continue() {
+        let (tx, rx) = oneshot::channel::<()>();
+        (Some(tx), Some(rx))
+    } else {
+        (None, None)
+    };
+
+    let (response_tx, mut response_rx) = mpsc::channel::<StreamChunk>(handle.stream_capacity);
     let tunnel_request = TunnelRequest {
         request: http_request,
         response_tx,
@@ -162,9 +484,25 @@ async fn forward_to_local_tunnel(
         return (StatusCode::BAD_GATEWAY, "Tunnel disconnected").into_response();
     }
 
+    // From here on, dropping this handler (client disconnect) cancels the upstream request
+    let mut cancel_guard = CancelOnDrop::new(handle.request_tx.clone(), stream_id.clone());
+
     let request_tx = handle.request_tx.clone();
     let stream_id_for_body = stream_id.clone();
     tokio::spawn(async move {
+        if let Some(continue_rx) = continue_rx {
+            // Hyper sends `100 Continue` when the body is first polled. If the
+            // final response comes first the body is never read at all.
+            let timeout = Duration::from_millis(constants::CONTINUE_TIMEOUT_MS);
+            if let Ok(Err(_)) = tokio::time::timeout(timeout, continue_rx).await {
+                let _ = request_tx
+                    .send(TunnelCommand::End {
+                        stream_id: stream_id_for_body,
+                    })
+                    .await;
+                return;
+            }
+        }
         let mut body_stream = body.into_data_stream();
         while let Some(chunk_result) = body_stream.next().await {
             match chunk_result {
@@ -193,18 +531,31 @@ async fn forward_to_local_tunnel(
             .await;
     });
 
-    let first_chunk = match response_rx.recv().await {
+    let first_chunk = loop {
+        match response_rx.recv().await {
+            Some(StreamChunk::Continue) => {
+                if let Some(continue_tx) = continue_tx.take() {
+                    let _ = continue_tx.send(());
+                }
+            }
+            chunk => break chunk,
+        }
+    };
+    drop(continue_tx);
+    let first_chunk = match first_chunk {
         Some(chunk) => chunk,
         None => {
+            cancel_guard.disarm();
             return (StatusCode::BAD_GATEWAY, "No response from tunnel").into_response();
         }
     };
 
     let headers_packet = match first_chunk {
         StreamChunk::Headers(h) => h,
-        StreamChunk::Error(e) => {
-            tracing::error!("Tunnel error: {}", e);
-            return (StatusCode::BAD_GATEWAY, "Tunnel error").into_response();
+        StreamChunk::Error { code, message } => {
+            cancel_guard.disarm();
+            tracing::error!("Tunnel error ({:?}): {}", code, message);
+            return (stream_error_status(code), message).into_response();
         }
         _ => {
             tracing::error!("Expected Headers chunk, got something else");
@@ -213,6 +564,8 @@ async fn forward_to_local_tunnel(
     };
 
     if headers_packet.is_websocket_upgrade() {
+        // The WebSocket bridge signals its own close
+        cancel_guard.disarm();
         let Some(ws_upgrade) = ws_upgrade else {
             return (StatusCode::BAD_GATEWAY, "WebSocket upgrade failed").into_response();
         };
@@ -230,6 +583,7 @@ async fn forward_to_local_tunnel(
         let request_tx = handle.request_tx.clone();
         let stream_id_clone = stream_id.clone();
         return ws_upgrade.on_upgrade(move |socket| async move {
+            let _stream_slot = stream_slot;
             bridge_websocket(socket, response_rx, request_tx, stream_id_clone).await;
         });
     }
@@ -241,29 +595,136 @@ async fn forward_to_local_tunnel(
         builder = builder.header(key.as_str(), value.as_str());
     }
 
+    // Nothing to wait for; any stray chunks land on the closed channel
+    if !headers_packet.has_body(parts.method.as_str()) {
+        cancel_guard.disarm();
+        return builder
+            .body(Body::empty())
+            .unwrap_or_else(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Response build failed").into_response());
+    }
+
+    // The guard moves into the body stream: if hyper drops the body before End,
+    // the client is told to abort the upstream request.
+    // Headers are already out, so a failure mid-body is surfaced by erroring the
+    // stream: hyper then aborts the connection (or resets the h2 stream) instead of
+    // finishing the response, and the browser can't mistake a partial body for a whole one.
+    // A flow-controlled client is granted more window as the visitor reads, so
+    // a slow reader holds back the client rather than queueing up here.
+    let window_tx = handle.flow_control.then(|| handle.request_tx.clone());
     let body_stream = async_stream::stream! {
-        while let Some(chunk) = response_rx.recv().await {
-            match chunk {
-                StreamChunk::Data(data) => {
-                    yield Ok::<_, std::io::Error>(axum::body::Bytes::from(data));
+        let mut cancel_guard = cancel_guard;
+        let _stream_slot = stream_slot;
+        let mut unacknowledged = 0usize;
+        loop {
+            match response_rx.recv().await {
+                Some(StreamChunk::Data(data)) => {
+                    let len = data.len();
+                    yield Ok::<_, std::io::Error>(Frame::data(axum::body::Bytes::from(data)));
+                    // Polled again, so hyper has taken the chunk
+                    unacknowledged += len;
+                    if let Some(window_tx) = window_tx.as_ref().filter(|_| unacknowledged >= WINDOW_UPDATE_BYTES) {
+                        let _ = window_tx
+                            .send(TunnelCommand::WindowUpdate {
+                                stream_id: stream_id.clone(),
+                                bytes: std::mem::take(&mut unacknowledged) as u32,
+                            })
+                            .await;
+                    }
                 }
-                StreamChunk::End => {
+                Some(StreamChunk::Trailers(trailers)) => {
+                    yield Ok(Frame::trailers(trailer_map(&trailers)));
+                }
+                Some(StreamChunk::End) => {
+                    cancel_guard.disarm();
                     break;
                 }
-                StreamChunk::Error(e) => {
+                Some(StreamChunk::Error { message: e, .. }) => {
+                    cancel_guard.disarm();
                     tracing::error!("Stream error: {}", e);
+                    yield Err(std::io::Error::other(e));
+                    break;
+                }
+                Some(_) => {}
+                None => {
+                    yield Err(std::io::Error::other("tunnel closed before the response finished"));
                     break;
                 }
-                _ => {}
             }
         }
     };
 
     builder
-        .body(Body::from_stream(body_stream))
+        .body(Body::new(StreamBody::new(body_stream)))
         .unwrap_or_else(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Response build failed").into_response())
 }
 
+/// Status for a stream that failed before its response headers
+fn stream_error_status(code: StreamErrorCode) -> StatusCode {
+    match code {
+        StreamErrorCode::UpstreamDown => StatusCode::SERVICE_UNAVAILABLE,
+        StreamErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        StreamErrorCode::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        StreamErrorCode::Cancelled | StreamErrorCode::Other => StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// Trailers as a header map, dropping any the client sent malformed.
+/// hyper only writes them on HTTP/1.1 if the response declared them in a
+/// `Trailer` header and the request sent `TE: trailers`; HTTP/2 always does.
+fn trailer_map(trailers: &[(String, String)]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for (key, value) in trailers {
+        match (HeaderName::from_bytes(key.as_bytes()), HeaderValue::from_str(value)) {
+            (Ok(name), Ok(value)) => {
+                map.append(name, value);
+            }
+            _ => tracing::debug!("Dropping malformed trailer {}", key),
+        }
+    }
+    map
+}
+
+/// Sends a `TunnelCommand::Cancel` for the stream when dropped, unless disarmed
+/// after the response completed.
+struct CancelOnDrop {
+    request_tx: mpsc::Sender<TunnelCommand>,
+    stream_id: String,
+    armed: bool,
+}
+
+impl CancelOnDrop {
+    fn new(request_tx: mpsc::Sender<TunnelCommand>, stream_id: String) -> Self {
+        Self {
+            request_tx,
+            stream_id,
+            armed: true,
+        }
+    }
+
+    fn disarm(&mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        let command = TunnelCommand::Cancel {
+            stream_id: std::mem::take(&mut self.stream_id),
+        };
+        // Drop can't await; fall back to a task if the channel is momentarily full
+        if let Err(mpsc::error::TrySendError::Full(command)) = self.request_tx.try_send(command) {
+            let request_tx = self.request_tx.clone();
+            tokio::spawn(async move {
+                let _ = request_tx.send(command).await;
+            });
+        }
+    }
+}
+
 /// Forward request to a remote node with streaming support
 async fn forward_to_remote_node(
     state: &AppState,
@@ -303,7 +764,9 @@ async fn forward_to_remote_node(
             .uri(&proxy_url);
 
         for (key, value) in &parts.headers {
-            if key.as_str().eq_ignore_ascii_case("host") {
+            // The upgrade answered here never takes up an extension, so the
+            // node behind it mustn't be offered one either
+            if key == axum::http::header::HOST || key == axum::http::header::SEC_WEBSOCKET_EXTENSIONS {
                 continue;
             }
             if let Ok(v) = value.to_str() {
@@ -352,6 +815,34 @@ async fn forward_to_remote_node(
         }
     }
 
+    let original_host = state.config.full_domain(subdomain);
+    match proxy_to_node(
+        &state.node_client,
+        &state.config.cluster_secret,
+        route_info,
+        &original_host,
+        Request::from_parts(parts, body),
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::error!("Proxy request failed: {}", e);
+            offline_response(state, subdomain, StatusCode::BAD_GATEWAY).await
+        }
+    }
+}
+
+/// Send a request to the node holding its tunnel. Both bodies are streamed,
+/// so neither node holds more than a few chunks of an upload or download.
+async fn proxy_to_node(
+    node_client: &reqwest::Client,
+    cluster_secret: &str,
+    route_info: &RouteInfo,
+    original_host: &str,
+    request: Request<Body>,
+) -> Result<Response<Body>, reqwest::Error> {
+    let (parts, body) = request.into_parts();
     let proxy_url = format!(
         "http://{}:{}/_internal/proxy{}",
         route_info.node_ip,
@@ -363,7 +854,7 @@ async fn forward_to_remote_node(
             .unwrap_or_else(|| "/".to_string())
     );
 
-    let mut proxy_request = state.http_client.request(
+    let mut proxy_request = node_client.request(
         reqwest::Method::from_bytes(parts.method.as_str().as_bytes()).unwrap_or(reqwest::Method::GET),
         &proxy_url,
     );
@@ -378,38 +869,186 @@ async fn forward_to_remote_node(
         result.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
     });
 
-    proxy_request = proxy_request
-        .header(constants::CLUSTER_SECRET_HEADER, &state.config.cluster_secret)
-        .header(
-            constants::ORIGINAL_HOST_HEADER,
-            state.config.full_domain(subdomain),
-        )
-        .body(reqwest::Body::wrap_stream(body_stream));
+    let resp = proxy_request
+        .header(constants::CLUSTER_SECRET_HEADER, cluster_secret)
+        .header(constants::ORIGINAL_HOST_HEADER, original_host)
+        .body(reqwest::Body::wrap_stream(body_stream))
+        .send()
+        .await?;
 
-    match proxy_request.send().await {
-        Ok(resp) => {
-            let status = resp.status();
-            let headers = resp.headers().clone();
+    let status = resp.status();
+    let headers = resp.headers().clone();
 
-            let body_stream = resp.bytes_stream().map(|result| {
-                result.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
-            });
+    let body_stream = resp.bytes_stream().map(|result| {
+        result.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    });
 
-            let mut builder =
-                Response::builder().status(StatusCode::from_u16(status.as_u16()).unwrap_or(StatusCode::OK));
+    let mut builder =
+        Response::builder().status(StatusCode::from_u16(status.as_u16()).unwrap_or(StatusCode::OK));
 
-            for (key, value) in headers.iter() {
-                builder = builder.header(key.as_str(), value.as_bytes());
-            }
+    for (key, value) in headers.iter() {
+        builder = builder.header(key.as_str(), value.as_bytes());
+    }
 
-            builder
-                .body(Body::from_stream(body_stream))
-                .unwrap_or_else(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Response build failed").into_response())
-        }
+    Ok(builder
+        .body(Body::from_stream(body_stream))
+        .unwrap_or_else(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Response build failed").into_response()))
+}
+
+/// Respond for a tunnel that can't be reached, using its registered offline page if any
+async fn offline_response(state: &AppState, subdomain: &str, status: StatusCode) -> Response<Body> {
+    let custom = match state.route_manager.get_offline_page(subdomain).await {
+        Ok(page) => page,
         Err(e) => {
-            tracing::error!("Proxy request failed: {}", e);
-            (StatusCode::BAD_GATEWAY, "Remote node unavailable").into_response()
+            tracing::error!("Failed to load offline page for {}: {}", subdomain, e);
+            None
+        }
+    };
+    render_offline_page(subdomain, status, custom)
+}
+
+fn render_offline_page(subdomain: &str, status: StatusCode, custom: Option<String>) -> Response<Body> {
+    let html = custom.unwrap_or_else(|| {
+        DEFAULT_OFFLINE_PAGE.replace("{subdomain}", &html_escape(subdomain))
+    });
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(html))
+        .unwrap_or_else(|_| (status, "Tunnel not found").into_response())
+}
+
+fn maintenance_response(page: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(page))
+        .unwrap()
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Bump the hop header on the way in. A tunnel whose upstream is its own
+/// public URL (or another tunnel leading back) sends the request round again
+/// with the header forwarded, so past `MAX_HOPS` it is answered with 508
+/// instead of looping until something runs out. Returns false for such a request.
+fn count_hop(headers: &mut axum::http::HeaderMap) -> bool {
+    let hops = headers
+        .get(constants::HOP_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u32>().ok())
+        .unwrap_or(0)
+        .saturating_add(1);
+
+    if hops > constants::MAX_HOPS {
+        tracing::warn!("Request looped through ingress {} times, rejecting", hops - 1);
+        return false;
+    }
+    headers.insert(constants::HOP_HEADER, HeaderValue::from(hops));
+    true
+}
+
+/// Describe the visitor's connection to the upstream: their IP is appended to
+/// `X-Forwarded-For`, and `X-Forwarded-Proto`/`X-Forwarded-Host` are replaced
+/// with the public scheme and host, so apps build absolute URLs that point at
+/// the tunnel rather than at localhost
+pub(crate) fn set_forwarded_headers(headers: &mut axum::http::HeaderMap, client_ip: IpAddr, host: &str) {
+    let mut forwarded_for: Vec<String> = headers
+        .get_all(constants::FORWARDED_FOR_HEADER)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .map(str::to_string)
+        .collect();
+    forwarded_for.push(client_ip.to_string());
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for.join(", ")) {
+        headers.insert(constants::FORWARDED_FOR_HEADER, value);
+    }
+
+    headers.insert(constants::FORWARDED_PROTO_HEADER, HeaderValue::from_static("https"));
+    headers.remove(constants::FORWARDED_HOST_HEADER);
+    if let Ok(value) = HeaderValue::from_str(host) {
+        headers.insert(constants::FORWARDED_HOST_HEADER, value);
+    }
+}
+
+fn loop_detected_response() -> Response<Body> {
+    (
+        StatusCode::LOOP_DETECTED,
+        "Loop detected: this tunnel's upstream points back at a dvaar URL",
+    )
+        .into_response()
+}
+
+/// Add a W3C `traceparent` header if the request doesn't carry one.
+/// An existing header is always left untouched so upstream traces stay connected.
+fn ensure_traceparent(headers: &mut axum::http::HeaderMap) {
+    if headers.contains_key(TRACEPARENT_HEADER) {
+        return;
+    }
+    if let Ok(value) = axum::http::HeaderValue::from_str(&generate_traceparent()) {
+        headers.insert(TRACEPARENT_HEADER, value);
+    }
+}
+
+/// Generate a sampled `traceparent` value: `00-<trace-id>-<parent-id>-01`
+fn generate_traceparent() -> String {
+    let mut rng = rand::thread_rng();
+    // All-zero ids are invalid per the spec
+    let trace_id: [u8; 16] = loop {
+        let id: [u8; 16] = rng.gen();
+        if id != [0; 16] {
+            break id;
+        }
+    };
+    let parent_id: [u8; 8] = loop {
+        let id: [u8; 8] = rng.gen();
+        if id != [0; 8] {
+            break id;
+        }
+    };
+    format!("00-{}-{}-01", hex::encode(trace_id), hex::encode(parent_id))
+}
+
+/// Find the route serving a subdomain. An exact route wins; otherwise leading
+/// labels are stripped (`a.b.myapp` -> `b.myapp` -> `myapp`) until a route
+/// registered as a wildcard turns up. Returns the owning subdomain with its route.
+async fn resolve_route<F, Fut>(
+    subdomain: &str,
+    mut lookup: F,
+) -> anyhow::Result<Option<(String, RouteInfo)>>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = anyhow::Result<Option<RouteInfo>>>,
+{
+    if let Some(route) = lookup(subdomain.to_string()).await? {
+        return Ok(Some((subdomain.to_string(), route)));
+    }
+
+    let mut rest = subdomain;
+    while let Some((_, parent)) = rest.split_once('.') {
+        if let Some(route) = lookup(parent.to_string()).await? {
+            if route.wildcard {
+                return Ok(Some((parent.to_string(), route)));
+            }
         }
+        rest = parent;
+    }
+    Ok(None)
+}
+
+/// Set (or with `None`, strip) the header telling the client which host a
+/// wildcard-routed request was sent to
+fn set_wildcard_host(headers: &mut axum::http::HeaderMap, host: Option<&str>) {
+    headers.remove(constants::WILDCARD_HOST_HEADER);
+    if let Some(value) = host.and_then(|h| HeaderValue::from_str(h).ok()) {
+        headers.insert(constants::WILDCARD_HOST_HEADER, value);
     }
 }
 
@@ -491,7 +1130,7 @@ async fn bridge_websocket(
                     let _ = ws_sender.send(Message::Close(close_frame)).await;
                     break;
                 }
-                StreamChunk::Error(e) => {
+                StreamChunk::Error { message: e, .. } => {
                     tracing::error!("WebSocket stream error: {}", e);
                     let _ = ws_sender.send(Message::Close(None)).await;
                     break;
@@ -666,3 +1305,1013 @@ fn tungstenite_to_axum_message(message: tungstenite::Message) -> Option<Message>
         tungstenite::Message::Frame(_) => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_ipv6_loopback_and_mapped_clients() {
+        let client = |addr: &str| client_addr(addr.parse().unwrap()).ip();
+
+        assert!(client("[::1]:5000").is_loopback());
+        assert_eq!(client("[::ffff:127.0.0.1]:5000"), IpAddr::from([127, 0, 0, 1]));
+        assert!(client("[::ffff:127.0.0.1]:5000").is_loopback());
+        assert_eq!(client("[::ffff:203.0.113.9]:5000"), IpAddr::from([203, 0, 113, 9]));
+        assert!(!client("[2001:db8::1]:5000").is_loopback());
+        assert!(client("127.0.0.1:5000").is_loopback());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_response_cancels_stream() {
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(4);
+
+        drop(CancelOnDrop::new(request_tx.clone(), "stream-1".to_string()));
+        match request_rx.try_recv() {
+            Ok(TunnelCommand::Cancel { stream_id }) => assert_eq!(stream_id, "stream-1"),
+            other => panic!("expected Cancel, got {:?}", other),
+        }
+
+        let mut guard = CancelOnDrop::new(request_tx, "stream-2".to_string());
+        guard.disarm();
+        drop(guard);
+        assert!(request_rx.try_recv().is_err());
+    }
+
+    /// Ingress whose tunnel's upstream is its own public URL: each pass
+    /// forwards the request, headers included, back to itself as the client would
+    #[tokio::test]
+    async fn test_self_referential_upstream_is_cut_off() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let passes = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+
+        let app = {
+            let url = url.clone();
+            let passes = passes.clone();
+            axum::Router::new().fallback(move |mut request: Request<Body>| {
+                let url = url.clone();
+                let passes = passes.clone();
+                async move {
+                    passes.fetch_add(1, Ordering::SeqCst);
+                    if !count_hop(request.headers_mut()) {
+                        return loop_detected_response();
+                    }
+                    let upstream = reqwest::Client::new()
+                        .get(&url)
+                        .headers(request.headers().clone())
+                        .send()
+                        .await
+                        .unwrap();
+                    let status = StatusCode::from_u16(upstream.status().as_u16()).unwrap();
+                    (status, upstream.bytes().await.unwrap()).into_response()
+                }
+            })
+        };
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let response = tokio::time::timeout(std::time::Duration::from_secs(10), reqwest::get(&url))
+            .await
+            .expect("loop should be broken, not hang")
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 508);
+        assert_eq!(passes.load(Ordering::SeqCst), constants::MAX_HOPS + 1);
+
+        // A garbled counter starts over rather than slipping past the check
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert(constants::HOP_HEADER, HeaderValue::from_static("lots"));
+        assert!(count_hop(&mut headers));
+        assert_eq!(headers[constants::HOP_HEADER], "1");
+    }
+
+    #[test]
+    fn test_traceparent_added_when_missing() {
+        let mut headers = axum::http::HeaderMap::new();
+        ensure_traceparent(&mut headers);
+
+        let value = headers.get(TRACEPARENT_HEADER).unwrap().to_str().unwrap();
+        let parts: Vec<&str> = value.split('-').collect();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], "00");
+        assert_eq!(parts[1].len(), 32);
+        assert_eq!(parts[2].len(), 16);
+        assert_eq!(parts[3], "01");
+        assert!(parts[1].chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn test_existing_traceparent_preserved() {
+        let existing = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert(TRACEPARENT_HEADER, existing.parse().unwrap());
+
+        ensure_traceparent(&mut headers);
+
+        assert_eq!(headers.get_all(TRACEPARENT_HEADER).iter().count(), 1);
+        assert_eq!(headers.get(TRACEPARENT_HEADER).unwrap(), existing);
+    }
+
+    #[test]
+    fn test_local_wildcard_owner_without_redis() {
+        let tunnels = DashMap::new();
+        let (request_tx, _request_rx) = mpsc::channel::<TunnelCommand>(1);
+        let mut wildcard = TunnelHandle::new(request_tx.clone(), "user-1".to_string());
+        wildcard.wildcard = true;
+        tunnels.insert("app".to_string(), wildcard);
+        tunnels.insert("plain".to_string(), TunnelHandle::new(request_tx, "user-2".to_string()));
+
+        assert_eq!(local_wildcard_owner(&tunnels, "pr-1.app"), Some("app".to_string()));
+        assert_eq!(local_wildcard_owner(&tunnels, "a.b.app"), Some("app".to_string()));
+        // Only wildcard tunnels answer for their children
+        assert_eq!(local_wildcard_owner(&tunnels, "pr-1.plain"), None);
+        assert_eq!(local_wildcard_owner(&tunnels, "app"), None);
+        assert_eq!(local_wildcard_owner(&tunnels, "other"), None);
+    }
+
+    #[tokio::test]
+    async fn test_mid_stream_error_aborts_response() {
+        use crate::routes::TunnelHandle;
+        use std::sync::Arc;
+
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+        let handle = TunnelHandle::new(request_tx, "user-1".to_string());
+        handle.ready.store(true, Ordering::Release);
+        let handle = Arc::new(handle);
+
+        // Fake tunnel: send headers and part of the body, then fail
+        tokio::spawn(async move {
+            while let Some(command) = request_rx.recv().await {
+                if let TunnelCommand::Request(req) = command {
+                    let tx = req.response_tx;
+                    let _ = tx
+                        .send(StreamChunk::Headers(dvaar_common::HttpResponsePacket {
+                            stream_id: req.request.stream_id,
+                            status: 200,
+                            headers: vec![],
+                        }))
+                        .await;
+                    let _ = tx.send(StreamChunk::Data(b"partial".to_vec())).await;
+                    let _ = tx
+                        .send(StreamChunk::Error {
+                            code: StreamErrorCode::Other,
+                            message: "upstream reset".to_string(),
+                        })
+                        .await;
+                }
+            }
+        });
+
+        let app = axum::Router::new().fallback(move |request: Request<Body>| {
+            let handle = handle.clone();
+            async move { forward_to_local_tunnel(&handle, request).await }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        // Depending on how far hyper got flushing, the client fails either while
+        // reading the head or the body; either way it must not see a clean EOF
+        let url = format!("http://{}/", addr);
+        let result = async { reqwest::get(&url).await?.bytes().await }.await;
+        assert!(result.is_err(), "partial body must not end cleanly: {:?}", result);
+    }
+
+    #[tokio::test]
+    async fn test_stream_error_codes_map_to_statuses() {
+        use crate::routes::TunnelHandle;
+
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+        let handle = TunnelHandle::new(request_tx, "user-1".to_string());
+        handle.ready.store(true, Ordering::Release);
+
+        // Fake tunnel failing each request with the code named by its path
+        tokio::spawn(async move {
+            while let Some(command) = request_rx.recv().await {
+                if let TunnelCommand::Request(req) = command {
+                    let code: u16 = req.request.uri.trim_start_matches('/').parse().unwrap();
+                    let _ = req
+                        .response_tx
+                        .send(StreamChunk::Error {
+                            code: StreamErrorCode::from(code),
+                            message: "upstream said no".to_string(),
+                        })
+                        .await;
+                }
+            }
+        });
+
+        for (code, expected) in [
+            (StreamErrorCode::UpstreamDown, StatusCode::SERVICE_UNAVAILABLE),
+            (StreamErrorCode::Timeout, StatusCode::GATEWAY_TIMEOUT),
+            (StreamErrorCode::BodyTooLarge, StatusCode::PAYLOAD_TOO_LARGE),
+            (StreamErrorCode::Cancelled, StatusCode::BAD_GATEWAY),
+            (StreamErrorCode::Other, StatusCode::BAD_GATEWAY),
+        ] {
+            let request = Request::builder()
+                .uri(format!("/{}", u16::from(code)))
+                .body(Body::empty())
+                .unwrap();
+            let response = forward_to_local_tunnel(&handle, request).await;
+            assert_eq!(response.status(), expected, "{:?}", code);
+
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(&body[..], b"upstream said no");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_reader_holds_back_a_flow_controlled_client() {
+        use crate::routes::TunnelHandle;
+        use http_body_util::BodyExt;
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::Arc;
+
+        const CHUNK: usize = 64 * 1024;
+        const WINDOW: usize = constants::STREAM_WINDOW_BYTES;
+        const TOTAL: usize = 4 * WINDOW;
+
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+        let mut handle = TunnelHandle::new(request_tx, "user-1".to_string());
+        handle.flow_control = true;
+        handle.ready.store(true, Ordering::Release);
+
+        // Fake client sending a large body as fast as its window allows
+        let sent = Arc::new(AtomicUsize::new(0));
+        let sent_by_client = sent.clone();
+        tokio::spawn(async move {
+            let Some(TunnelCommand::Request(req)) = request_rx.recv().await else {
+                panic!("expected a request");
+            };
+            let tx = req.response_tx;
+            let _ = tx
+                .send(StreamChunk::Headers(dvaar_common::HttpResponsePacket {
+                    stream_id: req.request.stream_id,
+                    status: 200,
+                    headers: Vec::new(),
+                }))
+                .await;
+            let mut credit = WINDOW;
+            while sent_by_client.load(Ordering::SeqCst) < TOTAL {
+                while credit < CHUNK {
+                    match request_rx.recv().await {
+                        Some(TunnelCommand::WindowUpdate { bytes, .. }) => credit += bytes as usize,
+                        Some(_) => {}
+                        None => return,
+                    }
+                }
+                credit -= CHUNK;
+                sent_by_client.fetch_add(CHUNK, Ordering::SeqCst);
+                let _ = tx.send(StreamChunk::Data(vec![0; CHUNK])).await;
+            }
+            let _ = tx.send(StreamChunk::End).await;
+        });
+
+        let request = Request::builder().uri("/large").body(Body::empty()).unwrap();
+        let mut body = forward_to_local_tunnel(&handle, request).await.into_body();
+        let mut read = 0;
+        while let Some(frame) = body.frame().await {
+            if read == 0 {
+                // A reader that stalls: the client stops at one window
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert_eq!(sent.load(Ordering::SeqCst), WINDOW);
+            }
+            read += frame.unwrap().into_data().map(|data| data.len()).unwrap_or(0);
+            let ahead = sent.load(Ordering::SeqCst) - read;
+            assert!(ahead <= WINDOW, "client got {} bytes ahead of the reader", ahead);
+        }
+        assert_eq!(read, TOTAL);
+    }
+
+    #[tokio::test]
+    async fn test_trailers_reach_downstream() {
+        use crate::routes::TunnelHandle;
+        use http_body_util::BodyExt;
+        use std::sync::Arc;
+
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+        let handle = TunnelHandle::new(request_tx, "user-1".to_string());
+        handle.ready.store(true, Ordering::Release);
+        let handle = Arc::new(handle);
+
+        // Fake tunnel answering like a gRPC-web upstream
+        tokio::spawn(async move {
+            while let Some(command) = request_rx.recv().await {
+                if let TunnelCommand::Request(req) = command {
+                    let tx = req.response_tx;
+                    let _ = tx
+                        .send(StreamChunk::Headers(dvaar_common::HttpResponsePacket {
+                            stream_id: req.request.stream_id,
+                            status: 200,
+                            headers: vec![("trailer".to_string(), "grpc-status".to_string())],
+                        }))
+                        .await;
+                    let _ = tx.send(StreamChunk::Data(b"hello".to_vec())).await;
+                    let _ = tx
+                        .send(StreamChunk::Trailers(vec![("grpc-status".to_string(), "0".to_string())]))
+                        .await;
+                    let _ = tx.send(StreamChunk::End).await;
+                }
+            }
+        });
+
+        let app = axum::Router::new().fallback(move |request: Request<Body>| {
+            let handle = handle.clone();
+            async move { forward_to_local_tunnel(&handle, request).await }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/", addr))
+            .header("te", "trailers")
+            .send()
+            .await
+            .unwrap();
+        let collected = reqwest::Body::from(response).collect().await.unwrap();
+        assert_eq!(collected.trailers().unwrap()["grpc-status"], "0");
+        assert_eq!(collected.to_bytes(), "hello");
+    }
+
+    async fn resolve_in(
+        routes: &[(&str, &str, bool)],
+        subdomain: &str,
+    ) -> Option<(String, String)> {
+        let routes: std::collections::HashMap<String, RouteInfo> = routes
+            .iter()
+            .map(|(name, user, wildcard)| {
+                let mut route = RouteInfo::new("10.0.0.1".to_string(), 6000, user.to_string());
+                route.wildcard = *wildcard;
+                (name.to_string(), route)
+            })
+            .collect();
+        resolve_route(subdomain, |name| {
+            let route = routes.get(&name).cloned();
+            async move { Ok(route) }
+        })
+        .await
+        .unwrap()
+        .map(|(owner, route)| (owner, route.user_id))
+    }
+
+    #[tokio::test]
+    async fn test_exact_route_beats_wildcard() {
+        let routes = [("myapp", "alice", true), ("api.myapp", "bob", false)];
+        assert_eq!(
+            resolve_in(&routes, "api.myapp").await,
+            Some(("api.myapp".to_string(), "bob".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_fallback() {
+        let routes = [("myapp", "alice", true), ("plain", "bob", false)];
+        assert_eq!(
+            resolve_in(&routes, "tenant1.myapp").await,
+            Some(("myapp".to_string(), "alice".to_string()))
+        );
+        assert_eq!(
+            resolve_in(&routes, "a.b.myapp").await,
+            Some(("myapp".to_string(), "alice".to_string()))
+        );
+        // Parents without the wildcard flag don't catch subdomains
+        assert_eq!(resolve_in(&routes, "tenant1.plain").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_host_forwarded_to_client() {
+        use crate::routes::TunnelHandle;
+
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+        let handle = TunnelHandle::new(request_tx, "user-1".to_string());
+        handle.ready.store(true, Ordering::Release);
+
+        let mut request = Request::builder()
+            .uri("/")
+            .header(constants::WILDCARD_HOST_HEADER, "spoofed.example.com")
+            .body(Body::empty())
+            .unwrap();
+        set_wildcard_host(request.headers_mut(), None);
+        assert!(request.headers().get(constants::WILDCARD_HOST_HEADER).is_none());
+        set_wildcard_host(request.headers_mut(), Some("tenant1.myapp.dvaar.app"));
+
+        tokio::spawn(async move { forward_to_local_tunnel(&handle, request).await });
+
+        match request_rx.recv().await {
+            Some(TunnelCommand::Request(req)) => {
+                let forwarded: Vec<&str> = req
+                    .request
+                    .headers
+                    .iter()
+                    .filter(|(k, _)| k.eq_ignore_ascii_case(constants::WILDCARD_HOST_HEADER))
+                    .map(|(_, v)| v.as_str())
+                    .collect();
+                assert_eq!(forwarded, vec!["tenant1.myapp.dvaar.app"]);
+            }
+            other => panic!("expected Request, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_headers_sent_to_client() {
+        use crate::routes::TunnelHandle;
+
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+        let handle = TunnelHandle::new(request_tx, "user-1".to_string());
+        handle.ready.store(true, Ordering::Release);
+
+        // An earlier proxy's hop is kept; a spoofed host and scheme are not
+        let mut request = Request::builder()
+            .uri("/login")
+            .header("X-Forwarded-For", "198.51.100.4")
+            .header("X-Forwarded-Proto", "http")
+            .header("X-Forwarded-Host", "evil.example.com")
+            .body(Body::empty())
+            .unwrap();
+        set_forwarded_headers(request.headers_mut(), "203.0.113.9".parse().unwrap(), "myapp.dvaar.app");
+
+        tokio::spawn(async move { forward_to_local_tunnel(&handle, request).await });
+
+        match request_rx.recv().await {
+            Some(TunnelCommand::Request(req)) => {
+                let values = |name: &str| -> Vec<String> {
+                    req.request
+                        .headers
+                        .iter()
+                        .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+                        .map(|(_, v)| v.clone())
+                        .collect()
+                };
+                assert_eq!(values(constants::FORWARDED_FOR_HEADER), vec!["198.51.100.4, 203.0.113.9"]);
+                assert_eq!(values(constants::FORWARDED_PROTO_HEADER), vec!["https"]);
+                assert_eq!(values(constants::FORWARDED_HOST_HEADER), vec!["myapp.dvaar.app"]);
+            }
+            other => panic!("expected Request, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_before_ready_gets_503() {
+        use crate::routes::TunnelHandle;
+
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+        let handle = TunnelHandle::new(request_tx, "user-1".to_string());
+
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = forward_to_local_tunnel(&handle, request).await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "1");
+        assert_eq!(body_string(response).await, "tunnel starting");
+        // Nothing was queued for a tunnel that can't serve it yet
+        assert!(request_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tunnels_of_one_user_share_a_request_budget() {
+        use crate::routes::billing::PlanFeatures;
+
+        let limiter = RateLimiter::without_redis().await;
+        // Not ready, so every request the limit lets through gets a 503
+        let tunnel = |user: &str| TunnelHandle::new(mpsc::channel(1).0, user.to_string());
+        let (api, web, other) = (tunnel("user-1"), tunnel("user-1"), tunnel("user-2"));
+        let status = |handle| {
+            let limiter = limiter.clone();
+            async move {
+                let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+                forward_within_rate_limit(&limiter, handle, request).await.status()
+            }
+        };
+
+        let budget = PlanFeatures::for_plan("free").requests_per_minute;
+        for n in 0..budget {
+            let handle = if n % 2 == 0 { &api } else { &web };
+            assert_eq!(status(handle).await, StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        // Neither tunnel has a budget of its own left
+        for handle in [&api, &web] {
+            let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+            let response = forward_within_rate_limit(&limiter, handle, request).await;
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(response.headers()["x-ratelimit-limit"], budget.to_string());
+            assert_eq!(response.headers()["x-ratelimit-remaining"], "0");
+        }
+        // Someone else's tunnel is unaffected
+        assert_eq!(status(&other).await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_request_header_limits() {
+        use crate::routes::TunnelHandle;
+
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+        let handle = TunnelHandle::new(request_tx, "user-1".to_string());
+        handle.ready.store(true, Ordering::Release);
+        let handle = std::sync::Arc::new(handle);
+
+        let with_headers = |count: usize| {
+            let mut request = Request::builder().uri("/");
+            for i in 0..count {
+                request = request.header(format!("x-h{}", i), "1");
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = forward_to_local_tunnel(&handle, with_headers(constants::MAX_HEADER_COUNT + 1)).await;
+        assert_eq!(response.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        assert!(request_rx.try_recv().is_err());
+
+        let forwarding = handle.clone();
+        let request = with_headers(constants::MAX_HEADER_COUNT);
+        tokio::spawn(async move { forward_to_local_tunnel(&forwarding, request).await });
+        match request_rx.recv().await {
+            Some(TunnelCommand::Request(req)) => {
+                assert_eq!(req.request.headers.len(), constants::MAX_HEADER_COUNT);
+            }
+            other => panic!("expected Request, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streams_past_the_limit_get_503() {
+        use crate::routes::TunnelHandle;
+        use std::sync::Arc;
+        use tokio::sync::Semaphore;
+
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+        let mut handle = TunnelHandle::new(request_tx, "user-1".to_string());
+        handle.stream_slots = Arc::new(Semaphore::new(2));
+        handle.ready.store(true, Ordering::Release);
+        let handle = Arc::new(handle);
+
+        let send = |path: &str| {
+            let handle = handle.clone();
+            let request = Request::builder().uri(path).body(Body::empty()).unwrap();
+            tokio::spawn(async move { forward_to_local_tunnel(&handle, request).await })
+        };
+
+        // Two requests reach the tunnel and wait on it
+        let first = send("/first");
+        let second = send("/second");
+        let mut pending = Vec::new();
+        while pending.len() < 2 {
+            // Each empty body also sends an `End`
+            if let Some(TunnelCommand::Request(req)) = request_rx.recv().await {
+                pending.push(req);
+            }
+        }
+
+        // The third is turned away without reaching it
+        let response = send("/third").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "1");
+        while let Ok(command) = request_rx.try_recv() {
+            assert!(!matches!(command, TunnelCommand::Request(_)));
+        }
+
+        // Slots come back once the responses have been read to the end
+        for req in pending {
+            req.response_tx
+                .send(StreamChunk::Headers(dvaar_common::HttpResponsePacket {
+                    stream_id: req.request.stream_id,
+                    status: 200,
+                    headers: vec![],
+                }))
+                .await
+                .unwrap();
+            req.response_tx.send(StreamChunk::Data(b"ok".to_vec())).await.unwrap();
+            req.response_tx.send(StreamChunk::End).await.unwrap();
+        }
+        for task in [first, second] {
+            let response = task.await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body_string(response).await, "ok");
+        }
+        let _fourth = send("/fourth");
+        loop {
+            if let Some(TunnelCommand::Request(req)) = request_rx.recv().await {
+                assert_eq!(req.request.uri, "/fourth");
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_answers_instead_of_forwarding() {
+        use crate::routes::TunnelHandle;
+        use std::sync::Arc;
+
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+        let handle = TunnelHandle::new(request_tx, "user-1".to_string());
+        handle.ready.store(true, Ordering::Release);
+        let handle = Arc::new(handle);
+        let request = || Request::builder().uri("/checkout").body(Body::empty()).unwrap();
+
+        // In maintenance the page comes back and the tunnel never sees the request
+        handle.maintenance.set(Some("<h1>Back soon</h1>".to_string()));
+        let response = forward_to_local_tunnel(&handle, request()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["cache-control"], "no-store");
+        assert_eq!(body_string(response).await, "<h1>Back soon</h1>");
+        assert!(request_rx.try_recv().is_err());
+
+        // Switched off, requests go through again
+        handle.maintenance.set(None);
+        let forwarding = {
+            let handle = handle.clone();
+            tokio::spawn(async move { forward_to_local_tunnel(&handle, request()).await })
+        };
+        let req = loop {
+            if let Some(TunnelCommand::Request(req)) = request_rx.recv().await {
+                break req;
+            }
+        };
+        assert_eq!(req.request.uri, "/checkout");
+        req.response_tx
+            .send(StreamChunk::Headers(dvaar_common::HttpResponsePacket {
+                stream_id: req.request.stream_id,
+                status: 200,
+                headers: vec![],
+            }))
+            .await
+            .unwrap();
+        req.response_tx.send(StreamChunk::Data(b"paid".to_vec())).await.unwrap();
+        req.response_tx.send(StreamChunk::End).await.unwrap();
+        let response = forwarding.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "paid");
+    }
+
+    #[tokio::test]
+    async fn test_access_rules_refuse_before_tunnel() {
+        use crate::routes::TunnelHandle;
+
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+        let mut handle = TunnelHandle::new(request_tx, "user-1".to_string());
+        handle.ready.store(true, Ordering::Release);
+        handle.access = AccessRules::new(&["GET".to_string()], &["/admin/**".to_string()]).unwrap();
+
+        let request = |method: &str, uri: &str| Request::builder().method(method).uri(uri).body(Body::empty()).unwrap();
+
+        let response = forward_to_local_tunnel(&handle, request("POST", "/admin/users")).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()["allow"], "GET");
+
+        let response = forward_to_local_tunnel(&handle, request("GET", "/admin/users?page=2")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(request_rx.try_recv().is_err());
+
+        // Allowed requests still reach the tunnel
+        tokio::spawn(async move { forward_to_local_tunnel(&handle, request("GET", "/docs")).await });
+        match request_rx.recv().await {
+            Some(TunnelCommand::Request(req)) => assert_eq!(req.request.uri, "/docs"),
+            other => panic!("expected Request, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue_upload_completes() {
+        use crate::routes::TunnelHandle;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+        let handle = std::sync::Arc::new(TunnelHandle::new(request_tx, "user-1".to_string()));
+        handle.ready.store(true, Ordering::Release);
+        let app = axum::Router::new().fallback(move |request: Request<Body>| async move {
+            forward_to_local_tunnel(&handle, request).await
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        // The tunnel client: no body until it says Continue, then store it
+        let tunnel = tokio::spawn(async move {
+            let Some(TunnelCommand::Request(req)) = request_rx.recv().await else {
+                panic!("expected Request");
+            };
+            assert!(req.request.expects_continue());
+            let early = tokio::time::timeout(Duration::from_millis(200), request_rx.recv()).await;
+            assert!(early.is_err(), "body arrived before Continue");
+
+            req.response_tx.send(StreamChunk::Continue).await.unwrap();
+            let mut body = Vec::new();
+            loop {
+                match request_rx.recv().await {
+                    Some(TunnelCommand::Data { data, .. }) => body.extend(data),
+                    Some(TunnelCommand::End { .. }) => break,
+                    other => panic!("expected body, got {:?}", other),
+                }
+            }
+            req.response_tx
+                .send(StreamChunk::Headers(dvaar_common::HttpResponsePacket {
+                    stream_id: req.request.stream_id.clone(),
+                    status: 201,
+                    headers: vec![("content-length".to_string(), "0".to_string())],
+                }))
+                .await
+                .unwrap();
+            req.response_tx.send(StreamChunk::End).await.unwrap();
+            body
+        });
+
+        // What curl does with a large upload
+        let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(b"PUT /upload HTTP/1.1\r\nhost: myapp.dvaar.app\r\ncontent-length: 7\r\nexpect: 100-continue\r\n\r\n")
+            .await
+            .unwrap();
+        let mut interim = [0u8; 25];
+        socket.read_exact(&mut interim).await.unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        socket.write_all(b"payload").await.unwrap();
+        let mut response = [0u8; 12];
+        socket.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 201");
+        assert_eq!(tunnel.await.unwrap(), b"payload");
+    }
+
+    #[tokio::test]
+    async fn test_bodiless_responses_skip_body() {
+        use crate::routes::TunnelHandle;
+
+        for (method, status) in [("HEAD", 200), ("GET", 304)] {
+            let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+            let handle = TunnelHandle::new(request_tx, "user-1".to_string());
+            handle.ready.store(true, Ordering::Release);
+
+            // Fake tunnel that (wrongly) sends a body and never ends the stream
+            tokio::spawn(async move {
+                while let Some(command) = request_rx.recv().await {
+                    if let TunnelCommand::Request(req) = command {
+                        let tx = req.response_tx;
+                        let _ = tx
+                            .send(StreamChunk::Headers(dvaar_common::HttpResponsePacket {
+                                stream_id: req.request.stream_id,
+                                status,
+                                headers: vec![("ETag".to_string(), "\"v1\"".to_string())],
+                            }))
+                            .await;
+                        let _ = tx.send(StreamChunk::Data(b"stray".to_vec())).await;
+                        std::future::pending::<()>().await;
+                    }
+                }
+            });
+
+            let request = Request::builder().method(method).uri("/").body(Body::empty()).unwrap();
+            let response = forward_to_local_tunnel(&handle, request).await;
+
+            assert_eq!(response.status().as_u16(), status);
+            assert_eq!(response.headers()["etag"], "\"v1\"");
+            assert_eq!(body_string(response).await, "");
+        }
+    }
+
+    /// The node a visitor reaches proxies to the node holding the tunnel.
+    /// Each direction moves 8 MiB, and the far end must see the first chunk
+    /// while the near end still holds back the rest, so no hop can be
+    /// collecting a whole body before passing it on.
+    #[tokio::test]
+    async fn test_large_bodies_stream_across_two_nodes() {
+        use crate::routes::TunnelHandle;
+        use std::sync::Arc;
+
+        const CHUNK_BYTES: usize = 64 * 1024;
+        const CHUNKS: usize = 128;
+        const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+        fn chunk(i: usize) -> Vec<u8> {
+            (0..CHUNK_BYTES).map(|j| ((i * 7 + j) % 251) as u8).collect()
+        }
+        fn checksum(sum: u64, bytes: &[u8]) -> u64 {
+            bytes.iter().fold(sum, |sum, &b| sum.wrapping_mul(31).wrapping_add(b as u64))
+        }
+        let expected = (0..CHUNKS).fold(0, |sum, i| checksum(sum, &chunk(i)));
+
+        // The tunnel's node: its internal port as `main` builds it
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+        let handle = TunnelHandle::new(request_tx, "user-1".to_string());
+        handle.ready.store(true, Ordering::Release);
+        let state = AppState::for_tests(crate::config::Config {
+            tunnel_domain: "dvaar.app".to_string(),
+            ..Default::default()
+        })
+        .await;
+        state.tunnels.insert("app".to_string(), handle);
+        let tunnel_node = axum::Router::new()
+            .merge(crate::routes::proxy::router())
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::<str>::from("cluster-secret"),
+                crate::routes::proxy::require_cluster_secret,
+            ))
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let route_info = RouteInfo::new("127.0.0.1".to_string(), listener.local_addr().unwrap().port(), "user-1".to_string());
+        tokio::spawn(async move { axum::serve(listener, tunnel_node).await });
+
+        // The visitor's node
+        let ingress_node = axum::Router::new().fallback(move |request: Request<Body>| {
+            let route_info = route_info.clone();
+            async move {
+                proxy_to_node(&reqwest::Client::new(), "cluster-secret", &route_info, "app.dvaar.app", request)
+                    .await
+                    .unwrap()
+            }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ingress_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, ingress_node).await });
+
+        // The tunnel client: read the upload, then send the download
+        let (upload_started_tx, upload_started_rx) = oneshot::channel();
+        let (download_started_tx, download_started_rx) = oneshot::channel::<()>();
+        let tunnel = tokio::spawn(async move {
+            let Some(TunnelCommand::Request(req)) = request_rx.recv().await else {
+                panic!("expected Request");
+            };
+            let mut upload_started_tx = Some(upload_started_tx);
+            let (mut received, mut sum) = (0, 0);
+            loop {
+                match request_rx.recv().await {
+                    Some(TunnelCommand::Data { data, .. }) => {
+                        if let Some(tx) = upload_started_tx.take() {
+                            let _ = tx.send(());
+                        }
+                        received += data.len();
+                        sum = checksum(sum, &data);
+                    }
+                    Some(TunnelCommand::End { .. }) => break,
+                    other => panic!("expected body, got {:?}", other),
+                }
+            }
+
+            let tx = req.response_tx;
+            tx.send(StreamChunk::Headers(dvaar_common::HttpResponsePacket {
+                stream_id: req.request.stream_id,
+                status: 200,
+                headers: vec![],
+            }))
+            .await
+            .unwrap();
+            tx.send(StreamChunk::Data(chunk(0))).await.unwrap();
+            tokio::time::timeout(STEP_TIMEOUT, download_started_rx)
+                .await
+                .expect("first download chunk held back")
+                .unwrap();
+            for i in 1..CHUNKS {
+                tx.send(StreamChunk::Data(chunk(i))).await.unwrap();
+            }
+            tx.send(StreamChunk::End).await.unwrap();
+            (received, sum)
+        });
+
+        // The visitor: one chunk, then the rest once the tunnel has it
+        let (body_tx, body_rx) = mpsc::channel::<Result<Vec<u8>, std::io::Error>>(1);
+        tokio::spawn(async move {
+            body_tx.send(Ok(chunk(0))).await.unwrap();
+            tokio::time::timeout(STEP_TIMEOUT, upload_started_rx)
+                .await
+                .expect("first upload chunk held back")
+                .unwrap();
+            for i in 1..CHUNKS {
+                body_tx.send(Ok(chunk(i))).await.unwrap();
+            }
+        });
+        let upload = futures_util::stream::unfold(body_rx, |mut rx| async move { rx.recv().await.map(|item| (item, rx)) });
+        let mut response = reqwest::Client::new()
+            .put(format!("http://{}/upload", ingress_addr))
+            .body(reqwest::Body::wrap_stream(upload))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let (mut received, mut sum) = (0, 0);
+        let mut download_started_tx = Some(download_started_tx);
+        while let Some(data) = response.chunk().await.unwrap() {
+            if let Some(tx) = download_started_tx.take() {
+                let _ = tx.send(());
+            }
+            received += data.len();
+            sum = checksum(sum, &data);
+        }
+        assert_eq!((received, sum), (CHUNK_BYTES * CHUNKS, expected), "download");
+        assert_eq!(tunnel.await.unwrap(), (CHUNK_BYTES * CHUNKS, expected), "upload");
+    }
+
+    async fn body_string(response: Response<Body>) -> String {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    /// A ready tunnel for `user` sharing `cache`, whose upstream answers
+    /// every request with a cacheable `body`; also returns how many requests
+    /// reached it
+    fn cached_tunnel(
+        cache: &std::sync::Arc<ResponseCache>,
+        user: &str,
+        body: &'static str,
+    ) -> (TunnelHandle, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(8);
+        let mut handle = TunnelHandle::new(request_tx, user.to_string());
+        handle.ready.store(true, Ordering::Release);
+        handle.route = Some(RouteInfo::new("10.0.0.1".to_string(), 8080, user.to_string()));
+        handle.response_cache = Some(cache.clone());
+
+        let forwarded = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = forwarded.clone();
+        tokio::spawn(async move {
+            while let Some(command) = request_rx.recv().await {
+                if let TunnelCommand::Request(req) = command {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let _ = req
+                        .response_tx
+                        .send(StreamChunk::Headers(dvaar_common::HttpResponsePacket {
+                            stream_id: req.request.stream_id,
+                            status: 200,
+                            headers: vec![("cache-control".to_string(), "public, max-age=60".to_string())],
+                        }))
+                        .await;
+                    let _ = req.response_tx.send(StreamChunk::Data(body.as_bytes().to_vec())).await;
+                    let _ = req.response_tx.send(StreamChunk::End).await;
+                }
+            }
+        });
+        (handle, forwarded)
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::builder().uri(uri).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit_is_still_subject_to_access_rules() {
+        let cache = std::sync::Arc::new(ResponseCache::new(8, 1024));
+        let (mut handle, forwarded) = cached_tunnel(&cache, "user-1", "secret");
+
+        for _ in 0..2 {
+            let response = forward_to_local_tunnel(&handle, get("/admin/report")).await;
+            assert_eq!(body_string(response).await, "secret");
+        }
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1, "second request is a hit");
+
+        handle.access = AccessRules::new(&[], &["/admin/**".to_string()]).unwrap();
+        let response = forward_to_local_tunnel(&handle, get("/admin/report")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_is_checked_before_the_cache() {
+        let cache = std::sync::Arc::new(ResponseCache::new(8, 1024));
+        let (handle, forwarded) = cached_tunnel(&cache, "user-1", "live");
+        let response = forward_to_local_tunnel(&handle, get("/")).await;
+        assert_eq!(body_string(response).await, "live");
+
+        handle.maintenance.set(Some("<h1>Back soon</h1>".to_string()));
+        let response = forward_to_local_tunnel(&handle, get("/")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body_string(response).await, "<h1>Back soon</h1>");
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit_does_not_survive_a_new_owner() {
+        let cache = std::sync::Arc::new(ResponseCache::new(8, 1024));
+        let (previous, _) = cached_tunnel(&cache, "user-1", "previous tenant");
+        let response = forward_to_local_tunnel(&previous, get("/index.html")).await;
+        assert_eq!(body_string(response).await, "previous tenant");
+
+        // The subdomain is handed to someone else's tunnel
+        let (next, forwarded) = cached_tunnel(&cache, "user-2", "new tenant");
+        let response = forward_to_local_tunnel(&next, get("/index.html")).await;
+        assert!(response.headers().get("x-cache").is_none());
+        assert_eq!(body_string(response).await, "new tenant");
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_custom_offline_page_served() {
+        let custom = "<h1>Back in five minutes</h1>".to_string();
+        let response = render_offline_page("demo", StatusCode::BAD_GATEWAY, Some(custom.clone()));
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(response.headers()["content-type"], "text/html; charset=utf-8");
+        assert_eq!(body_string(response).await, custom);
+    }
+
+    #[tokio::test]
+    async fn test_default_offline_page_served() {
+        let response = render_offline_page("demo", StatusCode::NOT_FOUND, None);
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = body_string(response).await;
+        assert!(body.contains("demo is offline"));
+        assert!(!body.contains("{subdomain}"));
+    }
+}
@@ -7,25 +7,57 @@ pub mod ingress;
 pub mod proxy;
 pub mod tunnel;
 
-use crate::{abuse::RateLimiter, config::Config, redis::RouteManager};
+use crate::{
+    abuse::{Blocklist, RateLimiter},
+    access_log::AccessLog,
+    access_rules::AccessRules,
+    backpressure::ChannelStats,
+    config::Config,
+    redis::RouteManager,
+    response_cache::ResponseCache,
+    services::{Authenticator, PostgresAuthenticator},
+    subdomain_names::SubdomainNames,
+};
+use billing::PlanFeatures;
 use dashmap::DashMap;
+use dvaar_common::{RouteInfo, UpstreamMetrics};
 use fred::clients::Client as RedisClient;
 use sqlx::PgPool;
-use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::sync::{mpsc, Semaphore};
+
+/// How long to wait for another node to accept a proxied request
+const NODE_CONNECT_TIMEOUT_SECONDS: u64 = 5;
 
 /// Shared application state
 #[derive(Clone)]
 pub struct AppState {
     pub config: Arc<Config>,
     pub db: PgPool,
-    pub redis: RedisClient,
     pub route_manager: Arc<RouteManager>,
     pub rate_limiter: RateLimiter,
+    /// Resolves tunnel client tokens to users (Postgres API keys by default)
+    pub authenticator: Arc<dyn Authenticator>,
+    /// Subdomain blocklist, including patterns from config
+    pub blocklist: Arc<Blocklist>,
+    /// Generator for random subdomains
+    pub subdomain_names: Arc<SubdomainNames>,
+    /// Per-request access log, if configured
+    pub access_log: Option<AccessLog>,
+    /// Cache for upstream GET responses, if enabled
+    pub response_cache: Option<Arc<ResponseCache>>,
     /// Local tunnel connections: subdomain -> tunnel sender
     pub tunnels: Arc<DashMap<String, TunnelHandle>>,
-    /// Shared HTTP client for inter-node communication (connection pooling)
+    /// Backpressure counters shared by all tunnels on this node
+    pub channel_stats: Arc<ChannelStats>,
+    /// Shared HTTP client for calls to outside APIs (GitHub, Stripe)
     pub http_client: reqwest::Client,
+    /// Client for proxying to other nodes. It has no overall timeout, so
+    /// long uploads and streamed responses aren't cut off partway.
+    pub node_client: reqwest::Client,
 }
 
 /// Handle to a tunnel connection
@@ -35,6 +67,90 @@ pub struct TunnelHandle {
     pub request_tx: mpsc::Sender<TunnelCommand>,
     /// User ID that owns this tunnel
     pub user_id: String,
+    /// Set once the tunnel's tasks are running and the client has been sent Ready
+    pub ready: Arc<AtomicBool>,
+    /// Buffer size for each stream's response channel
+    pub stream_capacity: usize,
+    /// The client holds response bodies back until `WindowUpdate` says the
+    /// visitor has read them
+    pub flow_control: bool,
+    /// One permit per request in flight; held until its response finishes
+    pub stream_slots: Arc<Semaphore>,
+    /// What the client last reported about its upstream
+    pub upstream: UpstreamHealth,
+    /// Methods and paths refused before reaching the tunnel
+    pub access: AccessRules,
+    /// Page answered instead of forwarding while the owner has it in maintenance
+    pub maintenance: Maintenance,
+    /// Also serves `*.<subdomain>`
+    pub wildcard: bool,
+    /// The route registered for it, kept to restore it if Redis loses it
+    pub route: Option<RouteInfo>,
+    /// The owner's plan when the tunnel connected, for request rate limits
+    pub plan: PlanFeatures,
+    /// The node's cache for GET responses, if enabled
+    pub response_cache: Option<Arc<ResponseCache>>,
+}
+
+impl TunnelHandle {
+    /// Handle for a tunnel that is still starting up
+    pub fn new(request_tx: mpsc::Sender<TunnelCommand>, user_id: String) -> Self {
+        Self {
+            request_tx,
+            user_id,
+            ready: Arc::new(AtomicBool::new(false)),
+            stream_capacity: dvaar_common::constants::STREAM_CHANNEL_CAPACITY,
+            flow_control: false,
+            stream_slots: Arc::new(Semaphore::new(dvaar_common::constants::MAX_STREAMS_PER_TUNNEL)),
+            upstream: UpstreamHealth::default(),
+            access: AccessRules::default(),
+            maintenance: Maintenance::default(),
+            wildcard: false,
+            route: None,
+            plan: PlanFeatures::for_plan("free"),
+            response_cache: None,
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+}
+
+/// Latest `Metrics` packet from a tunnel's client, shared with its receive task
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamHealth(Arc<std::sync::Mutex<Option<UpstreamMetrics>>>);
+
+impl UpstreamHealth {
+    /// Replace the previous report
+    pub fn record(&self, metrics: UpstreamMetrics) {
+        *self.0.lock().unwrap() = Some(metrics);
+    }
+
+    pub fn latest(&self) -> Option<UpstreamMetrics> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Whether the last report said most requests failed
+    pub fn is_failing(&self) -> bool {
+        self.latest().is_some_and(|metrics| metrics.is_failing())
+    }
+}
+
+/// Maintenance page for a tunnel, shared so it can be switched while the
+/// tunnel runs
+#[derive(Debug, Clone, Default)]
+pub struct Maintenance(Arc<std::sync::RwLock<Option<String>>>);
+
+impl Maintenance {
+    /// Start answering with `page`, or go back to forwarding with `None`
+    pub fn set(&self, page: Option<String>) {
+        *self.0.write().unwrap() = page;
+    }
+
+    pub fn page(&self) -> Option<String> {
+        self.0.read().unwrap().clone()
+    }
 }
 
 /// A request to be sent through the tunnel (headers only)
@@ -59,15 +175,25 @@ pub enum TunnelCommand {
     WebSocketFrame { stream_id: String, data: Vec<u8>, is_binary: bool },
     /// WebSocket closed by client
     WebSocketClose { stream_id: String, code: Option<u16>, reason: Option<String> },
+    /// Downstream went away before the response finished
+    Cancel { stream_id: String },
+    /// The visitor has read this much more of a response body
+    WindowUpdate { stream_id: String, bytes: u32 },
+    /// Tell the client why and drop the tunnel
+    Close { code: dvaar_common::CloseCode, reason: String },
 }
 
 /// A chunk of streaming response data
 #[derive(Debug, Clone)]
 pub enum StreamChunk {
-    /// Response headers (sent first)
+    /// The client is ready for an `Expect: 100-continue` request's body
+    Continue,
+    /// Response headers (sent first, after any `Continue`)
     Headers(dvaar_common::HttpResponsePacket),
     /// Body data chunk
     Data(Vec<u8>),
+    /// Trailing headers, after the last data chunk
+    Trailers(Vec<(String, String)>),
     /// End of stream
     End,
     /// WebSocket frame (after upgrade)
@@ -75,13 +201,24 @@ pub enum StreamChunk {
     /// WebSocket closed
     WebSocketClose { code: Option<u16>, reason: Option<String> },
     /// Error occurred
-    Error(String),
+    Error {
+        code: dvaar_common::StreamErrorCode,
+        message: String,
+    },
 }
 
 impl AppState {
-    pub async fn new(config: Config, db: PgPool, redis: RedisClient) -> Self {
+    pub async fn new(
+        config: Config,
+        db: PgPool,
+        redis: RedisClient,
+        blocklist: Blocklist,
+        subdomain_names: SubdomainNames,
+        access_log: Option<AccessLog>,
+    ) -> Self {
         let route_manager = Arc::new(RouteManager::new(redis.clone()));
-        let rate_limiter = RateLimiter::new(Arc::new(redis.clone()));
+        let rate_limiter = RateLimiter::new(Arc::new(redis.clone()), route_manager.breaker());
+        let authenticator = Arc::new(PostgresAuthenticator::new(db.clone()));
 
         // Create shared HTTP client with connection pooling
         let http_client = reqwest::Client::builder()
@@ -90,15 +227,91 @@ impl AppState {
             .timeout(std::time::Duration::from_secs(60))
             .build()
             .expect("Failed to create HTTP client");
+        let node_client = reqwest::Client::builder()
+            .pool_max_idle_per_host(100)
+            .pool_idle_timeout(std::time::Duration::from_secs(90))
+            .connect_timeout(std::time::Duration::from_secs(NODE_CONNECT_TIMEOUT_SECONDS))
+            .build()
+            .expect("Failed to create node HTTP client");
+
+        let response_cache = (config.response_cache_entries > 0).then(|| {
+            Arc::new(ResponseCache::new(
+                config.response_cache_entries,
+                config.response_cache_max_body_bytes,
+            ))
+        });
 
         Self {
             config: Arc::new(config),
             db,
-            redis,
             route_manager,
             rate_limiter,
+            authenticator,
+            blocklist: Arc::new(blocklist),
+            subdomain_names: Arc::new(subdomain_names),
+            access_log,
+            response_cache,
             tunnels: Arc::new(DashMap::new()),
+            channel_stats: Arc::new(ChannelStats::default()),
             http_client,
+            node_client,
         }
     }
+
+    /// State for tests that never reach Postgres or Redis: the pool only
+    /// connects when used and rate limits are counted on this node
+    #[cfg(test)]
+    pub(crate) async fn for_tests(config: Config) -> Self {
+        let db = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/dvaar_test")
+            .expect("valid database URL");
+        Self {
+            config: Arc::new(config),
+            authenticator: Arc::new(PostgresAuthenticator::new(db.clone())),
+            db,
+            route_manager: Arc::new(RouteManager::new(RedisClient::default())),
+            rate_limiter: RateLimiter::without_redis().await,
+            blocklist: Arc::new(Blocklist::new::<&str>(&[]).expect("no patterns")),
+            subdomain_names: Arc::new(SubdomainNames::default()),
+            access_log: None,
+            response_cache: None,
+            tunnels: Arc::new(DashMap::new()),
+            channel_stats: Arc::new(ChannelStats::default()),
+            http_client: reqwest::Client::new(),
+            node_client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upstream_health_keeps_latest_report() {
+        let (request_tx, _request_rx) = mpsc::channel(1);
+        let handle = TunnelHandle::new(request_tx, "user".to_string());
+        assert_eq!(handle.upstream.latest(), None);
+        assert!(!handle.upstream.is_failing());
+
+        // The receive task records through its own clone
+        let upstream = handle.upstream.clone();
+        upstream.record(UpstreamMetrics {
+            requests: 10,
+            upstream_errors: 8,
+            avg_latency_ms: 40,
+            active_streams: 2,
+        });
+        assert!(handle.upstream.is_failing());
+
+        let recovered = UpstreamMetrics {
+            requests: 12,
+            upstream_errors: 0,
+            avg_latency_ms: 35,
+            active_streams: 0,
+        };
+        upstream.record(recovered.clone());
+        assert_eq!(handle.upstream.latest(), Some(recovered));
+        assert!(!handle.upstream.is_failing());
+    }
 }
@@ -1,43 +1,58 @@
 //! Internal node-to-node proxy handler
 
-use crate::routes::{AppState, StreamChunk, TunnelCommand, TunnelRequest};
+use crate::routes::ingress::forward_within_rate_limit;
+use crate::routes::AppState;
 use axum::{
     body::Body,
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    extract::State,
+    extract::{Path, State},
     http::{Request, Response, StatusCode},
+    middleware::Next,
     response::IntoResponse,
-    routing::any,
-    Router,
+    routing::{any, post},
+    Json, Router,
 };
-use dvaar_common::{constants, HttpRequestPacket, new_stream_id};
-use futures_util::{SinkExt, StreamExt};
-use tokio::sync::mpsc;
+use dvaar_common::constants;
+use std::sync::Arc;
 
 /// Build the internal proxy router
 pub fn router() -> Router<AppState> {
     Router::new().route("/_internal/proxy", any(handle_internal_proxy))
     .route("/_internal/proxy/{*path}", any(handle_internal_proxy))
+    .route("/_internal/maintenance/{subdomain}", post(handle_internal_maintenance))
 }
 
-/// Handle internal proxy request from another node
-async fn handle_internal_proxy(
-    State(state): State<AppState>,
+/// Middleware for the internal port: only other nodes of this cluster, which
+/// send its `X-Cluster-Secret`, get through. Everything else is a 401, so a
+/// node reachable from outside (or from another cluster) can't be used to
+/// reach tunnels.
+pub async fn require_cluster_secret(
+    State(cluster_secret): State<Arc<str>>,
     request: Request<Body>,
+    next: Next,
 ) -> Response<Body> {
-    // Validate cluster secret
-    let cluster_secret = request
+    let given = request
         .headers()
         .get(constants::CLUSTER_SECRET_HEADER)
-        .and_then(|v| v.to_str().ok());
+        .map(|v| v.as_bytes());
 
-    match cluster_secret {
-        Some(secret) if secret == state.config.cluster_secret => {}
-        _ => {
-            return (StatusCode::FORBIDDEN, "Invalid cluster secret").into_response();
-        }
+    match given {
+        Some(given) if secret_matches(given, cluster_secret.as_bytes()) => next.run(request).await,
+        _ => (StatusCode::UNAUTHORIZED, "Invalid cluster secret").into_response(),
     }
+}
+
+/// An unset secret never matches, so an empty header can't pass on a node
+/// missing its configuration
+fn secret_matches(given: &[u8], expected: &[u8]) -> bool {
+    !expected.is_empty() && dvaar_common::constant_time_eq(given, expected)
+}
 
+/// Handle internal proxy request from another node. The cluster secret has
+/// been checked by `require_cluster_secret`.
+async fn handle_internal_proxy(
+    State(state): State<AppState>,
+    mut request: Request<Body>,
+) -> Response<Body> {
     // Get original host to determine subdomain
     // Note: Ingress sends X-Original-Host as subdomain.tunnel_domain (e.g., foo.dvaar.app)
     let original_host = request
@@ -67,164 +82,32 @@ async fn handle_internal_proxy(
         }
     };
 
-    let stream_id = new_stream_id();
-    let (mut parts, body) = request.into_parts();
-
-    let ws_upgrade = if is_websocket_upgrade_request(&parts.headers) {
-        match <WebSocketUpgrade as axum::extract::FromRequestParts<()>>::from_request_parts(
-            &mut parts,
-            &(),
-        )
-        .await
-        {
-            Ok(upgrade) => Some(upgrade),
-            Err(err) => {
-                tracing::warn!("WebSocket upgrade rejected: {}", err);
-                return (StatusCode::BAD_REQUEST, "Invalid WebSocket request").into_response();
-            }
-        }
-    } else {
-        None
-    };
-
-    let headers: Vec<(String, String)> = parts
-        .headers
-        .iter()
-        .filter(|(k, _)| {
-            !k.as_str().eq_ignore_ascii_case(constants::CLUSTER_SECRET_HEADER)
-                && !k
-                    .as_str()
-                    .eq_ignore_ascii_case(constants::ORIGINAL_HOST_HEADER)
-        })
-        .filter_map(|(k, v)| v.to_str().ok().map(|s| (k.to_string(), s.to_string())))
-        .collect();
-
-    let http_request = HttpRequestPacket {
-        stream_id: stream_id.clone(),
-        method: parts.method.to_string(),
-        uri: parts
-            .uri
-            .path_and_query()
-            .map(|pq| pq.to_string())
-            .unwrap_or_else(|| "/".to_string()),
-        headers,
-    };
-
-    let (response_tx, mut response_rx) = mpsc::channel::<StreamChunk>(32);
-    let tunnel_request = TunnelRequest {
-        request: http_request,
-        response_tx,
-    };
-
-    if handle
-        .request_tx
-        .send(TunnelCommand::Request(tunnel_request))
-        .await
-        .is_err()
-    {
-        return (StatusCode::BAD_GATEWAY, "Tunnel disconnected").into_response();
-    }
-
-    let request_tx = handle.request_tx.clone();
-    let stream_id_for_body = stream_id.clone();
-    tokio::spawn(async move {
-        let mut body_stream = body.into_data_stream();
-        while let Some(chunk_result) = body_stream.next().await {
-            match chunk_result {
-                Ok(chunk) => {
-                    if request_tx
-                        .send(TunnelCommand::Data {
-                            stream_id: stream_id_for_body.clone(),
-                            data: chunk.to_vec(),
-                        })
-                        .await
-                        .is_err()
-                    {
-                        return;
-                    }
-                }
-                Err(e) => {
-                    tracing::error!("Failed to read request body: {}", e);
-                    break;
-                }
-            }
-        }
-        let _ = request_tx
-            .send(TunnelCommand::End {
-                stream_id: stream_id_for_body,
-            })
-            .await;
-    });
-
-    let first_chunk = match response_rx.recv().await {
-        Some(chunk) => chunk,
-        None => {
-            return (StatusCode::BAD_GATEWAY, "No response from tunnel").into_response();
-        }
-    };
+    // Internal headers are for this hop only, don't leak them to the client
+    request.headers_mut().remove(constants::CLUSTER_SECRET_HEADER);
+    request.headers_mut().remove(constants::ORIGINAL_HOST_HEADER);
 
-    let headers_packet = match first_chunk {
-        StreamChunk::Headers(h) => h,
-        StreamChunk::Error(e) => {
-            tracing::error!("Tunnel error: {}", e);
-            return (StatusCode::BAD_GATEWAY, "Tunnel error").into_response();
-        }
-        _ => {
-            tracing::error!("Expected Headers chunk, got something else");
-            return (StatusCode::BAD_GATEWAY, "Protocol error").into_response();
-        }
-    };
+    forward_within_rate_limit(&state.rate_limiter, &handle, request).await
+}
 
-    if headers_packet.is_websocket_upgrade() {
-        let Some(ws_upgrade) = ws_upgrade else {
-            return (StatusCode::BAD_GATEWAY, "WebSocket upgrade failed").into_response();
-        };
+/// Maintenance switched through the API on another node
+#[derive(Debug, serde::Deserialize)]
+struct MaintenanceUpdate {
+    page: Option<String>,
+}
 
-        let mut ws_upgrade = ws_upgrade;
-        if let Some(protocol) = headers_packet
-            .headers
-            .iter()
-            .find(|(k, _)| k.eq_ignore_ascii_case("sec-websocket-protocol"))
-            .map(|(_, v)| v.to_string())
-        {
-            ws_upgrade = ws_upgrade.protocols([protocol]);
+/// Apply a maintenance change to a tunnel connected here
+async fn handle_internal_maintenance(
+    State(state): State<AppState>,
+    Path(subdomain): Path<String>,
+    Json(update): Json<MaintenanceUpdate>,
+) -> Response<Body> {
+    match state.tunnels.get(&subdomain) {
+        Some(handle) => {
+            handle.maintenance.set(update.page);
+            StatusCode::NO_CONTENT.into_response()
         }
-
-        let request_tx = handle.request_tx.clone();
-        let stream_id_clone = stream_id.clone();
-        return ws_upgrade.on_upgrade(move |socket| async move {
-            bridge_websocket(socket, response_rx, request_tx, stream_id_clone).await;
-        });
-    }
-
-    let status = StatusCode::from_u16(headers_packet.status).unwrap_or(StatusCode::OK);
-    let mut builder = Response::builder().status(status);
-
-    for (key, value) in &headers_packet.headers {
-        builder = builder.header(key.as_str(), value.as_str());
+        None => (StatusCode::NOT_FOUND, "Tunnel not found on this node").into_response(),
     }
-
-    let body_stream = async_stream::stream! {
-        while let Some(chunk) = response_rx.recv().await {
-            match chunk {
-                StreamChunk::Data(data) => {
-                    yield Ok::<_, std::io::Error>(axum::body::Bytes::from(data));
-                }
-                StreamChunk::End => {
-                    break;
-                }
-                StreamChunk::Error(e) => {
-                    tracing::error!("Stream error: {}", e);
-                    break;
-                }
-                _ => {}
-            }
-        }
-    };
-
-    builder
-        .body(Body::from_stream(body_stream))
-        .unwrap_or_else(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Response build failed").into_response())
 }
 
 /// Extract subdomain from host
@@ -244,156 +127,39 @@ fn extract_subdomain_from_host(host: &str, base_domain: &str) -> Option<String>
     }
 }
 
-fn is_websocket_upgrade_request(headers: &axum::http::HeaderMap) -> bool {
-    let has_upgrade_connection = headers
-        .get(axum::http::header::CONNECTION)
-        .and_then(|value| value.to_str().ok())
-        .map(|value| value.to_ascii_lowercase().contains("upgrade"))
-        .unwrap_or(false);
-
-    let has_websocket_upgrade = headers
-        .get(axum::http::header::UPGRADE)
-        .and_then(|value| value.to_str().ok())
-        .map(|value| value.eq_ignore_ascii_case("websocket"))
-        .unwrap_or(false);
-
-    has_upgrade_connection && has_websocket_upgrade
-}
-
-async fn bridge_websocket(
-    socket: WebSocket,
-    mut response_rx: mpsc::Receiver<StreamChunk>,
-    request_tx: mpsc::Sender<TunnelCommand>,
-    stream_id: String,
-) {
-    let (mut ws_sender, mut ws_receiver) = socket.split();
-
-    // Clone before spawning to avoid move issues
-    let request_tx_for_tunnel = request_tx.clone();
-    let request_tx_for_close = request_tx;
-    let stream_id_for_tunnel = stream_id.clone();
-    let stream_id_for_close = stream_id;
-
-    let to_client = tokio::spawn(async move {
-        while let Some(chunk) = response_rx.recv().await {
-            match chunk {
-                StreamChunk::WebSocketFrame { data, is_binary } => {
-                    let message = if is_binary {
-                        Message::Binary(data.into())
-                    } else {
-                        match String::from_utf8(data) {
-                            Ok(text) => Message::Text(text.into()),
-                            Err(err) => {
-                                tracing::warn!("Invalid UTF-8 websocket frame: {}", err);
-                                continue;
-                            }
-                        }
-                    };
-
-                    if ws_sender.send(message).await.is_err() {
-                        break;
-                    }
-                }
-                StreamChunk::WebSocketClose { code, reason } => {
-                    let close_frame = code.map(|code| axum::extract::ws::CloseFrame {
-                        code,
-                        reason: reason.unwrap_or_default().into(),
-                    });
-                    let _ = ws_sender.send(Message::Close(close_frame)).await;
-                    break;
-                }
-                StreamChunk::Error(e) => {
-                    tracing::error!("WebSocket stream error: {}", e);
-                    let _ = ws_sender.send(Message::Close(None)).await;
-                    break;
-                }
-                StreamChunk::End => {}
-                _ => {}
-            }
-        }
-    });
-
-    let to_tunnel = tokio::spawn(async move {
-        let mut sent_close = false;
-        while let Some(message) = ws_receiver.next().await {
-            match message {
-                Ok(Message::Text(text)) => {
-                    if request_tx_for_tunnel
-                        .send(TunnelCommand::WebSocketFrame {
-                            stream_id: stream_id_for_tunnel.clone(),
-                            data: text.as_bytes().to_vec(),
-                            is_binary: false,
-                        })
-                        .await
-                        .is_err()
-                    {
-                        break;
-                    }
-                }
-                Ok(Message::Binary(data)) => {
-                    if request_tx_for_tunnel
-                        .send(TunnelCommand::WebSocketFrame {
-                            stream_id: stream_id_for_tunnel.clone(),
-                            data: data.to_vec(),
-                            is_binary: true,
-                        })
-                        .await
-                        .is_err()
-                    {
-                        break;
-                    }
-                }
-                Ok(Message::Close(frame)) => {
-                    let (code, reason) = frame
-                        .map(|frame| (Some(frame.code), Some(frame.reason.to_string())))
-                        .unwrap_or((None, None));
-                    let _ = request_tx_for_tunnel
-                        .send(TunnelCommand::WebSocketClose {
-                            stream_id: stream_id_for_tunnel.clone(),
-                            code,
-                            reason,
-                        })
-                        .await;
-                    sent_close = true;
-                    break;
-                }
-                Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => {}
-                Err(err) => {
-                    tracing::debug!("WebSocket receive error: {}", err);
-                    let _ = request_tx_for_tunnel
-                        .send(TunnelCommand::WebSocketClose {
-                            stream_id: stream_id_for_tunnel.clone(),
-                            code: Some(1006),
-                            reason: Some("WebSocket receive error".to_string()),
-                        })
-                        .await;
-                    sent_close = true;
-                    break;
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, routing::get};
+    use tower::ServiceExt;
+
+    async fn status_with(secret: Option<&str>) -> StatusCode {
+        let app = Router::new()
+            .route("/_internal/proxy/{*path}", get(|| async { "through" }))
+            .layer(middleware::from_fn_with_state(
+                Arc::<str>::from("cluster-secret"),
+                require_cluster_secret,
+            ));
+        let mut request = Request::builder().uri("/_internal/proxy/api");
+        if let Some(secret) = secret {
+            request = request.header(constants::CLUSTER_SECRET_HEADER, secret);
         }
+        let response = app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+        response.status()
+    }
 
-        if !sent_close {
-            let _ = request_tx_for_tunnel
-                .send(TunnelCommand::WebSocketClose {
-                    stream_id: stream_id_for_tunnel.clone(),
-                    code: Some(1006),
-                    reason: Some("Client websocket closed".to_string()),
-                })
-                .await;
-        }
-    });
+    #[tokio::test]
+    async fn test_internal_requests_need_the_cluster_secret() {
+        assert_eq!(status_with(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status_with(Some("wrong-secret")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status_with(Some("cluster-secre")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status_with(Some("")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status_with(Some("cluster-secret")).await, StatusCode::OK);
+    }
 
-    tokio::select! {
-        _ = to_client => {
-            let _ = request_tx_for_close
-                .send(TunnelCommand::WebSocketClose {
-                    stream_id: stream_id_for_close,
-                    code: Some(1006),
-                    reason: Some("Client websocket closed".to_string()),
-                })
-                .await;
-        }
-        _ = to_tunnel => {}
+    #[test]
+    fn test_empty_cluster_secret_matches_nothing() {
+        assert!(!secret_matches(b"", b""));
+        assert!(secret_matches(b"s3cret", b"s3cret"));
     }
 }
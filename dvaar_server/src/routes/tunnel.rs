@@ -1,46 +1,253 @@
 //! WebSocket tunnel handler with streaming support
 
-use crate::abuse::{self, SubdomainCheck};
+use crate::abuse::{Blocklist, SubdomainCheck};
+use crate::access_rules::AccessRules;
+use crate::backpressure::{send_or_stall, ChannelStats, Delivery};
 use crate::db::queries;
-use crate::redis::{spawn_heartbeat, RouteManager};
+use crate::redis::{spawn_route_heartbeat, ByteUsage, NodeInfo, RouteManager};
+use crate::routes::billing::PlanFeatures;
 use crate::routes::{AppState, StreamChunk, TunnelCommand, TunnelHandle};
+use crate::services::{AuthedUser, Authenticator};
+use crate::subdomain_names::SubdomainNames;
+use crate::throttle::ByteRateLimiter;
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        ConnectInfo, Query, State,
     },
-    response::Response,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::get,
-    Router,
+    Extension, Json, Router,
 };
 use chrono::{DateTime, Utc};
-use dvaar_common::{constants, ClientHello, ControlPacket, RouteInfo, ServerHello};
+use dashmap::DashMap;
+use dvaar_common::{
+    constants, ClientHello, CloseCode, ControlPacket, Keepalive, ProtocolError, RouteInfo, ServerHello, StreamErrorCode,
+    WireFormat,
+};
 use futures_util::{SinkExt, StreamExt};
-use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::{mpsc, watch, Mutex};
+use tokio::sync::{mpsc, Mutex, Semaphore};
 
 #[derive(Debug)]
 struct StreamState {
     response_tx: mpsc::Sender<StreamChunk>,
-    is_websocket: bool,
+    phase: StreamPhase,
+}
+
+/// How far a stream's response has got, which decides what the client may
+/// send for it next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamPhase {
+    /// Opened by a request; only `Continue` or the response headers fit
+    AwaitingResponse,
+    /// Headers sent; body data, trailers and the end follow
+    Body,
+    /// Upgraded; only WebSocket frames and the close follow
+    WebSocket,
+}
+
+/// The Redis registrations a tunnel makes while it is being set up
+trait TunnelRegistry: Send + Sync + 'static {
+    fn claim_route(&self, subdomain: &str, route: &RouteInfo) -> impl Future<Output = anyhow::Result<bool>> + Send;
+
+    fn release_route(&self, subdomain: &str, connection_id: &str) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    fn unregister_user_tunnel(
+        &self,
+        user_id: &str,
+        subdomain: &str,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+impl TunnelRegistry for RouteManager {
+    fn claim_route(&self, subdomain: &str, route: &RouteInfo) -> impl Future<Output = anyhow::Result<bool>> + Send {
+        RouteManager::claim_route(self, subdomain, route)
+    }
+
+    fn release_route(&self, subdomain: &str, connection_id: &str) -> impl Future<Output = anyhow::Result<()>> + Send {
+        RouteManager::release_route(self, subdomain, connection_id)
+    }
+
+    fn unregister_user_tunnel(
+        &self,
+        user_id: &str,
+        subdomain: &str,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        RouteManager::unregister_user_tunnel(self, user_id, subdomain)
+    }
+}
+
+/// Undoes a tunnel's registrations when `handle_socket` exits.
+///
+/// Each stage is marked once it has succeeded. `release` cleans up inline on the
+/// normal paths; an early return, panic or abort falls back to `Drop`, which spawns
+/// the same cleanup so the route and the user's tunnel slot never leak.
+/// Once another connection of the user's has taken the subdomain over, its
+/// route and handle are left alone.
+struct RegistrationGuard<R: TunnelRegistry = RouteManager> {
+    registry: Arc<R>,
+    tunnels: Arc<DashMap<String, TunnelHandle>>,
+    subdomain: String,
+    user_id: String,
+    /// `RouteInfo::connection_id` of the route this connection registered
+    connection_id: String,
+    /// Route key written to Redis
+    route: bool,
+    /// Slot taken in the user's concurrent tunnel set
+    user_tunnel: bool,
+    /// Local handle inserted into `AppState::tunnels`
+    handle: bool,
+}
+
+impl<R: TunnelRegistry> RegistrationGuard<R> {
+    fn new(
+        registry: Arc<R>,
+        tunnels: Arc<DashMap<String, TunnelHandle>>,
+        subdomain: String,
+        user_id: String,
+        connection_id: String,
+    ) -> Self {
+        Self {
+            registry,
+            tunnels,
+            subdomain,
+            user_id,
+            connection_id,
+            route: false,
+            user_tunnel: false,
+            handle: false,
+        }
+    }
+
+    /// Undo everything registered so far and disarm the guard
+    async fn release(mut self) {
+        self.take_cleanup().await;
+    }
+
+    /// Remove the local handle now and return the Redis cleanup still owed
+    fn take_cleanup(&mut self) -> impl Future<Output = ()> + Send + 'static {
+        if std::mem::take(&mut self.handle) {
+            self.tunnels.remove_if(&self.subdomain, |_, handle| {
+                handle.route.as_ref().is_some_and(|route| route.connection_id == self.connection_id)
+            });
+        }
+
+        let route = std::mem::take(&mut self.route);
+        let user_tunnel = std::mem::take(&mut self.user_tunnel);
+        let registry = self.registry.clone();
+        let subdomain = self.subdomain.clone();
+        let user_id = self.user_id.clone();
+        let connection_id = self.connection_id.clone();
+
+        async move {
+            if route {
+                if let Err(e) = registry.release_route(&subdomain, &connection_id).await {
+                    tracing::error!("Failed to remove route {}: {}", subdomain, e);
+                }
+            }
+            if user_tunnel {
+                if let Err(e) = registry.unregister_user_tunnel(&user_id, &subdomain).await {
+                    tracing::error!("Failed to unregister tunnel {} for user {}: {}", subdomain, user_id, e);
+                }
+            }
+        }
+    }
+}
+
+impl<R: TunnelRegistry> Drop for RegistrationGuard<R> {
+    fn drop(&mut self) {
+        if !(self.route || self.user_tunnel || self.handle) {
+            return;
+        }
+        let cleanup = self.take_cleanup();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(cleanup);
+        }
+    }
 }
 
 /// Build the tunnel router
 pub fn router() -> Router<AppState> {
-    Router::new().route("/_dvaar/tunnel", get(ws_handler))
+    Router::new()
+        .route("/_dvaar/tunnel", get(ws_handler))
+        .route("/api/subdomains/check", get(check_subdomain))
+}
+
+#[derive(Debug, Deserialize)]
+struct SubdomainCheckQuery {
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SubdomainCheckResponse {
+    name: String,
+    available: bool,
+    /// Why the name can't be used, worded as the handshake would reject it
+    reason: Option<String>,
+}
+
+/// Tell the CLI whether it may ask for a subdomain, before it opens a tunnel
+async fn check_subdomain(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<SubdomainCheckQuery>,
+) -> Response {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "));
+    let Some(token) = token else {
+        return (StatusCode::UNAUTHORIZED, "Missing authorization header").into_response();
+    };
+    let user = match authenticate_client(state.authenticator.as_ref(), token).await {
+        Ok(user) => user,
+        Err(message) => return (StatusCode::UNAUTHORIZED, message).into_response(),
+    };
+
+    let can_request_subdomain = PlanFeatures::for_user(&user).custom_subdomains;
+    let verdict =
+        validate_requested_subdomain(&state, &query.name, &user.id.to_string(), can_request_subdomain).await;
+    Json(SubdomainCheckResponse {
+        name: query.name,
+        available: verdict.is_ok(),
+        reason: verdict.err(),
+    })
+    .into_response()
 }
 
 /// WebSocket upgrade handler
-async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> Response {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    // Absent when served without connect info, as in tests
+    connect_info: Option<Extension<ConnectInfo<SocketAddr>>>,
+    headers: HeaderMap,
+) -> Response {
+    // Set by Cloudflare in front of the API host
+    let header_value = |name| headers.get(name).and_then(|v| v.to_str().ok()).map(|s| s.to_string());
+    let client_country = header_value("cf-ipcountry");
+    let client_ip = header_value("cf-connecting-ip").or_else(|| connect_info.map(|Extension(ConnectInfo(addr))| addr.ip().to_canonical().to_string()));
+    ws.on_upgrade(|socket| handle_socket(socket, state, client_country, client_ip))
 }
 
 /// Handle a WebSocket connection
-async fn handle_socket(socket: WebSocket, state: AppState) {
-    let (mut sender, mut receiver) = socket.split();
+async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    client_country: Option<String>,
+    client_ip: Option<String>,
+) {
+    let (sink, mut receiver) = socket.split();
 
     // Wait for Init packet
     let init_msg = match tokio::time::timeout(Duration::from_secs(10), receiver.next()).await {
@@ -63,8 +270,8 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
         }
     };
 
-    let init_packet = match ControlPacket::from_bytes(&init_msg) {
-        Ok(ControlPacket::Init(hello)) => hello,
+    let (init_packet, wire) = match decode_init(&init_msg, state.config.max_frame_bytes, state.config.wire_format) {
+        Ok((ControlPacket::Init(hello), wire)) => (hello, wire),
         Ok(_) => {
             tracing::warn!("Expected Init packet");
             return;
@@ -74,43 +281,65 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
             return;
         }
     };
+    let mut sender = PacketSender {
+        sink,
+        wire,
+        usage: Arc::default(),
+    };
 
     // Authenticate
-    let user = match queries::find_user_by_token(&state.db, &init_packet.token).await {
-        Ok(Some(user)) => user,
-        Ok(None) => {
+    let user = match authenticate_client(state.authenticator.as_ref(), &init_packet.token).await {
+        Ok(user) => user,
+        Err(message) => {
             let error = ServerHello {
                 assigned_domain: String::new(),
-                error: Some("Invalid token".to_string()),
+                error: Some(message),
                 server_version: constants::PROTOCOL_VERSION.to_string(),
+                redirect_to: None,
             };
             let _ = send_packet(&mut sender, ControlPacket::InitAck(error)).await;
             return;
         }
-        Err(e) => {
-            tracing::error!("Database error during auth: {}", e);
-            let error = ServerHello {
+    };
+
+    // Send the client on to a better placed node, before registering anything
+    // here. A region the client asked for outranks where it connects from.
+    if let Some(region) = init_packet.region.as_deref() {
+        let (redirect_to, error) = match find_region_node(&state, region, init_packet.redirect_hops).await {
+            Ok(None) => (None, None),
+            Ok(Some(host)) => {
+                tracing::info!("Redirecting tunnel client to {} for region {}", host, region);
+                (Some(host), None)
+            }
+            Err(message) => (None, Some(message)),
+        };
+        if redirect_to.is_some() || error.is_some() {
+            let ack = ServerHello {
                 assigned_domain: String::new(),
-                error: Some("Authentication failed".to_string()),
+                error,
                 server_version: constants::PROTOCOL_VERSION.to_string(),
+                redirect_to,
             };
-            let _ = send_packet(&mut sender, ControlPacket::InitAck(error)).await;
+            let _ = send_packet(&mut sender, ControlPacket::InitAck(ack)).await;
             return;
         }
-    };
-
-    // Check rate limit for tunnel creation based on user's effective plan
-    let is_paid = if let Some(expires_at) = user.plan_expires_at {
-        if expires_at < chrono::Utc::now() {
-            false
-        } else {
-            user.is_paid()
+    } else if let Some(hops) = init_packet.redirect_hops {
+        if let Some(host) = find_redirect(&state, client_country.as_deref(), hops).await {
+            tracing::info!("Redirecting tunnel client from {:?} to {}", client_country, host);
+            let redirect = ServerHello {
+                assigned_domain: String::new(),
+                error: None,
+                server_version: constants::PROTOCOL_VERSION.to_string(),
+                redirect_to: Some(host),
+            };
+            let _ = send_packet(&mut sender, ControlPacket::InitAck(redirect)).await;
+            return;
         }
-    } else {
-        user.is_paid()
-    };
+    }
 
-    match state.rate_limiter.check_tunnel_creation(&user.id.to_string(), is_paid).await {
+    // Check rate limit for tunnel creation based on user's effective plan
+    let features = PlanFeatures::for_user(&user);
+    match state.rate_limiter.check_tunnel_creation(&user.id.to_string(), &features).await {
         Ok(result) if !result.allowed => {
             tracing::warn!(
                 "Rate limit exceeded for user {}: {}/{} tunnels",
@@ -126,6 +355,7 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                     result.reset_in_secs
                 )),
                 server_version: constants::PROTOCOL_VERSION.to_string(),
+                redirect_to: None,
             };
             let _ = send_packet(&mut sender, ControlPacket::InitAck(error)).await;
             return;
@@ -137,38 +367,22 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
     }
 
     // Check bandwidth limit
-    let effective_plan = if let Some(expires_at) = user.plan_expires_at {
-        if expires_at < chrono::Utc::now() {
-            "free"
-        } else {
-            user.plan.as_str()
-        }
-    } else {
-        user.plan.as_str()
-    };
-
-    let bandwidth_limit = match effective_plan {
-        "pro" => constants::BANDWIDTH_PRO,
-        "hobby" => constants::BANDWIDTH_HOBBY,
-        _ => constants::BANDWIDTH_FREE,
-    };
+    let effective_plan = user.effective_plan();
+    let bandwidth_limit = features.bandwidth;
 
     match state.route_manager.get_usage(&user.id.to_string()).await {
         Ok(current_usage) if current_usage >= bandwidth_limit => {
-            let limit_gb = bandwidth_limit / (1024 * 1024 * 1024);
             tracing::warn!(
                 "Bandwidth limit exceeded for user {}: {} bytes / {} GB",
                 user.email,
                 current_usage,
-                limit_gb
+                bandwidth_limit / (1024 * 1024 * 1024)
             );
             let error = ServerHello {
                 assigned_domain: String::new(),
-                error: Some(format!(
-                    "Monthly bandwidth limit exceeded ({} GB). Upgrade your plan at https://dvaar.io/billing",
-                    limit_gb
-                )),
+                error: Some(bandwidth_exceeded_message(bandwidth_limit)),
                 server_version: constants::PROTOCOL_VERSION.to_string(),
+                redirect_to: None,
             };
             let _ = send_packet(&mut sender, ControlPacket::InitAck(error)).await;
             return;
@@ -180,14 +394,10 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
     }
 
     // Determine concurrent tunnel limit for this plan
-    let concurrent_limit = match effective_plan {
-        "pro" => constants::CONCURRENT_TUNNELS_PRO,
-        "hobby" => constants::CONCURRENT_TUNNELS_HOBBY,
-        _ => constants::CONCURRENT_TUNNELS_FREE,
-    };
+    let concurrent_limit = features.max_concurrent;
 
     // Generate or validate subdomain
-    let can_request_subdomain = matches!(effective_plan, "hobby" | "pro");
+    let can_request_subdomain = features.custom_subdomains;
     let subdomain = match assign_subdomain(&state, &init_packet, &user.id.to_string(), can_request_subdomain).await {
         Ok(s) => s,
         Err(e) => {
@@ -195,36 +405,113 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                 assigned_domain: String::new(),
                 error: Some(e),
                 server_version: constants::PROTOCOL_VERSION.to_string(),
+                redirect_to: None,
             };
             let _ = send_packet(&mut sender, ControlPacket::InitAck(error)).await;
             return;
         }
     };
 
-    let full_domain = state.config.full_domain(&subdomain);
-    let full_url = state.config.full_url(&subdomain);
+    // A wildcard claims every name under the subdomain, so it has to be reserved
+    if init_packet.wildcard && !owns_reserved_subdomain(&state, &subdomain, &user.id.to_string()).await {
+        let error = ServerHello {
+            assigned_domain: String::new(),
+            error: Some("Wildcard routing requires a reserved subdomain".to_string()),
+            server_version: constants::PROTOCOL_VERSION.to_string(),
+            redirect_to: None,
+        };
+        let _ = send_packet(&mut sender, ControlPacket::InitAck(error)).await;
+        return;
+    }
+
+    let access = match AccessRules::new(&init_packet.allowed_methods, &init_packet.denied_paths) {
+        Ok(access) => access,
+        Err(message) => {
+            let error = ServerHello {
+                assigned_domain: String::new(),
+                error: Some(message),
+                server_version: constants::PROTOCOL_VERSION.to_string(),
+                redirect_to: None,
+            };
+            let _ = send_packet(&mut sender, ControlPacket::InitAck(error)).await;
+            return;
+        }
+    };
 
     // Register route in Redis
-    let route_info = RouteInfo::new(
+    let mut route_info = RouteInfo::new(
         state.config.node_ip.clone(),
         state.config.internal_port,
         user.id.to_string(),
     );
+    route_info.wildcard = init_packet.wildcard;
+
+    // The availability checks above can't stop another client taking the
+    // name in the meantime; claiming the route can
+    let requested = init_packet.requested_subdomain.is_some();
+    let claimed = claim_subdomain(state.route_manager.as_ref(), subdomain, requested, &route_info, || {
+        assign_random_subdomain(&state)
+    })
+    .await;
+    let subdomain = match claimed {
+        Ok(subdomain) => subdomain,
+        Err(e) => {
+            let error = ServerHello {
+                assigned_domain: String::new(),
+                error: Some(e),
+                server_version: constants::PROTOCOL_VERSION.to_string(),
+                redirect_to: None,
+            };
+            let _ = send_packet(&mut sender, ControlPacket::InitAck(error)).await;
+            return;
+        }
+    };
 
-    if let Err(e) = state.route_manager.register_route(&subdomain, &route_info).await {
-        tracing::error!("Failed to register route: {}", e);
-        let error = ServerHello {
-            assigned_domain: String::new(),
-            error: Some("Failed to register route".to_string()),
-            server_version: constants::PROTOCOL_VERSION.to_string(),
-        };
-        let _ = send_packet(&mut sender, ControlPacket::InitAck(error)).await;
-        return;
+    let full_domain = state.config.full_domain(&subdomain);
+    let full_url = state.config.full_url(&subdomain);
+
+    let user_id_for_cleanup = user.id.to_string();
+    let mut registration = RegistrationGuard::new(
+        state.route_manager.clone(),
+        state.tunnels.clone(),
+        subdomain.clone(),
+        user_id_for_cleanup.clone(),
+        route_info.connection_id.clone(),
+    );
+    registration.route = true;
+
+    // Create channels for request/response handling
+    let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(state.config.tunnel_channel_capacity);
+
+    // Insert the local handle straight after claiming the route, with nothing
+    // to wait on between, so anything the route attracts finds it (and gets
+    // a 503) until the tunnel is ready
+    let mut handle = TunnelHandle::new(request_tx, user.id.to_string());
+    handle.stream_capacity = state.config.stream_channel_capacity;
+    handle.flow_control = init_packet.flow_control;
+    handle.stream_slots = Arc::new(Semaphore::new(state.config.max_streams_per_tunnel));
+    handle.access = access;
+    handle.wildcard = init_packet.wildcard;
+    handle.route = Some(route_info.clone());
+    handle.plan = features.clone();
+    handle.response_cache = state.response_cache.clone();
+    let ready = handle.ready.clone();
+    let upstream = handle.upstream.clone();
+    let maintenance = handle.maintenance.clone();
+    state.tunnels.insert(subdomain.clone(), handle);
+    registration.handle = true;
+    // Maintenance goes with the route: a reconnect that takes the route over
+    // keeps it, while a name released and handed to someone else starts out
+    // of it. Loading it once the handle is in is soon enough: nothing is
+    // forwarded before the tunnel is ready.
+    match state.route_manager.get_maintenance(&subdomain).await {
+        Ok(page) => maintenance.set(page),
+        Err(e) => tracing::warn!("Failed to load maintenance state for {}: {}", subdomain, e),
     }
+    report_tunnel_count(&state);
 
     // Register tunnel in sorted set (tracks individual tunnels with timestamps)
     // Stale tunnels auto-expire after 1 min if heartbeat stops
-    let user_id_for_cleanup = user.id.to_string();
     match state.route_manager.register_user_tunnel(&user_id_for_cleanup, &subdomain, concurrent_limit).await {
         Ok((current_tunnels, false)) => {
             // Over limit
@@ -235,7 +522,7 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                 concurrent_limit
             );
             // Clean up the route we just registered
-            let _ = state.route_manager.remove_route(&subdomain).await;
+            registration.release().await;
 
             let upgrade_msg = match effective_plan {
                 "free" => "Upgrade to Hobby ($5/mo) for 10 concurrent tunnels: dvaar upgrade",
@@ -249,16 +536,20 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                     concurrent_limit, upgrade_msg
                 )),
                 server_version: constants::PROTOCOL_VERSION.to_string(),
+                redirect_to: None,
             };
             let _ = send_packet(&mut sender, ControlPacket::InitAck(error)).await;
             return;
         }
         Err(e) => {
             tracing::error!("Concurrent tunnel check failed: {}", e);
-            // On Redis error, allow the tunnel (fail open) but log it
+            // On Redis error, allow the tunnel (fail open) but log it.
+            // The slot may or may not have been taken, so release it either way.
+            registration.user_tunnel = true;
         }
         Ok((_, true)) => {
             // Successfully registered, we're under the limit
+            registration.user_tunnel = true;
         }
     }
 
@@ -267,15 +558,36 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
         assigned_domain: full_domain.clone(),
         error: None,
         server_version: constants::PROTOCOL_VERSION.to_string(),
+        redirect_to: None,
     };
 
     if send_packet(&mut sender, ControlPacket::InitAck(ack)).await.is_err() {
         // InitAck failed - clean up route AND unregister tunnel
-        let _ = state.route_manager.remove_route(&subdomain).await;
-        let _ = state.route_manager.unregister_user_tunnel(&user_id_for_cleanup, &subdomain).await;
+        registration.release().await;
         return;
     }
 
+    // Remember (or forget) the page ingress serves while this tunnel is down
+    let offline_page_result = match &init_packet.offline_page {
+        Some(html) if html.len() > constants::MAX_OFFLINE_PAGE_BYTES => {
+            tracing::warn!("Ignoring oversized offline page for {} ({} bytes)", subdomain, html.len());
+            Ok(())
+        }
+        Some(html) => {
+            // Only a reserved name stays the owner's once the tunnel is gone
+            let ttl_secs = if owns_reserved_subdomain(&state, &subdomain, &user.id.to_string()).await {
+                constants::OFFLINE_PAGE_TTL_SECONDS
+            } else {
+                constants::ROUTE_TTL_SECONDS as i64
+            };
+            state.route_manager.set_offline_page(&subdomain, html, ttl_secs).await
+        }
+        None => state.route_manager.clear_offline_page(&subdomain).await,
+    };
+    if let Err(e) = offline_page_result {
+        tracing::error!("Failed to update offline page for {}: {}", subdomain, e);
+    }
+
     tracing::info!(
         "Tunnel established: {} -> {} (user: {})",
         full_url,
@@ -283,25 +595,29 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
         user.email
     );
 
-    // Create channels for request/response handling
-    let (request_tx, mut request_rx) = mpsc::channel::<TunnelCommand>(32);
-    let (shutdown_tx, shutdown_rx) = watch::channel(false);
-
-    // Register local tunnel handle
-    state.tunnels.insert(
-        subdomain.clone(),
-        TunnelHandle {
-            request_tx,
-            user_id: user.id.to_string(),
-        },
-    );
+    // Audit trail; a failed write is logged but doesn't stop the tunnel
+    let tunnel_event = match queries::record_tunnel_event(
+        &state.db,
+        user.id,
+        &subdomain,
+        &state.config.node_ip,
+        client_ip.as_deref(),
+    )
+    .await
+    {
+        Ok(id) => Some(id),
+        Err(e) => {
+            tracing::error!("Failed to record tunnel event for {}: {}", subdomain, e);
+            None
+        }
+    };
 
     // Start heartbeat task (also refreshes user tunnel count TTL)
-    let heartbeat_handle = spawn_heartbeat(
-        RouteManager::new(state.redis.clone()),
+    let heartbeat_handle = spawn_route_heartbeat(
+        (*state.route_manager).clone(),
         subdomain.clone(),
+        route_info,
         user_id_for_cleanup.clone(),
-        shutdown_rx,
     );
 
     // Active streams: stream_id -> response channel
@@ -311,6 +627,15 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
     // WebSocket sender shared between tasks
     let sender = Arc::new(Mutex::new(sender));
     let sender_clone = sender.clone();
+    let ready_sender = sender.clone();
+
+    // One budget for both directions; request bodies and frames sent to the
+    // client wait on it, and so does each frame read back from it
+    let throttle = state
+        .config
+        .bandwidth_rate(effective_plan)
+        .map(|rate| Arc::new(ByteRateLimiter::per_second(rate)));
+    let throttle_send = throttle.clone();
 
     // Task to send requests to client
     let active_streams_clone = active_streams.clone();
@@ -326,7 +651,7 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                             stream_id.clone(),
                             StreamState {
                                 response_tx: tunnel_req.response_tx,
-                                is_websocket: false,
+                                phase: StreamPhase::AwaitingResponse,
                             },
                         );
                     }
@@ -337,20 +662,14 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                         send_packet(&mut *sender, packet).await
                     };
 
-                    if send_result.is_err() {
-                        let tx = {
-                            let mut streams = active_streams_clone.lock().await;
-                            streams.remove(&stream_id).map(|state| state.response_tx)
-                        };
-                        if let Some(tx) = tx {
-                            let _ = tx
-                                .send(StreamChunk::Error("Send failed".to_string()))
-                                .await;
-                        }
+                    if on_send_result(send_result, &active_streams_clone, &stream_id).await.is_break() {
                         break;
                     }
                 }
                 TunnelCommand::Data { stream_id, data } => {
+                    if let Some(throttle) = &throttle_send {
+                        throttle.acquire(data.len()).await;
+                    }
                     let packet = ControlPacket::Data {
                         stream_id: stream_id.clone(),
                         data,
@@ -359,16 +678,7 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                         let mut sender = sender_clone.lock().await;
                         send_packet(&mut *sender, packet).await
                     };
-                    if send_result.is_err() {
-                        let tx = {
-                            let mut streams = active_streams_clone.lock().await;
-                            streams.remove(&stream_id).map(|state| state.response_tx)
-                        };
-                        if let Some(tx) = tx {
-                            let _ = tx
-                                .send(StreamChunk::Error("Send failed".to_string()))
-                                .await;
-                        }
+                    if on_send_result(send_result, &active_streams_clone, &stream_id).await.is_break() {
                         break;
                     }
                 }
@@ -380,16 +690,7 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                         let mut sender = sender_clone.lock().await;
                         send_packet(&mut *sender, packet).await
                     };
-                    if send_result.is_err() {
-                        let tx = {
-                            let mut streams = active_streams_clone.lock().await;
-                            streams.remove(&stream_id).map(|state| state.response_tx)
-                        };
-                        if let Some(tx) = tx {
-                            let _ = tx
-                                .send(StreamChunk::Error("Send failed".to_string()))
-                                .await;
-                        }
+                    if on_send_result(send_result, &active_streams_clone, &stream_id).await.is_break() {
                         break;
                     }
                 }
@@ -398,6 +699,9 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                     data,
                     is_binary,
                 } => {
+                    if let Some(throttle) = &throttle_send {
+                        throttle.acquire(data.len()).await;
+                    }
                     let packet = ControlPacket::WebSocketFrame {
                         stream_id: stream_id.clone(),
                         data,
@@ -407,16 +711,7 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                         let mut sender = sender_clone.lock().await;
                         send_packet(&mut *sender, packet).await
                     };
-                    if send_result.is_err() {
-                        let tx = {
-                            let mut streams = active_streams_clone.lock().await;
-                            streams.remove(&stream_id).map(|state| state.response_tx)
-                        };
-                        if let Some(tx) = tx {
-                            let _ = tx
-                                .send(StreamChunk::Error("Send failed".to_string()))
-                                .await;
-                        }
+                    if on_send_result(send_result, &active_streams_clone, &stream_id).await.is_break() {
                         break;
                     }
                 }
@@ -434,19 +729,42 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                         let mut sender = sender_clone.lock().await;
                         send_packet(&mut *sender, packet).await
                     };
-                    if send_result.is_err() {
-                        let tx = {
-                            let mut streams = active_streams_clone.lock().await;
-                            streams.remove(&stream_id).map(|state| state.response_tx)
-                        };
-                        if let Some(tx) = tx {
-                            let _ = tx
-                                .send(StreamChunk::Error("Send failed".to_string()))
-                                .await;
-                        }
+                    if on_send_result(send_result, &active_streams_clone, &stream_id).await.is_break() {
+                        break;
+                    }
+                }
+                TunnelCommand::Cancel { stream_id } => {
+                    // Nobody is reading the response anymore, stop routing to it
+                    let was_active = active_streams_clone.lock().await.remove(&stream_id).is_some();
+                    if !was_active {
+                        continue;
+                    }
+
+                    tracing::debug!("Cancelling stream {}", stream_id);
+                    let packet = ControlPacket::StreamCancel { stream_id };
+                    let send_result = {
+                        let mut sender = sender_clone.lock().await;
+                        send_packet(&mut sender, packet).await
+                    };
+                    if let Err(SendError::Transport(_)) = send_result {
+                        break;
+                    }
+                }
+                TunnelCommand::WindowUpdate { stream_id, bytes } => {
+                    let packet = ControlPacket::WindowUpdate { stream_id, bytes };
+                    let send_result = {
+                        let mut sender = sender_clone.lock().await;
+                        send_packet(&mut sender, packet).await
+                    };
+                    if let Err(SendError::Transport(_)) = send_result {
                         break;
                     }
                 }
+                TunnelCommand::Close { code, reason } => {
+                    let mut sender = sender_clone.lock().await;
+                    let _ = send_packet(&mut sender, ControlPacket::Close { code, reason }).await;
+                    break;
+                }
             }
         }
     });
@@ -454,37 +772,121 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
     // Task to receive responses from client
     let active_streams_clone = active_streams.clone();
     let route_manager_clone = state.route_manager.clone();
-    let usage_is_paid = matches!(effective_plan, "hobby" | "pro");
+    let usage_is_paid = user.is_paid();
     let usage_plan_expires_at = user.plan_expires_at;
+    let ping_interval = Duration::from_secs(state.config.ws_ping_interval_secs);
+    let pong_timeout = Duration::from_secs(state.config.ws_pong_timeout_secs);
+    let subdomain_for_recv = subdomain.clone();
+    let stream_send_timeout = Duration::from_millis(state.config.stream_send_timeout_ms);
+    let max_frame_bytes = state.config.max_frame_bytes;
+    let usage_flush_interval = Duration::from_secs(state.config.usage_flush_interval_secs);
+    let mut usage_flush = UsageFlush::new(state.config.usage_flush_bytes, usage_flush_interval);
+    let (wire, usage_meter) = {
+        let sender = sender.lock().await;
+        (sender.wire, sender.usage.clone())
+    };
+    let channel_stats = state.channel_stats.clone();
 
     let recv_task = tokio::spawn(async move {
-        let mut bandwidth_buffer = 0u64;
         let user_id = user.id.to_string();
 
-        while let Some(msg) = receiver.next().await {
+        // Keepalive: ping the client and drop the tunnel if it stops answering,
+        // so half-open connections don't keep the route registered
+        let mut ping_ticker = tokio::time::interval_at(
+            tokio::time::Instant::now() + ping_interval,
+            ping_interval,
+        );
+        let mut last_pong = tokio::time::Instant::now();
+        // Small amounts of traffic still reach the user's usage promptly
+        let mut usage_ticker = tokio::time::interval_at(
+            tokio::time::Instant::now() + usage_flush_interval,
+            usage_flush_interval,
+        );
+        // Bare pings until the client shows it echoes nonces by sending one
+        let mut keepalive = Keepalive::new(false);
+
+        loop {
+            let msg = tokio::select! {
+                msg = receiver.next() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = ping_ticker.tick() => {
+                    if last_pong.elapsed() > pong_timeout {
+                        tracing::warn!(
+                            "Tunnel {} missed pongs for {:?}, closing",
+                            subdomain_for_recv,
+                            pong_timeout
+                        );
+                        // Best effort: a half-open client won't see it
+                        let mut sender = sender.lock().await;
+                        let _ = send_packet(
+                            &mut sender,
+                            ControlPacket::Close {
+                                code: CloseCode::Idle,
+                                reason: "No keepalive response from the client".to_string(),
+                            },
+                        )
+                        .await;
+                        break;
+                    }
+                    let mut sender = sender.lock().await;
+                    let _ = send_packet(&mut sender, keepalive.ping()).await;
+                    continue;
+                }
+                _ = usage_ticker.tick() => {
+                    // Uploads with little coming back are counted too
+                    if let Some(usage) = usage_flush.take(&usage_meter, tokio::time::Instant::now()) {
+                        let usage_ttl_secs = usage_ttl_secs(usage_is_paid, usage_plan_expires_at);
+                        let _ = route_manager_clone
+                            .increment_usage(&user_id, usage, usage_ttl_secs)
+                            .await;
+                    }
+                    continue;
+                }
+            };
+
             let data = match msg {
                 Ok(Message::Binary(data)) => data,
                 Ok(Message::Ping(data)) => {
                     let mut sender = sender.lock().await;
-                    let _ = sender.send(Message::Pong(data)).await;
+                    let _ = sender.sink.send(Message::Pong(data)).await;
+                    continue;
+                }
+                Ok(Message::Pong(_)) => {
+                    last_pong = tokio::time::Instant::now();
                     continue;
                 }
-                Ok(Message::Pong(_)) => continue,
                 Ok(Message::Close(_)) | Err(_) => break,
                 Ok(Message::Text(_)) => continue,
             };
 
             // Track bandwidth
-            bandwidth_buffer += data.len() as u64;
-            if bandwidth_buffer >= 1_000_000 {
+            usage_meter.add_egress(data.len());
+            if let Some(usage) = usage_flush.take(&usage_meter, tokio::time::Instant::now()) {
                 let usage_ttl_secs = usage_ttl_secs(usage_is_paid, usage_plan_expires_at);
-                let _ = route_manager_clone
-                    .increment_usage(&user_id, bandwidth_buffer, usage_ttl_secs)
+                let usage = route_manager_clone
+                    .increment_usage(&user_id, usage, usage_ttl_secs)
+                    .await;
+                if matches!(usage, Ok(total) if total >= bandwidth_limit) {
+                    tracing::warn!("Bandwidth limit reached mid-session for {}, closing", subdomain_for_recv);
+                    let mut sender = sender.lock().await;
+                    let _ = send_packet(
+                        &mut sender,
+                        ControlPacket::Close {
+                            code: CloseCode::BandwidthExceeded,
+                            reason: bandwidth_exceeded_message(bandwidth_limit),
+                        },
+                    )
                     .await;
-                bandwidth_buffer = 0;
+                    break;
+                }
+            }
+            if let Some(throttle) = &throttle {
+                throttle.acquire(data.len()).await;
             }
 
-            let packet = match ControlPacket::from_bytes(&data) {
+            let packet = match ControlPacket::decode_limited(&data, max_frame_bytes, wire) {
                 Ok(p) => p,
                 Err(e) => {
                     tracing::warn!("Failed to parse packet: {}", e);
@@ -493,112 +895,79 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
             };
 
             match packet {
-                ControlPacket::HttpResponse(response) => {
-                    let (tx, is_websocket) = {
-                        let mut streams = active_streams_clone.lock().await;
-                        if let Some(state) = streams.get_mut(&response.stream_id) {
-                            if response.is_websocket_upgrade() {
-                                state.is_websocket = true;
-                            }
-                            (Some(state.response_tx.clone()), state.is_websocket)
-                        } else {
-                            (None, false)
-                        }
-                    };
-
-                    if let Some(tx) = tx {
-                        let _ = tx.send(StreamChunk::Headers(response)).await;
-                    }
-
-                    if is_websocket {
-                        continue;
-                    }
-                }
-
-                ControlPacket::Data { stream_id, data } => {
-                    let tx = {
-                        let streams = active_streams_clone.lock().await;
-                        streams.get(&stream_id).map(|state| state.response_tx.clone())
-                    };
-                    if let Some(tx) = tx {
-                        let _ = tx.send(StreamChunk::Data(data)).await;
+                ControlPacket::Ping(nonce) => {
+                    if nonce.is_some() {
+                        keepalive.enable_nonces();
                     }
+                    let mut sender = sender.lock().await;
+                    let _ = send_packet(&mut *sender, ControlPacket::Pong(nonce)).await;
                 }
 
-                ControlPacket::End { stream_id } => {
-                    let tx = {
-                        let mut streams = active_streams_clone.lock().await;
-                        match streams.get(&stream_id) {
-                            Some(state) if state.is_websocket => None,
-                            Some(_) => streams.remove(&stream_id).map(|state| state.response_tx),
-                            None => None,
-                        }
-                    };
-                    if let Some(tx) = tx {
-                        let _ = tx.send(StreamChunk::End).await;
+                ControlPacket::Pong(nonce) => {
+                    last_pong = tokio::time::Instant::now();
+                    if let Some(rtt) = keepalive.round_trip(nonce) {
+                        tracing::trace!("Tunnel {} round trip {:?}", subdomain_for_recv, rtt);
                     }
                 }
 
-                ControlPacket::WebSocketFrame { stream_id, data, is_binary } => {
-                    let tx = {
-                        let streams = active_streams_clone.lock().await;
-                        streams.get(&stream_id).map(|state| state.response_tx.clone())
-                    };
-                    if let Some(tx) = tx {
-                        let _ = tx
-                            .send(StreamChunk::WebSocketFrame { data, is_binary })
-                            .await;
-                    }
-                }
-
-                ControlPacket::WebSocketClose { stream_id, code, reason } => {
-                    let tx = {
-                        let mut streams = active_streams_clone.lock().await;
-                        streams.remove(&stream_id).map(|state| state.response_tx)
-                    };
-                    if let Some(tx) = tx {
-                        let _ = tx.send(StreamChunk::WebSocketClose { code, reason }).await;
+                ControlPacket::Metrics(metrics) => {
+                    if let Err(e) = route_manager_clone
+                        .set_upstream_metrics(&subdomain_for_recv, &metrics)
+                        .await
+                    {
+                        tracing::debug!("Failed to store upstream metrics: {}", e);
                     }
+                    upstream.record(metrics);
                 }
 
-                ControlPacket::StreamError { stream_id, error } => {
-                    let tx = {
-                        let mut streams = active_streams_clone.lock().await;
-                        streams.remove(&stream_id).map(|state| state.response_tx)
-                    };
-                    if let Some(tx) = tx {
-                        let _ = tx.send(StreamChunk::Error(error)).await;
+                packet => {
+                    let routed = route_client_packet(&mut *active_streams_clone.lock().await, packet);
+                    if let Some((stream_id, tx, chunk)) = routed {
+                        deliver_chunk(
+                            &active_streams_clone,
+                            &sender,
+                            &stream_id,
+                            tx,
+                            chunk,
+                            stream_send_timeout,
+                            &channel_stats,
+                        )
+                        .await;
                     }
                 }
-
-                ControlPacket::Ping => {
-                    let mut sender = sender.lock().await;
-                    let _ = send_packet(&mut *sender, ControlPacket::Pong).await;
-                }
-
-                ControlPacket::Pong => {}
-
-                _ => {
-                    tracing::debug!("Unexpected packet type from client");
-                }
             }
         }
 
         // Flush remaining bandwidth
-        if bandwidth_buffer > 0 {
+        if let Some(usage) = usage_meter.take(0) {
             let usage_ttl_secs = usage_ttl_secs(usage_is_paid, usage_plan_expires_at);
             let _ = route_manager_clone
-                .increment_usage(&user_id, bandwidth_buffer, usage_ttl_secs)
+                .increment_usage(&user_id, usage, usage_ttl_secs)
                 .await;
         }
 
         // Close all active streams
         let mut streams = active_streams_clone.lock().await;
         for (_, state) in streams.drain() {
-            let _ = state.response_tx.send(StreamChunk::Error("Tunnel closed".to_string())).await;
+            // A stream that can't take the error still sees its channel close
+            let _ = state.response_tx.try_send(StreamChunk::Error {
+                code: StreamErrorCode::UpstreamDown,
+                message: "Tunnel closed".to_string(),
+            });
         }
     });
 
+    // Both tasks are running and the handle is in place: tell the client,
+    // then let traffic through
+    let ready_result = {
+        let mut sender = ready_sender.lock().await;
+        send_packet(&mut sender, ControlPacket::Ready).await
+    };
+    drop(ready_sender);
+    if ready_result.is_ok() {
+        ready.store(true, Ordering::Release);
+    }
+
     // Wait for either task to complete
     tokio::select! {
         _ = send_task => {}
@@ -606,28 +975,470 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
     }
 
     // Cleanup
-    let _ = shutdown_tx.send(true);
     heartbeat_handle.abort();
-    state.tunnels.remove(&subdomain);
-    let _ = state.route_manager.remove_route(&subdomain).await;
-    let _ = state.route_manager.unregister_user_tunnel(&user_id_for_cleanup, &subdomain).await;
+    registration.release().await;
+    report_tunnel_count(&state);
+
+    if let Some(id) = tunnel_event {
+        if let Err(e) = queries::close_tunnel_event(&state.db, id).await {
+            tracing::error!("Failed to record tunnel close for {}: {}", subdomain, e);
+        }
+    }
 
     tracing::info!("Tunnel closed: {}", full_domain);
 }
 
-/// Send a control packet
-async fn send_packet(
-    sender: &mut futures_util::stream::SplitSink<WebSocket, Message>,
+/// Push this node's tunnel count to the cluster registry now instead of
+/// waiting for the next node heartbeat, which corrects any update lost here
+fn report_tunnel_count(state: &AppState) {
+    let route_manager = state.route_manager.clone();
+    let node_id = state.config.node_ip.clone();
+    let tunnel_count = state.tunnels.len() as u32;
+    tokio::spawn(async move {
+        if let Err(e) = route_manager.update_node_tunnels(&node_id, tunnel_count).await {
+            tracing::debug!("Failed to update node tunnel count: {}", e);
+        }
+    });
+}
+
+/// What a client over its monthly quota is told, at connect or mid-session
+fn bandwidth_exceeded_message(limit: u64) -> String {
+    format!(
+        "Monthly bandwidth limit exceeded ({} GB). Upgrade your plan at https://dvaar.io/billing",
+        limit / (1024 * 1024 * 1024)
+    )
+}
+
+/// Match a packet from the client to the stream it names, moving the stream
+/// on to its next phase. Streams are only ever opened by this side, so a
+/// packet naming one the tunnel never opened, or one that doesn't fit where
+/// its stream is (body data before the headers, frames on a stream that
+/// wasn't upgraded), is dropped rather than passed to ingress. A stream error
+/// is accepted at any point.
+fn route_client_packet(
+    streams: &mut HashMap<String, StreamState>,
     packet: ControlPacket,
-) -> Result<(), axum::Error> {
-    let data = packet.to_bytes().map_err(|e| {
+) -> Option<(String, mpsc::Sender<StreamChunk>, StreamChunk)> {
+    let (stream_id, chunk) = match packet {
+        ControlPacket::HttpResponse(response) => (response.stream_id.clone(), StreamChunk::Headers(response)),
+        ControlPacket::Continue { stream_id } => (stream_id, StreamChunk::Continue),
+        ControlPacket::Data { stream_id, data } => (stream_id, StreamChunk::Data(data)),
+        ControlPacket::Trailers { stream_id, headers } => (stream_id, StreamChunk::Trailers(headers)),
+        ControlPacket::End { stream_id } => (stream_id, StreamChunk::End),
+        ControlPacket::WebSocketFrame { stream_id, data, is_binary } => {
+            (stream_id, StreamChunk::WebSocketFrame { data, is_binary })
+        }
+        ControlPacket::WebSocketClose { stream_id, code, reason } => {
+            (stream_id, StreamChunk::WebSocketClose { code, reason })
+        }
+        ControlPacket::StreamError { stream_id, error, code } => {
+            (stream_id, StreamChunk::Error { code, message: error })
+        }
+        _ => {
+            tracing::debug!("Unexpected packet type from client");
+            return None;
+        }
+    };
+
+    let Some(state) = streams.get_mut(&stream_id) else {
+        tracing::debug!("Dropping packet for stream {} the tunnel didn't open", stream_id);
+        return None;
+    };
+    let fits = matches!(
+        (&chunk, state.phase),
+        (StreamChunk::Continue | StreamChunk::Headers(_), StreamPhase::AwaitingResponse)
+            | (StreamChunk::Data(_) | StreamChunk::Trailers(_) | StreamChunk::End, StreamPhase::Body)
+            | (StreamChunk::WebSocketFrame { .. } | StreamChunk::WebSocketClose { .. }, StreamPhase::WebSocket)
+            | (StreamChunk::Error { .. }, _)
+    );
+    if !fits {
+        tracing::warn!("Dropping out-of-order packet for stream {} ({:?})", stream_id, state.phase);
+        return None;
+    }
+
+    let tx = match &chunk {
+        StreamChunk::Headers(response) => {
+            state.phase = if response.is_websocket_upgrade() {
+                StreamPhase::WebSocket
+            } else {
+                StreamPhase::Body
+            };
+            state.response_tx.clone()
+        }
+        StreamChunk::End | StreamChunk::WebSocketClose { .. } | StreamChunk::Error { .. } => {
+            streams.remove(&stream_id)?.response_tx
+        }
+        _ => state.response_tx.clone(),
+    };
+    Some((stream_id, tx, chunk))
+}
+
+/// Pass a chunk to its stream. A stream whose reader stops draining is failed
+/// on its own (dropped here, cancelled at the client) rather than holding up
+/// every other stream on the tunnel.
+async fn deliver_chunk(
+    active_streams: &Mutex<HashMap<String, StreamState>>,
+    sender: &Mutex<PacketSender>,
+    stream_id: &str,
+    tx: mpsc::Sender<StreamChunk>,
+    chunk: StreamChunk,
+    timeout: Duration,
+    stats: &ChannelStats,
+) {
+    if send_or_stall(&tx, chunk, timeout, stats).await != Delivery::Stalled {
+        return;
+    }
+
+    tracing::warn!("Stream {} stopped reading its response, failing it", stream_id);
+    // Once every sender is gone, ingress errors the response body
+    drop(tx);
+    let was_active = active_streams.lock().await.remove(stream_id).is_some();
+    if was_active {
+        let mut sender = sender.lock().await;
+        let _ = send_packet(
+            &mut sender,
+            ControlPacket::StreamCancel {
+                stream_id: stream_id.to_string(),
+            },
+        )
+        .await;
+    }
+}
+
+/// Host of a node that would serve a connecting client better than this one
+async fn find_redirect(state: &AppState, client_country: Option<&str>, hops: u32) -> Option<String> {
+    if hops >= constants::MAX_REDIRECT_HOPS {
+        return None;
+    }
+    let nodes = match state.route_manager.get_all_nodes().await {
+        Ok(nodes) => nodes,
+        Err(e) => {
+            tracing::warn!("Failed to list nodes for redirect: {}", e);
+            return None;
+        }
+    };
+    pick_redirect(client_country, &state.config.node_ip, &nodes, hops)
+}
+
+/// Decide whether a client belongs on another node. It does if this node is
+/// outside the client's region and a node inside it has room, or if this node
+/// is full and another isn't; among candidates the least loaded wins. Only
+/// nodes with a public host qualify. Past `MAX_REDIRECT_HOPS` the client stays
+/// put, so nodes with different views of the cluster can't bounce it around.
+fn pick_redirect(client_country: Option<&str>, this_node: &str, nodes: &[NodeInfo], hops: u32) -> Option<String> {
+    if hops >= constants::MAX_REDIRECT_HOPS {
+        return None;
+    }
+
+    let in_region = |node: &NodeInfo| match (client_country, node.region.as_deref()) {
+        (Some(country), Some(region)) => country.eq_ignore_ascii_case(region),
+        _ => false,
+    };
+    let has_room = |node: &NodeInfo| node.tunnel_count < node.max_tunnels;
+    let load = |node: &NodeInfo| node.tunnel_count as f64 / node.max_tunnels.max(1) as f64;
+
+    // A node missing from the registry (e.g. Redis was just flushed) has room
+    let (here_in_region, here_has_room) = nodes
+        .iter()
+        .find(|node| node.node_id == this_node)
+        .map_or((false, true), |node| (in_region(node), has_room(node)));
+    if here_in_region && here_has_room {
+        return None;
+    }
+
+    nodes
+        .iter()
+        .filter(|node| node.node_id != this_node && node.public_host.is_some() && has_room(node))
+        .filter(|node| in_region(node) || !here_has_room)
+        .min_by(|a, b| {
+            in_region(b)
+                .cmp(&in_region(a))
+                .then(load(a).partial_cmp(&load(b)).unwrap_or(std::cmp::Ordering::Equal))
+        })
+        .and_then(|node| node.public_host.clone())
+}
+
+/// Node for a client that asked for `region`: `None` to stay on this one
+async fn find_region_node(state: &AppState, region: &str, hops: Option<u32>) -> Result<Option<String>, String> {
+    let nodes = state.route_manager.get_all_nodes().await.unwrap_or_else(|e| {
+        // This node can still serve its own region
+        tracing::warn!("Failed to list nodes for region {}: {}", region, e);
+        Vec::new()
+    });
+    pick_region_node(
+        region,
+        &state.config.node_ip,
+        state.config.node_region.as_deref(),
+        &nodes,
+        hops,
+    )
+}
+
+/// Decide where a client that asked for `region` goes. It stays if this node
+/// is in the region and has room (or is the region's only node, full or not,
+/// so the usual capacity error applies). Otherwise it is sent to the least
+/// loaded node in the region, and failing that refused with the regions
+/// that do exist.
+fn pick_region_node(
+    region: &str,
+    this_node: &str,
+    this_region: Option<&str>,
+    nodes: &[NodeInfo],
+    hops: Option<u32>,
+) -> Result<Option<String>, String> {
+    let in_region = |node_region: Option<&str>| node_region.is_some_and(|r| r.eq_ignore_ascii_case(region));
+    let has_room = |node: &NodeInfo| node.tunnel_count < node.max_tunnels;
+    let load = |node: &NodeInfo| node.tunnel_count as f64 / node.max_tunnels.max(1) as f64;
+
+    let here_in_region = in_region(this_region);
+    let here_has_room = nodes.iter().find(|node| node.node_id == this_node).is_none_or(has_room);
+    if here_in_region && here_has_room {
+        return Ok(None);
+    }
+
+    let candidate = nodes
+        .iter()
+        .filter(|node| node.node_id != this_node && node.public_host.is_some() && has_room(node))
+        .filter(|node| in_region(node.region.as_deref()))
+        .min_by(|a, b| load(a).partial_cmp(&load(b)).unwrap_or(std::cmp::Ordering::Equal));
+    match (candidate, hops) {
+        (Some(node), Some(hops)) if hops < constants::MAX_REDIRECT_HOPS => return Ok(node.public_host.clone()),
+        _ if here_in_region => return Ok(None),
+        (Some(_), None) => {
+            return Err(format!(
+                "Region {} is served by another node, and this client can't follow redirects. Update dvaar and try again.",
+                region
+            ))
+        }
+        _ => {}
+    }
+
+    let mut regions: Vec<String> = nodes
+        .iter()
+        .filter_map(|node| node.region.as_deref().map(str::to_lowercase))
+        .chain(this_region.map(str::to_lowercase))
+        .collect();
+    regions.sort();
+    regions.dedup();
+    if regions.is_empty() {
+        Err(format!("No node is available in region {}", region))
+    } else {
+        Err(format!(
+            "No node is available in region {} (regions: {})",
+            region,
+            regions.join(", ")
+        ))
+    }
+}
+
+/// The tunnel's WebSocket write half, with the wire format settled at Init
+struct PacketSender {
+    sink: futures_util::stream::SplitSink<WebSocket, Message>,
+    wire: WireFormat,
+    /// Everything sent is request traffic, counted as ingress
+    usage: Arc<UsageMeter>,
+}
+
+/// Pending traffic added to the user's usage in one Redis call, unless
+/// `USAGE_FLUSH_BYTES` says otherwise
+pub const USAGE_FLUSH_BYTES: u64 = 1_000_000;
+
+/// Longest pending traffic waits for a flush, unless
+/// `USAGE_FLUSH_INTERVAL_SECS` says otherwise
+pub const USAGE_FLUSH_INTERVAL_SECS: u64 = 5;
+
+/// Tunnel bytes not yet added to the user's usage. The send side counts
+/// ingress, the receive side egress, and the receive side flushes both.
+#[derive(Debug, Default)]
+struct UsageMeter {
+    ingress: AtomicU64,
+    egress: AtomicU64,
+}
+
+impl UsageMeter {
+    fn add_ingress(&self, bytes: usize) {
+        self.ingress.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn add_egress(&self, bytes: usize) {
+        self.egress.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Take the pending bytes once together they reach `threshold`
+    /// (and there are any at all)
+    fn take(&self, threshold: u64) -> Option<ByteUsage> {
+        let pending = self.ingress.load(Ordering::Relaxed) + self.egress.load(Ordering::Relaxed);
+        if pending == 0 || pending < threshold {
+            return None;
+        }
+        Some(ByteUsage {
+            ingress: self.ingress.swap(0, Ordering::Relaxed),
+            egress: self.egress.swap(0, Ordering::Relaxed),
+        })
+    }
+}
+
+/// When the receive side flushes a tunnel's `UsageMeter`: once `bytes` are
+/// pending, or once `interval` has passed since the last flush and any are.
+/// A large transfer makes few Redis calls and a trickle of small requests is
+/// still counted within `interval`.
+struct UsageFlush {
+    bytes: u64,
+    interval: Duration,
+    last: tokio::time::Instant,
+}
+
+impl UsageFlush {
+    fn new(bytes: u64, interval: Duration) -> Self {
+        Self {
+            bytes,
+            interval,
+            last: tokio::time::Instant::now(),
+        }
+    }
+
+    fn take(&mut self, meter: &UsageMeter, now: tokio::time::Instant) -> Option<ByteUsage> {
+        let threshold = if now.duration_since(self.last) >= self.interval {
+            0
+        } else {
+            self.bytes
+        };
+        let usage = meter.take(threshold)?;
+        self.last = now;
+        Some(usage)
+    }
+}
+
+/// Decode a client's Init in whichever format it was sent, and settle the
+/// tunnel's format: JSON if the client sent JSON and this server allows it,
+/// MessagePack otherwise. The InitAck goes out in the settled format, which
+/// is how the client learns whether it got what it asked for.
+fn decode_init(
+    data: &[u8],
+    max_frame_bytes: usize,
+    server_format: WireFormat,
+) -> Result<(ControlPacket, WireFormat), ProtocolError> {
+    let requested = WireFormat::detect(data);
+    let packet = ControlPacket::decode_limited(data, max_frame_bytes, requested)?;
+    let wire = WireFormat::negotiate(requested, server_format);
+    if wire != requested {
+        tracing::debug!("Client asked for {:?} on the wire, falling back to {:?}", requested, wire);
+    }
+    Ok((packet, wire))
+}
+
+/// How often a write that hit a transient error is tried again
+const SEND_RETRY_ATTEMPTS: u32 = 5;
+
+/// Pause before the first retry; doubles after each one
+const SEND_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+#[derive(Debug, thiserror::Error)]
+enum SendError {
+    /// The packet couldn't be encoded. A bug, and only the packet's stream
+    /// is affected.
+    #[error("failed to encode packet: {0}")]
+    Encode(#[from] ProtocolError),
+
+    /// The connection failed; the tunnel is done
+    #[error("failed to write to tunnel: {0}")]
+    Transport(#[from] axum::Error),
+}
+
+/// Send a control packet
+async fn send_packet(sender: &mut PacketSender, packet: ControlPacket) -> Result<(), SendError> {
+    let data = packet.encode(sender.wire).inspect_err(|e| {
         tracing::error!("Failed to serialize packet: {}", e);
-        axum::Error::new(e)
     })?;
-    sender.send(Message::Binary(data.into())).await?;
+    let len = data.len();
+    send_with_retry(&mut sender.sink, Message::Binary(data.into())).await?;
+    sender.usage.add_ingress(len);
     Ok(())
 }
 
+/// Write a message, riding out a socket that is briefly unable to take it.
+/// Anything other than a transient I/O error fails at once.
+async fn send_with_retry<S>(sink: &mut S, message: Message) -> Result<(), axum::Error>
+where
+    S: futures_util::Sink<Message, Error = axum::Error> + Unpin,
+{
+    let mut delay = SEND_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match sink.send(message.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < SEND_RETRY_ATTEMPTS && is_transient(&e) => {
+                tracing::debug!("Tunnel write stalled ({}), retrying in {:?}", e, delay);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether a write error is the socket pushing back rather than the connection failing
+fn is_transient(error: &axum::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(e) = source {
+        if let Some(io) = e.downcast_ref::<std::io::Error>() {
+            return matches!(
+                io.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted | std::io::ErrorKind::TimedOut
+            );
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// After sending a stream's packet: whether the send loop carries on. A
+/// packet that can't be encoded fails only its stream; a broken connection
+/// fails the stream and ends the loop.
+async fn on_send_result(
+    result: Result<(), SendError>,
+    active_streams: &Mutex<HashMap<String, StreamState>>,
+    stream_id: &str,
+) -> ControlFlow<()> {
+    match result {
+        Ok(()) => ControlFlow::Continue(()),
+        Err(SendError::Encode(_)) => {
+            fail_stream(active_streams, stream_id).await;
+            ControlFlow::Continue(())
+        }
+        Err(SendError::Transport(_)) => {
+            fail_stream(active_streams, stream_id).await;
+            ControlFlow::Break(())
+        }
+    }
+}
+
+/// Drop a stream whose packet couldn't be sent, telling its reader
+async fn fail_stream(active_streams: &Mutex<HashMap<String, StreamState>>, stream_id: &str) {
+    let tx = active_streams.lock().await.remove(stream_id).map(|state| state.response_tx);
+    if let Some(tx) = tx {
+        let _ = tx
+            .send(StreamChunk::Error {
+                code: StreamErrorCode::Other,
+                message: "Send failed".to_string(),
+            })
+            .await;
+    }
+}
+
+/// Resolve the client's token, mapping failures to the error sent in `InitAck`
+pub(crate) async fn authenticate_client(authenticator: &dyn Authenticator, token: &str) -> Result<AuthedUser, String> {
+    match authenticator.authenticate(token).await {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err("Invalid token".to_string()),
+        Err(e) => {
+            tracing::error!("Authentication backend error: {}", e);
+            Err("Authentication failed".to_string())
+        }
+    }
+}
+
 /// Assign a subdomain (generate random if not requested)
 async fn assign_subdomain(
     state: &AppState,
@@ -636,59 +1447,195 @@ async fn assign_subdomain(
     can_request_subdomain: bool,
 ) -> Result<String, String> {
     if let Some(requested) = &init.requested_subdomain {
-        if !can_request_subdomain {
-            return Err("Custom subdomains require a paid plan".to_string());
-        }
-
-        match abuse::check_subdomain(requested) {
-            SubdomainCheck::Blocked(reason) => {
-                tracing::warn!(
-                    "Blocked subdomain request '{}' from user {}: {:?}",
-                    requested,
-                    user_id,
-                    reason
-                );
-                return Err(reason.message());
-            }
-            SubdomainCheck::Allowed => {}
+        validate_requested_subdomain(state, requested, user_id, can_request_subdomain).await?;
+        Ok(requested.clone())
+    } else if state.config.stable_subdomains {
+        // Same user, same name across reconnects - unless someone else holds
+        // it. The user's own route is taken over when the name is claimed: it
+        // may be a dropped connection that hasn't timed out yet.
+        let candidates = (0..STABLE_SUBDOMAIN_ATTEMPTS).map(|attempt| {
+            generate_stable_subdomain(&state.subdomain_names, user_id, &state.config.subdomain_salt, attempt)
+        });
+        let subdomain = first_available(candidates, |candidate| async move {
+            subdomain_taken(state, &candidate, user_id).await
+        })
+        .await;
+        match subdomain {
+            Some(subdomain) => Ok(subdomain),
+            None => assign_random_subdomain(state).await,
         }
+    } else {
+        assign_random_subdomain(state).await
+    }
+}
 
-        if let Ok(Some(route)) = state.route_manager.get_route(requested).await {
-            if route.user_id != user_id {
-                return Err("Subdomain is in use by another user".to_string());
+/// Claim the route for `subdomain`, so that of two clients racing for one
+/// name exactly one gets it. A name the client asked for is then refused;
+/// a generated one is swapped for a fresh name from `fresh_name`, a few times
+/// at most. Without Redis the name is kept: the tunnel is served from this
+/// node alone, and the heartbeat registers the route once Redis is back.
+async fn claim_subdomain<R, F, Fut>(
+    registry: &R,
+    mut subdomain: String,
+    requested: bool,
+    route: &RouteInfo,
+    mut fresh_name: F,
+) -> Result<String, String>
+where
+    R: TunnelRegistry,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<String, String>>,
+{
+    for _ in 0..RANDOM_SUBDOMAIN_ATTEMPTS {
+        match registry.claim_route(&subdomain, route).await {
+            Ok(true) => return Ok(subdomain),
+            Ok(false) if requested => return Err("Subdomain is in use by another user".to_string()),
+            Ok(false) => {
+                tracing::debug!("Lost {} to another client, picking another", subdomain);
+                subdomain = fresh_name().await?;
+            }
+            Err(e) => {
+                tracing::warn!("Failed to register route for {}, serving it from this node only: {}", subdomain, e);
+                return Ok(subdomain);
             }
         }
+    }
+    tracing::error!("No subdomain claimed after {} attempts", RANDOM_SUBDOMAIN_ATTEMPTS);
+    Err("Failed to assign a subdomain".to_string())
+}
 
-        if let Ok(Some(domain)) = queries::check_subdomain_owner(&state.db, requested).await {
-            if domain.user_id.to_string() != user_id {
-                return Err("Subdomain is reserved by another user".to_string());
-            }
+/// Pick a random subdomain nobody is using or has reserved
+async fn assign_random_subdomain(state: &AppState) -> Result<String, String> {
+    let candidates = (0..RANDOM_SUBDOMAIN_ATTEMPTS).map(|_| state.subdomain_names.generate());
+    first_available(candidates, |candidate| async move {
+        // Unlike a stable name, a random one is fresh: any holder is a collision
+        matches!(state.route_manager.get_route(&candidate).await, Ok(Some(_)))
+            || matches!(queries::check_subdomain_owner(&state.db, &candidate).await, Ok(Some(_)))
+    })
+    .await
+    .ok_or_else(|| {
+        tracing::error!("No free random subdomain after {} attempts", RANDOM_SUBDOMAIN_ATTEMPTS);
+        "Failed to assign a subdomain".to_string()
+    })
+}
+
+/// Check a subdomain the user asked for, with the reason it's refused if it is
+async fn validate_requested_subdomain(
+    state: &AppState,
+    requested: &str,
+    user_id: &str,
+    can_request_subdomain: bool,
+) -> Result<(), String> {
+    check_subdomain_request(&state.blocklist, requested, user_id, can_request_subdomain)?;
+    subdomain_holders(state, requested).await.check(user_id)
+}
+
+/// Checks on a requested subdomain that need no lookups
+fn check_subdomain_request(
+    blocklist: &Blocklist,
+    requested: &str,
+    user_id: &str,
+    can_request_subdomain: bool,
+) -> Result<(), String> {
+    if !can_request_subdomain {
+        return Err("Custom subdomains require a paid plan".to_string());
+    }
+
+    match blocklist.check(requested) {
+        SubdomainCheck::Blocked(reason) => {
+            tracing::warn!(
+                "Blocked subdomain request '{}' from user {}: {:?}",
+                requested,
+                user_id,
+                reason
+            );
+            Err(reason.message())
         }
+        SubdomainCheck::Allowed => Ok(()),
+    }
+}
 
-        Ok(requested.clone())
-    } else {
-        let subdomain = generate_random_subdomain();
-        Ok(subdomain)
+/// Users currently holding a subdomain
+#[derive(Debug, Default)]
+struct SubdomainHolders {
+    /// Owner of the live route, if one is registered
+    routed_to: Option<String>,
+    /// Owner of the reservation in the database
+    reserved_by: Option<String>,
+}
+
+impl SubdomainHolders {
+    fn check(&self, user_id: &str) -> Result<(), String> {
+        if self.routed_to.as_deref().is_some_and(|owner| owner != user_id) {
+            return Err("Subdomain is in use by another user".to_string());
+        }
+        if self.reserved_by.as_deref().is_some_and(|owner| owner != user_id) {
+            return Err("Subdomain is reserved by another user".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Whether the subdomain is reserved in the database by this user
+async fn owns_reserved_subdomain(state: &AppState, subdomain: &str, user_id: &str) -> bool {
+    matches!(
+        queries::check_subdomain_owner(&state.db, subdomain).await,
+        Ok(Some(domain)) if domain.user_id.to_string() == user_id
+    )
+}
+
+/// Look up who routes to and who reserved a subdomain
+async fn subdomain_holders(state: &AppState, subdomain: &str) -> SubdomainHolders {
+    SubdomainHolders {
+        routed_to: match state.route_manager.get_route(subdomain).await {
+            Ok(Some(route)) => Some(route.user_id),
+            _ => None,
+        },
+        reserved_by: match queries::check_subdomain_owner(&state.db, subdomain).await {
+            Ok(Some(domain)) => Some(domain.user_id.to_string()),
+            _ => None,
+        },
     }
 }
 
-/// Generate a random subdomain
-fn generate_random_subdomain() -> String {
-    let adjectives = [
-        "quick", "lazy", "happy", "sad", "bright", "dark", "cool", "warm", "fast", "slow",
-        "red", "blue", "green", "bold", "calm", "wild", "soft", "loud", "tiny", "huge",
-    ];
-    let nouns = [
-        "fox", "dog", "cat", "bird", "fish", "bear", "wolf", "deer", "hawk", "owl",
-        "tree", "lake", "hill", "rock", "wave", "star", "moon", "sun", "cloud", "rain",
-    ];
+/// Whether a subdomain is routed to, or reserved by, a different user
+async fn subdomain_taken(state: &AppState, subdomain: &str, user_id: &str) -> bool {
+    subdomain_holders(state, subdomain).await.check(user_id).is_err()
+}
 
-    let mut rng = rand::thread_rng();
-    let adj = adjectives[rng.gen_range(0..adjectives.len())];
-    let noun = nouns[rng.gen_range(0..nouns.len())];
-    let num: u16 = rng.gen_range(100..999);
+/// Return the first candidate that isn't taken
+async fn first_available<F, Fut>(
+    candidates: impl IntoIterator<Item = String>,
+    mut is_taken: F,
+) -> Option<String>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    for candidate in candidates {
+        if !is_taken(candidate.clone()).await {
+            return Some(candidate);
+        }
+    }
+    None
+}
 
-    format!("{}-{}-{}", adj, noun, num)
+/// Stable names tried before falling back to a random one
+const STABLE_SUBDOMAIN_ATTEMPTS: u32 = 3;
+
+/// Random names tried before giving up on the connection
+const RANDOM_SUBDOMAIN_ATTEMPTS: u32 = 5;
+
+/// Derive a subdomain from the user id, in the configured shape. Each `attempt`
+/// gives a different, equally stable name to try when an earlier one is taken.
+fn generate_stable_subdomain(names: &SubdomainNames, user_id: &str, salt: &str, attempt: u32) -> String {
+    let digest = Sha256::new()
+        .chain_update(salt.as_bytes())
+        .chain_update(b":")
+        .chain_update(user_id.as_bytes())
+        .chain_update(attempt.to_be_bytes())
+        .finalize();
+    names.derive(digest.into())
 }
 
 fn usage_ttl_secs(is_paid: bool, plan_expires_at: Option<DateTime<Utc>>) -> i64 {
@@ -705,3 +1652,799 @@ fn usage_ttl_secs(is_paid: bool, plan_expires_at: Option<DateTime<Utc>>) -> i64
 
     FREE_USAGE_TTL_SECS
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Registry that tracks Redis keys in memory
+    #[derive(Default)]
+    struct FakeRegistry {
+        /// Keys written, with the route stored under route keys
+        keys: std::sync::Mutex<HashMap<String, Option<RouteInfo>>>,
+    }
+
+    impl FakeRegistry {
+        fn insert(&self, key: String) {
+            self.keys.lock().unwrap().insert(key, None);
+        }
+
+        fn insert_route(&self, route: RouteInfo) {
+            self.keys.lock().unwrap().insert(route_key(), Some(route));
+        }
+
+        fn route(&self) -> Option<RouteInfo> {
+            self.keys.lock().unwrap().get(&route_key()).cloned().flatten()
+        }
+
+        fn keys(&self) -> HashSet<String> {
+            self.keys.lock().unwrap().keys().cloned().collect()
+        }
+    }
+
+    impl TunnelRegistry for FakeRegistry {
+        async fn claim_route(&self, subdomain: &str, route: &RouteInfo) -> anyhow::Result<bool> {
+            // Let a racing claim run between the call and the write
+            tokio::task::yield_now().await;
+            let mut keys = self.keys.lock().unwrap();
+            let key = format!("{}{}", constants::ROUTE_PREFIX, subdomain);
+            // Like the script: a free name or the same user's route is taken
+            let free = match keys.get(&key) {
+                None => true,
+                Some(held) => held.as_ref().is_some_and(|held| held.user_id == route.user_id),
+            };
+            if free {
+                keys.insert(key, Some(route.clone()));
+            }
+            Ok(free)
+        }
+
+        async fn release_route(&self, subdomain: &str, connection_id: &str) -> anyhow::Result<()> {
+            let mut keys = self.keys.lock().unwrap();
+            let key = format!("{}{}", constants::ROUTE_PREFIX, subdomain);
+            let ours = keys
+                .get(&key)
+                .is_some_and(|held| held.as_ref().is_some_and(|held| held.connection_id == connection_id));
+            if ours {
+                keys.remove(&key);
+            }
+            Ok(())
+        }
+
+        async fn unregister_user_tunnel(&self, user_id: &str, subdomain: &str) -> anyhow::Result<()> {
+            self.keys.lock().unwrap().remove(&format!(
+                "{}{}:{}",
+                constants::USER_TUNNELS_PREFIX,
+                user_id,
+                subdomain
+            ));
+            Ok(())
+        }
+    }
+
+    fn route_key() -> String {
+        format!("{}myapp", constants::ROUTE_PREFIX)
+    }
+
+    fn user_tunnel_key() -> String {
+        format!("{}user-1:myapp", constants::USER_TUNNELS_PREFIX)
+    }
+
+    /// user-1's route for "myapp", written by `connection_id`
+    fn owned_route(connection_id: &str) -> RouteInfo {
+        let mut route = RouteInfo::new("10.0.0.1".to_string(), 8080, "user-1".to_string());
+        route.connection_id = connection_id.to_string();
+        route
+    }
+
+    fn guard(registry: &Arc<FakeRegistry>) -> RegistrationGuard<FakeRegistry> {
+        RegistrationGuard::new(
+            registry.clone(),
+            Arc::new(DashMap::new()),
+            "myapp".to_string(),
+            "user-1".to_string(),
+            "conn-1".to_string(),
+        )
+    }
+
+    /// Wait for the cleanup spawned by `Drop` to run
+    async fn wait_until_empty(registry: &FakeRegistry) {
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !registry.keys().is_empty() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("registrations were not cleaned up");
+    }
+
+    #[tokio::test]
+    async fn test_release_after_full_setup() {
+        let registry = Arc::new(FakeRegistry::default());
+        let mut registration = guard(&registry);
+        let (request_tx, _request_rx) = mpsc::channel(1);
+
+        registry.insert_route(owned_route("conn-1"));
+        registration.route = true;
+        registry.insert(user_tunnel_key());
+        registration.user_tunnel = true;
+        let mut handle = TunnelHandle::new(request_tx, "user-1".to_string());
+        handle.route = Some(owned_route("conn-1"));
+        registration.tunnels.insert("myapp".to_string(), handle);
+        registration.handle = true;
+        let tunnels = registration.tunnels.clone();
+
+        registration.release().await;
+
+        assert!(registry.keys().is_empty());
+        assert!(tunnels.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_early_return_after_route_registration() {
+        let registry = Arc::new(FakeRegistry::default());
+        {
+            let mut registration = guard(&registry);
+            registry.insert_route(owned_route("conn-1"));
+            registration.route = true;
+            // Handler returns before the user tunnel is registered
+        }
+        wait_until_empty(&registry).await;
+    }
+
+    #[tokio::test]
+    async fn test_panic_between_stages() {
+        let registry = Arc::new(FakeRegistry::default());
+        let task_registry = registry.clone();
+        let result = tokio::spawn(async move {
+            let mut registration = guard(&task_registry);
+            task_registry.insert_route(owned_route("conn-1"));
+            registration.route = true;
+            task_registry.insert(user_tunnel_key());
+            registration.user_tunnel = true;
+            panic!("handler crashed before InitAck");
+        })
+        .await;
+
+        assert!(result.is_err());
+        wait_until_empty(&registry).await;
+    }
+
+    #[test]
+    fn test_wire_format_settled_at_init() {
+        let init = ControlPacket::Init(ClientHello {
+            token: "dvaar_test".to_string(),
+            requested_subdomain: None,
+            tunnel_type: dvaar_common::TunnelType::Http,
+            client_version: "0.1.0".to_string(),
+            offline_page: None,
+            wildcard: false,
+            redirect_hops: None,
+            allowed_methods: Vec::new(),
+            denied_paths: Vec::new(),
+            region: None,
+            flow_control: false,
+        });
+        let json = init.encode(WireFormat::Json).unwrap();
+        let msgpack = init.encode(WireFormat::MessagePack).unwrap();
+        let max = constants::MAX_FRAME_BYTES;
+
+        let (packet, wire) = decode_init(&json, max, WireFormat::Json).unwrap();
+        assert!(matches!(packet, ControlPacket::Init(hello) if hello.token == "dvaar_test"));
+        assert_eq!(wire, WireFormat::Json);
+
+        // A JSON client on a server that doesn't allow it is still understood,
+        // and told to fall back by an InitAck in MessagePack
+        let (packet, wire) = decode_init(&json, max, WireFormat::MessagePack).unwrap();
+        assert!(matches!(packet, ControlPacket::Init(_)));
+        assert_eq!(wire, WireFormat::MessagePack);
+
+        let (_, wire) = decode_init(&msgpack, max, WireFormat::Json).unwrap();
+        assert_eq!(wire, WireFormat::MessagePack);
+
+        assert!(decode_init(&json, json.len() - 1, WireFormat::Json).is_err());
+    }
+
+    #[test]
+    fn test_stable_subdomain_is_deterministic() {
+        let words = |list: &[&str]| list.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        let names = SubdomainNames::new(
+            words(&["amber", "azure", "coral"]),
+            words(&["otter", "raven", "lynx"]),
+            "{noun}-{adjective}-{suffix}",
+        )
+        .unwrap();
+        let first = generate_stable_subdomain(&names, "user-1", "salt", 0);
+        assert_eq!(first, generate_stable_subdomain(&names, "user-1", "salt", 0));
+        assert_ne!(first, generate_stable_subdomain(&names, "user-1", "other-salt", 0));
+
+        // Built from the configured wordlists and template
+        let parts: Vec<&str> = first.split('-').collect();
+        assert_eq!(parts.len(), 3);
+        assert!(["otter", "raven", "lynx"].contains(&parts[0]), "{}", first);
+        assert!(["amber", "azure", "coral"].contains(&parts[1]), "{}", first);
+        assert_eq!(parts[2].len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_collision_retries_next_candidate() {
+        let candidates: Vec<String> = (0..STABLE_SUBDOMAIN_ATTEMPTS)
+            .map(|attempt| generate_stable_subdomain(&SubdomainNames::default(), "user-1", "", attempt))
+            .collect();
+        let taken = candidates[0].clone();
+
+        let picked = first_available(candidates.clone(), |c| {
+            let is_taken = c == taken;
+            async move { is_taken }
+        })
+        .await;
+        assert_eq!(picked, Some(candidates[1].clone()));
+
+        // Every candidate taken: caller falls back to a random name
+        assert_eq!(first_available(candidates, |_| async { true }).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_keeps_stable_subdomain_while_old_route_lives() {
+        let registry = FakeRegistry::default();
+        let route = |user: &str| RouteInfo::new("10.0.0.1".to_string(), 8080, user.to_string());
+        let candidates: Vec<String> = (0..STABLE_SUBDOMAIN_ATTEMPTS)
+            .map(|attempt| generate_stable_subdomain(&SubdomainNames::default(), "user-1", "", attempt))
+            .collect();
+        let name = candidates[0].clone();
+        let key = format!("{}{}", constants::ROUTE_PREFIX, name);
+        let held_by = |registry: &FakeRegistry| registry.keys.lock().unwrap()[&key].clone().unwrap();
+
+        // The dropped connection's route hasn't expired when the client is back
+        let dropped = route("user-1");
+        assert!(registry.claim_route(&name, &dropped).await.unwrap());
+        let holders = SubdomainHolders {
+            routed_to: Some(held_by(&registry).user_id),
+            reserved_by: None,
+        };
+        let picked = first_available(candidates, |_| {
+            let taken = holders.check("user-1").is_err();
+            async move { taken }
+        })
+        .await;
+        assert_eq!(picked.as_ref(), Some(&name));
+
+        let reconnected = route("user-1");
+        let claimed = claim_subdomain(&registry, name.clone(), false, &reconnected, || async {
+            Ok("other".to_string())
+        })
+        .await;
+        assert_eq!(claimed, Ok(name.clone()));
+        assert_eq!(held_by(&registry).connection_id, reconnected.connection_id);
+
+        // The old connection's cleanup leaves the new route alone
+        registry.release_route(&name, &dropped.connection_id).await.unwrap();
+        assert_eq!(held_by(&registry).connection_id, reconnected.connection_id);
+
+        // Another user's live route still moves a user on to their next name
+        assert!(holders.check("user-2").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_random_collision_retries_until_free() {
+        let names = SubdomainNames::default();
+        let taken: HashSet<String> = (0..50).map(|_| names.generate()).collect();
+        let mut checked = Vec::new();
+
+        // Pretend the first few draws collide in Redis
+        let picked = first_available(
+            (0..RANDOM_SUBDOMAIN_ATTEMPTS).map(|attempt| {
+                if attempt < 3 {
+                    taken.iter().nth(attempt as usize).unwrap().clone()
+                } else {
+                    names.generate()
+                }
+            }),
+            |candidate| {
+                checked.push(candidate.clone());
+                let is_taken = taken.contains(&candidate);
+                async move { is_taken }
+            },
+        )
+        .await
+        .unwrap();
+        assert!(!taken.contains(&picked));
+        assert!(checked.len() >= 4, "{:?}", checked);
+        assert_eq!(checked.last(), Some(&picked));
+    }
+
+    #[tokio::test]
+    async fn test_unmarked_stages_are_left_alone() {
+        let registry = Arc::new(FakeRegistry::default());
+        // Another connection for the same user already holds these
+        registry.insert(route_key());
+        registry.insert(user_tunnel_key());
+
+        guard(&registry).release().await;
+
+        assert_eq!(registry.keys().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_only_one_racer_claims_a_name() {
+        let registry = FakeRegistry::default();
+        let route = |user: &str| RouteInfo::new("10.0.0.1".to_string(), 8080, user.to_string());
+        let (first, second) = (route("user-1"), route("user-2"));
+        let fresh = |name: &'static str| move || async move { Ok(name.to_string()) };
+
+        // Both were told "myapp" was free; one keeps it, the other moves on
+        let (a, b) = tokio::join!(
+            claim_subdomain(&registry, "myapp".to_string(), false, &first, fresh("other-a")),
+            claim_subdomain(&registry, "myapp".to_string(), false, &second, fresh("other-b")),
+        );
+        let mut won = vec![a.unwrap(), b.unwrap()];
+        won.sort();
+        assert!(won == ["myapp", "other-b"] || won == ["myapp", "other-a"], "{:?}", won);
+        assert!(registry.keys().contains(&route_key()));
+
+        // A name the client asked for is refused instead
+        let (a, b) = tokio::join!(
+            claim_subdomain(&registry, "api".to_string(), true, &first, fresh("unused")),
+            claim_subdomain(&registry, "api".to_string(), true, &second, fresh("unused")),
+        );
+        let results = [a, b];
+        assert_eq!(results.iter().filter(|result| result.as_deref() == Ok("api")).count(), 1);
+        assert!(results.contains(&Err("Subdomain is in use by another user".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_same_user_reconnect_race_keeps_the_survivor() {
+        let registry = Arc::new(FakeRegistry::default());
+        let tunnels = Arc::new(DashMap::new());
+        let (first, second) = (owned_route("conn-1"), owned_route("conn-2"));
+        let fresh = || async { Ok("unused".to_string()) };
+
+        // Two connections of the same user both get the name; the last write wins
+        let (a, b) = tokio::join!(
+            claim_subdomain(&*registry, "myapp".to_string(), true, &first, fresh),
+            claim_subdomain(&*registry, "myapp".to_string(), true, &second, fresh),
+        );
+        assert_eq!((a.as_deref(), b.as_deref()), (Ok("myapp"), Ok("myapp")));
+        let survivor = registry.route().expect("route was not stored");
+        assert_eq!(registry.keys(), HashSet::from([route_key()]));
+
+        let (request_tx, _request_rx) = mpsc::channel(1);
+        let mut handle = TunnelHandle::new(request_tx, "user-1".to_string());
+        handle.route = Some(survivor.clone());
+        tunnels.insert("myapp".to_string(), handle);
+
+        let registration = |connection_id: &str| {
+            let mut registration = RegistrationGuard::new(
+                registry.clone(),
+                tunnels.clone(),
+                "myapp".to_string(),
+                "user-1".to_string(),
+                connection_id.to_string(),
+            );
+            registration.route = true;
+            registration.handle = true;
+            registration
+        };
+        let loser = if survivor.connection_id == "conn-1" { "conn-2" } else { "conn-1" };
+
+        // The connection that was taken over leaves the survivor's route and handle
+        registration(loser).release().await;
+        assert_eq!(registry.route().map(|route| route.connection_id), Some(survivor.connection_id.clone()));
+        assert!(tunnels.contains_key("myapp"));
+
+        registration(&survivor.connection_id).release().await;
+        assert!(registry.keys().is_empty());
+        assert!(tunnels.is_empty());
+    }
+
+    /// Authenticator backed by a fixed token table, or failing outright
+    struct InMemoryAuthenticator {
+        users: HashMap<String, AuthedUser>,
+        fail: bool,
+    }
+
+    impl Authenticator for InMemoryAuthenticator {
+        fn authenticate<'a>(
+            &'a self,
+            token: &'a str,
+        ) -> futures_util::future::BoxFuture<'a, anyhow::Result<Option<AuthedUser>>> {
+            Box::pin(async move {
+                if self.fail {
+                    anyhow::bail!("backend unavailable");
+                }
+                Ok(self.users.get(token).cloned())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_authenticator() {
+        let user = AuthedUser {
+            id: uuid::Uuid::new_v4(),
+            email: "dev@example.com".to_string(),
+            plan: "hobby".to_string(),
+            plan_expires_at: None,
+        };
+        let mut authenticator = InMemoryAuthenticator {
+            users: HashMap::from([("secret-token".to_string(), user.clone())]),
+            fail: false,
+        };
+
+        let authed = authenticate_client(&authenticator, "secret-token").await.unwrap();
+        assert_eq!(authed.id, user.id);
+        assert!(authed.is_paid());
+
+        assert_eq!(
+            authenticate_client(&authenticator, "wrong-token").await.unwrap_err(),
+            "Invalid token"
+        );
+
+        authenticator.fail = true;
+        assert_eq!(
+            authenticate_client(&authenticator, "secret-token").await.unwrap_err(),
+            "Authentication failed"
+        );
+    }
+
+    fn node(id: &str, region: &str, tunnel_count: u32, public_host: Option<&str>) -> NodeInfo {
+        NodeInfo {
+            node_id: id.to_string(),
+            ip: id.to_string(),
+            port: 8080,
+            region: Some(region.to_string()),
+            tunnel_count,
+            max_tunnels: 10,
+            public_host: public_host.map(|h| h.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_redirect_prefers_region_then_load() {
+        let nodes = vec![
+            node("eu", "DE", 3, Some("eu.dvaar.io")),
+            node("us-1", "US", 8, Some("us-1.dvaar.io")),
+            node("us-2", "US", 2, Some("us-2.dvaar.io")),
+            node("us-3", "US", 0, None),
+        ];
+
+        // Already on a node in the client's region
+        assert_eq!(pick_redirect(Some("de"), "eu", &nodes, 0), None);
+        // Least loaded node in the region with a public host
+        assert_eq!(pick_redirect(Some("US"), "eu", &nodes, 0).as_deref(), Some("us-2.dvaar.io"));
+        // No node in the client's region, and this one has room
+        assert_eq!(pick_redirect(Some("JP"), "eu", &nodes, 0), None);
+        // Country unknown
+        assert_eq!(pick_redirect(None, "eu", &nodes, 0), None);
+    }
+
+    #[test]
+    fn test_region_preference() {
+        let nodes = vec![
+            node("eu-1", "EU", 3, Some("eu-1.dvaar.io")),
+            node("us-1", "US", 8, Some("us-1.dvaar.io")),
+            node("us-2", "US", 2, Some("us-2.dvaar.io")),
+        ];
+
+        // This node is in the region
+        assert_eq!(pick_region_node("eu", "eu-1", Some("EU"), &nodes, Some(0)), Ok(None));
+        // Least loaded node in the region
+        assert_eq!(
+            pick_region_node("us", "eu-1", Some("EU"), &nodes, Some(0)),
+            Ok(Some("us-2.dvaar.io".to_string()))
+        );
+        // A full node hands over to another in the region, or keeps the client if it's the only one
+        let full = vec![
+            node("us-1", "US", 10, Some("us-1.dvaar.io")),
+            node("us-2", "US", 2, Some("us-2.dvaar.io")),
+        ];
+        assert_eq!(
+            pick_region_node("us", "us-1", Some("US"), &full, Some(0)),
+            Ok(Some("us-2.dvaar.io".to_string()))
+        );
+        assert_eq!(pick_region_node("us", "us-1", Some("US"), &full[..1], Some(0)), Ok(None));
+    }
+
+    #[test]
+    fn test_region_without_a_node_is_refused() {
+        let nodes = vec![
+            node("eu-1", "EU", 3, Some("eu-1.dvaar.io")),
+            node("us-1", "US", 10, Some("us-1.dvaar.io")),
+            node("ap-1", "AP", 0, None),
+        ];
+
+        let error = pick_region_node("jp", "eu-1", Some("EU"), &nodes, Some(0)).unwrap_err();
+        assert_eq!(error, "No node is available in region jp (regions: ap, eu, us)");
+        // Full, or without a public host to redirect to
+        assert!(pick_region_node("us", "eu-1", Some("EU"), &nodes, Some(0)).is_err());
+        assert!(pick_region_node("ap", "eu-1", Some("EU"), &nodes, Some(0)).is_err());
+        // Redirects used up, or not supported by the client
+        let open_us = [node("us-2", "US", 0, Some("us-2.dvaar.io"))];
+        let hops = Some(constants::MAX_REDIRECT_HOPS);
+        assert!(pick_region_node("us", "eu-1", Some("EU"), &open_us, hops).is_err());
+        let error = pick_region_node("us", "eu-1", Some("EU"), &open_us, None).unwrap_err();
+        assert!(error.contains("can't follow redirects"), "{}", error);
+        // Registry unavailable, but this node is in the region
+        assert_eq!(pick_region_node("eu", "eu-1", Some("eu"), &[], Some(0)), Ok(None));
+        assert_eq!(
+            pick_region_node("us", "eu-1", Some("EU"), &[], Some(0)),
+            Err("No node is available in region us (regions: eu)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_full_node_redirects_anywhere_with_room() {
+        let nodes = vec![
+            node("eu", "DE", 10, Some("eu.dvaar.io")),
+            node("us-1", "US", 9, Some("us-1.dvaar.io")),
+            node("us-2", "US", 4, Some("us-2.dvaar.io")),
+        ];
+        assert_eq!(pick_redirect(Some("DE"), "eu", &nodes, 0).as_deref(), Some("us-2.dvaar.io"));
+        assert_eq!(pick_redirect(None, "eu", &nodes, 0).as_deref(), Some("us-2.dvaar.io"));
+    }
+
+    #[test]
+    fn test_redirected_client_is_not_redirected_again() {
+        // Two nodes that each think the other is the better fit
+        let nodes = vec![
+            node("a", "US", 10, Some("a.dvaar.io")),
+            node("b", "US", 10, Some("b.dvaar.io")),
+        ];
+        let nodes_seen_by_b = vec![
+            node("a", "US", 0, Some("a.dvaar.io")),
+            node("b", "US", 10, Some("b.dvaar.io")),
+        ];
+        assert_eq!(pick_redirect(Some("US"), "a", &nodes, 0), None);
+        assert_eq!(pick_redirect(Some("US"), "b", &nodes_seen_by_b, 0).as_deref(), Some("a.dvaar.io"));
+        assert_eq!(pick_redirect(Some("US"), "b", &nodes_seen_by_b, constants::MAX_REDIRECT_HOPS), None);
+    }
+
+    #[test]
+    fn test_subdomain_check_blocked_taken_available() {
+        let blocklist = Blocklist::new(&["acme*"]).unwrap();
+
+        assert!(check_subdomain_request(&blocklist, "myapp", "u1", false)
+            .unwrap_err()
+            .contains("paid plan"));
+        assert!(check_subdomain_request(&blocklist, "acmecorp", "u1", true).is_err());
+        assert!(check_subdomain_request(&blocklist, "myapp", "u1", true).is_ok());
+
+        let routed = SubdomainHolders { routed_to: Some("u2".to_string()), reserved_by: None };
+        assert_eq!(routed.check("u1").unwrap_err(), "Subdomain is in use by another user");
+        let reserved = SubdomainHolders { routed_to: None, reserved_by: Some("u2".to_string()) };
+        assert_eq!(reserved.check("u1").unwrap_err(), "Subdomain is reserved by another user");
+
+        // Free, or already held by the caller
+        let free = SubdomainHolders { routed_to: None, reserved_by: None };
+        assert!(free.check("u1").is_ok());
+        let own = SubdomainHolders { routed_to: Some("u1".to_string()), reserved_by: Some("u1".to_string()) };
+        assert!(own.check("u1").is_ok());
+    }
+
+    /// Sink that fails writes with the queued errors, then accepts them
+    #[derive(Default)]
+    struct FlakySink {
+        failures: std::collections::VecDeque<std::io::ErrorKind>,
+        attempts: usize,
+        sent: Vec<Message>,
+    }
+
+    impl futures_util::Sink<Message> for FlakySink {
+        type Error = axum::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), axum::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), axum::Error> {
+            self.attempts += 1;
+            match self.failures.pop_front() {
+                Some(kind) => Err(axum::Error::new(std::io::Error::from(kind))),
+                None => {
+                    self.sent.push(item);
+                    Ok(())
+                }
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), axum::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), axum::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn data_message(stream_id: &str, byte: u8) -> Message {
+        let packet = ControlPacket::Data { stream_id: stream_id.to_string(), data: vec![byte] };
+        Message::Binary(packet.encode(WireFormat::MessagePack).unwrap().into())
+    }
+
+    fn open_stream(streams: &mut HashMap<String, StreamState>, stream_id: &str) -> mpsc::Receiver<StreamChunk> {
+        let (response_tx, response_rx) = mpsc::channel(8);
+        streams.insert(
+            stream_id.to_string(),
+            StreamState {
+                response_tx,
+                phase: StreamPhase::AwaitingResponse,
+            },
+        );
+        response_rx
+    }
+
+    fn response(stream_id: &str, status: u16) -> ControlPacket {
+        ControlPacket::HttpResponse(dvaar_common::HttpResponsePacket {
+            stream_id: stream_id.to_string(),
+            status,
+            headers: Vec::new(),
+        })
+    }
+
+    fn data(stream_id: &str, data: &[u8]) -> ControlPacket {
+        ControlPacket::Data {
+            stream_id: stream_id.to_string(),
+            data: data.to_vec(),
+        }
+    }
+
+    fn end(stream_id: &str) -> ControlPacket {
+        ControlPacket::End {
+            stream_id: stream_id.to_string(),
+        }
+    }
+
+    fn frame(stream_id: &str) -> ControlPacket {
+        ControlPacket::WebSocketFrame {
+            stream_id: stream_id.to_string(),
+            data: b"hi".to_vec(),
+            is_binary: false,
+        }
+    }
+
+    #[test]
+    fn test_forged_stream_ids_are_ignored() {
+        let mut streams = HashMap::new();
+        let _rx = open_stream(&mut streams, "legit");
+
+        // Nothing reaches a stream the tunnel never opened
+        for packet in [
+            response("forged", 200),
+            data("forged", b"injected"),
+            end("forged"),
+            frame("forged"),
+        ] {
+            assert!(route_client_packet(&mut streams, packet).is_none());
+        }
+
+        // Body data can't get ahead of the headers
+        assert!(route_client_packet(&mut streams, data("legit", b"injected")).is_none());
+        assert!(route_client_packet(&mut streams, end("legit")).is_none());
+        assert_eq!(streams["legit"].phase, StreamPhase::AwaitingResponse);
+
+        // The legitimate response still goes through intact
+        let (id, _, chunk) = route_client_packet(&mut streams, response("legit", 200)).unwrap();
+        assert_eq!(id, "legit");
+        assert!(matches!(chunk, StreamChunk::Headers(h) if h.status == 200));
+        assert!(route_client_packet(&mut streams, response("legit", 500)).is_none());
+        let (_, _, chunk) = route_client_packet(&mut streams, data("legit", b"body")).unwrap();
+        assert!(matches!(chunk, StreamChunk::Data(d) if d == b"body"));
+        assert!(route_client_packet(&mut streams, frame("legit")).is_none());
+        let (_, _, chunk) = route_client_packet(&mut streams, end("legit")).unwrap();
+        assert!(matches!(chunk, StreamChunk::End));
+        assert!(streams.is_empty());
+
+        // Once finished, the stream takes nothing more
+        assert!(route_client_packet(&mut streams, data("legit", b"late")).is_none());
+    }
+
+    #[test]
+    fn test_websocket_frames_need_an_upgraded_stream() {
+        let mut streams = HashMap::new();
+        let _rx = open_stream(&mut streams, "ws");
+
+        assert!(route_client_packet(&mut streams, frame("ws")).is_none());
+        route_client_packet(&mut streams, response("ws", 101)).unwrap();
+        assert_eq!(streams["ws"].phase, StreamPhase::WebSocket);
+
+        assert!(matches!(route_client_packet(&mut streams, frame("ws")), Some((_, _, StreamChunk::WebSocketFrame { .. }))));
+        // An upgraded stream takes no HTTP body, and only a close ends it
+        assert!(route_client_packet(&mut streams, data("ws", b"x")).is_none());
+        assert!(route_client_packet(&mut streams, end("ws")).is_none());
+        let close = ControlPacket::WebSocketClose { stream_id: "ws".to_string(), code: Some(1000), reason: None };
+        assert!(route_client_packet(&mut streams, close).is_some());
+        assert!(streams.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transient_send_pause_keeps_other_streams_flowing() {
+        let mut sink = FlakySink {
+            failures: [std::io::ErrorKind::WouldBlock, std::io::ErrorKind::WouldBlock].into(),
+            ..Default::default()
+        };
+
+        let messages = [data_message("a", 1), data_message("b", 1), data_message("a", 2)];
+        for message in &messages {
+            send_with_retry(&mut sink, message.clone()).await.unwrap();
+        }
+        assert_eq!(sink.sent, messages);
+        assert_eq!(sink.attempts, 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_send_gives_up_on_connection_errors() {
+        // A dead connection fails on the first attempt
+        let mut sink = FlakySink {
+            failures: [std::io::ErrorKind::ConnectionReset].into(),
+            ..Default::default()
+        };
+        assert!(send_with_retry(&mut sink, data_message("a", 1)).await.is_err());
+        assert_eq!(sink.attempts, 1);
+
+        // One that never drains fails once the retries run out
+        let mut sink = FlakySink {
+            failures: vec![std::io::ErrorKind::WouldBlock; 10].into(),
+            ..Default::default()
+        };
+        assert!(send_with_retry(&mut sink, data_message("a", 1)).await.is_err());
+        assert_eq!(sink.attempts, SEND_RETRY_ATTEMPTS as usize + 1);
+        assert!(sink.sent.is_empty());
+    }
+
+    #[test]
+    fn test_large_response_is_metered_as_egress() {
+        let wire = WireFormat::MessagePack;
+        let meter = UsageMeter::default();
+        let stream_id = dvaar_common::new_stream_id();
+
+        // A bodiless GET goes down the tunnel...
+        let request = ControlPacket::HttpRequest(dvaar_common::HttpRequestPacket {
+            stream_id: stream_id.clone(),
+            method: "GET".to_string(),
+            uri: "/video.mp4".to_string(),
+            headers: vec![("Host".to_string(), "app.dvaar.app".to_string())],
+        });
+        meter.add_ingress(request.encode(wire).unwrap().len());
+        assert_eq!(meter.take(USAGE_FLUSH_BYTES), None);
+
+        // ...and a 2 MB response comes back up
+        for _ in 0..4 {
+            let chunk = ControlPacket::Data {
+                stream_id: stream_id.clone(),
+                data: vec![0; 512 * 1024],
+            };
+            meter.add_egress(chunk.encode(wire).unwrap().len());
+        }
+
+        let usage = meter.take(USAGE_FLUSH_BYTES).unwrap();
+        assert!(usage.egress >= 2 * 1024 * 1024);
+        assert!(usage.egress > 1000 * usage.ingress, "{:?}", usage);
+        assert_eq!(usage.total(), usage.ingress + usage.egress);
+        assert_eq!(meter.take(0), None);
+    }
+
+    #[test]
+    fn test_usage_flushes_on_size_or_time() {
+        let meter = UsageMeter::default();
+        let mut flush = UsageFlush::new(1000, Duration::from_secs(5));
+        let start = flush.last;
+
+        // Size: a burst over the threshold goes at once
+        meter.add_egress(400);
+        assert_eq!(flush.take(&meter, start + Duration::from_secs(1)), None);
+        meter.add_ingress(700);
+        let usage = flush.take(&meter, start + Duration::from_secs(2)).unwrap();
+        assert_eq!((usage.ingress, usage.egress), (700, 400));
+
+        // Time: a trickle goes once the interval has passed since that flush
+        meter.add_egress(10);
+        assert_eq!(flush.take(&meter, start + Duration::from_secs(6)), None);
+        let usage = flush.take(&meter, start + Duration::from_secs(7)).unwrap();
+        assert_eq!(usage.total(), 10);
+
+        // Nothing pending means no call, however long it has been
+        assert_eq!(flush.take(&meter, start + Duration::from_secs(60)), None);
+    }
+}
@@ -0,0 +1,73 @@
+//! Pluggable authentication for tunnel clients
+//!
+//! `handle_socket` only needs to turn a token into a user with a plan. The
+//! default backend looks the token up in Postgres; self-hosters can swap in
+//! their own (static tokens, env vars, an external IdP) by implementing
+//! [`Authenticator`] and putting it in `AppState`.
+
+use crate::db::{queries, User};
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// The user a token belongs to, as far as tunnel setup is concerned
+#[derive(Debug, Clone)]
+pub struct AuthedUser {
+    pub id: Uuid,
+    pub email: String,
+    pub plan: String,
+    pub plan_expires_at: Option<DateTime<Utc>>,
+}
+
+impl AuthedUser {
+    /// Whether the plan in force is a paid one
+    pub fn is_paid(&self) -> bool {
+        matches!(self.effective_plan(), "hobby" | "pro")
+    }
+
+    /// The plan in force now: an expired paid plan counts as free
+    pub fn effective_plan(&self) -> &str {
+        match self.plan_expires_at {
+            Some(expires_at) if expires_at < Utc::now() => "free",
+            _ => self.plan.as_str(),
+        }
+    }
+}
+
+impl From<User> for AuthedUser {
+    fn from(user: User) -> Self {
+        Self {
+            id: user.id,
+            email: user.email,
+            plan: user.plan,
+            plan_expires_at: user.plan_expires_at,
+        }
+    }
+}
+
+/// Resolves a client token to a user
+pub trait Authenticator: Send + Sync {
+    /// `Ok(None)` means the token is unknown; `Err` means the backend failed
+    fn authenticate<'a>(&'a self, token: &'a str) -> BoxFuture<'a, anyhow::Result<Option<AuthedUser>>>;
+}
+
+/// Default backend: API keys stored in Postgres
+pub struct PostgresAuthenticator {
+    db: PgPool,
+}
+
+impl PostgresAuthenticator {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+impl Authenticator for PostgresAuthenticator {
+    fn authenticate<'a>(&'a self, token: &'a str) -> BoxFuture<'a, anyhow::Result<Option<AuthedUser>>> {
+        Box::pin(async move {
+            let user = queries::find_user_by_token(&self.db, token).await?;
+            Ok(user.map(AuthedUser::from))
+        })
+    }
+}
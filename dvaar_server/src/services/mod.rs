@@ -1,6 +1,13 @@
 //! Services module
-//! Currently empty - services are integrated into route handlers
-//! This module can be expanded for:
+//! Most services are integrated into route handlers; this holds the pieces
+//! that are meant to be swappable:
+//! - Authentication backends
+//!
+//! It can be expanded for:
 //! - Background jobs
 //! - Billing sync
 //! - Usage aggregation
+
+pub mod authenticator;
+
+pub use authenticator::{AuthedUser, Authenticator, PostgresAuthenticator};
@@ -0,0 +1,285 @@
+//! Expiring links to a tunnel's inspector
+//!
+//! `dvaar share` asks the API for a link like
+//! `https://myapp.dvaar.app/_dvaar/inspect?token=...` that opens a read-only
+//! view of the tunnel's inspector. The token is `<expiry>.<signature>`: the
+//! unix time it stops working and an HMAC-SHA256 over the subdomain, the
+//! user holding it and that time, keyed with `SHARE_LINK_SECRET`. A link is
+//! checked against whoever holds the subdomain when it is opened, so it dies
+//! with the name passing to someone else. Nothing is stored, so a link can't
+//! be revoked, only left to expire.
+//!
+//! Ingress trades a valid token for a cookie scoped to the share path, then
+//! forwards the requests under it with `INSPECT_SHARE_HEADER` set and the
+//! prefix removed. The client answers those from its inspector instead of
+//! the upstream, and only if they are reads of that tunnel's own requests
+//! and metrics. Nothing under the share path ever reaches the app.
+
+use axum::body::Body;
+use axum::http::{header, HeaderValue, Request, Response, StatusCode, Uri};
+use axum::response::IntoResponse;
+use dvaar_common::constants;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Path on every tunnel's host the shared inspector is served under
+pub const SHARE_PATH: &str = "/_dvaar/inspect";
+
+/// Cookie holding the token once the link has been opened
+const SHARE_COOKIE: &str = "dvaar_inspect";
+
+/// How long a link lasts unless asked otherwise
+pub const DEFAULT_SHARE_TTL_SECS: u64 = 60 * 60;
+
+/// Longest a link may last
+pub const MAX_SHARE_TTL_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ShareLinkError {
+    #[error("malformed share token")]
+    Malformed,
+    #[error("share token signature doesn't match")]
+    BadSignature,
+    #[error("share link has expired")]
+    Expired,
+}
+
+/// Token letting its holder view `subdomain`'s inspector until `expires_at`,
+/// for as long as `owner` holds the subdomain
+pub fn mint(secret: &str, subdomain: &str, owner: &str, expires_at: u64) -> String {
+    let signature = signature(secret, subdomain, owner, expires_at).finalize().into_bytes();
+    format!("{}.{}", expires_at, hex::encode(signature))
+}
+
+/// Check a token for `subdomain`, held by `owner`, as of `now`, returning
+/// when it expires
+pub fn verify(secret: &str, subdomain: &str, owner: &str, token: &str, now: u64) -> Result<u64, ShareLinkError> {
+    let (expires_at, sig) = token.split_once('.').ok_or(ShareLinkError::Malformed)?;
+    let expires_at: u64 = expires_at.parse().map_err(|_| ShareLinkError::Malformed)?;
+    let sig = hex::decode(sig).map_err(|_| ShareLinkError::Malformed)?;
+    signature(secret, subdomain, owner, expires_at)
+        .verify_slice(&sig)
+        .map_err(|_| ShareLinkError::BadSignature)?;
+    if expires_at <= now {
+        return Err(ShareLinkError::Expired);
+    }
+    Ok(expires_at)
+}
+
+fn signature(secret: &str, subdomain: &str, owner: &str, expires_at: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(subdomain.as_bytes());
+    mac.update(b":");
+    mac.update(owner.as_bytes());
+    mac.update(b":");
+    mac.update(expires_at.to_string().as_bytes());
+    mac
+}
+
+/// Whether `path` is under `SHARE_PATH`
+pub fn is_share_path(path: &str) -> bool {
+    path.strip_prefix(SHARE_PATH)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Deal with a request under `SHARE_PATH` before it can reach the app.
+/// `owner` is the user now holding `subdomain`, if anyone does.
+///
+/// A link being opened (`?token=`) is answered here: a redirect that swaps the
+/// token in the URL for a cookie, or a 403. Later requests carrying the cookie
+/// are rewritten to the inspector's own path, marked for the client, and
+/// `None` is returned to forward them. Any other request just loses a marker
+/// a visitor may have sent.
+pub fn handle_share_path(
+    secret: &str,
+    subdomain: &str,
+    owner: Option<&str>,
+    request: &mut Request<Body>,
+    now: u64,
+) -> Option<Response<Body>> {
+    request.headers_mut().remove(constants::INSPECT_SHARE_HEADER);
+    let path = request.uri().path();
+    let inspector_path = match path.strip_prefix(SHARE_PATH) {
+        Some("") => "/",
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => return None,
+    };
+    // Nobody holds the subdomain, so no link for it can be current
+    let Some(owner) = owner else {
+        return Some(denied(ShareLinkError::BadSignature));
+    };
+
+    if let Some(token) = query_param(request.uri(), "token") {
+        return Some(match verify(secret, subdomain, owner, &token, now) {
+            Ok(expires_at) => Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(header::LOCATION, format!("{}/", SHARE_PATH))
+                .header(
+                    header::SET_COOKIE,
+                    format!(
+                        "{}={}; Path={}; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+                        SHARE_COOKIE,
+                        token,
+                        SHARE_PATH,
+                        expires_at - now
+                    ),
+                )
+                .header(header::CACHE_CONTROL, "no-store")
+                .body(Body::empty())
+                .unwrap(),
+            Err(e) => denied(e),
+        });
+    }
+
+    let Some(token) = cookie(request, SHARE_COOKIE) else {
+        return Some(denied(ShareLinkError::Malformed));
+    };
+    if let Err(e) = verify(secret, subdomain, owner, &token, now) {
+        return Some(denied(e));
+    }
+
+    let inspector_uri = match request.uri().query() {
+        Some(query) => format!("{}?{}", inspector_path, query),
+        None => inspector_path.to_string(),
+    };
+    let Ok(inspector_uri) = inspector_uri.parse::<Uri>() else {
+        return Some((StatusCode::BAD_REQUEST, "Bad request").into_response());
+    };
+    *request.uri_mut() = inspector_uri;
+    request
+        .headers_mut()
+        .insert(constants::INSPECT_SHARE_HEADER, HeaderValue::from_static("1"));
+    // The token has done its job; the client has no use for it
+    request.headers_mut().remove(header::COOKIE);
+    None
+}
+
+fn denied(error: ShareLinkError) -> Response<Body> {
+    let message = match error {
+        ShareLinkError::Expired => "This share link has expired",
+        _ => "This share link is not valid",
+    };
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::from(message))
+        .unwrap()
+}
+
+fn query_param(uri: &Uri, name: &str) -> Option<String> {
+    uri.query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+fn cookie(request: &Request<Body>, name: &str) -> Option<String> {
+    request
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test-share-secret";
+    const NOW: u64 = 1_700_000_000;
+    const OWNER: &str = "user-1";
+
+    #[test]
+    fn test_valid_expired_and_tampered_tokens() {
+        let token = mint(SECRET, "myapp", OWNER, NOW + 600);
+        assert_eq!(verify(SECRET, "myapp", OWNER, &token, NOW), Ok(NOW + 600));
+
+        assert_eq!(verify(SECRET, "myapp", OWNER, &token, NOW + 600), Err(ShareLinkError::Expired));
+
+        // A later expiry, another tunnel, another holder or another key all break the signature
+        let (_, sig) = token.split_once('.').unwrap();
+        let extended = format!("{}.{}", NOW + 6000, sig);
+        assert_eq!(verify(SECRET, "myapp", OWNER, &extended, NOW), Err(ShareLinkError::BadSignature));
+        assert_eq!(verify(SECRET, "other", OWNER, &token, NOW), Err(ShareLinkError::BadSignature));
+        assert_eq!(verify(SECRET, "myapp", "user-2", &token, NOW), Err(ShareLinkError::BadSignature));
+        assert_eq!(verify("another-secret", "myapp", OWNER, &token, NOW), Err(ShareLinkError::BadSignature));
+        let mut flipped = token.clone();
+        let last = if flipped.ends_with('0') { "1" } else { "0" };
+        flipped.replace_range(flipped.len() - 1.., last);
+        assert_eq!(verify(SECRET, "myapp", OWNER, &flipped, NOW), Err(ShareLinkError::BadSignature));
+
+        for malformed in ["", "no-dot", "soon.abcd", "123.not-hex"] {
+            assert_eq!(verify(SECRET, "myapp", OWNER, malformed, NOW), Err(ShareLinkError::Malformed), "{}", malformed);
+        }
+    }
+
+    fn request(uri: &str, cookie: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().uri(uri).header(constants::INSPECT_SHARE_HEADER, "1");
+        if let Some(cookie) = cookie {
+            builder = builder.header(header::COOKIE, cookie);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_is_share_path() {
+        assert!(is_share_path("/_dvaar/inspect"));
+        assert!(is_share_path("/_dvaar/inspect/api/tunnels/t1/requests"));
+        assert!(!is_share_path("/_dvaar/inspector"));
+        assert!(!is_share_path("/"));
+    }
+
+    #[test]
+    fn test_share_path_requests() {
+        let token = mint(SECRET, "myapp", OWNER, NOW + 600);
+
+        // Opening the link swaps the token for a cookie
+        let mut opened = request(&format!("/_dvaar/inspect?token={}", token), None);
+        let response = handle_share_path(SECRET, "myapp", Some(OWNER), &mut opened, NOW).unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(response.headers()[header::LOCATION], "/_dvaar/inspect/");
+        let set_cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(set_cookie.starts_with(&format!("dvaar_inspect={};", token)));
+        assert!(set_cookie.contains("Max-Age=600"));
+
+        // With the cookie, requests go to the inspector's own paths
+        let cookie = format!("theme=dark; dvaar_inspect={}", token);
+        let mut api = request("/_dvaar/inspect/api/ws?tunnel=abc", Some(&cookie));
+        assert!(handle_share_path(SECRET, "myapp", Some(OWNER), &mut api, NOW).is_none());
+        assert_eq!(api.uri(), "/api/ws?tunnel=abc");
+        assert_eq!(api.headers()[constants::INSPECT_SHARE_HEADER], "1");
+        assert!(api.headers().get(header::COOKIE).is_none());
+
+        // Without a valid token nothing is forwarded
+        for (uri, cookie) in [
+            ("/_dvaar/inspect/", None),
+            ("/_dvaar/inspect/api/requests", Some(format!("dvaar_inspect={}", mint(SECRET, "other", OWNER, NOW + 600)))),
+            ("/_dvaar/inspect?token=garbage", None),
+        ] {
+            let mut refused = request(uri, cookie.as_deref());
+            let response = handle_share_path(SECRET, "myapp", Some(OWNER), &mut refused, NOW).unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", uri);
+        }
+        // Nor once the subdomain has passed to someone else, or to nobody
+        for owner in [Some("user-2"), None] {
+            let mut reissued = request("/_dvaar/inspect/", Some(&format!("dvaar_inspect={}", token)));
+            let response = handle_share_path(SECRET, "myapp", owner, &mut reissued, NOW).unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{:?}", owner);
+        }
+        let mut expired = request("/_dvaar/inspect/", Some(&format!("dvaar_inspect={}", token)));
+        assert!(handle_share_path(SECRET, "myapp", Some(OWNER), &mut expired, NOW + 601).is_some());
+
+        // The app's own paths pass, minus any marker a visitor forged
+        for uri in ["/", "/_dvaar/inspector", "/api/requests"] {
+            let mut app = request(uri, None);
+            assert!(handle_share_path(SECRET, "myapp", Some(OWNER), &mut app, NOW).is_none());
+            assert_eq!(app.uri(), uri);
+            assert!(app.headers().get(constants::INSPECT_SHARE_HEADER).is_none());
+        }
+    }
+}
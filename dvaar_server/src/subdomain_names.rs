@@ -0,0 +1,277 @@
+//! Random subdomain names
+//!
+//! Tunnels without a requested subdomain get a name like `quick-fox-123`,
+//! rendered from a template and two wordlists. Operators can swap in their
+//! own lists (one word per line) and template; see `.env.example`.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::path::Path;
+
+/// Placeholders a template may use
+const PLACEHOLDERS: [&str; 4] = ["{adjective}", "{noun}", "{number}", "{suffix}"];
+
+pub const DEFAULT_TEMPLATE: &str = "{adjective}-{noun}-{number}";
+
+const DEFAULT_ADJECTIVES: &[&str] = &[
+    "quick", "lazy", "happy", "sad", "bright", "dark", "cool", "warm", "fast", "slow",
+    "red", "blue", "green", "bold", "calm", "wild", "soft", "loud", "tiny", "huge",
+    "brave", "clever", "eager", "fancy", "gentle", "jolly", "kind", "lucky", "merry", "neat",
+    "proud", "quiet", "rapid", "shiny", "sunny", "swift", "tidy", "vivid", "witty", "zesty",
+    "amber", "azure", "coral", "crisp", "dusty", "fuzzy", "golden", "hazy", "icy", "lunar",
+    "misty", "noble", "olive", "plain", "rosy", "rusty", "silent", "solar", "stormy", "violet",
+    "white", "young", "keen", "fresh",
+];
+
+const DEFAULT_NOUNS: &[&str] = &[
+    "fox", "dog", "cat", "bird", "fish", "bear", "wolf", "deer", "hawk", "owl",
+    "tree", "lake", "hill", "rock", "wave", "star", "moon", "sun", "cloud", "rain",
+    "badger", "beaver", "bison", "crane", "eagle", "falcon", "ferret", "gecko", "heron", "koala",
+    "lemur", "lynx", "moose", "otter", "panda", "raven", "robin", "seal", "swan", "tiger",
+    "brook", "canyon", "cliff", "comet", "delta", "dune", "field", "forest", "glade", "grove",
+    "harbor", "island", "meadow", "mesa", "orbit", "peak", "prairie", "reef", "ridge", "river",
+    "spring", "valley", "willow", "breeze",
+];
+
+/// Characters of the `{suffix}` placeholder
+const SUFFIX_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+const SUFFIX_LEN: usize = 4;
+
+/// Error building the generator from config
+#[derive(Debug, thiserror::Error)]
+pub enum SubdomainNamesError {
+    #[error("Failed to read wordlist {path}: {source}")]
+    Read {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("The {0} wordlist is empty")]
+    EmptyList(&'static str),
+
+    #[error("Wordlist entry '{0}' isn't a lowercase DNS label")]
+    InvalidWord(String),
+
+    #[error("Subdomain template '{0}' must use {{adjective}}, {{noun}}, {{number}} or {{suffix}} and nothing else in braces")]
+    InvalidTemplate(String),
+}
+
+/// Generates random subdomains from a template and wordlists
+#[derive(Debug, Clone)]
+pub struct SubdomainNames {
+    adjectives: Vec<String>,
+    nouns: Vec<String>,
+    template: String,
+}
+
+impl Default for SubdomainNames {
+    fn default() -> Self {
+        Self {
+            adjectives: DEFAULT_ADJECTIVES.iter().map(|w| w.to_string()).collect(),
+            nouns: DEFAULT_NOUNS.iter().map(|w| w.to_string()).collect(),
+            template: DEFAULT_TEMPLATE.to_string(),
+        }
+    }
+}
+
+impl SubdomainNames {
+    pub fn new(
+        adjectives: Vec<String>,
+        nouns: Vec<String>,
+        template: &str,
+    ) -> Result<Self, SubdomainNamesError> {
+        check_words(&adjectives, "adjective")?;
+        check_words(&nouns, "noun")?;
+        check_template(template)?;
+        Ok(Self {
+            adjectives,
+            nouns,
+            template: template.to_string(),
+        })
+    }
+
+    /// Load wordlist files (built-in lists where a path is unset) and the template
+    pub fn load(
+        adjectives_file: Option<&str>,
+        nouns_file: Option<&str>,
+        template: &str,
+    ) -> Result<Self, SubdomainNamesError> {
+        let defaults = Self::default();
+        let adjectives = match adjectives_file {
+            Some(path) => read_wordlist(path)?,
+            None => defaults.adjectives,
+        };
+        let nouns = match nouns_file {
+            Some(path) => read_wordlist(path)?,
+            None => defaults.nouns,
+        };
+        Self::new(adjectives, nouns, template)
+    }
+
+    /// Number of distinct names the template can produce
+    pub fn combinations(&self) -> u128 {
+        let mut total: u128 = 1;
+        if self.template.contains("{adjective}") {
+            total *= self.adjectives.len() as u128;
+        }
+        if self.template.contains("{noun}") {
+            total *= self.nouns.len() as u128;
+        }
+        if self.template.contains("{number}") {
+            total *= 899;
+        }
+        if self.template.contains("{suffix}") {
+            total *= (SUFFIX_CHARS.len() as u128).pow(SUFFIX_LEN as u32);
+        }
+        total
+    }
+
+    /// A fresh random name
+    pub fn generate(&self) -> String {
+        self.generate_with(&mut rand::thread_rng())
+    }
+
+    /// The name `seed` always gives with these wordlists and template.
+    /// Changing either renames everything derived this way.
+    pub fn derive(&self, seed: [u8; 32]) -> String {
+        self.generate_with(&mut StdRng::from_seed(seed))
+    }
+
+    fn generate_with(&self, rng: &mut impl Rng) -> String {
+        let adjective = &self.adjectives[rng.gen_range(0..self.adjectives.len())];
+        let noun = &self.nouns[rng.gen_range(0..self.nouns.len())];
+        let number: u16 = rng.gen_range(100..999);
+        let suffix: String = (0..SUFFIX_LEN)
+            .map(|_| SUFFIX_CHARS[rng.gen_range(0..SUFFIX_CHARS.len())] as char)
+            .collect();
+        self.render(adjective, noun, number, &suffix)
+    }
+
+    fn render(&self, adjective: &str, noun: &str, number: u16, suffix: &str) -> String {
+        self.template
+            .replace("{adjective}", adjective)
+            .replace("{noun}", noun)
+            .replace("{number}", &number.to_string())
+            .replace("{suffix}", suffix)
+    }
+}
+
+fn read_wordlist(path: &str) -> Result<Vec<String>, SubdomainNamesError> {
+    let contents = std::fs::read_to_string(Path::new(path)).map_err(|source| SubdomainNamesError::Read {
+        path: path.to_string(),
+        source,
+    })?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+fn check_words(words: &[String], list: &'static str) -> Result<(), SubdomainNamesError> {
+    if words.is_empty() {
+        return Err(SubdomainNamesError::EmptyList(list));
+    }
+    let valid = |word: &str| {
+        !word.is_empty()
+            && word.len() <= 20
+            && word.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+            && !word.starts_with('-')
+            && !word.ends_with('-')
+    };
+    match words.iter().find(|word| !valid(word)) {
+        Some(word) => Err(SubdomainNamesError::InvalidWord(word.clone())),
+        None => Ok(()),
+    }
+}
+
+fn check_template(template: &str) -> Result<(), SubdomainNamesError> {
+    let invalid = || SubdomainNamesError::InvalidTemplate(template.to_string());
+    let mut rest = template;
+    let mut placeholders = 0;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}').ok_or_else(invalid)? + start + 1;
+        if !PLACEHOLDERS.contains(&&rest[start..end]) {
+            return Err(invalid());
+        }
+        placeholders += 1;
+        rest = &rest[end..];
+    }
+    if placeholders == 0 || template.matches('}').count() != placeholders {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(list: &[&str]) -> Vec<String> {
+        list.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn test_template_rendering() {
+        let names = SubdomainNames::new(words(&["quick"]), words(&["fox"]), "{noun}-{adjective}{number}").unwrap();
+        assert_eq!(names.render("quick", "fox", 123, "ab12"), "fox-quick123");
+
+        let names = SubdomainNames::new(words(&["quick"]), words(&["fox"]), "{adjective}-{noun}-{suffix}").unwrap();
+        let name = names.generate();
+        let suffix = name.strip_prefix("quick-fox-").unwrap();
+        assert_eq!(suffix.len(), SUFFIX_LEN);
+        assert!(suffix.bytes().all(|b| SUFFIX_CHARS.contains(&b)));
+        assert_eq!(names.combinations(), 36u128.pow(4));
+        assert_eq!(names.derive([7; 32]), names.derive([7; 32]));
+
+        for template in ["plain", "{adjective}-{colour}", "{noun", "{noun}}"] {
+            assert!(
+                matches!(
+                    SubdomainNames::new(words(&["a"]), words(&["b"]), template),
+                    Err(SubdomainNamesError::InvalidTemplate(_))
+                ),
+                "{}",
+                template
+            );
+        }
+        assert!(matches!(
+            SubdomainNames::new(words(&["Bad Word"]), words(&["b"]), DEFAULT_TEMPLATE),
+            Err(SubdomainNamesError::InvalidWord(_))
+        ));
+        assert!(matches!(
+            SubdomainNames::new(Vec::new(), words(&["b"]), DEFAULT_TEMPLATE),
+            Err(SubdomainNamesError::EmptyList("adjective"))
+        ));
+    }
+
+    #[test]
+    fn test_larger_wordlist_is_used() {
+        let dir = std::env::temp_dir().join(format!("dvaar-wordlist-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let adjectives: Vec<String> = (0..500).map(|i| format!("adj{}", i)).collect();
+        let path = dir.join("adjectives.txt");
+        std::fs::write(&path, format!("# one per line\n{}\n\n", adjectives.join("\n"))).unwrap();
+
+        let names = SubdomainNames::load(Some(path.to_str().unwrap()), None, "{adjective}-{noun}").unwrap();
+        assert_eq!(names.combinations(), 500 * DEFAULT_NOUNS.len() as u128);
+
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..2000 {
+            let name = names.generate();
+            let (adjective, noun) = name.split_once('-').unwrap();
+            assert!(adjectives.iter().any(|a| a == adjective), "{}", name);
+            assert!(DEFAULT_NOUNS.contains(&noun), "{}", name);
+            seen.insert(adjective.to_string());
+        }
+        // Far more than the 64 built-in adjectives show up
+        assert!(seen.len() > 300, "only {} adjectives used", seen.len());
+
+        assert!(matches!(
+            SubdomainNames::load(Some(dir.join("missing.txt").to_str().unwrap()), None, DEFAULT_TEMPLATE),
+            Err(SubdomainNamesError::Read { .. })
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
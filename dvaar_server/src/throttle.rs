@@ -0,0 +1,120 @@
+//! Per-tunnel bandwidth throttling
+//!
+//! The monthly cap bounds how much a tunnel moves in total, not how fast, so
+//! one tunnel could still saturate the node's uplink for a while. Each tunnel
+//! gets a token bucket sized by its plan; traffic beyond the rate is delayed,
+//! never dropped, which pushes back on the sender through the usual flow
+//! control.
+
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Token bucket over bytes. Tokens refill at `rate` per second up to `burst`;
+/// a caller that takes more than is available goes into debt and sleeps until
+/// the debt is repaid, so concurrent callers are served in turn.
+#[derive(Debug)]
+pub struct ByteRateLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl ByteRateLimiter {
+    /// Limit to `bytes_per_sec`, allowing a burst of one second's worth
+    pub fn per_second(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec as f64;
+        Self {
+            rate,
+            burst: rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until `bytes` may pass
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+            bucket.refilled_at = now;
+
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK: usize = 64 * 1024;
+    const CHUNKS: usize = 64;
+    const RATE: u64 = 256 * 1024;
+
+    /// Bytes per second achieved pushing `CHUNKS` chunks through `limiter`
+    async fn throughput(limiter: Option<&ByteRateLimiter>) -> f64 {
+        let started = Instant::now();
+        for _ in 0..CHUNKS {
+            if let Some(limiter) = limiter {
+                limiter.acquire(CHUNK).await;
+            }
+            // Stands in for the socket write
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        (CHUNK * CHUNKS) as f64 / started.elapsed().as_secs_f64()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_capped_tunnel_stays_under_rate() {
+        let limiter = ByteRateLimiter::per_second(RATE);
+        let capped = throughput(Some(&limiter)).await;
+        // The first second's burst passes at once, only the rest is paced
+        let total = (CHUNK * CHUNKS) as f64;
+        let ceiling = RATE as f64 * total / (total - RATE as f64);
+        assert!(capped <= ceiling * 1.01, "capped at {} B/s", capped);
+        assert!(capped >= RATE as f64 * 0.9, "throttled too hard: {} B/s", capped);
+
+        let uncapped = throughput(None).await;
+        assert!(uncapped > RATE as f64 * 10.0, "uncapped at {} B/s", uncapped);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_concurrent_senders_share_the_rate() {
+        let limiter = std::sync::Arc::new(ByteRateLimiter::per_second(RATE));
+        let started = Instant::now();
+        let senders: Vec<_> = (0..4)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move {
+                    for _ in 0..16 {
+                        limiter.acquire(CHUNK).await;
+                    }
+                })
+            })
+            .collect();
+        for sender in senders {
+            sender.await.unwrap();
+        }
+
+        // 4 MiB at 256 KiB/s, less the 256 KiB burst
+        let expected = (4 * 16 * CHUNK) as f64 / RATE as f64 - 1.0;
+        let elapsed = started.elapsed().as_secs_f64();
+        assert!(elapsed >= expected, "finished in {}s, expected at least {}s", elapsed, expected);
+    }
+}
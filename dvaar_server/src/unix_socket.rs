@@ -0,0 +1,115 @@
+//! Public listener on a Unix domain socket
+//!
+//! For running behind a reverse proxy on the same host. Unix peers have no IP,
+//! so every connection reports a loopback `SocketAddr`: handlers keep their
+//! `ConnectInfo<SocketAddr>` extractor, and the proxy on the other end is
+//! treated like any other local caller.
+
+use axum::serve::Listener;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::net::{UnixListener, UnixStream};
+
+/// Address reported for every connection
+const PEER_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+pub struct UnixSocketListener {
+    inner: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocketListener {
+    /// Bind `path`, replacing a socket left behind by a previous run.
+    /// Any other kind of file at `path` is an error.
+    pub fn bind(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        remove_stale_socket(&path)?;
+        let inner = UnixListener::bind(&path)?;
+        Ok(Self { inner, path })
+    }
+}
+
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+impl Listener for UnixSocketListener {
+    type Io = UnixStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            match self.inner.accept().await {
+                Ok((stream, _)) => return (stream, PEER_ADDR),
+                Err(e) => {
+                    tracing::warn!("Failed to accept connection on {}: {}", self.path.display(), e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(PEER_ADDR)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::ConnectInfo, routing::get, serve::ListenerExt, Router};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_serves_health_over_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("dvaar-uds-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dvaar.sock");
+
+        // A socket left over from an earlier run is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = UnixSocketListener::bind(&path).unwrap();
+
+        let app = Router::new().route(
+            "/health",
+            get(|ConnectInfo(addr): ConnectInfo<SocketAddr>| async move {
+                format!("ok from {}", addr.ip())
+            }),
+        );
+        tokio::spawn(async move {
+            axum::serve(
+                listener.tap_io(|_| {}),
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("ok from 127.0.0.1"));
+
+        // Regular files are left alone
+        let file = dir.join("not-a-socket");
+        std::fs::write(&file, b"").unwrap();
+        assert!(UnixSocketListener::bind(&file).is_err());
+    }
+}